@@ -105,9 +105,8 @@ fn main() -> Result<()> {
         .with_context(|| "unable to load model")?;
 
     // initialize the context
-    let ctx_params = LlamaContextParams::default()
-        .with_n_threads_batch(std::thread::available_parallelism()?.get().try_into()?)
-        .with_embeddings(true);
+    let ctx_params = LlamaContextParams::for_embeddings(&model)
+        .with_n_threads_batch(std::thread::available_parallelism()?.get().try_into()?);
 
     let mut ctx = model
         .new_context(&backend, ctx_params)
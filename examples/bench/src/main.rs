@@ -0,0 +1,381 @@
+//! Measure prompt processing and token generation throughput, like llama.cpp's `llama-bench`.
+//!
+//! Every combination of the comma separated `--n-gpu-layers`, `--threads`, `--n-batch` and
+//! `--n-ubatch` values runs every test: `pp<n>` decodes a prompt of `n` tokens in batches of
+//! `n_batch`, `tg<n>` generates `n` tokens one decode at a time. Each test is repeated after a
+//! warmup run and reported as the mean and standard deviation of tokens per second, as a
+//! markdown table or as JSON for comparing runs in CI:
+//!
+//! ```console
+//! cargo run -p bench --release --features cuda -- model.gguf --n-gpu-layers 0,99 --output json
+//! ```
+//!
+//! With `--grammar-file`, `gr<n>-<mode>` tests also generate up to `n` tokens greedily with the
+//! grammar, applied to the whole vocabulary every step (`full`), only when the unconstrained
+//! token is not allowed (`lazy`) or from a mask cache filled by the warmup run (`cached`). These
+//! count the tokens actually generated, since the grammar may end the output early:
+//!
+//! ```console
+//! cargo run -p bench --release -- model.gguf -p 0 -n 256 --grammar-file json.gbnf
+//! ```
+
+use std::fmt::{Display, Formatter};
+use std::num::{NonZeroU32, NonZeroUsize};
+use std::ops::ControlFlow;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+use clap::{Parser, ValueEnum};
+use llama_cpp_2::context::params::LlamaContextParams;
+use llama_cpp_2::context::LlamaContext;
+use llama_cpp_2::generate::{GenerationParams, SamplingParams};
+use llama_cpp_2::grammar::cache::CompiledGrammar;
+use llama_cpp_2::llama_backend::LlamaBackend;
+use llama_cpp_2::llama_batch::LlamaBatch;
+use llama_cpp_2::model::params::LlamaModelParams;
+use llama_cpp_2::model::LlamaModel;
+use llama_cpp_2::token::LlamaToken;
+use serde::Serialize;
+
+#[derive(clap::Parser, Debug, Clone)]
+struct Args {
+    /// The path to the model
+    model: PathBuf,
+    /// The prompt lengths to test, 0 skips prompt processing
+    #[arg(short = 'p', long, value_delimiter = ',', default_value = "512")]
+    n_prompt: Vec<usize>,
+    /// The generation lengths to test, 0 skips generation
+    #[arg(short = 'n', long, value_delimiter = ',', default_value = "128")]
+    n_gen: Vec<usize>,
+    /// The batch sizes to test
+    #[arg(short = 'b', long, value_delimiter = ',', default_value = "2048")]
+    n_batch: Vec<u32>,
+    /// The physical batch sizes to test
+    #[arg(long, value_delimiter = ',', default_value = "512")]
+    n_ubatch: Vec<u32>,
+    /// The thread counts to test (default: all available threads)
+    #[arg(short = 't', long, value_delimiter = ',')]
+    threads: Vec<i32>,
+    /// The numbers of layers to offload to the gpu to test
+    #[arg(long, value_delimiter = ',', default_value = "99")]
+    n_gpu_layers: Vec<u32>,
+    /// Also generate with the GBNF grammar in this file, as `gr<n>-<mode>` tests
+    #[arg(long)]
+    grammar_file: Option<PathBuf>,
+    /// The ways of applying the grammar to test
+    #[arg(
+        long,
+        value_enum,
+        value_delimiter = ',',
+        default_value = "full,lazy,cached"
+    )]
+    grammar_mode: Vec<GrammarMode>,
+    /// How often every test is repeated
+    #[arg(short = 'r', long, default_value_t = 5)]
+    repetitions: usize,
+    /// The output format
+    #[arg(short = 'o', long, value_enum, default_value_t = Output::Markdown)]
+    output: Output,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum Output {
+    Markdown,
+    Json,
+}
+
+/// How a `gr<n>` test applies the grammar.
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum GrammarMode {
+    /// To every token of the vocabulary, every step.
+    Full,
+    /// Only when the token sampled without it is not allowed.
+    Lazy,
+    /// From a mask cache shared by the repetitions.
+    Cached,
+}
+
+impl GrammarMode {
+    /// The grammar to instantiate for every repetition of the test.
+    fn grammar(self, grammar: &CompiledGrammar) -> CompiledGrammar {
+        match self {
+            GrammarMode::Full | GrammarMode::Lazy => grammar.clone(),
+            GrammarMode::Cached => grammar
+                .clone()
+                .with_mask_cache(NonZeroUsize::new(1 << 16).expect("nonzero")),
+        }
+    }
+}
+
+impl Display for GrammarMode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            GrammarMode::Full => "full",
+            GrammarMode::Lazy => "lazy",
+            GrammarMode::Cached => "cached",
+        })
+    }
+}
+
+/// What a test measures.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Test {
+    /// Decode a prompt of this many tokens.
+    Prompt(usize),
+    /// Generate this many tokens one by one.
+    Generate(usize),
+    /// Sample up to this many tokens with the grammar.
+    Grammar(usize, GrammarMode),
+}
+
+impl Test {
+    fn n_tokens(self) -> usize {
+        match self {
+            Test::Prompt(n_tokens) | Test::Generate(n_tokens) | Test::Grammar(n_tokens, _) => {
+                n_tokens
+            }
+        }
+    }
+}
+
+impl Display for Test {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Test::Prompt(n_tokens) => write!(f, "pp{n_tokens}"),
+            Test::Generate(n_tokens) => write!(f, "tg{n_tokens}"),
+            Test::Grammar(n_tokens, mode) => write!(f, "gr{n_tokens}-{mode}"),
+        }
+    }
+}
+
+/// The result of one test on one configuration.
+#[derive(Debug, Clone, Serialize)]
+struct Row {
+    model: String,
+    model_size: u64,
+    model_n_params: u64,
+    n_gpu_layers: u32,
+    n_threads: i32,
+    n_batch: u32,
+    n_ubatch: u32,
+    test: String,
+    n_prompt: usize,
+    n_gen: usize,
+    avg_ts: f64,
+    stddev_ts: f64,
+    samples_ns: Vec<u64>,
+}
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+    let threads = if args.threads.is_empty() {
+        let available = std::thread::available_parallelism()
+            .map_or(4, |n| i32::try_from(n.get()).unwrap_or(i32::MAX));
+        vec![available]
+    } else {
+        args.threads.clone()
+    };
+    let tests: Vec<Test> = args
+        .n_prompt
+        .iter()
+        .map(|&n| Test::Prompt(n))
+        .chain(args.n_gen.iter().map(|&n| Test::Generate(n)))
+        .chain(args.grammar_file.iter().flat_map(|_| {
+            args.n_gen.iter().flat_map(|&n| {
+                args.grammar_mode
+                    .iter()
+                    .map(move |&mode| Test::Grammar(n, mode))
+            })
+        }))
+        .filter(|test| test.n_tokens() > 0)
+        .collect();
+    let grammar = args
+        .grammar_file
+        .as_ref()
+        .map(|path| {
+            let gbnf = std::fs::read_to_string(path)
+                .with_context(|| format!("unable to read {}", path.display()))?;
+            CompiledGrammar::new(&gbnf)
+                .with_context(|| format!("invalid grammar {}", path.display()))
+        })
+        .transpose()?;
+    let n_ctx = tests.iter().map(|test| test.n_tokens()).max().unwrap_or(1);
+    let n_ctx = NonZeroU32::new(u32::try_from(n_ctx)?).context("no tests to run")?;
+
+    let backend = LlamaBackend::init()?;
+    let mut rows = Vec::new();
+    for &n_gpu_layers in &args.n_gpu_layers {
+        let model_params = LlamaModelParams::default().with_n_gpu_layers(n_gpu_layers);
+        let model = LlamaModel::load_from_file(&backend, &args.model, &model_params)
+            .with_context(|| format!("unable to load {}", args.model.display()))?;
+        for &n_threads in &threads {
+            for &n_batch in &args.n_batch {
+                for &n_ubatch in &args.n_ubatch {
+                    let params = LlamaContextParams::default()
+                        .with_n_ctx(Some(n_ctx))
+                        .with_n_batch(n_batch)
+                        .with_n_ubatch(n_ubatch.min(n_batch))
+                        .with_n_threads(n_threads)
+                        .with_n_threads_batch(n_threads);
+                    let mut ctx = model
+                        .new_context(&backend, params)
+                        .context("unable to create the context")?;
+                    for &test in &tests {
+                        let grammar = match (test, &grammar) {
+                            (Test::Grammar(_, mode), Some(grammar)) => Some(mode.grammar(grammar)),
+                            _ => None,
+                        };
+                        let samples = (0..=args.repetitions)
+                            .map(|_| run(&mut ctx, test, grammar.as_ref()))
+                            .collect::<Result<Vec<_>>>()?;
+                        // the first run is the warmup
+                        let row = Row::new(
+                            &args,
+                            &model,
+                            &ctx,
+                            n_gpu_layers,
+                            n_threads,
+                            test,
+                            &samples[1..],
+                        );
+                        if args.output == Output::Markdown {
+                            eprintln!("{} {}: {:.2} t/s", row.model, row.test, row.avg_ts);
+                        }
+                        rows.push(row);
+                    }
+                }
+            }
+        }
+    }
+
+    match args.output {
+        Output::Markdown => print_markdown(&rows),
+        Output::Json => println!("{}", serde_json::to_string_pretty(&rows)?),
+    }
+    Ok(())
+}
+
+/// Run `test` once on a cleared cache and return how long the decodes took and how many tokens
+/// they processed.
+fn run(
+    ctx: &mut LlamaContext,
+    test: Test,
+    grammar: Option<&CompiledGrammar>,
+) -> Result<(Duration, usize)> {
+    let n_batch = usize::try_from(ctx.n_batch())?;
+    let n_vocab = ctx.model.n_vocab();
+    // llama-bench decodes random tokens; any valid token costs the same
+    let token = |i: usize| LlamaToken::new(i32::try_from(i).unwrap_or(0) % n_vocab);
+    ctx.clear_kv_cache();
+    ctx.synchronize();
+
+    let start = Instant::now();
+    let n_processed = match test {
+        Test::Prompt(n_tokens) => {
+            let mut batch = LlamaBatch::new(n_batch, 1);
+            for first in (0..n_tokens).step_by(n_batch) {
+                batch.clear();
+                for i in first..n_tokens.min(first + n_batch) {
+                    batch.add(token(i), i32::try_from(i)?, &[0], i + 1 == n_tokens)?;
+                }
+                ctx.decode(&mut batch)?;
+            }
+            n_tokens
+        }
+        Test::Generate(n_tokens) => {
+            let mut batch = LlamaBatch::new(1, 1);
+            for i in 0..n_tokens {
+                batch.clear();
+                batch.add(token(i), i32::try_from(i)?, &[0], true)?;
+                ctx.decode(&mut batch)?;
+            }
+            n_tokens
+        }
+        Test::Grammar(n_tokens, mode) => {
+            let grammar = grammar.context("grammar tests need a grammar")?;
+            let mut params = GenerationParams::default()
+                .with_sampling(SamplingParams::greedy())
+                .with_max_tokens(n_tokens)
+                .with_grammar(grammar.instantiate())
+                .with_lazy_grammar(mode == GrammarMode::Lazy);
+            let prompt = [ctx.model.token_bos()];
+            let generation = ctx.generate(&prompt, &mut params, |_| ControlFlow::Continue(()))?;
+            generation.tokens.len()
+        }
+    };
+    ctx.synchronize();
+    Ok((start.elapsed(), n_processed))
+}
+
+impl Row {
+    #[allow(clippy::cast_precision_loss)]
+    fn new(
+        args: &Args,
+        model: &LlamaModel,
+        ctx: &LlamaContext,
+        n_gpu_layers: u32,
+        n_threads: i32,
+        test: Test,
+        samples: &[(Duration, usize)],
+    ) -> Self {
+        let ts: Vec<f64> = samples
+            .iter()
+            .map(|(sample, n_tokens)| *n_tokens as f64 / sample.as_secs_f64())
+            .collect();
+        let avg_ts = ts.iter().sum::<f64>() / ts.len().max(1) as f64;
+        let stddev_ts = if ts.len() > 1 {
+            let variance =
+                ts.iter().map(|ts| (ts - avg_ts).powi(2)).sum::<f64>() / (ts.len() - 1) as f64;
+            variance.sqrt()
+        } else {
+            0.0
+        };
+        let (n_prompt, n_gen) = match test {
+            Test::Prompt(n_tokens) => (n_tokens, 0),
+            Test::Generate(n_tokens) | Test::Grammar(n_tokens, _) => (0, n_tokens),
+        };
+        Self {
+            model: args.model.file_name().map_or_else(
+                || args.model.display().to_string(),
+                |name| name.to_string_lossy().into_owned(),
+            ),
+            model_size: model.size(),
+            model_n_params: model.n_params(),
+            n_gpu_layers,
+            n_threads,
+            n_batch: ctx.n_batch(),
+            n_ubatch: ctx.n_ubatch(),
+            test: test.to_string(),
+            n_prompt,
+            n_gen,
+            avg_ts,
+            stddev_ts,
+            samples_ns: samples
+                .iter()
+                .map(|(sample, _)| u64::try_from(sample.as_nanos()).unwrap_or(u64::MAX))
+                .collect(),
+        }
+    }
+}
+
+#[allow(clippy::cast_precision_loss)]
+fn print_markdown(rows: &[Row]) {
+    println!("| model | size | params | ngl | threads | n_batch | n_ubatch | test | t/s |");
+    println!("| --- | ---: | ---: | ---: | ---: | ---: | ---: | ---: | ---: |");
+    for row in rows {
+        println!(
+            "| {} | {:.2} GiB | {:.2} B | {} | {} | {} | {} | {} | {:.2} ± {:.2} |",
+            row.model,
+            row.model_size as f64 / f64::from(1 << 30),
+            row.model_n_params as f64 / 1e9,
+            row.n_gpu_layers,
+            row.n_threads,
+            row.n_batch,
+            row.n_ubatch,
+            row.test,
+            row.avg_ts,
+            row.stddev_ts
+        );
+    }
+}
@@ -0,0 +1,209 @@
+//! The thread that owns the contexts and runs every request.
+//!
+//! Contexts and [`GenerationParams`] cannot leave the thread that uses them, so the handlers send
+//! [`Job`]s and the engine builds the params itself. Chat completions are submitted to one
+//! [`SlotManager`] and every step decodes all of them together; their tokens are sent back as
+//! [`StreamEvent`]s. Embeddings run on a separate context between two steps.
+
+use std::collections::HashMap;
+use std::num::{NonZeroU32, NonZeroUsize};
+use std::ops::ControlFlow;
+
+use anyhow::{Context, Result};
+use llama_cpp_2::context::params::LlamaContextParams;
+use llama_cpp_2::context::LlamaContext;
+use llama_cpp_2::generate::slots::{RequestId, SlotManager};
+use llama_cpp_2::generate::stream::StreamEvent;
+use llama_cpp_2::generate::GenerationParams;
+use llama_cpp_2::grammar::cache::GrammarCache;
+use llama_cpp_2::llama_backend::LlamaBackend;
+use llama_cpp_2::model::LlamaModel;
+use llama_cpp_2::openai::ChatCompletionRequest;
+use llama_cpp_2::token::LlamaToken;
+use tokio::sync::{mpsc, oneshot};
+
+use crate::error::ApiError;
+
+/// How the engine sets up its contexts.
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// The number of chat completions generated at once.
+    pub n_slots: u32,
+    /// The size of the context shared by the slots.
+    pub n_ctx: NonZeroU32,
+    /// The token limit of completions that set none.
+    pub max_tokens: Option<usize>,
+    /// The number of compiled `response_format` grammars kept.
+    pub grammar_cache: NonZeroUsize,
+    /// Whether to create a context for embeddings.
+    pub embeddings: bool,
+}
+
+/// Work sent to the engine by a handler.
+#[derive(Debug)]
+pub enum Job {
+    /// Generate a chat completion of the templated and tokenized `prompt`. `accepted` is answered
+    /// before any event is sent, the events end with [`StreamEvent::Done`] or
+    /// [`StreamEvent::Error`].
+    Chat {
+        prompt: Vec<LlamaToken>,
+        request: Box<ChatCompletionRequest>,
+        accepted: oneshot::Sender<Result<(), ApiError>>,
+        events: mpsc::UnboundedSender<StreamEvent>,
+    },
+    /// Embed every text.
+    Embed {
+        texts: Vec<String>,
+        reply: oneshot::Sender<Result<Vec<Vec<f32>>, ApiError>>,
+    },
+}
+
+/// Create the contexts, then serve `jobs` until every sender is dropped. `ready` is sent once the
+/// contexts exist.
+///
+/// # Errors
+///
+/// If a context cannot be created.
+pub fn run(
+    backend: &LlamaBackend,
+    model: &LlamaModel,
+    config: &Config,
+    mut jobs: mpsc::UnboundedReceiver<Job>,
+    ready: oneshot::Sender<()>,
+) -> Result<()> {
+    let params = LlamaContextParams::default()
+        .with_n_ctx(Some(config.n_ctx))
+        .with_n_seq_max(config.n_slots);
+    let mut ctx = model
+        .new_context(backend, params)
+        .context("unable to create the context")?;
+    let mut embeddings = config
+        .embeddings
+        .then(|| model.new_context(backend, LlamaContextParams::for_embeddings(model)))
+        .transpose()
+        .context("unable to create the embeddings context")?;
+    let n_slots = usize::try_from(config.n_slots).context("n_slots does not fit into a usize")?;
+    let mut engine = Engine {
+        slots: SlotManager::new(&ctx, n_slots),
+        grammars: GrammarCache::new(config.grammar_cache),
+        max_tokens: config.max_tokens,
+        requests: HashMap::new(),
+    };
+    let _ = ready.send(());
+
+    loop {
+        if engine.slots.is_idle() {
+            let Some(job) = jobs.blocking_recv() else {
+                return Ok(());
+            };
+            engine.handle(job, embeddings.as_mut());
+        }
+        while let Ok(job) = jobs.try_recv() {
+            engine.handle(job, embeddings.as_mut());
+        }
+        engine.step(&mut ctx);
+    }
+}
+
+/// The state of the engine thread besides its contexts.
+struct Engine {
+    slots: SlotManager,
+    grammars: GrammarCache,
+    max_tokens: Option<usize>,
+    /// Where to send the events of every submitted request.
+    requests: HashMap<RequestId, mpsc::UnboundedSender<StreamEvent>>,
+}
+
+impl Engine {
+    fn handle(&mut self, job: Job, embeddings: Option<&mut LlamaContext>) {
+        match job {
+            Job::Chat {
+                prompt,
+                request,
+                accepted,
+                events,
+            } => match self.submit(prompt, &request, events.clone()) {
+                Ok(id) => {
+                    self.requests.insert(id, events);
+                    let _ = accepted.send(Ok(()));
+                }
+                Err(err) => {
+                    let _ = accepted.send(Err(err));
+                }
+            },
+            Job::Embed { texts, reply } => {
+                let result = match embeddings {
+                    Some(ctx) => ctx
+                        .embed_batch(&texts, true)
+                        .collect::<Result<Vec<_>, _>>()
+                        .map_err(ApiError::bad_request),
+                    None => Err(ApiError::bad_request(
+                        "embeddings are disabled, start the server with --embeddings",
+                    )),
+                };
+                let _ = reply.send(result);
+            }
+        }
+    }
+
+    /// Queue `request` with the params it asks for.
+    fn submit(
+        &mut self,
+        prompt: Vec<LlamaToken>,
+        request: &ChatCompletionRequest,
+        events: mpsc::UnboundedSender<StreamEvent>,
+    ) -> Result<RequestId, ApiError> {
+        let mut params = GenerationParams::try_from(request)
+            .map_err(ApiError::bad_request)?
+            .with_disconnect_check(move || events.is_closed());
+        if let (None, Some(max_tokens)) = (request.max_tokens(), self.max_tokens) {
+            params = params.with_max_tokens(max_tokens);
+        }
+        let grammar = request
+            .grammar(&self.grammars)
+            .map_err(ApiError::bad_request)?;
+        if let Some(grammar) = grammar {
+            params = params.with_grammar(grammar.instantiate());
+        }
+        self.slots
+            .submit(prompt, params)
+            .map_err(ApiError::bad_request)
+    }
+
+    /// Decode one batch and send the new token of every request.
+    fn step(&mut self, ctx: &mut LlamaContext) {
+        let requests = &self.requests;
+        let result = self.slots.step(ctx, |id, event| {
+            if let Some(events) = requests.get(&id) {
+                let _ = events.send(StreamEvent::Token {
+                    token: event.token,
+                    text: event.text.to_string(),
+                    logprob: event.logprob,
+                });
+            }
+            ControlFlow::Continue(())
+        });
+        let finished = match result {
+            Ok(finished) => finished,
+            Err(err) => {
+                // the failed requests were dropped, the others go on
+                for id in &err.failed {
+                    if let Some(events) = self.requests.remove(id) {
+                        let _ = events.send(StreamEvent::Error(err.error.clone()));
+                    }
+                }
+                err.finished
+            }
+        };
+        for (id, generation) in finished {
+            if let Some(events) = self.requests.remove(&id) {
+                let _ = events.send(StreamEvent::Done {
+                    finish_reason: generation.finish_reason,
+                    stats: generation.stats,
+                });
+            }
+        }
+        // requests of clients that went away while queued are dropped without finishing
+        self.requests.retain(|_, events| !events.is_closed());
+    }
+}
@@ -0,0 +1,134 @@
+//! A minimal OpenAI-compatible server on top of llama-cpp-2.
+//!
+//! It serves `/v1/chat/completions` (streamed as server sent events or as one response),
+//! `/v1/embeddings` and `/v1/models`. One engine thread owns the contexts and runs every chat
+//! completion on a [`SlotManager`](llama_cpp_2::generate::slots::SlotManager), so concurrent
+//! requests are batched together; the HTTP handlers only template, tokenize and forward.
+//!
+//! ```console
+//! cargo run -p server --release -- path/to/model.gguf --parallel 4 --embeddings
+//! curl localhost:8080/v1/chat/completions -d '{"model": "llama", "messages": [{"role": "user", "content": "Hi!"}]}'
+//! ```
+
+use std::net::SocketAddr;
+use std::num::{NonZeroU32, NonZeroUsize};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::thread::JoinHandle;
+
+use anyhow::{anyhow, Context, Result};
+use clap::Parser;
+use llama_cpp_2::chat::template::TemplateSelector;
+use llama_cpp_2::llama_backend::LlamaBackend;
+use llama_cpp_2::model::params::LlamaModelParams;
+use llama_cpp_2::model::LlamaModel;
+use tokio::sync::{mpsc, oneshot};
+
+mod engine;
+mod error;
+mod routes;
+
+#[derive(clap::Parser, Debug, Clone)]
+struct Args {
+    /// The path to the model
+    model: PathBuf,
+    /// The name the model is served as (default: the file name of the model)
+    #[arg(long)]
+    alias: Option<String>,
+    /// The address to listen on
+    #[arg(long, default_value = "127.0.0.1:8080")]
+    address: SocketAddr,
+    /// The number of chat completions generated at once
+    #[arg(long, default_value_t = 4)]
+    parallel: u32,
+    /// The size of the context shared by the parallel completions, in tokens
+    #[arg(short = 'c', long, default_value_t = NonZeroU32::new(4096).expect("4096 is not 0"))]
+    ctx_size: NonZeroU32,
+    /// The maximum number of tokens of a completion that sets no `max_tokens`
+    #[arg(long)]
+    max_tokens: Option<usize>,
+    /// The number of distinct `response_format` grammars kept compiled
+    #[arg(long, default_value_t = NonZeroUsize::new(16).expect("16 is not 0"))]
+    grammar_cache: NonZeroUsize,
+    /// The chat template to use instead of the model's, a llama.cpp template name such as
+    /// `chatml` or Jinja source
+    #[arg(long)]
+    chat_template: Option<String>,
+    /// Serve `/v1/embeddings` from a second context
+    #[arg(long)]
+    embeddings: bool,
+    /// The number of layers to offload to the gpu
+    #[cfg(any(feature = "cuda", feature = "vulkan", feature = "metal"))]
+    #[arg(long, default_value_t = 1000)]
+    n_gpu_layers: u32,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args = Args::parse();
+
+    let backend = Arc::new(LlamaBackend::init()?);
+    let model_params = LlamaModelParams::default();
+    #[cfg(any(feature = "cuda", feature = "vulkan", feature = "metal"))]
+    let model_params = model_params.with_n_gpu_layers(args.n_gpu_layers);
+    let model = Arc::new(
+        LlamaModel::load_from_file(&backend, &args.model, &model_params)
+            .with_context(|| format!("unable to load {}", args.model.display()))?,
+    );
+    let model_name = args.alias.clone().unwrap_or_else(|| {
+        args.model.file_name().map_or_else(
+            || "llama".to_string(),
+            |name| name.to_string_lossy().into_owned(),
+        )
+    });
+    let mut selector = TemplateSelector::new();
+    if let Some(template) = &args.chat_template {
+        selector = selector.with_forced(template);
+    }
+    let template = selector.select(&model);
+    match &template {
+        Some(template) => eprintln!("chat template: {:?}", template.source),
+        None => eprintln!("chat template: none found, using llama.cpp's default"),
+    }
+
+    let (jobs, receiver) = mpsc::unbounded_channel();
+    let config = engine::Config {
+        n_slots: args.parallel,
+        n_ctx: args.ctx_size,
+        max_tokens: args.max_tokens,
+        grammar_cache: args.grammar_cache,
+        embeddings: args.embeddings,
+    };
+    let (ready, started) = oneshot::channel();
+    let engine = {
+        let (backend, model) = (Arc::clone(&backend), Arc::clone(&model));
+        std::thread::Builder::new()
+            .name("engine".to_string())
+            .spawn(move || engine::run(&backend, &model, &config, receiver, ready))?
+    };
+    // the engine only drops `ready` without sending if it failed to create its contexts
+    if started.await.is_err() {
+        return join(engine);
+    }
+
+    let app = routes::router(routes::AppState::new(
+        model,
+        model_name,
+        template.map(|template| template.template),
+        jobs,
+    ));
+    let listener = tokio::net::TcpListener::bind(args.address)
+        .await
+        .with_context(|| format!("unable to listen on {}", args.address))?;
+    eprintln!("listening on http://{}", args.address);
+    axum::serve(listener, app).await?;
+
+    join(engine)
+}
+
+/// Wait for the engine thread to stop and return its error.
+fn join(engine: JoinHandle<Result<()>>) -> Result<()> {
+    engine
+        .join()
+        .map_err(|_| anyhow!("the engine thread panicked"))?
+}
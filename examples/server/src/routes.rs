@@ -0,0 +1,269 @@
+//! The HTTP handlers.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use axum::extract::State;
+use axum::response::sse::{Event as SseEvent, KeepAlive, Sse};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use llama_cpp_2::generate::stream::StreamEvent;
+use llama_cpp_2::model::{AddBos, LlamaModel, Special};
+use llama_cpp_2::openai::{
+    self, ChatCompletionChunk, ChatCompletionRequest, ChatCompletionResponse, Usage,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tokio::sync::{mpsc, oneshot};
+use tokio_stream::wrappers::UnboundedReceiverStream;
+use tokio_stream::StreamExt;
+
+use crate::engine::Job;
+use crate::error::ApiError;
+
+/// What every handler shares.
+#[derive(Debug, Clone)]
+pub struct AppState {
+    model: Arc<LlamaModel>,
+    model_name: Arc<str>,
+    /// The chat template, `None` to let llama.cpp pick one.
+    template: Option<Arc<str>>,
+    jobs: mpsc::UnboundedSender<Job>,
+    next_id: Arc<AtomicU64>,
+}
+
+impl AppState {
+    pub fn new(
+        model: Arc<LlamaModel>,
+        model_name: String,
+        template: Option<String>,
+        jobs: mpsc::UnboundedSender<Job>,
+    ) -> Self {
+        Self {
+            model,
+            model_name: model_name.into(),
+            template: template.map(Into::into),
+            jobs,
+            next_id: Arc::default(),
+        }
+    }
+}
+
+/// The routes of the server.
+pub fn router(state: AppState) -> Router {
+    Router::new()
+        .route("/v1/chat/completions", post(chat_completions))
+        .route("/v1/embeddings", post(embeddings))
+        .route("/v1/models", get(models))
+        .with_state(state)
+}
+
+/// Reject the parts of a request the server cannot serve instead of ignoring them.
+fn check_supported(request: &ChatCompletionRequest) -> Result<(), ApiError> {
+    let unsupported = if request.tools.is_some() {
+        Some("tools")
+    } else if request.tool_choice.is_some() {
+        Some("tool_choice")
+    } else if request.n.is_some_and(|n| n != 1) {
+        Some("n other than 1")
+    } else if request.logprobs == Some(true) || request.top_logprobs.is_some() {
+        Some("logprobs")
+    } else {
+        None
+    };
+    match unsupported {
+        Some(field) => Err(ApiError::bad_request(format!(
+            "{field} is not supported by this server"
+        ))),
+        None => Ok(()),
+    }
+}
+
+/// Seconds since the unix epoch.
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs())
+}
+
+async fn chat_completions(
+    State(state): State<AppState>,
+    Json(request): Json<ChatCompletionRequest>,
+) -> Result<Response, ApiError> {
+    check_supported(&request)?;
+    let messages = request.chat_messages().map_err(ApiError::bad_request)?;
+    let prompt = state
+        .model
+        .apply_chat_template(
+            state.template.as_deref().map(str::to_string),
+            messages,
+            true,
+        )
+        .map_err(ApiError::bad_request)?;
+    let prompt = state
+        .model
+        .str_to_token_with_special(&prompt, AddBos::Always, Special::Tokenize)
+        .map_err(ApiError::bad_request)?;
+
+    let stream = request.stream;
+    let (accepted, reply) = oneshot::channel();
+    let (sender, mut events) = mpsc::unbounded_channel();
+    state
+        .jobs
+        .send(Job::Chat {
+            prompt,
+            request: Box::new(request),
+            accepted,
+            events: sender,
+        })
+        .map_err(|_| ApiError::unavailable())?;
+    reply.await.map_err(|_| ApiError::unavailable())??;
+
+    let id = format!("chatcmpl-{}", state.next_id.fetch_add(1, Ordering::Relaxed));
+    let created = now();
+    if stream {
+        return Ok(stream_completion(id, &state.model_name, created, events).into_response());
+    }
+
+    let mut content = String::new();
+    while let Some(event) = events.recv().await {
+        match event {
+            StreamEvent::Token { text, .. } | StreamEvent::Text(text) => content.push_str(&text),
+            StreamEvent::ToolCallDelta(_) => {}
+            StreamEvent::Done {
+                finish_reason,
+                stats,
+            } => {
+                let response = ChatCompletionResponse::new(
+                    id,
+                    &*state.model_name,
+                    created,
+                    content,
+                    openai::FinishReason::from(&finish_reason),
+                    Usage::from(&stats),
+                );
+                return Ok(Json(response).into_response());
+            }
+            StreamEvent::Error(err) => return Err(ApiError::internal(err)),
+        }
+    }
+    Err(ApiError::unavailable())
+}
+
+/// Stream the events of a completion as chunks: the role first, then the text, then the finish
+/// reason with the usage and `[DONE]`.
+fn stream_completion(
+    id: String,
+    model: &str,
+    created: u64,
+    events: mpsc::UnboundedReceiver<StreamEvent>,
+) -> impl IntoResponse {
+    let role = ChatCompletionChunk::role(id.clone(), model, created);
+    let model = model.to_string();
+    let chunks = UnboundedReceiverStream::new(events).filter_map(move |event| match event {
+        StreamEvent::Error(err) => {
+            Some(SseEvent::default().json_data(ApiError::internal(err).body()))
+        }
+        event => ChatCompletionChunk::from_event(&event, id.clone(), model.clone(), created)
+            .map(|chunk| SseEvent::default().json_data(chunk)),
+    });
+    let stream = tokio_stream::once(SseEvent::default().json_data(role))
+        .chain(chunks)
+        .chain(tokio_stream::once(Ok(SseEvent::default().data("[DONE]"))));
+    Sse::new(stream).keep_alive(KeepAlive::default())
+}
+
+/// The body of `/v1/embeddings`.
+#[derive(Debug, Deserialize)]
+struct EmbeddingRequest {
+    input: EmbeddingInput,
+}
+
+/// One text or many.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum EmbeddingInput {
+    One(String),
+    Many(Vec<String>),
+}
+
+#[derive(Debug, Serialize)]
+struct EmbeddingResponse {
+    object: &'static str,
+    data: Vec<EmbeddingData>,
+    model: String,
+    usage: EmbeddingUsage,
+}
+
+#[derive(Debug, Serialize)]
+struct EmbeddingData {
+    object: &'static str,
+    embedding: Vec<f32>,
+    index: usize,
+}
+
+#[derive(Debug, Serialize)]
+struct EmbeddingUsage {
+    prompt_tokens: usize,
+    total_tokens: usize,
+}
+
+async fn embeddings(
+    State(state): State<AppState>,
+    Json(request): Json<EmbeddingRequest>,
+) -> Result<Json<EmbeddingResponse>, ApiError> {
+    let texts = match request.input {
+        EmbeddingInput::One(text) => vec![text],
+        EmbeddingInput::Many(texts) => texts,
+    };
+    if texts.is_empty() {
+        return Err(ApiError::bad_request("input is empty"));
+    }
+    let mut n_tokens = 0;
+    for text in &texts {
+        n_tokens += state
+            .model
+            .str_to_token(text, AddBos::Always)
+            .map_err(ApiError::bad_request)?
+            .len();
+    }
+
+    let (reply, embeddings) = oneshot::channel();
+    state
+        .jobs
+        .send(Job::Embed { texts, reply })
+        .map_err(|_| ApiError::unavailable())?;
+    let embeddings = embeddings.await.map_err(|_| ApiError::unavailable())??;
+    Ok(Json(EmbeddingResponse {
+        object: "list",
+        data: embeddings
+            .into_iter()
+            .enumerate()
+            .map(|(index, embedding)| EmbeddingData {
+                object: "embedding",
+                embedding,
+                index,
+            })
+            .collect(),
+        model: state.model_name.to_string(),
+        usage: EmbeddingUsage {
+            prompt_tokens: n_tokens,
+            total_tokens: n_tokens,
+        },
+    }))
+}
+
+#[allow(clippy::unused_async)]
+async fn models(State(state): State<AppState>) -> Json<serde_json::Value> {
+    Json(json!({
+        "object": "list",
+        "data": [{
+            "id": &*state.model_name,
+            "object": "model",
+            "created": 0,
+            "owned_by": "llama-cpp-2",
+        }],
+    }))
+}
@@ -0,0 +1,216 @@
+//! A small `llama-cli`: complete a prompt or chat with a model, streaming the output.
+//!
+//! Unlike the `simple` example, which drives the decode loop by hand, this uses the high level
+//! APIs ([`LlamaContext::generate`](llama_cpp_2::context::LlamaContext::generate) and
+//! [`ChatSession`]) and so doubles as a smoke test of them on every backend:
+//!
+//! ```console
+//! cargo run -p simple-cli --release --features cuda -- model.gguf -p "The capital of France is" --temp 0
+//! cargo run -p simple-cli --release -- model.gguf --chat --system "You are a pirate."
+//! ```
+//!
+//! Timings are printed to stderr, so stdout only carries the generated text.
+
+use std::io::{BufRead, Write};
+use std::num::NonZeroU32;
+use std::ops::ControlFlow;
+use std::path::PathBuf;
+
+use anyhow::{bail, Context, Result};
+use clap::Parser;
+use llama_cpp_2::chat::ChatSession;
+use llama_cpp_2::context::params::LlamaContextParams;
+use llama_cpp_2::context::LlamaContext;
+use llama_cpp_2::generate::{GenerationParams, GenerationStats, SamplingParams, TokenEvent};
+use llama_cpp_2::grammar::cache::CompiledGrammar;
+use llama_cpp_2::llama_backend::LlamaBackend;
+use llama_cpp_2::model::params::LlamaModelParams;
+use llama_cpp_2::model::{AddBos, LlamaChatMessage, LlamaModel};
+
+#[derive(clap::Parser, Debug, Clone)]
+struct Args {
+    /// The path to the model
+    model: PathBuf,
+    /// The prompt to complete
+    #[arg(short = 'p', long, conflicts_with = "chat")]
+    prompt: Option<String>,
+    /// Read the prompt to complete from a file
+    #[arg(short = 'f', long, conflicts_with_all = ["prompt", "chat"])]
+    file: Option<PathBuf>,
+    /// Chat with the model, reading one message per line from stdin
+    #[arg(long)]
+    chat: bool,
+    /// The system message of the chat
+    #[arg(long, requires = "chat")]
+    system: Option<String>,
+    /// The maximum number of tokens to generate (per message in chat mode)
+    #[arg(short = 'n', long, default_value_t = 256)]
+    n_predict: usize,
+    /// Stop generating at this string, can be repeated
+    #[arg(long)]
+    stop: Vec<String>,
+    /// Constrain the output with the GBNF grammar in this file
+    #[arg(long)]
+    grammar_file: Option<PathBuf>,
+    /// Only apply the grammar when the token sampled without it is not allowed
+    #[arg(long, requires = "grammar_file")]
+    lazy_grammar: bool,
+    /// The temperature, 0 samples greedily
+    #[arg(long)]
+    temp: Option<f32>,
+    /// Keep only the k most likely tokens, 0 disables it
+    #[arg(long)]
+    top_k: Option<i32>,
+    /// Keep the most likely tokens up to this cumulative probability, 1 disables it
+    #[arg(long)]
+    top_p: Option<f32>,
+    /// Drop tokens less likely than this fraction of the most likely one, 0 disables it
+    #[arg(long)]
+    min_p: Option<f32>,
+    /// Penalize repeated tokens, 1 disables it
+    #[arg(long)]
+    repeat_penalty: Option<f32>,
+    /// The seed of the sampler (default: the seed of the context)
+    #[arg(short = 's', long)]
+    seed: Option<u32>,
+    /// The size of the context (default: loaded from the model)
+    #[arg(short = 'c', long)]
+    ctx_size: Option<NonZeroU32>,
+    /// Do not print llama.cpp's logs to stderr
+    #[arg(short = 'q', long)]
+    quiet: bool,
+    /// The number of threads to use (default: use all available threads)
+    #[arg(short = 't', long)]
+    threads: Option<i32>,
+    /// The number of layers to offload to the gpu
+    #[cfg(any(feature = "cuda", feature = "vulkan", feature = "metal"))]
+    #[arg(long, default_value_t = 1000)]
+    n_gpu_layers: u32,
+}
+
+impl Args {
+    /// The sampling chain the flags ask for, llama.cpp's defaults where they don't.
+    fn sampling(&self) -> SamplingParams {
+        let defaults = SamplingParams::default();
+        SamplingParams {
+            temperature: self.temp.unwrap_or(defaults.temperature),
+            top_k: self.top_k.unwrap_or(defaults.top_k),
+            top_p: self.top_p.unwrap_or(defaults.top_p),
+            min_p: self.min_p.unwrap_or(defaults.min_p),
+            repeat_penalty: self.repeat_penalty.unwrap_or(defaults.repeat_penalty),
+            seed: self.seed,
+            ..defaults
+        }
+    }
+}
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+
+    if args.quiet {
+        LlamaBackend::silence_logs();
+    }
+    let backend = LlamaBackend::init()?;
+    let model_params = LlamaModelParams::default();
+    #[cfg(any(feature = "cuda", feature = "vulkan", feature = "metal"))]
+    let model_params = model_params.with_n_gpu_layers(args.n_gpu_layers);
+    let model = LlamaModel::load_from_file(&backend, &args.model, &model_params)
+        .with_context(|| format!("unable to load {}", args.model.display()))?;
+
+    let mut ctx_params = LlamaContextParams::default().with_n_ctx(args.ctx_size);
+    if let Some(threads) = args.threads {
+        ctx_params = ctx_params
+            .with_n_threads(threads)
+            .with_n_threads_batch(threads);
+    }
+    let mut ctx = model
+        .new_context(&backend, ctx_params)
+        .context("unable to create the context")?;
+
+    let grammar = args
+        .grammar_file
+        .as_ref()
+        .map(|path| {
+            let gbnf = std::fs::read_to_string(path)
+                .with_context(|| format!("unable to read {}", path.display()))?;
+            CompiledGrammar::new(&gbnf)
+                .with_context(|| format!("invalid grammar {}", path.display()))
+        })
+        .transpose()?;
+    // a fresh set of params for every generation, so the grammar starts over
+    let params = || {
+        let params = GenerationParams::default()
+            .with_sampling(args.sampling())
+            .with_max_tokens(args.n_predict)
+            .with_stop_strings(args.stop.iter().cloned());
+        match &grammar {
+            Some(grammar) => params
+                .with_grammar(grammar.instantiate())
+                .with_lazy_grammar(args.lazy_grammar),
+            None => params,
+        }
+    };
+
+    if args.chat {
+        chat(&mut ctx, args.system.as_deref(), params)
+    } else {
+        let prompt = match (&args.prompt, &args.file) {
+            (Some(prompt), _) => prompt.clone(),
+            (None, Some(file)) => std::fs::read_to_string(file)
+                .with_context(|| format!("unable to read {}", file.display()))?,
+            (None, None) => bail!("pass a prompt with --prompt or --file, or chat with --chat"),
+        };
+        complete(&mut ctx, &prompt, &mut params())
+    }
+}
+
+/// Print the text of every token as soon as it can be streamed.
+fn print_token(event: &TokenEvent<'_>) -> ControlFlow<()> {
+    let mut stdout = std::io::stdout().lock();
+    // a closed stdout (e.g. piped into `head`) stops generating
+    match write!(stdout, "{}", event.text).and_then(|()| stdout.flush()) {
+        Ok(()) => ControlFlow::Continue(()),
+        Err(_) => ControlFlow::Break(()),
+    }
+}
+
+fn print_stats(stats: &GenerationStats) {
+    eprintln!("\n\n{stats}");
+}
+
+/// Complete `prompt` once.
+fn complete(ctx: &mut LlamaContext, prompt: &str, params: &mut GenerationParams) -> Result<()> {
+    let tokens = ctx.model.str_to_token(prompt, AddBos::Always)?;
+    print!("{prompt}");
+    let generation = ctx.generate(&tokens, params, |event| print_token(&event))?;
+    print_stats(&generation.stats);
+    Ok(())
+}
+
+/// Answer every line of stdin until it is closed.
+fn chat(
+    ctx: &mut LlamaContext,
+    system: Option<&str>,
+    params: impl Fn() -> GenerationParams,
+) -> Result<()> {
+    let mut session = ChatSession::new(0);
+    if let Some(system) = system {
+        session.push(
+            ctx.model,
+            LlamaChatMessage::new("system".to_string(), system.to_string())?,
+        )?;
+    }
+    let mut lines = std::io::stdin().lock().lines();
+    loop {
+        eprint!("> ");
+        let Some(line) = lines.next().transpose()? else {
+            return Ok(());
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+        let message = LlamaChatMessage::new("user".to_string(), line)?;
+        let answer = session.respond(ctx, message, &mut params(), |event| print_token(&event))?;
+        print_stats(&answer.stats);
+    }
+}
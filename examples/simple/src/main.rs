@@ -252,7 +252,7 @@ either reduce n_len or increase n_ctx"
             let candidates_p = LlamaTokenDataArray::from_iter(candidates, false);
 
             // sample the most likely token
-            let new_token_id = ctx.sample_token_greedy(candidates_p);
+            let new_token_id = ctx.try_sample_token_greedy(candidates_p)?;
 
             // is it an end of stream?
             if new_token_id == model.token_eos() {
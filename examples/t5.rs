@@ -0,0 +1,48 @@
+//! # T5
+//!
+//! Run an encoder-decoder model: the source text is encoded once and the decoder generates the
+//! answer from the decoder start token, which `generate` does for every model with an encoder.
+//! Convert a model such as `google/flan-t5-small` with llama.cpp's `convert_hf_to_gguf.py`, then:
+//!
+//! ```console
+//! cargo run --example t5 -- flan-t5-small.gguf "translate English to German: How old are you?"
+//! ```
+use llama_cpp_2::context::params::LlamaContextParams;
+use llama_cpp_2::generate::{GenerationParams, SamplingParams};
+use llama_cpp_2::llama_backend::LlamaBackend;
+use llama_cpp_2::model::params::LlamaModelParams;
+use llama_cpp_2::model::{AddBos, LlamaModel};
+use std::io::Write;
+use std::ops::ControlFlow;
+
+fn main() {
+    let mut args = std::env::args().skip(1);
+    let model_path = args.next().expect("Please specify model path");
+    let source = args
+        .next()
+        .unwrap_or_else(|| "translate English to German: The house is wonderful.".to_string());
+
+    let backend = LlamaBackend::init().unwrap();
+    let model = LlamaModel::load_from_file(&backend, model_path, &LlamaModelParams::default())
+        .expect("unable to load model");
+    assert!(model.has_encoder(), "the model has no encoder");
+    let mut ctx = model
+        .new_context(&backend, LlamaContextParams::default())
+        .expect("unable to create the llama_context");
+
+    let tokens = model
+        .str_to_token(&source, AddBos::Always)
+        .unwrap_or_else(|_| panic!("failed to tokenize {source}"));
+    let mut params = GenerationParams::default()
+        .with_sampling(SamplingParams::greedy())
+        .with_max_tokens(64);
+    let generation = ctx
+        .generate(&tokens, &mut params, |event| {
+            print!("{}", event.text);
+            std::io::stdout().flush().unwrap();
+            ControlFlow::Continue(())
+        })
+        .expect("generation failed");
+    println!();
+    eprintln!("{}", generation.stats);
+}
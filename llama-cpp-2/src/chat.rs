@@ -0,0 +1,401 @@
+//! A multi-turn chat that keeps its conversation in the KV cache.
+//!
+//! [`ChatSession`] renders the conversation with the model's chat template and only decodes the
+//! tokens that are not in the KV cache yet, so a new turn costs the new message rather than the
+//! whole history. When the conversation no longer fits, its [`truncation`] policy picks the
+//! messages to drop and the session evicts their cells from the KV cache. For a chat loop that
+//! manages its own KV cache, [`incremental::IncrementalTemplate`] renders the conversation
+//! message by message and returns only the new text. [`template::TemplateSelector`] picks a
+//! template for models whose embedded one is missing or unusable.
+//!
+//! # Example
+//!
+//! ```no_run
+//! # use std::ops::ControlFlow;
+//! # use llama_cpp_2::chat::ChatSession;
+//! # use llama_cpp_2::context::LlamaContext;
+//! # use llama_cpp_2::generate::GenerationParams;
+//! # use llama_cpp_2::model::LlamaChatMessage;
+//! # fn run(ctx: &mut LlamaContext) -> Result<(), Box<dyn std::error::Error>> {
+//! let mut chat = ChatSession::new(0);
+//! let system = LlamaChatMessage::new("system".into(), "You are a pirate.".into())?;
+//! chat.push(ctx.model, system)?;
+//! for question in ["Hi!", "Where is the treasure?"] {
+//!     let question = LlamaChatMessage::new("user".into(), question.into())?;
+//!     let mut params = GenerationParams::default().with_max_tokens(256);
+//!     let answer = chat.respond(ctx, question, &mut params, |_| ControlFlow::Continue(()))?;
+//!     println!("{}", answer.text);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use std::fmt::{Debug, Formatter};
+use std::ops::ControlFlow;
+
+use crate::chat::format::{PromptFormat, PromptFormatError};
+use crate::chat::template::ChatTemplate;
+use crate::chat::truncation::{ChatTurn, KeepSystemAndRecent, Truncation, TruncationPolicy};
+use crate::context::LlamaContext;
+use crate::generate::{GenerateError, Generation, GenerationParams, TokenEvent};
+use crate::llama_batch::{BatchAddError, LlamaBatch};
+#[cfg(feature = "jinja")]
+use crate::model::Special;
+use crate::model::{AddBos, LlamaChatMessage, LlamaModel};
+use crate::token::LlamaToken;
+use crate::{ApplyChatTemplateError, DecodeError, NewLlamaChatMessageError, StringToTokenError};
+use llama_cpp_sys_2::llama_pos;
+
+pub mod format;
+pub mod incremental;
+#[cfg(feature = "jinja")]
+pub mod jinja;
+pub mod template;
+pub mod truncation;
+
+/// Failed to add a message to or respond in a [`ChatSession`].
+#[derive(Debug, thiserror::Error)]
+pub enum ChatError {
+    /// The response could not be converted into a message.
+    #[error("{0}")]
+    Message(#[from] NewLlamaChatMessageError),
+    /// The chat template could not be applied.
+    #[error("{0}")]
+    Template(#[from] ApplyChatTemplateError),
+    /// The rendered conversation could not be tokenized.
+    #[error("{0}")]
+    Tokenize(#[from] StringToTokenError),
+    /// The conversation could not be tokenized with the [`PromptFormat`].
+    #[error("{0}")]
+    Format(#[from] PromptFormatError),
+    /// Failed to add a token of a replacement message to the batch.
+    #[error("{0}")]
+    BatchAdd(#[from] BatchAddError),
+    /// Failed to decode a replacement message.
+    #[error("{0}")]
+    Decode(#[from] DecodeError),
+    /// Generating the response failed.
+    #[error("{0}")]
+    Generate(#[from] GenerateError),
+    /// The conversation could not be rendered with the [`jinja::JinjaTemplate`].
+    #[cfg(feature = "jinja")]
+    #[error("{0}")]
+    Jinja(#[from] jinja::JinjaError),
+}
+
+/// A conversation on one sequence of a context, see the [module documentation](self).
+pub struct ChatSession {
+    seq_id: i32,
+    template: Option<ChatTemplate>,
+    format: Option<PromptFormat>,
+    #[cfg(feature = "jinja")]
+    jinja: Option<(jinja::JinjaTemplate, jinja::TemplateOptions)>,
+    n_reserve: usize,
+    truncation: Box<dyn TruncationPolicy>,
+    messages: Vec<LlamaChatMessage>,
+    /// The number of tokens of each message as rendered by the template.
+    n_tokens: Vec<usize>,
+    /// The tokens in the KV cache of the sequence, token `i` at position `i`.
+    cached: Vec<LlamaToken>,
+}
+
+impl Debug for ChatSession {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ChatSession")
+            .field("seq_id", &self.seq_id)
+            .field("template", &self.template)
+            .field("format", &self.format)
+            .field("n_reserve", &self.n_reserve)
+            .field("messages", &self.messages)
+            .field("n_tokens", &self.n_tokens)
+            .field("n_cached", &self.cached.len())
+            .finish_non_exhaustive()
+    }
+}
+
+impl ChatSession {
+    /// An empty conversation on `seq_id` using the model's chat template, truncated with
+    /// [`KeepSystemAndRecent`] and reserving 512 tokens of the context for each response.
+    #[must_use]
+    pub fn new(seq_id: i32) -> Self {
+        Self {
+            seq_id,
+            template: None,
+            format: None,
+            #[cfg(feature = "jinja")]
+            jinja: None,
+            n_reserve: 512,
+            truncation: Box::new(KeepSystemAndRecent::default()),
+            messages: Vec::new(),
+            n_tokens: Vec::new(),
+            cached: Vec::new(),
+        }
+    }
+
+    /// Use the chat template `template` (a template name or Jinja source, see
+    /// [`LlamaModel::apply_chat_template`]) instead of the model's.
+    #[must_use]
+    pub fn with_template(self, template: impl Into<String>) -> Self {
+        self.with_chat_template(ChatTemplate::forced(template))
+    }
+
+    /// Use `template`, usually picked by a [`template::TemplateSelector`], instead of the model's.
+    #[must_use]
+    pub fn with_chat_template(mut self, template: ChatTemplate) -> Self {
+        self.template = Some(template);
+        self
+    }
+
+    /// The chat template set with [`Self::with_template`] or [`Self::with_chat_template`] and
+    /// where it came from. `None` if the session uses the model's template as llama.cpp picks it.
+    #[must_use]
+    pub fn template(&self) -> Option<&ChatTemplate> {
+        self.template.as_ref()
+    }
+
+    /// Tokenize the conversation with `format` instead of a chat template, for models without a
+    /// usable one.
+    #[must_use]
+    pub fn with_format(mut self, format: PromptFormat) -> Self {
+        self.format = Some(format);
+        self
+    }
+
+    /// Render the conversation with `template` instead of llama.cpp's built-in templates, with the
+    /// tools and thinking switch of `options`. `add_generation_prompt` of `options` is ignored,
+    /// the session sets it itself.
+    #[cfg(feature = "jinja")]
+    #[must_use]
+    pub fn with_jinja(
+        mut self,
+        template: jinja::JinjaTemplate,
+        options: jinja::TemplateOptions,
+    ) -> Self {
+        self.jinja = Some((template, options));
+        self
+    }
+
+    /// Truncate the conversation with `policy`.
+    #[must_use]
+    pub fn with_truncation(mut self, policy: impl TruncationPolicy + 'static) -> Self {
+        self.truncation = Box::new(policy);
+        self
+    }
+
+    /// Keep `n_reserve` tokens of the context free for the response.
+    #[must_use]
+    pub fn with_reserve(mut self, n_reserve: usize) -> Self {
+        self.n_reserve = n_reserve;
+        self
+    }
+
+    /// The messages of the conversation.
+    #[must_use]
+    pub fn messages(&self) -> &[LlamaChatMessage] {
+        &self.messages
+    }
+
+    /// The number of tokens of the conversation that are in the KV cache.
+    #[must_use]
+    pub fn n_cached(&self) -> usize {
+        self.cached.len()
+    }
+
+    /// Forget the conversation and clear its sequence.
+    pub fn reset(&mut self, ctx: &mut LlamaContext) {
+        self.messages.clear();
+        self.n_tokens.clear();
+        self.cached.clear();
+        ctx.clear_kv_cache_seq(self.seq_id, None, None);
+    }
+
+    /// The first `n_messages` messages rendered and tokenized.
+    fn tokenize(
+        &self,
+        model: &LlamaModel,
+        n_messages: usize,
+        add_ass: bool,
+    ) -> Result<Vec<LlamaToken>, ChatError> {
+        if n_messages == 0 && !add_ass {
+            return Ok(Vec::new());
+        }
+        if let Some(format) = self.format {
+            return Ok(format.tokenize(model, &self.messages[..n_messages], add_ass)?);
+        }
+        #[cfg(feature = "jinja")]
+        if let Some((template, options)) = &self.jinja {
+            let messages: Vec<_> = self.messages[..n_messages]
+                .iter()
+                .map(jinja::JinjaMessage::from)
+                .collect();
+            let options = jinja::TemplateOptions {
+                add_generation_prompt: add_ass,
+                ..options.clone()
+            };
+            let text = template.render(&messages, &options)?;
+            return Ok(model.str_to_token_with_special(&text, AddBos::Never, Special::Tokenize)?);
+        }
+        let text = model.apply_chat_template(
+            self.template
+                .as_ref()
+                .map(|template| template.template.clone()),
+            self.messages[..n_messages].to_vec(),
+            add_ass,
+        )?;
+        Ok(model.str_to_token(&text, AddBos::Always)?)
+    }
+
+    /// The number of tokens of the message at `index`.
+    fn count_tokens(&self, model: &LlamaModel, index: usize) -> Result<usize, ChatError> {
+        let before = self.tokenize(model, index, false)?.len();
+        let after = self.tokenize(model, index + 1, false)?.len();
+        Ok(after.saturating_sub(before))
+    }
+
+    /// Append `message` without responding to it, e.g. a system message or an earlier
+    /// conversation. It is decoded with the next response.
+    ///
+    /// # Errors
+    ///
+    /// If the template cannot be applied or the result cannot be tokenized.
+    pub fn push(&mut self, model: &LlamaModel, message: LlamaChatMessage) -> Result<(), ChatError> {
+        self.messages.push(message);
+        match self.count_tokens(model, self.messages.len() - 1) {
+            Ok(n_tokens) => {
+                self.n_tokens.push(n_tokens);
+                Ok(())
+            }
+            Err(err) => {
+                self.messages.pop();
+                Err(err)
+            }
+        }
+    }
+
+    /// Append `message`, truncate the conversation if needed and generate the response with
+    /// [`LlamaContext::generate`], which is appended as an `assistant` message.
+    ///
+    /// Only the tokens after the longest prefix that is still in the KV cache are decoded.
+    /// [`GenerationParams::seq_id`] and [`GenerationParams::n_cached`] are set by the session and
+    /// context shifting is disabled, as it would move positions the session relies on.
+    ///
+    /// # Errors
+    ///
+    /// See [`ChatError`].
+    ///
+    /// # Panics
+    ///
+    /// - if `n_ctx` does not fit into a usize
+    pub fn respond(
+        &mut self,
+        ctx: &mut LlamaContext,
+        message: LlamaChatMessage,
+        params: &mut GenerationParams,
+        on_token: impl FnMut(TokenEvent<'_>) -> ControlFlow<()>,
+    ) -> Result<Generation, ChatError> {
+        self.push(ctx.model, message)?;
+        let n_ctx = usize::try_from(ctx.n_ctx()).expect("n_ctx fits into a usize");
+        self.truncate(ctx, n_ctx.saturating_sub(self.n_reserve))?;
+
+        let prompt = self.tokenize(ctx.model, self.messages.len(), true)?;
+        let n_cached = self
+            .cached
+            .iter()
+            .zip(&prompt)
+            .take_while(|(cached, token)| cached == token)
+            .count();
+
+        params.seq_id = self.seq_id;
+        params.n_cached = n_cached;
+        let context_shift = std::mem::replace(&mut params.context_shift, false);
+        let generation = ctx.generate(&prompt, params, on_token);
+        params.context_shift = context_shift;
+        let generation = generation?;
+
+        let n_past = usize::try_from(ctx.kv_cache_seq_pos_max(self.seq_id) + 1).unwrap_or(0);
+        self.cached = prompt;
+        self.cached.extend_from_slice(&generation.tokens);
+        self.cached.truncate(n_past);
+
+        let answer = LlamaChatMessage::new("assistant".to_string(), generation.text.clone())?;
+        self.push(ctx.model, answer)?;
+        Ok(generation)
+    }
+
+    /// Ask the truncation policy to fit the conversation into `budget` tokens and apply its
+    /// decision to the messages and the KV cache.
+    fn truncate(&mut self, ctx: &mut LlamaContext, budget: usize) -> Result<(), ChatError> {
+        let turns: Vec<ChatTurn<'_>> = self
+            .messages
+            .iter()
+            .zip(&self.n_tokens)
+            .map(|(message, &n_tokens)| ChatTurn { message, n_tokens })
+            .collect();
+        let (range, replacement) = match self.truncation.truncate(&turns, budget) {
+            Truncation::Keep => return Ok(()),
+            Truncation::Remove(range) => (range, None),
+            Truncation::Replace(range, message) => (range, Some(message)),
+        };
+        let range = range.start..range.end.min(self.messages.len() - 1);
+        if range.is_empty() {
+            return Ok(());
+        }
+        tracing::debug!(seq_id = self.seq_id, ?range, "truncating chat");
+
+        let start = self.n_tokens[..range.start].iter().sum::<usize>();
+        let end = start + self.n_tokens[range.clone()].iter().sum::<usize>();
+        self.messages.drain(range.clone());
+        self.n_tokens.drain(range.clone());
+        let replacement = if let Some(message) = replacement {
+            self.messages.insert(range.start, message);
+            let n_tokens = self.count_tokens(ctx.model, range.start)?;
+            self.n_tokens.insert(range.start, n_tokens);
+            let tokens = self.tokenize(ctx.model, range.start + 1, false)?;
+            tokens[tokens.len() - n_tokens..].to_vec()
+        } else {
+            Vec::new()
+        };
+        self.splice_cache(ctx, start, end, &replacement)
+    }
+
+    /// Replace the tokens `start..end` in the KV cache with `replacement`, shifting the positions
+    /// of the tokens after them.
+    fn splice_cache(
+        &mut self,
+        ctx: &mut LlamaContext,
+        start: usize,
+        end: usize,
+        replacement: &[LlamaToken],
+    ) -> Result<(), ChatError> {
+        let n_cached = self.cached.len();
+        if start >= n_cached {
+            return Ok(());
+        }
+        let end = end.min(n_cached);
+        let pos = |i: usize| llama_pos::try_from(i).expect("positions fit into a llama_pos");
+        let delta = pos(replacement.len()) - pos(end - start);
+        unsafe {
+            let context = ctx.context.as_ptr();
+            llama_cpp_sys_2::llama_kv_cache_seq_rm(context, self.seq_id, pos(start), pos(end));
+            llama_cpp_sys_2::llama_kv_cache_seq_add(context, self.seq_id, pos(end), -1, delta);
+        }
+        ctx.forget_cached_tokens(self.seq_id, pos(start));
+        self.cached.splice(start..end, replacement.iter().copied());
+
+        let n_batch = usize::try_from(ctx.n_batch()).expect("n_batch fits into a usize");
+        let mut batch = LlamaBatch::new(n_batch, 1);
+        for (i, chunk) in replacement.chunks(n_batch).enumerate() {
+            batch.clear();
+            for (j, token) in chunk.iter().enumerate() {
+                batch.add(*token, pos(start + i * n_batch + j), &[self.seq_id], false)?;
+            }
+            if let Err(err) = ctx.decode(&mut batch) {
+                // the replacement is incomplete, drop everything from it on
+                ctx.clear_kv_cache_seq(self.seq_id, None, None);
+                self.cached.clear();
+                return Err(err.into());
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,356 @@
+//! Prompt formats implemented in Rust, for models whose GGUF has no usable chat template.
+//!
+//! Unlike rendering a template to a string and tokenizing it, [`PromptFormat::tokenize`] builds
+//! the token sequence directly: the markers of the format become the model's special tokens and
+//! the messages are tokenized as plain text, so a message containing `<|im_end|>` cannot end its
+//! turn early. Otherwise the tokens are the same as those of the [rendered](PromptFormat::render)
+//! text: the text between two markers is tokenized in one piece, so sentencepiece vocabularies
+//! only add their leading space where llama.cpp adds it after a special token as well.
+//!
+//! A [`FormatTokenizer`] looks up the markers of the format in the vocabulary once instead of on
+//! every call, for apps that tokenize many conversations with the same model.
+//!
+//! ```no_run
+//! # use llama_cpp_2::chat::format::PromptFormat;
+//! # use llama_cpp_2::model::{LlamaChatMessage, LlamaModel};
+//! # fn run(model: &LlamaModel) -> Result<(), Box<dyn std::error::Error>> {
+//! let messages = [
+//!     LlamaChatMessage::new("system".into(), "You are terse.".into())?,
+//!     LlamaChatMessage::new("user".into(), "What is 2 + 2?".into())?,
+//! ];
+//! let tokens = PromptFormat::ChatMl.tokenize(model, &messages, true)?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::collections::HashMap;
+use std::fmt::Write;
+
+use crate::model::{AddBos, LlamaChatMessage, LlamaModel, Special};
+use crate::token::LlamaToken;
+use crate::StringToTokenError;
+
+/// Failed to tokenize a conversation with a [`PromptFormat`].
+#[derive(Debug, thiserror::Error)]
+pub enum PromptFormatError {
+    /// The model has no single token for a marker the format requires.
+    #[error("the model has no {0} token")]
+    MissingSpecialToken(String),
+    /// A message could not be tokenized.
+    #[error("{0}")]
+    Tokenize(#[from] StringToTokenError),
+}
+
+/// A chat prompt format.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PromptFormat {
+    /// `<|im_start|>role\ncontent<|im_end|>\n`, used by Qwen, Yi, Hermes and many fine-tunes.
+    ChatMl,
+    /// Llama 2 chat: `<s>[INST] <<SYS>>\nsystem\n<</SYS>>\n\nuser [/INST] assistant </s>`.
+    Llama2,
+    /// Llama 3: `<|start_header_id|>role<|end_header_id|>\n\ncontent<|eot_id|>`.
+    Llama3,
+    /// Mistral instruct: `<s>[INST] user [/INST] assistant</s>`, the system prompt is prepended to
+    /// the first user message. `[INST]` and `[/INST]` are special tokens if the vocabulary has
+    /// them (v3 and later) and plain text otherwise.
+    Mistral,
+    /// Gemma: `<start_of_turn>user\ncontent<end_of_turn>\n` with `model` as the assistant role,
+    /// the system prompt is prepended to the first user message.
+    Gemma,
+    /// Phi 3: `<|role|>\ncontent<|end|>\n`.
+    Phi3,
+}
+
+/// A part of a formatted conversation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Piece {
+    /// The model's beginning of sequence token.
+    Bos,
+    /// The model's end of sequence token.
+    Eos,
+    /// A special token, given by its text.
+    Special(String),
+    /// A special token if the vocabulary has it, plain text otherwise.
+    SpecialOrText(&'static str),
+    /// Plain text.
+    Text(String),
+}
+
+/// The system prompt of `messages` (if the first message is one) and the other messages.
+fn split_system(messages: &[LlamaChatMessage]) -> (Option<&str>, &[LlamaChatMessage]) {
+    match messages.split_first() {
+        Some((first, rest)) if first.role() == "system" => (Some(first.content()), rest),
+        _ => (None, messages),
+    }
+}
+
+impl PromptFormat {
+    /// The pieces of the conversation.
+    fn pieces(self, messages: &[LlamaChatMessage], add_assistant: bool) -> Vec<Piece> {
+        use Piece::{Bos, Eos, Special, SpecialOrText, Text};
+        let special = |text: &str| Special(text.to_string());
+        let mut pieces = Vec::new();
+        match self {
+            Self::ChatMl => {
+                for message in messages {
+                    pieces.push(special("<|im_start|>"));
+                    pieces.push(Text(format!("{}\n{}", message.role(), message.content())));
+                    pieces.push(special("<|im_end|>"));
+                    pieces.push(Text("\n".to_string()));
+                }
+                if add_assistant {
+                    pieces.push(special("<|im_start|>"));
+                    pieces.push(Text("assistant\n".to_string()));
+                }
+            }
+            Self::Llama2 => {
+                let (mut system, messages) = split_system(messages);
+                for message in messages {
+                    if message.role() == "assistant" {
+                        pieces.push(Text(format!(" {} ", message.content().trim())));
+                        pieces.push(Eos);
+                        continue;
+                    }
+                    let mut text = "[INST] ".to_string();
+                    if let Some(system) = system.take() {
+                        let _ = write!(text, "<<SYS>>\n{system}\n<</SYS>>\n\n");
+                    }
+                    let _ = write!(text, "{} [/INST]", message.content().trim());
+                    pieces.push(Bos);
+                    pieces.push(Text(text));
+                }
+            }
+            Self::Llama3 => {
+                pieces.push(Bos);
+                let header = |pieces: &mut Vec<Piece>, role: &str| {
+                    pieces.push(special("<|start_header_id|>"));
+                    pieces.push(Text(role.to_string()));
+                    pieces.push(special("<|end_header_id|>"));
+                };
+                for message in messages {
+                    header(&mut pieces, message.role());
+                    pieces.push(Text(format!("\n\n{}", message.content().trim())));
+                    pieces.push(special("<|eot_id|>"));
+                }
+                if add_assistant {
+                    header(&mut pieces, "assistant");
+                    pieces.push(Text("\n\n".to_string()));
+                }
+            }
+            Self::Mistral => {
+                let (mut system, messages) = split_system(messages);
+                pieces.push(Bos);
+                for message in messages {
+                    if message.role() == "assistant" {
+                        pieces.push(Text(format!(" {}", message.content().trim())));
+                        pieces.push(Eos);
+                        continue;
+                    }
+                    let content = match system.take() {
+                        Some(system) => format!("{system}\n\n{}", message.content().trim()),
+                        None => message.content().trim().to_string(),
+                    };
+                    pieces.push(SpecialOrText("[INST]"));
+                    pieces.push(Text(format!(" {content} ")));
+                    pieces.push(SpecialOrText("[/INST]"));
+                }
+            }
+            Self::Gemma => {
+                let (mut system, messages) = split_system(messages);
+                pieces.push(Bos);
+                for message in messages {
+                    let role = match message.role() {
+                        "assistant" => "model",
+                        role => role,
+                    };
+                    let content = match system.take() {
+                        Some(system) if role == "user" => {
+                            format!("{system}\n\n{}", message.content().trim())
+                        }
+                        _ => message.content().trim().to_string(),
+                    };
+                    pieces.push(special("<start_of_turn>"));
+                    pieces.push(Text(format!("{role}\n{content}")));
+                    pieces.push(special("<end_of_turn>"));
+                    pieces.push(Text("\n".to_string()));
+                }
+                if add_assistant {
+                    pieces.push(special("<start_of_turn>"));
+                    pieces.push(Text("model\n".to_string()));
+                }
+            }
+            Self::Phi3 => {
+                for message in messages {
+                    pieces.push(Special(format!("<|{}|>", message.role())));
+                    pieces.push(Text(format!("\n{}", message.content())));
+                    pieces.push(special("<|end|>"));
+                    pieces.push(Text("\n".to_string()));
+                }
+                if add_assistant {
+                    pieces.push(special("<|assistant|>"));
+                    pieces.push(Text("\n".to_string()));
+                }
+            }
+        }
+        pieces
+    }
+
+    /// Render the conversation as text, e.g. to log the prompt. Tokenizing the text with special
+    /// tokens gives the same tokens as [`PromptFormat::tokenize`], unless a message contains the
+    /// text of a marker or the model strips whitespace next to its special tokens (Phi 3).
+    ///
+    /// ```
+    /// # use llama_cpp_2::chat::format::PromptFormat;
+    /// # use llama_cpp_2::model::LlamaChatMessage;
+    /// let messages = [LlamaChatMessage::new("user".into(), "Hi".into())?];
+    /// assert_eq!(
+    ///     PromptFormat::ChatMl.render(&messages, true),
+    ///     "<|im_start|>user\nHi<|im_end|>\n<|im_start|>assistant\n"
+    /// );
+    /// assert_eq!(
+    ///     PromptFormat::Gemma.render(&messages, true),
+    ///     "<bos><start_of_turn>user\nHi<end_of_turn>\n<start_of_turn>model\n"
+    /// );
+    /// # Ok::<(), llama_cpp_2::NewLlamaChatMessageError>(())
+    /// ```
+    #[must_use]
+    pub fn render(self, messages: &[LlamaChatMessage], add_assistant: bool) -> String {
+        let (bos, eos) = match self {
+            Self::Llama3 => ("<|begin_of_text|>", "<|end_of_text|>"),
+            Self::Gemma => ("<bos>", "<eos>"),
+            _ => ("<s>", "</s>"),
+        };
+        self.pieces(messages, add_assistant)
+            .into_iter()
+            .map(|piece| match piece {
+                Piece::Bos => bos.to_string(),
+                Piece::Eos => eos.to_string(),
+                Piece::Special(text) | Piece::Text(text) => text,
+                Piece::SpecialOrText(text) => text.to_string(),
+            })
+            .collect()
+    }
+
+    /// Tokenize the conversation, ending with the start of an assistant turn if `add_assistant`.
+    /// Use a [`FormatTokenizer`] to tokenize many conversations.
+    ///
+    /// # Errors
+    ///
+    /// - if the model has no token for a marker of the format, i.e. the format does not fit the
+    ///   model
+    /// - if a message contains a null byte
+    pub fn tokenize(
+        self,
+        model: &LlamaModel,
+        messages: &[LlamaChatMessage],
+        add_assistant: bool,
+    ) -> Result<Vec<LlamaToken>, PromptFormatError> {
+        self.tokenizer(model).tokenize(messages, add_assistant)
+    }
+
+    /// A tokenizer for this format and `model` that remembers the special tokens it looked up.
+    #[must_use]
+    pub fn tokenizer(self, model: &LlamaModel) -> FormatTokenizer<'_> {
+        FormatTokenizer {
+            format: self,
+            model,
+            markers: HashMap::new(),
+        }
+    }
+}
+
+/// Tokenizes conversations in a [`PromptFormat`] for one model, see
+/// [`PromptFormat::tokenizer`]. The special tokens of the markers are looked up on first use and
+/// remembered.
+#[derive(Debug)]
+pub struct FormatTokenizer<'a> {
+    format: PromptFormat,
+    model: &'a LlamaModel,
+    /// The special token of every marker looked up so far, `None` if the vocabulary has none.
+    markers: HashMap<String, Option<LlamaToken>>,
+}
+
+impl FormatTokenizer<'_> {
+    /// The format conversations are tokenized in.
+    #[must_use]
+    pub fn format(&self) -> PromptFormat {
+        self.format
+    }
+
+    /// Tokenize the conversation, see [`PromptFormat::tokenize`].
+    ///
+    /// # Errors
+    ///
+    /// - if the model has no token for a marker of the format, i.e. the format does not fit the
+    ///   model
+    /// - if a message contains a null byte
+    pub fn tokenize(
+        &mut self,
+        messages: &[LlamaChatMessage],
+        add_assistant: bool,
+    ) -> Result<Vec<LlamaToken>, PromptFormatError> {
+        let model = self.model;
+        let mut tokens = Vec::new();
+        // the text since the last token, tokenized at once like llama.cpp tokenizes the text
+        // between two special tokens
+        let mut text = String::new();
+        let flush = |tokens: &mut Vec<LlamaToken>, text: &mut String| {
+            if !text.is_empty() {
+                tokens.extend(model.str_to_token_with_special(
+                    text,
+                    AddBos::Never,
+                    Special::Plaintext,
+                )?);
+                text.clear();
+            }
+            Ok::<_, PromptFormatError>(())
+        };
+        for piece in self.format.pieces(messages, add_assistant) {
+            let token = match piece {
+                Piece::Bos => model.token_bos(),
+                Piece::Eos => model.token_eos(),
+                Piece::Special(marker) => self
+                    .special_token(&marker)?
+                    .ok_or(PromptFormatError::MissingSpecialToken(marker))?,
+                Piece::SpecialOrText(marker) => match self.special_token(marker)? {
+                    Some(token) => token,
+                    None => {
+                        text.push_str(marker);
+                        continue;
+                    }
+                },
+                Piece::Text(piece) => {
+                    text.push_str(&piece);
+                    continue;
+                }
+            };
+            flush(&mut tokens, &mut text)?;
+            tokens.push(token);
+        }
+        flush(&mut tokens, &mut text)?;
+        Ok(tokens)
+    }
+
+    /// The single special token with the text `marker`, if the vocabulary has one.
+    fn special_token(&mut self, marker: &str) -> Result<Option<LlamaToken>, PromptFormatError> {
+        if let Some(&token) = self.markers.get(marker) {
+            return Ok(token);
+        }
+        let tokens =
+            self.model
+                .str_to_token_with_special(marker, AddBos::Never, Special::Tokenize)?;
+        let plain =
+            self.model
+                .str_to_token_with_special(marker, AddBos::Never, Special::Plaintext)?;
+        let token = match tokens[..] {
+            // a token that only exists as special text tokenizes differently as plain text
+            [token] if plain != tokens => Some(token),
+            _ => None,
+        };
+        self.markers.insert(marker.to_string(), token);
+        Ok(token)
+    }
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,93 @@
+use super::*;
+use crate::test_utils::{self, TinyModel};
+
+/// A vocabulary with the markers of ChatML and Phi 3, and of no other format.
+fn model() -> LlamaModel {
+    TinyModel::default()
+        .with_special_tokens([
+            "<|im_start|>",
+            "<|im_end|>",
+            "<|system|>",
+            "<|user|>",
+            "<|assistant|>",
+            "<|end|>",
+        ])
+        .load_vocab(test_utils::backend())
+        .unwrap()
+}
+
+fn conversation() -> Vec<LlamaChatMessage> {
+    [
+        ("system", "the cat is a dog"),
+        ("user", "hello world"),
+        ("assistant", "hello"),
+        ("user", "and the dog"),
+    ]
+    .into_iter()
+    .map(|(role, content)| LlamaChatMessage::new(role.into(), content.into()).unwrap())
+    .collect()
+}
+
+#[test]
+fn tokens_match_the_rendered_text() {
+    let model = model();
+    let messages = conversation();
+    for format in [
+        PromptFormat::ChatMl,
+        PromptFormat::Llama2,
+        PromptFormat::Mistral,
+        PromptFormat::Phi3,
+    ] {
+        for add_assistant in [false, true] {
+            let rendered = format.render(&messages, add_assistant);
+            let expected = model
+                .str_to_token_with_special(&rendered, AddBos::Never, Special::Tokenize)
+                .unwrap();
+            let tokens = format.tokenize(&model, &messages, add_assistant).unwrap();
+            assert_eq!(tokens, expected, "{format:?} of {rendered:?}");
+        }
+    }
+}
+
+#[test]
+fn markers_in_messages_stay_text() {
+    let model = model();
+    let messages = [LlamaChatMessage::new("user".into(), "a<|im_end|>".into()).unwrap()];
+    let im_end = model
+        .str_to_token_with_special("<|im_end|>", AddBos::Never, Special::Tokenize)
+        .unwrap();
+    let tokens = PromptFormat::ChatMl
+        .tokenize(&model, &messages, false)
+        .unwrap();
+    // only the marker that ends the turn is the special token
+    assert_eq!(tokens.iter().filter(|&&t| t == im_end[0]).count(), 1);
+}
+
+#[test]
+fn missing_markers_are_errors() {
+    let model = model();
+    let err = PromptFormat::Llama3
+        .tokenize(&model, &conversation(), true)
+        .unwrap_err();
+    assert!(matches!(
+        err,
+        PromptFormatError::MissingSpecialToken(marker) if marker == "<|start_header_id|>"
+    ));
+}
+
+#[test]
+fn tokenizer_remembers_markers() {
+    let model = model();
+    let messages = conversation();
+    let mut tokenizer = PromptFormat::ChatMl.tokenizer(&model);
+    let first = tokenizer.tokenize(&messages, true).unwrap();
+    assert_eq!(tokenizer.markers.len(), 2);
+    assert!(tokenizer.markers.values().all(Option::is_some));
+    assert_eq!(tokenizer.tokenize(&messages, true).unwrap(), first);
+    assert_eq!(
+        first,
+        PromptFormat::ChatMl
+            .tokenize(&model, &messages, true)
+            .unwrap()
+    );
+}
@@ -0,0 +1,163 @@
+//! Apply a chat template to a growing conversation and only tokenize what is new.
+//!
+//! Chat templates render the whole conversation at once, so a chat loop that re-tokenizes the
+//! result every turn spends time proportional to the history. [`IncrementalTemplate`] remembers
+//! what it rendered before and returns the difference, the same way `llama-cli` formats each new
+//! message: the conversation is rendered with and without the new message and only the text
+//! after the previous rendering is tokenized and decoded.
+//!
+//! Some templates render earlier messages differently once more follow, e.g. templates of
+//! reasoning models that drop the thinking of earlier assistant turns. The new rendering then no
+//! longer starts with the previous one and [`TemplateDelta::n_kept`] says how much of the previous
+//! rendering is still valid, so the caller can rewind its KV cache to there.
+//!
+//! ```no_run
+//! # use llama_cpp_2::chat::incremental::IncrementalTemplate;
+//! # use llama_cpp_2::model::{LlamaChatMessage, LlamaModel};
+//! # fn run(model: &LlamaModel) -> Result<(), Box<dyn std::error::Error>> {
+//! let mut chat = IncrementalTemplate::new();
+//! let question = LlamaChatMessage::new("user".into(), "Hi!".into())?;
+//! let delta = chat.push(model, question, true)?;
+//! assert!(delta.is_append());
+//! let tokens = delta.tokenize(model)?;
+//! // ... decode `tokens`, generate the answer and push it with `add_ass` false
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::model::{AddBos, LlamaChatMessage, LlamaModel};
+use crate::token::LlamaToken;
+use crate::{ApplyChatTemplateError, StringToTokenError};
+
+/// The change of the rendered conversation caused by [`IncrementalTemplate::push`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TemplateDelta {
+    /// The number of bytes of the previous rendering that are unchanged. Equal to its length
+    /// unless the template re-rendered earlier messages differently.
+    pub n_kept: usize,
+    /// The length of the previous rendering.
+    pub n_previous: usize,
+    /// The text that follows the kept bytes.
+    pub text: String,
+}
+
+impl TemplateDelta {
+    /// The difference between the `previous` rendering and the `current` one.
+    #[must_use]
+    pub fn between(previous: &str, current: &str) -> Self {
+        let n_kept = previous
+            .char_indices()
+            .zip(current.chars())
+            .find(|((_, a), b)| a != b)
+            .map_or(previous.len().min(current.len()), |((i, _), _)| i);
+        Self {
+            n_kept,
+            n_previous: previous.len(),
+            text: current[n_kept..].to_string(),
+        }
+    }
+
+    /// Whether the previous rendering is unchanged and the text only has to be appended.
+    #[must_use]
+    pub fn is_append(&self) -> bool {
+        self.n_kept == self.n_previous
+    }
+
+    /// Tokenize the text, with a bos token if it starts the conversation.
+    ///
+    /// # Errors
+    ///
+    /// If the text cannot be tokenized.
+    pub fn tokenize(&self, model: &LlamaModel) -> Result<Vec<LlamaToken>, StringToTokenError> {
+        let add_bos = if self.n_kept == 0 {
+            AddBos::Always
+        } else {
+            AddBos::Never
+        };
+        model.str_to_token(&self.text, add_bos)
+    }
+}
+
+/// A conversation rendered with a chat template one message at a time, see the
+/// [module docs](self).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IncrementalTemplate {
+    template: Option<String>,
+    messages: Vec<LlamaChatMessage>,
+    /// The rendering of the last push, with the generation prompt if it asked for one.
+    rendered: String,
+}
+
+impl IncrementalTemplate {
+    /// An empty conversation using the model's chat template.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Use the chat template `template` (a template name or Jinja source, see
+    /// [`LlamaModel::apply_chat_template`]) instead of the model's.
+    #[must_use]
+    pub fn with_template(mut self, template: impl Into<String>) -> Self {
+        self.template = Some(template.into());
+        self
+    }
+
+    /// The messages of the conversation.
+    #[must_use]
+    pub fn messages(&self) -> &[LlamaChatMessage] {
+        &self.messages
+    }
+
+    /// The conversation as rendered by the last [`IncrementalTemplate::push`].
+    #[must_use]
+    pub fn rendered(&self) -> &str {
+        &self.rendered
+    }
+
+    /// Append `message` and return the change of the rendered conversation. With `add_ass` the
+    /// rendering ends with the start of an assistant turn, which the next push, typically of the
+    /// model's answer, continues. The delta of an answer contains its text, which is usually in
+    /// the KV cache already, and the end of turn after it.
+    ///
+    /// # Errors
+    ///
+    /// If the template cannot be applied. The message is not appended then.
+    pub fn push(
+        &mut self,
+        model: &LlamaModel,
+        message: LlamaChatMessage,
+        add_ass: bool,
+    ) -> Result<TemplateDelta, ApplyChatTemplateError> {
+        self.messages.push(message);
+        match self.render(model, add_ass) {
+            Ok(delta) => Ok(delta),
+            Err(err) => {
+                self.messages.pop();
+                Err(err)
+            }
+        }
+    }
+
+    fn render(
+        &mut self,
+        model: &LlamaModel,
+        add_ass: bool,
+    ) -> Result<TemplateDelta, ApplyChatTemplateError> {
+        let rendered =
+            model.apply_chat_template(self.template.clone(), self.messages.clone(), add_ass)?;
+        // an answer is rendered after the generation prompt it answers, so diff against that
+        let delta = TemplateDelta::between(&self.rendered, &rendered);
+        self.rendered = rendered;
+        Ok(delta)
+    }
+
+    /// Forget the conversation.
+    pub fn reset(&mut self) {
+        self.messages.clear();
+        self.rendered.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,35 @@
+use super::*;
+
+#[test]
+fn appends_to_an_unchanged_rendering() {
+    let previous = "<|im_start|>user\nHi<|im_end|>\n<|im_start|>assistant\n";
+    let current = format!("{previous}Hello!<|im_end|>\n");
+    let delta = TemplateDelta::between(previous, &current);
+    assert!(delta.is_append());
+    assert_eq!(delta.n_kept, previous.len());
+    assert_eq!(delta.text, "Hello!<|im_end|>\n");
+}
+
+#[test]
+fn keeps_the_common_prefix_of_a_changed_rendering() {
+    let previous = "<user>Hi</user><assistant><think>hmm</think>Hello!</assistant>";
+    let current = "<user>Hi</user><assistant>Hello!</assistant><user>Bye</user>";
+    let delta = TemplateDelta::between(previous, current);
+    assert!(!delta.is_append());
+    assert_eq!(delta.n_kept, "<user>Hi</user><assistant>".len());
+    assert_eq!(delta.text, "Hello!</assistant><user>Bye</user>");
+}
+
+#[test]
+fn splits_on_char_boundaries() {
+    let delta = TemplateDelta::between("日本", "日本語");
+    assert!(delta.is_append());
+    assert_eq!(delta.text, "語");
+
+    let delta = TemplateDelta::between("日本", "日他");
+    assert_eq!(delta.n_kept, "日".len());
+    assert_eq!(delta.text, "他");
+
+    let delta = TemplateDelta::between("", "日");
+    assert_eq!((delta.n_kept, delta.text.as_str()), (0, "日"));
+}
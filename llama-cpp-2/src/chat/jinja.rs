@@ -0,0 +1,326 @@
+//! Jinja chat templates rendered in Rust with [minijinja](https://docs.rs/minijinja).
+//!
+//! `llama_chat_apply_template` only recognizes a fixed list of templates by their markers and
+//! cannot render tools, reasoning content or the loops and conditionals of recent templates
+//! (Qwen 3, DeepSeek-R1, Llama 3.1 with tools). [`JinjaTemplate`] runs the template itself, with
+//! the Python string methods and helpers (`raise_exception`, `strftime_now`) the Hugging Face
+//! templates rely on.
+//!
+//! ```no_run
+//! # use llama_cpp_2::chat::jinja::{JinjaMessage, JinjaTemplate, TemplateOptions};
+//! # use llama_cpp_2::model::{AddBos, LlamaModel, Special};
+//! # fn run(model: &LlamaModel) -> Result<(), Box<dyn std::error::Error>> {
+//! let template = JinjaTemplate::from_model(model)?;
+//! let messages = [JinjaMessage::new("user", "What is the weather in Paris?")];
+//! let options = TemplateOptions {
+//!     add_generation_prompt: true,
+//!     tools: vec![serde_json::json!({
+//!         "type": "function",
+//!         "function": { "name": "get_weather", "parameters": { "type": "object" } }
+//!     })],
+//!     ..TemplateOptions::default()
+//! };
+//! let prompt = template.render(&messages, &options)?;
+//! let tokens = model.str_to_token_with_special(&prompt, AddBos::Never, Special::Tokenize)?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::fmt::{Debug, Formatter};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use minijinja::{context, Environment, ErrorKind};
+use serde::Serialize;
+
+use crate::model::{LlamaChatMessage, LlamaModel, Special};
+use crate::{ChatTemplateError, TokenToStringError};
+
+/// The name the template is stored under in its environment.
+const TEMPLATE_NAME: &str = "chat_template";
+
+/// Failed to load or render a [`JinjaTemplate`].
+#[derive(Debug, thiserror::Error)]
+pub enum JinjaError {
+    /// The template has a syntax error or failed while rendering, including errors raised by the
+    /// template itself with `raise_exception`.
+    #[error("{0}")]
+    Template(#[from] minijinja::Error),
+    /// The model has no chat template.
+    #[error("{0}")]
+    MissingTemplate(#[from] ChatTemplateError),
+    /// The text of the bos or eos token could not be read.
+    #[error("{0}")]
+    SpecialToken(#[from] TokenToStringError),
+}
+
+/// A function call of an assistant message.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct JinjaToolCall {
+    /// The id of the call, referenced by the tool message with its result.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    /// Always `function`.
+    #[serde(rename = "type")]
+    pub kind: String,
+    /// The function and its arguments.
+    pub function: JinjaFunctionCall,
+}
+
+/// The function of a [`JinjaToolCall`].
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct JinjaFunctionCall {
+    /// The name of the function.
+    pub name: String,
+    /// The arguments as a JSON object.
+    pub arguments: serde_json::Value,
+}
+
+impl JinjaToolCall {
+    /// A call of the function `name` with `arguments`.
+    #[must_use]
+    pub fn new(name: impl Into<String>, arguments: serde_json::Value) -> Self {
+        Self {
+            id: None,
+            kind: "function".to_string(),
+            function: JinjaFunctionCall {
+                name: name.into(),
+                arguments,
+            },
+        }
+    }
+}
+
+/// A message as chat templates see it, in the shape of the OpenAI chat API.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct JinjaMessage {
+    /// `system`, `user`, `assistant` or `tool`.
+    pub role: String,
+    /// The text of the message. `None` for assistant messages that only call tools.
+    pub content: Option<String>,
+    /// The reasoning ("thinking") of an assistant message, for templates that render it
+    /// separately from the content.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reasoning_content: Option<String>,
+    /// The tool calls of an assistant message.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub tool_calls: Vec<JinjaToolCall>,
+    /// The call a tool message answers.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tool_call_id: Option<String>,
+    /// The name of the tool of a tool message.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+}
+
+impl JinjaMessage {
+    /// A message with `role` and `content`.
+    #[must_use]
+    pub fn new(role: impl Into<String>, content: impl Into<String>) -> Self {
+        Self {
+            role: role.into(),
+            content: Some(content.into()),
+            ..Self::default()
+        }
+    }
+
+    /// Set the reasoning content.
+    #[must_use]
+    pub fn with_reasoning_content(mut self, reasoning_content: impl Into<String>) -> Self {
+        self.reasoning_content = Some(reasoning_content.into());
+        self
+    }
+
+    /// Add a tool call.
+    #[must_use]
+    pub fn with_tool_call(mut self, tool_call: JinjaToolCall) -> Self {
+        self.tool_calls.push(tool_call);
+        self
+    }
+}
+
+impl From<&LlamaChatMessage> for JinjaMessage {
+    fn from(message: &LlamaChatMessage) -> Self {
+        Self::new(message.role(), message.content())
+    }
+}
+
+/// The variables of a render besides the messages.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TemplateOptions {
+    /// End with the start of an assistant turn.
+    pub add_generation_prompt: bool,
+    /// The tools the model may call, as OpenAI function definitions. Templates without tool
+    /// support ignore them.
+    pub tools: Vec<serde_json::Value>,
+    /// Passed as `enable_thinking`, which templates of hybrid reasoning models (Qwen 3) use to
+    /// switch reasoning on or off. Unset if `None`.
+    pub enable_thinking: Option<bool>,
+}
+
+/// A compiled Jinja chat template, see the [module docs](self).
+pub struct JinjaTemplate {
+    env: Environment<'static>,
+    bos_token: String,
+    eos_token: String,
+}
+
+impl Debug for JinjaTemplate {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("JinjaTemplate")
+            .field("bos_token", &self.bos_token)
+            .field("eos_token", &self.eos_token)
+            .finish_non_exhaustive()
+    }
+}
+
+impl JinjaTemplate {
+    /// Compile the template `source`. `bos_token` and `eos_token` are empty, set them with
+    /// [`JinjaTemplate::with_special_tokens`].
+    ///
+    /// # Errors
+    ///
+    /// If the template has a syntax error.
+    pub fn new(source: impl Into<String>) -> Result<Self, JinjaError> {
+        let mut env = Environment::new();
+        // the settings of transformers' apply_chat_template
+        env.set_trim_blocks(true);
+        env.set_lstrip_blocks(true);
+        env.set_unknown_method_callback(minijinja_contrib::pycompat::unknown_method_callback);
+        env.add_function("raise_exception", raise_exception);
+        env.add_function("strftime_now", strftime_now);
+        env.add_template_owned(TEMPLATE_NAME, source.into())?;
+        Ok(Self {
+            env,
+            bos_token: String::new(),
+            eos_token: String::new(),
+        })
+    }
+
+    /// Compile the `tokenizer.chat_template` of `model`, with its bos and eos tokens.
+    ///
+    /// # Errors
+    ///
+    /// If the model has no template or it has a syntax error.
+    pub fn from_model(model: &LlamaModel) -> Result<Self, JinjaError> {
+        let source = match model.get_chat_template(16 * 1024) {
+            Err(ChatTemplateError::BuffSizeError(size)) => model.get_chat_template(size)?,
+            result => result?,
+        };
+        let bos = model.token_to_str(model.token_bos(), Special::Tokenize)?;
+        let eos = model.token_to_str(model.token_eos(), Special::Tokenize)?;
+        Ok(Self::new(source)?.with_special_tokens(bos, eos))
+    }
+
+    /// Set the text of the `bos_token` and `eos_token` variables.
+    #[must_use]
+    pub fn with_special_tokens(
+        mut self,
+        bos_token: impl Into<String>,
+        eos_token: impl Into<String>,
+    ) -> Self {
+        self.bos_token = bos_token.into();
+        self.eos_token = eos_token.into();
+        self
+    }
+
+    /// Render `messages`. The result usually starts with the bos token, so tokenize it with
+    /// [`AddBos::Never`](crate::model::AddBos::Never) and [`Special::Tokenize`].
+    ///
+    /// # Errors
+    ///
+    /// If rendering fails, e.g. the template raises an exception for an unsupported role.
+    pub fn render(
+        &self,
+        messages: &[JinjaMessage],
+        options: &TemplateOptions,
+    ) -> Result<String, JinjaError> {
+        let template = self.env.get_template(TEMPLATE_NAME)?;
+        let tools = (!options.tools.is_empty()).then_some(&options.tools);
+        Ok(template.render(context! {
+            messages,
+            tools,
+            add_generation_prompt => options.add_generation_prompt,
+            enable_thinking => options.enable_thinking,
+            bos_token => self.bos_token,
+            eos_token => self.eos_token,
+        })?)
+    }
+}
+
+/// `raise_exception(message)`, used by templates to reject conversations they cannot render.
+fn raise_exception(message: String) -> Result<String, minijinja::Error> {
+    Err(minijinja::Error::new(ErrorKind::InvalidOperation, message))
+}
+
+/// `strftime_now(format)`, the current UTC time formatted with the most common `strftime`
+/// directives (`%Y %m %d %B %b %H %M %S %%`).
+#[allow(clippy::needless_pass_by_value)]
+fn strftime_now(format: String) -> String {
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| now.as_secs());
+    strftime(&format, secs)
+}
+
+const MONTHS: [&str; 12] = [
+    "January",
+    "February",
+    "March",
+    "April",
+    "May",
+    "June",
+    "July",
+    "August",
+    "September",
+    "October",
+    "November",
+    "December",
+];
+
+/// Format the unix timestamp `secs` (UTC).
+fn strftime(format: &str, secs: u64) -> String {
+    let (year, month, day) = civil_from_days(secs / 86_400);
+    let month_name = MONTHS[usize::try_from(month - 1).expect("months are 1 to 12")];
+    let mut out = String::new();
+    let mut chars = format.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('Y') => out += &year.to_string(),
+            Some('m') => out += &format!("{month:02}"),
+            Some('d') => out += &format!("{day:02}"),
+            Some('B') => out += month_name,
+            Some('b') => out += &month_name[..3],
+            Some('H') => out += &format!("{:02}", secs / 3600 % 24),
+            Some('M') => out += &format!("{:02}", secs / 60 % 60),
+            Some('S') => out += &format!("{:02}", secs % 60),
+            Some('%') => out.push('%'),
+            Some(other) => {
+                out.push('%');
+                out.push(other);
+            }
+            None => out.push('%'),
+        }
+    }
+    out
+}
+
+/// The year, month and day of the `days`th day since 1970-01-01 (Howard Hinnant's algorithm).
+fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests;
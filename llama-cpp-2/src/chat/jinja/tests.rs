@@ -0,0 +1,100 @@
+use super::*;
+
+const CHATML_WITH_TOOLS: &str = r#"{{ bos_token }}
+{%- if tools %}
+<|im_start|>system
+Tools: {{ tools | tojson }}<|im_end|>
+{% endif %}
+{%- for message in messages %}
+{%- if message.role not in ["system", "user", "assistant", "tool"] %}
+{{- raise_exception("unknown role " ~ message.role) }}
+{%- endif %}
+<|im_start|>{{ message.role }}
+{% if message.reasoning_content %}<think>{{ message.reasoning_content | trim }}</think>
+{% endif %}
+{%- if message.content %}{{ message.content }}{% endif %}
+{%- for call in message.tool_calls %}<tool_call>{{ call.function | tojson }}</tool_call>{% endfor %}<|im_end|>
+{% endfor %}
+{%- if add_generation_prompt %}<|im_start|>assistant
+{% if enable_thinking is false %}<think></think>
+{% endif %}
+{%- endif %}"#;
+
+fn template() -> JinjaTemplate {
+    JinjaTemplate::new(CHATML_WITH_TOOLS)
+        .unwrap()
+        .with_special_tokens("<s>", "</s>")
+}
+
+#[test]
+fn renders_messages_and_generation_prompt() {
+    let messages = [
+        JinjaMessage::new("user", "Hi"),
+        JinjaMessage::new("assistant", "Hello!").with_reasoning_content(" greet back "),
+    ];
+    let options = TemplateOptions {
+        add_generation_prompt: true,
+        enable_thinking: Some(false),
+        ..TemplateOptions::default()
+    };
+    assert_eq!(
+        template().render(&messages, &options).unwrap(),
+        "<s><|im_start|>user\nHi<|im_end|>\n\
+         <|im_start|>assistant\n<think>greet back</think>\nHello!<|im_end|>\n\
+         <|im_start|>assistant\n<think></think>\n"
+    );
+}
+
+#[test]
+fn renders_tools_and_tool_calls() {
+    let call = JinjaToolCall::new("get_weather", serde_json::json!({ "city": "Paris" }));
+    let messages = [JinjaMessage {
+        role: "assistant".to_string(),
+        ..JinjaMessage::default()
+    }
+    .with_tool_call(call)];
+    let options = TemplateOptions {
+        tools: vec![serde_json::json!({ "name": "get_weather" })],
+        ..TemplateOptions::default()
+    };
+    let prompt = template().render(&messages, &options).unwrap();
+    assert!(prompt.starts_with("<s><|im_start|>system\nTools: [{\"name\":\"get_weather\"}]"));
+    let call = &prompt[prompt.find("<tool_call>").unwrap()..];
+    assert!(call.contains("\"name\":\"get_weather\""), "{call}");
+    assert!(
+        call.contains("\"arguments\":{\"city\":\"Paris\"}"),
+        "{call}"
+    );
+}
+
+#[test]
+fn raises_template_exceptions() {
+    let messages = [JinjaMessage::new("narrator", "Once upon a time")];
+    let err = template()
+        .render(&messages, &TemplateOptions::default())
+        .unwrap_err();
+    assert!(err.to_string().contains("unknown role narrator"), "{err}");
+}
+
+#[test]
+fn supports_python_string_methods() {
+    let template = JinjaTemplate::new("{{ messages[0].content.strip().upper() }}").unwrap();
+    let messages = [JinjaMessage::new("user", "  hi  ")];
+    assert_eq!(
+        template
+            .render(&messages, &TemplateOptions::default())
+            .unwrap(),
+        "HI"
+    );
+}
+
+#[test]
+fn formats_dates() {
+    // 2024-02-29 13:05:09 UTC
+    assert_eq!(
+        strftime("%d %B %Y, %b %H:%M:%S %% %q", 1_709_211_909),
+        "29 February 2024, Feb 13:05:09 % %q"
+    );
+    assert_eq!(civil_from_days(0), (1970, 1, 1));
+    assert_eq!(civil_from_days(11_016), (2000, 2, 29));
+}
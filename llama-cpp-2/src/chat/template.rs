@@ -0,0 +1,187 @@
+//! Pick the chat template of a model, and say where it came from.
+//!
+//! Most GGUF files embed the template the model was trained with in `tokenizer.chat_template`,
+//! and that is the one to trust. Some older conversions have none, and some embed a template
+//! llama.cpp cannot apply; for those a [`TemplateSelector`] recognizes well-known model families
+//! by their name and uses the matching built-in template of llama.cpp. When neither applies, the
+//! selector falls back to a template the app provides, and an app that knows better than the
+//! file can force a template for a model outright:
+//!
+//! ```no_run
+//! # use llama_cpp_2::chat::template::{TemplateSelector, TemplateSource};
+//! # use llama_cpp_2::chat::ChatSession;
+//! # use llama_cpp_2::model::LlamaModel;
+//! # fn run(model: &LlamaModel) {
+//! let selector = TemplateSelector::new().with_fallback("chatml");
+//! if let Some(template) = selector.select(model) {
+//!     if template.source != TemplateSource::Embedded {
+//!         eprintln!("using the {:?} template {}", template.source, template.template);
+//!     }
+//!     let chat = ChatSession::new(0).with_chat_template(template);
+//! }
+//! # }
+//! ```
+
+use crate::model::{LlamaChatMessage, LlamaModel};
+use crate::ChatTemplateError;
+
+/// Where a [`ChatTemplate`] came from, in the order a [`TemplateSelector`] tries them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TemplateSource {
+    /// The app forced it with [`TemplateSelector::with_forced`] or
+    /// [`ChatSession::with_template`](super::ChatSession::with_template).
+    Forced,
+    /// The template embedded in the model, `tokenizer.chat_template`.
+    Embedded,
+    /// The built-in template of llama.cpp for the model family, recognized by the model's name.
+    BuiltIn,
+    /// The app's fallback, [`TemplateSelector::with_fallback`].
+    Fallback,
+}
+
+/// A chat template and where it came from.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ChatTemplate {
+    /// The template: a built-in template name or Jinja source, as
+    /// [`LlamaModel::apply_chat_template`] takes it.
+    pub template: String,
+    /// Where the template came from.
+    pub source: TemplateSource,
+}
+
+impl ChatTemplate {
+    /// `template` forced by the app.
+    #[must_use]
+    pub fn forced(template: impl Into<String>) -> Self {
+        Self {
+            template: template.into(),
+            source: TemplateSource::Forced,
+        }
+    }
+}
+
+/// Picks the chat template of a model, see the [module docs](self).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TemplateSelector {
+    forced: Option<String>,
+    fallback: Option<String>,
+}
+
+impl TemplateSelector {
+    /// A selector that trusts the model: the embedded template, then the built-in template for
+    /// the model's name.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Always use `template`, whatever the model embeds.
+    #[must_use]
+    pub fn with_forced(mut self, template: impl Into<String>) -> Self {
+        self.forced = Some(template.into());
+        self
+    }
+
+    /// Use `template` if the model embeds no usable template and its name is not recognized.
+    #[must_use]
+    pub fn with_fallback(mut self, template: impl Into<String>) -> Self {
+        self.fallback = Some(template.into());
+        self
+    }
+
+    /// The template to chat with `model`: the forced template, the embedded template if
+    /// llama.cpp can apply it, the built-in template for the model's `general.name`,
+    /// `general.basename` or `general.architecture`, or the fallback, whichever comes first.
+    /// Returns `None` if none of them applies.
+    #[must_use]
+    pub fn select(&self, model: &LlamaModel) -> Option<ChatTemplate> {
+        let embedded = match model.get_chat_template(4096) {
+            Err(ChatTemplateError::BuffSizeError(size)) => model.get_chat_template(size).ok(),
+            template => template.ok(),
+        };
+        let names: Vec<String> = ["general.name", "general.basename", "general.architecture"]
+            .into_iter()
+            .filter_map(|key| model.meta_val_str(key))
+            .collect();
+        let names: Vec<&str> = names.iter().map(String::as_str).collect();
+        self.choose(embedded.as_deref(), &names, |template| {
+            can_apply(model, template)
+        })
+    }
+
+    /// The template for a model that embeds `embedded` and is called `names`, where `usable`
+    /// tells whether llama.cpp can apply an embedded template.
+    fn choose(
+        &self,
+        embedded: Option<&str>,
+        names: &[&str],
+        usable: impl FnOnce(&str) -> bool,
+    ) -> Option<ChatTemplate> {
+        let (template, source) = if let Some(forced) = &self.forced {
+            (forced.clone(), TemplateSource::Forced)
+        } else if let Some(embedded) = embedded.filter(|embedded| usable(embedded)) {
+            (embedded.to_string(), TemplateSource::Embedded)
+        } else if let Some(name) = names.iter().find_map(|name| built_in_for(name)) {
+            (name.to_string(), TemplateSource::BuiltIn)
+        } else {
+            (self.fallback.clone()?, TemplateSource::Fallback)
+        };
+        Some(ChatTemplate { template, source })
+    }
+}
+
+/// Model families by a part of their lowercased name, and the built-in llama.cpp template they
+/// use. The first match wins, so fine-tunes come before the base models they are named after
+/// (`Nous-Hermes-2-Mistral` is ChatML, not Mistral).
+const BUILT_IN: &[(&str, &str)] = &[
+    ("hermes", "chatml"),
+    ("dolphin", "chatml"),
+    ("zephyr", "zephyr"),
+    ("vicuna", "vicuna"),
+    ("openchat", "openchat"),
+    ("llama-3", "llama3"),
+    ("llama3", "llama3"),
+    ("llama 3", "llama3"),
+    ("llama-2", "llama2"),
+    ("llama2", "llama2"),
+    ("llama 2", "llama2"),
+    ("mistral", "mistral"),
+    ("mixtral", "mistral"),
+    ("gemma", "gemma"),
+    ("phi-3", "phi3"),
+    ("phi3", "phi3"),
+    ("command-r", "command-r"),
+    ("qwen", "chatml"),
+    ("yi-", "chatml"),
+];
+
+/// The built-in llama.cpp template for a model called `name`, if its family is known.
+///
+/// ```
+/// # use llama_cpp_2::chat::template::built_in_for;
+/// assert_eq!(built_in_for("Meta-Llama-3.1-8B-Instruct"), Some("llama3"));
+/// assert_eq!(built_in_for("qwen2"), Some("chatml"));
+/// assert_eq!(built_in_for("my-own-model"), None);
+/// ```
+#[must_use]
+pub fn built_in_for(name: &str) -> Option<&'static str> {
+    let name = name.to_lowercase();
+    BUILT_IN
+        .iter()
+        .find(|(family, _)| name.contains(family))
+        .map(|(_, template)| *template)
+}
+
+/// Whether llama.cpp can apply `template`, which it refuses for Jinja templates it does not
+/// recognize.
+fn can_apply(model: &LlamaModel, template: &str) -> bool {
+    let Ok(message) = LlamaChatMessage::new("user".to_string(), "Hi!".to_string()) else {
+        return false;
+    };
+    model
+        .apply_chat_template(Some(template.to_string()), vec![message], true)
+        .is_ok()
+}
+
+#[cfg(test)]
+mod tests;
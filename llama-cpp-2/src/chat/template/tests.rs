@@ -0,0 +1,57 @@
+use super::*;
+
+#[test]
+fn prefers_forced_then_embedded_then_built_in_then_fallback() {
+    let selector = TemplateSelector::new().with_fallback("chatml");
+    let chosen = |selector: &TemplateSelector, embedded, names: &[&str], usable| {
+        let template = selector.choose(embedded, names, |_| usable).unwrap();
+        (template.template, template.source)
+    };
+
+    let embedded = Some("{{ messages }}");
+    assert_eq!(
+        chosen(&selector, embedded, &["Gemma 2 9b It"], true),
+        ("{{ messages }}".to_string(), TemplateSource::Embedded)
+    );
+    // llama.cpp cannot apply the embedded template, so the name decides
+    assert_eq!(
+        chosen(&selector, embedded, &["Gemma 2 9b It"], false),
+        ("gemma".to_string(), TemplateSource::BuiltIn)
+    );
+    assert_eq!(
+        chosen(&selector, None, &["my-model", "phi3"], true),
+        ("phi3".to_string(), TemplateSource::BuiltIn)
+    );
+    assert_eq!(
+        chosen(&selector, None, &["my-model", "llama"], true),
+        ("chatml".to_string(), TemplateSource::Fallback)
+    );
+    assert!(TemplateSelector::new()
+        .choose(None, &[], |_| true)
+        .is_none());
+
+    let forced = selector.with_forced("llama3");
+    assert_eq!(
+        chosen(&forced, embedded, &["Gemma 2 9b It"], true),
+        ("llama3".to_string(), TemplateSource::Forced)
+    );
+}
+
+#[test]
+fn recognizes_model_families() {
+    for (name, template) in [
+        ("Meta Llama 3.1 8B Instruct", Some("llama3")),
+        ("Llama-2-7b-chat-hf", Some("llama2")),
+        ("Mistral-7B-Instruct-v0.3", Some("mistral")),
+        ("Mixtral-8x7B-Instruct-v0.1", Some("mistral")),
+        ("gemma2", Some("gemma")),
+        ("Phi-3-mini-4k-instruct", Some("phi3")),
+        ("Qwen2.5 7B Instruct", Some("chatml")),
+        ("Nous-Hermes-2-Mistral-7B-DPO", Some("chatml")),
+        ("command-r", Some("command-r")),
+        // the architecture alone does not tell llama 2 from llama 3
+        ("llama", None),
+    ] {
+        assert_eq!(built_in_for(name), template, "{name}");
+    }
+}
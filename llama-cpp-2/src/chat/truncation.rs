@@ -0,0 +1,175 @@
+//! Policies that decide which messages a [`ChatSession`](super::ChatSession) drops when the
+//! conversation no longer fits into the context.
+//!
+//! A policy sees every message with the number of tokens it takes up and returns a
+//! [`Truncation`]. The session removes (or replaces) the messages and the corresponding cells of
+//! the KV cache, shifting the positions of the later messages, so the rest of the conversation is
+//! not decoded again.
+
+use std::fmt::{Debug, Formatter};
+use std::ops::Range;
+
+use crate::model::LlamaChatMessage;
+
+/// A message of a chat and the number of tokens it takes up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChatTurn<'a> {
+    /// The message.
+    pub message: &'a LlamaChatMessage,
+    /// The number of tokens of the message as rendered by the chat template.
+    pub n_tokens: usize,
+}
+
+/// What a [`TruncationPolicy`] wants done with the conversation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Truncation {
+    /// Keep every message.
+    Keep,
+    /// Remove the messages in the range.
+    Remove(Range<usize>),
+    /// Replace the messages in the range with a single message, e.g. a summary of them.
+    Replace(Range<usize>, LlamaChatMessage),
+}
+
+/// Decides how to shrink a conversation.
+pub trait TruncationPolicy {
+    /// Called before every response with all messages, including the new one, and the number of
+    /// tokens the conversation may take up. The last message must not be removed.
+    fn truncate(&mut self, turns: &[ChatTurn<'_>], budget: usize) -> Truncation;
+}
+
+impl<F> TruncationPolicy for F
+where
+    F: FnMut(&[ChatTurn<'_>], usize) -> Truncation,
+{
+    fn truncate(&mut self, turns: &[ChatTurn<'_>], budget: usize) -> Truncation {
+        self(turns, budget)
+    }
+}
+
+/// The end of the shortest range starting at `start` whose removal makes `turns` fit into
+/// `budget`, never including the last turn. `None` if they already fit.
+fn removal_end(turns: &[ChatTurn<'_>], start: usize, budget: usize) -> Option<usize> {
+    let mut total: usize = turns.iter().map(|turn| turn.n_tokens).sum();
+    if total <= budget {
+        return None;
+    }
+    let mut end = start;
+    while total > budget && end + 1 < turns.len() {
+        total -= turns[end].n_tokens;
+        end += 1;
+    }
+    (end > start).then_some(end)
+}
+
+/// The index of the first message after a leading system message.
+fn after_system(turns: &[ChatTurn<'_>]) -> usize {
+    usize::from(
+        turns
+            .first()
+            .is_some_and(|turn| turn.message.role() == "system"),
+    )
+}
+
+/// Drop the oldest messages, including the system message, until the conversation fits.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SlidingWindow {
+    /// Keep the conversation below this many tokens even if the context has room for more.
+    pub max_tokens: Option<usize>,
+}
+
+impl TruncationPolicy for SlidingWindow {
+    fn truncate(&mut self, turns: &[ChatTurn<'_>], budget: usize) -> Truncation {
+        let budget = self.max_tokens.map_or(budget, |max| max.min(budget));
+        removal_end(turns, 0, budget).map_or(Truncation::Keep, |end| Truncation::Remove(0..end))
+    }
+}
+
+/// Keep a leading system message and drop the oldest messages after it until the conversation
+/// fits. This is the default policy of a [`ChatSession`](super::ChatSession).
+///
+/// ```
+/// # use llama_cpp_2::chat::truncation::{ChatTurn, KeepSystemAndRecent, Truncation, TruncationPolicy};
+/// # use llama_cpp_2::model::LlamaChatMessage;
+/// let system = LlamaChatMessage::new("system".into(), "Be brief.".into())?;
+/// let user = LlamaChatMessage::new("user".into(), "Hi".into())?;
+/// let assistant = LlamaChatMessage::new("assistant".into(), "Hello".into())?;
+/// let turns = [
+///     ChatTurn { message: &system, n_tokens: 10 },
+///     ChatTurn { message: &user, n_tokens: 40 },
+///     ChatTurn { message: &assistant, n_tokens: 40 },
+///     ChatTurn { message: &user, n_tokens: 40 },
+/// ];
+/// let mut policy = KeepSystemAndRecent::default();
+/// assert_eq!(policy.truncate(&turns, 200), Truncation::Keep);
+/// assert_eq!(policy.truncate(&turns, 60), Truncation::Remove(1..3));
+/// # Ok::<(), llama_cpp_2::NewLlamaChatMessageError>(())
+/// ```
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct KeepSystemAndRecent {
+    /// Keep the conversation below this many tokens even if the context has room for more.
+    pub max_tokens: Option<usize>,
+}
+
+impl TruncationPolicy for KeepSystemAndRecent {
+    fn truncate(&mut self, turns: &[ChatTurn<'_>], budget: usize) -> Truncation {
+        let budget = self.max_tokens.map_or(budget, |max| max.min(budget));
+        let start = after_system(turns);
+        removal_end(turns, start, budget)
+            .map_or(Truncation::Keep, |end| Truncation::Remove(start..end))
+    }
+}
+
+/// Replace the oldest messages after a leading system message with a summary when the
+/// conversation does not fit, keeping the `keep_recent` latest messages as they are.
+///
+/// `summarize` gets the messages to replace and returns the message that replaces them (for
+/// example a `system` message produced by running the model on them in another context). If it
+/// returns `None` the messages are removed instead.
+pub struct Summarize<F> {
+    /// The number of latest messages that are never summarized.
+    pub keep_recent: usize,
+    /// Summarizes the messages.
+    pub summarize: F,
+}
+
+impl<F> Summarize<F>
+where
+    F: FnMut(&[&LlamaChatMessage]) -> Option<LlamaChatMessage>,
+{
+    /// Summarize with `summarize`, keeping the `keep_recent` latest messages.
+    pub fn new(keep_recent: usize, summarize: F) -> Self {
+        Self {
+            keep_recent,
+            summarize,
+        }
+    }
+}
+
+impl<F> Debug for Summarize<F> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Summarize")
+            .field("keep_recent", &self.keep_recent)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<F> TruncationPolicy for Summarize<F>
+where
+    F: FnMut(&[&LlamaChatMessage]) -> Option<LlamaChatMessage>,
+{
+    fn truncate(&mut self, turns: &[ChatTurn<'_>], budget: usize) -> Truncation {
+        let total: usize = turns.iter().map(|turn| turn.n_tokens).sum();
+        let start = after_system(turns);
+        let end = turns.len().saturating_sub(self.keep_recent.max(1));
+        if total <= budget || end <= start {
+            return Truncation::Keep;
+        }
+        let messages: Vec<&LlamaChatMessage> =
+            turns[start..end].iter().map(|turn| turn.message).collect();
+        match (self.summarize)(&messages) {
+            Some(summary) => Truncation::Replace(start..end, summary),
+            None => Truncation::Remove(start..end),
+        }
+    }
+}
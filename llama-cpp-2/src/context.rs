@@ -1,23 +1,32 @@
 //! Safe wrapper around `llama_context`.
 
+use std::collections::HashMap;
 use std::fmt::{Debug, Formatter};
 use std::num::NonZeroI32;
 use std::ptr::NonNull;
 use std::slice;
 
-use crate::llama_batch::LlamaBatch;
+use llama_cpp_sys_2::llama_pos;
+
+use crate::context::cancel::Abort;
+use crate::context::params::LlamaPoolingType;
+use crate::llama_batch::{BatchAddError, LlamaBatch};
 use crate::model::{LlamaLoraAdapter, LlamaModel};
 use crate::timing::LlamaTimings;
 use crate::token::data::LlamaTokenData;
 use crate::token::LlamaToken;
 use crate::{
     DecodeError, EmbeddingsError, EncodeError, LlamaLoraAdapterRemoveError,
-    LlamaLoraAdapterSetError,
+    LlamaLoraAdapterSetError, LogitsError,
 };
 
+pub mod cancel;
+pub mod decode;
+pub mod infill;
 pub mod kv_cache;
 pub mod params;
 pub mod sample;
+pub mod score;
 pub mod session;
 
 /// Safe wrapper around `llama_context`.
@@ -28,6 +37,11 @@ pub struct LlamaContext<'a> {
     pub model: &'a LlamaModel,
     initialized_logits: Vec<i32>,
     embeddings_enabled: bool,
+    causal_attn: bool,
+    cancellation: Option<Box<Abort>>,
+    /// The tokens known to be in the KV cache of each sequence at positions `0..len`, a prefix of
+    /// what is actually there. See [`LlamaContext::cached_tokens`].
+    cached_tokens: HashMap<i32, Vec<LlamaToken>>,
 }
 
 impl Debug for LlamaContext<'_> {
@@ -49,6 +63,9 @@ impl<'model> LlamaContext<'model> {
             model: llama_model,
             initialized_logits: Vec::new(),
             embeddings_enabled,
+            causal_attn: llama_model.has_causal_attention(),
+            cancellation: None,
+            cached_tokens: HashMap::new(),
         }
     }
 
@@ -64,47 +81,228 @@ impl<'model> LlamaContext<'model> {
         unsafe { llama_cpp_sys_2::llama_n_ubatch(self.context.as_ptr()) }
     }
 
+    /// How the embeddings of a sequence's tokens are pooled into the embedding of
+    /// [`LlamaContext::embeddings_seq_ith`], as set with
+    /// [`LlamaContextParams::with_pooling_type`](params::LlamaContextParams::with_pooling_type)
+    /// or taken from the model.
+    #[must_use]
+    pub fn pooling_type(&self) -> LlamaPoolingType {
+        LlamaPoolingType::from(unsafe {
+            llama_cpp_sys_2::llama_pooling_type(self.context.as_ptr())
+        })
+    }
+
+    /// Whether tokens attend only to the tokens before them. Decoder models do, embedding
+    /// encoders such as BERT attend to the whole sequence and need it fully in one ubatch.
+    #[must_use]
+    pub fn causal_attn(&self) -> bool {
+        self.causal_attn
+    }
+
+    /// Switch between causal and non-causal attention, e.g. to embed with a decoder model
+    /// trained for bidirectional embeddings (GritLM).
+    pub fn set_causal_attn(&mut self, causal_attn: bool) {
+        unsafe { llama_cpp_sys_2::llama_set_causal_attn(self.context.as_ptr(), causal_attn) };
+        self.causal_attn = causal_attn;
+    }
+
+    /// Gets the max number of sequences the context was created for.
+    #[must_use]
+    pub fn n_seq_max(&self) -> u32 {
+        unsafe { llama_cpp_sys_2::llama_n_seq_max(self.context.as_ptr()) }
+    }
+
     /// Gets the size of the context.
     #[must_use]
     pub fn n_ctx(&self) -> u32 {
         unsafe { llama_cpp_sys_2::llama_n_ctx(self.context.as_ptr()) }
     }
 
-    /// Decodes the batch.
+    /// Decodes the batch. [`LlamaContext::decode_batch`] reports the outcome of each sequence of
+    /// the batch instead.
     ///
     /// # Errors
     ///
     /// - `DecodeError` if the decoding failed.
+    /// - [`DecodeError::Aborted`] if the [cancellation token](cancel) stopped the decode.
+    /// - [`DecodeError::UbatchTooSmall`] if the attention is non-causal and the batch does not fit
+    ///   into one ubatch.
     ///
     /// # Panics
     ///
     /// - the returned [`std::ffi::c_int`] from llama-cpp does not fit into a i32 (this should never happen on most systems)
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            level = "debug",
+            skip_all,
+            fields(n_tokens = batch.n_tokens(), duration_us = tracing::field::Empty)
+        )
+    )]
     pub fn decode(&mut self, batch: &mut LlamaBatch) -> Result<(), DecodeError> {
+        let n_ubatch = self.n_ubatch();
+        if !self.causal_attn && u32::try_from(batch.n_tokens()).unwrap_or(0) > n_ubatch {
+            // llama.cpp asserts instead of returning an error
+            return Err(DecodeError::UbatchTooSmall {
+                n_tokens: batch.n_tokens(),
+                n_ubatch,
+            });
+        }
+        #[cfg(any(feature = "metrics", feature = "tracing"))]
+        let start = std::time::Instant::now();
+        self.reset_aborted();
         let result =
             unsafe { llama_cpp_sys_2::llama_decode(self.context.as_ptr(), batch.llama_batch) };
+        #[cfg(feature = "tracing")]
+        tracing::Span::current().record(
+            "duration_us",
+            u64::try_from(start.elapsed().as_micros()).unwrap_or(u64::MAX),
+        );
+        #[cfg(feature = "metrics")]
+        crate::metrics::record_decode(
+            batch.n_tokens(),
+            start.elapsed(),
+            self.get_kv_cache_used_cells(),
+            self.n_ctx(),
+        );
 
         match NonZeroI32::new(result) {
+            // an aborted graph computation is not reported by llama_decode
+            None if self.aborted() => {
+                self.initialized_logits.clear();
+                self.record_batch(batch, false);
+                Err(DecodeError::Aborted)
+            }
             None => {
                 self.initialized_logits
                     .clone_from(&batch.initialized_logits);
+                self.record_batch(batch, true);
                 Ok(())
             }
-            Some(error) => Err(DecodeError::from(error)),
+            Some(error) => {
+                self.record_batch(batch, false);
+                Err(DecodeError::from(error))
+            }
         }
     }
 
+    /// Update [`LlamaContext::cached_tokens`] after decoding `batch`. Tokens continuing a sequence
+    /// are appended if the decode succeeded, everything from the position of a token on is
+    /// forgotten otherwise.
+    fn record_batch(&mut self, batch: &LlamaBatch, decoded: bool) {
+        for i in 0..batch.n_tokens() {
+            let Some((token, pos)) = batch.token_pos(i) else {
+                continue;
+            };
+            let pos = usize::try_from(pos).unwrap_or(0);
+            for &seq_id in batch.seq_ids(i) {
+                let cached = self.cached_tokens.entry(seq_id).or_default();
+                cached.truncate(pos);
+                match token {
+                    Some(token) if decoded && pos == cached.len() => cached.push(token),
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    /// Forget the cached tokens of `seq_id` (every sequence if negative) from `p0` on (all of them
+    /// if negative), after its KV cache was changed other than by decoding.
+    pub(crate) fn forget_cached_tokens(&mut self, seq_id: i32, p0: llama_pos) {
+        let p0 = usize::try_from(p0).unwrap_or(0);
+        if seq_id < 0 {
+            self.cached_tokens
+                .values_mut()
+                .for_each(|cached| cached.truncate(p0));
+        } else if let Some(cached) = self.cached_tokens.get_mut(&seq_id) {
+            cached.truncate(p0);
+        }
+    }
+
+    /// Decodes a row-major `[n_tokens x n_embd]` matrix of externally computed embeddings (e.g. the
+    /// output of an image or audio encoder projected into the model's embedding space) into
+    /// sequence `seq_id`, starting at position `pos_0`.
+    ///
+    /// The embeddings are split into batches of at most `n_batch`. The logits of the last embedding
+    /// are initialized, so text generation can continue directly afterwards.
+    ///
+    /// # Returns
+    ///
+    /// The position after the last embedding, i.e. where the next token of the sequence goes.
+    ///
+    /// # Errors
+    ///
+    /// - if the length of `embds` is not a multiple of the model's `n_embd`.
+    /// - if decoding any of the batches fails.
+    ///
+    /// # Panics
+    ///
+    /// - `n_embd` or `n_batch` does not fit into a usize
+    pub fn decode_embeddings(
+        &mut self,
+        embds: &[f32],
+        pos_0: llama_pos,
+        seq_id: i32,
+    ) -> crate::Result<llama_pos> {
+        let n_embd =
+            usize::try_from(self.model.n_embd()).expect("n_embd does not fit into a usize");
+        let n_batch = usize::try_from(self.n_batch()).expect("n_batch does not fit into a usize");
+        if n_embd == 0 || embds.len() % n_embd != 0 {
+            return Err(BatchAddError::EmbeddingSizeMismatch {
+                expected: n_embd,
+                actual: embds.len(),
+            }
+            .into());
+        }
+
+        let mut batch = LlamaBatch::new_embeddings(n_batch, n_embd, 1);
+        let mut pos = pos_0;
+        let n_chunks = embds.len().div_ceil(n_batch * n_embd);
+        for (i, chunk) in embds.chunks(n_batch * n_embd).enumerate() {
+            batch.clear();
+            pos = batch.add_embeddings(chunk, pos, seq_id, i == n_chunks - 1)?;
+            self.decode(&mut batch)?;
+        }
+
+        Ok(pos)
+    }
+
     /// Encodes the batch.
     ///
     /// # Errors
     ///
     /// - `EncodeError` if the decoding failed.
+    /// - [`EncodeError::UbatchTooSmall`] if the batch does not fit into one ubatch.
     ///
     /// # Panics
     ///
     /// - the returned [`std::ffi::c_int`] from llama-cpp does not fit into a i32 (this should never happen on most systems)
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            level = "debug",
+            skip_all,
+            fields(n_tokens = batch.n_tokens(), duration_us = tracing::field::Empty)
+        )
+    )]
     pub fn encode(&mut self, batch: &mut LlamaBatch) -> Result<(), EncodeError> {
+        let n_ubatch = self.n_ubatch();
+        if u32::try_from(batch.n_tokens()).unwrap_or(0) > n_ubatch {
+            // llama.cpp asserts instead of returning an error
+            return Err(EncodeError::UbatchTooSmall {
+                n_tokens: batch.n_tokens(),
+                n_ubatch,
+            });
+        }
+        #[cfg(feature = "tracing")]
+        let start = std::time::Instant::now();
         let result =
             unsafe { llama_cpp_sys_2::llama_encode(self.context.as_ptr(), batch.llama_batch) };
+        #[cfg(feature = "tracing")]
+        tracing::Span::current().record(
+            "duration_us",
+            u64::try_from(start.elapsed().as_micros()).unwrap_or(u64::MAX),
+        );
 
         match NonZeroI32::new(result) {
             None => {
@@ -116,12 +314,67 @@ impl<'model> LlamaContext<'model> {
         }
     }
 
+    /// Run a throwaway decode of the BOS and EOS tokens, like llama.cpp's examples do before the
+    /// first request. This pages in the model weights and builds the compute graphs, so the first
+    /// real decode is not several times slower than the following ones.
+    ///
+    /// For encoder-decoder models the tokens are encoded and the decoder start token is decoded.
+    /// The KV cache and the timings are cleared afterwards.
+    ///
+    /// # Errors
+    ///
+    /// - if encoding or decoding the warmup batch fails.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use llama_cpp_2::context::LlamaContext;
+    /// # fn run(ctx: &mut LlamaContext) -> llama_cpp_2::Result<()> {
+    /// ctx.warmup()?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn warmup(&mut self) -> crate::Result<()> {
+        let mut tokens: Vec<LlamaToken> = [self.model.token_bos(), self.model.token_eos()]
+            .into_iter()
+            .filter(|token| token.0 != -1)
+            .collect();
+
+        if self.model.has_encoder() && !tokens.is_empty() {
+            self.encode(&mut LlamaBatch::get_one(&tokens)?)?;
+            let start = self.model.decode_start_token();
+            tokens = vec![if start.0 == -1 {
+                self.model.token_bos()
+            } else {
+                start
+            }];
+        }
+        if !tokens.is_empty() {
+            self.decode(&mut LlamaBatch::get_one(&tokens)?)?;
+        }
+
+        self.clear_kv_cache();
+        self.synchronize();
+        self.initialized_logits.clear();
+        self.reset_timings();
+        Ok(())
+    }
+
+    /// Wait until every computation queued by [`LlamaContext::decode`] and
+    /// [`LlamaContext::encode`] finished. Backends such as CUDA and Metal run a decode
+    /// asynchronously and only block on reading its results, so time a decode up to this call.
+    pub fn synchronize(&mut self) {
+        unsafe { llama_cpp_sys_2::llama_synchronize(self.context.as_ptr()) };
+    }
+
     /// Get the embeddings for the `i`th sequence in the current context.
     ///
     /// # Returns
     ///
     /// A slice containing the embeddings for the last decoded batch.
     /// The size corresponds to the `n_embd` parameter of the context's model.
+    /// The slice borrows llama.cpp's output buffer, which is overwritten by the next decode, use
+    /// [`LlamaContext::embeddings_seq_ith_to_vec`] to keep a copy.
     ///
     /// # Errors
     ///
@@ -158,6 +411,8 @@ impl<'model> LlamaContext<'model> {
     ///
     /// A slice containing the embeddings for the last decoded batch of the given token.
     /// The size corresponds to the `n_embd` parameter of the context's model.
+    /// The slice borrows llama.cpp's output buffer, which is overwritten by the next decode, use
+    /// [`LlamaContext::embeddings_ith_to_vec`] to keep a copy.
     ///
     /// # Errors
     ///
@@ -187,6 +442,24 @@ impl<'model> LlamaContext<'model> {
         }
     }
 
+    /// Copy the embeddings of the `i`th sequence, see [`LlamaContext::embeddings_seq_ith`].
+    ///
+    /// # Errors
+    ///
+    /// See [`LlamaContext::embeddings_seq_ith`].
+    pub fn embeddings_seq_ith_to_vec(&self, i: i32) -> Result<Vec<f32>, EmbeddingsError> {
+        self.embeddings_seq_ith(i).map(<[f32]>::to_vec)
+    }
+
+    /// Copy the embeddings of the `i`th token, see [`LlamaContext::embeddings_ith`].
+    ///
+    /// # Errors
+    ///
+    /// See [`LlamaContext::embeddings_ith`].
+    pub fn embeddings_ith_to_vec(&self, i: i32) -> Result<Vec<f32>, EmbeddingsError> {
+        self.embeddings_ith(i).map(<[f32]>::to_vec)
+    }
+
     /// Get the logits for the last token in the context.
     ///
     /// # Returns
@@ -195,12 +468,19 @@ impl<'model> LlamaContext<'model> {
     ///
     /// # Panics
     ///
-    /// - underlying logits data is null
+    /// - underlying logits data is null, see [`LlamaContext::try_candidates`]
     pub fn candidates(&self) -> impl Iterator<Item = LlamaTokenData> + '_ {
-        (0_i32..).zip(self.get_logits()).map(|(i, logit)| {
-            let token = LlamaToken::new(i);
-            LlamaTokenData::new(token, *logit, 0_f32)
-        })
+        token_data(self.get_logits())
+    }
+
+    /// Get the logits for the last token in the context, or an error instead of panicking if they
+    /// are not available.
+    ///
+    /// # Errors
+    ///
+    /// See [`LlamaContext::try_get_logits`].
+    pub fn try_candidates(&self) -> Result<impl Iterator<Item = LlamaTokenData> + '_, LogitsError> {
+        self.try_get_logits().map(token_data)
     }
 
     /// Token logits obtained from the last call to `decode()`.
@@ -217,25 +497,51 @@ impl<'model> LlamaContext<'model> {
     /// # Panics
     ///
     /// - `n_vocab` does not fit into a usize
-    /// - token data returned is null
+    /// - token data returned is null, see [`LlamaContext::try_get_logits`]
     pub fn get_logits(&self) -> &[f32] {
+        self.try_get_logits()
+            .expect("logits data for last token is null")
+    }
+
+    /// [`LlamaContext::get_logits`], or an error instead of panicking if there are none.
+    ///
+    /// # Errors
+    ///
+    /// [`LogitsError::NullReturn`] if llama.cpp has no logits, e.g. before the first decode.
+    ///
+    /// # Panics
+    ///
+    /// - `n_vocab` does not fit into a usize
+    pub fn try_get_logits(&self) -> Result<&[f32], LogitsError> {
         let data = unsafe { llama_cpp_sys_2::llama_get_logits(self.context.as_ptr()) };
-        assert!(!data.is_null(), "logits data for last token is null");
+        if data.is_null() {
+            return Err(LogitsError::NullReturn);
+        }
         let len = usize::try_from(self.model.n_vocab()).expect("n_vocab does not fit into a usize");
 
-        unsafe { slice::from_raw_parts(data, len) }
+        Ok(unsafe { slice::from_raw_parts(data, len) })
     }
 
     /// Get the logits for the ith token in the context.
     ///
     /// # Panics
     ///
-    /// - logit `i` is not initialized.
+    /// - logit `i` is not initialized, see [`LlamaContext::try_candidates_ith`].
     pub fn candidates_ith(&self, i: i32) -> impl Iterator<Item = LlamaTokenData> + '_ {
-        (0_i32..).zip(self.get_logits_ith(i)).map(|(i, logit)| {
-            let token = LlamaToken::new(i);
-            LlamaTokenData::new(token, *logit, 0_f32)
-        })
+        token_data(self.expect_logits_ith(i))
+    }
+
+    /// Get the logits for the ith token in the context, or an error instead of panicking if they
+    /// are not available.
+    ///
+    /// # Errors
+    ///
+    /// See [`LlamaContext::try_get_logits_ith`].
+    pub fn try_candidates_ith(
+        &self,
+        i: i32,
+    ) -> Result<impl Iterator<Item = LlamaTokenData> + '_, LogitsError> {
+        self.try_get_logits_ith(i).map(token_data)
     }
 
     /// Get the logits for the ith token in the context.
@@ -245,24 +551,106 @@ impl<'model> LlamaContext<'model> {
     /// - `i` is greater than `n_ctx`
     /// - `n_vocab` does not fit into a usize
     /// - logit `i` is not initialized.
+    ///
+    /// [`LlamaContext::try_get_logits_ith`] returns these as errors instead.
     #[must_use]
+    #[deprecated(
+        since = "0.1.70",
+        note = "panics if the logits are not available, use `try_get_logits_ith` instead"
+    )]
     pub fn get_logits_ith(&self, i: i32) -> &[f32] {
-        assert!(
-            self.initialized_logits.contains(&i),
-            "logit {i} is not initialized. only {:?} is",
-            self.initialized_logits
-        );
-        assert!(
-            self.n_ctx() > u32::try_from(i).expect("i does not fit into a u32"),
-            "n_ctx ({}) must be greater than i ({})",
-            self.n_ctx(),
-            i
-        );
+        self.expect_logits_ith(i)
+    }
+
+    /// [`LlamaContext::try_get_logits_ith`], panicking with the initialized logits on error.
+    fn expect_logits_ith(&self, i: i32) -> &[f32] {
+        match self.try_get_logits_ith(i) {
+            Ok(logits) => logits,
+            Err(LogitsError::NotInitialized(i)) => panic!(
+                "logit {i} is not initialized. only {:?} is",
+                self.initialized_logits
+            ),
+            Err(err) => panic!("{err}"),
+        }
+    }
+
+    /// Get the logits for the ith token in the context, or an error instead of panicking if they
+    /// are not available.
+    ///
+    /// The slice borrows llama.cpp's output buffer without copying (`n_vocab` floats per token),
+    /// use [`LlamaContext::logits_ith_to_vec`] to keep the logits past the next decode.
+    ///
+    /// # Errors
+    ///
+    /// - `i` is greater than `n_ctx`
+    /// - logit `i` was not requested in the last decoded batch.
+    ///
+    /// # Panics
+    ///
+    /// - `n_vocab` does not fit into a usize
+    pub fn try_get_logits_ith(&self, i: i32) -> Result<&[f32], LogitsError> {
+        let (data, len) = self.logits_ith_ptr(i)?;
+        Ok(unsafe { slice::from_raw_parts(data, len) })
+    }
+
+    /// The logits of the last token of `seq_id` in `batch`, which must be the batch decoded last,
+    /// see [`LlamaBatch::seq_logits_index`].
+    ///
+    /// # Errors
+    ///
+    /// - [`LogitsError::SeqNotInBatch`] if no token of `seq_id` has logits in `batch`.
+    /// - See [`LlamaContext::try_get_logits_ith`].
+    ///
+    /// # Panics
+    ///
+    /// - `n_vocab` does not fit into a usize
+    pub fn seq_logits(&self, batch: &LlamaBatch, seq_id: i32) -> Result<&[f32], LogitsError> {
+        let i = batch
+            .seq_logits_index(seq_id)
+            .ok_or(LogitsError::SeqNotInBatch(seq_id))?;
+        self.try_get_logits_ith(i)
+    }
+
+    /// Copy the logits of the ith token, see [`LlamaContext::try_get_logits_ith`].
+    ///
+    /// # Errors
+    ///
+    /// See [`LlamaContext::try_get_logits_ith`].
+    pub fn logits_ith_to_vec(&self, i: i32) -> Result<Vec<f32>, LogitsError> {
+        self.try_get_logits_ith(i).map(<[f32]>::to_vec)
+    }
+
+    /// Get the logits for the ith token in the context mutably, so they can be modified before
+    /// sampling (see [`sample::logits_processor`]).
+    ///
+    /// # Errors
+    ///
+    /// - `i` is greater than `n_ctx`
+    /// - logit `i` was not requested in the last decoded batch.
+    ///
+    /// # Panics
+    ///
+    /// - `n_vocab` does not fit into a usize
+    pub fn try_get_logits_ith_mut(&mut self, i: i32) -> Result<&mut [f32], LogitsError> {
+        let (data, len) = self.logits_ith_ptr(i)?;
+        Ok(unsafe { slice::from_raw_parts_mut(data, len) })
+    }
+
+    fn logits_ith_ptr(&self, i: i32) -> Result<(*mut f32, usize), LogitsError> {
+        if !self.initialized_logits.contains(&i) {
+            return Err(LogitsError::NotInitialized(i));
+        }
+        let n_ctx = self.n_ctx();
+        if !u32::try_from(i).is_ok_and(|i| i < n_ctx) {
+            return Err(LogitsError::OutOfContext { i, n_ctx });
+        }
 
         let data = unsafe { llama_cpp_sys_2::llama_get_logits_ith(self.context.as_ptr(), i) };
+        if data.is_null() {
+            return Err(LogitsError::NullReturn);
+        }
         let len = usize::try_from(self.model.n_vocab()).expect("n_vocab does not fit into a usize");
-
-        unsafe { slice::from_raw_parts(data, len) }
+        Ok((data, len))
     }
 
     /// Reset the timings for the context.
@@ -276,6 +664,16 @@ impl<'model> LlamaContext<'model> {
         LlamaTimings { timings }
     }
 
+    /// Write the timings to `writer` instead of stderr, as `llama_print_timings` would. See
+    /// [`LlamaTimings::write_to`].
+    ///
+    /// # Errors
+    ///
+    /// If writing fails.
+    pub fn write_timings(&mut self, writer: impl std::io::Write) -> std::io::Result<()> {
+        self.timings().write_to(writer)
+    }
+
     /// Sets a lora adapter.
     ///
     /// # Errors
@@ -325,6 +723,14 @@ impl<'model> LlamaContext<'model> {
     }
 }
 
+/// The token data of `logits`, with the token id as index.
+fn token_data(logits: &[f32]) -> impl Iterator<Item = LlamaTokenData> + '_ {
+    (0_i32..).zip(logits).map(|(i, logit)| {
+        let token = LlamaToken::new(i);
+        LlamaTokenData::new(token, *logit, 0_f32)
+    })
+}
+
 impl Drop for LlamaContext<'_> {
     fn drop(&mut self) {
         unsafe { llama_cpp_sys_2::llama_free(self.context.as_ptr()) }
@@ -0,0 +1,149 @@
+//! Cooperative cancellation of decoding and generation.
+//!
+//! A [`CancellationToken`] installed with [`LlamaContext::set_cancellation_token`] is checked by
+//! ggml between graph nodes through the context's abort callback, so a long prompt decode stops
+//! early instead of running to completion. Only the CPU backend checks the callback, with layers
+//! offloaded to a GPU the running decode finishes and the cancellation is noticed afterwards.
+//!
+//! A [`LlamaContext::decode`] stopped by the abort callback returns [`DecodeError::Aborted`], one
+//! that finished before the callback saw the cancellation succeeds. The KV cache cells of the
+//! tokens in an aborted batch were allocated but may hold garbage, remove them with
+//! [`LlamaContext::clear_kv_cache_seq`] before decoding the sequence again.
+//! [`LlamaContext::generate`] does this itself.
+//!
+//! ```no_run
+//! # use llama_cpp_2::context::cancel::CancellationToken;
+//! # use llama_cpp_2::context::LlamaContext;
+//! # fn run(ctx: &mut LlamaContext) {
+//! let token = CancellationToken::new();
+//! ctx.set_cancellation_token(token.clone());
+//! // e.g. in the "stop" button handler of another thread
+//! std::thread::spawn(move || token.cancel());
+//! # }
+//! ```
+//!
+//! [`DecodeError::Aborted`]: crate::DecodeError::Aborted
+
+use std::ffi::c_void;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use crate::context::LlamaContext;
+
+/// A cheaply cloneable flag shared between a [`LlamaContext`] and whoever wants to cancel it.
+///
+/// Once cancelled the token stays cancelled until [`CancellationToken::reset`] is called, so a
+/// cancellation that arrives between two generations is not lost.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancellationToken {
+    /// A new, not cancelled token.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Request cancellation of the context(s) using this token.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Release);
+    }
+
+    /// Whether cancellation was requested.
+    #[must_use]
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Acquire)
+    }
+
+    /// Clear a previous cancellation so the token can be used for the next request.
+    pub fn reset(&self) {
+        self.cancelled.store(false, Ordering::Release);
+    }
+}
+
+/// The data of the abort callback: the installed token and whether the callback stopped the
+/// current decode. Boxed by the context so the pointer handed to llama.cpp stays valid.
+#[derive(Debug)]
+pub(super) struct Abort {
+    token: CancellationToken,
+    fired: AtomicBool,
+}
+
+/// The abort callback handed to llama.cpp. `data` points to the [`Abort`] kept alive by the
+/// context.
+extern "C" fn abort_callback(data: *mut c_void) -> bool {
+    let abort = unsafe { &*data.cast::<Abort>() };
+    let cancelled = abort.token.is_cancelled();
+    if cancelled {
+        abort.fired.store(true, Ordering::Relaxed);
+    }
+    cancelled
+}
+
+impl LlamaContext<'_> {
+    /// Install `token` as the cancellation token of this context, replacing a previous one.
+    ///
+    /// See the [module docs](crate::context::cancel) for what happens on cancellation.
+    pub fn set_cancellation_token(&mut self, token: CancellationToken) {
+        let abort = Box::new(Abort {
+            token,
+            fired: AtomicBool::new(false),
+        });
+        let data = std::ptr::from_ref(&*abort).cast_mut().cast::<c_void>();
+        unsafe {
+            llama_cpp_sys_2::llama_set_abort_callback(
+                self.context.as_ptr(),
+                Some(abort_callback),
+                data,
+            );
+        }
+        // keeps the data alive for as long as the callback is installed
+        self.cancellation = Some(abort);
+    }
+
+    /// Remove the cancellation token of this context.
+    pub fn clear_cancellation_token(&mut self) {
+        unsafe {
+            llama_cpp_sys_2::llama_set_abort_callback(
+                self.context.as_ptr(),
+                None,
+                std::ptr::null_mut(),
+            );
+        }
+        self.cancellation = None;
+    }
+
+    /// The cancellation token of this context, if one is installed.
+    #[must_use]
+    pub fn cancellation_token(&self) -> Option<&CancellationToken> {
+        self.cancellation.as_ref().map(|abort| &abort.token)
+    }
+
+    /// Whether the installed cancellation token (if any) was cancelled.
+    #[must_use]
+    pub fn is_cancelled(&self) -> bool {
+        self.cancellation_token()
+            .is_some_and(CancellationToken::is_cancelled)
+    }
+
+    /// Forget that the previous decode was aborted, before the next one starts.
+    pub(super) fn reset_aborted(&self) {
+        if let Some(abort) = &self.cancellation {
+            abort.fired.store(false, Ordering::Relaxed);
+        }
+    }
+
+    /// Whether the abort callback stopped a graph computation since
+    /// [`LlamaContext::reset_aborted`]. Unlike [`LlamaContext::is_cancelled`], a cancellation that
+    /// arrives after the decode finished does not count.
+    pub(super) fn aborted(&self) -> bool {
+        self.cancellation
+            .as_ref()
+            .is_some_and(|abort| abort.fired.load(Ordering::Relaxed))
+    }
+}
+
+#[cfg(test)]
+mod tests;
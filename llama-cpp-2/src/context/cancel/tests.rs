@@ -0,0 +1,33 @@
+use std::num::NonZeroU32;
+
+use super::*;
+use crate::context::params::LlamaContextParams;
+use crate::llama_batch::LlamaBatch;
+use crate::model::AddBos;
+use crate::test_utils::{self, TinyModel};
+use crate::DecodeError;
+
+#[test]
+fn only_a_decode_stopped_by_the_callback_is_aborted() {
+    let model = TinyModel::default().load(test_utils::backend()).unwrap();
+    let params = LlamaContextParams::default().with_n_ctx(NonZeroU32::new(64));
+    let mut ctx = model.new_context(test_utils::backend(), params).unwrap();
+    let token = CancellationToken::new();
+    ctx.set_cancellation_token(token.clone());
+    let tokens = model.str_to_token("the cat is", AddBos::Always).unwrap();
+    let mut batch = LlamaBatch::new(16, 1);
+    batch.add_sequence(&tokens, 0, false).unwrap();
+
+    token.cancel();
+    assert_eq!(ctx.decode(&mut batch), Err(DecodeError::Aborted));
+    assert!(ctx.cached_tokens(0).is_empty());
+
+    token.reset();
+    ctx.clear_kv_cache_seq(0, None, None);
+    assert_eq!(ctx.decode(&mut batch), Ok(()));
+    assert_eq!(ctx.cached_tokens(0), tokens);
+    // a cancellation after the decode finished does not turn it into an abort
+    token.cancel();
+    assert!(ctx.is_cancelled());
+    assert!(ctx.try_get_logits_ith(batch.n_tokens() - 1).is_ok());
+}
@@ -0,0 +1,148 @@
+//! Decode a batch of many sequences and get the outcome of each.
+//!
+//! [`LlamaContext::decode`] reports one status for the whole batch, but a batch under continuous
+//! batching belongs to many requests. [`LlamaContext::decode_batch`] breaks the outcome down by
+//! sequence: where its logits are, how many of its tokens made it into the KV cache and whether
+//! it failed. llama.cpp decodes a batch in ubatches and keeps the ones that succeeded when it runs
+//! out of KV cache slots, so sequences that were finished by then are fine and only the rest have
+//! to be retried or dropped:
+//!
+//! ```no_run
+//! # use llama_cpp_2::context::LlamaContext;
+//! # use llama_cpp_2::llama_batch::LlamaBatch;
+//! # use llama_cpp_2::token::LlamaToken;
+//! # fn run(ctx: &mut LlamaContext, prompts: &[Vec<LlamaToken>]) -> Result<(), Box<dyn std::error::Error>> {
+//! let mut batch = LlamaBatch::new(512, 4);
+//! for (seq_id, prompt) in (0..).zip(prompts) {
+//!     batch.add_sequence_at(prompt, 0, seq_id)?;
+//! }
+//! let decoded = ctx.decode_batch(&mut batch);
+//! for (seq_id, seq) in &decoded.seqs {
+//!     match (&seq.error, seq.logits_index) {
+//!         (Some(err), _) => eprintln!("{seq_id} failed after {} tokens: {err}", seq.n_accepted),
+//!         (None, Some(i)) => println!("{seq_id}: {} logits", ctx.try_get_logits_ith(i)?.len()),
+//!         (None, None) => {}
+//!     }
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use std::collections::BTreeMap;
+
+use llama_cpp_sys_2::llama_pos;
+
+use crate::context::LlamaContext;
+use crate::llama_batch::LlamaBatch;
+use crate::DecodeError;
+
+/// The outcome of [`LlamaContext::decode_batch`] for each sequence of the batch.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BatchDecode {
+    /// The outcome of every sequence with tokens in the batch, by sequence id.
+    pub seqs: BTreeMap<i32, SeqDecode>,
+    /// The error of the decode, `None` if the whole batch was decoded.
+    pub error: Option<DecodeError>,
+}
+
+impl BatchDecode {
+    /// Whether the whole batch was decoded.
+    #[must_use]
+    pub fn is_ok(&self) -> bool {
+        self.error.is_none()
+    }
+
+    /// The outcome of `seq_id`, `None` if it has no tokens in the batch.
+    #[must_use]
+    pub fn seq(&self, seq_id: i32) -> Option<&SeqDecode> {
+        self.seqs.get(&seq_id)
+    }
+
+    /// The sequences that failed and their errors.
+    pub fn failed(&self) -> impl Iterator<Item = (i32, &DecodeError)> {
+        self.seqs
+            .iter()
+            .filter_map(|(&seq_id, seq)| Some((seq_id, seq.error.as_ref()?)))
+    }
+}
+
+/// The outcome of [`LlamaContext::decode_batch`] for one sequence.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SeqDecode {
+    /// The number of tokens of the sequence in the batch.
+    pub n_tokens: usize,
+    /// The number of those tokens that are in the KV cache now.
+    pub n_accepted: usize,
+    /// The index of the sequence's logits, see [`LlamaBatch::seq_logits_index`]. `None` if it
+    /// requested none or the decode failed: logits are only available after the whole batch was
+    /// decoded, so decode the last token again to get them.
+    pub logits_index: Option<i32>,
+    /// The error if not all tokens of the sequence were decoded.
+    pub error: Option<DecodeError>,
+}
+
+impl SeqDecode {
+    /// Whether every token of the sequence was decoded.
+    #[must_use]
+    pub fn is_ok(&self) -> bool {
+        self.error.is_none()
+    }
+}
+
+impl LlamaContext<'_> {
+    /// [Decode](LlamaContext::decode) `batch` and report the outcome of each of its sequences, see
+    /// the [module docs](self).
+    ///
+    /// If llama.cpp ran out of KV cache slots, the tokens of the ubatches it decoded before stay
+    /// in the KV cache and count as accepted. After any other error, including an
+    /// [abort](DecodeError::Aborted), no token counts as accepted: clear or truncate the failed
+    /// sequences before decoding them again.
+    #[must_use]
+    pub fn decode_batch(&mut self, batch: &mut LlamaBatch) -> BatchDecode {
+        let mut positions: BTreeMap<i32, Vec<llama_pos>> = BTreeMap::new();
+        for i in 0..batch.n_tokens() {
+            let Some((_, pos)) = batch.token_pos(i) else {
+                continue;
+            };
+            for &seq_id in batch.seq_ids(i) {
+                positions.entry(seq_id).or_default().push(pos);
+            }
+        }
+        let pos_max_before: BTreeMap<i32, llama_pos> = positions
+            .keys()
+            .map(|&seq_id| (seq_id, self.kv_cache_seq_pos_max(seq_id)))
+            .collect();
+
+        let error = self.decode(batch).err();
+        let logits = batch.logits_indices();
+        let seqs = positions
+            .into_iter()
+            .map(|(seq_id, positions)| {
+                let n_tokens = positions.len();
+                let n_accepted = match error {
+                    None => n_tokens,
+                    Some(DecodeError::NoKvCacheSlot) => {
+                        let after = self.kv_cache_seq_pos_max(seq_id);
+                        let before = pos_max_before[&seq_id];
+                        positions
+                            .iter()
+                            .filter(|&&pos| pos > before && pos <= after)
+                            .count()
+                    }
+                    Some(_) => 0,
+                };
+                let seq = SeqDecode {
+                    n_tokens,
+                    n_accepted,
+                    logits_index: logits.get(&seq_id).copied().filter(|_| error.is_none()),
+                    error: error.clone().filter(|_| n_accepted < n_tokens),
+                };
+                (seq_id, seq)
+            })
+            .collect();
+        BatchDecode { seqs, error }
+    }
+}
+
+#[cfg(test)]
+mod tests;
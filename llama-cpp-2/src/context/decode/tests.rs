@@ -0,0 +1,73 @@
+use std::num::NonZeroU32;
+
+use super::*;
+use crate::context::params::LlamaContextParams;
+use crate::model::{AddBos, LlamaModel};
+use crate::test_utils::{self, TinyModel};
+use crate::token::LlamaToken;
+
+/// A context of 64 cells, decoded in ubatches of 16 tokens.
+fn context(model: &LlamaModel) -> LlamaContext<'_> {
+    let params = LlamaContextParams::default()
+        .with_n_ctx(NonZeroU32::new(64))
+        .with_n_batch(64)
+        .with_n_ubatch(16)
+        .with_n_seq_max(3);
+    model.new_context(test_utils::backend(), params).unwrap()
+}
+
+fn tokens(model: &LlamaModel, n: usize) -> Vec<LlamaToken> {
+    let cat = model.str_to_token("cat", AddBos::Never).unwrap()[0];
+    vec![cat; n]
+}
+
+#[test]
+fn every_sequence_is_accepted_with_its_logits() {
+    let model = TinyModel::default().load(test_utils::backend()).unwrap();
+    let mut ctx = context(&model);
+    let mut batch = LlamaBatch::new(64, 3);
+    batch.add_sequence_at(&tokens(&model, 5), 0, 0).unwrap();
+    batch.add_sequence_at(&tokens(&model, 3), 0, 1).unwrap();
+
+    let decoded = ctx.decode_batch(&mut batch);
+    assert!(decoded.is_ok());
+    assert_eq!(decoded.seqs.len(), 2);
+    let logits = batch.logits_indices();
+    for (seq_id, n_tokens) in [(0, 5), (1, 3)] {
+        let seq = decoded.seq(seq_id).unwrap();
+        assert!(seq.is_ok());
+        assert_eq!(seq.n_tokens, n_tokens);
+        assert_eq!(seq.n_accepted, n_tokens);
+        assert_eq!(seq.logits_index, Some(logits[&seq_id]));
+        assert!(ctx.try_get_logits_ith(logits[&seq_id]).is_ok());
+    }
+    assert_eq!(decoded.seq(2), None);
+}
+
+#[test]
+fn a_full_cache_keeps_the_ubatches_decoded_before() {
+    let model = TinyModel::default().load(test_utils::backend()).unwrap();
+    let mut ctx = context(&model);
+    let mut batch = LlamaBatch::new(64, 3);
+    batch.add_sequence_at(&tokens(&model, 40), 0, 0).unwrap();
+    assert!(ctx.decode_batch(&mut batch).is_ok());
+
+    // 24 free cells: the first ubatch (seq 1) fits, the second (seq 2) does not
+    batch.clear();
+    batch.add_sequence_at(&tokens(&model, 16), 0, 1).unwrap();
+    batch.add_sequence_at(&tokens(&model, 16), 0, 2).unwrap();
+    let decoded = ctx.decode_batch(&mut batch);
+
+    assert_eq!(decoded.error, Some(DecodeError::NoKvCacheSlot));
+    let accepted = decoded.seq(1).unwrap();
+    assert_eq!(accepted.n_accepted, 16);
+    assert_eq!(accepted.error, None);
+    assert_eq!(accepted.logits_index, None);
+    let failed = decoded.seq(2).unwrap();
+    assert_eq!(failed.n_accepted, 0);
+    assert_eq!(failed.error, Some(DecodeError::NoKvCacheSlot));
+    assert_eq!(
+        decoded.failed().collect::<Vec<_>>(),
+        [(2, &DecodeError::NoKvCacheSlot)]
+    );
+}
@@ -0,0 +1,162 @@
+//! Fill-in-the-middle (infill) generation.
+//!
+//! Code models trained for infilling expect the text before and after the cursor wrapped in
+//! special prefix / suffix / middle tokens. The layout mirrors llama.cpp's `infill` example.
+
+use std::ops::ControlFlow;
+
+use crate::context::LlamaContext;
+use crate::generate::{GenerateError, GenerationParams, SamplingParams};
+use crate::model::AddBos;
+use crate::token::LlamaToken;
+use crate::StringToTokenError;
+
+/// Failed to run an infill.
+#[derive(Debug, thiserror::Error)]
+pub enum InfillError {
+    /// The model has no token for the given part of the fill-in-the-middle layout.
+    #[error("the model has no fill-in-the-middle {0} token")]
+    MissingToken(&'static str),
+    /// Failed to tokenize the prefix or the suffix.
+    #[error("{0}")]
+    StringToToken(#[from] StringToTokenError),
+    /// Failed to generate the middle, e.g. because the prompt does not fit into the context.
+    #[error("{0}")]
+    Generate(#[from] GenerateError),
+}
+
+/// The order in which prefix and suffix are presented to the model.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum InfillMode {
+    /// `<PRE> prefix <SUF> suffix <MID>`. Used by most models.
+    #[default]
+    PrefixSuffixMiddle,
+    /// `<SUF> suffix <PRE> prefix <MID>`. Some models perform better with this order.
+    SuffixPrefixMiddle,
+}
+
+/// Parameters for [`LlamaContext::infill`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct InfillParams {
+    /// The maximum number of tokens to generate.
+    pub max_tokens: usize,
+    /// The order of prefix and suffix.
+    pub mode: InfillMode,
+    /// Whether to start the prompt with a beginning of stream token.
+    pub add_bos: AddBos,
+    /// The sampling chain. Greedy by default.
+    pub sampling: SamplingParams,
+    /// The sequence to generate on. It is cleared before the prompt is decoded.
+    pub seq_id: i32,
+}
+
+impl Default for InfillParams {
+    fn default() -> Self {
+        Self {
+            max_tokens: 256,
+            mode: InfillMode::default(),
+            add_bos: AddBos::Always,
+            sampling: SamplingParams::greedy(),
+            seq_id: 0,
+        }
+    }
+}
+
+impl LlamaContext<'_> {
+    /// Assemble the fill-in-the-middle prompt for `prefix` and `suffix` using the special tokens of
+    /// the model. The beginning of stream and middle tokens are left out if the model has none.
+    ///
+    /// # Errors
+    ///
+    /// - if the model has no prefix or suffix token.
+    /// - if the prefix or suffix can not be tokenized.
+    pub fn infill_tokens(
+        &self,
+        prefix: &str,
+        suffix: &str,
+        mode: InfillMode,
+        add_bos: AddBos,
+    ) -> Result<Vec<LlamaToken>, InfillError> {
+        let prefix_token = self.model.token_prefix();
+        if prefix_token.0 < 0 {
+            return Err(InfillError::MissingToken("prefix"));
+        }
+        let suffix_token = self.model.token_suffix();
+        if suffix_token.0 < 0 {
+            return Err(InfillError::MissingToken("suffix"));
+        }
+
+        let mut prefix_tokens = vec![prefix_token];
+        prefix_tokens.extend(self.model.str_to_token(prefix, AddBos::Never)?);
+        let mut suffix_tokens = vec![suffix_token];
+        suffix_tokens.extend(self.model.str_to_token(suffix, AddBos::Never)?);
+
+        let (first, second) = match mode {
+            InfillMode::PrefixSuffixMiddle => (prefix_tokens, suffix_tokens),
+            InfillMode::SuffixPrefixMiddle => (suffix_tokens, prefix_tokens),
+        };
+
+        let mut tokens = Vec::with_capacity(first.len() + second.len() + 2);
+        let bos_token = self.model.token_bos();
+        if add_bos == AddBos::Always && bos_token.0 >= 0 {
+            tokens.push(bos_token);
+        }
+        tokens.extend(first);
+        tokens.extend(second);
+
+        // not every model has a middle token, in that case generation starts right after the suffix
+        let middle_token = self.model.token_middle();
+        if middle_token.0 >= 0 {
+            tokens.push(middle_token);
+        }
+
+        Ok(tokens)
+    }
+
+    /// Generate the text between `prefix` and `suffix` with [`LlamaContext::generate`].
+    ///
+    /// This uses [`InfillParams::seq_id`] of the context and clears it before starting.
+    /// Generation stops at the end of turn (or any other end of generation) token, or once
+    /// `params.max_tokens` tokens have been generated. A character cut off by `max_tokens` ends
+    /// the text as a replacement character.
+    ///
+    /// llama.cpp's infill sampler, which merges candidates sharing a prefix and prefers ending
+    /// the middle once the end of turn token is likely enough, is part of its `llama_sampler`
+    /// API. The linked llama.cpp only has the `llama_sample_*` functions, so the middle is
+    /// sampled with the regular [`SamplingParams`] chain instead.
+    ///
+    /// # Errors
+    ///
+    /// See [`InfillError`] for more information.
+    ///
+    /// ```no_run
+    /// # use llama_cpp_2::context::infill::InfillParams;
+    /// # use llama_cpp_2::llama_backend::LlamaBackend;
+    /// # use llama_cpp_2::model::LlamaModel;
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// let backend = LlamaBackend::init()?;
+    /// let model = LlamaModel::load_from_file(&backend, "path/to/codellama.gguf", &Default::default())?;
+    /// let mut ctx = model.new_context(&backend, Default::default())?;
+    /// let middle = ctx.infill("fn add(a: i32, b: i32) -> i32 {\n", "\n}", &InfillParams::default())?;
+    /// println!("{middle}");
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn infill(
+        &mut self,
+        prefix: &str,
+        suffix: &str,
+        params: &InfillParams,
+    ) -> Result<String, InfillError> {
+        let tokens = self.infill_tokens(prefix, suffix, params.mode, params.add_bos)?;
+        let mut generation = GenerationParams::default()
+            .with_sampling(params.sampling)
+            .with_max_tokens(params.max_tokens)
+            .with_seq_id(params.seq_id);
+        let generation = self.generate(&tokens, &mut generation, |_| ControlFlow::Continue(()))?;
+        Ok(generation.text)
+    }
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,101 @@
+use std::num::NonZeroU32;
+
+use super::*;
+use crate::context::params::LlamaContextParams;
+use crate::model::LlamaModel;
+use crate::test_utils::{self, TinyModel};
+
+fn context(model: &LlamaModel) -> LlamaContext<'_> {
+    let params = LlamaContextParams::default().with_n_ctx(NonZeroU32::new(64));
+    model.new_context(test_utils::backend(), params).unwrap()
+}
+
+#[test]
+fn a_model_without_fim_tokens_cannot_infill() {
+    let model = TinyModel::default().load(test_utils::backend()).unwrap();
+    let mut ctx = context(&model);
+    let err = ctx
+        .infill_tokens("the cat", "a dog", InfillMode::default(), AddBos::Always)
+        .unwrap_err();
+    assert!(matches!(err, InfillError::MissingToken("prefix")));
+    let err = ctx
+        .infill("the cat", "a dog", &InfillParams::default())
+        .unwrap_err();
+    assert!(matches!(err, InfillError::MissingToken("prefix")));
+}
+
+#[test]
+fn prompt_layout_follows_the_mode() {
+    let model = TinyModel::default()
+        .with_fim_tokens()
+        .load(test_utils::backend())
+        .unwrap();
+    let ctx = context(&model);
+    let prefix = model.str_to_token("the cat", AddBos::Never).unwrap();
+    let suffix = model.str_to_token("a dog", AddBos::Never).unwrap();
+    let (bos, pre, suf, mid) = (
+        model.token_bos(),
+        model.token_prefix(),
+        model.token_suffix(),
+        model.token_middle(),
+    );
+
+    let tokens = ctx
+        .infill_tokens(
+            "the cat",
+            "a dog",
+            InfillMode::PrefixSuffixMiddle,
+            AddBos::Always,
+        )
+        .unwrap();
+    let expected: Vec<_> = [bos, pre]
+        .into_iter()
+        .chain(prefix.iter().copied())
+        .chain([suf])
+        .chain(suffix.iter().copied())
+        .chain([mid])
+        .collect();
+    assert_eq!(tokens, expected);
+
+    let tokens = ctx
+        .infill_tokens(
+            "the cat",
+            "a dog",
+            InfillMode::SuffixPrefixMiddle,
+            AddBos::Never,
+        )
+        .unwrap();
+    let expected: Vec<_> = [suf]
+        .into_iter()
+        .chain(suffix.iter().copied())
+        .chain([pre])
+        .chain(prefix.iter().copied())
+        .chain([mid])
+        .collect();
+    assert_eq!(tokens, expected);
+}
+
+#[test]
+fn infill_generates_the_middle_greedily() {
+    let model = TinyModel::default()
+        .with_fim_tokens()
+        .load(test_utils::backend())
+        .unwrap();
+    let mut ctx = context(&model);
+    let params = InfillParams {
+        max_tokens: 8,
+        ..InfillParams::default()
+    };
+    let middle = ctx.infill("the cat", "a dog", &params).unwrap();
+
+    let prompt = ctx
+        .infill_tokens("the cat", "a dog", params.mode, params.add_bos)
+        .unwrap();
+    let mut generation = GenerationParams::default()
+        .with_sampling(SamplingParams::greedy())
+        .with_max_tokens(8);
+    let expected = ctx
+        .generate(&prompt, &mut generation, |_| ControlFlow::Continue(()))
+        .unwrap();
+    assert_eq!(middle, expected.text);
+}
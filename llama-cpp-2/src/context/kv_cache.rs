@@ -1,6 +1,7 @@
 //! utilities for working with the kv cache
 
 use crate::context::LlamaContext;
+use llama_cpp_sys_2::llama_pos;
 use std::ffi::c_int;
 use std::num::NonZeroU8;
 
@@ -14,6 +15,7 @@ impl LlamaContext<'_> {
     /// * `size` - The size of the cache to copy.
     pub fn copy_cache(&mut self, src: i32, dest: i32, size: i32) {
         unsafe { llama_cpp_sys_2::llama_kv_cache_seq_cp(self.context.as_ptr(), src, dest, 0, size) }
+        self.forget_cached_tokens(dest, 0);
     }
 
     /// Copy the cache from one sequence to another.
@@ -30,6 +32,7 @@ impl LlamaContext<'_> {
         unsafe {
             llama_cpp_sys_2::llama_kv_cache_seq_cp(self.context.as_ptr(), src, dest, p0, p1);
         }
+        self.forget_cached_tokens(dest, p0);
     }
 
     /// Clear the kv cache for the given sequence.
@@ -45,6 +48,7 @@ impl LlamaContext<'_> {
         unsafe {
             llama_cpp_sys_2::llama_kv_cache_seq_rm(self.context.as_ptr(), src, p0, p1);
         }
+        self.forget_cached_tokens(src, p0);
     }
 
     /// Returns the number of used KV cells (i.e. have at least one sequence assigned to them)
@@ -56,6 +60,7 @@ impl LlamaContext<'_> {
     /// Clear the KV cache
     pub fn clear_kv_cache(&mut self) {
         unsafe { llama_cpp_sys_2::llama_kv_cache_clear(self.context.as_ptr()) }
+        self.forget_cached_tokens(-1, 0);
     }
 
     /// Removes all tokens that do not belong to the specified sequence
@@ -65,6 +70,7 @@ impl LlamaContext<'_> {
     /// * `seq_id` - The sequence id to keep
     pub fn llama_kv_cache_seq_keep(&mut self, seq_id: i32) {
         unsafe { llama_cpp_sys_2::llama_kv_cache_seq_keep(self.context.as_ptr(), seq_id) }
+        self.cached_tokens.retain(|&id, _| id == seq_id);
     }
 
     #[allow(clippy::doc_markdown)]
@@ -82,9 +88,21 @@ impl LlamaContext<'_> {
     pub fn kv_cache_seq_add(&mut self, seq_id: i32, p0: Option<u16>, p1: Option<u16>, delta: i32) {
         let p0 = p0.map_or(-1, i32::from);
         let p1 = p1.map_or(-1, i32::from);
+        self.kv_cache_seq_add_pos(seq_id, p0, p1, delta);
+    }
+
+    /// [`Self::kv_cache_seq_add`] with positions of any size, negative for an open end.
+    pub(crate) fn kv_cache_seq_add_pos(
+        &mut self,
+        seq_id: i32,
+        p0: llama_pos,
+        p1: llama_pos,
+        delta: llama_pos,
+    ) {
         unsafe {
             llama_cpp_sys_2::llama_kv_cache_seq_add(self.context.as_ptr(), seq_id, p0, p1, delta);
         }
+        self.forget_cached_tokens(seq_id, p0);
     }
 
     /// Integer division of the positions by factor of `d > 1`
@@ -107,8 +125,84 @@ impl LlamaContext<'_> {
     ) {
         let p0 = p0.map_or(-1, i32::from);
         let p1 = p1.map_or(-1, i32::from);
-        let d = c_int::from(d.get());
+        self.kv_cache_seq_div_pos(seq_id, p0, p1, c_int::from(d.get()));
+    }
+
+    /// [`Self::kv_cache_seq_div`] with positions and a factor of any size, negative positions for
+    /// an open end.
+    pub(crate) fn kv_cache_seq_div_pos(
+        &mut self,
+        seq_id: i32,
+        p0: llama_pos,
+        p1: llama_pos,
+        d: c_int,
+    ) {
         unsafe { llama_cpp_sys_2::llama_kv_cache_seq_div(self.context.as_ptr(), seq_id, p0, p1, d) }
+        self.forget_cached_tokens(seq_id, p0);
+    }
+
+    /// Whether the positions in the KV cache can be shifted with [`Self::kv_cache_seq_add`] (and
+    /// therefore [`Self::kv_cache_shift`]). When this is false, shifting corrupts the cache and
+    /// callers should clear the sequence and decode the kept tokens again instead.
+    ///
+    /// The linked llama.cpp predates `llama_kv_cache_can_shift`, so this applies the same rule on
+    /// the rust side: recurrent models (Mamba, RWKV) have no positional cache to shift and
+    /// DeepSeek2's compressed attention cache does not support shifting.
+    #[must_use]
+    pub fn kv_cache_can_shift(&self) -> bool {
+        // the recurrent architectures of the llama.cpp submodule (`llama_model_is_recurrent`) and
+        // deepseek2, as `llama_kv_cache_can_shift` checks them. Update this list together with
+        // the submodule, architectures it does not know yet fall back to shiftable.
+        const UNSHIFTABLE: [&str; 3] = ["mamba", "rwkv6", "deepseek2"];
+
+        // no architecture, llama.cpp refuses to load such a model in the first place
+        self.model
+            .meta_val_str("general.architecture")
+            .is_none_or(|arch| !UNSHIFTABLE.contains(&arch.as_str()))
+    }
+
+    /// Make room in a full context by discarding half of the tokens after the first `n_keep`, the
+    /// same way `llama-cli` does for infinite generation. Check [`Self::kv_cache_can_shift`] first.
+    ///
+    /// The tokens in `[n_keep, n_keep + n_discard)` are removed and the ones after them are shifted
+    /// back by `n_discard` positions. The caller must subtract the returned `n_discard` from its
+    /// own position counter before decoding the next token.
+    ///
+    /// # Parameters
+    ///
+    /// * `seq_id` - The sequence id to shift
+    /// * `n_keep` - The number of tokens at the start of the sequence to keep (usually the prompt, including bos)
+    /// * `n_past` - The number of tokens currently in the sequence
+    ///
+    /// # Returns
+    ///
+    /// The number of positions that were discarded. `0` if there was nothing to discard.
+    pub fn kv_cache_shift(&mut self, seq_id: i32, n_keep: i32, n_past: i32) -> i32 {
+        let n_left = n_past - n_keep;
+        let n_discard = n_left / 2;
+        if n_discard <= 0 {
+            return 0;
+        }
+
+        unsafe {
+            llama_cpp_sys_2::llama_kv_cache_seq_rm(
+                self.context.as_ptr(),
+                seq_id,
+                n_keep,
+                n_keep + n_discard,
+            );
+            llama_cpp_sys_2::llama_kv_cache_seq_add(
+                self.context.as_ptr(),
+                seq_id,
+                n_keep + n_discard,
+                n_past,
+                -n_discard,
+            );
+        }
+        self.forget_cached_tokens(seq_id, n_keep);
+
+        tracing::debug!(seq_id, n_keep, n_past, n_discard, "Shifted kv cache");
+        n_discard
     }
 
     /// Returns the largest position present in the KV cache for the specified sequence
@@ -154,6 +248,19 @@ impl LlamaContext<'_> {
             unsafe { llama_cpp_sys_2::llama_kv_cache_view_init(self.context.as_ptr(), n_max_seq) };
         KVCacheView { view, ctx: self }
     }
+
+    /// Take a snapshot of the layout of the KV cache, see [`KVCacheDump`]. (use only for debugging
+    /// purposes)
+    ///
+    /// # Parameters
+    ///
+    /// * `n_max_seq` - Maximum number of sequences shown per cell, see [`Self::new_kv_cache_view`].
+    #[must_use]
+    pub fn kv_cache_dump(&self, n_max_seq: i32) -> KVCacheDump {
+        let mut view = self.new_kv_cache_view(n_max_seq);
+        view.update();
+        view.dump()
+    }
 }
 
 /// Information associated with an individual cell in the KV cache view.
@@ -246,6 +353,160 @@ impl<'a> KVCacheView<'a> {
     }
 }
 
+impl KVCacheView<'_> {
+    /// Copy the current state of the view into an owned [`KVCacheDump`].
+    ///
+    /// # Panics
+    ///
+    /// - if `n_cells` or `n_max_seq` does not fit into usize.
+    #[must_use]
+    pub fn dump(&self) -> KVCacheDump {
+        let cells = self
+            .cells()
+            .zip(self.cells_sequences())
+            .map(|(cell, seq_ids)| KVCacheDumpCell {
+                pos: cell.pos,
+                seq_ids: seq_ids.iter().copied().filter(|id| *id >= 0).collect(),
+            })
+            .collect();
+        KVCacheDump {
+            token_count: self.token_count(),
+            used_cells: self.used_cells(),
+            max_contiguous: self.max_contiguous(),
+            max_contiguous_idx: self.max_contiguous_idx(),
+            n_seq_max: self.view.n_seq_max,
+            cells,
+        }
+    }
+}
+
+/// A cell of a [`KVCacheDump`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KVCacheDumpCell {
+    /// The position of the token in this cell. Negative if the cell is empty.
+    pub pos: llama_cpp_sys_2::llama_pos,
+    /// The sequences the token in this cell belongs to.
+    pub seq_ids: Vec<llama_cpp_sys_2::llama_seq_id>,
+}
+
+/// How a sequence is laid out in the KV cache.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KVCacheSequenceLayout {
+    /// The number of cells holding tokens of the sequence.
+    pub n_cells: usize,
+    /// The smallest position of the sequence.
+    pub pos_min: llama_cpp_sys_2::llama_pos,
+    /// The largest position of the sequence.
+    pub pos_max: llama_cpp_sys_2::llama_pos,
+    /// The index of the first cell holding the sequence.
+    pub first_cell: usize,
+    /// The index of the last cell holding the sequence.
+    pub last_cell: usize,
+}
+
+/// An owned snapshot of the KV cache layout, taken with [`KVCacheView::dump`] or
+/// [`LlamaContext::kv_cache_dump`].
+///
+/// The [`Display`](std::fmt::Display) implementation prints a map of the cells in the style of
+/// `llama_kv_cache_dump_view_seqs` from llama.cpp: every cell is printed as `.` if it is empty,
+/// the symbol of its sequence (`0-9`, then `A-Z`, then `#`) if it holds a single one, or `+` if
+/// it is shared. This makes fragmentation visible when decoding fails with no free KV slot.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KVCacheDump {
+    /// The number of tokens in the cache, counting shared cells once per sequence.
+    pub token_count: i32,
+    /// The number of populated cells.
+    pub used_cells: i32,
+    /// The largest run of empty cells.
+    pub max_contiguous: i32,
+    /// The index of the start of the largest run of empty cells.
+    pub max_contiguous_idx: i32,
+    /// The maximum number of sequences recorded per cell.
+    pub n_seq_max: i32,
+    /// Every cell of the cache.
+    pub cells: Vec<KVCacheDumpCell>,
+}
+
+impl KVCacheDump {
+    /// The layout of every sequence in the cache, by sequence id.
+    #[must_use]
+    pub fn sequences(
+        &self,
+    ) -> std::collections::BTreeMap<llama_cpp_sys_2::llama_seq_id, KVCacheSequenceLayout> {
+        let mut sequences = std::collections::BTreeMap::new();
+        for (i, cell) in self.cells.iter().enumerate() {
+            for seq_id in &cell.seq_ids {
+                sequences
+                    .entry(*seq_id)
+                    .and_modify(|layout: &mut KVCacheSequenceLayout| {
+                        layout.n_cells += 1;
+                        layout.pos_min = layout.pos_min.min(cell.pos);
+                        layout.pos_max = layout.pos_max.max(cell.pos);
+                        layout.last_cell = i;
+                    })
+                    .or_insert(KVCacheSequenceLayout {
+                        n_cells: 1,
+                        pos_min: cell.pos,
+                        pos_max: cell.pos,
+                        first_cell: i,
+                        last_cell: i,
+                    });
+            }
+        }
+        sequences
+    }
+}
+
+/// The symbol used for a sequence in the cell map.
+fn sequence_symbol(seq_id: llama_cpp_sys_2::llama_seq_id) -> char {
+    u32::try_from(seq_id)
+        .ok()
+        .and_then(|id| std::char::from_digit(id, 36))
+        .map_or('#', |c| c.to_ascii_uppercase())
+}
+
+impl std::fmt::Display for KVCacheDump {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        const ROW_SIZE: usize = 80;
+
+        writeln!(
+            f,
+            "=== KV cache: total cells {}, max sequences per cell {}, populated cells {}, total tokens in cache {}, largest empty slot={} @ {}",
+            self.cells.len(),
+            self.n_seq_max,
+            self.used_cells,
+            self.token_count,
+            self.max_contiguous,
+            self.max_contiguous_idx,
+        )?;
+        for (seq_id, layout) in self.sequences() {
+            writeln!(
+                f,
+                "seq {seq_id} ({}): {} cells, pos {}..={}, cells {}..={}",
+                sequence_symbol(seq_id),
+                layout.n_cells,
+                layout.pos_min,
+                layout.pos_max,
+                layout.first_cell,
+                layout.last_cell,
+            )?;
+        }
+        for (row, cells) in self.cells.chunks(ROW_SIZE).enumerate() {
+            write!(f, "{:5}: ", row * ROW_SIZE)?;
+            for cell in cells {
+                let symbol = match cell.seq_ids.as_slice() {
+                    [] => '.',
+                    [seq_id] => sequence_symbol(*seq_id),
+                    _ => '+',
+                };
+                write!(f, "{symbol}")?;
+            }
+            writeln!(f)?;
+        }
+        writeln!(f, "=== Done dumping")
+    }
+}
+
 impl<'a> Drop for KVCacheView<'a> {
     fn drop(&mut self) {
         unsafe {
@@ -253,3 +514,6 @@ impl<'a> Drop for KVCacheView<'a> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests;
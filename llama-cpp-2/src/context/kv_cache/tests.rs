@@ -0,0 +1,129 @@
+use super::*;
+use crate::context::params::LlamaContextParams;
+use crate::test_utils::{self, TinyModel};
+
+fn cell(
+    pos: llama_cpp_sys_2::llama_pos,
+    seq_ids: &[llama_cpp_sys_2::llama_seq_id],
+) -> KVCacheDumpCell {
+    KVCacheDumpCell {
+        pos,
+        seq_ids: seq_ids.to_vec(),
+    }
+}
+
+/// Seq 0 at positions 0 and 1, a prefix shared by seqs 0 and 1, an empty cell, seq 1 at
+/// position 3 and seq 40, which has no symbol of its own.
+fn dump() -> KVCacheDump {
+    KVCacheDump {
+        token_count: 6,
+        used_cells: 5,
+        max_contiguous: 1,
+        max_contiguous_idx: 3,
+        n_seq_max: 4,
+        cells: vec![
+            cell(0, &[0]),
+            cell(1, &[0]),
+            cell(2, &[0, 1]),
+            cell(-1, &[]),
+            cell(3, &[1]),
+            cell(0, &[40]),
+        ],
+    }
+}
+
+#[test]
+fn sequences_summarize_their_cells() {
+    let sequences = dump().sequences();
+    assert_eq!(
+        sequences.keys().copied().collect::<Vec<_>>(),
+        vec![0, 1, 40]
+    );
+    assert_eq!(
+        sequences[&0],
+        KVCacheSequenceLayout {
+            n_cells: 3,
+            pos_min: 0,
+            pos_max: 2,
+            first_cell: 0,
+            last_cell: 2,
+        }
+    );
+    assert_eq!(
+        sequences[&1],
+        KVCacheSequenceLayout {
+            n_cells: 2,
+            pos_min: 2,
+            pos_max: 3,
+            first_cell: 2,
+            last_cell: 4,
+        }
+    );
+    assert_eq!(sequences[&40].n_cells, 1);
+}
+
+#[test]
+fn empty_cache_has_no_sequences() {
+    let dump = KVCacheDump {
+        token_count: 0,
+        used_cells: 0,
+        max_contiguous: 2,
+        max_contiguous_idx: 0,
+        n_seq_max: 1,
+        cells: vec![cell(-1, &[]), cell(-1, &[])],
+    };
+    assert!(dump.sequences().is_empty());
+    assert_eq!(
+        dump.to_string().lines().collect::<Vec<_>>(),
+        vec![
+            "=== KV cache: total cells 2, max sequences per cell 1, populated cells 0, total tokens in cache 0, largest empty slot=2 @ 0",
+            "    0: ..",
+            "=== Done dumping",
+        ]
+    );
+}
+
+#[test]
+fn displays_a_map_of_the_cells() {
+    assert_eq!(
+        dump().to_string().lines().collect::<Vec<_>>(),
+        vec![
+            "=== KV cache: total cells 6, max sequences per cell 4, populated cells 5, total tokens in cache 6, largest empty slot=1 @ 3",
+            "seq 0 (0): 3 cells, pos 0..=2, cells 0..=2",
+            "seq 1 (1): 2 cells, pos 2..=3, cells 2..=4",
+            "seq 40 (#): 1 cells, pos 0..=0, cells 5..=5",
+            "    0: 00+.1#",
+            "=== Done dumping",
+        ]
+    );
+}
+
+#[test]
+fn wraps_the_map_every_80_cells() {
+    let dump = KVCacheDump {
+        cells: (0..90).map(|pos| cell(pos, &[11])).collect(),
+        ..dump()
+    };
+    let text = dump.to_string();
+    let map: Vec<&str> = text.lines().skip(2).take(2).collect();
+    assert_eq!(
+        map,
+        vec![
+            format!("    0: {}", "B".repeat(80)),
+            format!("   80: {}", "B".repeat(10)),
+        ]
+    );
+}
+
+#[test]
+fn llama_models_can_shift() {
+    let model = TinyModel::default().load(test_utils::backend()).unwrap();
+    let ctx = model
+        .new_context(test_utils::backend(), LlamaContextParams::default())
+        .unwrap();
+    assert_eq!(
+        model.meta_val_str("general.architecture").as_deref(),
+        Some("llama")
+    );
+    assert!(ctx.kv_cache_can_shift());
+}
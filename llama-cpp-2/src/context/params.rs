@@ -2,6 +2,8 @@
 use std::fmt::Debug;
 use std::num::NonZeroU32;
 
+use crate::model::LlamaModel;
+
 /// A rusty wrapper around `rope_scaling_type`.
 #[repr(i8)]
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
@@ -16,6 +18,105 @@ pub enum RopeScalingType {
     Yarn = 2,
 }
 
+impl RopeScalingType {
+    /// The scaling type the model was trained with, read from its `<arch>.rope.scaling.type`
+    /// metadata. Models without that key use [`RopeScalingType::None`]; unknown values are
+    /// [`RopeScalingType::Unspecified`].
+    ///
+    /// `longrope` (Phi-3 128k and later) is [`RopeScalingType::None`]: llama.cpp has no such
+    /// scaling type and applies the long and short rope factors stored in the model on top of
+    /// unscaled rope, whatever the scaling type is.
+    #[must_use]
+    pub fn of_model(model: &LlamaModel) -> Self {
+        let Some(arch) = model.meta_val_str("general.architecture") else {
+            return Self::None;
+        };
+        Self::from_name(
+            model
+                .meta_val_str(&format!("{arch}.rope.scaling.type"))
+                .as_deref(),
+        )
+    }
+
+    /// The scaling type called `name` in `<arch>.rope.scaling.type`, see
+    /// [`RopeScalingType::of_model`].
+    pub(crate) fn from_name(name: Option<&str>) -> Self {
+        match name {
+            None | Some("none") => Self::None,
+            Some("linear") => Self::Linear,
+            Some("yarn") => Self::Yarn,
+            Some("longrope") => Self::None,
+            Some(_) => Self::Unspecified,
+        }
+    }
+}
+
+/// The rope scaling of [`LlamaContextParams`] does not fit the model.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub enum RopeScalingError {
+    /// The requested scaling conflicts with the one the model was trained with.
+    #[error("model was trained with {trained:?} rope scaling, which {requested:?} cannot replace")]
+    Mismatch {
+        /// The scaling set on the params.
+        requested: RopeScalingType,
+        /// The scaling of the model.
+        trained: RopeScalingType,
+    },
+}
+
+/// A combination of [`LlamaContextParams`] that llama.cpp would reject, abort on or silently run
+/// badly with, see [`LlamaContextParams::validate`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub enum ContextParamsError {
+    /// The physical batch is larger than the logical one.
+    #[error(
+        "n_ubatch ({n_ubatch}) is larger than n_batch ({n_batch}), lower n_ubatch or raise n_batch"
+    )]
+    UbatchLargerThanBatch {
+        /// The physical batch size.
+        n_ubatch: u32,
+        /// The logical batch size.
+        n_batch: u32,
+    },
+    /// The context has no sequences.
+    #[error("n_seq_max is 0, a context needs at least one sequence")]
+    NoSequences,
+    /// There are more sequences than cells in the context, so some could never hold a token.
+    #[error(
+        "n_seq_max ({n_seq_max}) is larger than n_ctx ({n_ctx}), lower n_seq_max or raise n_ctx"
+    )]
+    TooManySequences {
+        /// The number of sequences.
+        n_seq_max: u32,
+        /// The size of the context.
+        n_ctx: u32,
+    },
+    /// The model is an embedding encoder, which only produces embeddings.
+    #[error("the model attends non-causally and only produces embeddings, enable embeddings (see LlamaContextParams::for_embeddings)")]
+    EncoderWithoutEmbeddings,
+    /// A pooling type is set although embeddings are disabled.
+    #[error("pooling type {0:?} is set but embeddings are disabled, enable embeddings or leave the pooling type unspecified")]
+    PoolingWithoutEmbeddings(LlamaPoolingType),
+    /// llama.cpp can only quantize the V cache with flash attention.
+    #[error("the V cache type {type_v} is quantized, which needs flash attention: enable it or use an f16 V cache")]
+    QuantizedVCacheWithoutFlashAttn {
+        /// The `ggml_type` of the V cache.
+        type_v: llama_cpp_sys_2::ggml_type,
+    },
+    /// The context is longer than the model was trained for and no rope scaling is set, so
+    /// positions past the trained context produce garbage.
+    #[error("n_ctx ({n_ctx}) is larger than the {n_ctx_train} tokens the model was trained on, set a rope scaling type (or RopeScalingType::None to run past it anyway, e.g. with self-extend)")]
+    ContextBeyondTraining {
+        /// The size of the context.
+        n_ctx: u32,
+        /// The context size the model was trained on.
+        n_ctx_train: u32,
+    },
+    /// The rope scaling does not fit the model.
+    #[error("{0}")]
+    RopeScaling(#[from] RopeScalingError),
+}
+
 /// Create a `RopeScalingType` from a `c_int` - returns `RopeScalingType::ScalingUnspecified` if
 /// the value is not recognized.
 impl From<i32> for RopeScalingType {
@@ -41,6 +142,63 @@ impl From<RopeScalingType> for i32 {
     }
 }
 
+/// A rusty wrapper around `llama_pooling_type`: how the embeddings of the tokens of a sequence are
+/// combined into the embedding of the sequence.
+#[repr(i8)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum LlamaPoolingType {
+    /// Use the pooling type of the model
+    Unspecified = -1,
+    /// No pooling, only the embeddings of the individual tokens are available
+    None = 0,
+    /// The mean of the token embeddings (nomic-embed, e5)
+    Mean = 1,
+    /// The embedding of the first (`[CLS]`) token (bge)
+    Cls = 2,
+    /// The embedding of the last token
+    Last = 3,
+}
+
+impl LlamaPoolingType {
+    /// The pooling type the model was converted with, read from its `<arch>.pooling_type`
+    /// metadata. Models without that key, such as decoder models, use [`LlamaPoolingType::None`].
+    #[must_use]
+    pub fn of_model(model: &LlamaModel) -> Self {
+        model
+            .meta_val_str("general.architecture")
+            .and_then(|arch| model.meta_val_str(&format!("{arch}.pooling_type")))
+            .and_then(|pooling| pooling.parse::<i32>().ok())
+            .map_or(Self::None, Self::from)
+    }
+}
+
+/// Create a `LlamaPoolingType` from a `c_int` - returns `LlamaPoolingType::Unspecified` if the
+/// value is not recognized.
+impl From<i32> for LlamaPoolingType {
+    fn from(value: i32) -> Self {
+        match value {
+            0 => Self::None,
+            1 => Self::Mean,
+            2 => Self::Cls,
+            3 => Self::Last,
+            _ => Self::Unspecified,
+        }
+    }
+}
+
+/// Create a `c_int` from a `LlamaPoolingType`.
+impl From<LlamaPoolingType> for i32 {
+    fn from(value: LlamaPoolingType) -> Self {
+        match value {
+            LlamaPoolingType::None => 0,
+            LlamaPoolingType::Mean => 1,
+            LlamaPoolingType::Cls => 2,
+            LlamaPoolingType::Last => 3,
+            LlamaPoolingType::Unspecified => -1,
+        }
+    }
+}
+
 /// A safe wrapper around `llama_context_params`.
 ///
 /// Generally this should be created with [`Default::default()`] and then modified with `with_*` methods.
@@ -197,6 +355,37 @@ impl LlamaContextParams {
         self.context_params.n_ubatch
     }
 
+    /// Set the maximum number of sequences, e.g. the number of slots of a
+    /// [`SlotManager`](crate::generate::slots::SlotManager).
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use llama_cpp_2::context::params::LlamaContextParams;
+    /// let params = LlamaContextParams::default()
+    ///     .with_n_seq_max(4);
+    /// assert_eq!(params.n_seq_max(), 4);
+    /// ```
+    #[must_use]
+    pub fn with_n_seq_max(mut self, n_seq_max: u32) -> Self {
+        self.context_params.n_seq_max = n_seq_max;
+        self
+    }
+
+    /// Get the maximum number of sequences
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use llama_cpp_2::context::params::LlamaContextParams;
+    /// let params = LlamaContextParams::default();
+    /// assert_eq!(params.n_seq_max(), 1);
+    /// ```
+    #[must_use]
+    pub fn n_seq_max(&self) -> u32 {
+        self.context_params.n_seq_max
+    }
+
     /// Set the type of rope scaling.
     ///
     /// # Examples
@@ -226,6 +415,21 @@ impl LlamaContextParams {
         RopeScalingType::from(self.context_params.rope_scaling_type)
     }
 
+    /// The rope scaling that is in effect when a context for `model` is created with these params:
+    /// the one set with [`Self::with_rope_scaling_type`], or the model's own if it is
+    /// [`RopeScalingType::Unspecified`].
+    ///
+    /// # Errors
+    ///
+    /// - [`RopeScalingError::Mismatch`] if a model trained with `YaRN` is asked to use no or
+    ///   linear scaling, which breaks its long context positions.
+    pub fn effective_rope_scaling_type(
+        &self,
+        model: &LlamaModel,
+    ) -> Result<RopeScalingType, RopeScalingError> {
+        resolve_rope_scaling(self.rope_scaling_type(), RopeScalingType::of_model(model))
+    }
+
     /// Set the rope frequency base.
     ///
     /// # Examples
@@ -284,6 +488,151 @@ impl LlamaContextParams {
         self.context_params.rope_freq_scale
     }
 
+    /// Set the YaRN extrapolation mix factor. Negative values take the factor from the model.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use llama_cpp_2::context::params::LlamaContextParams;
+    /// let params = LlamaContextParams::default()
+    ///     .with_yarn_ext_factor(0.5);
+    /// assert_eq!(params.yarn_ext_factor(), 0.5);
+    /// ```
+    #[must_use]
+    pub fn with_yarn_ext_factor(mut self, yarn_ext_factor: f32) -> Self {
+        self.context_params.yarn_ext_factor = yarn_ext_factor;
+        self
+    }
+
+    /// Get the YaRN extrapolation mix factor.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// let params = llama_cpp_2::context::params::LlamaContextParams::default();
+    /// assert_eq!(params.yarn_ext_factor(), -1.0);
+    /// ```
+    #[must_use]
+    pub fn yarn_ext_factor(&self) -> f32 {
+        self.context_params.yarn_ext_factor
+    }
+
+    /// Set the YaRN magnitude scaling factor.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use llama_cpp_2::context::params::LlamaContextParams;
+    /// let params = LlamaContextParams::default()
+    ///     .with_yarn_attn_factor(0.8);
+    /// assert_eq!(params.yarn_attn_factor(), 0.8);
+    /// ```
+    #[must_use]
+    pub fn with_yarn_attn_factor(mut self, yarn_attn_factor: f32) -> Self {
+        self.context_params.yarn_attn_factor = yarn_attn_factor;
+        self
+    }
+
+    /// Get the YaRN magnitude scaling factor.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// let params = llama_cpp_2::context::params::LlamaContextParams::default();
+    /// assert_eq!(params.yarn_attn_factor(), 1.0);
+    /// ```
+    #[must_use]
+    pub fn yarn_attn_factor(&self) -> f32 {
+        self.context_params.yarn_attn_factor
+    }
+
+    /// Set the YaRN low correction dimension.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use llama_cpp_2::context::params::LlamaContextParams;
+    /// let params = LlamaContextParams::default()
+    ///     .with_yarn_beta_fast(16.0);
+    /// assert_eq!(params.yarn_beta_fast(), 16.0);
+    /// ```
+    #[must_use]
+    pub fn with_yarn_beta_fast(mut self, yarn_beta_fast: f32) -> Self {
+        self.context_params.yarn_beta_fast = yarn_beta_fast;
+        self
+    }
+
+    /// Get the YaRN low correction dimension.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// let params = llama_cpp_2::context::params::LlamaContextParams::default();
+    /// assert_eq!(params.yarn_beta_fast(), 32.0);
+    /// ```
+    #[must_use]
+    pub fn yarn_beta_fast(&self) -> f32 {
+        self.context_params.yarn_beta_fast
+    }
+
+    /// Set the YaRN high correction dimension.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use llama_cpp_2::context::params::LlamaContextParams;
+    /// let params = LlamaContextParams::default()
+    ///     .with_yarn_beta_slow(2.0);
+    /// assert_eq!(params.yarn_beta_slow(), 2.0);
+    /// ```
+    #[must_use]
+    pub fn with_yarn_beta_slow(mut self, yarn_beta_slow: f32) -> Self {
+        self.context_params.yarn_beta_slow = yarn_beta_slow;
+        self
+    }
+
+    /// Get the YaRN high correction dimension.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// let params = llama_cpp_2::context::params::LlamaContextParams::default();
+    /// assert_eq!(params.yarn_beta_slow(), 1.0);
+    /// ```
+    #[must_use]
+    pub fn yarn_beta_slow(&self) -> f32 {
+        self.context_params.yarn_beta_slow
+    }
+
+    /// Set the original context size the model was trained with, used by YaRN. `0` takes it from the model.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use llama_cpp_2::context::params::LlamaContextParams;
+    /// let params = LlamaContextParams::default()
+    ///     .with_yarn_orig_ctx(4096);
+    /// assert_eq!(params.yarn_orig_ctx(), 4096);
+    /// ```
+    #[must_use]
+    pub fn with_yarn_orig_ctx(mut self, yarn_orig_ctx: u32) -> Self {
+        self.context_params.yarn_orig_ctx = yarn_orig_ctx;
+        self
+    }
+
+    /// Get the original context size the model was trained with, used by YaRN.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// let params = llama_cpp_2::context::params::LlamaContextParams::default();
+    /// assert_eq!(params.yarn_orig_ctx(), 0);
+    /// ```
+    #[must_use]
+    pub fn yarn_orig_ctx(&self) -> u32 {
+        self.context_params.yarn_orig_ctx
+    }
+
     /// Get the number of threads.
     ///
     /// # Examples
@@ -371,6 +720,175 @@ impl LlamaContextParams {
         self
     }
 
+    /// Set how the token embeddings of a sequence are pooled. The default,
+    /// [`LlamaPoolingType::Unspecified`], uses the model's.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use llama_cpp_2::context::params::{LlamaContextParams, LlamaPoolingType};
+    /// let params = LlamaContextParams::default()
+    ///     .with_pooling_type(LlamaPoolingType::Mean);
+    /// assert_eq!(params.pooling_type(), LlamaPoolingType::Mean);
+    /// ```
+    #[must_use]
+    pub fn with_pooling_type(mut self, pooling_type: LlamaPoolingType) -> Self {
+        self.context_params.pooling_type = i32::from(pooling_type);
+        self
+    }
+
+    /// Get how the token embeddings of a sequence are pooled.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use llama_cpp_2::context::params::{LlamaContextParams, LlamaPoolingType};
+    /// let params = LlamaContextParams::default();
+    /// assert_eq!(params.pooling_type(), LlamaPoolingType::Unspecified);
+    /// ```
+    #[must_use]
+    pub fn pooling_type(&self) -> LlamaPoolingType {
+        LlamaPoolingType::from(self.context_params.pooling_type)
+    }
+
+    /// Use flash attention, which saves memory and is needed for a quantized V cache.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use llama_cpp_2::context::params::LlamaContextParams;
+    /// let params = LlamaContextParams::default().with_flash_attn(true);
+    /// assert!(params.flash_attn());
+    /// ```
+    #[must_use]
+    pub fn with_flash_attn(mut self, flash_attn: bool) -> Self {
+        self.context_params.flash_attn = flash_attn;
+        self
+    }
+
+    /// Whether flash attention is used.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// let params = llama_cpp_2::context::params::LlamaContextParams::default();
+    /// assert!(!params.flash_attn());
+    /// ```
+    #[must_use]
+    pub fn flash_attn(&self) -> bool {
+        self.context_params.flash_attn
+    }
+
+    /// Set the `ggml_type` of the K cache, e.g. [`llama_cpp_sys_2::GGML_TYPE_Q8_0`] to halve its
+    /// size.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use llama_cpp_2::context::params::LlamaContextParams;
+    /// let params = LlamaContextParams::default().with_type_k(llama_cpp_sys_2::GGML_TYPE_Q8_0);
+    /// assert_eq!(params.type_k(), llama_cpp_sys_2::GGML_TYPE_Q8_0);
+    /// ```
+    #[must_use]
+    pub fn with_type_k(mut self, type_k: llama_cpp_sys_2::ggml_type) -> Self {
+        self.context_params.type_k = type_k;
+        self
+    }
+
+    /// Get the `ggml_type` of the K cache, [`llama_cpp_sys_2::GGML_TYPE_F16`] by default.
+    #[must_use]
+    pub fn type_k(&self) -> llama_cpp_sys_2::ggml_type {
+        self.context_params.type_k
+    }
+
+    /// Set the `ggml_type` of the V cache. Quantized types need
+    /// [flash attention](Self::with_flash_attn).
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use llama_cpp_2::context::params::LlamaContextParams;
+    /// let params = LlamaContextParams::default()
+    ///     .with_flash_attn(true)
+    ///     .with_type_v(llama_cpp_sys_2::GGML_TYPE_Q8_0);
+    /// assert_eq!(params.type_v(), llama_cpp_sys_2::GGML_TYPE_Q8_0);
+    /// ```
+    #[must_use]
+    pub fn with_type_v(mut self, type_v: llama_cpp_sys_2::ggml_type) -> Self {
+        self.context_params.type_v = type_v;
+        self
+    }
+
+    /// Get the `ggml_type` of the V cache, [`llama_cpp_sys_2::GGML_TYPE_F16`] by default.
+    #[must_use]
+    pub fn type_v(&self) -> llama_cpp_sys_2::ggml_type {
+        self.context_params.type_v
+    }
+
+    /// Set the fragmentation threshold of the KV cache. When more than this fraction of the cache
+    /// is fragmented it is defragmented automatically before the next decode. A negative value
+    /// (the default) disables automatic defragmentation.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use llama_cpp_2::context::params::LlamaContextParams;
+    /// let params = LlamaContextParams::default()
+    ///    .with_defrag_threshold(0.1);
+    /// assert_eq!(params.defrag_threshold(), 0.1);
+    /// ```
+    #[must_use]
+    pub fn with_defrag_threshold(mut self, defrag_threshold: f32) -> Self {
+        self.context_params.defrag_thold = defrag_threshold;
+        self
+    }
+
+    /// Get the fragmentation threshold of the KV cache.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// let params = llama_cpp_2::context::params::LlamaContextParams::default();
+    /// assert_eq!(params.defrag_threshold(), -1.0);
+    /// ```
+    #[must_use]
+    pub fn defrag_threshold(&self) -> f32 {
+        self.context_params.defrag_thold
+    }
+
+    /// Keep the KV cache and the attention ops on the GPU (the default) or, with `false`, in host
+    /// memory while the offloaded layers stay on the GPU. Saves VRAM at the cost of speed.
+    ///
+    /// The linked llama.cpp predates the separate op offload and unified KV cache toggles, so
+    /// this is the only offload control of the context.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use llama_cpp_2::context::params::LlamaContextParams;
+    /// let params = LlamaContextParams::default()
+    ///    .with_offload_kqv(false);
+    /// assert!(!params.offload_kqv());
+    /// ```
+    #[must_use]
+    pub fn with_offload_kqv(mut self, offload_kqv: bool) -> Self {
+        self.context_params.offload_kqv = offload_kqv;
+        self
+    }
+
+    /// Check whether the KV cache is offloaded to the GPU.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// let params = llama_cpp_2::context::params::LlamaContextParams::default();
+    /// assert!(params.offload_kqv());
+    /// ```
+    #[must_use]
+    pub fn offload_kqv(&self) -> bool {
+        self.context_params.offload_kqv
+    }
+
     /// Set the evaluation callback.
     ///
     /// # Examples
@@ -411,6 +929,138 @@ impl LlamaContextParams {
         self.context_params.cb_eval_user_data = cb_eval_user_data;
         self
     }
+
+    /// Parameters for embedding with `model`, including encoder-only models such as bge,
+    /// nomic-embed and e5 that llama.cpp otherwise asserts on:
+    ///
+    /// - embeddings are enabled and the context is as long as the model was trained for,
+    /// - models with non-causal attention get `n_batch` and `n_ubatch` of the whole context,
+    ///   since every sequence has to be decoded in a single ubatch,
+    /// - models without a pooling type of their own are [mean pooled](LlamaPoolingType::Mean),
+    ///   so [`LlamaContext::embeddings_seq_ith`](crate::context::LlamaContext::embeddings_seq_ith)
+    ///   always has an embedding.
+    ///
+    /// Raise [`n_seq_max`](Self::with_n_seq_max) to embed several texts per decode with
+    /// [`LlamaContext::embed_batch`](crate::context::LlamaContext::embed_batch). The tokenizers of
+    /// these models (WPM for BERT, UGM for T5) add their `[CLS]` / `[SEP]` style tokens with
+    /// [`AddBos::Always`](crate::model::AddBos::Always).
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use llama_cpp_2::context::params::LlamaContextParams;
+    /// # use llama_cpp_2::llama_backend::LlamaBackend;
+    /// # use llama_cpp_2::model::LlamaModel;
+    /// # fn run(backend: &LlamaBackend, model: &LlamaModel) -> Result<(), Box<dyn std::error::Error>> {
+    /// let params = LlamaContextParams::for_embeddings(model).with_n_seq_max(8);
+    /// let mut ctx = model.new_context(backend, params)?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    #[must_use]
+    pub fn for_embeddings(model: &LlamaModel) -> Self {
+        let n_ctx = model.n_ctx_train();
+        let mut params = Self::default()
+            .with_embeddings(true)
+            .with_n_ctx(NonZeroU32::new(n_ctx));
+        if !model.has_causal_attention() {
+            params = params.with_n_batch(n_ctx).with_n_ubatch(n_ctx);
+        }
+        if matches!(
+            LlamaPoolingType::of_model(model),
+            LlamaPoolingType::None | LlamaPoolingType::Unspecified
+        ) {
+            params = params.with_pooling_type(LlamaPoolingType::Mean);
+        }
+        params
+    }
+
+    /// Check that the params fit together and fit `model`, before llama.cpp aborts the process
+    /// or silently misbehaves on them. [`LlamaModel::new_context`] calls this.
+    ///
+    /// # Errors
+    ///
+    /// The first problem found, see [`ContextParamsError`]. Each names the params to change.
+    pub fn validate(&self, model: &LlamaModel) -> Result<(), ContextParamsError> {
+        let (n_batch, n_ubatch) = (self.n_batch(), self.n_ubatch());
+        if n_ubatch > n_batch {
+            return Err(ContextParamsError::UbatchLargerThanBatch { n_ubatch, n_batch });
+        }
+        let n_ctx_train = model.n_ctx_train();
+        let n_ctx = self.n_ctx().map_or(n_ctx_train, NonZeroU32::get);
+        match self.n_seq_max() {
+            0 => return Err(ContextParamsError::NoSequences),
+            n_seq_max if n_seq_max > n_ctx => {
+                return Err(ContextParamsError::TooManySequences { n_seq_max, n_ctx })
+            }
+            _ => {}
+        }
+        if !self.embeddings() {
+            if !model.has_causal_attention() {
+                return Err(ContextParamsError::EncoderWithoutEmbeddings);
+            }
+            let pooling_type = self.pooling_type();
+            if pooling_type != LlamaPoolingType::Unspecified {
+                return Err(ContextParamsError::PoolingWithoutEmbeddings(pooling_type));
+            }
+        }
+        let type_v = self.type_v();
+        if !self.flash_attn() && unsafe { llama_cpp_sys_2::ggml_is_quantized(type_v) } {
+            return Err(ContextParamsError::QuantizedVCacheWithoutFlashAttn { type_v });
+        }
+        let rope_scaling = self.effective_rope_scaling_type(model)?;
+        // an explicit `None` opts out, e.g. for self-extend
+        if n_ctx > n_ctx_train
+            && n_ctx_train > 0
+            && self.rope_scaling_type() == RopeScalingType::Unspecified
+            && rope_scaling == RopeScalingType::None
+            && self.rope_freq_scale() == 0.0
+        {
+            return Err(ContextParamsError::ContextBeyondTraining { n_ctx, n_ctx_train });
+        }
+        Ok(())
+    }
+
+    /// Parameters for reproducible generations, to pair with
+    /// [`SamplingParams::deterministic`](crate::generate::SamplingParams::deterministic).
+    ///
+    /// The context's own random number generator (used by samplers that take a context, such as
+    /// [`LlamaTokenDataArray::sample_token`](crate::token::data_array::LlamaTokenDataArray::sample_token))
+    /// is seeded with `0`, the thread counts are fixed instead of depending on the machine and the
+    /// prompt is always split into the same physical batches (`n_ubatch == n_batch`). llama.cpp's
+    /// CPU backend then computes the same logits on every run; GPU backends may not, so offload no
+    /// layers if the output must be bit-identical.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use llama_cpp_2::context::params::LlamaContextParams;
+    /// let params = LlamaContextParams::deterministic();
+    /// assert_eq!(params.seed(), 0);
+    /// assert_eq!(params.n_threads(), params.n_threads_batch());
+    /// assert_eq!(params.n_ubatch(), params.n_batch());
+    /// ```
+    #[must_use]
+    pub fn deterministic() -> Self {
+        let params = Self::default().with_seed(0).with_n_threads(4);
+        let n_batch = params.n_batch();
+        params.with_n_threads_batch(4).with_n_ubatch(n_batch)
+    }
+}
+
+/// The rope scaling in effect when `requested` is set for a model `trained` with a scaling, see
+/// [`LlamaContextParams::effective_rope_scaling_type`].
+fn resolve_rope_scaling(
+    requested: RopeScalingType,
+    trained: RopeScalingType,
+) -> Result<RopeScalingType, RopeScalingError> {
+    match requested {
+        RopeScalingType::Unspecified => Ok(trained),
+        RopeScalingType::None | RopeScalingType::Linear if trained == RopeScalingType::Yarn => {
+            Err(RopeScalingError::Mismatch { requested, trained })
+        }
+        RopeScalingType::None | RopeScalingType::Linear | RopeScalingType::Yarn => Ok(requested),
+    }
 }
 
 /// Default parameters for `LlamaContext`. (as defined in llama.cpp by `llama_context_default_params`)
@@ -427,3 +1077,6 @@ impl Default for LlamaContextParams {
         Self { context_params }
     }
 }
+
+#[cfg(test)]
+mod tests;
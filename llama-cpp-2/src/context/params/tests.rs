@@ -0,0 +1,80 @@
+use super::*;
+
+#[test]
+fn reads_scaling_type_names() {
+    assert_eq!(RopeScalingType::from_name(None), RopeScalingType::None);
+    assert_eq!(
+        RopeScalingType::from_name(Some("none")),
+        RopeScalingType::None
+    );
+    assert_eq!(
+        RopeScalingType::from_name(Some("linear")),
+        RopeScalingType::Linear
+    );
+    assert_eq!(
+        RopeScalingType::from_name(Some("yarn")),
+        RopeScalingType::Yarn
+    );
+    assert_eq!(
+        RopeScalingType::from_name(Some("something-new")),
+        RopeScalingType::Unspecified
+    );
+}
+
+#[test]
+fn longrope_uses_unscaled_rope() {
+    let trained = RopeScalingType::from_name(Some("longrope"));
+    assert_eq!(trained, RopeScalingType::None);
+    assert_eq!(
+        resolve_rope_scaling(RopeScalingType::Unspecified, trained),
+        Ok(RopeScalingType::None)
+    );
+    assert_eq!(
+        resolve_rope_scaling(RopeScalingType::Linear, trained),
+        Ok(RopeScalingType::Linear)
+    );
+}
+
+#[test]
+fn unspecified_scaling_uses_the_trained_one() {
+    for trained in [
+        RopeScalingType::None,
+        RopeScalingType::Linear,
+        RopeScalingType::Yarn,
+    ] {
+        assert_eq!(
+            resolve_rope_scaling(RopeScalingType::Unspecified, trained),
+            Ok(trained)
+        );
+    }
+}
+
+#[test]
+fn yarn_models_reject_no_or_linear_scaling() {
+    for requested in [RopeScalingType::None, RopeScalingType::Linear] {
+        assert_eq!(
+            resolve_rope_scaling(requested, RopeScalingType::Yarn),
+            Err(RopeScalingError::Mismatch {
+                requested,
+                trained: RopeScalingType::Yarn
+            })
+        );
+    }
+    assert_eq!(
+        resolve_rope_scaling(RopeScalingType::Yarn, RopeScalingType::None),
+        Ok(RopeScalingType::Yarn)
+    );
+}
+
+#[test]
+fn scaling_types_round_trip_through_c_ints() {
+    for scaling in [
+        RopeScalingType::Unspecified,
+        RopeScalingType::None,
+        RopeScalingType::Linear,
+        RopeScalingType::Yarn,
+    ] {
+        assert_eq!(RopeScalingType::from(i32::from(scaling)), scaling);
+    }
+    assert_eq!(RopeScalingType::from(3), RopeScalingType::Unspecified);
+}
@@ -1,10 +1,17 @@
 //! Sampling functions for the context.
 
+use std::sync::Arc;
+
 use crate::context::LlamaContext;
+use crate::grammar::mask::{self, TokenMask};
 use crate::grammar::LlamaGrammar;
+use crate::token::data::LlamaTokenData;
 use crate::token::data_array::LlamaTokenDataArray;
 use crate::token::LlamaToken;
+use crate::SamplerError;
 
+pub mod logit_bias;
+pub mod logits_processor;
 #[cfg(feature = "sampler")]
 pub mod sampler;
 
@@ -18,13 +25,49 @@ impl LlamaContext<'_> {
                 token.0,
             );
         }
+        grammar.state = mask::advance(grammar.state, token);
     }
 
     /// Perform grammar sampling.
+    ///
+    /// If the grammar has a [mask cache](crate::grammar::mask), the allowed tokens of its state
+    /// are taken from the cache, or computed for the whole vocabulary and cached.
     pub fn sample_grammar(
         &mut self,
         llama_token_data_array: &mut LlamaTokenDataArray,
         llama_grammar: &LlamaGrammar,
+    ) {
+        let Some(masks) = &llama_grammar.masks else {
+            self.sample_grammar_uncached(llama_token_data_array, llama_grammar);
+            return;
+        };
+        let n_vocab = usize::try_from(self.model.n_vocab()).unwrap_or(0);
+        let mask = masks.get(llama_grammar.state, n_vocab).unwrap_or_else(|| {
+            let mut vocabulary = LlamaTokenDataArray::from_logits(&vec![0.0; n_vocab]);
+            self.sample_grammar_uncached(&mut vocabulary, llama_grammar);
+            let mask = Arc::new(TokenMask::from_candidates(n_vocab, &vocabulary));
+            masks.insert(llama_grammar.state, Arc::clone(&mask));
+            mask
+        });
+        mask.apply(llama_token_data_array);
+    }
+
+    /// Whether `grammar` allows `token` next, without checking the rest of the vocabulary.
+    pub(crate) fn grammar_allows_token(
+        &mut self,
+        grammar: &LlamaGrammar,
+        token: LlamaToken,
+    ) -> bool {
+        let mut candidates =
+            LlamaTokenDataArray::new(vec![LlamaTokenData::new(token, 0.0, 0.0)], false);
+        self.sample_grammar(&mut candidates, grammar);
+        candidates.data[0].logit() > f32::NEG_INFINITY
+    }
+
+    fn sample_grammar_uncached(
+        &mut self,
+        llama_token_data_array: &mut LlamaTokenDataArray,
+        llama_grammar: &LlamaGrammar,
     ) {
         unsafe {
             llama_token_data_array.modify_as_c_llama_token_data_array(|c_llama_token_data_array| {
@@ -50,8 +93,28 @@ impl LlamaContext<'_> {
     ///
     /// - if `token_data` is empty
     #[must_use]
-    pub fn sample_token_greedy(&mut self, mut token_data: LlamaTokenDataArray) -> LlamaToken {
-        assert!(!token_data.data.is_empty(), "no tokens");
+    #[deprecated(
+        since = "0.1.70",
+        note = "panics if `token_data` is empty, use `try_sample_token_greedy` instead"
+    )]
+    pub fn sample_token_greedy(&mut self, token_data: LlamaTokenDataArray) -> LlamaToken {
+        self.try_sample_token_greedy(token_data).expect("no tokens")
+    }
+
+    /// Sample a token greedily, returning an error instead of panicking if `token_data` is empty.
+    ///
+    /// See [`LlamaContext::sample_token_greedy`].
+    ///
+    /// # Errors
+    ///
+    /// - if `token_data` is empty
+    pub fn try_sample_token_greedy(
+        &mut self,
+        mut token_data: LlamaTokenDataArray,
+    ) -> Result<LlamaToken, SamplerError> {
+        if token_data.data.is_empty() {
+            return Err(SamplerError::EmptyCandidates);
+        }
         let mut data_arr = llama_cpp_sys_2::llama_token_data_array {
             data: token_data
                 .data
@@ -66,7 +129,7 @@ impl LlamaContext<'_> {
                 std::ptr::addr_of_mut!(data_arr),
             )
         };
-        LlamaToken(token)
+        Ok(LlamaToken(token))
     }
 
     /// See [`LlamaTokenDataArray::sample_tail_free`]
@@ -0,0 +1,127 @@
+//! Bias the logits of words and phrases instead of token ids.
+//!
+//! Users think of biases as "never say *sorry*" or "prefer *yes*", but a bias applies to the
+//! logits of tokens. [`LogitBias::from_words`] tokenizes each word and biases the first or all of
+//! its tokens, see [`MultiTokenBias`], and runs as a [`LogitsProcessor`]:
+//!
+//! ```no_run
+//! # use std::collections::HashMap;
+//! # use llama_cpp_2::context::sample::logit_bias::{LogitBias, MultiTokenBias};
+//! # use llama_cpp_2::generate::GenerationParams;
+//! # use llama_cpp_2::model::LlamaModel;
+//! # fn run(model: &LlamaModel) -> Result<(), Box<dyn std::error::Error>> {
+//! let words = HashMap::from([(" sorry", f32::NEG_INFINITY), (" yes", 2.0)]);
+//! let bias = LogitBias::from_words(model, &words, MultiTokenBias::FirstToken)?;
+//! let params = GenerationParams::default().with_logits_processor(bias);
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Tokenizers split a word differently depending on what precedes it, most commonly with or
+//! without a leading space, so a key is only biased in the form it is written in.
+
+use std::collections::{BTreeMap, HashMap};
+
+use crate::context::sample::logits_processor::LogitsProcessor;
+use crate::model::{AddBos, LlamaModel};
+use crate::token::LlamaToken;
+use crate::StringToTokenError;
+
+/// Which tokens of a word that is more than one token long are biased.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MultiTokenBias {
+    /// Only the first token. Banning a word this way also bans every other word starting with
+    /// that token, but biasing the first token is what decides whether the word starts at all.
+    #[default]
+    FirstToken,
+    /// Every token of the word. A negative bias then also discourages words sharing any of its
+    /// tokens.
+    AllTokens,
+}
+
+/// Adds a bias to the logits of tokens, see the [module docs](self).
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LogitBias {
+    biases: BTreeMap<LlamaToken, f32>,
+}
+
+impl LogitBias {
+    /// Bias each token by its value. Biases of a token given more than once add up.
+    ///
+    /// ```
+    /// # use llama_cpp_2::context::sample::logit_bias::LogitBias;
+    /// # use llama_cpp_2::context::sample::logits_processor::LogitsProcessor;
+    /// # use llama_cpp_2::token::LlamaToken;
+    /// let mut bias = LogitBias::new([
+    ///     (LlamaToken::new(0), f32::NEG_INFINITY),
+    ///     (LlamaToken::new(2), 1.0),
+    ///     (LlamaToken::new(2), 0.5),
+    /// ]);
+    /// let mut logits = vec![1.0, 2.0, 3.0];
+    /// bias.process(&mut logits, &[]);
+    /// assert_eq!(logits, vec![f32::NEG_INFINITY, 2.0, 4.5]);
+    /// ```
+    #[must_use]
+    pub fn new(biases: impl IntoIterator<Item = (LlamaToken, f32)>) -> Self {
+        let mut bias = Self::default();
+        bias.extend(biases);
+        bias
+    }
+
+    /// Tokenize every word of `words` with `model` and bias its tokens according to `policy`.
+    ///
+    /// # Errors
+    ///
+    /// If a word cannot be tokenized.
+    pub fn from_words<S: AsRef<str>>(
+        model: &LlamaModel,
+        words: &HashMap<S, f32>,
+        policy: MultiTokenBias,
+    ) -> Result<Self, StringToTokenError> {
+        let mut bias = Self::default();
+        for (word, &value) in words {
+            let tokens = model.str_to_token(word.as_ref(), AddBos::Never)?;
+            let tokens = match policy {
+                MultiTokenBias::FirstToken => &tokens[..tokens.len().min(1)],
+                MultiTokenBias::AllTokens => &tokens[..],
+            };
+            bias.extend(tokens.iter().map(|&token| (token, value)));
+        }
+        Ok(bias)
+    }
+
+    /// Add the biases of `biases`.
+    pub fn extend(&mut self, biases: impl IntoIterator<Item = (LlamaToken, f32)>) {
+        for (token, value) in biases {
+            *self.biases.entry(token).or_insert(0.0) += value;
+        }
+    }
+
+    /// The token-level biases, ordered by token.
+    #[must_use]
+    pub fn biases(&self) -> Vec<(LlamaToken, f32)> {
+        self.biases
+            .iter()
+            .map(|(&token, &value)| (token, value))
+            .collect()
+    }
+
+    /// Whether no token is biased.
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.biases.is_empty()
+    }
+}
+
+impl LogitsProcessor for LogitBias {
+    fn process(&mut self, logits: &mut [f32], _history: &[LlamaToken]) {
+        for (token, value) in &self.biases {
+            if let Some(logit) = usize::try_from(token.0)
+                .ok()
+                .and_then(|id| logits.get_mut(id))
+            {
+                *logit += value;
+            }
+        }
+    }
+}
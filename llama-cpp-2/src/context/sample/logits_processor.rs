@@ -0,0 +1,148 @@
+//! Hooks to modify the raw logits of a token before any sampling happens.
+//!
+//! A [`LogitsProcessor`] sees the full logits row (one value per token in the vocabulary) and the
+//! tokens generated so far. This is the place for interventions that need every logit, such as
+//! watermarking or masking tokens based on an external state machine.
+//!
+//! # Example
+//!
+//! ```
+//! # use llama_cpp_2::context::sample::logits_processor::LogitsProcessor;
+//! # use llama_cpp_2::token::LlamaToken;
+//! // never produce token 0 and never repeat the previous token
+//! let mut processors: Vec<Box<dyn LogitsProcessor>> = vec![
+//!     Box::new(|logits: &mut [f32], _: &[LlamaToken]| logits[0] = f32::NEG_INFINITY),
+//!     Box::new(|logits: &mut [f32], history: &[LlamaToken]| {
+//!         if let Some(last) = history.last() {
+//!             logits[last.0 as usize] = f32::NEG_INFINITY;
+//!         }
+//!     }),
+//! ];
+//!
+//! let mut logits = vec![1.0, 2.0, 3.0];
+//! processors.process(&mut logits, &[LlamaToken::new(2)]);
+//! assert_eq!(logits, vec![f32::NEG_INFINITY, 2.0, f32::NEG_INFINITY]);
+//! ```
+
+use crate::context::LlamaContext;
+use crate::model::LlamaModel;
+use crate::token::LlamaToken;
+use crate::LogitsError;
+
+/// Modifies the raw logits of a token before sampling.
+pub trait LogitsProcessor {
+    /// Modify `logits` in place. `history` is the tokens of the sequence so far.
+    fn process(&mut self, logits: &mut [f32], history: &[LlamaToken]);
+}
+
+impl<F> LogitsProcessor for F
+where
+    F: FnMut(&mut [f32], &[LlamaToken]),
+{
+    fn process(&mut self, logits: &mut [f32], history: &[LlamaToken]) {
+        self(logits, history);
+    }
+}
+
+/// Runs the processors in order.
+impl LogitsProcessor for Vec<Box<dyn LogitsProcessor>> {
+    fn process(&mut self, logits: &mut [f32], history: &[LlamaToken]) {
+        for processor in self {
+            processor.process(logits, history);
+        }
+    }
+}
+
+/// Bans the end of generation tokens of a model so generation only ends on a stopping criterion,
+/// like llama.cpp's `--ignore-eos`.
+///
+/// llama.cpp no longer bans the end of sequence token as part of the penalties, this is the
+/// separate stage that does. Add it with
+/// [`GenerationParams::with_ignore_eos`](crate::generate::GenerationParams::with_ignore_eos),
+/// before the other processors so they see the banned logits.
+///
+/// ```
+/// # use llama_cpp_2::context::sample::logits_processor::{IgnoreEos, LogitsProcessor};
+/// # use llama_cpp_2::token::LlamaToken;
+/// let mut ignore_eos = IgnoreEos::from_tokens([LlamaToken::new(2)]);
+/// let mut logits = vec![1.0, 2.0, 3.0];
+/// ignore_eos.process(&mut logits, &[]);
+/// assert_eq!(logits, vec![1.0, 2.0, f32::NEG_INFINITY]);
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IgnoreEos {
+    tokens: Vec<LlamaToken>,
+}
+
+impl IgnoreEos {
+    /// Ban every token of `model` that [ends generation](LlamaModel::is_eog_token). The
+    /// vocabulary is scanned once here rather than on every token.
+    #[must_use]
+    pub fn new(model: &LlamaModel) -> Self {
+        Self::from_tokens(
+            (0..model.n_vocab())
+                .map(LlamaToken::new)
+                .filter(|&token| model.is_eog_token(token)),
+        )
+    }
+
+    /// Ban `tokens`.
+    #[must_use]
+    pub fn from_tokens(tokens: impl IntoIterator<Item = LlamaToken>) -> Self {
+        Self {
+            tokens: tokens.into_iter().collect(),
+        }
+    }
+
+    /// The banned tokens.
+    #[must_use]
+    pub fn tokens(&self) -> &[LlamaToken] {
+        &self.tokens
+    }
+}
+
+impl LogitsProcessor for IgnoreEos {
+    fn process(&mut self, logits: &mut [f32], _history: &[LlamaToken]) {
+        for token in &self.tokens {
+            if let Some(logit) = usize::try_from(token.0)
+                .ok()
+                .and_then(|id| logits.get_mut(id))
+            {
+                *logit = f32::NEG_INFINITY;
+            }
+        }
+    }
+}
+
+impl LlamaContext<'_> {
+    /// Run `processor` on the logits of the ith token in the last decoded batch. The change is
+    /// made in place, so everything reading the logits afterwards (such as
+    /// [`LlamaContext::candidates_ith`]) sees the processed values.
+    ///
+    /// # Errors
+    ///
+    /// See [`LlamaContext::try_get_logits_ith_mut`].
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use llama_cpp_2::context::LlamaContext;
+    /// # use llama_cpp_2::token::LlamaToken;
+    /// # fn sample(ctx: &mut LlamaContext, history: &[LlamaToken], banned: LlamaToken) -> Result<(), llama_cpp_2::LogitsError> {
+    /// let mut ban = |logits: &mut [f32], _: &[LlamaToken]| logits[banned.0 as usize] = f32::NEG_INFINITY;
+    /// ctx.process_logits_ith(0, history, &mut ban)?;
+    /// let candidates = ctx.candidates_ith(0);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn process_logits_ith(
+        &mut self,
+        i: i32,
+        history: &[LlamaToken],
+        processor: &mut (impl LogitsProcessor + ?Sized),
+    ) -> Result<(), LogitsError> {
+        let logits = self.try_get_logits_ith_mut(i)?;
+        processor.process(logits, history);
+        Ok(())
+    }
+}
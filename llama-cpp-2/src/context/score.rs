@@ -0,0 +1,277 @@
+//! Score (query, passage) pairs for reranking.
+//!
+//! A cross-encoder reads the query and the passage together and judges how relevant the passage
+//! is, which ranks better than comparing separately pooled embeddings. The linked llama.cpp has no
+//! rank pooling to run the classification head of BERT rerankers, so the relevance is read from
+//! the logits of the last token: [`PairScorer`] compares the logit of a positive token (`yes`)
+//! with a negative one (`no`), which covers causal LMs prompted or fine-tuned to judge relevance.
+//!
+//! [`LlamaContext::score_pairs`] packs as many pairs as fit into each decode, each as its own
+//! sequence:
+//!
+//! ```no_run
+//! # use llama_cpp_2::context::LlamaContext;
+//! # use llama_cpp_2::context::score::PairScorer;
+//! # fn run(ctx: &mut LlamaContext) -> Result<(), Box<dyn std::error::Error>> {
+//! let scorer = PairScorer::from_words(ctx.model, "yes", Some("no"))?.with_template(
+//!     "<|im_start|>user\nDoes the passage answer the query? Answer yes or no.\n\
+//!      Query: {query}\nPassage: {passage}<|im_end|>\n<|im_start|>assistant\n",
+//! );
+//! let pairs = [
+//!     ("what is rust?", "Rust is a systems programming language."),
+//!     ("what is rust?", "Iron oxide forms on wet iron."),
+//! ];
+//! let scores = ctx.score_pairs(&pairs, &scorer)?;
+//! assert!(scores[0] > scores[1]);
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::context::LlamaContext;
+use crate::llama_batch::{BatchAddError, LlamaBatch};
+use crate::model::{AddBos, LlamaModel, Special};
+use crate::token::LlamaToken;
+use crate::{DecodeError, LogitsError, StringToTokenError};
+
+/// Failed to score the pairs of [`LlamaContext::score_pairs`].
+#[derive(Debug, thiserror::Error)]
+pub enum ScorePairsError {
+    /// A query, passage or word could not be tokenized.
+    #[error("{0}")]
+    Tokenize(#[from] StringToTokenError),
+    /// A word of [`PairScorer::from_words`] is not exactly one token.
+    #[error("{0:?} is not a single token")]
+    NotOneToken(String),
+    /// A pair has no tokens.
+    #[error("pair {index} has no tokens")]
+    Empty {
+        /// The index of the pair.
+        index: usize,
+    },
+    /// A pair has more tokens than fit into one batch.
+    #[error("pair {index} has {n_tokens} tokens but n_batch is {n_batch}")]
+    PairTooLong {
+        /// The index of the pair.
+        index: usize,
+        /// The number of tokens of the pair.
+        n_tokens: usize,
+        /// The batch size of the context.
+        n_batch: usize,
+    },
+    /// Failed to add a token to the batch.
+    #[error("{0}")]
+    BatchAdd(#[from] BatchAddError),
+    /// Failed to decode a batch.
+    #[error("{0}")]
+    Decode(#[from] DecodeError),
+    /// Failed to read the logits of the last token of a pair.
+    #[error("{0}")]
+    Logits(#[from] LogitsError),
+}
+
+/// How a pair is laid out for the model.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum PairFormat {
+    /// `bos query eos sep passage eos`, the layout of llama.cpp's reranking. Tokens the model
+    /// does not have are left out.
+    #[default]
+    Special,
+    /// A prompt where `{query}` and `{passage}` are replaced by the pair, tokenized with a bos
+    /// token and special tokens.
+    Template(String),
+}
+
+/// Turns the logits of the last token of a pair into its relevance, see the
+/// [module docs](self).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PairScorer {
+    format: PairFormat,
+    positive: LlamaToken,
+    negative: Option<LlamaToken>,
+}
+
+impl PairScorer {
+    /// Score pairs by the log probability of `positive` after them.
+    #[must_use]
+    pub fn new(positive: LlamaToken) -> Self {
+        Self {
+            format: PairFormat::default(),
+            positive,
+            negative: None,
+        }
+    }
+
+    /// Score pairs by the tokens of the words `positive` and `negative`, see
+    /// [`PairScorer::with_negative`].
+    ///
+    /// # Errors
+    ///
+    /// If a word cannot be tokenized or is not exactly one token.
+    pub fn from_words(
+        model: &LlamaModel,
+        positive: &str,
+        negative: Option<&str>,
+    ) -> Result<Self, ScorePairsError> {
+        let token = |word: &str| match model.str_to_token(word, AddBos::Never)?[..] {
+            [token] => Ok(token),
+            _ => Err(ScorePairsError::NotOneToken(word.to_string())),
+        };
+        let scorer = Self::new(token(positive)?);
+        Ok(match negative {
+            Some(negative) => scorer.with_negative(token(negative)?),
+            None => scorer,
+        })
+    }
+
+    /// Score pairs by how much more likely `positive` is than `negative`: the difference of their
+    /// logits, i.e. the log odds of `positive` among the two.
+    #[must_use]
+    pub fn with_negative(mut self, negative: LlamaToken) -> Self {
+        self.negative = Some(negative);
+        self
+    }
+
+    /// Lay pairs out with `template`, see [`PairFormat::Template`].
+    #[must_use]
+    pub fn with_template(mut self, template: impl Into<String>) -> Self {
+        self.format = PairFormat::Template(template.into());
+        self
+    }
+
+    /// The tokens of a pair for `model`.
+    ///
+    /// # Errors
+    ///
+    /// If the query, passage or template cannot be tokenized.
+    pub fn tokenize(
+        &self,
+        model: &LlamaModel,
+        query: &str,
+        passage: &str,
+    ) -> Result<Vec<LlamaToken>, StringToTokenError> {
+        match &self.format {
+            PairFormat::Special => {
+                let present = |token: LlamaToken| (token.0 >= 0).then_some(token);
+                let (bos, eos) = (present(model.token_bos()), present(model.token_eos()));
+                let mut tokens: Vec<LlamaToken> = bos.into_iter().collect();
+                tokens.extend(model.str_to_token(query, AddBos::Never)?);
+                tokens.extend(eos);
+                tokens.extend(present(model.token_sep()));
+                tokens.extend(model.str_to_token(passage, AddBos::Never)?);
+                tokens.extend(eos);
+                Ok(tokens)
+            }
+            PairFormat::Template(template) => {
+                let prompt = template
+                    .replace("{query}", query)
+                    .replace("{passage}", passage);
+                model.str_to_token_with_special(&prompt, AddBos::Always, Special::Tokenize)
+            }
+        }
+    }
+
+    /// The relevance of a pair from the logits of its last token. Tokens outside of `logits`
+    /// count as impossible.
+    ///
+    /// ```
+    /// # use llama_cpp_2::context::score::PairScorer;
+    /// # use llama_cpp_2::token::LlamaToken;
+    /// let logits = [2.0, 0.5, 0.5];
+    /// let log_odds = PairScorer::new(LlamaToken::new(0)).with_negative(LlamaToken::new(1));
+    /// assert_eq!(log_odds.score(&logits), 1.5);
+    ///
+    /// let logprob = PairScorer::new(LlamaToken::new(1)).score(&[0.0, 0.0]);
+    /// assert!((logprob - 0.5_f32.ln()).abs() < 1e-6);
+    /// ```
+    #[must_use]
+    pub fn score(&self, logits: &[f32]) -> f32 {
+        let logit = |token: LlamaToken| {
+            usize::try_from(token.0)
+                .ok()
+                .and_then(|id| logits.get(id))
+                .copied()
+                .unwrap_or(f32::NEG_INFINITY)
+        };
+        let positive = logit(self.positive);
+        match self.negative {
+            Some(negative) => positive - logit(negative),
+            None => {
+                let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+                let sum: f32 = logits.iter().map(|logit| (logit - max).exp()).sum();
+                positive - max - sum.ln()
+            }
+        }
+    }
+}
+
+impl LlamaContext<'_> {
+    /// The relevance of every `(query, passage)` pair according to `scorer`, in order. Pairs are
+    /// packed into as few decodes as [`n_batch`](LlamaContext::n_batch) and
+    /// [`n_seq_max`](LlamaContext::n_seq_max) allow; the kv cache is cleared before every decode.
+    ///
+    /// # Errors
+    ///
+    /// - [`ScorePairsError::PairTooLong`] if a pair does not fit into one batch.
+    /// - if tokenizing, decoding or reading the logits fails.
+    ///
+    /// # Panics
+    ///
+    /// - if `n_batch` or `n_seq_max` does not fit into a usize
+    pub fn score_pairs<Q: AsRef<str>, P: AsRef<str>>(
+        &mut self,
+        pairs: &[(Q, P)],
+        scorer: &PairScorer,
+    ) -> Result<Vec<f32>, ScorePairsError> {
+        let n_batch = usize::try_from(self.n_batch()).expect("n_batch fits into a usize");
+        let n_seq_max = usize::try_from(self.n_seq_max()).expect("n_seq_max fits into a usize");
+        let tokenized = pairs
+            .iter()
+            .enumerate()
+            .map(|(index, (query, passage))| {
+                let tokens = scorer.tokenize(self.model, query.as_ref(), passage.as_ref())?;
+                match tokens.len() {
+                    0 => Err(ScorePairsError::Empty { index }),
+                    n_tokens if n_tokens > n_batch => Err(ScorePairsError::PairTooLong {
+                        index,
+                        n_tokens,
+                        n_batch,
+                    }),
+                    _ => Ok(tokens),
+                }
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut scores = Vec::with_capacity(pairs.len());
+        let mut batch = LlamaBatch::new(n_batch, 1);
+        let mut remaining = &tokenized[..];
+        while !remaining.is_empty() {
+            batch.clear();
+            let mut n_seqs = 0;
+            let mut n_tokens = 0;
+            for tokens in remaining.iter().take(n_seq_max.max(1)) {
+                if n_tokens + tokens.len() > n_batch {
+                    break;
+                }
+                batch.add_sequence(
+                    tokens,
+                    i32::try_from(n_seqs).expect("n_seq_max fits"),
+                    false,
+                )?;
+                n_seqs += 1;
+                n_tokens += tokens.len();
+            }
+            self.clear_kv_cache();
+            self.decode(&mut batch)?;
+
+            for seq_id in 0..n_seqs {
+                let seq_id = i32::try_from(seq_id).expect("n_seq_max fits");
+                scores.push(scorer.score(self.seq_logits(&batch, seq_id)?));
+            }
+            remaining = &remaining[n_seqs..];
+        }
+        Ok(scores)
+    }
+}
+
+#[cfg(test)]
+mod tests;
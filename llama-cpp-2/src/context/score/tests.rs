@@ -0,0 +1,46 @@
+use std::num::NonZeroU32;
+
+use super::*;
+use crate::context::params::LlamaContextParams;
+use crate::test_utils::{self, TinyModel};
+
+/// A context that decodes at most 24 tokens of `n_seq_max` sequences at once.
+fn context(model: &LlamaModel, n_seq_max: u32) -> LlamaContext<'_> {
+    let params = LlamaContextParams::default()
+        .with_n_ctx(NonZeroU32::new(64))
+        .with_n_batch(24)
+        .with_n_ubatch(24)
+        .with_n_seq_max(n_seq_max);
+    model.new_context(test_utils::backend(), params).unwrap()
+}
+
+#[test]
+fn packed_pairs_score_like_single_pairs() {
+    let model = TinyModel::default().load(test_utils::backend()).unwrap();
+    let scorer = PairScorer::from_words(&model, "cat", Some("dog")).unwrap();
+    // about 5 tokens per short pair and 10 per long one: the first decode is limited by
+    // n_seq_max to three short pairs, the second by n_batch to a short and a long pair
+    let pairs = [
+        ("cat", "dog"),
+        ("dog", "cat"),
+        ("the", "a"),
+        ("a", "the"),
+        ("the cat is a dog", "hello world"),
+        ("hello world", "the dog is a cat"),
+    ];
+
+    let packed = context(&model, 3).score_pairs(&pairs, &scorer).unwrap();
+
+    let mut single = context(&model, 1);
+    let expected: Vec<f32> = pairs
+        .iter()
+        .map(|pair| single.score_pairs(&[*pair], &scorer).unwrap()[0])
+        .collect();
+    assert_eq!(packed.len(), pairs.len());
+    for (i, (packed, expected)) in packed.iter().zip(&expected).enumerate() {
+        assert!(
+            (packed - expected).abs() < 1e-4,
+            "pair {i}: packed {packed}, single {expected}"
+        );
+    }
+}
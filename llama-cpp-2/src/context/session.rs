@@ -1,7 +1,10 @@
 //! utilities for working with session files
 
 use crate::context::LlamaContext;
+use crate::llama_batch::{BatchAddError, LlamaBatch};
 use crate::token::LlamaToken;
+use crate::DecodeError;
+use llama_cpp_sys_2::llama_pos;
 use std::ffi::{CString, NulError};
 use std::path::{Path, PathBuf};
 
@@ -46,7 +49,171 @@ pub enum LoadSessionError {
     },
 }
 
+/// Failed to apply a prompt with [`LlamaContext::apply_prompt`].
+#[derive(Debug, thiserror::Error)]
+pub enum ApplyPromptError {
+    /// The prompt has no tokens.
+    #[error("the prompt is empty")]
+    EmptyPrompt,
+    /// The prompt does not fit into the context.
+    #[error("the prompt has {n_tokens} tokens but the context only {n_ctx}")]
+    PromptTooLong {
+        /// The number of prompt tokens.
+        n_tokens: usize,
+        /// The size of the context.
+        n_ctx: u32,
+    },
+    /// Failed to add a token to the batch.
+    #[error("{0}")]
+    BatchAdd(#[from] BatchAddError),
+    /// Failed to decode the new tokens.
+    #[error("{0}")]
+    Decode(#[from] DecodeError),
+}
+
+/// How much of a new prompt is already in the KV cache, found by comparing it against the tokens
+/// of a loaded session or an earlier generation.
+///
+/// ```
+/// # use llama_cpp_2::context::session::PrefixMatch;
+/// # use llama_cpp_2::token::LlamaToken;
+/// let cached = [1, 2, 3, 4].map(LlamaToken::new);
+/// let prompt = [1, 2, 5].map(LlamaToken::new);
+/// let prefix = PrefixMatch::new(&cached, &prompt);
+/// assert_eq!(prefix.n_common, 2);
+/// assert_eq!(prefix.divergence(), Some(2));
+/// assert_eq!(prefix.suffix(&prompt), &[LlamaToken::new(5)]);
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PrefixMatch {
+    /// The length of the longest common prefix.
+    pub n_common: usize,
+    /// The number of cached tokens.
+    pub n_cached: usize,
+    /// The number of prompt tokens.
+    pub n_prompt: usize,
+}
+
+impl PrefixMatch {
+    /// Compare the `cached` tokens with a new `prompt`.
+    #[must_use]
+    pub fn new(cached: &[LlamaToken], prompt: &[LlamaToken]) -> Self {
+        let n_common = cached
+            .iter()
+            .zip(prompt)
+            .take_while(|(cached, prompt)| cached == prompt)
+            .count();
+        Self {
+            n_common,
+            n_cached: cached.len(),
+            n_prompt: prompt.len(),
+        }
+    }
+
+    /// The position where the cached tokens stop being valid for the prompt, or `None` if the
+    /// prompt starts with all of them. Everything in the cache from this position on has to be
+    /// removed before the suffix is decoded.
+    #[must_use]
+    pub fn divergence(&self) -> Option<usize> {
+        (self.n_common < self.n_cached).then_some(self.n_common)
+    }
+
+    /// The number of prompt tokens that can be reused. At least the last prompt token is always
+    /// evaluated again, since its logits are needed to sample from.
+    #[must_use]
+    pub fn n_reusable(&self) -> usize {
+        self.n_common.min(self.n_prompt.saturating_sub(1))
+    }
+
+    /// The part of `prompt` that still has to be decoded.
+    ///
+    /// # Panics
+    ///
+    /// If `prompt` is shorter than the prompt this was created with.
+    #[must_use]
+    pub fn suffix<'p>(&self, prompt: &'p [LlamaToken]) -> &'p [LlamaToken] {
+        &prompt[self.n_reusable()..]
+    }
+}
+
 impl LlamaContext<'_> {
+    /// The tokens in the KV cache of `seq_id` at positions `0..len`, as far as the context knows:
+    /// every token decoded on the sequence, up to where its cache was last changed other than by
+    /// decoding (cleared, shifted, copied into or loaded). Changes made through the raw
+    /// `llama_cpp_sys_2` functions are not seen, clear the sequence after them.
+    #[must_use]
+    pub fn cached_tokens(&self, seq_id: i32) -> &[LlamaToken] {
+        self.cached_tokens.get(&seq_id).map_or(&[], Vec::as_slice)
+    }
+
+    /// Bring the KV cache of `seq_id` to `prompt` with as little work as possible: keep the
+    /// prefix it shares with the [cached tokens](LlamaContext::cached_tokens), remove what
+    /// follows and decode only the rest of `prompt`. Returns the number of tokens reused.
+    ///
+    /// The last prompt token is always decoded and is the only token with logits, so sampling
+    /// can start right away with [`LlamaContext::get_logits`] or the samplers. This is the
+    /// pattern of a chat server, which gets the whole conversation with every request:
+    ///
+    /// ```no_run
+    /// # use llama_cpp_2::context::LlamaContext;
+    /// # use llama_cpp_2::token::LlamaToken;
+    /// # fn run(ctx: &mut LlamaContext, turns: &[Vec<LlamaToken>]) -> Result<(), Box<dyn std::error::Error>> {
+    /// for conversation in turns {
+    ///     let n_reused = ctx.apply_prompt(conversation, 0)?;
+    ///     println!("decoded {} new tokens", conversation.len() - n_reused);
+    ///     // sample and decode the reply on sequence 0 ...
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// - [`ApplyPromptError::EmptyPrompt`] if `prompt` is empty.
+    /// - [`ApplyPromptError::PromptTooLong`] if `prompt` does not fit into the context.
+    /// - [`ApplyPromptError::Decode`] if decoding fails. The cache of `seq_id` then holds the
+    ///   reused prefix and possibly some of the new tokens.
+    ///
+    /// # Panics
+    ///
+    /// - if `n_ctx` or `n_batch` does not fit into a usize
+    /// - if the number of prompt tokens does not fit into a [`llama_pos`]
+    pub fn apply_prompt(
+        &mut self,
+        prompt: &[LlamaToken],
+        seq_id: i32,
+    ) -> Result<usize, ApplyPromptError> {
+        let n_ctx = self.n_ctx();
+        if prompt.is_empty() {
+            return Err(ApplyPromptError::EmptyPrompt);
+        }
+        if prompt.len() > usize::try_from(n_ctx).expect("n_ctx fits into a usize") {
+            return Err(ApplyPromptError::PromptTooLong {
+                n_tokens: prompt.len(),
+                n_ctx,
+            });
+        }
+
+        let prefix = PrefixMatch::new(self.cached_tokens(seq_id), prompt);
+        let n_reused = prefix.n_reusable();
+        let mut pos = llama_pos::try_from(n_reused).expect("n_tokens fits into a llama_pos");
+        unsafe {
+            llama_cpp_sys_2::llama_kv_cache_seq_rm(self.context.as_ptr(), seq_id, pos, -1);
+        }
+        self.forget_cached_tokens(seq_id, pos);
+
+        let n_batch = usize::try_from(self.n_batch()).expect("n_batch fits into a usize");
+        let suffix = prefix.suffix(prompt);
+        let mut batch = LlamaBatch::new(n_batch.min(suffix.len()), 1);
+        for chunk in suffix.chunks(n_batch) {
+            batch.clear();
+            batch.add_sequence_at(chunk, pos, seq_id)?;
+            pos += llama_pos::try_from(chunk.len()).expect("n_tokens fits into a llama_pos");
+            self.decode(&mut batch)?;
+        }
+        Ok(n_reused)
+    }
+
     /// Save the current session to a file.
     ///
     /// # Parameters
@@ -120,6 +287,8 @@ impl LlamaContext<'_> {
                 &mut n_out,
             )
         };
+        // the loaded state replaces the whole cache
+        self.forget_cached_tokens(-1, 0);
         if load_session_success {
             if n_out > max_tokens {
                 return Err(LoadSessionError::InsufficientMaxLength { n_out, max_tokens });
@@ -159,6 +328,7 @@ impl LlamaContext<'_> {
     ///
     /// help wanted: not entirely sure what the safety requirements are here.
     pub unsafe fn set_state_data(&mut self, src: &[u8]) -> usize {
+        self.forget_cached_tokens(-1, 0);
         unsafe { llama_cpp_sys_2::llama_set_state_data(self.context.as_ptr(), src.as_ptr()) }
     }
 }
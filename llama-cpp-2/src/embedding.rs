@@ -0,0 +1,6 @@
+//! Utilities for working with embeddings produced by [`crate::context::LlamaContext::embeddings_seq_ith`]
+//! and [`crate::context::LlamaContext::embeddings_ith`].
+
+pub mod batch;
+pub mod math;
+pub mod tokens;
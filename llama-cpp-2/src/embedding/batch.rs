@@ -0,0 +1,247 @@
+//! Embedding many texts with as few decodes as possible.
+//!
+//! [`LlamaContext::embed_batch`] tokenizes the texts lazily and packs as many of them as fit into
+//! one ubatch, each as its own sequence, so a single decode produces the pooled embeddings of all
+//! of them. The context needs embeddings enabled, a model with a pooling type other than
+//! [`llama_cpp_sys_2::LLAMA_POOLING_TYPE_NONE`] and an
+//! [`n_seq_max`](crate::context::params::LlamaContextParams::with_n_seq_max) above one to pack
+//! more than one text per decode. Embeddings of Matryoshka models can be shortened with
+//! [`BatchEmbeddings::with_dimensions`].
+//!
+//! ```no_run
+//! # use llama_cpp_2::context::LlamaContext;
+//! # fn run(ctx: &mut LlamaContext, corpus: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+//! for (text, embedding) in corpus.iter().zip(ctx.embed_batch(corpus, true)) {
+//!     let embedding = embedding?;
+//!     println!("{text}: {:?}", &embedding[..4]);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use std::collections::VecDeque;
+use std::fmt::{Debug, Formatter};
+
+use crate::context::LlamaContext;
+use crate::llama_batch::{BatchAddError, LlamaBatch};
+use crate::model::AddBos;
+use crate::token::LlamaToken;
+use crate::{DecodeError, EmbeddingsError, StringToTokenError};
+
+/// Failed to embed a text of [`LlamaContext::embed_batch`].
+#[derive(Debug, thiserror::Error)]
+pub enum EmbedBatchError {
+    /// A text could not be tokenized.
+    #[error("failed to tokenize input: {0}")]
+    Tokenize(#[from] StringToTokenError),
+    /// A text has more tokens than fit into one ubatch. Pooling needs the whole sequence in a
+    /// single decode, so it cannot be split.
+    #[error("input {index} has {n_tokens} tokens but n_ubatch is {n_ubatch}")]
+    InputTooLong {
+        /// The index of the text among the inputs.
+        index: usize,
+        /// The number of tokens of the text.
+        n_tokens: usize,
+        /// The ubatch size of the context.
+        n_ubatch: usize,
+    },
+    /// The tokens could not be added to the batch.
+    #[error("failed to add input to the batch: {0}")]
+    Batch(#[from] BatchAddError),
+    /// The batch could not be decoded.
+    #[error("failed to decode the batch: {0}")]
+    Decode(#[from] DecodeError),
+    /// The pooled embeddings could not be read.
+    #[error("failed to get embeddings: {0}")]
+    Embeddings(#[from] EmbeddingsError),
+}
+
+/// An iterator over the embeddings of the texts given to [`LlamaContext::embed_batch`], in order.
+///
+/// After an error the iterator is fused and yields nothing more.
+pub struct BatchEmbeddings<'c, 'a, I> {
+    ctx: &'c mut LlamaContext<'a>,
+    inputs: I,
+    batch: LlamaBatch,
+    /// A tokenized text that did not fit into the previous batch.
+    pending: Option<Vec<LlamaToken>>,
+    ready: VecDeque<Vec<f32>>,
+    /// An input that failed, reported after the embeddings of the texts before it.
+    error: Option<EmbedBatchError>,
+    next_index: usize,
+    n_ubatch: usize,
+    n_seq_max: usize,
+    normalize: bool,
+    dimensions: Option<usize>,
+    done: bool,
+}
+
+impl<I> Debug for BatchEmbeddings<'_, '_, I> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("BatchEmbeddings")
+            .field("ready", &self.ready.len())
+            .field("next_index", &self.next_index)
+            .field("n_ubatch", &self.n_ubatch)
+            .field("n_seq_max", &self.n_seq_max)
+            .field("normalize", &self.normalize)
+            .field("dimensions", &self.dimensions)
+            .field("done", &self.done)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<'a> LlamaContext<'a> {
+    /// Embed every text of `texts`, packing as many of them into each decode as the context's
+    /// [`n_ubatch`](LlamaContext::n_ubatch) and [`n_seq_max`](LlamaContext::n_seq_max) allow.
+    /// With `normalize` the embeddings are scaled to unit length, see
+    /// [`normalize`](super::math::normalize).
+    ///
+    /// Texts are tokenized as they are needed, so `texts` can be a lazy iterator over a corpus
+    /// much larger than memory. The kv cache is cleared before every decode.
+    ///
+    /// # Panics
+    ///
+    /// - if `n_seq_max` does not fit into an [`i32`]
+    pub fn embed_batch<I>(
+        &mut self,
+        texts: I,
+        normalize: bool,
+    ) -> BatchEmbeddings<'_, 'a, I::IntoIter>
+    where
+        I: IntoIterator,
+        I::Item: AsRef<str>,
+    {
+        let n_ubatch =
+            usize::try_from(self.n_ubatch()).expect("n_ubatch does not fit into a usize");
+        let n_seq_max =
+            usize::try_from(self.n_seq_max()).expect("n_seq_max does not fit into a usize");
+        let batch = LlamaBatch::new(
+            n_ubatch,
+            i32::try_from(n_seq_max).expect("n_seq_max does not fit into an i32"),
+        );
+        BatchEmbeddings {
+            ctx: self,
+            inputs: texts.into_iter(),
+            batch,
+            pending: None,
+            ready: VecDeque::new(),
+            error: None,
+            next_index: 0,
+            n_ubatch,
+            n_seq_max: n_seq_max.max(1),
+            normalize,
+            dimensions: None,
+            done: false,
+        }
+    }
+}
+
+impl<I> BatchEmbeddings<'_, '_, I> {
+    /// Truncate every embedding to its first `dimensions` values and re-normalize it, see
+    /// [`truncate`](super::math::truncate). Only meaningful for models trained with Matryoshka
+    /// representation learning, whose leading dimensions carry most of the meaning.
+    #[must_use]
+    pub fn with_dimensions(mut self, dimensions: usize) -> Self {
+        self.dimensions = Some(dimensions);
+        self
+    }
+}
+
+impl<I> BatchEmbeddings<'_, '_, I>
+where
+    I: Iterator,
+    I::Item: AsRef<str>,
+{
+    /// Tokenize the next text, or return `None` if there are no texts left.
+    fn next_input(&mut self) -> Option<Result<Vec<LlamaToken>, EmbedBatchError>> {
+        if let Some(tokens) = self.pending.take() {
+            return Some(Ok(tokens));
+        }
+        let text = self.inputs.next()?;
+        let index = self.next_index;
+        self.next_index += 1;
+        let tokens = match self.ctx.model.str_to_token(text.as_ref(), AddBos::Always) {
+            Ok(tokens) => tokens,
+            Err(err) => return Some(Err(err.into())),
+        };
+        if tokens.len() > self.n_ubatch {
+            return Some(Err(EmbedBatchError::InputTooLong {
+                index,
+                n_tokens: tokens.len(),
+                n_ubatch: self.n_ubatch,
+            }));
+        }
+        Some(Ok(tokens))
+    }
+
+    /// Decode the next batch of texts and queue their embeddings. A text that cannot be embedded
+    /// ends the batch and is kept in `error`.
+    fn fill(&mut self) -> Result<(), EmbedBatchError> {
+        self.batch.clear();
+        let mut n_seqs = 0;
+        let mut n_tokens = 0;
+        while n_seqs < self.n_seq_max {
+            let tokens = match self.next_input() {
+                Some(Ok(tokens)) => tokens,
+                Some(Err(err)) => {
+                    self.error = Some(err);
+                    break;
+                }
+                None => break,
+            };
+            if n_tokens + tokens.len() > self.n_ubatch {
+                self.pending = Some(tokens);
+                break;
+            }
+            let seq_id = i32::try_from(n_seqs).expect("n_seq_max fits into an i32");
+            self.batch.add_sequence(&tokens, seq_id, false)?;
+            n_tokens += tokens.len();
+            n_seqs += 1;
+        }
+        if n_seqs == 0 {
+            return Ok(());
+        }
+
+        self.ctx.clear_kv_cache();
+        self.ctx.decode(&mut self.batch)?;
+        for seq_id in 0..n_seqs {
+            let seq_id = i32::try_from(seq_id).expect("n_seq_max fits into an i32");
+            let mut embedding = self.ctx.embeddings_seq_ith(seq_id)?.to_vec();
+            if let Some(dimensions) = self.dimensions {
+                super::math::truncate(&mut embedding, dimensions);
+            } else if self.normalize {
+                super::math::normalize(&mut embedding);
+            }
+            self.ready.push_back(embedding);
+        }
+        Ok(())
+    }
+}
+
+impl<I> Iterator for BatchEmbeddings<'_, '_, I>
+where
+    I: Iterator,
+    I::Item: AsRef<str>,
+{
+    type Item = Result<Vec<f32>, EmbedBatchError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(embedding) = self.ready.pop_front() {
+            return Some(Ok(embedding));
+        }
+        if self.done {
+            return None;
+        }
+        if self.error.is_none() {
+            if let Err(err) = self.fill() {
+                self.error = Some(err);
+                self.ready.clear();
+            }
+        }
+        if let Some(embedding) = self.ready.pop_front() {
+            return Some(Ok(embedding));
+        }
+        self.done = true;
+        self.error.take().map(Err)
+    }
+}
@@ -0,0 +1,137 @@
+//! Vector math for comparing embeddings.
+//!
+//! # Examples
+//!
+//! ```
+//! # use llama_cpp_2::embedding::math::top_k;
+//! let documents = vec![vec![1.0, 0.0], vec![0.0, 1.0], vec![0.7, 0.7]];
+//! let nearest = top_k(&[1.0, 0.1], &documents, 2);
+//! assert_eq!(nearest.iter().map(|(i, _)| *i).collect::<Vec<_>>(), vec![0, 2]);
+//! ```
+
+/// The dot product of two vectors.
+///
+/// # Panics
+///
+/// If `a` and `b` have different lengths.
+///
+/// ```
+/// # use llama_cpp_2::embedding::math::dot;
+/// assert_eq!(dot(&[1.0, 2.0, 3.0], &[4.0, 5.0, 6.0]), 32.0);
+/// ```
+#[must_use]
+pub fn dot(a: &[f32], b: &[f32]) -> f32 {
+    assert_eq!(a.len(), b.len(), "embeddings have different lengths");
+    a.iter().zip(b).map(|(a, b)| a * b).sum()
+}
+
+/// The euclidean (L2) norm of a vector.
+///
+/// ```
+/// # use llama_cpp_2::embedding::math::norm;
+/// assert_eq!(norm(&[3.0, 4.0]), 5.0);
+/// ```
+#[must_use]
+pub fn norm(a: &[f32]) -> f32 {
+    dot(a, a).sqrt()
+}
+
+/// Scale a vector to unit length in place. A zero vector is left unchanged.
+///
+/// ```
+/// # use llama_cpp_2::embedding::math::normalize;
+/// let mut embedding = vec![3.0, 4.0];
+/// normalize(&mut embedding);
+/// assert_eq!(embedding, vec![0.6, 0.8]);
+/// ```
+pub fn normalize(a: &mut [f32]) {
+    let norm = norm(a);
+    if norm > 0.0 {
+        a.iter_mut().for_each(|x| *x /= norm);
+    }
+}
+
+/// Keep the first `dimensions` values of a Matryoshka (MRL) embedding and scale the rest back to
+/// unit length. Embeddings with at most `dimensions` values are only normalized.
+///
+/// ```
+/// # use llama_cpp_2::embedding::math::truncate;
+/// let mut embedding = vec![3.0, 4.0, 12.0];
+/// truncate(&mut embedding, 2);
+/// assert_eq!(embedding, vec![0.6, 0.8]);
+/// ```
+pub fn truncate(embedding: &mut Vec<f32>, dimensions: usize) {
+    embedding.truncate(dimensions);
+    normalize(embedding);
+}
+
+/// The cosine similarity of two vectors, between -1 (opposite) and 1 (same direction). Returns 0
+/// if either vector is zero.
+///
+/// # Panics
+///
+/// If `a` and `b` have different lengths.
+///
+/// ```
+/// # use llama_cpp_2::embedding::math::cosine_similarity;
+/// assert_eq!(cosine_similarity(&[1.0, 0.0], &[2.0, 0.0]), 1.0);
+/// assert_eq!(cosine_similarity(&[1.0, 0.0], &[0.0, 1.0]), 0.0);
+/// ```
+#[must_use]
+pub fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    let norms = norm(a) * norm(b);
+    if norms == 0.0 {
+        0.0
+    } else {
+        dot(a, b) / norms
+    }
+}
+
+/// Find the `k` candidates most similar to `query` by cosine similarity with a brute-force scan.
+///
+/// Returns `(index, similarity)` pairs sorted from most to least similar, candidates with the
+/// same similarity by index. Candidates containing NaN have a NaN similarity and come last. Fewer
+/// than `k` pairs are returned if there are fewer candidates.
+///
+/// # Panics
+///
+/// If a candidate has a different length than `query`.
+///
+/// ```
+/// # use llama_cpp_2::embedding::math::top_k;
+/// let candidates = vec![vec![0.0, 1.0], vec![1.0, 0.0]];
+/// let nearest = top_k(&[1.0, 0.0], &candidates, 5);
+/// assert_eq!(nearest, vec![(1, 1.0), (0, 0.0)]);
+/// ```
+#[must_use]
+pub fn top_k(query: &[f32], candidates: &[Vec<f32>], k: usize) -> Vec<(usize, f32)> {
+    let query_norm = norm(query);
+    let mut scored = candidates
+        .iter()
+        .enumerate()
+        .map(|(i, candidate)| {
+            let norms = query_norm * norm(candidate);
+            let similarity = if norms == 0.0 {
+                0.0
+            } else {
+                dot(query, candidate) / norms
+            };
+            (i, similarity)
+        })
+        .collect::<Vec<_>>();
+    let by_similarity = |a: &(usize, f32), b: &(usize, f32)| {
+        a.1.is_nan()
+            .cmp(&b.1.is_nan())
+            .then_with(|| b.1.total_cmp(&a.1))
+            .then(a.0.cmp(&b.0))
+    };
+    if k < scored.len() {
+        scored.select_nth_unstable_by(k, by_similarity);
+        scored.truncate(k);
+    }
+    scored.sort_by(by_similarity);
+    scored
+}
+
+#[cfg(test)]
+mod tests;
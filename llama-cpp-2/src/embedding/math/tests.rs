@@ -0,0 +1,39 @@
+use super::*;
+
+#[test]
+fn zero_vectors_have_zero_similarity() {
+    assert_eq!(cosine_similarity(&[0.0, 0.0], &[1.0, 0.0]), 0.0);
+    let candidates = vec![vec![0.0, 0.0], vec![1.0, 0.0]];
+    assert_eq!(top_k(&[1.0, 0.0], &candidates, 2), vec![(1, 1.0), (0, 0.0)]);
+    assert_eq!(top_k(&[0.0, 0.0], &candidates, 2), vec![(0, 0.0), (1, 0.0)]);
+
+    let mut zero = vec![0.0, 0.0];
+    normalize(&mut zero);
+    assert_eq!(zero, vec![0.0, 0.0]);
+}
+
+#[test]
+fn nan_similarities_come_last() {
+    let candidates = vec![
+        vec![f32::NAN, 0.0],
+        vec![0.0, 1.0],
+        vec![1.0, 0.0],
+        vec![-1.0, 0.0],
+    ];
+    let nearest = top_k(&[1.0, 0.0], &candidates, 4);
+    let order: Vec<usize> = nearest.iter().map(|(i, _)| *i).collect();
+    assert_eq!(order, vec![2, 1, 3, 0]);
+    assert!(nearest[3].1.is_nan());
+
+    let nearest = top_k(&[1.0, 0.0], &candidates, 2);
+    assert_eq!(nearest, vec![(2, 1.0), (1, 0.0)]);
+}
+
+#[test]
+fn ties_are_ordered_by_index() {
+    let candidates = vec![vec![0.0, 1.0], vec![1.0, 0.0], vec![0.0, 2.0]];
+    assert_eq!(
+        top_k(&[1.0, 0.0], &candidates, 3),
+        vec![(1, 1.0), (0, 0.0), (2, 0.0)]
+    );
+}
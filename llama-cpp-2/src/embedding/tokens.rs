@@ -0,0 +1,224 @@
+//! Per-token (unpooled) embeddings, for late-interaction retrieval (ColBERT) and token-level
+//! analysis.
+//!
+//! With a [pooling type](crate::context::params::LlamaContextParams::with_pooling_type) of
+//! [`LlamaPoolingType::None`] llama.cpp keeps the hidden state of every token that requested an
+//! output instead of one embedding per sequence. [`LlamaContext::embed_tokens`] decodes a sequence
+//! with an output on every token and returns them as [`TokenEmbeddings`], a row-major
+//! `[n_tokens x n_embd]` matrix; [`LlamaContext::token_embeddings_seq`] reads them from a batch
+//! decoded by the caller.
+//!
+//! ```no_run
+//! # use llama_cpp_2::context::LlamaContext;
+//! # use llama_cpp_2::model::AddBos;
+//! # fn run(ctx: &mut LlamaContext) -> Result<(), Box<dyn std::error::Error>> {
+//! let query = ctx.model.str_to_token("what is rust?", AddBos::Always)?;
+//! let document = ctx.model.str_to_token("Rust is a systems programming language.", AddBos::Always)?;
+//! let mut query = ctx.embed_tokens(&query, 0)?;
+//! let mut document = ctx.embed_tokens(&document, 0)?;
+//! query.normalize();
+//! document.normalize();
+//! println!("score: {}", query.max_sim(&document));
+//! # Ok(())
+//! # }
+//! ```
+
+use llama_cpp_sys_2::llama_pos;
+
+use crate::context::params::LlamaPoolingType;
+use crate::context::LlamaContext;
+use crate::embedding::math::{dot, normalize};
+use crate::llama_batch::{BatchAddError, LlamaBatch};
+use crate::token::LlamaToken;
+use crate::{DecodeError, EmbeddingsError};
+
+/// Failed to embed the tokens of [`LlamaContext::embed_tokens`].
+#[derive(Debug, thiserror::Error)]
+pub enum EmbedTokensError {
+    /// The context pools the token embeddings, so only the pooled embedding is kept. Create it
+    /// with [`LlamaPoolingType::None`].
+    #[error("the context pools embeddings with {0:?}, per-token embeddings need no pooling")]
+    Pooled(LlamaPoolingType),
+    /// The tokens could not be added to the batch.
+    #[error("failed to add the tokens to the batch: {0}")]
+    Batch(#[from] BatchAddError),
+    /// The batch could not be decoded.
+    #[error("failed to decode the batch: {0}")]
+    Decode(#[from] DecodeError),
+    /// The embeddings could not be read.
+    #[error("failed to get embeddings: {0}")]
+    Embeddings(#[from] EmbeddingsError),
+}
+
+/// The embeddings of the tokens of a sequence, one row of `n_embd` values per token.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TokenEmbeddings {
+    data: Vec<f32>,
+    n_embd: usize,
+}
+
+impl TokenEmbeddings {
+    /// Wrap the row-major embeddings `data` of tokens with `n_embd` values each. Returns `None`
+    /// if `n_embd` is zero or does not divide the length of `data`.
+    ///
+    /// ```
+    /// # use llama_cpp_2::embedding::tokens::TokenEmbeddings;
+    /// let embeddings = TokenEmbeddings::new(vec![1.0, 0.0, 0.0, 1.0, 0.6, 0.8], 2).unwrap();
+    /// assert_eq!(embeddings.n_tokens(), 3);
+    /// assert_eq!(embeddings.get(2), Some(&[0.6, 0.8][..]));
+    /// assert!(TokenEmbeddings::new(vec![1.0, 2.0, 3.0], 2).is_none());
+    /// ```
+    #[must_use]
+    pub fn new(data: Vec<f32>, n_embd: usize) -> Option<Self> {
+        (n_embd > 0 && data.len() % n_embd == 0).then_some(Self { data, n_embd })
+    }
+
+    /// The number of tokens.
+    #[must_use]
+    pub fn n_tokens(&self) -> usize {
+        self.data.len() / self.n_embd
+    }
+
+    /// The size of the embedding of one token.
+    #[must_use]
+    pub fn n_embd(&self) -> usize {
+        self.n_embd
+    }
+
+    /// Whether there are no tokens.
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    /// The embedding of the `i`th token.
+    #[must_use]
+    pub fn get(&self, i: usize) -> Option<&[f32]> {
+        self.data.get(i * self.n_embd..(i + 1) * self.n_embd)
+    }
+
+    /// The embeddings of the tokens in order.
+    pub fn iter(&self) -> impl ExactSizeIterator<Item = &[f32]> + '_ {
+        self.data.chunks_exact(self.n_embd)
+    }
+
+    /// All embeddings, one token after the other.
+    #[must_use]
+    pub fn as_slice(&self) -> &[f32] {
+        &self.data
+    }
+
+    /// The embeddings, one token after the other.
+    #[must_use]
+    pub fn into_vec(self) -> Vec<f32> {
+        self.data
+    }
+
+    /// Scale the embedding of every token to unit length, see [`normalize`].
+    pub fn normalize(&mut self) {
+        self.data.chunks_exact_mut(self.n_embd).for_each(normalize);
+    }
+
+    /// The late-interaction (`MaxSim`) score of ColBERT: for every token of `self` the largest
+    /// dot product with a token of `document`, summed. Normalize both first to sum cosine
+    /// similarities.
+    ///
+    /// ```
+    /// # use llama_cpp_2::embedding::tokens::TokenEmbeddings;
+    /// let query = TokenEmbeddings::new(vec![1.0, 0.0, 0.0, 1.0], 2).unwrap();
+    /// let document = TokenEmbeddings::new(vec![0.5, 0.0, 0.0, 0.25, 1.0, 0.0], 2).unwrap();
+    /// assert_eq!(query.max_sim(&document), 1.25);
+    /// ```
+    ///
+    /// # Panics
+    ///
+    /// If the embeddings have different sizes.
+    #[must_use]
+    pub fn max_sim(&self, document: &TokenEmbeddings) -> f32 {
+        assert_eq!(
+            self.n_embd, document.n_embd,
+            "embeddings have different sizes"
+        );
+        self.iter()
+            .map(|query| {
+                document
+                    .iter()
+                    .map(|token| dot(query, token))
+                    .fold(f32::NEG_INFINITY, f32::max)
+            })
+            .filter(|score| score.is_finite())
+            .sum()
+    }
+}
+
+impl LlamaContext<'_> {
+    /// Decode `tokens` on `seq_id` with an output on every token and return the embedding of
+    /// each, see the [module docs](self). The sequence is cleared first. Tokens beyond
+    /// [`n_batch`](LlamaContext::n_batch) are decoded in further batches, which only models
+    /// with causal attention support.
+    ///
+    /// # Errors
+    ///
+    /// - [`EmbedTokensError::Pooled`] if the context pools the embeddings.
+    /// - if decoding or reading the embeddings fails; embeddings must be enabled.
+    ///
+    /// # Panics
+    ///
+    /// - if `n_batch` or `n_embd` does not fit into a usize
+    /// - if the number of tokens does not fit into a [`llama_pos`]
+    pub fn embed_tokens(
+        &mut self,
+        tokens: &[LlamaToken],
+        seq_id: i32,
+    ) -> Result<TokenEmbeddings, EmbedTokensError> {
+        let pooling_type = self.pooling_type();
+        if pooling_type != LlamaPoolingType::None {
+            return Err(EmbedTokensError::Pooled(pooling_type));
+        }
+        let n_batch = usize::try_from(self.n_batch()).expect("n_batch fits into a usize");
+        let n_embd = usize::try_from(self.model.n_embd()).expect("n_embd fits into a usize");
+        self.clear_kv_cache_seq(seq_id, None, None);
+
+        let mut data = Vec::with_capacity(tokens.len() * n_embd);
+        let mut batch = LlamaBatch::new(n_batch.min(tokens.len()).max(1), 1);
+        let mut pos: llama_pos = 0;
+        for chunk in tokens.chunks(n_batch) {
+            batch.clear();
+            for &token in chunk {
+                batch.add(token, pos, &[seq_id], true)?;
+                pos = pos.checked_add(1).expect("n_tokens fits into a llama_pos");
+            }
+            self.decode(&mut batch)?;
+            for i in 0..batch.n_tokens() {
+                data.extend_from_slice(self.embeddings_ith(i)?);
+            }
+        }
+        Ok(TokenEmbeddings { data, n_embd })
+    }
+
+    /// The embeddings of the tokens of `seq_id` that requested an output in `batch`, which must
+    /// be the batch decoded last. Use it to embed several sequences in one decode, adding every
+    /// token with `logits` set.
+    ///
+    /// # Errors
+    ///
+    /// See [`LlamaContext::embeddings_ith`].
+    ///
+    /// # Panics
+    ///
+    /// - if `n_embd` does not fit into a usize
+    pub fn token_embeddings_seq(
+        &self,
+        batch: &LlamaBatch,
+        seq_id: i32,
+    ) -> Result<TokenEmbeddings, EmbeddingsError> {
+        let n_embd = usize::try_from(self.model.n_embd()).expect("n_embd fits into a usize");
+        let mut data = Vec::new();
+        for &i in &batch.initialized_logits {
+            if batch.has_seq_id(i, seq_id) {
+                data.extend_from_slice(self.embeddings_ith(i)?);
+            }
+        }
+        Ok(TokenEmbeddings { data, n_embd })
+    }
+}
@@ -0,0 +1,1190 @@
+//! A high level generation loop built on top of the lower level context APIs.
+//!
+//! [`LlamaContext::generate`] decodes a prompt and samples tokens until a [`stop`] criterion, the
+//! end of generation token or the end of the context is reached, streaming the text to a callback
+//! as it goes. [`LlamaContext::generate_stream`] reports the same as typed events,
+//! including tool calls and why the stream ended.
+//!
+//! # Example
+//!
+//! ```no_run
+//! # use std::ops::ControlFlow;
+//! # use llama_cpp_2::context::LlamaContext;
+//! # use llama_cpp_2::generate::GenerationParams;
+//! # use llama_cpp_2::model::AddBos;
+//! # fn run(ctx: &mut LlamaContext) -> Result<(), Box<dyn std::error::Error>> {
+//! let prompt = ctx.model.str_to_token("The capital of France is", AddBos::Always)?;
+//! let mut params = GenerationParams::default()
+//!     .with_max_tokens(32)
+//!     .with_stop_strings(["\n"]);
+//! let generation = ctx.generate(&prompt, &mut params, |event| {
+//!     print!("{}", event.text);
+//!     ControlFlow::Continue(())
+//! })?;
+//! println!("\nstopped because of {:?}", generation.finish_reason);
+//! # Ok(())
+//! # }
+//! ```
+
+use std::fmt::{Debug, Display, Formatter};
+use std::ops::ControlFlow;
+use std::time::{Duration, Instant};
+
+use crate::context::sample::logits_processor::{IgnoreEos, LogitsProcessor};
+use crate::context::session::PrefixMatch;
+use crate::context::LlamaContext;
+use crate::generate::latency::{LatencySummary, TokenLatencies};
+use crate::generate::self_extend::SelfExtend;
+use crate::generate::stop::{MaxTokens, StopCheck, StopStrings, StoppingCriteria};
+use crate::generate::stream::ToolCallParser;
+use crate::grammar::LlamaGrammar;
+use crate::llama_batch::{BatchAddError, LlamaBatch};
+use crate::model::{LlamaModel, Special};
+use crate::token::data_array::LlamaTokenDataArray;
+use crate::token::LlamaToken;
+use crate::{DecodeError, EncodeError, LogitsError, SamplerError, TokenToStringError};
+use llama_cpp_sys_2::llama_pos;
+
+pub mod choice;
+#[cfg(feature = "json")]
+pub mod json;
+pub mod latency;
+pub mod lookahead;
+pub mod self_extend;
+pub mod seq2seq;
+pub mod slots;
+pub mod stop;
+pub mod stream;
+
+/// Failed to generate.
+#[derive(Debug, Clone, thiserror::Error)]
+pub enum GenerateError {
+    /// The prompt has no tokens.
+    #[error("the prompt is empty")]
+    EmptyPrompt,
+    /// The prompt does not fit into the context.
+    #[error("the prompt of {n_tokens} tokens does not fit into a context of {n_ctx}")]
+    PromptTooLong {
+        /// The number of tokens in the prompt.
+        n_tokens: usize,
+        /// The size of the context.
+        n_ctx: u32,
+    },
+    /// Failed to add a token to the batch.
+    #[error("{0}")]
+    BatchAdd(#[from] BatchAddError),
+    /// Failed to decode a batch.
+    #[error("{0}")]
+    Decode(#[from] DecodeError),
+    /// Failed to encode the input of an encoder-decoder model.
+    #[error("{0}")]
+    Encode(#[from] EncodeError),
+    /// [`LlamaContext::generate_seq2seq`] was called with a model without an encoder.
+    #[error("the model has no encoder")]
+    NoEncoder,
+    /// Failed to read the logits of the last token.
+    #[error("{0}")]
+    Logits(#[from] LogitsError),
+    /// Failed to sample a token.
+    #[error("{0}")]
+    Sampler(#[from] SamplerError),
+    /// Failed to convert a generated token to bytes.
+    #[error("{0}")]
+    TokenToString(#[from] TokenToStringError),
+}
+
+/// Why a generation ended.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FinishReason {
+    /// The model generated an end of generation token.
+    EndOfGeneration,
+    /// [`stop::MaxTokens`] tokens were generated.
+    MaxTokens,
+    /// The [`stop::Timeout`] elapsed.
+    Timeout,
+    /// The text contained this stop string.
+    StopString(String),
+    /// [`stop::StopToken`] matched this token.
+    StopToken(LlamaToken),
+    /// The context is full and could not be shifted.
+    ContextFull,
+    /// The grammar allows none of the candidate tokens, e.g. because a logits processor such as
+    /// [`IgnoreEos`] removed the end of generation token that ends a complete match. The text
+    /// is a prefix of a match of the grammar.
+    GrammarExhausted,
+    /// The callback returned [`ControlFlow::Break`] or the context's
+    /// [cancellation token](crate::context::cancel) was cancelled.
+    Cancelled,
+    /// A custom [`StoppingCriteria`] stopped for this reason.
+    Other(String),
+}
+
+/// The parameters of the sampling chain. The defaults match llama.cpp's `common` defaults.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SamplingParams {
+    /// The temperature. `0` or less samples greedily and skips the truncation samplers.
+    pub temperature: f32,
+    /// Keep only the `top_k` most likely tokens. `0` disables it.
+    pub top_k: i32,
+    /// Keep the most likely tokens up to a cumulative probability of `top_p`. `1` disables it.
+    pub top_p: f32,
+    /// Drop tokens less likely than `min_p` times the most likely one. `0` disables it.
+    pub min_p: f32,
+    /// Penalty for repeating any token of the last `repeat_last_n`. `1` disables it.
+    pub repeat_penalty: f32,
+    /// The number of tokens considered for penalties.
+    pub repeat_last_n: usize,
+    /// Penalty proportional to how often a token occurred in the last `repeat_last_n`.
+    pub frequency_penalty: f32,
+    /// Penalty for a token occurring at all in the last `repeat_last_n`.
+    pub presence_penalty: f32,
+    /// Draw tokens from a random number generator seeded with this instead of the context's shared
+    /// one. The same seed, prompt, model and context parameters give the same output, see
+    /// [`SamplingParams::deterministic`].
+    pub seed: Option<u32>,
+}
+
+impl Default for SamplingParams {
+    fn default() -> Self {
+        Self {
+            temperature: 0.8,
+            top_k: 40,
+            top_p: 0.95,
+            min_p: 0.05,
+            repeat_penalty: 1.0,
+            repeat_last_n: 64,
+            frequency_penalty: 0.0,
+            presence_penalty: 0.0,
+            seed: None,
+        }
+    }
+}
+
+impl SamplingParams {
+    /// Greedy sampling, always picking the most likely token.
+    #[must_use]
+    pub fn greedy() -> Self {
+        Self {
+            temperature: 0.0,
+            ..Self::default()
+        }
+    }
+
+    /// The default sampling chain with a fixed seed, for reproducible generations (e.g. snapshot
+    /// tests of model behaviour).
+    ///
+    /// Every stochastic step of [`LlamaContext::generate`] and the
+    /// [`SlotManager`](slots::SlotManager) draws from the seeded generator, so runs are identical
+    /// as long as the logits are. They are bit-identical on the same machine, build and backend
+    /// with the same [`LlamaContextParams`](crate::context::params::LlamaContextParams) (see
+    /// [`LlamaContextParams::deterministic`](crate::context::params::LlamaContextParams::deterministic)):
+    /// a different `n_batch` or `n_ubatch` splits the prompt differently and changes the order of
+    /// floating point operations, and some GPU kernels are not deterministic at all.
+    ///
+    /// ```
+    /// # use llama_cpp_2::generate::SamplingParams;
+    /// let sampling = SamplingParams::deterministic(42);
+    /// assert_eq!(sampling.seed, Some(42));
+    /// assert_eq!(sampling.temperature, SamplingParams::default().temperature);
+    /// ```
+    #[must_use]
+    pub fn deterministic(seed: u32) -> Self {
+        Self {
+            seed: Some(seed),
+            ..Self::default()
+        }
+    }
+
+    fn has_penalties(&self) -> bool {
+        self.repeat_last_n > 0
+            && ((self.repeat_penalty - 1.0).abs() > f32::EPSILON
+                || self.frequency_penalty != 0.0
+                || self.presence_penalty != 0.0)
+    }
+}
+
+/// The stages of the chain in the order they run, leaving out disabled ones.
+///
+/// ```
+/// # use llama_cpp_2::generate::SamplingParams;
+/// let sampling = SamplingParams { repeat_penalty: 1.1, ..SamplingParams::deterministic(42) };
+/// assert_eq!(
+///     sampling.to_string(),
+///     "penalties(last_n=64, repeat=1.1, freq=0, present=0) → top_k(40) → top_p(0.95) → min_p(0.05) → temp(0.8) → dist(seed=42)"
+/// );
+/// assert_eq!(SamplingParams::greedy().to_string(), "greedy");
+/// ```
+impl Display for SamplingParams {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if self.has_penalties() {
+            write!(
+                f,
+                "penalties(last_n={}, repeat={}, freq={}, present={}) → ",
+                self.repeat_last_n,
+                self.repeat_penalty,
+                self.frequency_penalty,
+                self.presence_penalty
+            )?;
+        }
+        if self.temperature <= 0.0 {
+            return f.write_str("greedy");
+        }
+        if self.top_k > 0 {
+            write!(f, "top_k({}) → ", self.top_k)?;
+        }
+        if self.top_p < 1.0 {
+            write!(f, "top_p({}) → ", self.top_p)?;
+        }
+        if self.min_p > 0.0 {
+            write!(f, "min_p({}) → ", self.min_p)?;
+        }
+        write!(f, "temp({}) → ", self.temperature)?;
+        match self.seed {
+            Some(seed) => write!(f, "dist(seed={seed})"),
+            None => f.write_str("dist"),
+        }
+    }
+}
+
+/// The parameters of [`LlamaContext::generate`].
+pub struct GenerationParams {
+    /// The sampling chain.
+    pub sampling: SamplingParams,
+    /// Criteria checked after every token, see [`stop`].
+    pub stopping: Vec<Box<dyn StoppingCriteria>>,
+    /// Run on the raw logits before sampling, see [`LogitsProcessor`].
+    pub logits_processors: Vec<Box<dyn LogitsProcessor>>,
+    /// Constrain the output to a grammar.
+    pub grammar: Option<LlamaGrammar>,
+    /// Sample without the grammar first and only apply it to every candidate when the token is
+    /// not allowed, see [`GenerationParams::with_lazy_grammar`]. Defaults to `false`.
+    pub lazy_grammar: bool,
+    /// The sequence to generate on. It is cleared before the prompt is decoded, except for the
+    /// first `n_cached` positions.
+    pub seq_id: i32,
+    /// The number of leading prompt tokens that are already in the KV cache of `seq_id`, e.g.
+    /// from an earlier turn of a chat. Only the rest of the prompt is decoded. Defaults to `0`.
+    pub n_cached: usize,
+    /// Shift the context with [`LlamaContext::kv_cache_shift`] when it is full instead of stopping
+    /// with [`FinishReason::ContextFull`].
+    pub context_shift: bool,
+    /// The number of prompt tokens to keep when shifting. Defaults to the whole prompt.
+    pub n_keep: Option<usize>,
+    /// Compress old positions with [self-extend](self_extend) so the positions stay within the
+    /// trained context. The context still needs a cell for every token. When set, `n_cached` and
+    /// `context_shift` are ignored.
+    pub self_extend: Option<SelfExtend>,
+    /// Separates tool calls from the content in [`LlamaContext::generate_stream`].
+    pub tool_call_parser: Option<Box<dyn ToolCallParser>>,
+    /// Continue the random number generator of [`SamplingParams::seed`] from this state instead
+    /// of seeding it, see [`SamplerState`].
+    pub sampler_state: Option<SamplerState>,
+    /// Record the latency of every generated token into this buffer, see [`latency`].
+    pub latencies: Option<TokenLatencies>,
+    /// Checked before every decode, see [`GenerationParams::with_disconnect_check`].
+    pub disconnected: Option<Box<dyn Fn() -> bool>>,
+}
+
+impl Default for GenerationParams {
+    fn default() -> Self {
+        Self {
+            sampling: SamplingParams::default(),
+            stopping: Vec::new(),
+            logits_processors: Vec::new(),
+            grammar: None,
+            lazy_grammar: false,
+            seq_id: 0,
+            n_cached: 0,
+            context_shift: false,
+            n_keep: None,
+            self_extend: None,
+            tool_call_parser: None,
+            sampler_state: None,
+            latencies: None,
+            disconnected: None,
+        }
+    }
+}
+
+impl Debug for GenerationParams {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("GenerationParams")
+            .field("sampling", &self.sampling)
+            .field(
+                "stopping",
+                &format!("{} stopping criteria", self.stopping.len()),
+            )
+            .field(
+                "logits_processors",
+                &format!("{} logits processors", self.logits_processors.len()),
+            )
+            .field("grammar", &self.grammar)
+            .field("lazy_grammar", &self.lazy_grammar)
+            .field("seq_id", &self.seq_id)
+            .field("n_cached", &self.n_cached)
+            .field("context_shift", &self.context_shift)
+            .field("n_keep", &self.n_keep)
+            .field("self_extend", &self.self_extend)
+            .field("tool_call_parser", &self.tool_call_parser.is_some())
+            .field("sampler_state", &self.sampler_state)
+            .field(
+                "latencies",
+                &self.latencies.as_ref().map(TokenLatencies::len),
+            )
+            .field("disconnected", &self.disconnected.is_some())
+            .finish()
+    }
+}
+
+impl GenerationParams {
+    /// Set the sampling chain.
+    #[must_use]
+    pub fn with_sampling(mut self, sampling: SamplingParams) -> Self {
+        self.sampling = sampling;
+        self
+    }
+
+    /// Add a stopping criterion.
+    #[must_use]
+    pub fn with_stopping(mut self, criteria: impl StoppingCriteria + 'static) -> Self {
+        self.stopping.push(Box::new(criteria));
+        self
+    }
+
+    /// Stop after `max_tokens` tokens, see [`MaxTokens`].
+    #[must_use]
+    pub fn with_max_tokens(self, max_tokens: usize) -> Self {
+        self.with_stopping(MaxTokens(max_tokens))
+    }
+
+    /// Stop on any of `stop`, see [`StopStrings`].
+    #[must_use]
+    pub fn with_stop_strings(self, stop: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        self.with_stopping(StopStrings::new(stop))
+    }
+
+    /// Add a logits processor.
+    #[must_use]
+    pub fn with_logits_processor(mut self, processor: impl LogitsProcessor + 'static) -> Self {
+        self.logits_processors.push(Box::new(processor));
+        self
+    }
+
+    /// Never end on an end of generation token of `model`, only on a stopping criterion such as
+    /// [`GenerationParams::with_max_tokens`]. See [`IgnoreEos`].
+    #[must_use]
+    pub fn with_ignore_eos(mut self, model: &LlamaModel) -> Self {
+        self.logits_processors
+            .insert(0, Box::new(IgnoreEos::new(model)));
+        self
+    }
+
+    /// Constrain the output to `grammar`.
+    #[must_use]
+    pub fn with_grammar(mut self, grammar: LlamaGrammar) -> Self {
+        self.grammar = Some(grammar);
+        self
+    }
+
+    /// Apply the grammar lazily, as llama.cpp's samplers do: sample a token without it, and only
+    /// if the grammar does not allow that token apply the grammar to every candidate and sample
+    /// again. Checking one token instead of the whole vocabulary makes most steps of a
+    /// structured output as fast as unconstrained ones on large vocabularies.
+    ///
+    /// Greedy sampling picks the same tokens either way. Otherwise the token is drawn from the
+    /// unconstrained distribution when the grammar allows it, which can differ from drawing from
+    /// the constrained one, and the [log probability](TokenEvent::logprob) and
+    /// [confidence](TokenEvent::confidence) of such tokens are those of the unconstrained
+    /// distribution.
+    #[must_use]
+    pub fn with_lazy_grammar(mut self, lazy: bool) -> Self {
+        self.lazy_grammar = lazy;
+        self
+    }
+
+    /// Set the sequence to generate on.
+    #[must_use]
+    pub fn with_seq_id(mut self, seq_id: i32) -> Self {
+        self.seq_id = seq_id;
+        self
+    }
+
+    /// Reuse the first `n_cached` prompt tokens already in the KV cache of the sequence.
+    #[must_use]
+    pub fn with_cached_prefix(mut self, n_cached: usize) -> Self {
+        self.n_cached = n_cached;
+        self
+    }
+
+    /// Reuse the longest prefix `prompt` shares with the `cached` tokens of the sequence, such as
+    /// the tokens of a loaded session or the prompt and output of the previous turn. See
+    /// [`PrefixMatch`].
+    #[must_use]
+    pub fn with_cached_tokens(self, cached: &[LlamaToken], prompt: &[LlamaToken]) -> Self {
+        let prefix = PrefixMatch::new(cached, prompt);
+        self.with_cached_prefix(prefix.n_reusable())
+    }
+
+    /// Separate tool calls from the content of [`LlamaContext::generate_stream`] with `parser`.
+    #[must_use]
+    pub fn with_tool_call_parser(mut self, parser: impl ToolCallParser + 'static) -> Self {
+        self.tool_call_parser = Some(Box::new(parser));
+        self
+    }
+
+    /// Enable context shifting, keeping the first `n_keep` tokens (or the whole prompt if `None`).
+    #[must_use]
+    pub fn with_context_shift(mut self, n_keep: Option<usize>) -> Self {
+        self.context_shift = true;
+        self.n_keep = n_keep;
+        self
+    }
+
+    /// Run past the trained context with [self-extend](self_extend).
+    #[must_use]
+    pub fn with_self_extend(mut self, self_extend: SelfExtend) -> Self {
+        self.self_extend = Some(self_extend);
+        self
+    }
+
+    /// Record the latency of every generated token into `latencies`, see [`latency`].
+    #[must_use]
+    pub fn with_latencies(mut self, latencies: TokenLatencies) -> Self {
+        self.latencies = Some(latencies);
+        self
+    }
+
+    /// Stop with [`FinishReason::Cancelled`] as soon as `disconnected` returns true, e.g. because
+    /// the client of the stream went away. It is checked before every decode, of the prompt
+    /// chunks and of every token, and a [`SlotManager`](slots::SlotManager) releases the
+    /// sequence of the request at its next step. To also stop a decode that is already running,
+    /// cancel the context's [`CancellationToken`](crate::context::cancel::CancellationToken) at
+    /// the same time.
+    ///
+    /// ```
+    /// # use std::sync::atomic::{AtomicBool, Ordering};
+    /// # use std::sync::Arc;
+    /// # use llama_cpp_2::generate::GenerationParams;
+    /// let gone = Arc::new(AtomicBool::new(false));
+    /// let params = GenerationParams::default().with_disconnect_check({
+    ///     let gone = Arc::clone(&gone);
+    ///     move || gone.load(Ordering::Relaxed)
+    /// });
+    /// // in the handler that notices the client is gone
+    /// gone.store(true, Ordering::Relaxed);
+    /// ```
+    #[must_use]
+    pub fn with_disconnect_check(mut self, disconnected: impl Fn() -> bool + 'static) -> Self {
+        self.disconnected = Some(Box::new(disconnected));
+        self
+    }
+
+    /// Whether the [disconnect check](GenerationParams::with_disconnect_check) says the
+    /// consumer is gone.
+    pub(crate) fn is_disconnected(&self) -> bool {
+        self.disconnected
+            .as_ref()
+            .is_some_and(|disconnected| disconnected())
+    }
+
+    /// Resume sampling from the [`Generation::sampler_state`] of an earlier generation.
+    #[must_use]
+    pub fn with_sampler_state(mut self, state: SamplerState) -> Self {
+        self.sampler_state = Some(state);
+        self
+    }
+
+    /// The random number generator for sampling: resumed from `sampler_state`, seeded with the
+    /// seed or `None` for the context's shared one.
+    fn rng(&self) -> Option<SeededRng> {
+        self.sampler_state
+            .map(|state| SeededRng(state.rng))
+            .or_else(|| self.sampling.seed.map(SeededRng::new))
+    }
+}
+
+/// Timings of a single [`LlamaContext::generate`] call.
+///
+/// The timings are measured from the start of the call and include tokenization to text and
+/// sampling, so they reflect what a user of the stream observes rather than the decode time alone.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct GenerationStats {
+    /// The number of prompt tokens decoded, without the reused [`GenerationParams::n_cached`].
+    pub n_prompt_tokens: usize,
+    /// The number of tokens generated so far, without the end of generation token.
+    pub n_generated_tokens: usize,
+    /// The time it took to decode the prompt.
+    pub prompt_time: Duration,
+    /// The time until the first token was sampled. `None` until then.
+    pub time_to_first_token: Option<Duration>,
+    /// The time from the end of the prompt decode to the latest sampled token.
+    pub generation_time: Duration,
+    /// The percentiles of the per-token latencies with [`GenerationParams::latencies`]. Only
+    /// set on the stats of the finished [`Generation`].
+    pub latency: Option<LatencySummary>,
+}
+
+impl GenerationStats {
+    /// The prompt processing rate in tokens per second. `None` if no time was measured.
+    #[must_use]
+    pub fn prompt_tokens_per_second(&self) -> Option<f64> {
+        tokens_per_second(self.n_prompt_tokens, self.prompt_time)
+    }
+
+    /// The generation rate in tokens per second. `None` if no token was generated yet.
+    #[must_use]
+    pub fn generation_tokens_per_second(&self) -> Option<f64> {
+        tokens_per_second(self.n_generated_tokens, self.generation_time)
+    }
+}
+
+/// `n_tokens` per second of `time`, `None` if either is zero.
+#[allow(clippy::cast_precision_loss)]
+fn tokens_per_second(n_tokens: usize, time: Duration) -> Option<f64> {
+    let secs = time.as_secs_f64();
+    (n_tokens > 0 && secs > 0.0).then(|| n_tokens as f64 / secs)
+}
+
+/// Formats the stats like the timings llama.cpp prints at the end of a run.
+impl Display for GenerationStats {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "prompt: {} tokens in {:.2} ms",
+            self.n_prompt_tokens,
+            self.prompt_time.as_secs_f64() * 1000.0
+        )?;
+        if let Some(rate) = self.prompt_tokens_per_second() {
+            write!(f, " ({rate:.2} tokens per second)")?;
+        }
+        write!(
+            f,
+            ", generation: {} tokens in {:.2} ms",
+            self.n_generated_tokens,
+            self.generation_time.as_secs_f64() * 1000.0
+        )?;
+        if let Some(rate) = self.generation_tokens_per_second() {
+            write!(f, " ({rate:.2} tokens per second)")?;
+        }
+        if let Some(ttft) = self.time_to_first_token {
+            write!(
+                f,
+                ", time to first token: {:.2} ms",
+                ttft.as_secs_f64() * 1000.0
+            )?;
+        }
+        if let Some(latency) = self.latency {
+            write!(f, ", per token: {latency}")?;
+        }
+        Ok(())
+    }
+}
+
+/// A generated token passed to the callback of [`LlamaContext::generate`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TokenEvent<'a> {
+    /// The sampled token.
+    pub token: LlamaToken,
+    /// The text that can be streamed after this token. This can be empty (for tokens that are
+    /// only part of a UTF-8 character, or text held back by a stopping criterion) or contain
+    /// text of earlier tokens that was held back. The end of generation token carries the text
+    /// that was still held back when generation ended.
+    pub text: &'a str,
+    /// The log probability of the token before truncation samplers (top-k, top-p, min-p) ran.
+    pub logprob: f32,
+    /// How sure the sampler was of the token.
+    pub confidence: Confidence,
+    /// The timings up to and including this token.
+    pub stats: GenerationStats,
+}
+
+/// How sure the sampler was of a token, computed from the distribution the token was drawn
+/// from: what is left after the truncation samplers and temperature, or all candidates when
+/// sampling greedily. Useful for hallucination heuristics, stopping early or showing confidence.
+///
+/// ```
+/// # use llama_cpp_2::generate::Confidence;
+/// # use llama_cpp_2::token::data::LlamaTokenData;
+/// # use llama_cpp_2::token::data_array::LlamaTokenDataArray;
+/// # use llama_cpp_2::token::LlamaToken;
+/// let candidates = LlamaTokenDataArray::from_iter(
+///     [0, 1].map(|id| LlamaTokenData::new(LlamaToken::new(id), 0.0, 0.0)),
+///     false,
+/// );
+/// let confidence = Confidence::of(&candidates, LlamaToken::new(0));
+/// assert!((confidence.probability - 0.5).abs() < 1e-6);
+/// assert!((confidence.entropy - std::f32::consts::LN_2).abs() < 1e-6);
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Confidence {
+    /// The probability of the token in that distribution.
+    pub probability: f32,
+    /// The entropy of that distribution in nats: 0 when only one token could be drawn, the log
+    /// of the number of candidates when all were equally likely.
+    pub entropy: f32,
+}
+
+impl Confidence {
+    /// The confidence in `token` of the softmax of the logits of `candidates`.
+    #[must_use]
+    pub fn of(candidates: &LlamaTokenDataArray, token: LlamaToken) -> Self {
+        let log_sum_exp = log_sum_exp(candidates);
+        let mut confidence = Self::default();
+        for data in &candidates.data {
+            let logprob = data.logit() - log_sum_exp;
+            if !logprob.is_finite() {
+                continue;
+            }
+            let probability = logprob.exp();
+            confidence.entropy -= probability * logprob;
+            if data.id() == token {
+                confidence.probability = probability;
+            }
+        }
+        confidence
+    }
+}
+
+/// A token of [`LlamaContext::sample_next`].
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Sampled {
+    pub(crate) token: LlamaToken,
+    pub(crate) logprob: f32,
+    pub(crate) confidence: Confidence,
+}
+
+/// The result of [`LlamaContext::generate`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct Generation {
+    /// The generated text, without a matched stop string.
+    pub text: String,
+    /// The generated tokens, without the end of generation token.
+    pub tokens: Vec<LlamaToken>,
+    /// Why generation ended.
+    pub finish_reason: FinishReason,
+    /// The timings of the whole call.
+    pub stats: GenerationStats,
+    /// The state of the seeded random number generator after the last token, `None` if sampling
+    /// was not seeded.
+    pub sampler_state: Option<SamplerState>,
+}
+
+impl LlamaContext<'_> {
+    /// Decode `prompt` on [`GenerationParams::seq_id`] and generate until a stopping criterion
+    /// matches, the model produces an end of generation token or the context is full.
+    ///
+    /// `on_token` is called for every sampled token with the text that became streamable. Return
+    /// [`ControlFlow::Break`] to stop with [`FinishReason::Cancelled`]. Every event carries the
+    /// [`GenerationStats`] so far, the final stats are on the returned [`Generation`].
+    ///
+    /// Only the prompt after the first [`GenerationParams::n_cached`] tokens is decoded. The
+    /// sequence is left in the KV cache, holding the prompt and every generated token except the
+    /// last one. If the [cancellation token](crate::context::cancel) of the context is
+    /// cancelled, generation ends with [`FinishReason::Cancelled`] after the current token; a
+    /// decode aborted by it is removed from the KV cache again, and if that happens while decoding
+    /// the prompt the sequence is cleared and no tokens are generated.
+    ///
+    /// For models with an encoder, such as T5, the prompt is the encoder input instead: it is
+    /// encoded and the decoder generates from the decoder start token, see
+    /// [`LlamaContext::generate_seq2seq`].
+    ///
+    /// # Errors
+    ///
+    /// See [`GenerateError`].
+    ///
+    /// # Panics
+    ///
+    /// - if `n_ctx` or `n_batch` does not fit into a usize
+    /// - if `n_ctx` does not fit into a [`llama_pos`]
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            skip_all,
+            fields(
+                seq_id = params.seq_id,
+                n_prompt_tokens = prompt.len(),
+                sampling = %params.sampling,
+                n_generated_tokens = tracing::field::Empty,
+                finish_reason = tracing::field::Empty,
+            )
+        )
+    )]
+    pub fn generate(
+        &mut self,
+        prompt: &[LlamaToken],
+        params: &mut GenerationParams,
+        on_token: impl FnMut(TokenEvent<'_>) -> ControlFlow<()>,
+    ) -> Result<Generation, GenerateError> {
+        if self.model.has_encoder() {
+            self.generate_seq2seq(prompt, params, on_token)
+        } else {
+            self.generate_decoder(prompt, params, on_token)
+        }
+    }
+
+    /// [`LlamaContext::generate`] with a decoder-only model, or the decoder of an
+    /// encoder-decoder model after encoding.
+    #[allow(clippy::too_many_lines)]
+    fn generate_decoder(
+        &mut self,
+        prompt: &[LlamaToken],
+        params: &mut GenerationParams,
+        mut on_token: impl FnMut(TokenEvent<'_>) -> ControlFlow<()>,
+    ) -> Result<Generation, GenerateError> {
+        let start = Instant::now();
+        let n_ctx = self.n_ctx();
+        let n_ctx_usize = usize::try_from(n_ctx).expect("n_ctx fits into a usize");
+        if prompt.is_empty() {
+            return Err(GenerateError::EmptyPrompt);
+        }
+        if prompt.len() >= n_ctx_usize {
+            return Err(GenerateError::PromptTooLong {
+                n_tokens: prompt.len(),
+                n_ctx,
+            });
+        }
+
+        let mut rng = params.rng();
+        let seq_id = params.seq_id;
+        // at least the last prompt token is decoded to get its logits
+        let n_cached = if params.self_extend.is_some() {
+            0
+        } else {
+            params.n_cached.min(prompt.len() - 1)
+        };
+        let mut n_past = llama_pos::try_from(n_cached).expect("n_cached fits into a llama_pos");
+        unsafe {
+            llama_cpp_sys_2::llama_kv_cache_seq_rm(self.context.as_ptr(), seq_id, n_past, -1);
+        }
+        self.forget_cached_tokens(seq_id, n_past);
+
+        let mut n_batch = usize::try_from(self.n_batch()).expect("n_batch fits into a usize");
+        if let Some(self_extend) = &params.self_extend {
+            n_batch = self_extend.n_batch(n_batch);
+        }
+        let mut ga_i = 0;
+        let mut batch = LlamaBatch::new(n_batch, 1);
+        let uncached = &prompt[n_cached..];
+        let last_index = uncached.len() - 1;
+        for (i, chunk) in uncached.chunks(n_batch).enumerate() {
+            if let Some(self_extend) = &params.self_extend {
+                self_extend.apply(self, seq_id, &mut ga_i, &mut n_past);
+            }
+            batch.clear();
+            for (j, token) in chunk.iter().enumerate() {
+                batch.add(*token, n_past, &[seq_id], i * n_batch + j == last_index)?;
+                n_past += 1;
+            }
+            let decoded = if params.is_disconnected() {
+                Err(DecodeError::Aborted)
+            } else {
+                self.decode(&mut batch)
+            };
+            match decoded {
+                Err(DecodeError::Aborted) => {
+                    self.clear_kv_cache_seq(seq_id, None, None);
+                    return Ok(Generation {
+                        text: String::new(),
+                        tokens: Vec::new(),
+                        finish_reason: FinishReason::Cancelled,
+                        stats: GenerationStats {
+                            prompt_time: start.elapsed(),
+                            ..GenerationStats::default()
+                        },
+                        sampler_state: rng.as_ref().map(SeededRng::state),
+                    });
+                }
+                result => result?,
+            }
+        }
+        let prompt_done = Instant::now();
+        #[cfg(feature = "metrics")]
+        crate::metrics::record_prompt(uncached.len());
+        let mut stats = GenerationStats {
+            n_prompt_tokens: uncached.len(),
+            prompt_time: prompt_done - start,
+            ..GenerationStats::default()
+        };
+
+        let n_ctx_pos = llama_pos::try_from(n_ctx).expect("n_ctx fits into a llama_pos");
+        let n_keep = llama_pos::try_from(params.n_keep.unwrap_or(prompt.len()).min(prompt.len()))
+            .expect("n_keep fits into a llama_pos");
+        let mut history = prompt.to_vec();
+        let mut tokens = Vec::new();
+        let mut text = String::new();
+        let mut pending = Vec::new();
+        let mut streamed = 0;
+        if let Some(latencies) = &mut params.latencies {
+            latencies.clear();
+        }
+        let mut last_step = prompt_done;
+
+        let finish_reason = loop {
+            let Some(Sampled {
+                token,
+                logprob,
+                confidence,
+            }) = self.sample_next(batch.n_tokens() - 1, &history, params, rng.as_mut())?
+            else {
+                break FinishReason::GrammarExhausted;
+            };
+            if let Some(latencies) = &mut params.latencies {
+                let now = Instant::now();
+                latencies.push(now - last_step);
+                last_step = now;
+            }
+            stats
+                .time_to_first_token
+                .get_or_insert_with(|| start.elapsed());
+            stats.generation_time = prompt_done.elapsed();
+
+            if self.model.is_eog_token(token) {
+                push_lossy(&mut text, &mut pending);
+                let text = &text[streamed..];
+                let _ = on_token(TokenEvent {
+                    token,
+                    text,
+                    logprob,
+                    confidence,
+                    stats,
+                });
+                break FinishReason::EndOfGeneration;
+            }
+
+            tokens.push(token);
+            stats.n_generated_tokens = tokens.len();
+            #[cfg(feature = "metrics")]
+            crate::metrics::record_generated_token();
+            history.push(token);
+            pending.extend(self.model.token_to_bytes(token, Special::Plaintext)?);
+            push_utf8(&mut text, &mut pending);
+
+            let check = StopCheck {
+                tokens: &tokens,
+                text: &text,
+                elapsed: start.elapsed(),
+            };
+            let stop = params.stopping.check(&check);
+            let mut end = match &stop {
+                Some(stop) => stop.text_len.min(text.len()),
+                None => text.len() - params.stopping.holdback(&text).min(text.len()),
+            };
+            while !text.is_char_boundary(end) {
+                end -= 1;
+            }
+            let end = end.max(streamed);
+            let flow = on_token(TokenEvent {
+                token,
+                text: &text[streamed..end],
+                logprob,
+                confidence,
+                stats,
+            });
+            streamed = end;
+
+            if let Some(stop) = stop {
+                text.truncate(end);
+                break stop.reason;
+            }
+            if flow.is_break() || self.is_cancelled() || params.is_disconnected() {
+                break FinishReason::Cancelled;
+            }
+
+            if let Some(self_extend) = &params.self_extend {
+                // positions are compressed, but every token still takes a cell
+                if history.len() >= n_ctx_usize {
+                    break FinishReason::ContextFull;
+                }
+                self_extend.apply(self, seq_id, &mut ga_i, &mut n_past);
+            } else if n_past >= n_ctx_pos {
+                let n_discard = if params.context_shift && self.kv_cache_can_shift() {
+                    self.kv_cache_shift(seq_id, n_keep, n_past)
+                } else {
+                    0
+                };
+                if n_discard == 0 {
+                    break FinishReason::ContextFull;
+                }
+                n_past -= n_discard;
+            }
+
+            batch.clear();
+            batch.add(token, n_past, &[seq_id], true)?;
+            n_past += 1;
+            match self.decode(&mut batch) {
+                Err(DecodeError::Aborted) => {
+                    // drop the cell of the token that was not decoded
+                    unsafe {
+                        llama_cpp_sys_2::llama_kv_cache_seq_rm(
+                            self.context.as_ptr(),
+                            seq_id,
+                            n_past - 1,
+                            -1,
+                        );
+                    }
+                    self.forget_cached_tokens(seq_id, n_past - 1);
+                    break FinishReason::Cancelled;
+                }
+                result => result?,
+            }
+        };
+
+        push_lossy(&mut text, &mut pending);
+        stats.latency = params.latencies.as_ref().and_then(TokenLatencies::summary);
+        #[cfg(feature = "tracing")]
+        tracing::Span::current()
+            .record("n_generated_tokens", tokens.len())
+            .record("finish_reason", tracing::field::debug(&finish_reason));
+        Ok(Generation {
+            text,
+            tokens,
+            finish_reason,
+            stats,
+            sampler_state: rng.as_ref().map(SeededRng::state),
+        })
+    }
+
+    /// Run the logits processors and the sampling chain on the logits of the ith token. Returns
+    /// the token with its log probability and confidence, or `None` if the grammar allows none
+    /// of the candidates. The token is drawn with `rng` if given and with the context's random
+    /// number generator otherwise.
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "trace", skip_all, fields(i = i)))]
+    fn sample_next(
+        &mut self,
+        i: i32,
+        history: &[LlamaToken],
+        params: &mut GenerationParams,
+        mut rng: Option<&mut SeededRng>,
+    ) -> Result<Option<Sampled>, GenerateError> {
+        if !params.logits_processors.is_empty() {
+            self.process_logits_ith(i, history, &mut params.logits_processors)?;
+        }
+        let mut candidates = LlamaTokenDataArray::from_logits(self.try_get_logits_ith(i)?);
+
+        let sampling = params.sampling;
+        if sampling.has_penalties() {
+            let last_n = &history[history.len().saturating_sub(sampling.repeat_last_n)..];
+            candidates.sample_repetition_penalty(
+                None,
+                last_n,
+                sampling.repeat_last_n,
+                sampling.repeat_penalty,
+                sampling.frequency_penalty,
+                sampling.presence_penalty,
+            );
+        }
+        let sampled = match &params.grammar {
+            Some(grammar) if params.lazy_grammar => {
+                let unconstrained = candidates.clone();
+                let sampled = self.sample_candidates(candidates, sampling, rng.as_deref_mut())?;
+                if self.grammar_allows_token(grammar, sampled.token) {
+                    sampled
+                } else {
+                    let mut candidates = unconstrained;
+                    self.sample_grammar(&mut candidates, grammar);
+                    self.sample_candidates(candidates, sampling, rng)?
+                }
+            }
+            Some(grammar) => {
+                self.sample_grammar(&mut candidates, grammar);
+                self.sample_candidates(candidates, sampling, rng)?
+            }
+            None => self.sample_candidates(candidates, sampling, rng)?,
+        };
+
+        if let Some(grammar) = &mut params.grammar {
+            // a token the grammar rules out has a logit of minus infinity, and accepting it
+            // would abort in llama.cpp
+            if !sampled.logprob.is_finite() {
+                return Ok(None);
+            }
+            if !self.model.is_eog_token(sampled.token) {
+                self.grammar_accept_token(grammar, sampled.token);
+            }
+        }
+        Ok(Some(sampled))
+    }
+
+    /// Run the sampling chain on `candidates` and return the token with its log probability and
+    /// confidence, see [`LlamaContext::sample_next`].
+    fn sample_candidates(
+        &mut self,
+        mut candidates: LlamaTokenDataArray,
+        sampling: SamplingParams,
+        rng: Option<&mut SeededRng>,
+    ) -> Result<Sampled, GenerateError> {
+        let log_sum_exp = log_sum_exp(&candidates);
+        let logit_of = |candidates: &LlamaTokenDataArray, token| {
+            candidates
+                .data
+                .iter()
+                .find(|data| data.id() == token)
+                .map_or(f32::NEG_INFINITY, |data| data.logit())
+        };
+
+        let (token, logit, confidence) = if sampling.temperature <= 0.0 {
+            let best = candidates
+                .data
+                .iter()
+                .max_by(|a, b| a.logit().total_cmp(&b.logit()))
+                .ok_or(SamplerError::EmptyCandidates)?;
+            (
+                best.id(),
+                best.logit(),
+                Confidence::of(&candidates, best.id()),
+            )
+        } else {
+            if candidates.data.is_empty() {
+                return Err(SamplerError::EmptyCandidates.into());
+            }
+            let logits = candidates.clone();
+            if sampling.top_k > 0 {
+                candidates.sample_top_k(None, sampling.top_k, 1);
+            }
+            candidates.sample_top_p(None, sampling.top_p, 1);
+            candidates.sample_min_p(None, sampling.min_p, 1);
+            candidates.sample_temp(None, sampling.temperature);
+            let token = match rng {
+                Some(rng) => rng.sample(&mut candidates)?,
+                None => candidates.sample_token(self),
+            };
+            (
+                token,
+                logit_of(&logits, token),
+                Confidence::of(&candidates, token),
+            )
+        };
+        Ok(Sampled {
+            token,
+            logprob: logit - log_sum_exp,
+            confidence,
+        })
+    }
+}
+
+/// The state of the random number generator of a seeded sampling chain, to resume a generation
+/// reproducibly. A generation that is stopped, saved with
+/// [`LlamaContext::save_session_file`] next to its [`Generation::sampler_state`] and continued
+/// [with the state](GenerationParams::with_sampler_state) on the prompt and generated tokens
+/// draws the same tokens as one that ran through.
+///
+/// Unseeded sampling draws from the context's random number generator, which llama.cpp
+/// already saves in the session file. Mirostat keeps its state in the `mu` its caller passes to
+/// [`LlamaTokenDataArray::sample_token_mirostat_v2`], which is saved the same way.
+///
+/// ```
+/// # use llama_cpp_2::generate::SamplerState;
+/// let state = SamplerState::from_bytes([1, 0, 0, 0, 0, 0, 0, 0]);
+/// assert_eq!(SamplerState::from_bytes(state.to_bytes()), state);
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SamplerState {
+    rng: u64,
+}
+
+impl SamplerState {
+    /// The state as bytes, e.g. to write it next to a session file.
+    #[must_use]
+    pub fn to_bytes(self) -> [u8; 8] {
+        self.rng.to_le_bytes()
+    }
+
+    /// The state from the bytes of [`SamplerState::to_bytes`].
+    #[must_use]
+    pub fn from_bytes(bytes: [u8; 8]) -> Self {
+        Self {
+            rng: u64::from_le_bytes(bytes),
+        }
+    }
+}
+
+/// A SplitMix64 random number generator, for sampling independently of the random number generator
+/// shared by all sequences of a context.
+#[derive(Debug, Clone)]
+struct SeededRng(u64);
+
+impl SeededRng {
+    fn new(seed: u32) -> Self {
+        Self(u64::from(seed))
+    }
+
+    fn state(&self) -> SamplerState {
+        SamplerState { rng: self.0 }
+    }
+
+    /// A uniformly distributed number in `[0, 1)`.
+    #[allow(clippy::cast_precision_loss)]
+    fn next_f32(&mut self) -> f32 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^= z >> 31;
+        // the top 24 bits are exactly representable
+        (z >> 40) as f32 / (1 << 24) as f32
+    }
+
+    /// Draw a token from the softmax of the candidates.
+    fn sample(&mut self, candidates: &mut LlamaTokenDataArray) -> Result<LlamaToken, SamplerError> {
+        candidates.sample_softmax(None);
+        let mut threshold = self.next_f32();
+        for data in &candidates.data {
+            threshold -= data.p();
+            if threshold < 0.0 {
+                return Ok(data.id());
+            }
+        }
+        // rounding can leave a bit of probability mass unused
+        candidates
+            .data
+            .last()
+            .map(|data| data.id())
+            .ok_or(SamplerError::EmptyCandidates)
+    }
+}
+
+/// `ln(sum(exp(logit)))` over the candidates, computed without overflowing.
+fn log_sum_exp(candidates: &LlamaTokenDataArray) -> f32 {
+    let max = candidates
+        .data
+        .iter()
+        .map(|data| data.logit())
+        .fold(f32::NEG_INFINITY, f32::max);
+    if !max.is_finite() {
+        return max;
+    }
+    let sum: f32 = candidates
+        .data
+        .iter()
+        .map(|data| (data.logit() - max).exp())
+        .sum();
+    max + sum.ln()
+}
+
+/// Move the complete UTF-8 characters at the start of `pending` to `text`. Invalid bytes are
+/// replaced, an incomplete character at the end stays in `pending`.
+fn push_utf8(text: &mut String, pending: &mut Vec<u8>) {
+    match std::str::from_utf8(pending) {
+        Ok(valid) => {
+            text.push_str(valid);
+            pending.clear();
+        }
+        Err(err) if err.error_len().is_some() => push_lossy(text, pending),
+        Err(err) => {
+            let valid = err.valid_up_to();
+            text.push_str(&String::from_utf8_lossy(&pending[..valid]));
+            pending.drain(..valid);
+        }
+    }
+}
+
+/// Move all of `pending` to `text`, replacing invalid bytes.
+fn push_lossy(text: &mut String, pending: &mut Vec<u8>) {
+    text.push_str(&String::from_utf8_lossy(pending));
+    pending.clear();
+}
+
+#[cfg(test)]
+mod tests;
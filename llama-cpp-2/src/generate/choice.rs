@@ -0,0 +1,238 @@
+//! Constrained choice: make the model pick one of a few strings.
+//!
+//! Classifying with a model usually means prompting it to answer with one of a few labels and
+//! hoping it does. [`LlamaContext::choose`] instead constrains decoding to the prefix tree of the
+//! tokenized options: every step only considers the tokens that continue some option, so the
+//! answer is always one of them, and options that share a prefix share its decodes.
+//!
+//! ```no_run
+//! # use llama_cpp_2::context::LlamaContext;
+//! # use llama_cpp_2::model::AddBos;
+//! # fn run(ctx: &mut LlamaContext) -> Result<(), Box<dyn std::error::Error>> {
+//! let prompt = ctx.model.str_to_token(
+//!     "Review: \"The battery died after two days.\"\nSentiment (positive, negative or neutral):",
+//!     AddBos::Always,
+//! )?;
+//! let options = [" positive", " negative", " neutral"];
+//! let choice = ctx.choose(&prompt, &options, 0)?;
+//! println!("{} (p = {:.2})", options[choice.index], choice.logprob.exp());
+//! # Ok(())
+//! # }
+//! ```
+
+use llama_cpp_sys_2::llama_pos;
+
+use crate::context::session::ApplyPromptError;
+use crate::context::LlamaContext;
+use crate::llama_batch::{BatchAddError, LlamaBatch};
+use crate::model::AddBos;
+use crate::token::LlamaToken;
+use crate::{DecodeError, LogitsError, StringToTokenError};
+
+/// Failed to choose an option with [`LlamaContext::choose`].
+#[derive(Debug, thiserror::Error)]
+pub enum ChooseError {
+    /// There are no options to choose from.
+    #[error("no options to choose from")]
+    NoOptions,
+    /// An option has no tokens.
+    #[error("option {index} has no tokens")]
+    EmptyOption {
+        /// The index of the option.
+        index: usize,
+    },
+    /// The options do not fit into the context after the prompt.
+    #[error("the context is full")]
+    ContextFull,
+    /// An option could not be tokenized.
+    #[error("{0}")]
+    Tokenize(#[from] StringToTokenError),
+    /// Failed to decode the prompt.
+    #[error("{0}")]
+    ApplyPrompt(#[from] ApplyPromptError),
+    /// Failed to add a token to the batch.
+    #[error("{0}")]
+    BatchAdd(#[from] BatchAddError),
+    /// Failed to decode a token of an option.
+    #[error("{0}")]
+    Decode(#[from] DecodeError),
+    /// Failed to read the logits of a token.
+    #[error("{0}")]
+    Logits(#[from] LogitsError),
+}
+
+/// The option [`LlamaContext::choose`] picked.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Choice {
+    /// The index of the option.
+    pub index: usize,
+    /// The log probability of the tokens of the option after the prompt, unconstrained: the
+    /// model's own probability of answering with it.
+    pub logprob: f32,
+    /// The tokens of the option.
+    pub tokens: Vec<LlamaToken>,
+}
+
+/// A node of the prefix tree of the tokenized options.
+#[derive(Debug, Default)]
+struct Node {
+    children: Vec<(LlamaToken, usize)>,
+    /// The option that ends here, the first one if several tokenize the same.
+    option: Option<usize>,
+}
+
+/// The prefix tree of `options`, the root first.
+fn prefix_tree(options: &[Vec<LlamaToken>]) -> Vec<Node> {
+    let mut nodes = vec![Node::default()];
+    for (index, tokens) in options.iter().enumerate() {
+        let mut node = 0;
+        for &token in tokens {
+            node = match nodes[node].children.iter().find(|(t, _)| *t == token) {
+                Some(&(_, child)) => child,
+                None => {
+                    nodes.push(Node::default());
+                    let child = nodes.len() - 1;
+                    nodes[node].children.push((token, child));
+                    child
+                }
+            };
+        }
+        nodes[node].option.get_or_insert(index);
+    }
+    nodes
+}
+
+/// `ln(sum(exp(logit)))`, computed without overflowing.
+fn log_sum_exp(logits: &[f32]) -> f32 {
+    let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    if !max.is_finite() {
+        return max;
+    }
+    max + logits
+        .iter()
+        .map(|logit| (logit - max).exp())
+        .sum::<f32>()
+        .ln()
+}
+
+/// The logit of `token`, negative infinity for tokens outside the vocabulary.
+fn logit(logits: &[f32], LlamaToken(id): LlamaToken) -> f32 {
+    usize::try_from(id)
+        .ok()
+        .and_then(|id| logits.get(id))
+        .copied()
+        .unwrap_or(f32::NEG_INFINITY)
+}
+
+impl LlamaContext<'_> {
+    /// Decode `prompt` on `seq_id` and let the model pick the most likely of `options`, one token
+    /// at a time among the tokens that continue an option. The options are tokenized without a
+    /// bos token, so they usually start with the space that separates them from the prompt.
+    ///
+    /// When an option is a prefix of another one (`"no"` and `"not sure"`), the longer one is
+    /// only followed if its next token is more likely than ending generation.
+    ///
+    /// The prompt is applied like [`LlamaContext::apply_prompt`] does, reusing the cached prefix.
+    /// The sequence is left in the KV cache, holding the prompt and every token of the choice
+    /// except the last one.
+    ///
+    /// # Errors
+    ///
+    /// - [`ChooseError::NoOptions`] or [`ChooseError::EmptyOption`] if there is nothing to
+    ///   choose.
+    /// - [`ChooseError::ContextFull`] if the context fills up before an option is complete.
+    /// - if tokenizing, decoding or reading the logits fails.
+    ///
+    /// # Panics
+    ///
+    /// - if `n_ctx` or `n_batch` does not fit into a usize
+    /// - if the number of tokens does not fit into a [`llama_pos`]
+    pub fn choose<S: AsRef<str>>(
+        &mut self,
+        prompt: &[LlamaToken],
+        options: &[S],
+        seq_id: i32,
+    ) -> Result<Choice, ChooseError> {
+        if options.is_empty() {
+            return Err(ChooseError::NoOptions);
+        }
+        let tokenized = options
+            .iter()
+            .enumerate()
+            .map(|(index, option)| {
+                let tokens = self.model.str_to_token(option.as_ref(), AddBos::Never)?;
+                if tokens.is_empty() {
+                    return Err(ChooseError::EmptyOption { index });
+                }
+                Ok(tokens)
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let nodes = prefix_tree(&tokenized);
+
+        self.apply_prompt(prompt, seq_id)?;
+        let n_ctx = usize::try_from(self.n_ctx()).expect("n_ctx fits into a usize");
+        let mut batch = LlamaBatch::new(1, 1);
+        let mut node = 0;
+        let mut logprob = 0.0;
+        let mut tokens = Vec::new();
+        loop {
+            let current = &nodes[node];
+            if current.children.is_empty() {
+                let index = current
+                    .option
+                    .expect("every leaf of the prefix tree ends an option");
+                return Ok(Choice {
+                    index,
+                    logprob,
+                    tokens,
+                });
+            }
+            // the prompt leaves its last token as the only one with logits, a choice its first
+            let logits = if tokens.is_empty() {
+                self.try_get_logits()?
+            } else {
+                self.try_get_logits_ith(0)?
+            };
+            let (token, child, best_logit) = current
+                .children
+                .iter()
+                .map(|&(token, child)| (token, child, logit(logits, token)))
+                .max_by(|a, b| a.2.total_cmp(&b.2))
+                .expect("the node has children");
+            if let Some(index) = current.option {
+                let end_logit = (0..self.model.n_vocab())
+                    .map(LlamaToken::new)
+                    .filter(|token| self.model.is_eog_token(*token))
+                    .map(|token| logit(logits, token))
+                    .fold(f32::NEG_INFINITY, f32::max);
+                if end_logit >= best_logit {
+                    return Ok(Choice {
+                        index,
+                        logprob,
+                        tokens,
+                    });
+                }
+            }
+            logprob += best_logit - log_sum_exp(logits);
+            tokens.push(token);
+            node = child;
+
+            // a complete option needs no more logits
+            if nodes[node].children.is_empty() {
+                continue;
+            }
+            let pos = prompt.len() + tokens.len() - 1;
+            if pos >= n_ctx {
+                return Err(ChooseError::ContextFull);
+            }
+            batch.clear();
+            batch.add(
+                token,
+                llama_pos::try_from(pos).expect("n_tokens fits into a llama_pos"),
+                &[seq_id],
+                true,
+            )?;
+            self.decode(&mut batch)?;
+        }
+    }
+}
@@ -0,0 +1,181 @@
+//! Generate JSON that matches a schema and deserialize it.
+//!
+//! [`LlamaContext::generate_json`] constrains sampling with a grammar generated from the schema
+//! by [`to_gbnf`], so the output is always syntactically valid JSON of the right shape. The
+//! keywords the grammar cannot express (`minimum`, `maxLength`, ...) are checked afterwards with
+//! [`validate`]; when that or deserializing fails the model is told what was wrong and asked
+//! again.
+
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+
+use crate::context::LlamaContext;
+use crate::generate::{FinishReason, GenerateError, Generation, GenerationParams};
+use crate::grammar::cache::CompiledGrammar;
+use crate::grammar::json_schema::{to_gbnf, validate, JsonSchemaError};
+use crate::grammar::LlamaGrammarFromStrError;
+use crate::model::AddBos;
+use crate::token::LlamaToken;
+use crate::StringToTokenError;
+
+/// Failed to generate JSON that matches the schema.
+#[derive(Debug, thiserror::Error)]
+pub enum GenerateJsonError {
+    /// The schema could not be converted to a grammar.
+    #[error("{0}")]
+    Schema(#[from] JsonSchemaError),
+    /// The grammar generated from the schema was rejected by llama.cpp.
+    #[error("{0}")]
+    Grammar(#[from] LlamaGrammarFromStrError),
+    /// Generation failed.
+    #[error("{0}")]
+    Generate(#[from] GenerateError),
+    /// The feedback for a retry could not be tokenized.
+    #[error("{0}")]
+    Tokenize(#[from] StringToTokenError),
+    /// No attempt produced a value that matches the schema.
+    #[error("no valid JSON after {attempts} attempts, the last one failed with: {reason}")]
+    Invalid {
+        /// The number of generations.
+        attempts: usize,
+        /// The output of the last attempt.
+        output: String,
+        /// Why the last output was rejected.
+        reason: String,
+    },
+}
+
+/// The result of [`LlamaContext::generate_json`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct JsonGeneration<T> {
+    /// The deserialized value.
+    pub value: T,
+    /// The generation of the accepted attempt.
+    pub generation: Generation,
+    /// The number of generations, `1` if the first one was accepted.
+    pub attempts: usize,
+}
+
+/// Why `generation` is not an acceptable value of `schema`, if it is not.
+fn check<T: DeserializeOwned>(schema: &Value, generation: &Generation) -> Result<T, String> {
+    if generation.finish_reason != FinishReason::EndOfGeneration {
+        return Err(format!(
+            "generation stopped early ({:?})",
+            generation.finish_reason
+        ));
+    }
+    let value: Value = serde_json::from_str(&generation.text).map_err(|e| e.to_string())?;
+    if let Err(violations) = validate(schema, &value) {
+        let violations: Vec<String> = violations.iter().map(ToString::to_string).collect();
+        return Err(violations.join("; "));
+    }
+    serde_json::from_value(value).map_err(|e| e.to_string())
+}
+
+impl LlamaContext<'_> {
+    /// Generate a JSON value of `schema` after `prompt` and deserialize it into `T`.
+    ///
+    /// Every attempt samples with a grammar generated from `schema` in place of
+    /// [`GenerationParams::grammar`], which is restored afterwards. An output is rejected if
+    /// generation did not end with the end of generation token, if it does not match `schema`
+    /// (see [`validate`]) or if it cannot be deserialized into `T`. Then the rejected output and
+    /// a message with the reason are appended to the prompt and generation is retried, up to
+    /// `max_retries` times.
+    ///
+    /// # Errors
+    ///
+    /// See [`GenerateJsonError`].
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use llama_cpp_2::context::LlamaContext;
+    /// # use llama_cpp_2::generate::GenerationParams;
+    /// # use llama_cpp_2::model::AddBos;
+    /// # fn run(ctx: &mut LlamaContext) -> Result<(), Box<dyn std::error::Error>> {
+    /// #[derive(serde::Deserialize)]
+    /// struct City {
+    ///     name: String,
+    ///     population: u64,
+    /// }
+    ///
+    /// let schema = serde_json::json!({
+    ///     "type": "object",
+    ///     "properties": {
+    ///         "name": { "type": "string", "maxLength": 64 },
+    ///         "population": { "type": "integer", "minimum": 0 },
+    ///     },
+    ///     "required": ["name", "population"],
+    /// });
+    /// let prompt = ctx
+    ///     .model
+    ///     .str_to_token("The largest city of France as JSON:", AddBos::Always)?;
+    /// let mut params = GenerationParams::default().with_max_tokens(128);
+    /// let city = ctx.generate_json::<City>(&prompt, &schema, &mut params, 2)?;
+    /// println!("{} has {} inhabitants", city.value.name, city.value.population);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn generate_json<T: DeserializeOwned>(
+        &mut self,
+        prompt: &[LlamaToken],
+        schema: &Value,
+        params: &mut GenerationParams,
+        max_retries: usize,
+    ) -> Result<JsonGeneration<T>, GenerateJsonError> {
+        let grammar = CompiledGrammar::new(&to_gbnf(schema)?)?;
+        let original_grammar = params.grammar.take();
+        let result = self.generate_json_attempts(prompt, schema, &grammar, params, max_retries);
+        params.grammar = original_grammar;
+        result
+    }
+
+    fn generate_json_attempts<T: DeserializeOwned>(
+        &mut self,
+        prompt: &[LlamaToken],
+        schema: &Value,
+        grammar: &CompiledGrammar,
+        params: &mut GenerationParams,
+        max_retries: usize,
+    ) -> Result<JsonGeneration<T>, GenerateJsonError> {
+        let mut prompt = prompt.to_vec();
+        let mut attempts = 0;
+        loop {
+            attempts += 1;
+            params.grammar = Some(grammar.instantiate());
+            let generation =
+                self.generate(&prompt, params, |_| std::ops::ControlFlow::Continue(()))?;
+            if generation.finish_reason == FinishReason::Cancelled {
+                return Err(GenerateJsonError::Invalid {
+                    attempts,
+                    output: generation.text,
+                    reason: "generation was cancelled".to_string(),
+                });
+            }
+            let reason = match check::<T>(schema, &generation) {
+                Ok(value) => {
+                    return Ok(JsonGeneration {
+                        value,
+                        generation,
+                        attempts,
+                    })
+                }
+                Err(reason) => reason,
+            };
+            tracing::debug!(attempts, %reason, "generated JSON was rejected");
+            if attempts > max_retries {
+                return Err(GenerateJsonError::Invalid {
+                    attempts,
+                    output: generation.text,
+                    reason,
+                });
+            }
+
+            prompt.extend_from_slice(&generation.tokens);
+            let feedback = format!(
+                "\nThe JSON above is invalid: {reason}. Reply again with only the corrected JSON.\n"
+            );
+            prompt.extend(self.model.str_to_token(&feedback, AddBos::Never)?);
+        }
+    }
+}
@@ -0,0 +1,145 @@
+//! The latency of every generated token, to diagnose jitter such as thermal throttling or defrag
+//! pauses that the average rate of [`GenerationStats`](super::GenerationStats) hides.
+//!
+//! Give [`GenerationParams::with_latencies`](super::GenerationParams::with_latencies) a
+//! [`TokenLatencies`] buffer and [`LlamaContext::generate`](crate::context::LlamaContext::generate)
+//! records the time between consecutive sampled tokens into it: sampling, the callback and
+//! decoding the token. The buffer is cleared at the start of every generation and kept in the
+//! params, so it is reused without allocating; the percentiles are on the final
+//! [`GenerationStats::latency`](super::GenerationStats::latency).
+//!
+//! ```no_run
+//! # use std::ops::ControlFlow;
+//! # use llama_cpp_2::context::LlamaContext;
+//! # use llama_cpp_2::generate::latency::TokenLatencies;
+//! # use llama_cpp_2::generate::GenerationParams;
+//! # use llama_cpp_2::token::LlamaToken;
+//! # fn run(ctx: &mut LlamaContext, prompt: &[LlamaToken]) -> Result<(), Box<dyn std::error::Error>> {
+//! let mut params = GenerationParams::default().with_latencies(TokenLatencies::with_capacity(512));
+//! let generation = ctx.generate(prompt, &mut params, |_| ControlFlow::Continue(()))?;
+//! if let Some(latency) = generation.stats.latency {
+//!     println!("per token: {latency}");
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use std::fmt::{Display, Formatter};
+use std::time::Duration;
+
+/// A buffer of per-token latencies, see the [module docs](self).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TokenLatencies {
+    steps: Vec<Duration>,
+}
+
+impl TokenLatencies {
+    /// An empty buffer.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// An empty buffer with room for `n_tokens` latencies.
+    #[must_use]
+    pub fn with_capacity(n_tokens: usize) -> Self {
+        Self {
+            steps: Vec::with_capacity(n_tokens),
+        }
+    }
+
+    /// Record the latency of the next token.
+    pub fn push(&mut self, latency: Duration) {
+        self.steps.push(latency);
+    }
+
+    /// Remove every latency, keeping the allocation.
+    pub fn clear(&mut self) {
+        self.steps.clear();
+    }
+
+    /// The latencies in the order the tokens were generated.
+    #[must_use]
+    pub fn as_slice(&self) -> &[Duration] {
+        &self.steps
+    }
+
+    /// The number of latencies.
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.steps.len()
+    }
+
+    /// Whether no latency was recorded.
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.steps.is_empty()
+    }
+
+    /// The latency below which `percentile` percent of the tokens are, by the nearest rank.
+    /// `None` if no latency was recorded. `percentile` is clamped to `0..=100`.
+    ///
+    /// ```
+    /// # use std::time::Duration;
+    /// # use llama_cpp_2::generate::latency::TokenLatencies;
+    /// let mut latencies = TokenLatencies::new();
+    /// for ms in [30, 10, 20, 40] {
+    ///     latencies.push(Duration::from_millis(ms));
+    /// }
+    /// assert_eq!(latencies.percentile(50.0), Some(Duration::from_millis(20)));
+    /// assert_eq!(latencies.percentile(95.0), Some(Duration::from_millis(40)));
+    /// assert_eq!(TokenLatencies::new().percentile(50.0), None);
+    /// ```
+    #[must_use]
+    pub fn percentile(&self, percentile: f64) -> Option<Duration> {
+        let mut sorted = self.steps.clone();
+        sorted.sort_unstable();
+        nearest_rank(&sorted, percentile)
+    }
+
+    /// The median, 95th percentile and largest latency. `None` if no latency was recorded.
+    #[must_use]
+    pub fn summary(&self) -> Option<LatencySummary> {
+        let mut sorted = self.steps.clone();
+        sorted.sort_unstable();
+        Some(LatencySummary {
+            p50: nearest_rank(&sorted, 50.0)?,
+            p95: nearest_rank(&sorted, 95.0)?,
+            max: *sorted.last()?,
+        })
+    }
+}
+
+/// The smallest value of `sorted` that at least `percentile` percent of the values are not above.
+#[allow(
+    clippy::cast_possible_truncation,
+    clippy::cast_precision_loss,
+    clippy::cast_sign_loss
+)]
+fn nearest_rank(sorted: &[Duration], percentile: f64) -> Option<Duration> {
+    let rank = (percentile.clamp(0.0, 100.0) / 100.0 * sorted.len() as f64).ceil() as usize;
+    sorted.get(rank.saturating_sub(1)).copied()
+}
+
+/// Percentiles of the per-token latencies of a generation, see [`TokenLatencies::summary`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LatencySummary {
+    /// The median latency.
+    pub p50: Duration,
+    /// The latency 95% of the tokens are not above.
+    pub p95: Duration,
+    /// The largest latency.
+    pub max: Duration,
+}
+
+impl Display for LatencySummary {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "p50 {:.2} ms, p95 {:.2} ms, max {:.2} ms",
+            self.p50.as_secs_f64() * 1000.0,
+            self.p95.as_secs_f64() * 1000.0,
+            self.max.as_secs_f64() * 1000.0
+        )
+    }
+}
@@ -0,0 +1,506 @@
+//! Experimental lookahead decoding: speculative decoding without a draft model.
+//!
+//! Text often repeats itself: code quotes identifiers of the prompt, summaries quote the document
+//! and lists repeat their structure. [`LlamaContext::generate_lookahead`] looks up the last few
+//! generated tokens in the prompt and output so far and treats what followed them there as
+//! candidate continuations. All candidates are verified in one decode, each on a sequence of its
+//! own sharing the KV cache of the prompt, and the longest one the model agrees with is accepted,
+//! so a step can produce several tokens for the cost of one decode.
+//!
+//! Verification is greedy, so the output is the same as [`SamplingParams::greedy`] would produce.
+//! This pays off on memory bandwidth bound devices, where decoding a few more tokens per batch is
+//! almost free.
+//!
+//! ```no_run
+//! # use std::ops::ControlFlow;
+//! # use llama_cpp_2::context::LlamaContext;
+//! # use llama_cpp_2::generate::lookahead::Lookahead;
+//! # use llama_cpp_2::generate::GenerationParams;
+//! # use llama_cpp_2::token::LlamaToken;
+//! # fn run(ctx: &mut LlamaContext, prompt: &[LlamaToken]) -> Result<(), Box<dyn std::error::Error>> {
+//! // the context needs n_seq_max of at least 1 + the number of candidates
+//! let lookahead = Lookahead::new(8, 3).expect("at least one token and one candidate");
+//! let mut params = GenerationParams::default().with_max_tokens(256);
+//! let result = ctx.generate_lookahead(prompt, &mut params, &lookahead, |event| {
+//!     print!("{}", event.text);
+//!     ControlFlow::Continue(())
+//! })?;
+//! println!("\n{}", result.speculative);
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! [`SamplingParams::greedy`]: super::SamplingParams::greedy
+
+use std::ops::ControlFlow;
+use std::time::{Duration, Instant};
+
+use crate::context::LlamaContext;
+use crate::generate::stop::{StopCheck, StoppingCriteria};
+use crate::generate::{
+    push_lossy, push_utf8, Confidence, FinishReason, GenerateError, Generation, GenerationParams,
+    GenerationStats, Sampled, TokenEvent,
+};
+use crate::llama_batch::LlamaBatch;
+use crate::model::Special;
+use crate::speculative::SpeculativeStats;
+use crate::token::LlamaToken;
+use crate::{DecodeError, LogitsError};
+use llama_cpp_sys_2::llama_pos;
+
+/// The parameters of [`LlamaContext::generate_lookahead`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Lookahead {
+    n_draft: usize,
+    n_candidates: usize,
+    ngram: usize,
+}
+
+impl Lookahead {
+    /// Verify up to `n_candidates` continuations of up to `n_draft` tokens each per step,
+    /// looked up by the last 2 tokens. Returns `None` if either is 0.
+    #[must_use]
+    pub fn new(n_draft: usize, n_candidates: usize) -> Option<Self> {
+        (n_draft > 0 && n_candidates > 0).then_some(Self {
+            n_draft,
+            n_candidates,
+            ngram: 2,
+        })
+    }
+
+    /// Look continuations up by the last `ngram` tokens, falling back to fewer if that finds
+    /// none. Longer n-grams find fewer but better candidates.
+    #[must_use]
+    pub fn with_ngram(mut self, ngram: usize) -> Self {
+        self.ngram = ngram.max(1);
+        self
+    }
+
+    /// The maximum length of a candidate.
+    #[must_use]
+    pub fn n_draft(&self) -> usize {
+        self.n_draft
+    }
+
+    /// The maximum number of candidates per step.
+    #[must_use]
+    pub fn n_candidates(&self) -> usize {
+        self.n_candidates
+    }
+
+    /// The continuations of the end of `history`: the tokens that followed earlier occurrences
+    /// of its last n-gram, most recent occurrence first and without duplicates.
+    ///
+    /// ```
+    /// # use llama_cpp_2::generate::lookahead::Lookahead;
+    /// # use llama_cpp_2::token::LlamaToken;
+    /// let history: Vec<LlamaToken> = [1, 2, 3, 4, 1, 2, 5, 1, 2].map(LlamaToken::new).to_vec();
+    /// let lookahead = Lookahead::new(2, 4).unwrap();
+    /// let candidates = lookahead.candidates(&history);
+    /// assert_eq!(candidates, [[5, 1].map(LlamaToken::new), [3, 4].map(LlamaToken::new)]);
+    /// ```
+    #[must_use]
+    pub fn candidates<'h>(&self, history: &'h [LlamaToken]) -> Vec<&'h [LlamaToken]> {
+        let mut candidates: Vec<&[LlamaToken]> = Vec::new();
+        for ngram in (1..=self.ngram.min(history.len())).rev() {
+            let key = &history[history.len() - ngram..];
+            // occurrences that are followed by at least one token, most recent first
+            for start in (0..history.len() - ngram).rev() {
+                if &history[start..start + ngram] != key {
+                    continue;
+                }
+                let from = start + ngram;
+                let candidate = &history[from..(from + self.n_draft).min(history.len())];
+                if !candidates.contains(&candidate) {
+                    candidates.push(candidate);
+                    if candidates.len() == self.n_candidates {
+                        return candidates;
+                    }
+                }
+            }
+            if !candidates.is_empty() {
+                break;
+            }
+        }
+        candidates
+    }
+}
+
+/// The result of [`LlamaContext::generate_lookahead`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct LookaheadGeneration {
+    /// The generation, as [`LlamaContext::generate`] returns it.
+    pub generation: Generation,
+    /// How many candidate tokens were verified and accepted. Every candidate of a step counts as
+    /// drafted, the accepted tokens are those of the best one.
+    pub speculative: SpeculativeStats,
+}
+
+impl LlamaContext<'_> {
+    /// Like [`LlamaContext::generate`] with greedy sampling, but verify the candidate
+    /// continuations of [`Lookahead::candidates`] along with every token, see the
+    /// [module docs](self).
+    ///
+    /// Candidates are decoded on the sequences other than [`GenerationParams::seq_id`], as many
+    /// as [`n_seq_max`](LlamaContext::n_seq_max) allows. Those sequences are cleared before and
+    /// after every step, so they must not be in use. The sampling parameters, grammar and logits
+    /// processors of `params` are ignored, context shifting and self-extend are not supported.
+    /// The sequence is left in the KV cache as `generate` leaves it.
+    ///
+    /// # Errors
+    ///
+    /// See [`GenerateError`].
+    ///
+    /// # Panics
+    ///
+    /// - if `n_ctx`, `n_batch` or `n_seq_max` does not fit into a usize
+    /// - if `n_ctx` does not fit into a [`llama_pos`]
+    #[allow(clippy::too_many_lines)]
+    pub fn generate_lookahead(
+        &mut self,
+        prompt: &[LlamaToken],
+        params: &mut GenerationParams,
+        lookahead: &Lookahead,
+        mut on_token: impl FnMut(TokenEvent<'_>) -> ControlFlow<()>,
+    ) -> Result<LookaheadGeneration, GenerateError> {
+        let start = Instant::now();
+        let n_ctx = self.n_ctx();
+        let n_ctx_pos = llama_pos::try_from(n_ctx).expect("n_ctx fits into a llama_pos");
+        if prompt.is_empty() {
+            return Err(GenerateError::EmptyPrompt);
+        }
+        if prompt.len() >= usize::try_from(n_ctx).expect("n_ctx fits into a usize") {
+            return Err(GenerateError::PromptTooLong {
+                n_tokens: prompt.len(),
+                n_ctx,
+            });
+        }
+
+        let seq_id = params.seq_id;
+        let n_batch = usize::try_from(self.n_batch()).expect("n_batch fits into a usize");
+        let n_seq_max = usize::try_from(self.n_seq_max()).expect("n_seq_max fits into a usize");
+        let candidate_seqs: Vec<i32> = (0..)
+            .filter(|&id| id != seq_id)
+            .take(lookahead.n_candidates.min(n_seq_max.saturating_sub(1)))
+            .collect();
+        let n_draft = lookahead
+            .n_draft
+            .min(n_batch.saturating_sub(1) / candidate_seqs.len().max(1));
+
+        let n_cached = params.n_cached.min(prompt.len() - 1);
+        let mut n_past = llama_pos::try_from(n_cached).expect("n_cached fits into a llama_pos");
+        self.remove_seq(seq_id, n_past);
+        let mut batch = LlamaBatch::new(n_batch, i32::try_from(n_seq_max).unwrap_or(i32::MAX));
+        let uncached = &prompt[n_cached..];
+        for (i, chunk) in uncached.chunks(n_batch).enumerate() {
+            batch.clear();
+            for (j, token) in chunk.iter().enumerate() {
+                batch.add(
+                    *token,
+                    n_past,
+                    &[seq_id],
+                    i * n_batch + j == uncached.len() - 1,
+                )?;
+                n_past += 1;
+            }
+            let decoded = if params.is_disconnected() {
+                Err(DecodeError::Aborted)
+            } else {
+                self.decode(&mut batch)
+            };
+            match decoded {
+                Err(DecodeError::Aborted) => {
+                    self.clear_kv_cache_seq(seq_id, None, None);
+                    return Ok(LookaheadGeneration {
+                        generation: Generation {
+                            text: String::new(),
+                            tokens: Vec::new(),
+                            finish_reason: FinishReason::Cancelled,
+                            stats: GenerationStats {
+                                prompt_time: start.elapsed(),
+                                ..GenerationStats::default()
+                            },
+                            sampler_state: None,
+                        },
+                        speculative: SpeculativeStats::default(),
+                    });
+                }
+                result => result?,
+            }
+        }
+        let prompt_done = Instant::now();
+        let mut output = Output {
+            history: prompt.to_vec(),
+            tokens: Vec::new(),
+            text: String::new(),
+            pending: Vec::new(),
+            streamed: 0,
+            stats: GenerationStats {
+                n_prompt_tokens: uncached.len(),
+                prompt_time: prompt_done - start,
+                ..GenerationStats::default()
+            },
+        };
+        let mut speculative = SpeculativeStats::default();
+
+        let mut sampled = self.greedy(batch.n_tokens() - 1)?;
+        let finish_reason = 'generate: loop {
+            if let Some(reason) =
+                output.push(self, params, sampled, start, prompt_done, &mut on_token)?
+            {
+                break reason;
+            }
+            if n_past >= n_ctx_pos {
+                break FinishReason::ContextFull;
+            }
+
+            // candidates may not run past the end of the context
+            let room = usize::try_from(n_ctx_pos - n_past - 1).unwrap_or(0);
+            let candidates: Vec<Vec<LlamaToken>> = lookahead
+                .candidates(&output.history)
+                .into_iter()
+                .take(candidate_seqs.len())
+                .map(|candidate| candidate[..candidate.len().min(n_draft).min(room)].to_vec())
+                .filter(|candidate| !candidate.is_empty())
+                .collect();
+            let seqs = &candidate_seqs[..candidates.len()];
+
+            let step_start = Instant::now();
+            batch.clear();
+            let mut token_seqs = vec![seq_id];
+            token_seqs.extend_from_slice(seqs);
+            for &seq in seqs {
+                self.remove_seq(seq, -1);
+                unsafe {
+                    llama_cpp_sys_2::llama_kv_cache_seq_cp(
+                        self.context.as_ptr(),
+                        seq_id,
+                        seq,
+                        -1,
+                        -1,
+                    );
+                }
+                self.forget_cached_tokens(seq, 0);
+            }
+            batch.add(sampled.token, n_past, &token_seqs, true)?;
+            for (candidate, &seq) in candidates.iter().zip(seqs) {
+                for (pos, &draft) in (n_past + 1..).zip(candidate) {
+                    batch.add(draft, pos, &[seq], true)?;
+                }
+            }
+            let decoded = self.decode(&mut batch);
+            if let Err(DecodeError::Aborted) = decoded {
+                for &seq in seqs {
+                    self.remove_seq(seq, -1);
+                }
+                self.remove_seq(seq_id, n_past);
+                break FinishReason::Cancelled;
+            }
+            decoded?;
+
+            // walk every candidate as long as it agrees with the model
+            let next = self.greedy(0)?;
+            let mut best = (0, Vec::new(), next);
+            let mut first = 1;
+            for (i, candidate) in candidates.iter().enumerate() {
+                let mut next = next;
+                let mut accepted = Vec::new();
+                while accepted.len() < candidate.len() && candidate[accepted.len()] == next.token {
+                    accepted.push(next);
+                    // the prediction after the accepted token
+                    let index = i32::try_from(first + accepted.len() - 1)
+                        .expect("batch indices fit into an i32");
+                    next = self.greedy(index)?;
+                }
+                if accepted.len() > best.1.len() {
+                    best = (i, accepted, next);
+                }
+                first += candidate.len();
+            }
+            let (best_index, accepted, next) = best;
+            let n_accepted = accepted.len();
+            speculative.record_round(
+                candidates.iter().map(Vec::len).sum(),
+                n_accepted,
+                Duration::ZERO,
+                step_start.elapsed(),
+            );
+
+            // keep the accepted tokens of the best candidate on the sequence
+            if n_accepted > 0 {
+                let end = n_past + 1 + llama_pos::try_from(n_accepted).expect("n_draft fits");
+                unsafe {
+                    llama_cpp_sys_2::llama_kv_cache_seq_cp(
+                        self.context.as_ptr(),
+                        seqs[best_index],
+                        seq_id,
+                        n_past + 1,
+                        end,
+                    );
+                }
+                self.forget_cached_tokens(seq_id, n_past + 1);
+            }
+            for &seq in seqs {
+                self.remove_seq(seq, -1);
+            }
+            n_past += 1;
+
+            for draft in accepted {
+                n_past += 1;
+                if let Some(reason) =
+                    output.push(self, params, draft, start, prompt_done, &mut on_token)?
+                {
+                    break 'generate reason;
+                }
+            }
+            sampled = next;
+        };
+
+        push_lossy(&mut output.text, &mut output.pending);
+        Ok(LookaheadGeneration {
+            generation: Generation {
+                text: output.text,
+                tokens: output.tokens,
+                finish_reason,
+                stats: output.stats,
+                sampler_state: None,
+            },
+            speculative,
+        })
+    }
+
+    /// Remove the positions from `p0` on of `seq_id`, all of them if `p0` is negative.
+    fn remove_seq(&mut self, seq_id: i32, p0: llama_pos) {
+        unsafe {
+            llama_cpp_sys_2::llama_kv_cache_seq_rm(self.context.as_ptr(), seq_id, p0, -1);
+        }
+        self.forget_cached_tokens(seq_id, p0);
+    }
+
+    /// The most likely token at the ith position of the last batch, with its log probability
+    /// and confidence over the whole vocabulary.
+    fn greedy(&self, i: i32) -> Result<Sampled, LogitsError> {
+        let logits = self.try_get_logits_ith(i)?;
+        let (id, _) = logits
+            .iter()
+            .enumerate()
+            .max_by(|(_, a), (_, b)| a.total_cmp(b))
+            .unwrap_or((0, &0.0));
+        let token = LlamaToken::new(i32::try_from(id).expect("token ids fit into an i32"));
+        let (logprob, confidence) = log_softmax(logits, id);
+        Ok(Sampled {
+            token,
+            logprob,
+            confidence,
+        })
+    }
+}
+
+/// `ln(softmax(logits)[id])` and the confidence in `id`.
+fn log_softmax(logits: &[f32], id: usize) -> (f32, Confidence) {
+    let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    let sum: f32 = logits.iter().map(|logit| (logit - max).exp()).sum();
+    let log_sum_exp = max + sum.ln();
+    let entropy = logits
+        .iter()
+        .map(|logit| logit - log_sum_exp)
+        .filter(|logprob| logprob.is_finite())
+        .map(|logprob| -logprob.exp() * logprob)
+        .sum();
+    let logprob = logits
+        .get(id)
+        .map_or(f32::NEG_INFINITY, |logit| logit - log_sum_exp);
+    let confidence = Confidence {
+        probability: logprob.exp(),
+        entropy,
+    };
+    (logprob, confidence)
+}
+
+/// The generated text and tokens, streamed like [`LlamaContext::generate`] streams them.
+struct Output {
+    history: Vec<LlamaToken>,
+    tokens: Vec<LlamaToken>,
+    text: String,
+    pending: Vec<u8>,
+    streamed: usize,
+    stats: GenerationStats,
+}
+
+impl Output {
+    /// Append `token` and pass the streamable text to `on_token`. Returns why generation ends,
+    /// if it does.
+    #[allow(clippy::too_many_arguments)]
+    fn push(
+        &mut self,
+        ctx: &LlamaContext,
+        params: &mut GenerationParams,
+        Sampled {
+            token,
+            logprob,
+            confidence,
+        }: Sampled,
+        start: Instant,
+        prompt_done: Instant,
+        on_token: &mut impl FnMut(TokenEvent<'_>) -> ControlFlow<()>,
+    ) -> Result<Option<FinishReason>, GenerateError> {
+        self.stats
+            .time_to_first_token
+            .get_or_insert_with(|| start.elapsed());
+        self.stats.generation_time = prompt_done.elapsed();
+        if ctx.model.is_eog_token(token) {
+            push_lossy(&mut self.text, &mut self.pending);
+            let _ = on_token(TokenEvent {
+                token,
+                text: &self.text[self.streamed..],
+                logprob,
+                confidence,
+                stats: self.stats,
+            });
+            self.streamed = self.text.len();
+            return Ok(Some(FinishReason::EndOfGeneration));
+        }
+
+        self.tokens.push(token);
+        self.stats.n_generated_tokens = self.tokens.len();
+        self.history.push(token);
+        self.pending
+            .extend(ctx.model.token_to_bytes(token, Special::Plaintext)?);
+        push_utf8(&mut self.text, &mut self.pending);
+
+        let check = StopCheck {
+            tokens: &self.tokens,
+            text: &self.text,
+            elapsed: start.elapsed(),
+        };
+        let stop = params.stopping.check(&check);
+        let mut end = match &stop {
+            Some(stop) => stop.text_len.min(self.text.len()),
+            None => self.text.len() - params.stopping.holdback(&self.text).min(self.text.len()),
+        };
+        while !self.text.is_char_boundary(end) {
+            end -= 1;
+        }
+        let end = end.max(self.streamed);
+        let flow = on_token(TokenEvent {
+            token,
+            text: &self.text[self.streamed..end],
+            logprob,
+            confidence,
+            stats: self.stats,
+        });
+        self.streamed = end;
+
+        if let Some(stop) = stop {
+            self.text.truncate(end);
+            return Ok(Some(stop.reason));
+        }
+        if flow.is_break() || ctx.is_cancelled() || params.is_disconnected() {
+            return Ok(Some(FinishReason::Cancelled));
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests;
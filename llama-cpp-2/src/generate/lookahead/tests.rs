@@ -0,0 +1,61 @@
+use std::num::NonZeroU32;
+
+use super::*;
+use crate::context::params::LlamaContextParams;
+use crate::generate::SamplingParams;
+use crate::model::{AddBos, LlamaModel};
+use crate::test_utils::{self, TinyModel};
+
+const N_SEQ_MAX: u32 = 4;
+
+fn context(model: &LlamaModel) -> LlamaContext<'_> {
+    let params = LlamaContextParams::deterministic()
+        .with_n_ctx(NonZeroU32::new(256))
+        .with_n_seq_max(N_SEQ_MAX);
+    model.new_context(test_utils::backend(), params).unwrap()
+}
+
+fn params() -> GenerationParams {
+    GenerationParams::default()
+        .with_sampling(SamplingParams::greedy())
+        .with_max_tokens(32)
+}
+
+#[test]
+fn lookahead_generates_the_same_tokens_as_greedy_generation() {
+    let model = TinyModel::default().load(test_utils::backend()).unwrap();
+    // repetition gives the lookup candidates to verify
+    let prompt = model
+        .str_to_token(
+            "the cat is a cat and the cat is a dog and the cat is",
+            AddBos::Always,
+        )
+        .unwrap();
+
+    let mut ctx = context(&model);
+    let expected = ctx
+        .generate(&prompt, &mut params(), |_| ControlFlow::Continue(()))
+        .unwrap();
+    let expected_pos_max = ctx.kv_cache_seq_pos_max(0);
+
+    let mut ctx = context(&model);
+    let lookahead = Lookahead::new(4, 3).unwrap();
+    let result = ctx
+        .generate_lookahead(&prompt, &mut params(), &lookahead, |_| {
+            ControlFlow::Continue(())
+        })
+        .unwrap();
+
+    assert_eq!(result.generation.tokens, expected.tokens);
+    assert_eq!(result.generation.text, expected.text);
+    assert_eq!(result.generation.finish_reason, expected.finish_reason);
+    assert_eq!(ctx.kv_cache_seq_pos_max(0), expected_pos_max);
+    for seq_id in 1..N_SEQ_MAX {
+        let seq_id = i32::try_from(seq_id).unwrap();
+        assert_eq!(
+            ctx.kv_cache_seq_pos_max(seq_id),
+            -1,
+            "seq {seq_id} is not empty"
+        );
+    }
+}
@@ -0,0 +1,89 @@
+//! Self-extend (group attention) for running a model past the context it was trained on.
+//!
+//! Self-extend keeps the positions of the KV cache within the trained context by grouping every
+//! `ga_n` old positions into one with [`LlamaContext::kv_cache_seq_div`], a window of `ga_w`
+//! positions at a time, while the most recent tokens keep their exact positions. It needs no
+//! fine-tuning, only a context with enough cells for all the tokens. Set the rope scaling to
+//! [`RopeScalingType::None`] explicitly, otherwise
+//! [`LlamaContextParams::validate`] rejects a context longer than the trained one:
+//!
+//! ```no_run
+//! # use std::num::NonZeroU32;
+//! # use llama_cpp_2::context::params::{LlamaContextParams, RopeScalingType};
+//! # use llama_cpp_2::generate::self_extend::SelfExtend;
+//! # use llama_cpp_2::generate::GenerationParams;
+//! // a model trained on 4096 tokens, running with 4 times that
+//! let ctx_params = LlamaContextParams::default()
+//!     .with_n_ctx(NonZeroU32::new(16384))
+//!     .with_rope_scaling_type(RopeScalingType::None);
+//! let params = GenerationParams::default()
+//!     .with_self_extend(SelfExtend::new(4, 2048).expect("2048 is a multiple of 4"));
+//! ```
+//!
+//! [`LlamaContext::kv_cache_seq_div`]: crate::context::LlamaContext::kv_cache_seq_div
+//! [`RopeScalingType::None`]: crate::context::params::RopeScalingType::None
+//! [`LlamaContextParams::validate`]: crate::context::params::LlamaContextParams::validate
+
+use crate::context::LlamaContext;
+use llama_cpp_sys_2::llama_pos;
+
+/// The parameters of self-extend, see the [module docs](self).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SelfExtend {
+    ga_n: llama_pos,
+    ga_w: llama_pos,
+}
+
+impl SelfExtend {
+    /// Group `ga_n` positions into one in windows of `ga_w` positions. Returns `None` unless
+    /// `ga_n` is at least 2 and `ga_w` is a multiple of it.
+    #[must_use]
+    pub fn new(ga_n: u32, ga_w: u32) -> Option<Self> {
+        let ga_n = llama_pos::try_from(ga_n).ok()?;
+        let ga_w = llama_pos::try_from(ga_w).ok()?;
+        (ga_n > 1 && ga_w > 0 && ga_w % ga_n == 0).then_some(Self { ga_n, ga_w })
+    }
+
+    /// The group attention factor.
+    #[must_use]
+    pub fn ga_n(&self) -> u32 {
+        self.ga_n.unsigned_abs()
+    }
+
+    /// The group attention width.
+    #[must_use]
+    pub fn ga_w(&self) -> u32 {
+        self.ga_w.unsigned_abs()
+    }
+
+    /// The number of positions that can be decoded at once without skipping a window.
+    pub(crate) fn n_batch(&self, n_batch: usize) -> usize {
+        n_batch.min(usize::try_from(self.ga_w).expect("ga_w fits into a usize"))
+    }
+
+    /// Compress every full window before `n_past` on `seq_id`, the same way `llama-cli` does with
+    /// `--grp-attn-n` and `--grp-attn-w`. `ga_i` is the start of the next window, starting at 0,
+    /// and `n_past` is reduced by the positions that were freed.
+    pub(crate) fn apply(
+        &self,
+        ctx: &mut LlamaContext,
+        seq_id: i32,
+        ga_i: &mut llama_pos,
+        n_past: &mut llama_pos,
+    ) {
+        let Self { ga_n, ga_w } = *self;
+        while *n_past >= *ga_i + ga_w {
+            let ib = (ga_n * *ga_i) / ga_w;
+            let bd = (ga_w / ga_n) * (ga_n - 1);
+            let dd = (ga_w / ga_n) - ib * bd - ga_w;
+            ctx.kv_cache_seq_add_pos(seq_id, *ga_i, *n_past, ib * bd);
+            ctx.kv_cache_seq_div_pos(seq_id, *ga_i + ib * bd, *ga_i + ib * bd + ga_w, ga_n);
+            ctx.kv_cache_seq_add_pos(seq_id, *ga_i + ib * bd + ga_w, *n_past + ib * bd, dd);
+            *n_past -= bd;
+            *ga_i += ga_w / ga_n;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests;
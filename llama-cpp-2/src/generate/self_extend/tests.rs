@@ -0,0 +1,48 @@
+use std::num::NonZeroU32;
+use std::ops::ControlFlow;
+
+use super::*;
+use crate::context::params::{LlamaContextParams, RopeScalingType};
+use crate::generate::{FinishReason, GenerationParams, SamplingParams};
+use crate::model::AddBos;
+use crate::test_utils::{self, TinyModel};
+
+#[test]
+fn new_checks_the_group_size() {
+    assert!(SelfExtend::new(4, 32).is_some());
+    assert!(SelfExtend::new(1, 32).is_none());
+    assert!(SelfExtend::new(4, 30).is_none());
+    assert!(SelfExtend::new(256, 512).is_some());
+}
+
+#[test]
+fn positions_stay_within_the_trained_context() {
+    let model = TinyModel::default()
+        .with_n_ctx_train(64)
+        .load(test_utils::backend())
+        .unwrap();
+    let ctx_params = LlamaContextParams::default()
+        .with_n_ctx(NonZeroU32::new(256))
+        .with_rope_scaling_type(RopeScalingType::None);
+    let mut ctx = model
+        .new_context(test_utils::backend(), ctx_params)
+        .unwrap();
+    let prompt = model.str_to_token("the cat is", AddBos::Always).unwrap();
+    let mut params = GenerationParams::default()
+        .with_sampling(SamplingParams::greedy())
+        .with_max_tokens(100)
+        .with_ignore_eos(&model)
+        .with_self_extend(SelfExtend::new(4, 32).unwrap());
+
+    let generation = ctx
+        .generate(&prompt, &mut params, |_| ControlFlow::Continue(()))
+        .unwrap();
+
+    assert_eq!(generation.finish_reason, FinishReason::MaxTokens);
+    // every token but the last generated one is in the cache, at compressed positions
+    let n_past = i32::try_from(prompt.len() + generation.tokens.len() - 1).unwrap();
+    assert_eq!(ctx.get_kv_cache_token_count(), n_past);
+    let pos_max = ctx.kv_cache_seq_pos_max(0);
+    assert!(pos_max < n_past - 1, "{pos_max} is not compressed");
+    assert!(pos_max < 64, "{pos_max} is past the trained context");
+}
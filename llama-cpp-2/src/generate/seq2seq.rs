@@ -0,0 +1,78 @@
+//! Generation with encoder-decoder models such as T5 and Flan-T5.
+//!
+//! These models do not continue a prompt. The source text is run through the encoder once and
+//! the decoder generates the target from scratch, attending to the encoder output, starting from
+//! the model's [decoder start token](crate::model::LlamaModel::decode_start_token).
+//! [`LlamaContext::generate_seq2seq`] does both and then runs the normal generation loop.
+//! [`LlamaContext::generate`] and everything built on it (streaming, chat, JSON) call it for
+//! models that [have an encoder](crate::model::LlamaModel::has_encoder), so the same code runs
+//! both kinds of models:
+//!
+//! ```no_run
+//! # use std::ops::ControlFlow;
+//! # use llama_cpp_2::context::LlamaContext;
+//! # use llama_cpp_2::generate::GenerationParams;
+//! # use llama_cpp_2::model::AddBos;
+//! # fn run(ctx: &mut LlamaContext) -> Result<(), Box<dyn std::error::Error>> {
+//! let source = ctx
+//!     .model
+//!     .str_to_token("translate English to German: The house is wonderful.", AddBos::Always)?;
+//! let mut params = GenerationParams::default().with_max_tokens(64);
+//! let generation = ctx.generate(&source, &mut params, |_| ControlFlow::Continue(()))?;
+//! println!("{}", generation.text);
+//! # Ok(())
+//! # }
+//! ```
+
+use std::ops::ControlFlow;
+
+use crate::context::LlamaContext;
+use crate::generate::{GenerateError, Generation, GenerationParams, TokenEvent};
+use crate::llama_batch::LlamaBatch;
+use crate::token::LlamaToken;
+
+impl LlamaContext<'_> {
+    /// Encode `source` and generate the decoder output for it with `params`, see the
+    /// [module docs](self). The decoder starts from the model's decoder start token, or its bos
+    /// token if it has none. The encoder output replaces the previous one, so the decoder never
+    /// reuses cached tokens: [`GenerationParams::n_cached`] is ignored.
+    ///
+    /// # Errors
+    ///
+    /// - [`GenerateError::NoEncoder`] if the model has no encoder.
+    /// - [`GenerateError::EmptyPrompt`] if `source` is empty.
+    /// - [`GenerateError::Encode`] if encoding fails, e.g. because `source` does not fit into
+    ///   one ubatch.
+    /// - See [`LlamaContext::generate`] for the errors of the decoder.
+    ///
+    /// # Panics
+    ///
+    /// See [`LlamaContext::generate`].
+    pub fn generate_seq2seq(
+        &mut self,
+        source: &[LlamaToken],
+        params: &mut GenerationParams,
+        on_token: impl FnMut(TokenEvent<'_>) -> ControlFlow<()>,
+    ) -> Result<Generation, GenerateError> {
+        if !self.model.has_encoder() {
+            return Err(GenerateError::NoEncoder);
+        }
+        if source.is_empty() {
+            return Err(GenerateError::EmptyPrompt);
+        }
+        let mut batch = LlamaBatch::new(source.len(), 1);
+        batch.add_sequence(source, params.seq_id, false)?;
+        self.encode(&mut batch)?;
+
+        let start = self.model.decode_start_token();
+        let start = if start.0 == -1 {
+            self.model.token_bos()
+        } else {
+            start
+        };
+        let n_cached = std::mem::take(&mut params.n_cached);
+        let generation = self.generate_decoder(&[start], params, on_token);
+        params.n_cached = n_cached;
+        generation
+    }
+}
@@ -0,0 +1,511 @@
+//! Generate for many requests at once on one context (continuous batching).
+//!
+//! A [`SlotManager`] owns a fixed number of slots, one per sequence of the context. Requests
+//! wait in a queue until a slot is free. Every [`SlotManager::step`] decodes a single batch with
+//! the latest token of every generating slot and as much of the pending prompts as fits, then
+//! samples each slot from its own index in the batch with its own [`GenerationParams`]: the
+//! sampling chain, seed, grammar, logits processors and stopping criteria all apply per request.
+//!
+//! Slots with a [`SamplingParams::seed`](super::SamplingParams::seed) draw from their own random
+//! number generator, so a seeded request gives the same output no matter what runs next to it.
+//! Unseeded slots share the context's generator.
+//!
+//! Grammars are per request too: a slot applies the [`GenerationParams::grammar`] of its request
+//! and accepts its sampled token into that grammar only, so requests with different grammars
+//! run side by side. Requests with the same grammar each need their own copy in its start
+//! state, from [`CompiledGrammar::instantiate`]; the copies share the compiled grammar's
+//! [mask cache](crate::grammar::mask), if it has one. A request whose grammar allows none of
+//! the candidates finishes alone with [`FinishReason::GrammarExhausted`].
+//!
+//! ```no_run
+//! # use std::ops::ControlFlow;
+//! # use llama_cpp_2::context::LlamaContext;
+//! # use llama_cpp_2::generate::slots::SlotManager;
+//! # use llama_cpp_2::generate::{GenerationParams, SamplingParams};
+//! # use llama_cpp_2::model::AddBos;
+//! # fn run(ctx: &mut LlamaContext) -> Result<(), Box<dyn std::error::Error>> {
+//! // the context was created with `LlamaContextParams::with_n_seq_max(2)`
+//! let mut slots = SlotManager::new(ctx, 2);
+//! let creative = SamplingParams { temperature: 1.2, seed: Some(7), ..SamplingParams::default() };
+//! let story = ctx.model.str_to_token("Once upon a time", AddBos::Always)?;
+//! let sum = ctx.model.str_to_token("2 + 2 =", AddBos::Always)?;
+//! slots.submit(story, GenerationParams::default().with_sampling(creative).with_max_tokens(64))?;
+//! slots.submit(sum, GenerationParams::default().with_sampling(SamplingParams::greedy()))?;
+//! while !slots.is_idle() {
+//!     let finished = slots.step(ctx, |id, event| {
+//!         print!("[{id}] {}", event.text);
+//!         ControlFlow::Continue(())
+//!     })?;
+//!     for (id, generation) in finished {
+//!         println!("\n[{id}] finished: {:?}", generation.finish_reason);
+//!     }
+//! }
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! [`CompiledGrammar::instantiate`]: crate::grammar::cache::CompiledGrammar::instantiate
+
+use std::collections::VecDeque;
+use std::fmt::{Debug, Formatter};
+use std::ops::ControlFlow;
+use std::time::Instant;
+
+use llama_cpp_sys_2::llama_pos;
+
+use crate::context::LlamaContext;
+use crate::generate::stop::{StopCheck, StoppingCriteria};
+use crate::generate::{
+    push_lossy, push_utf8, FinishReason, GenerateError, Generation, GenerationParams,
+    GenerationStats, Sampled, SeededRng, TokenEvent,
+};
+use crate::llama_batch::LlamaBatch;
+use crate::model::Special;
+use crate::token::LlamaToken;
+
+/// Identifies a request submitted to a [`SlotManager`].
+pub type RequestId = u64;
+
+/// A [`SlotManager::step`] failed for some of the requests. The failed requests were dropped and
+/// their sequences cleared, every other request goes on with the next step.
+#[derive(Debug, thiserror::Error)]
+#[error("{} requests failed: {error}", .failed.len())]
+pub struct StepError {
+    /// The first error of the step. If the batch could not be filled or decoded, every request
+    /// that was active failed with it.
+    #[source]
+    pub error: GenerateError,
+    /// The requests that failed.
+    pub failed: Vec<RequestId>,
+    /// The requests that finished in the step before or besides the failure, as
+    /// [`SlotManager::step`] returns them.
+    pub finished: Vec<(RequestId, Generation)>,
+}
+
+/// A request assigned to a sequence of the context.
+struct Slot {
+    id: RequestId,
+    seq_id: i32,
+    params: GenerationParams,
+    /// The slot's own random number generator if the request is seeded.
+    rng: Option<SeededRng>,
+    prompt: Vec<LlamaToken>,
+    /// The number of prompt tokens decoded so far.
+    n_decoded: usize,
+    /// The position of the next token in the sequence.
+    n_past: llama_pos,
+    /// The prompt and the generated tokens.
+    history: Vec<LlamaToken>,
+    tokens: Vec<LlamaToken>,
+    text: String,
+    /// Bytes of an incomplete UTF-8 character.
+    pending: Vec<u8>,
+    /// The length of the text passed to the callback.
+    streamed: usize,
+    /// The sampled token that has to be decoded in the next batch.
+    next: Option<LlamaToken>,
+    /// The index of the slot's logits in the current batch.
+    i_batch: Option<i32>,
+    start: Instant,
+    prompt_done: Option<Instant>,
+    stats: GenerationStats,
+    cancelled: bool,
+}
+
+impl Slot {
+    /// Sample the next token from the ith logits of the last batch and stream its text. Returns
+    /// why the request finished, `None` if it goes on.
+    fn sample(
+        &mut self,
+        ctx: &mut LlamaContext,
+        i: i32,
+        n_ctx_slot: usize,
+        on_token: &mut impl FnMut(RequestId, TokenEvent<'_>) -> ControlFlow<()>,
+    ) -> Result<Option<FinishReason>, GenerateError> {
+        let prompt_done = *self.prompt_done.get_or_insert_with(|| {
+            self.stats.n_prompt_tokens = self.prompt.len();
+            self.stats.prompt_time = self.start.elapsed();
+            #[cfg(feature = "metrics")]
+            crate::metrics::record_prompt(self.prompt.len());
+            Instant::now()
+        });
+        let sampled = ctx.sample_next(i, &self.history, &mut self.params, self.rng.as_mut())?;
+        self.stats
+            .time_to_first_token
+            .get_or_insert_with(|| self.start.elapsed());
+        self.stats.generation_time = prompt_done.elapsed();
+        let Some(Sampled {
+            token,
+            logprob,
+            confidence,
+        }) = sampled
+        else {
+            return Ok(Some(FinishReason::GrammarExhausted));
+        };
+
+        if ctx.model.is_eog_token(token) {
+            push_lossy(&mut self.text, &mut self.pending);
+            let _ = on_token(
+                self.id,
+                TokenEvent {
+                    token,
+                    text: &self.text[self.streamed..],
+                    logprob,
+                    confidence,
+                    stats: self.stats,
+                },
+            );
+            self.streamed = self.text.len();
+            return Ok(Some(FinishReason::EndOfGeneration));
+        }
+
+        self.tokens.push(token);
+        self.stats.n_generated_tokens = self.tokens.len();
+        #[cfg(feature = "metrics")]
+        crate::metrics::record_generated_token();
+        self.history.push(token);
+        self.pending
+            .extend(ctx.model.token_to_bytes(token, Special::Plaintext)?);
+        push_utf8(&mut self.text, &mut self.pending);
+
+        let check = StopCheck {
+            tokens: &self.tokens,
+            text: &self.text,
+            elapsed: self.start.elapsed(),
+        };
+        let stop = self.params.stopping.check(&check);
+        let mut end = match &stop {
+            Some(stop) => stop.text_len.min(self.text.len()),
+            None => {
+                let holdback = self.params.stopping.holdback(&self.text);
+                self.text.len() - holdback.min(self.text.len())
+            }
+        };
+        while !self.text.is_char_boundary(end) {
+            end -= 1;
+        }
+        let end = end.max(self.streamed);
+        let flow = on_token(
+            self.id,
+            TokenEvent {
+                token,
+                text: &self.text[self.streamed..end],
+                logprob,
+                confidence,
+                stats: self.stats,
+            },
+        );
+        self.streamed = end;
+
+        if let Some(stop) = stop {
+            self.text.truncate(end);
+            return Ok(Some(stop.reason));
+        }
+        if flow.is_break() || self.cancelled || ctx.is_cancelled() || self.params.is_disconnected()
+        {
+            return Ok(Some(FinishReason::Cancelled));
+        }
+        if !usize::try_from(self.n_past).is_ok_and(|n_past| n_past < n_ctx_slot) {
+            return Ok(Some(FinishReason::ContextFull));
+        }
+        self.next = Some(token);
+        Ok(None)
+    }
+
+    fn finish(mut self, finish_reason: FinishReason) -> Generation {
+        push_lossy(&mut self.text, &mut self.pending);
+        Generation {
+            text: self.text,
+            tokens: self.tokens,
+            finish_reason,
+            stats: self.stats,
+            sampler_state: self.rng.as_ref().map(SeededRng::state),
+        }
+    }
+}
+
+/// Runs many generations at once on the sequences of a context, see the [module](self) docs.
+///
+/// Of the [`GenerationParams`] of a request, `seq_id` is replaced by the slot's sequence and
+/// `n_cached`, `context_shift`, `n_keep` and `self_extend` are ignored: every request starts on a
+/// cleared sequence and ends with [`FinishReason::ContextFull`] when it reaches its share of the
+/// context.
+pub struct SlotManager {
+    slots: Vec<Option<Slot>>,
+    queue: VecDeque<(RequestId, Vec<LlamaToken>, GenerationParams)>,
+    batch: LlamaBatch,
+    n_batch: usize,
+    /// The number of positions of a sequence, `n_ctx / n_slots`.
+    n_ctx_slot: usize,
+    next_id: RequestId,
+}
+
+impl Debug for SlotManager {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SlotManager")
+            .field("n_slots", &self.slots.len())
+            .field("n_active", &self.n_active())
+            .field("n_queued", &self.n_queued())
+            .field("n_batch", &self.n_batch)
+            .field("n_ctx_slot", &self.n_ctx_slot)
+            .finish_non_exhaustive()
+    }
+}
+
+impl SlotManager {
+    /// Manage `n_slots` slots on sequences `0..n_slots` of `ctx`, which must have been created
+    /// with at least that many sequences (see
+    /// [`LlamaContextParams::with_n_seq_max`](crate::context::params::LlamaContextParams::with_n_seq_max)).
+    /// Each slot gets `n_ctx / n_slots` positions.
+    ///
+    /// # Panics
+    ///
+    /// - if `n_slots` is 0 or does not fit into an [`i32`]
+    /// - if `n_ctx` or `n_batch` does not fit into a usize
+    #[must_use]
+    pub fn new(ctx: &LlamaContext, n_slots: usize) -> Self {
+        assert!(n_slots > 0, "a slot manager needs at least one slot");
+        assert!(i32::try_from(n_slots).is_ok(), "n_slots fits into an i32");
+        let n_ctx = usize::try_from(ctx.n_ctx()).expect("n_ctx fits into a usize");
+        let n_batch = usize::try_from(ctx.n_batch()).expect("n_batch fits into a usize");
+        Self {
+            slots: (0..n_slots).map(|_| None).collect(),
+            queue: VecDeque::new(),
+            batch: LlamaBatch::new(n_batch, 1),
+            n_batch,
+            n_ctx_slot: n_ctx / n_slots,
+            next_id: 0,
+        }
+    }
+
+    /// The number of requests being generated.
+    #[must_use]
+    pub fn n_active(&self) -> usize {
+        self.slots.iter().flatten().count()
+    }
+
+    /// The number of requests waiting for a slot.
+    #[must_use]
+    pub fn n_queued(&self) -> usize {
+        self.queue.len()
+    }
+
+    /// Whether there is nothing to generate.
+    #[must_use]
+    pub fn is_idle(&self) -> bool {
+        self.queue.is_empty() && self.n_active() == 0
+    }
+
+    /// Queue a request. It is assigned to a slot by the next [`SlotManager::step`] that finds one
+    /// free.
+    ///
+    /// # Errors
+    ///
+    /// - [`GenerateError::EmptyPrompt`] if `prompt` is empty
+    /// - [`GenerateError::PromptTooLong`] if `prompt` does not fit into a slot
+    pub fn submit(
+        &mut self,
+        prompt: Vec<LlamaToken>,
+        params: GenerationParams,
+    ) -> Result<RequestId, GenerateError> {
+        if prompt.is_empty() {
+            return Err(GenerateError::EmptyPrompt);
+        }
+        if prompt.len() >= self.n_ctx_slot {
+            return Err(GenerateError::PromptTooLong {
+                n_tokens: prompt.len(),
+                n_ctx: u32::try_from(self.n_ctx_slot).unwrap_or(u32::MAX),
+            });
+        }
+        let id = self.next_id;
+        self.next_id += 1;
+        self.queue.push_back((id, prompt, params));
+        #[cfg(feature = "metrics")]
+        crate::metrics::set_queue_depth(self.queue.len());
+        Ok(id)
+    }
+
+    /// Cancel a request. A queued request is dropped, an active one finishes with
+    /// [`FinishReason::Cancelled`] at the next step. Returns `false` if the request is unknown
+    /// or already finished.
+    pub fn cancel(&mut self, id: RequestId) -> bool {
+        if let Some(index) = self.queue.iter().position(|(queued, _, _)| *queued == id) {
+            self.queue.remove(index);
+            #[cfg(feature = "metrics")]
+            crate::metrics::set_queue_depth(self.queue.len());
+            return true;
+        }
+        match self.slots.iter_mut().flatten().find(|slot| slot.id == id) {
+            Some(slot) => {
+                slot.cancelled = true;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Move queued requests into free slots.
+    fn assign(&mut self, ctx: &mut LlamaContext) {
+        for (seq_id, entry) in (0..).zip(&mut self.slots) {
+            if entry.is_some() {
+                continue;
+            }
+            let Some((id, prompt, mut params)) = self.queue.pop_front() else {
+                break;
+            };
+            ctx.clear_kv_cache_seq(seq_id, None, None);
+            params.seq_id = seq_id;
+            *entry = Some(Slot {
+                id,
+                seq_id,
+                rng: params.rng(),
+                params,
+                history: prompt.clone(),
+                prompt,
+                n_decoded: 0,
+                n_past: 0,
+                tokens: Vec::new(),
+                text: String::new(),
+                pending: Vec::new(),
+                streamed: 0,
+                next: None,
+                i_batch: None,
+                start: Instant::now(),
+                prompt_done: None,
+                stats: GenerationStats::default(),
+                cancelled: false,
+            });
+        }
+        #[cfg(feature = "metrics")]
+        crate::metrics::set_queue_depth(self.queue.len());
+    }
+
+    /// Fill the batch: the sampled token of every generating slot first, so generation is never
+    /// starved by long prompts, then prompt tokens while there is room.
+    fn fill_batch(&mut self) -> Result<(), GenerateError> {
+        self.batch.clear();
+        for slot in self.slots.iter_mut().flatten() {
+            slot.i_batch = None;
+            if let Some(token) = slot.next.take() {
+                self.batch.add(token, slot.n_past, &[slot.seq_id], true)?;
+                slot.n_past += 1;
+                slot.i_batch = Some(self.batch.n_tokens() - 1);
+            }
+        }
+        for slot in self.slots.iter_mut().flatten() {
+            while slot.n_decoded < slot.prompt.len()
+                && usize::try_from(self.batch.n_tokens()).is_ok_and(|n| n < self.n_batch)
+            {
+                let last = slot.n_decoded + 1 == slot.prompt.len();
+                let token = slot.prompt[slot.n_decoded];
+                self.batch.add(token, slot.n_past, &[slot.seq_id], last)?;
+                slot.n_decoded += 1;
+                slot.n_past += 1;
+                if last {
+                    slot.i_batch = Some(self.batch.n_tokens() - 1);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Assign queued requests to free slots, decode one batch and sample every slot whose logits
+    /// are in it. `on_token` gets the id of the request with every [`TokenEvent`], return
+    /// [`ControlFlow::Break`] to cancel that request. Returns the requests that finished.
+    ///
+    /// # Errors
+    ///
+    /// [`StepError`] with the requests that finished anyway:
+    /// - if adding to the batch or decoding fails, all active requests are dropped and their
+    ///   sequences cleared, the queue is kept
+    /// - if sampling fails for a request, only that request is dropped
+    pub fn step(
+        &mut self,
+        ctx: &mut LlamaContext,
+        mut on_token: impl FnMut(RequestId, TokenEvent<'_>) -> ControlFlow<()>,
+    ) -> Result<Vec<(RequestId, Generation)>, StepError> {
+        let mut finished = Vec::new();
+        // a client that went away while queued is dropped like a cancelled request
+        self.queue
+            .retain(|(_, _, params)| !params.is_disconnected());
+        for entry in &mut self.slots {
+            if !entry
+                .as_ref()
+                .is_some_and(|slot| slot.cancelled || slot.params.is_disconnected())
+            {
+                continue;
+            }
+            if let Some(slot) = entry.take() {
+                ctx.clear_kv_cache_seq(slot.seq_id, None, None);
+                finished.push((slot.id, slot.finish(FinishReason::Cancelled)));
+            }
+        }
+        self.assign(ctx);
+
+        let result = self.fill_batch().and_then(|()| {
+            if self.batch.n_tokens() > 0 {
+                ctx.decode(&mut self.batch)?;
+            }
+            Ok(())
+        });
+        if let Err(error) = result {
+            let failed = self.drop_active(ctx);
+            return Err(StepError {
+                error,
+                failed,
+                finished,
+            });
+        }
+
+        let n_ctx_slot = self.n_ctx_slot;
+        let mut error = None;
+        let mut failed = Vec::new();
+        for entry in &mut self.slots {
+            let Some(slot) = entry.as_mut() else {
+                continue;
+            };
+            let Some(i) = slot.i_batch else {
+                continue;
+            };
+            match slot.sample(ctx, i, n_ctx_slot, &mut on_token) {
+                Ok(None) => {}
+                Ok(Some(reason)) => {
+                    ctx.clear_kv_cache_seq(slot.seq_id, None, None);
+                    if let Some(slot) = entry.take() {
+                        finished.push((slot.id, slot.finish(reason)));
+                    }
+                }
+                Err(err) => {
+                    ctx.clear_kv_cache_seq(slot.seq_id, None, None);
+                    failed.push(slot.id);
+                    error.get_or_insert(err);
+                    *entry = None;
+                }
+            }
+        }
+        match error {
+            Some(error) => Err(StepError {
+                error,
+                failed,
+                finished,
+            }),
+            None => Ok(finished),
+        }
+    }
+
+    /// Drop every active request and clear its sequence. Returns the ids of the dropped
+    /// requests.
+    fn drop_active(&mut self, ctx: &mut LlamaContext) -> Vec<RequestId> {
+        self.slots
+            .iter_mut()
+            .filter_map(Option::take)
+            .map(|slot| {
+                ctx.clear_kv_cache_seq(slot.seq_id, None, None);
+                slot.id
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests;
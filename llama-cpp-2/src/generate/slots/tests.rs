@@ -0,0 +1,340 @@
+use std::collections::HashMap;
+use std::num::{NonZeroU32, NonZeroUsize};
+
+use super::*;
+use crate::context::params::LlamaContextParams;
+use crate::generate::SamplingParams;
+use crate::grammar::cache::CompiledGrammar;
+use crate::model::{AddBos, LlamaModel};
+use crate::test_utils::{self, TinyModel};
+
+fn tiny_model() -> LlamaModel {
+    TinyModel::default().load(test_utils::backend()).unwrap()
+}
+
+/// A context with `n_slots` sequences of 64 positions each.
+fn context(model: &LlamaModel, n_slots: u32) -> LlamaContext<'_> {
+    let params = LlamaContextParams::default()
+        .with_n_ctx(NonZeroU32::new(64 * n_slots))
+        .with_n_seq_max(n_slots);
+    model.new_context(test_utils::backend(), params).unwrap()
+}
+
+/// Greedy generation of exactly `max_tokens` tokens.
+fn params(model: &LlamaModel, max_tokens: usize) -> GenerationParams {
+    GenerationParams::default()
+        .with_sampling(SamplingParams::greedy())
+        .with_max_tokens(max_tokens)
+        .with_ignore_eos(model)
+}
+
+fn prompt(model: &LlamaModel) -> Vec<LlamaToken> {
+    model.str_to_token("hello world", AddBos::Always).unwrap()
+}
+
+/// Step until the manager is idle. Returns every finished request with the step it finished in,
+/// and the steps in which each request streamed a token.
+fn run(
+    slots: &mut SlotManager,
+    ctx: &mut LlamaContext,
+) -> (
+    HashMap<RequestId, (usize, Generation)>,
+    HashMap<RequestId, Vec<usize>>,
+) {
+    let mut finished = HashMap::new();
+    let mut streamed: HashMap<RequestId, Vec<usize>> = HashMap::new();
+    let mut n_step = 0;
+    while !slots.is_idle() {
+        let done = slots
+            .step(ctx, |id, _| {
+                streamed.entry(id).or_default().push(n_step);
+                ControlFlow::Continue(())
+            })
+            .unwrap();
+        finished.extend(
+            done.into_iter()
+                .map(|(id, generation)| (id, (n_step, generation))),
+        );
+        n_step += 1;
+        assert!(n_step < 1000, "the slots never became idle");
+    }
+    (finished, streamed)
+}
+
+#[test]
+fn rejects_prompts_that_do_not_fit_a_slot() {
+    let model = tiny_model();
+    let ctx = context(&model, 2);
+    let mut slots = SlotManager::new(&ctx, 2);
+    assert!(matches!(
+        slots.submit(Vec::new(), params(&model, 1)),
+        Err(GenerateError::EmptyPrompt)
+    ));
+    let too_long = vec![model.token_bos(); 64];
+    assert!(matches!(
+        slots.submit(too_long, params(&model, 1)),
+        Err(GenerateError::PromptTooLong {
+            n_tokens: 64,
+            n_ctx: 64
+        })
+    ));
+    assert!(slots.is_idle());
+}
+
+#[test]
+fn queued_requests_wait_for_a_free_slot() {
+    let model = tiny_model();
+    let mut ctx = context(&model, 2);
+    let mut slots = SlotManager::new(&ctx, 2);
+    let ids: Vec<RequestId> = [3, 6, 2]
+        .into_iter()
+        .map(|max_tokens| {
+            slots
+                .submit(prompt(&model), params(&model, max_tokens))
+                .unwrap()
+        })
+        .collect();
+    assert_eq!((slots.n_active(), slots.n_queued()), (0, 3));
+
+    let first = slots
+        .step(&mut ctx, |_, _| ControlFlow::Continue(()))
+        .unwrap();
+    assert!(first.is_empty());
+    assert_eq!((slots.n_active(), slots.n_queued()), (2, 1));
+
+    let (finished, streamed) = run(&mut slots, &mut ctx);
+    assert_eq!(finished.len(), 3);
+    // the third request only got the slot of the first one
+    let (first_done, _) = finished[&ids[0]];
+    assert!(streamed[&ids[2]][0] > first_done);
+    assert!(finished[&ids[1]].0 > first_done);
+    for (id, max_tokens) in ids.iter().zip([3, 6, 2]) {
+        let (_, generation) = &finished[id];
+        assert_eq!(generation.finish_reason, FinishReason::MaxTokens);
+        assert_eq!(generation.tokens.len(), max_tokens);
+        assert_eq!(generation.stats.n_prompt_tokens, prompt(&model).len());
+    }
+}
+
+#[test]
+fn finished_and_cancelled_requests_free_their_sequence() {
+    let model = tiny_model();
+    let mut ctx = context(&model, 1);
+    let mut slots = SlotManager::new(&ctx, 1);
+    let long = slots.submit(prompt(&model), params(&model, 32)).unwrap();
+    let queued = slots.submit(prompt(&model), params(&model, 2)).unwrap();
+    slots
+        .step(&mut ctx, |_, _| ControlFlow::Continue(()))
+        .unwrap();
+    assert!(ctx.kv_cache_seq_pos_max(0) >= 0);
+
+    assert!(slots.cancel(long));
+    let done = slots
+        .step(&mut ctx, |_, _| ControlFlow::Continue(()))
+        .unwrap();
+    let [(id, generation)] = &done[..] else {
+        panic!("expected the cancelled request, got {done:?}");
+    };
+    assert_eq!(
+        (*id, &generation.finish_reason),
+        (long, &FinishReason::Cancelled)
+    );
+    assert!(!slots.cancel(long));
+
+    // the queued request took over the cleared sequence
+    assert_eq!((slots.n_active(), slots.n_queued()), (1, 0));
+    let prompt_len = i32::try_from(prompt(&model).len()).unwrap();
+    assert_eq!(ctx.kv_cache_seq_pos_max(0), prompt_len - 1);
+    let (finished, _) = run(&mut slots, &mut ctx);
+    assert_eq!(finished[&queued].1.finish_reason, FinishReason::MaxTokens);
+    assert_eq!(ctx.kv_cache_seq_pos_max(0), -1);
+}
+
+#[test]
+fn requests_finish_on_their_own() {
+    let model = tiny_model();
+    let mut ctx = context(&model, 3);
+    let mut slots = SlotManager::new(&ctx, 3);
+    let short = slots.submit(prompt(&model), params(&model, 2)).unwrap();
+    let stopped = slots.submit(prompt(&model), params(&model, 50)).unwrap();
+    // runs into the end of its 64 positions
+    let endless = slots.submit(prompt(&model), params(&model, 100)).unwrap();
+
+    let mut finished = HashMap::new();
+    let mut n_streamed = 0;
+    while !slots.is_idle() {
+        let done = slots
+            .step(&mut ctx, |id, _| {
+                if id == stopped {
+                    n_streamed += 1;
+                    if n_streamed == 5 {
+                        return ControlFlow::Break(());
+                    }
+                }
+                ControlFlow::Continue(())
+            })
+            .unwrap();
+        for (id, generation) in done {
+            // the other requests are still generating
+            if id != endless {
+                assert!(slots.n_active() > 0);
+            }
+            finished.insert(id, generation);
+        }
+    }
+
+    assert_eq!(finished[&short].finish_reason, FinishReason::MaxTokens);
+    assert_eq!(finished[&short].tokens.len(), 2);
+    assert_eq!(finished[&stopped].finish_reason, FinishReason::Cancelled);
+    assert_eq!(finished[&stopped].tokens.len(), 5);
+    assert_eq!(finished[&endless].finish_reason, FinishReason::ContextFull);
+    // the last token is sampled into the 65th position, which is never decoded
+    let n_positions = prompt(&model).len() + finished[&endless].tokens.len();
+    assert_eq!(n_positions, 65);
+}
+
+/// A grammar and whether a text is a prefix of one of its matches.
+struct Case {
+    prompt: &'static str,
+    gbnf: &'static str,
+    is_prefix: fn(&str) -> bool,
+}
+
+fn cases() -> [Case; 4] {
+    [
+        Case {
+            prompt: "Is the sky blue? Answer yes or no:",
+            gbnf: r#"root ::= "yes" | "no""#,
+            is_prefix: |text| "yes".starts_with(text) || "no".starts_with(text),
+        },
+        Case {
+            prompt: "The first ten digits of pi are",
+            gbnf: "root ::= [0-9]+",
+            is_prefix: |text| text.bytes().all(|byte| byte.is_ascii_digit()),
+        },
+        Case {
+            prompt: "Name a few fruits:",
+            gbnf: "root ::= [a-z]+ (\" \" [a-z]+)*",
+            is_prefix: |text| {
+                text.bytes()
+                    .all(|byte| byte.is_ascii_lowercase() || byte == b' ')
+            },
+        },
+        Case {
+            prompt: "How many legs does a spider have? As JSON:",
+            gbnf: r#"root ::= "{\"legs\": " [0-9]+ "}""#,
+            is_prefix: |text| {
+                let open = "{\"legs\": ";
+                match text.strip_prefix(open) {
+                    Some(rest) => {
+                        let digits = rest.strip_suffix('}').unwrap_or(rest);
+                        digits.bytes().all(|byte| byte.is_ascii_digit())
+                    }
+                    None => open.starts_with(text),
+                }
+            },
+        },
+    ]
+}
+
+/// Run many grammar constrained requests with mixed grammars, sampling chains, lazy grammars,
+/// shared mask caches, cancellations and dead end grammars through four slots, and check that
+/// every request only ever produced text its own grammar allows. The tiny model has byte tokens
+/// for every character the grammars need, so its random weights pick tokens the grammars rule
+/// out all the time.
+#[test]
+fn grammars_stay_independent_across_slots() {
+    let model = tiny_model();
+    let n_slots = 4;
+    let params = LlamaContextParams::default()
+        .with_n_ctx(NonZeroU32::new(512))
+        .with_n_batch(64)
+        .with_n_seq_max(n_slots);
+    let mut ctx = model.new_context(test_utils::backend(), params).unwrap();
+    let mut slots = SlotManager::new(&ctx, usize::try_from(n_slots).unwrap());
+
+    let cases = cases();
+    let grammars: Vec<CompiledGrammar> = cases
+        .iter()
+        .enumerate()
+        .map(|(i, case)| {
+            let grammar = CompiledGrammar::new(case.gbnf).unwrap();
+            // half of the grammars share a mask cache between their requests
+            if i % 2 == 0 {
+                grammar.with_mask_cache(NonZeroUsize::new(256).unwrap())
+            } else {
+                grammar
+            }
+        })
+        .collect();
+
+    let mut requests = HashMap::new();
+    for n in 0..32 {
+        let case = n % cases.len();
+        let sampling = match n % 3 {
+            0 => SamplingParams::greedy(),
+            1 => SamplingParams::deterministic(u32::try_from(n).unwrap()),
+            _ => SamplingParams {
+                temperature: 1.5,
+                ..SamplingParams::default()
+            },
+        };
+        let mut params = GenerationParams::default()
+            .with_sampling(sampling)
+            .with_max_tokens(16)
+            .with_grammar(grammars[case].instantiate())
+            .with_lazy_grammar(n % 2 == 1);
+        // ending is the only way out of a complete match, so these run into a dead end
+        let dead_end = n % 8 == 0;
+        if dead_end {
+            params = params.with_ignore_eos(&model);
+        }
+        let prompt = model
+            .str_to_token(cases[case].prompt, AddBos::Always)
+            .unwrap();
+        let id = slots.submit(prompt, params).unwrap();
+        requests.insert(id, (case, dead_end, String::new()));
+    }
+
+    let mut finished = HashMap::new();
+    let mut cancelled = None;
+    let mut n_steps = 0;
+    while !slots.is_idle() {
+        n_steps += 1;
+        // request 3 is generating by now, unless it already finished
+        if n_steps == 5 && slots.cancel(3) {
+            cancelled = Some(3);
+        }
+        let done = slots
+            .step(&mut ctx, |id, event| {
+                let (case, _, streamed) = requests.get_mut(&id).unwrap();
+                streamed.push_str(event.text);
+                assert!(
+                    (cases[*case].is_prefix)(streamed),
+                    "request {id} streamed {streamed:?}"
+                );
+                ControlFlow::Continue(())
+            })
+            .unwrap();
+        finished.extend(done);
+    }
+
+    assert_eq!(finished.len(), requests.len());
+    for (id, generation) in &finished {
+        let (case, dead_end, streamed) = &requests[id];
+        assert!(
+            (cases[*case].is_prefix)(&generation.text),
+            "request {id} generated {:?}",
+            generation.text
+        );
+        assert!(generation.text.starts_with(streamed.as_str()));
+        let reason = &generation.finish_reason;
+        match reason {
+            FinishReason::Cancelled => assert_eq!(Some(*id), cancelled),
+            FinishReason::EndOfGeneration => assert!(!dead_end, "request {id}"),
+            FinishReason::GrammarExhausted => assert!(dead_end, "request {id}"),
+            FinishReason::MaxTokens => {}
+            _ => panic!("request {id} finished with {reason:?}"),
+        }
+    }
+}
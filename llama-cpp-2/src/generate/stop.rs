@@ -0,0 +1,214 @@
+//! Stopping criteria consulted by [`LlamaContext::generate`] after every token.
+//!
+//! Criteria see the tokens and text generated so far and decide whether generation is done.
+//! [`StoppingCriteria::holdback`] lets a criterion withhold the end of the text from streaming
+//! while it may still turn into a match, so a stop string is never partially streamed.
+//!
+//! [`LlamaContext::generate`]: crate::context::LlamaContext::generate
+//!
+//! # Example
+//!
+//! ```
+//! # use std::time::Duration;
+//! # use llama_cpp_2::generate::FinishReason;
+//! # use llama_cpp_2::generate::stop::{MaxTokens, StopCheck, StopStrings, StoppingCriteria, Timeout};
+//! # use llama_cpp_2::token::LlamaToken;
+//! let mut criteria: Vec<Box<dyn StoppingCriteria>> = vec![
+//!     Box::new(MaxTokens(128)),
+//!     Box::new(Timeout(Duration::from_secs(30))),
+//!     Box::new(StopStrings::new(["\nUser:"])),
+//! ];
+//!
+//! // "\nUs" could still become "\nUser:", so it is held back from streaming
+//! assert_eq!(criteria.holdback("Hello!\nUs"), 3);
+//!
+//! let tokens = [LlamaToken::new(1); 3];
+//! let check = StopCheck { tokens: &tokens, text: "Hello!\nUser: hi", elapsed: Duration::ZERO };
+//! let stop = criteria.check(&check).unwrap();
+//! assert_eq!(stop.reason, FinishReason::StopString("\nUser:".to_string()));
+//! assert_eq!(&check.text[..stop.text_len], "Hello!");
+//! ```
+
+use crate::generate::FinishReason;
+use crate::token::LlamaToken;
+use std::fmt::{Debug, Formatter};
+use std::time::Duration;
+
+/// The state of a generation passed to [`StoppingCriteria::check`].
+#[derive(Debug, Clone, Copy)]
+pub struct StopCheck<'a> {
+    /// The tokens generated so far, including the one just sampled.
+    pub tokens: &'a [LlamaToken],
+    /// The text generated so far.
+    pub text: &'a str,
+    /// The time since generation started, including prompt processing.
+    pub elapsed: Duration,
+}
+
+/// Why and where generation should stop.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StopMatch {
+    /// The reason reported in the result.
+    pub reason: FinishReason,
+    /// The length in bytes of the text to keep. Text after this is removed from the result and
+    /// never streamed (e.g. the stop string itself).
+    pub text_len: usize,
+}
+
+/// Decides when a generation is done.
+pub trait StoppingCriteria {
+    /// Called after every generated token. Return `Some` to stop.
+    fn check(&mut self, state: &StopCheck<'_>) -> Option<StopMatch>;
+
+    /// The number of bytes at the end of `text` that must not be streamed yet because they may
+    /// become part of a match with the next tokens.
+    fn holdback(&self, _text: &str) -> usize {
+        0
+    }
+}
+
+impl<F> StoppingCriteria for F
+where
+    F: FnMut(&StopCheck<'_>) -> Option<StopMatch>,
+{
+    fn check(&mut self, state: &StopCheck<'_>) -> Option<StopMatch> {
+        self(state)
+    }
+}
+
+/// Stops at the first criterion that matches, holding back as much text as any of them needs.
+impl StoppingCriteria for Vec<Box<dyn StoppingCriteria>> {
+    fn check(&mut self, state: &StopCheck<'_>) -> Option<StopMatch> {
+        self.iter_mut().find_map(|criteria| criteria.check(state))
+    }
+
+    fn holdback(&self, text: &str) -> usize {
+        self.iter()
+            .map(|criteria| criteria.holdback(text))
+            .max()
+            .unwrap_or(0)
+    }
+}
+
+/// Stop after generating this many tokens.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MaxTokens(pub usize);
+
+impl StoppingCriteria for MaxTokens {
+    fn check(&mut self, state: &StopCheck<'_>) -> Option<StopMatch> {
+        (state.tokens.len() >= self.0).then_some(StopMatch {
+            reason: FinishReason::MaxTokens,
+            text_len: state.text.len(),
+        })
+    }
+}
+
+/// Stop once generation has taken this long. Checked between tokens, so a single slow decode can
+/// overshoot it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timeout(pub Duration);
+
+impl StoppingCriteria for Timeout {
+    fn check(&mut self, state: &StopCheck<'_>) -> Option<StopMatch> {
+        (state.elapsed >= self.0).then_some(StopMatch {
+            reason: FinishReason::Timeout,
+            text_len: state.text.len(),
+        })
+    }
+}
+
+/// Stop when the predicate returns true for a generated token. The text of that token is kept.
+pub struct StopToken<F>(pub F);
+
+impl<F> Debug for StopToken<F> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("StopToken")
+            .field(&"FnMut(LlamaToken) -> bool")
+            .finish()
+    }
+}
+
+impl<F> StoppingCriteria for StopToken<F>
+where
+    F: FnMut(LlamaToken) -> bool,
+{
+    fn check(&mut self, state: &StopCheck<'_>) -> Option<StopMatch> {
+        let token = *state.tokens.last()?;
+        (self.0)(token).then_some(StopMatch {
+            reason: FinishReason::StopToken(token),
+            text_len: state.text.len(),
+        })
+    }
+}
+
+/// Stop when the text contains one of the stop strings. The stop string and everything after it
+/// is removed from the result.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StopStrings {
+    stop: Vec<String>,
+    /// Matches can only start at or after this offset, everything before it was searched.
+    searched: usize,
+}
+
+impl StopStrings {
+    /// Stop on any of `stop`. Empty strings are ignored.
+    pub fn new(stop: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        Self {
+            stop: stop
+                .into_iter()
+                .map(Into::into)
+                .filter(|s: &String| !s.is_empty())
+                .collect(),
+            searched: 0,
+        }
+    }
+
+    /// The stop strings.
+    #[must_use]
+    pub fn stop_strings(&self) -> &[String] {
+        &self.stop
+    }
+}
+
+impl StoppingCriteria for StopStrings {
+    fn check(&mut self, state: &StopCheck<'_>) -> Option<StopMatch> {
+        let text = state.text;
+        if self.searched > text.len() {
+            // reused for a new generation
+            self.searched = 0;
+        }
+        let mut from = self.searched;
+        while !text.is_char_boundary(from) {
+            from -= 1;
+        }
+        let found = self
+            .stop
+            .iter()
+            .filter_map(|stop| text[from..].find(stop.as_str()).map(|i| (from + i, stop)))
+            .min_by_key(|(i, _)| *i);
+        if let Some((i, stop)) = found {
+            self.searched = 0;
+            return Some(StopMatch {
+                reason: FinishReason::StopString(stop.clone()),
+                text_len: i,
+            });
+        }
+        // a match may still start in the last `longest - 1` bytes
+        let longest = self.stop.iter().map(String::len).max().unwrap_or(0);
+        self.searched = text.len().saturating_sub(longest.saturating_sub(1));
+        None
+    }
+
+    fn holdback(&self, text: &str) -> usize {
+        self.stop
+            .iter()
+            .flat_map(|stop| {
+                stop.char_indices()
+                    .map(|(i, _)| i)
+                    .skip(1)
+                    .filter(|i| text.ends_with(&stop[..*i]))
+            })
+            .max()
+            .unwrap_or(0)
+    }
+}
@@ -0,0 +1,215 @@
+//! Generation as a stream of typed events.
+//!
+//! [`LlamaContext::generate_stream`] runs [`LlamaContext::generate`] and reports everything that
+//! happens as a [`StreamEvent`]: the text of every token, the tool calls a [`ToolCallParser`]
+//! recognizes, and finally why generation ended or the error it failed with. A consumer on the
+//! other end of a channel learns everything from the events alone.
+//!
+//! ```no_run
+//! # use std::ops::ControlFlow;
+//! # use std::sync::mpsc;
+//! # use llama_cpp_2::context::LlamaContext;
+//! # use llama_cpp_2::generate::stream::StreamEvent;
+//! # use llama_cpp_2::generate::GenerationParams;
+//! # use llama_cpp_2::token::LlamaToken;
+//! # fn run(ctx: &mut LlamaContext, prompt: &[LlamaToken]) {
+//! let (sender, receiver) = mpsc::channel();
+//! let mut params = GenerationParams::default().with_max_tokens(128);
+//! ctx.generate_stream(prompt, &mut params, |event| {
+//!     // stop generating once the receiver is gone
+//!     match sender.send(event) {
+//!         Ok(()) => ControlFlow::Continue(()),
+//!         Err(_) => ControlFlow::Break(()),
+//!     }
+//! });
+//! for event in receiver {
+//!     match event {
+//!         StreamEvent::Token { text, .. } | StreamEvent::Text(text) => print!("{text}"),
+//!         StreamEvent::ToolCallDelta(call) => println!("tool call {call:?}"),
+//!         StreamEvent::Done { finish_reason, stats } => println!("\n{finish_reason:?}: {stats}"),
+//!         StreamEvent::Error(err) => eprintln!("generation failed: {err}"),
+//!     }
+//! }
+//! # }
+//! ```
+//!
+//! The unbounded channel above buffers everything the model generates, however slow the consumer
+//! is. [`LlamaContext::generate_to_channel`] sends into a bounded channel instead, so generation
+//! pauses while the channel is full and stops once the receiver is dropped, e.g. when the client
+//! of a server-sent events stream is slow or disconnected:
+//!
+//! ```no_run
+//! # use std::sync::mpsc;
+//! # use std::thread;
+//! # use llama_cpp_2::context::LlamaContext;
+//! # use llama_cpp_2::generate::stream::StreamEvent;
+//! # use llama_cpp_2::generate::GenerationParams;
+//! # use llama_cpp_2::token::LlamaToken;
+//! # fn run(ctx: &mut LlamaContext, prompt: &[LlamaToken]) {
+//! let (sender, receiver) = mpsc::sync_channel(16);
+//! let client = thread::spawn(move || {
+//!     for event in receiver {
+//!         if let StreamEvent::Token { text, .. } = event {
+//!             print!("{text}");
+//!         }
+//!     }
+//! });
+//! let mut params = GenerationParams::default().with_max_tokens(128);
+//! ctx.generate_to_channel(prompt, &mut params, &sender);
+//! drop(sender);
+//! client.join().expect("the client does not panic");
+//! # }
+//! ```
+
+use std::ops::ControlFlow;
+use std::sync::mpsc::SyncSender;
+
+use crate::context::LlamaContext;
+use crate::generate::{
+    Confidence, FinishReason, GenerateError, Generation, GenerationParams, GenerationStats,
+};
+use crate::token::LlamaToken;
+
+/// A part of a tool call recognized in the generated text.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ToolCallFragment {
+    /// The index of the call among the calls of the generation.
+    pub index: usize,
+    /// The name of the function. Only set on the first fragment of a call.
+    pub name: Option<String>,
+    /// The next part of the JSON encoded arguments.
+    pub arguments: String,
+}
+
+/// An event of [`LlamaContext::generate_stream`]. The last event is always either
+/// [`StreamEvent::Done`] or [`StreamEvent::Error`], unless the consumer stopped the stream.
+#[derive(Debug)]
+pub enum StreamEvent {
+    /// A token was generated.
+    Token {
+        /// The sampled token.
+        token: LlamaToken,
+        /// The text that became streamable, see [`TokenEvent::text`](super::TokenEvent::text).
+        /// With a [`ToolCallParser`] this is only the content, the tool calls are sent as
+        /// [`StreamEvent::ToolCallDelta`].
+        text: String,
+        /// The log probability of the token.
+        logprob: f32,
+    },
+    /// Content that is not tied to a token, sent when generation ended. This is text the
+    /// [`ToolCallParser`] held back, such as markup that turned out not to be a tool call.
+    Text(String),
+    /// A part of a tool call.
+    ToolCallDelta(ToolCallFragment),
+    /// Generation ended.
+    Done {
+        /// Why generation ended.
+        finish_reason: FinishReason,
+        /// The timings of the whole generation.
+        stats: GenerationStats,
+    },
+    /// Generation failed.
+    Error(GenerateError),
+}
+
+/// Separates the tool calls of a model from the content of its output while streaming.
+///
+/// [`ToolCallFormat::stream`](crate::openai::tools::ToolCallFormat::stream) implements it for the
+/// formats of the `openai` feature.
+pub trait ToolCallParser {
+    /// Split the next streamable `text` into content, which is returned, and parts of tool
+    /// calls, which are added to `tool_calls`. Text that may start a tool call can be held back.
+    fn push(&mut self, text: &str, tool_calls: &mut Vec<ToolCallFragment>) -> String;
+
+    /// Called when generation ended. Returns the content that was held back, adds the calls that
+    /// were still open to `tool_calls` and prepares the parser for another generation.
+    fn finish(&mut self, tool_calls: &mut Vec<ToolCallFragment>) -> String;
+}
+
+impl LlamaContext<'_> {
+    /// [`LlamaContext::generate`], reporting the output as [`StreamEvent`]s. Return
+    /// [`ControlFlow::Break`] from `on_event` to stop generating, no events are sent after that.
+    ///
+    /// If [`GenerationParams::tool_call_parser`] is set, tool calls are taken out of the token
+    /// text and sent as [`StreamEvent::ToolCallDelta`]s. The returned [`Generation`] still has
+    /// the raw text. Returns `None` if generation failed; the error is sent as the last event.
+    pub fn generate_stream(
+        &mut self,
+        prompt: &[LlamaToken],
+        params: &mut GenerationParams,
+        mut on_event: impl FnMut(StreamEvent) -> ControlFlow<()>,
+    ) -> Option<Generation> {
+        let mut parser = params.tool_call_parser.take();
+        let mut tool_calls = Vec::new();
+        let mut flow = ControlFlow::Continue(());
+        let result = self.generate(prompt, params, |event| {
+            let text = match &mut parser {
+                Some(parser) => parser.push(event.text, &mut tool_calls),
+                None => event.text.to_string(),
+            };
+            flow = on_event(StreamEvent::Token {
+                token: event.token,
+                text,
+                logprob: event.logprob,
+            });
+            for fragment in tool_calls.drain(..) {
+                if flow.is_continue() {
+                    flow = on_event(StreamEvent::ToolCallDelta(fragment));
+                }
+            }
+            flow
+        });
+
+        let mut events = Vec::new();
+        if let Some(parser) = &mut parser {
+            let text = parser.finish(&mut tool_calls);
+            if !text.is_empty() {
+                events.push(StreamEvent::Text(text));
+            }
+            events.extend(tool_calls.drain(..).map(StreamEvent::ToolCallDelta));
+        }
+        params.tool_call_parser = parser;
+        let generation = match result {
+            Ok(generation) => {
+                events.push(StreamEvent::Done {
+                    finish_reason: generation.finish_reason.clone(),
+                    stats: generation.stats,
+                });
+                Some(generation)
+            }
+            Err(err) => {
+                events.push(StreamEvent::Error(err));
+                None
+            }
+        };
+        for event in events {
+            if flow.is_break() {
+                break;
+            }
+            flow = on_event(event);
+        }
+        generation
+    }
+
+    /// [`LlamaContext::generate_stream`] into a bounded channel, created with
+    /// [`std::sync::mpsc::sync_channel`]. Sending blocks while the channel is full, so no token is
+    /// decoded ahead of what the consumer has room for, and generation stops with
+    /// [`FinishReason::Cancelled`] once the receiver is dropped.
+    ///
+    /// A consumer that keeps the receiver but stops reading blocks generation until it reads
+    /// again; drop the receiver to give up on a stream.
+    pub fn generate_to_channel(
+        &mut self,
+        prompt: &[LlamaToken],
+        params: &mut GenerationParams,
+        sender: &SyncSender<StreamEvent>,
+    ) -> Option<Generation> {
+        self.generate_stream(prompt, params, |event| match sender.send(event) {
+            Ok(()) => ControlFlow::Continue(()),
+            Err(_) => ControlFlow::Break(()),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests;
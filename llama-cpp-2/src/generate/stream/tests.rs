@@ -0,0 +1,34 @@
+use std::num::NonZeroU32;
+use std::sync::mpsc;
+use std::thread;
+
+use super::*;
+use crate::context::params::LlamaContextParams;
+use crate::generate::SamplingParams;
+use crate::model::AddBos;
+use crate::test_utils::{self, TinyModel};
+
+#[test]
+fn dropping_the_receiver_cancels_generation() {
+    let model = TinyModel::default().load(test_utils::backend()).unwrap();
+    let params = LlamaContextParams::default().with_n_ctx(NonZeroU32::new(64));
+    let mut ctx = model.new_context(test_utils::backend(), params).unwrap();
+    let prompt = model.str_to_token("the cat is", AddBos::Always).unwrap();
+    let mut params = GenerationParams::default()
+        .with_sampling(SamplingParams::greedy())
+        .with_max_tokens(32)
+        .with_ignore_eos(&model);
+
+    // a channel without a buffer: every send waits for the client to take the event
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let client = thread::spawn(move || receiver.recv().unwrap());
+    let generation = ctx
+        .generate_to_channel(&prompt, &mut params, &sender)
+        .unwrap();
+    let first = client.join().unwrap();
+
+    assert!(matches!(first, StreamEvent::Token { token, .. } if token == generation.tokens[0]));
+    assert_eq!(generation.finish_reason, FinishReason::Cancelled);
+    // the second token could not be sent, nothing was generated ahead of the client
+    assert_eq!(generation.tokens.len(), 2);
+}
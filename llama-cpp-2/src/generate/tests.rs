@@ -0,0 +1,55 @@
+use std::num::NonZeroU32;
+
+use super::*;
+use crate::context::params::LlamaContextParams;
+use crate::model::AddBos;
+use crate::test_utils::{self, TinyModel};
+
+/// Generate 16 tokens after a short prompt in a fresh deterministic context.
+fn generate(model: &LlamaModel, sampling: SamplingParams) -> Generation {
+    let ctx_params = LlamaContextParams::deterministic().with_n_ctx(NonZeroU32::new(128));
+    let mut ctx = model
+        .new_context(test_utils::backend(), ctx_params)
+        .unwrap();
+    let prompt = model.str_to_token("the cat is", AddBos::Always).unwrap();
+    let mut params = GenerationParams::default()
+        .with_sampling(sampling)
+        .with_max_tokens(16)
+        .with_ignore_eos(model);
+    ctx.generate(&prompt, &mut params, |_| ControlFlow::Continue(()))
+        .unwrap()
+}
+
+#[test]
+fn the_same_seed_generates_the_same_tokens() {
+    let model = TinyModel::default().load(test_utils::backend()).unwrap();
+    let first = generate(&model, SamplingParams::deterministic(42));
+    let second = generate(&model, SamplingParams::deterministic(42));
+    assert_eq!(first.tokens.len(), 16);
+    assert_eq!(first.tokens, second.tokens);
+    assert_eq!(first.sampler_state, second.sampler_state);
+}
+
+#[test]
+fn different_seeds_can_diverge() {
+    let model = TinyModel::default().load(test_utils::backend()).unwrap();
+    let sampling = SamplingParams {
+        temperature: 2.0,
+        top_k: 0,
+        top_p: 1.0,
+        min_p: 0.0,
+        ..SamplingParams::deterministic(0)
+    };
+    let first = generate(&model, sampling);
+    let diverged = (1..16).any(|seed| {
+        let other = generate(
+            &model,
+            SamplingParams {
+                seed: Some(seed),
+                ..sampling
+            },
+        );
+        other.tokens != first.tokens
+    });
+    assert!(diverged, "16 seeds generated the same tokens");
+}
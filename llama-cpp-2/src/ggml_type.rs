@@ -0,0 +1,573 @@
+//! Rust equivalents of `ggml_type` (the data type of a tensor) and `llama_ftype` (the
+//! quantization a model file was made with).
+//!
+//! The discriminants are the values of `ggml.h` and `llama.h`. They are stored in GGUF files, so
+//! llama.cpp never renumbers them and types newer than the linked llama.cpp can still be named.
+//!
+//! ```
+//! # use llama_cpp_2::ggml_type::{GgmlFileType, GgmlType};
+//! let file_type: GgmlFileType = "q4_k_m".parse().unwrap();
+//! assert_eq!(file_type.to_string(), "Q4_K - Medium");
+//! assert_eq!(file_type.main_type(), GgmlType::Q4K);
+//! assert_eq!(GgmlType::Q4K.to_string(), "q4_K");
+//! assert_eq!(GgmlType::Q4K.bits_per_weight(), 4.5);
+//! ```
+
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+
+/// The data type of a tensor, a rust flavored equivalent of `ggml_type`.
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[allow(missing_docs)]
+pub enum GgmlType {
+    F32 = 0,
+    F16 = 1,
+    Q4_0 = 2,
+    Q4_1 = 3,
+    Q5_0 = 6,
+    Q5_1 = 7,
+    Q8_0 = 8,
+    Q8_1 = 9,
+    Q2K = 10,
+    Q3K = 11,
+    Q4K = 12,
+    Q5K = 13,
+    Q6K = 14,
+    Q8K = 15,
+    IQ2XXS = 16,
+    IQ2XS = 17,
+    IQ3XXS = 18,
+    IQ1S = 19,
+    IQ4NL = 20,
+    IQ3S = 21,
+    IQ2S = 22,
+    IQ4XS = 23,
+    I8 = 24,
+    I16 = 25,
+    I32 = 26,
+    I64 = 27,
+    F64 = 28,
+    IQ1M = 29,
+    BF16 = 30,
+    /// [`GgmlType::Q4_0`] repacked for ARM NEON.
+    Q4_0_4_4 = 31,
+    /// [`GgmlType::Q4_0`] repacked for ARM i8mm.
+    Q4_0_4_8 = 32,
+    /// [`GgmlType::Q4_0`] repacked for ARM SVE.
+    Q4_0_8_8 = 33,
+    /// Ternary weights (-1, 0, 1) packed at 1.69 bits, for BitNet style models.
+    TQ1_0 = 34,
+    /// Ternary weights (-1, 0, 1) packed at 2.06 bits, for BitNet style models.
+    TQ2_0 = 35,
+}
+
+/// Every [`GgmlType`], in the order of their values.
+const GGML_TYPES: [GgmlType; 34] = [
+    GgmlType::F32,
+    GgmlType::F16,
+    GgmlType::Q4_0,
+    GgmlType::Q4_1,
+    GgmlType::Q5_0,
+    GgmlType::Q5_1,
+    GgmlType::Q8_0,
+    GgmlType::Q8_1,
+    GgmlType::Q2K,
+    GgmlType::Q3K,
+    GgmlType::Q4K,
+    GgmlType::Q5K,
+    GgmlType::Q6K,
+    GgmlType::Q8K,
+    GgmlType::IQ2XXS,
+    GgmlType::IQ2XS,
+    GgmlType::IQ3XXS,
+    GgmlType::IQ1S,
+    GgmlType::IQ4NL,
+    GgmlType::IQ3S,
+    GgmlType::IQ2S,
+    GgmlType::IQ4XS,
+    GgmlType::I8,
+    GgmlType::I16,
+    GgmlType::I32,
+    GgmlType::I64,
+    GgmlType::F64,
+    GgmlType::IQ1M,
+    GgmlType::BF16,
+    GgmlType::Q4_0_4_4,
+    GgmlType::Q4_0_4_8,
+    GgmlType::Q4_0_8_8,
+    GgmlType::TQ1_0,
+    GgmlType::TQ2_0,
+];
+
+impl GgmlType {
+    /// Every type, in the order of their values.
+    #[must_use]
+    pub fn all() -> &'static [GgmlType] {
+        &GGML_TYPES
+    }
+
+    /// The name ggml gives the type (`ggml_type_name`), e.g. `q4_K`.
+    #[must_use]
+    pub fn name(self) -> &'static str {
+        match self {
+            GgmlType::F32 => "f32",
+            GgmlType::F16 => "f16",
+            GgmlType::Q4_0 => "q4_0",
+            GgmlType::Q4_1 => "q4_1",
+            GgmlType::Q5_0 => "q5_0",
+            GgmlType::Q5_1 => "q5_1",
+            GgmlType::Q8_0 => "q8_0",
+            GgmlType::Q8_1 => "q8_1",
+            GgmlType::Q2K => "q2_K",
+            GgmlType::Q3K => "q3_K",
+            GgmlType::Q4K => "q4_K",
+            GgmlType::Q5K => "q5_K",
+            GgmlType::Q6K => "q6_K",
+            GgmlType::Q8K => "q8_K",
+            GgmlType::IQ2XXS => "iq2_xxs",
+            GgmlType::IQ2XS => "iq2_xs",
+            GgmlType::IQ3XXS => "iq3_xxs",
+            GgmlType::IQ1S => "iq1_s",
+            GgmlType::IQ4NL => "iq4_nl",
+            GgmlType::IQ3S => "iq3_s",
+            GgmlType::IQ2S => "iq2_s",
+            GgmlType::IQ4XS => "iq4_xs",
+            GgmlType::I8 => "i8",
+            GgmlType::I16 => "i16",
+            GgmlType::I32 => "i32",
+            GgmlType::I64 => "i64",
+            GgmlType::F64 => "f64",
+            GgmlType::IQ1M => "iq1_m",
+            GgmlType::BF16 => "bf16",
+            GgmlType::Q4_0_4_4 => "q4_0_4x4",
+            GgmlType::Q4_0_4_8 => "q4_0_4x8",
+            GgmlType::Q4_0_8_8 => "q4_0_8x8",
+            GgmlType::TQ1_0 => "tq1_0",
+            GgmlType::TQ2_0 => "tq2_0",
+        }
+    }
+
+    /// The number of elements stored together in one block.
+    #[must_use]
+    pub fn block_size(self) -> u64 {
+        match self {
+            GgmlType::F32
+            | GgmlType::F16
+            | GgmlType::BF16
+            | GgmlType::F64
+            | GgmlType::I8
+            | GgmlType::I16
+            | GgmlType::I32
+            | GgmlType::I64 => 1,
+            GgmlType::Q4_0
+            | GgmlType::Q4_1
+            | GgmlType::Q5_0
+            | GgmlType::Q5_1
+            | GgmlType::Q8_0
+            | GgmlType::Q8_1
+            | GgmlType::IQ4NL
+            | GgmlType::Q4_0_4_4
+            | GgmlType::Q4_0_4_8
+            | GgmlType::Q4_0_8_8 => 32,
+            _ => 256,
+        }
+    }
+
+    /// The size of one block in bytes.
+    #[must_use]
+    pub fn type_size(self) -> u64 {
+        match self {
+            GgmlType::I8 => 1,
+            GgmlType::F16 | GgmlType::BF16 | GgmlType::I16 => 2,
+            GgmlType::F32 | GgmlType::I32 => 4,
+            GgmlType::F64 | GgmlType::I64 => 8,
+            GgmlType::Q4_0
+            | GgmlType::IQ4NL
+            | GgmlType::Q4_0_4_4
+            | GgmlType::Q4_0_4_8
+            | GgmlType::Q4_0_8_8 => 18,
+            GgmlType::Q4_1 => 20,
+            GgmlType::Q5_0 => 22,
+            GgmlType::Q5_1 => 24,
+            GgmlType::Q8_0 => 34,
+            GgmlType::Q8_1 => 36,
+            GgmlType::Q2K => 84,
+            GgmlType::Q3K | GgmlType::IQ3S => 110,
+            GgmlType::Q4K => 144,
+            GgmlType::Q5K => 176,
+            GgmlType::Q6K => 210,
+            GgmlType::Q8K => 292,
+            GgmlType::IQ2XXS | GgmlType::TQ2_0 => 66,
+            GgmlType::IQ2XS => 74,
+            GgmlType::IQ3XXS => 98,
+            GgmlType::IQ1S => 50,
+            GgmlType::IQ2S => 82,
+            GgmlType::IQ4XS => 136,
+            GgmlType::IQ1M => 56,
+            GgmlType::TQ1_0 => 54,
+        }
+    }
+
+    /// The number of bits one element takes, including the scales of its block.
+    ///
+    /// ```
+    /// # use llama_cpp_2::ggml_type::GgmlType;
+    /// assert_eq!(GgmlType::F16.bits_per_weight(), 16.0);
+    /// assert_eq!(GgmlType::Q8_0.bits_per_weight(), 8.5);
+    /// assert_eq!(GgmlType::IQ2XXS.bits_per_weight(), 2.0625);
+    /// ```
+    #[must_use]
+    #[allow(clippy::cast_precision_loss)]
+    pub fn bits_per_weight(self) -> f64 {
+        (self.type_size() * 8) as f64 / self.block_size() as f64
+    }
+
+    /// The size in bytes of `n_elements` elements. A partial block takes a whole one.
+    #[must_use]
+    pub fn row_size(self, n_elements: u64) -> u64 {
+        n_elements.div_ceil(self.block_size()) * self.type_size()
+    }
+
+    /// Whether the type is quantized into blocks with scales.
+    #[must_use]
+    pub fn is_quantized(self) -> bool {
+        !matches!(
+            self,
+            GgmlType::F32
+                | GgmlType::F16
+                | GgmlType::BF16
+                | GgmlType::F64
+                | GgmlType::I8
+                | GgmlType::I16
+                | GgmlType::I32
+                | GgmlType::I64
+        )
+    }
+}
+
+impl Display for GgmlType {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// The value is not a known `ggml_type` or `llama_ftype`.
+#[derive(thiserror::Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GgmlTypeFromIntError {
+    /// The value is not a known `ggml_type`.
+    #[error("unknown ggml type {0}")]
+    UnknownType(llama_cpp_sys_2::ggml_type),
+    /// The value is not a known `llama_ftype`.
+    #[error("unknown file type {0}")]
+    UnknownFileType(llama_cpp_sys_2::llama_ftype),
+}
+
+impl TryFrom<llama_cpp_sys_2::ggml_type> for GgmlType {
+    type Error = GgmlTypeFromIntError;
+
+    fn try_from(value: llama_cpp_sys_2::ggml_type) -> Result<Self, Self::Error> {
+        GGML_TYPES
+            .iter()
+            .copied()
+            .find(|ggml_type| *ggml_type as llama_cpp_sys_2::ggml_type == value)
+            .ok_or(GgmlTypeFromIntError::UnknownType(value))
+    }
+}
+
+impl From<GgmlType> for llama_cpp_sys_2::ggml_type {
+    fn from(value: GgmlType) -> Self {
+        value as _
+    }
+}
+
+/// The name is not a known type.
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+#[error("unknown type {0:?}")]
+pub struct ParseGgmlTypeError(pub String);
+
+impl FromStr for GgmlType {
+    type Err = ParseGgmlTypeError;
+
+    /// Parse the [name](GgmlType::name) of a type, ignoring case.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        GGML_TYPES
+            .iter()
+            .copied()
+            .find(|ggml_type| ggml_type.name().eq_ignore_ascii_case(s))
+            .ok_or_else(|| ParseGgmlTypeError(s.to_string()))
+    }
+}
+
+/// The quantization of a model file, a rust flavored equivalent of `llama_ftype`. Most
+/// quantizations mix types: a few sensitive tensors are kept at a higher precision than the
+/// [main type](GgmlFileType::main_type).
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[allow(missing_docs)]
+pub enum GgmlFileType {
+    AllF32 = 0,
+    MostlyF16 = 1,
+    MostlyQ4_0 = 2,
+    MostlyQ4_1 = 3,
+    MostlyQ8_0 = 7,
+    MostlyQ5_0 = 8,
+    MostlyQ5_1 = 9,
+    MostlyQ2K = 10,
+    MostlyQ3KS = 11,
+    MostlyQ3KM = 12,
+    MostlyQ3KL = 13,
+    MostlyQ4KS = 14,
+    MostlyQ4KM = 15,
+    MostlyQ5KS = 16,
+    MostlyQ5KM = 17,
+    MostlyQ6K = 18,
+    MostlyIQ2XXS = 19,
+    MostlyIQ2XS = 20,
+    MostlyQ2KS = 21,
+    MostlyIQ3XS = 22,
+    MostlyIQ3XXS = 23,
+    MostlyIQ1S = 24,
+    MostlyIQ4NL = 25,
+    MostlyIQ3S = 26,
+    MostlyIQ3M = 27,
+    MostlyIQ2S = 28,
+    MostlyIQ2M = 29,
+    MostlyIQ4XS = 30,
+    MostlyIQ1M = 31,
+    MostlyBF16 = 32,
+    MostlyQ4_0_4_4 = 33,
+    MostlyQ4_0_4_8 = 34,
+    MostlyQ4_0_8_8 = 35,
+    MostlyTQ1_0 = 36,
+    MostlyTQ2_0 = 37,
+}
+
+/// Every [`GgmlFileType`], in the order of their values.
+const FILE_TYPES: [GgmlFileType; 35] = [
+    GgmlFileType::AllF32,
+    GgmlFileType::MostlyF16,
+    GgmlFileType::MostlyQ4_0,
+    GgmlFileType::MostlyQ4_1,
+    GgmlFileType::MostlyQ8_0,
+    GgmlFileType::MostlyQ5_0,
+    GgmlFileType::MostlyQ5_1,
+    GgmlFileType::MostlyQ2K,
+    GgmlFileType::MostlyQ3KS,
+    GgmlFileType::MostlyQ3KM,
+    GgmlFileType::MostlyQ3KL,
+    GgmlFileType::MostlyQ4KS,
+    GgmlFileType::MostlyQ4KM,
+    GgmlFileType::MostlyQ5KS,
+    GgmlFileType::MostlyQ5KM,
+    GgmlFileType::MostlyQ6K,
+    GgmlFileType::MostlyIQ2XXS,
+    GgmlFileType::MostlyIQ2XS,
+    GgmlFileType::MostlyQ2KS,
+    GgmlFileType::MostlyIQ3XS,
+    GgmlFileType::MostlyIQ3XXS,
+    GgmlFileType::MostlyIQ1S,
+    GgmlFileType::MostlyIQ4NL,
+    GgmlFileType::MostlyIQ3S,
+    GgmlFileType::MostlyIQ3M,
+    GgmlFileType::MostlyIQ2S,
+    GgmlFileType::MostlyIQ2M,
+    GgmlFileType::MostlyIQ4XS,
+    GgmlFileType::MostlyIQ1M,
+    GgmlFileType::MostlyBF16,
+    GgmlFileType::MostlyQ4_0_4_4,
+    GgmlFileType::MostlyQ4_0_4_8,
+    GgmlFileType::MostlyQ4_0_8_8,
+    GgmlFileType::MostlyTQ1_0,
+    GgmlFileType::MostlyTQ2_0,
+];
+
+impl GgmlFileType {
+    /// Every file type, in the order of their values.
+    #[must_use]
+    pub fn all() -> &'static [GgmlFileType] {
+        &FILE_TYPES
+    }
+
+    /// The short name `llama-quantize` accepts and model repositories put in file names, e.g.
+    /// `Q4_K_M`.
+    #[must_use]
+    pub fn short_name(self) -> &'static str {
+        match self {
+            GgmlFileType::AllF32 => "F32",
+            GgmlFileType::MostlyF16 => "F16",
+            GgmlFileType::MostlyQ4_0 => "Q4_0",
+            GgmlFileType::MostlyQ4_1 => "Q4_1",
+            GgmlFileType::MostlyQ8_0 => "Q8_0",
+            GgmlFileType::MostlyQ5_0 => "Q5_0",
+            GgmlFileType::MostlyQ5_1 => "Q5_1",
+            GgmlFileType::MostlyQ2K => "Q2_K",
+            GgmlFileType::MostlyQ3KS => "Q3_K_S",
+            GgmlFileType::MostlyQ3KM => "Q3_K_M",
+            GgmlFileType::MostlyQ3KL => "Q3_K_L",
+            GgmlFileType::MostlyQ4KS => "Q4_K_S",
+            GgmlFileType::MostlyQ4KM => "Q4_K_M",
+            GgmlFileType::MostlyQ5KS => "Q5_K_S",
+            GgmlFileType::MostlyQ5KM => "Q5_K_M",
+            GgmlFileType::MostlyQ6K => "Q6_K",
+            GgmlFileType::MostlyIQ2XXS => "IQ2_XXS",
+            GgmlFileType::MostlyIQ2XS => "IQ2_XS",
+            GgmlFileType::MostlyQ2KS => "Q2_K_S",
+            GgmlFileType::MostlyIQ3XS => "IQ3_XS",
+            GgmlFileType::MostlyIQ3XXS => "IQ3_XXS",
+            GgmlFileType::MostlyIQ1S => "IQ1_S",
+            GgmlFileType::MostlyIQ4NL => "IQ4_NL",
+            GgmlFileType::MostlyIQ3S => "IQ3_S",
+            GgmlFileType::MostlyIQ3M => "IQ3_M",
+            GgmlFileType::MostlyIQ2S => "IQ2_S",
+            GgmlFileType::MostlyIQ2M => "IQ2_M",
+            GgmlFileType::MostlyIQ4XS => "IQ4_XS",
+            GgmlFileType::MostlyIQ1M => "IQ1_M",
+            GgmlFileType::MostlyBF16 => "BF16",
+            GgmlFileType::MostlyQ4_0_4_4 => "Q4_0_4_4",
+            GgmlFileType::MostlyQ4_0_4_8 => "Q4_0_4_8",
+            GgmlFileType::MostlyQ4_0_8_8 => "Q4_0_8_8",
+            GgmlFileType::MostlyTQ1_0 => "TQ1_0",
+            GgmlFileType::MostlyTQ2_0 => "TQ2_0",
+        }
+    }
+
+    /// The description llama.cpp prints for the file type (`llama_model_ftype_name`), e.g.
+    /// `Q4_K - Medium`. This is also the [`Display`] of the file type.
+    #[must_use]
+    pub fn description(self) -> &'static str {
+        match self {
+            GgmlFileType::AllF32 => "all F32",
+            GgmlFileType::MostlyQ2K => "Q2_K - Medium",
+            GgmlFileType::MostlyQ2KS => "Q2_K - Small",
+            GgmlFileType::MostlyQ3KS => "Q3_K - Small",
+            GgmlFileType::MostlyQ3KM => "Q3_K - Medium",
+            GgmlFileType::MostlyQ3KL => "Q3_K - Large",
+            GgmlFileType::MostlyQ4KS => "Q4_K - Small",
+            GgmlFileType::MostlyQ4KM => "Q4_K - Medium",
+            GgmlFileType::MostlyQ5KS => "Q5_K - Small",
+            GgmlFileType::MostlyQ5KM => "Q5_K - Medium",
+            GgmlFileType::MostlyIQ2XXS => "IQ2_XXS - 2.0625 bpw",
+            GgmlFileType::MostlyIQ2XS => "IQ2_XS - 2.3125 bpw",
+            GgmlFileType::MostlyIQ2S => "IQ2_S - 2.5 bpw",
+            GgmlFileType::MostlyIQ2M => "IQ2_M - 2.7 bpw",
+            GgmlFileType::MostlyIQ3XS => "IQ3_XS - 3.3 bpw",
+            GgmlFileType::MostlyIQ3XXS => "IQ3_XXS - 3.0625 bpw",
+            GgmlFileType::MostlyIQ1S => "IQ1_S - 1.5625 bpw",
+            GgmlFileType::MostlyIQ1M => "IQ1_M - 1.75 bpw",
+            GgmlFileType::MostlyIQ4NL => "IQ4_NL - 4.5 bpw",
+            GgmlFileType::MostlyIQ4XS => "IQ4_XS - 4.25 bpw",
+            GgmlFileType::MostlyIQ3S => "IQ3_S - 3.4375 bpw",
+            GgmlFileType::MostlyIQ3M => "IQ3_S mix - 3.66 bpw",
+            GgmlFileType::MostlyTQ1_0 => "TQ1_0 - 1.69 bpw ternary",
+            GgmlFileType::MostlyTQ2_0 => "TQ2_0 - 2.06 bpw ternary",
+            file_type => file_type.short_name(),
+        }
+    }
+
+    /// The type of most tensors of a file quantized to this file type, the one `llama-quantize`
+    /// starts from before keeping some tensors at a higher precision.
+    #[must_use]
+    pub fn main_type(self) -> GgmlType {
+        match self {
+            GgmlFileType::AllF32 => GgmlType::F32,
+            GgmlFileType::MostlyF16 => GgmlType::F16,
+            GgmlFileType::MostlyBF16 => GgmlType::BF16,
+            GgmlFileType::MostlyQ4_0 => GgmlType::Q4_0,
+            GgmlFileType::MostlyQ4_1 => GgmlType::Q4_1,
+            GgmlFileType::MostlyQ5_0 => GgmlType::Q5_0,
+            GgmlFileType::MostlyQ5_1 => GgmlType::Q5_1,
+            GgmlFileType::MostlyQ8_0 => GgmlType::Q8_0,
+            GgmlFileType::MostlyQ2K | GgmlFileType::MostlyQ2KS => GgmlType::Q2K,
+            GgmlFileType::MostlyQ3KS | GgmlFileType::MostlyQ3KM | GgmlFileType::MostlyQ3KL => {
+                GgmlType::Q3K
+            }
+            GgmlFileType::MostlyQ4KS | GgmlFileType::MostlyQ4KM => GgmlType::Q4K,
+            GgmlFileType::MostlyQ5KS | GgmlFileType::MostlyQ5KM => GgmlType::Q5K,
+            GgmlFileType::MostlyQ6K => GgmlType::Q6K,
+            GgmlFileType::MostlyIQ2XXS => GgmlType::IQ2XXS,
+            GgmlFileType::MostlyIQ2XS | GgmlFileType::MostlyIQ2S => GgmlType::IQ2XS,
+            GgmlFileType::MostlyIQ2M => GgmlType::IQ2S,
+            GgmlFileType::MostlyIQ3XXS => GgmlType::IQ3XXS,
+            GgmlFileType::MostlyIQ3XS | GgmlFileType::MostlyIQ3S | GgmlFileType::MostlyIQ3M => {
+                GgmlType::IQ3S
+            }
+            GgmlFileType::MostlyIQ1S => GgmlType::IQ1S,
+            GgmlFileType::MostlyIQ1M => GgmlType::IQ1M,
+            GgmlFileType::MostlyIQ4NL => GgmlType::IQ4NL,
+            GgmlFileType::MostlyIQ4XS => GgmlType::IQ4XS,
+            GgmlFileType::MostlyQ4_0_4_4 => GgmlType::Q4_0_4_4,
+            GgmlFileType::MostlyQ4_0_4_8 => GgmlType::Q4_0_4_8,
+            GgmlFileType::MostlyQ4_0_8_8 => GgmlType::Q4_0_8_8,
+            GgmlFileType::MostlyTQ1_0 => GgmlType::TQ1_0,
+            GgmlFileType::MostlyTQ2_0 => GgmlType::TQ2_0,
+        }
+    }
+
+    /// The bits per weight of the [main type](GgmlFileType::main_type). Files are a little
+    /// larger, since the tensors kept at a higher precision are not counted.
+    #[must_use]
+    pub fn bits_per_weight(self) -> f64 {
+        self.main_type().bits_per_weight()
+    }
+
+    /// Whether the file type needs an importance matrix to quantize with acceptable quality.
+    /// llama.cpp refuses to quantize to these without one.
+    #[must_use]
+    pub fn needs_imatrix(self) -> bool {
+        matches!(
+            self,
+            GgmlFileType::MostlyIQ2XXS
+                | GgmlFileType::MostlyIQ2XS
+                | GgmlFileType::MostlyIQ2S
+                | GgmlFileType::MostlyQ2KS
+                | GgmlFileType::MostlyIQ1S
+                | GgmlFileType::MostlyIQ1M
+        )
+    }
+}
+
+impl Display for GgmlFileType {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.description())
+    }
+}
+
+impl TryFrom<llama_cpp_sys_2::llama_ftype> for GgmlFileType {
+    type Error = GgmlTypeFromIntError;
+
+    /// Convert a `llama_ftype`, ignoring the flag llama.cpp sets on guessed file types.
+    fn try_from(value: llama_cpp_sys_2::llama_ftype) -> Result<Self, Self::Error> {
+        let file_type = value & !llama_cpp_sys_2::LLAMA_FTYPE_GUESSED;
+        FILE_TYPES
+            .iter()
+            .copied()
+            .find(|known| *known as llama_cpp_sys_2::llama_ftype == file_type)
+            .ok_or(GgmlTypeFromIntError::UnknownFileType(value))
+    }
+}
+
+impl From<GgmlFileType> for llama_cpp_sys_2::llama_ftype {
+    fn from(value: GgmlFileType) -> Self {
+        value as _
+    }
+}
+
+impl FromStr for GgmlFileType {
+    type Err = ParseGgmlTypeError;
+
+    /// Parse the [short name](GgmlFileType::short_name) of a file type, ignoring case.
+    ///
+    /// ```
+    /// # use llama_cpp_2::ggml_type::GgmlFileType;
+    /// assert_eq!("IQ4_XS".parse(), Ok(GgmlFileType::MostlyIQ4XS));
+    /// assert!("Q4_K_X".parse::<GgmlFileType>().is_err());
+    /// ```
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        FILE_TYPES
+            .iter()
+            .copied()
+            .find(|file_type| file_type.short_name().eq_ignore_ascii_case(s))
+            .ok_or_else(|| ParseGgmlTypeError(s.to_string()))
+    }
+}
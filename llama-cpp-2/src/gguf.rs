@@ -0,0 +1,593 @@
+//! A reader for the header of GGUF files.
+//!
+//! This parses the metadata and tensor infos of a GGUF file in rust without loading any tensor
+//! data (or llama.cpp for that matter), so it is cheap enough to call on a multi-GB model to show
+//! information about it before deciding to load it.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! # use llama_cpp_2::gguf::GgufFile;
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! let gguf = GgufFile::open("path/to/model.gguf")?;
+//! println!("version {} alignment {}", gguf.version(), gguf.alignment());
+//! for (key, value) in gguf.metadata() {
+//!     println!("{key} = {value}");
+//! }
+//! for tensor in gguf.tensors() {
+//!     println!("{} {:?} {}", tensor.name, tensor.dimensions, tensor.type_name());
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use std::ffi::CStr;
+use std::fmt::{Display, Formatter};
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::Path;
+use std::string::FromUtf8Error;
+
+use crate::ggml_type::{GgmlFileType, GgmlType};
+
+pub mod estimate;
+pub mod metadata;
+pub mod writer;
+
+#[cfg(test)]
+mod tests;
+
+/// The magic bytes every GGUF file starts with.
+pub const GGUF_MAGIC: [u8; 4] = *b"GGUF";
+
+/// The alignment of the tensor data if `general.alignment` is not set.
+pub const GGUF_DEFAULT_ALIGNMENT: u64 = 32;
+
+/// The maximum number of dimensions of a tensor.
+const GGML_MAX_DIMS: u32 = 4;
+
+/// An error that can occur while reading a GGUF file.
+#[derive(Debug, thiserror::Error)]
+#[allow(clippy::module_name_repetitions)]
+pub enum GgufError {
+    /// Reading the file failed (this includes the file being truncated).
+    #[error("{0}")]
+    Io(#[from] std::io::Error),
+    /// The file does not start with the GGUF magic.
+    #[error("invalid magic {0:?}, not a GGUF file")]
+    InvalidMagic([u8; 4]),
+    /// The file has a version this reader does not understand.
+    #[error("unsupported GGUF version {0}")]
+    UnsupportedVersion(u32),
+    /// A metadata value has an unknown type.
+    #[error("unknown GGUF value type {0}")]
+    UnknownValueType(u32),
+    /// A tensor has more dimensions than ggml supports.
+    #[error("tensor {name} has {n_dims} dimensions")]
+    TooManyDimensions {
+        /// The name of the tensor.
+        name: String,
+        /// The number of dimensions.
+        n_dims: u32,
+    },
+    /// A string was not valid utf8.
+    #[error("{0}")]
+    FromUtf8Error(#[from] FromUtf8Error),
+    /// Arrays are nested deeper than [`MAX_ARRAY_DEPTH`].
+    #[error("arrays are nested deeper than {MAX_ARRAY_DEPTH} levels")]
+    ArrayTooDeep,
+    /// An array has more elements than the rest of the file can hold.
+    #[error("array of {n} elements of {element_size} bytes does not fit into the remaining {remaining} bytes")]
+    ArrayTooLarge {
+        /// The number of elements.
+        n: u64,
+        /// The smallest size of an element in the file.
+        element_size: u64,
+        /// The number of bytes after the array header.
+        remaining: u64,
+    },
+}
+
+/// How deep arrays of arrays may be nested. llama.cpp supports no nesting at all, the limit keeps
+/// a corrupted file from overflowing the stack.
+pub const MAX_ARRAY_DEPTH: usize = 8;
+
+/// A typed metadata value.
+#[derive(Debug, Clone, PartialEq)]
+#[allow(clippy::module_name_repetitions)]
+pub enum GgufValue {
+    /// `GGUF_TYPE_UINT8`
+    U8(u8),
+    /// `GGUF_TYPE_INT8`
+    I8(i8),
+    /// `GGUF_TYPE_UINT16`
+    U16(u16),
+    /// `GGUF_TYPE_INT16`
+    I16(i16),
+    /// `GGUF_TYPE_UINT32`
+    U32(u32),
+    /// `GGUF_TYPE_INT32`
+    I32(i32),
+    /// `GGUF_TYPE_FLOAT32`
+    F32(f32),
+    /// `GGUF_TYPE_BOOL`
+    Bool(bool),
+    /// `GGUF_TYPE_STRING`
+    String(String),
+    /// `GGUF_TYPE_ARRAY`
+    Array(Vec<GgufValue>),
+    /// `GGUF_TYPE_UINT64`
+    U64(u64),
+    /// `GGUF_TYPE_INT64`
+    I64(i64),
+    /// `GGUF_TYPE_FLOAT64`
+    F64(f64),
+}
+
+impl GgufValue {
+    /// The value as an unsigned integer, if it is a non-negative integer of any width.
+    ///
+    /// ```
+    /// # use llama_cpp_2::gguf::GgufValue;
+    /// assert_eq!(GgufValue::U32(4096).as_u64(), Some(4096));
+    /// assert_eq!(GgufValue::I32(-1).as_u64(), None);
+    /// assert_eq!(GgufValue::F32(1.0).as_u64(), None);
+    /// ```
+    #[must_use]
+    pub fn as_u64(&self) -> Option<u64> {
+        match *self {
+            GgufValue::U8(v) => Some(u64::from(v)),
+            GgufValue::U16(v) => Some(u64::from(v)),
+            GgufValue::U32(v) => Some(u64::from(v)),
+            GgufValue::U64(v) => Some(v),
+            GgufValue::I8(v) => u64::try_from(v).ok(),
+            GgufValue::I16(v) => u64::try_from(v).ok(),
+            GgufValue::I32(v) => u64::try_from(v).ok(),
+            GgufValue::I64(v) => u64::try_from(v).ok(),
+            _ => None,
+        }
+    }
+
+    /// The value as a signed integer, if it is an integer of any width that fits.
+    #[must_use]
+    pub fn as_i64(&self) -> Option<i64> {
+        match *self {
+            GgufValue::I8(v) => Some(i64::from(v)),
+            GgufValue::I16(v) => Some(i64::from(v)),
+            GgufValue::I32(v) => Some(i64::from(v)),
+            GgufValue::I64(v) => Some(v),
+            GgufValue::U8(v) => Some(i64::from(v)),
+            GgufValue::U16(v) => Some(i64::from(v)),
+            GgufValue::U32(v) => Some(i64::from(v)),
+            GgufValue::U64(v) => i64::try_from(v).ok(),
+            _ => None,
+        }
+    }
+
+    /// The value as a float, if it is a float of any width.
+    #[must_use]
+    pub fn as_f64(&self) -> Option<f64> {
+        match *self {
+            GgufValue::F32(v) => Some(f64::from(v)),
+            GgufValue::F64(v) => Some(v),
+            _ => None,
+        }
+    }
+
+    /// The value as a bool, if it is a bool.
+    #[must_use]
+    pub fn as_bool(&self) -> Option<bool> {
+        match *self {
+            GgufValue::Bool(v) => Some(v),
+            _ => None,
+        }
+    }
+
+    /// The value as a string, if it is a string.
+    #[must_use]
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            GgufValue::String(v) => Some(v),
+            _ => None,
+        }
+    }
+
+    /// The value as an array, if it is an array.
+    #[must_use]
+    pub fn as_array(&self) -> Option<&[GgufValue]> {
+        match self {
+            GgufValue::Array(v) => Some(v),
+            _ => None,
+        }
+    }
+
+    /// The name of the type of the value, as used by llama.cpp's `gguf_type_name`.
+    #[must_use]
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            GgufValue::U8(_) => "u8",
+            GgufValue::I8(_) => "i8",
+            GgufValue::U16(_) => "u16",
+            GgufValue::I16(_) => "i16",
+            GgufValue::U32(_) => "u32",
+            GgufValue::I32(_) => "i32",
+            GgufValue::F32(_) => "f32",
+            GgufValue::Bool(_) => "bool",
+            GgufValue::String(_) => "str",
+            GgufValue::Array(_) => "arr",
+            GgufValue::U64(_) => "u64",
+            GgufValue::I64(_) => "i64",
+            GgufValue::F64(_) => "f64",
+        }
+    }
+}
+
+/// Long arrays (such as the vocabulary) are abbreviated.
+impl Display for GgufValue {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        const MAX_DISPLAYED_ITEMS: usize = 8;
+        match self {
+            GgufValue::U8(v) => write!(f, "{v}"),
+            GgufValue::I8(v) => write!(f, "{v}"),
+            GgufValue::U16(v) => write!(f, "{v}"),
+            GgufValue::I16(v) => write!(f, "{v}"),
+            GgufValue::U32(v) => write!(f, "{v}"),
+            GgufValue::I32(v) => write!(f, "{v}"),
+            GgufValue::F32(v) => write!(f, "{v}"),
+            GgufValue::Bool(v) => write!(f, "{v}"),
+            GgufValue::String(v) => write!(f, "{v:?}"),
+            GgufValue::U64(v) => write!(f, "{v}"),
+            GgufValue::I64(v) => write!(f, "{v}"),
+            GgufValue::F64(v) => write!(f, "{v}"),
+            GgufValue::Array(values) => {
+                write!(f, "[")?;
+                for (i, value) in values.iter().take(MAX_DISPLAYED_ITEMS).enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{value}")?;
+                }
+                if values.len() > MAX_DISPLAYED_ITEMS {
+                    write!(f, ", ... ({} items)", values.len())?;
+                }
+                write!(f, "]")
+            }
+        }
+    }
+}
+
+/// Information about a tensor stored in a GGUF file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[allow(clippy::module_name_repetitions)]
+pub struct GgufTensorInfo {
+    /// The name of the tensor.
+    pub name: String,
+    /// The size of each dimension, innermost first (ggml's `ne`).
+    pub dimensions: Vec<u64>,
+    /// The ggml type of the tensor data.
+    pub ggml_type: llama_cpp_sys_2::ggml_type,
+    /// The offset of the tensor data relative to [`GgufFile::data_offset`].
+    pub offset: u64,
+}
+
+impl GgufTensorInfo {
+    /// The number of elements in the tensor.
+    ///
+    /// ```
+    /// # use llama_cpp_2::gguf::GgufTensorInfo;
+    /// let tensor = GgufTensorInfo {
+    ///     name: "token_embd.weight".to_string(),
+    ///     dimensions: vec![4096, 32000],
+    ///     ggml_type: 0,
+    ///     offset: 0,
+    /// };
+    /// assert_eq!(tensor.n_elements(), 4096 * 32000);
+    /// ```
+    #[must_use]
+    pub fn n_elements(&self) -> u64 {
+        self.dimensions.iter().product()
+    }
+
+    /// The name of the ggml type of the tensor (e.g. `q4_K`).
+    ///
+    /// # Panics
+    ///
+    /// If ggml returns a type name that is not valid utf8.
+    #[must_use]
+    pub fn type_name(&self) -> &'static str {
+        // ggml returns "NONE" for out of range types, so this is fine for any value read from a file
+        let name = unsafe { CStr::from_ptr(llama_cpp_sys_2::ggml_type_name(self.ggml_type)) };
+        name.to_str().expect("ggml type names are valid utf8")
+    }
+
+    /// The ggml type of the tensor, or `None` if it is unknown to this crate.
+    #[must_use]
+    pub fn tensor_type(&self) -> Option<GgmlType> {
+        GgmlType::try_from(self.ggml_type).ok()
+    }
+}
+
+/// The header of a GGUF file: its metadata and tensor infos.
+#[derive(Debug, Clone, PartialEq)]
+#[allow(clippy::module_name_repetitions)]
+pub struct GgufFile {
+    version: u32,
+    alignment: u64,
+    data_offset: u64,
+    metadata: Vec<(String, GgufValue)>,
+    tensors: Vec<GgufTensorInfo>,
+}
+
+impl GgufFile {
+    /// Read the header of the GGUF file at `path`. No tensor data is read.
+    ///
+    /// # Errors
+    ///
+    /// See [`GgufError`] for more information.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, GgufError> {
+        let file = File::open(path)?;
+        let len = file.metadata()?.len();
+        Self::read_sized(BufReader::new(file), len)
+    }
+
+    /// Read a GGUF header from `reader`. Reading stops at the end of the tensor infos.
+    ///
+    /// # Errors
+    ///
+    /// See [`GgufError`] for more information.
+    pub fn read(reader: impl Read) -> Result<Self, GgufError> {
+        Self::read_from(GgufReader {
+            inner: reader,
+            position: 0,
+            len: None,
+        })
+    }
+
+    /// Like [`GgufFile::read`], for a `reader` of `len` bytes. Arrays with more elements than the
+    /// rest of the input can hold fail with [`GgufError::ArrayTooLarge`] before any of them is
+    /// read.
+    ///
+    /// ```
+    /// # use llama_cpp_2::gguf::{GgufFile, GgufValue};
+    /// # use llama_cpp_2::gguf::writer::GgufWriter;
+    /// let bytes = GgufWriter::new()
+    ///     .with_metadata("general.architecture", GgufValue::String("llama".to_string()))
+    ///     .to_bytes();
+    /// let gguf = GgufFile::read_sized(bytes.as_slice(), bytes.len() as u64)?;
+    /// assert_eq!(gguf.architecture(), Some("llama"));
+    /// # Ok::<(), llama_cpp_2::gguf::GgufError>(())
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// See [`GgufError`] for more information.
+    pub fn read_sized(reader: impl Read, len: u64) -> Result<Self, GgufError> {
+        Self::read_from(GgufReader {
+            inner: reader,
+            position: 0,
+            len: Some(len),
+        })
+    }
+
+    fn read_from<R: Read>(mut reader: GgufReader<R>) -> Result<Self, GgufError> {
+        let mut magic = [0; 4];
+        reader.read_exact(&mut magic)?;
+        if magic != GGUF_MAGIC {
+            return Err(GgufError::InvalidMagic(magic));
+        }
+
+        // version 1 used 32 bit lengths and is not supported by llama.cpp anymore
+        let version = reader.read_u32()?;
+        if !(2..=3).contains(&version) {
+            return Err(GgufError::UnsupportedVersion(version));
+        }
+
+        let n_tensors = reader.read_u64()?;
+        let n_kv = reader.read_u64()?;
+
+        let mut metadata = Vec::with_capacity(capacity_hint(n_kv));
+        for _ in 0..n_kv {
+            let key = reader.read_string()?;
+            let value_type = reader.read_u32()?;
+            let value = reader.read_value(value_type, 0)?;
+            metadata.push((key, value));
+        }
+
+        let mut tensors = Vec::with_capacity(capacity_hint(n_tensors));
+        for _ in 0..n_tensors {
+            let name = reader.read_string()?;
+            let n_dims = reader.read_u32()?;
+            if n_dims > GGML_MAX_DIMS {
+                return Err(GgufError::TooManyDimensions { name, n_dims });
+            }
+            let dimensions = (0..n_dims)
+                .map(|_| reader.read_u64())
+                .collect::<Result<Vec<_>, _>>()?;
+            let ggml_type = reader.read_u32()?;
+            let offset = reader.read_u64()?;
+            tensors.push(GgufTensorInfo {
+                name,
+                dimensions,
+                ggml_type,
+                offset,
+            });
+        }
+
+        let alignment = metadata
+            .iter()
+            .find(|(key, _)| key == "general.alignment")
+            .and_then(|(_, value)| value.as_u64())
+            .filter(|alignment| *alignment > 0)
+            .unwrap_or(GGUF_DEFAULT_ALIGNMENT);
+        let data_offset = reader.position.div_ceil(alignment) * alignment;
+
+        Ok(Self {
+            version,
+            alignment,
+            data_offset,
+            metadata,
+            tensors,
+        })
+    }
+
+    /// The GGUF version of the file.
+    #[must_use]
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    /// The alignment of the tensor data.
+    #[must_use]
+    pub fn alignment(&self) -> u64 {
+        self.alignment
+    }
+
+    /// The offset in the file at which the tensor data starts.
+    #[must_use]
+    pub fn data_offset(&self) -> u64 {
+        self.data_offset
+    }
+
+    /// All metadata key-value pairs in the order they are stored in the file.
+    #[must_use]
+    pub fn metadata(&self) -> &[(String, GgufValue)] {
+        &self.metadata
+    }
+
+    /// Get a metadata value by key.
+    #[must_use]
+    pub fn get(&self, key: &str) -> Option<&GgufValue> {
+        self.metadata
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, value)| value)
+    }
+
+    /// All tensor infos in the order they are stored in the file.
+    #[must_use]
+    pub fn tensors(&self) -> &[GgufTensorInfo] {
+        &self.tensors
+    }
+
+    /// Get a tensor info by name.
+    #[must_use]
+    pub fn tensor(&self, name: &str) -> Option<&GgufTensorInfo> {
+        self.tensors.iter().find(|tensor| tensor.name == name)
+    }
+
+    /// The quantization the file was made with, read from `general.file_type`. Returns `None` if
+    /// the file does not store it or it is unknown.
+    #[must_use]
+    pub fn file_type(&self) -> Option<GgmlFileType> {
+        let file_type = self.get("general.file_type")?.as_u64()?;
+        GgmlFileType::try_from(llama_cpp_sys_2::llama_ftype::try_from(file_type).ok()?).ok()
+    }
+}
+
+/// Do not trust counts read from the file for preallocation, a corrupted count would allocate
+/// until the process is killed instead of failing with an unexpected EOF.
+fn capacity_hint(n: u64) -> usize {
+    usize::try_from(n.min(4096)).unwrap_or(0)
+}
+
+/// A little endian reader that keeps track of its position.
+struct GgufReader<R> {
+    inner: R,
+    position: u64,
+    /// The length of the input, if known.
+    len: Option<u64>,
+}
+
+impl<R: Read> GgufReader<R> {
+    fn read_exact(&mut self, buf: &mut [u8]) -> std::io::Result<()> {
+        self.inner.read_exact(buf)?;
+        self.position += buf.len() as u64;
+        Ok(())
+    }
+
+    fn read_array<const N: usize>(&mut self) -> std::io::Result<[u8; N]> {
+        let mut buf = [0; N];
+        self.read_exact(&mut buf)?;
+        Ok(buf)
+    }
+
+    fn read_u32(&mut self) -> std::io::Result<u32> {
+        self.read_array().map(u32::from_le_bytes)
+    }
+
+    fn read_u64(&mut self) -> std::io::Result<u64> {
+        self.read_array().map(u64::from_le_bytes)
+    }
+
+    fn read_string(&mut self) -> Result<String, GgufError> {
+        let len = self.read_u64()?;
+        let mut buf = Vec::with_capacity(capacity_hint(len));
+        let read = (&mut self.inner).take(len).read_to_end(&mut buf)?;
+        self.position += read as u64;
+        if read as u64 != len {
+            return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+        }
+        Ok(String::from_utf8(buf)?)
+    }
+
+    /// Read a value of `value_type` inside `depth` arrays.
+    fn read_value(&mut self, value_type: u32, depth: usize) -> Result<GgufValue, GgufError> {
+        let value = match value_type {
+            0 => GgufValue::U8(u8::from_le_bytes(self.read_array()?)),
+            1 => GgufValue::I8(i8::from_le_bytes(self.read_array()?)),
+            2 => GgufValue::U16(u16::from_le_bytes(self.read_array()?)),
+            3 => GgufValue::I16(i16::from_le_bytes(self.read_array()?)),
+            4 => GgufValue::U32(u32::from_le_bytes(self.read_array()?)),
+            5 => GgufValue::I32(i32::from_le_bytes(self.read_array()?)),
+            6 => GgufValue::F32(f32::from_le_bytes(self.read_array()?)),
+            7 => GgufValue::Bool(self.read_array::<1>()?[0] != 0),
+            8 => GgufValue::String(self.read_string()?),
+            9 => {
+                if depth == MAX_ARRAY_DEPTH {
+                    return Err(GgufError::ArrayTooDeep);
+                }
+                let element_type = self.read_u32()?;
+                let n = self.read_u64()?;
+                self.check_fits(n, element_type)?;
+                let mut values = Vec::with_capacity(capacity_hint(n));
+                for _ in 0..n {
+                    values.push(self.read_value(element_type, depth + 1)?);
+                }
+                GgufValue::Array(values)
+            }
+            10 => GgufValue::U64(u64::from_le_bytes(self.read_array()?)),
+            11 => GgufValue::I64(i64::from_le_bytes(self.read_array()?)),
+            12 => GgufValue::F64(f64::from_le_bytes(self.read_array()?)),
+            unknown => return Err(GgufError::UnknownValueType(unknown)),
+        };
+        Ok(value)
+    }
+
+    /// Check that `n` values of `value_type` fit into the rest of the input, if its length is
+    /// known.
+    fn check_fits(&self, n: u64, value_type: u32) -> Result<(), GgufError> {
+        let Some(len) = self.len else {
+            return Ok(());
+        };
+        let element_size = match value_type {
+            0 | 1 | 7 => 1,
+            2 | 3 => 2,
+            4..=6 => 4,
+            // strings start with their length, arrays with their type and length
+            8 | 10..=12 => 8,
+            9 => 12,
+            unknown => return Err(GgufError::UnknownValueType(unknown)),
+        };
+        let remaining = len.saturating_sub(self.position);
+        if n.checked_mul(element_size)
+            .map_or(true, |size| size > remaining)
+        {
+            return Err(GgufError::ArrayTooLarge {
+                n,
+                element_size,
+                remaining,
+            });
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,554 @@
+//! Estimate the memory a model will need before loading it.
+//!
+//! The estimate follows how llama.cpp distributes a model: the token embeddings stay on the CPU,
+//! the last `n_gpu_layers` repeating layers (and the output layer if all of them are offloaded) go
+//! to the GPU, and the KV cache of a layer lives on the same device as the layer when
+//! `offload_kqv` is set. With several GPUs the offloaded layers are split between them in
+//! proportion to their free memory, as llama.cpp does by default.
+//!
+//! The weight and KV cache sizes are exact. The compute buffer size is a heuristic based on the
+//! largest intermediate tensors of a transformer forward pass and should be treated as an
+//! approximation.
+//!
+//! [`gpu_devices`] asks the GPU backend ggml was built with for the free memory of its devices,
+//! so a configuration can be checked before the model is loaded:
+//!
+//! ```no_run
+//! # use llama_cpp_2::gguf::GgufFile;
+//! # use llama_cpp_2::gguf::estimate::{
+//! #     estimate_memory_on, gpu_devices, MemoryEstimateParams, DEFAULT_SAFETY_MARGIN,
+//! # };
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! let gguf = GgufFile::open("path/to/model.gguf")?;
+//! let devices = gpu_devices();
+//! let params = MemoryEstimateParams { n_ctx: 8192, n_gpu_layers: 99, ..Default::default() };
+//! let estimate = estimate_memory_on(&gguf, &params, &devices)?;
+//! for (device, memory) in devices.iter().zip(&estimate.gpus) {
+//!     println!("{}: {} of {} bytes", device.description, memory.total(), device.free);
+//! }
+//! if !estimate.fits(&devices, DEFAULT_SAFETY_MARGIN) {
+//!     println!("this will not fit, offload fewer layers or use a smaller context");
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+#[cfg(any(feature = "cuda", feature = "vulkan"))]
+use std::ffi::{c_char, c_int, CStr};
+
+use crate::gguf::{GgufFile, GgufValue};
+use llama_cpp_sys_2::ggml_type;
+
+/// Failed to estimate the memory footprint of a model.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum MemoryEstimateError {
+    /// A metadata key required for the estimate is missing or has the wrong type.
+    #[error("missing or invalid metadata key {0}")]
+    MissingKey(String),
+    /// A tensor has a ggml type unknown to the linked ggml.
+    #[error("tensor {name} has unknown ggml type {ggml_type}")]
+    UnknownTensorType {
+        /// The name of the tensor.
+        name: String,
+        /// The type of the tensor.
+        ggml_type: ggml_type,
+    },
+}
+
+/// The configuration to estimate the memory footprint for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryEstimateParams {
+    /// The context size.
+    pub n_ctx: u32,
+    /// The physical batch size.
+    pub n_ubatch: u32,
+    /// The number of layers to offload to the GPU.
+    pub n_gpu_layers: u32,
+    /// The type of the K cache.
+    pub type_k: ggml_type,
+    /// The type of the V cache.
+    pub type_v: ggml_type,
+    /// Whether the KV cache of offloaded layers is kept on the GPU.
+    pub offload_kqv: bool,
+    /// Whether flash attention is used (which avoids materializing the attention scores).
+    pub flash_attn: bool,
+}
+
+/// The defaults of `llama_context_default_params` with no layers offloaded.
+impl Default for MemoryEstimateParams {
+    fn default() -> Self {
+        Self {
+            n_ctx: 512,
+            n_ubatch: 512,
+            n_gpu_layers: 0,
+            type_k: llama_cpp_sys_2::GGML_TYPE_F16,
+            type_v: llama_cpp_sys_2::GGML_TYPE_F16,
+            offload_kqv: true,
+            flash_attn: false,
+        }
+    }
+}
+
+/// The estimated memory use of a single device in bytes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DeviceMemory {
+    /// Model weights.
+    pub weights: u64,
+    /// KV cache.
+    pub kv_cache: u64,
+    /// Compute buffers (approximate).
+    pub compute: u64,
+}
+
+impl DeviceMemory {
+    /// The sum of weights, KV cache and compute buffers.
+    #[must_use]
+    pub fn total(&self) -> u64 {
+        self.weights + self.kv_cache + self.compute
+    }
+}
+
+/// The estimated memory use of a model on the CPU and a single GPU.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MemoryEstimate {
+    /// Memory in host RAM.
+    pub cpu: DeviceMemory,
+    /// Memory on the GPU.
+    pub gpu: DeviceMemory,
+}
+
+impl MemoryEstimate {
+    /// The memory used across all devices.
+    #[must_use]
+    pub fn total(&self) -> u64 {
+        self.cpu.total() + self.gpu.total()
+    }
+}
+
+/// The estimated memory use of a model on the CPU and each of the GPUs it is split across, see
+/// [`estimate_memory_on`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DeviceMemoryEstimate {
+    /// Memory in host RAM.
+    pub cpu: DeviceMemory,
+    /// Memory on each GPU, in the order of the devices the estimate was made for.
+    pub gpus: Vec<DeviceMemory>,
+}
+
+impl DeviceMemoryEstimate {
+    /// The memory used across all devices.
+    #[must_use]
+    pub fn total(&self) -> u64 {
+        self.cpu.total() + self.gpus.iter().map(DeviceMemory::total).sum::<u64>()
+    }
+
+    /// Whether the memory of every GPU fits into the free memory of its device minus
+    /// `safety_margin`. Host RAM is not checked.
+    #[must_use]
+    pub fn fits(&self, devices: &[GpuDevice], safety_margin: u64) -> bool {
+        self.gpus.len() <= devices.len()
+            && self
+                .gpus
+                .iter()
+                .zip(devices)
+                .all(|(gpu, device)| gpu.total() <= device.free.saturating_sub(safety_margin))
+    }
+
+    /// The memory of the GPU `device`, or of the CPU if `None`.
+    fn device_mut(&mut self, device: Option<usize>) -> &mut DeviceMemory {
+        match device {
+            Some(gpu) => &mut self.gpus[gpu],
+            None => &mut self.cpu,
+        }
+    }
+}
+
+/// A GPU ggml can offload to and its memory in bytes, see [`gpu_devices`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GpuDevice {
+    /// The name of the device as the backend reports it.
+    pub description: String,
+    /// The memory that is free on the device.
+    pub free: u64,
+    /// The memory of the device.
+    pub total: u64,
+}
+
+/// The GPUs of the backend ggml was built with, in the order llama.cpp numbers them (as
+/// [`LlamaModelParams::with_main_gpu`](crate::model::params::LlamaModelParams::with_main_gpu)
+/// takes them).
+///
+/// CUDA and Vulkan report the free memory of each device. Metal reports its recommended working
+/// set minus what the process has allocated, see [`metal::device_memory`](crate::metal). Empty if
+/// ggml was built without a GPU backend.
+#[must_use]
+pub fn gpu_devices() -> Vec<GpuDevice> {
+    #[allow(unused_mut)]
+    let mut devices = Vec::new();
+    #[cfg(feature = "cuda")]
+    devices.extend(backend_devices(
+        unsafe { llama_cpp_sys_2::ggml_backend_cuda_get_device_count() },
+        |device, description, size| unsafe {
+            llama_cpp_sys_2::ggml_backend_cuda_get_device_description(device, description, size);
+        },
+        |device, free, total| unsafe {
+            llama_cpp_sys_2::ggml_backend_cuda_get_device_memory(device, free, total);
+        },
+    ));
+    #[cfg(feature = "vulkan")]
+    devices.extend(backend_devices(
+        unsafe { llama_cpp_sys_2::ggml_backend_vk_get_device_count() },
+        |device, description, size| unsafe {
+            llama_cpp_sys_2::ggml_backend_vk_get_device_description(device, description, size);
+        },
+        |device, free, total| unsafe {
+            llama_cpp_sys_2::ggml_backend_vk_get_device_memory(device, free, total);
+        },
+    ));
+    #[cfg(all(feature = "metal", any(target_os = "macos", target_os = "ios")))]
+    devices.extend(crate::metal::device_memory().map(|memory| GpuDevice {
+        description: "Metal".to_string(),
+        free: memory.available(),
+        total: memory.recommended_max_working_set_size,
+    }));
+    devices
+}
+
+/// The `count` devices of a ggml backend, queried with its `describe` and `memory` functions.
+#[cfg(any(feature = "cuda", feature = "vulkan"))]
+fn backend_devices(
+    count: c_int,
+    describe: impl Fn(c_int, *mut c_char, usize),
+    memory: impl Fn(c_int, *mut usize, *mut usize),
+) -> impl Iterator<Item = GpuDevice> {
+    (0..count).map(move |device| {
+        let mut description = [0; 256];
+        describe(device, description.as_mut_ptr(), description.len());
+        let description = unsafe { CStr::from_ptr(description.as_ptr()) }
+            .to_string_lossy()
+            .into_owned();
+        let (mut free, mut total) = (0, 0);
+        memory(device, &mut free, &mut total);
+        GpuDevice {
+            description,
+            free: free as u64,
+            total: total as u64,
+        }
+    })
+}
+
+/// The hyperparameters of a model needed for the estimate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Hparams {
+    pub(crate) n_layer: u64,
+    pub(crate) n_embd: u64,
+    pub(crate) n_ff: u64,
+    pub(crate) n_head: u64,
+    pub(crate) n_embd_k_gqa: u64,
+    pub(crate) n_embd_v_gqa: u64,
+    pub(crate) n_vocab: u64,
+}
+
+impl Hparams {
+    pub(crate) fn from_gguf(gguf: &GgufFile) -> Result<Self, MemoryEstimateError> {
+        let arch = gguf
+            .get("general.architecture")
+            .and_then(GgufValue::as_str)
+            .ok_or_else(|| MemoryEstimateError::MissingKey("general.architecture".to_string()))?;
+
+        let key = |name: &str| format!("{arch}.{name}");
+        let required = |name: &str| {
+            let key = key(name);
+            gguf.get(&key)
+                .and_then(max_u64)
+                .ok_or(MemoryEstimateError::MissingKey(key))
+        };
+        let optional = |name: &str| gguf.get(&key(name)).and_then(max_u64);
+
+        let n_layer = required("block_count")?;
+        let n_embd = required("embedding_length")?;
+        let n_ff = optional("feed_forward_length").unwrap_or(4 * n_embd);
+        let n_head = optional("attention.head_count").unwrap_or(0);
+        let n_head_kv = optional("attention.head_count_kv").unwrap_or(n_head);
+        let head_dim = n_embd.checked_div(n_head).unwrap_or(0);
+        let n_embd_head_k = optional("attention.key_length").unwrap_or(head_dim);
+        let n_embd_head_v = optional("attention.value_length").unwrap_or(head_dim);
+        let n_vocab = optional("vocab_size")
+            .or_else(|| {
+                gguf.get("tokenizer.ggml.tokens")
+                    .and_then(GgufValue::as_array)
+                    .map(|tokens| tokens.len() as u64)
+            })
+            .unwrap_or(0);
+
+        Ok(Self {
+            n_layer,
+            n_embd,
+            n_ff,
+            n_head,
+            n_embd_k_gqa: n_embd_head_k * n_head_kv,
+            n_embd_v_gqa: n_embd_head_v * n_head_kv,
+            n_vocab,
+        })
+    }
+}
+
+/// Some hyperparameters are stored per layer as arrays, use the largest value in that case.
+fn max_u64(value: &GgufValue) -> Option<u64> {
+    match value {
+        GgufValue::Array(values) => values.iter().filter_map(GgufValue::as_u64).max(),
+        value => value.as_u64(),
+    }
+}
+
+/// The size in bytes of `n` elements of `ggml_type`, or `None` if ggml does not know the type.
+pub(crate) fn type_size(ggml_type: ggml_type, n: u64) -> Option<u64> {
+    if ggml_type >= llama_cpp_sys_2::GGML_TYPE_COUNT {
+        return None;
+    }
+    // removed types (such as q4_2) have a block size of 0
+    let block_size = i64::from(unsafe { llama_cpp_sys_2::ggml_blck_size(ggml_type) });
+    let block_size = u64::try_from(block_size).ok().filter(|size| *size > 0)?;
+    let type_size = u64::try_from(unsafe { llama_cpp_sys_2::ggml_type_size(ggml_type) }).ok()?;
+    Some(type_size * n.div_ceil(block_size))
+}
+
+/// The layer a tensor belongs to, if it is part of a repeating layer (`blk.<n>.`).
+pub(crate) fn tensor_layer(name: &str) -> Option<u64> {
+    name.strip_prefix("blk.")?.split('.').next()?.parse().ok()
+}
+
+/// Whether a tensor belongs to the output layer.
+fn is_output_tensor(name: &str) -> bool {
+    name.starts_with("output.") || name.starts_with("output_norm.")
+}
+
+/// The split points of `split`: the cumulative shares of the GPUs, normalized to end at 1. An
+/// empty or all zero split puts everything on the first GPU.
+fn split_points(split: &[f32]) -> Vec<f32> {
+    let sum: f32 = split.iter().sum();
+    if sum <= 0.0 {
+        return vec![1.0; split.len().max(1)];
+    }
+    split
+        .iter()
+        .scan(0.0, |cumulative, share| {
+            *cumulative += share;
+            Some(*cumulative / sum)
+        })
+        .collect()
+}
+
+/// The GPU at the `fraction` of the offloaded layers, like `llm_load_tensors` picks it: the first
+/// whose split point is past `fraction`.
+fn gpu_at(points: &[f32], fraction: f32) -> usize {
+    points
+        .iter()
+        .position(|point| *point > fraction)
+        .unwrap_or(points.len() - 1)
+}
+
+/// Estimate the memory needed to load the model described by `gguf` with `params`.
+///
+/// # Errors
+///
+/// - if the architecture, block count or embedding length is missing from the metadata.
+/// - if a tensor has a type unknown to ggml.
+///
+/// ```no_run
+/// # use llama_cpp_2::gguf::GgufFile;
+/// # use llama_cpp_2::gguf::estimate::{estimate_memory, MemoryEstimateParams};
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// let gguf = GgufFile::open("path/to/model.gguf")?;
+/// let params = MemoryEstimateParams { n_ctx: 8192, n_gpu_layers: 99, ..Default::default() };
+/// let estimate = estimate_memory(&gguf, &params)?;
+/// if estimate.gpu.total() > 6 * 1024 * 1024 * 1024 {
+///     println!("this will not fit on the GPU");
+/// }
+/// # Ok(())
+/// # }
+/// ```
+pub fn estimate_memory(
+    gguf: &GgufFile,
+    params: &MemoryEstimateParams,
+) -> Result<MemoryEstimate, MemoryEstimateError> {
+    let estimate = estimate_memory_split(gguf, params, &[])?;
+    Ok(MemoryEstimate {
+        cpu: estimate.cpu,
+        gpu: estimate.gpus[0],
+    })
+}
+
+/// Estimate the memory needed to load the model described by `gguf` with `params` on
+/// `devices`, which llama.cpp splits the offloaded layers between in proportion to their free
+/// memory. The estimate has one [`DeviceMemory`] for each device, check it with
+/// [`DeviceMemoryEstimate::fits`].
+///
+/// # Errors
+///
+/// See [`estimate_memory`].
+pub fn estimate_memory_on(
+    gguf: &GgufFile,
+    params: &MemoryEstimateParams,
+    devices: &[GpuDevice],
+) -> Result<DeviceMemoryEstimate, MemoryEstimateError> {
+    if devices.is_empty() {
+        // without a GPU llama.cpp keeps every layer on the CPU
+        let params = MemoryEstimateParams {
+            n_gpu_layers: 0,
+            ..*params
+        };
+        let estimate = estimate_memory_split(gguf, &params, &[])?;
+        return Ok(DeviceMemoryEstimate {
+            cpu: estimate.cpu,
+            gpus: Vec::new(),
+        });
+    }
+    #[allow(clippy::cast_precision_loss)]
+    let split: Vec<f32> = devices.iter().map(|device| device.free as f32).collect();
+    estimate_memory_split(gguf, params, &split)
+}
+
+/// The estimate for the offloaded layers split between GPUs by the shares in `split`.
+fn estimate_memory_split(
+    gguf: &GgufFile,
+    params: &MemoryEstimateParams,
+    split: &[f32],
+) -> Result<DeviceMemoryEstimate, MemoryEstimateError> {
+    let hparams = Hparams::from_gguf(gguf)?;
+    let n_gpu_layers = u64::from(params.n_gpu_layers);
+    let i_gpu_start = hparams.n_layer.saturating_sub(n_gpu_layers);
+    let output_on_gpu = n_gpu_layers > hparams.n_layer;
+
+    // the device of each layer, `None` for the CPU, with the output layer last
+    let points = split_points(split);
+    let act_gpu_layers = n_gpu_layers.min(hparams.n_layer + 1);
+    #[allow(clippy::cast_precision_loss)]
+    let device_of = |layer: u64| {
+        (layer >= i_gpu_start && layer < i_gpu_start + act_gpu_layers).then(|| {
+            gpu_at(
+                &points,
+                (layer - i_gpu_start) as f32 / act_gpu_layers as f32,
+            )
+        })
+    };
+    let output_device = if output_on_gpu {
+        device_of(i_gpu_start + act_gpu_layers - 1)
+    } else {
+        None
+    };
+
+    let mut estimate = DeviceMemoryEstimate {
+        cpu: DeviceMemory::default(),
+        gpus: vec![DeviceMemory::default(); points.len()],
+    };
+    for tensor in gguf.tensors() {
+        let size = type_size(tensor.ggml_type, tensor.n_elements()).ok_or_else(|| {
+            MemoryEstimateError::UnknownTensorType {
+                name: tensor.name.clone(),
+                ggml_type: tensor.ggml_type,
+            }
+        })?;
+        let device = match tensor_layer(&tensor.name) {
+            Some(layer) if layer < hparams.n_layer => device_of(layer),
+            Some(_) => None,
+            None if is_output_tensor(&tensor.name) => output_device,
+            None => None,
+        };
+        estimate.device_mut(device).weights += size;
+    }
+
+    let n_ctx = u64::from(params.n_ctx);
+    let kv_per_layer = type_size(params.type_k, hparams.n_embd_k_gqa * n_ctx)
+        .zip(type_size(params.type_v, hparams.n_embd_v_gqa * n_ctx))
+        .map(|(k, v)| k + v)
+        .ok_or_else(|| MemoryEstimateError::UnknownTensorType {
+            name: "cache_k / cache_v".to_string(),
+            ggml_type: params.type_k,
+        })?;
+    for layer in 0..hparams.n_layer {
+        let device = device_of(layer).filter(|_| params.offload_kqv);
+        estimate.device_mut(device).kv_cache += kv_per_layer;
+    }
+
+    // The largest live tensors of a forward pass over one ubatch (in f32): the logits, the
+    // attention scores (unless flash attention fuses them away), and a few hidden state sized
+    // activations around the feed forward network.
+    let n_ubatch = u64::from(params.n_ubatch);
+    let logits = hparams.n_vocab * n_ubatch;
+    let attention = if params.flash_attn {
+        0
+    } else {
+        n_ctx * n_ubatch * hparams.n_head
+    };
+    let activations = n_ubatch * (4 * hparams.n_embd + 2 * hparams.n_ff);
+    // every GPU with layers runs them on its own buffers, the logits are computed with the
+    // output layer or after the last offloaded layer
+    let gpus: Vec<usize> = (0..hparams.n_layer).filter_map(device_of).collect();
+    match output_device.or(gpus.last().copied()) {
+        Some(logits_gpu) => {
+            for &gpu in &gpus {
+                estimate.gpus[gpu].compute = 4 * (attention + activations);
+            }
+            estimate.gpus[logits_gpu].compute += 4 * logits;
+            // inputs and outputs are staged through host memory
+            estimate.cpu.compute = 4 * (logits + n_ubatch * hparams.n_embd);
+        }
+        None => estimate.cpu.compute = 4 * (logits + attention + activations),
+    }
+
+    Ok(estimate)
+}
+
+/// The memory [`recommend_n_gpu_layers`] leaves free for other processes, the driver and
+/// allocations the estimate does not account for: 512 MiB.
+pub const DEFAULT_SAFETY_MARGIN: u64 = 512 * 1024 * 1024;
+
+/// The largest `n_gpu_layers` whose estimated memory [fits](DeviceMemoryEstimate::fits) on
+/// `devices` with `safety_margin` left free on each, usually the [`gpu_devices`]. A value above
+/// the block count of the model means every layer, including the output layer, is offloaded.
+/// `0` if there are no devices. All other fields of `params` are used as given.
+///
+/// The layers are split between the devices by their free memory, as [`estimate_memory_on`]
+/// estimates them.
+///
+/// # Errors
+///
+/// See [`estimate_memory`].
+///
+/// ```no_run
+/// # use llama_cpp_2::gguf::GgufFile;
+/// # use llama_cpp_2::gguf::estimate::{
+/// #     gpu_devices, recommend_n_gpu_layers, MemoryEstimateParams, DEFAULT_SAFETY_MARGIN,
+/// # };
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// let gguf = GgufFile::open("path/to/model.gguf")?;
+/// let params = MemoryEstimateParams { n_ctx: 8192, ..Default::default() };
+/// let n_gpu_layers = recommend_n_gpu_layers(&gguf, &params, &gpu_devices(), DEFAULT_SAFETY_MARGIN)?;
+/// println!("offloading {n_gpu_layers} layers");
+/// # Ok(())
+/// # }
+/// ```
+pub fn recommend_n_gpu_layers(
+    gguf: &GgufFile,
+    params: &MemoryEstimateParams,
+    devices: &[GpuDevice],
+    safety_margin: u64,
+) -> Result<u32, MemoryEstimateError> {
+    if devices.is_empty() {
+        return Ok(0);
+    }
+    let n_layer = Hparams::from_gguf(gguf)?.n_layer;
+    // the split moves layers between devices as more are offloaded, so the memory of a single
+    // device does not grow with every layer: try them all, starting with the most
+    for n_gpu_layers in (1..=u32::try_from(n_layer + 1).unwrap_or(u32::MAX)).rev() {
+        let params = MemoryEstimateParams {
+            n_gpu_layers,
+            ..*params
+        };
+        if estimate_memory_on(gguf, &params, devices)?.fits(devices, safety_margin) {
+            return Ok(n_gpu_layers);
+        }
+    }
+    Ok(0)
+}
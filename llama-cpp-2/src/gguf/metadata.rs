@@ -0,0 +1,171 @@
+//! Typed accessors for well-known metadata keys of GGUF files.
+//!
+//! Hyperparameters are stored under keys prefixed with the architecture (`llama.context_length`,
+//! `qwen2.rope.freq_base`, ...) and in whatever integer or float width the converter chose. The
+//! accessors on [`GgufFile`] look up the right key and convert the value, so callers don't have
+//! to:
+//!
+//! ```
+//! # use llama_cpp_2::gguf::writer::GgufWriter;
+//! # use llama_cpp_2::gguf::metadata::TokenizerModel;
+//! # use llama_cpp_2::gguf::{GgufFile, GgufValue};
+//! # use llama_cpp_2::context::params::RopeScalingType;
+//! let bytes = GgufWriter::new()
+//!     .with_metadata("general.architecture", GgufValue::String("qwen2".to_string()))
+//!     .with_metadata("qwen2.context_length", GgufValue::U32(32768))
+//!     .with_metadata("qwen2.rope.freq_base", GgufValue::F32(1_000_000.0))
+//!     .with_metadata("tokenizer.ggml.model", GgufValue::String("gpt2".to_string()))
+//!     .to_bytes();
+//! let gguf = GgufFile::read(bytes.as_slice())?;
+//! assert_eq!(gguf.architecture(), Some("qwen2"));
+//! assert_eq!(gguf.context_length(), Some(32768));
+//! assert_eq!(gguf.tokenizer_model(), Some(TokenizerModel::Gpt2));
+//! let rope = gguf.rope().unwrap();
+//! assert_eq!(rope.freq_base, Some(1_000_000.0));
+//! assert_eq!(rope.scaling_type, RopeScalingType::None);
+//! # Ok::<(), llama_cpp_2::gguf::GgufError>(())
+//! ```
+
+use crate::context::params::RopeScalingType;
+use crate::model::VocabType;
+
+use super::{GgufFile, GgufValue};
+
+/// The tokenizer of a model, from `tokenizer.ggml.model`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum TokenizerModel {
+    /// `llama`: a `SentencePiece` BPE tokenizer.
+    Llama,
+    /// `gpt2`: a byte level BPE tokenizer.
+    Gpt2,
+    /// `bert`: a `WordPiece` tokenizer, used by BERT style embedding models.
+    Bert,
+    /// `t5`: a unigram tokenizer.
+    T5,
+    /// `rwkv`: the greedy tokenizer of RWKV models.
+    Rwkv,
+    /// `no_vocab`: the model has no vocabulary.
+    NoVocab,
+    /// A tokenizer this version does not know.
+    Other(String),
+}
+
+impl TokenizerModel {
+    /// The tokenizer called `name` in `tokenizer.ggml.model`.
+    #[must_use]
+    pub fn from_name(name: &str) -> Self {
+        match name {
+            "llama" => Self::Llama,
+            "gpt2" => Self::Gpt2,
+            "bert" => Self::Bert,
+            "t5" => Self::T5,
+            "rwkv" => Self::Rwkv,
+            "no_vocab" => Self::NoVocab,
+            other => Self::Other(other.to_string()),
+        }
+    }
+
+    /// The name stored in `tokenizer.ggml.model`.
+    #[must_use]
+    pub fn name(&self) -> &str {
+        match self {
+            Self::Llama => "llama",
+            Self::Gpt2 => "gpt2",
+            Self::Bert => "bert",
+            Self::T5 => "t5",
+            Self::Rwkv => "rwkv",
+            Self::NoVocab => "no_vocab",
+            Self::Other(name) => name,
+        }
+    }
+
+    /// The [`VocabType`] llama.cpp loads the tokenizer as, if it is one of them.
+    #[must_use]
+    pub fn vocab_type(&self) -> Option<VocabType> {
+        match self {
+            Self::Llama => Some(VocabType::SPM),
+            Self::Gpt2 => Some(VocabType::BPE),
+            Self::Bert => Some(VocabType::WPM),
+            Self::T5 => Some(VocabType::UGM),
+            Self::Rwkv | Self::NoVocab | Self::Other(_) => None,
+        }
+    }
+}
+
+/// The rotary position embedding parameters of a model, from the `<arch>.rope.*` keys.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RopeParams {
+    /// The number of dimensions of each head that are rotated, `rope.dimension_count`.
+    pub dimension_count: Option<u64>,
+    /// The base frequency, `rope.freq_base`.
+    pub freq_base: Option<f64>,
+    /// The scaling the model was trained with, `rope.scaling.type`. [`RopeScalingType::None`]
+    /// if it is not set, [`RopeScalingType::Unspecified`] if it is unknown.
+    pub scaling_type: RopeScalingType,
+    /// The scaling factor, `rope.scaling.factor` or the older `rope.scale_linear`.
+    pub scaling_factor: Option<f64>,
+    /// The context length before scaling, `rope.scaling.original_context_length`.
+    pub original_context_length: Option<u64>,
+}
+
+impl GgufFile {
+    /// The architecture, `general.architecture`, which prefixes the keys of the hyperparameters.
+    #[must_use]
+    pub fn architecture(&self) -> Option<&str> {
+        self.get("general.architecture")?.as_str()
+    }
+
+    /// The version of the quantization format, `general.quantization_version`.
+    #[must_use]
+    pub fn quantization_version(&self) -> Option<u32> {
+        u32::try_from(self.get("general.quantization_version")?.as_u64()?).ok()
+    }
+
+    /// The tokenizer, `tokenizer.ggml.model`.
+    #[must_use]
+    pub fn tokenizer_model(&self) -> Option<TokenizerModel> {
+        let name = self.get("tokenizer.ggml.model")?.as_str()?;
+        Some(TokenizerModel::from_name(name))
+    }
+
+    /// The context length the model was trained with, `<arch>.context_length`.
+    #[must_use]
+    pub fn context_length(&self) -> Option<u64> {
+        self.hparam("context_length")?.as_u64()
+    }
+
+    /// The size of the embeddings, `<arch>.embedding_length`.
+    #[must_use]
+    pub fn embedding_length(&self) -> Option<u64> {
+        self.hparam("embedding_length")?.as_u64()
+    }
+
+    /// The default chat template, `tokenizer.chat_template`.
+    #[must_use]
+    pub fn chat_template(&self) -> Option<&str> {
+        self.get("tokenizer.chat_template")?.as_str()
+    }
+
+    /// The rotary position embedding parameters. Returns `None` if the architecture is not set.
+    #[must_use]
+    pub fn rope(&self) -> Option<RopeParams> {
+        self.architecture()?;
+        let u64_of = |name| self.hparam(name).and_then(GgufValue::as_u64);
+        let f64_of = |name| self.hparam(name).and_then(GgufValue::as_f64);
+        Some(RopeParams {
+            dimension_count: u64_of("rope.dimension_count"),
+            freq_base: f64_of("rope.freq_base"),
+            scaling_type: RopeScalingType::from_name(
+                self.hparam("rope.scaling.type").and_then(GgufValue::as_str),
+            ),
+            scaling_factor: f64_of("rope.scaling.factor").or_else(|| f64_of("rope.scale_linear")),
+            original_context_length: u64_of("rope.scaling.original_context_length"),
+        })
+    }
+
+    /// The value of the hyperparameter `<arch>.<name>`.
+    fn hparam(&self, name: &str) -> Option<&GgufValue> {
+        let arch = self.architecture()?;
+        self.get(&format!("{arch}.{name}"))
+    }
+}
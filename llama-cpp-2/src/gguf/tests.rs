@@ -0,0 +1,467 @@
+use super::*;
+
+fn push_string(buf: &mut Vec<u8>, s: &str) {
+    buf.extend((s.len() as u64).to_le_bytes());
+    buf.extend(s.as_bytes());
+}
+
+/// A GGUF v3 header with a few metadata values and two tensors.
+fn sample_gguf(alignment: Option<u32>) -> Vec<u8> {
+    let mut buf = Vec::new();
+    buf.extend(GGUF_MAGIC);
+    buf.extend(3_u32.to_le_bytes());
+    // n_tensors
+    buf.extend(2_u64.to_le_bytes());
+    // n_kv
+    buf.extend((4_u64 + u64::from(alignment.is_some())).to_le_bytes());
+
+    push_string(&mut buf, "general.architecture");
+    buf.extend(8_u32.to_le_bytes());
+    push_string(&mut buf, "llama");
+
+    push_string(&mut buf, "llama.context_length");
+    buf.extend(4_u32.to_le_bytes());
+    buf.extend(4096_u32.to_le_bytes());
+
+    push_string(&mut buf, "llama.rope.freq_base");
+    buf.extend(6_u32.to_le_bytes());
+    buf.extend(10000.0_f32.to_le_bytes());
+
+    push_string(&mut buf, "tokenizer.ggml.tokens");
+    buf.extend(9_u32.to_le_bytes());
+    buf.extend(8_u32.to_le_bytes());
+    buf.extend(3_u64.to_le_bytes());
+    for token in ["<s>", "</s>", "hello"] {
+        push_string(&mut buf, token);
+    }
+
+    if let Some(alignment) = alignment {
+        push_string(&mut buf, "general.alignment");
+        buf.extend(4_u32.to_le_bytes());
+        buf.extend(alignment.to_le_bytes());
+    }
+
+    push_string(&mut buf, "token_embd.weight");
+    buf.extend(2_u32.to_le_bytes());
+    buf.extend(8_u64.to_le_bytes());
+    buf.extend(3_u64.to_le_bytes());
+    // GGML_TYPE_F32
+    buf.extend(0_u32.to_le_bytes());
+    buf.extend(0_u64.to_le_bytes());
+
+    push_string(&mut buf, "output_norm.weight");
+    buf.extend(1_u32.to_le_bytes());
+    buf.extend(8_u64.to_le_bytes());
+    // GGML_TYPE_F16
+    buf.extend(1_u32.to_le_bytes());
+    buf.extend(96_u64.to_le_bytes());
+
+    buf
+}
+
+#[test]
+fn reads_metadata_and_tensors() {
+    let bytes = sample_gguf(None);
+    let gguf = GgufFile::read(bytes.as_slice()).unwrap();
+
+    assert_eq!(gguf.version(), 3);
+    assert_eq!(gguf.alignment(), GGUF_DEFAULT_ALIGNMENT);
+    assert_eq!(gguf.data_offset() % GGUF_DEFAULT_ALIGNMENT, 0);
+    assert!(gguf.data_offset() >= bytes.len() as u64);
+
+    assert_eq!(gguf.metadata().len(), 4);
+    assert_eq!(
+        gguf.get("general.architecture").and_then(GgufValue::as_str),
+        Some("llama")
+    );
+    assert_eq!(
+        gguf.get("llama.context_length").and_then(GgufValue::as_u64),
+        Some(4096)
+    );
+    assert_eq!(
+        gguf.get("llama.rope.freq_base").and_then(GgufValue::as_f64),
+        Some(10000.0)
+    );
+    let tokens = gguf
+        .get("tokenizer.ggml.tokens")
+        .and_then(GgufValue::as_array)
+        .unwrap();
+    assert_eq!(tokens.len(), 3);
+    assert_eq!(tokens[2].as_str(), Some("hello"));
+
+    assert_eq!(gguf.tensors().len(), 2);
+    let embd = gguf.tensor("token_embd.weight").unwrap();
+    assert_eq!(embd.dimensions, vec![8, 3]);
+    assert_eq!(embd.n_elements(), 24);
+    assert_eq!(embd.ggml_type, 0);
+    let norm = gguf.tensor("output_norm.weight").unwrap();
+    assert_eq!(norm.offset, 96);
+}
+
+#[test]
+fn respects_alignment() {
+    let bytes = sample_gguf(Some(64));
+    let gguf = GgufFile::read(bytes.as_slice()).unwrap();
+    assert_eq!(gguf.alignment(), 64);
+    assert_eq!(gguf.data_offset() % 64, 0);
+}
+
+#[test]
+fn rejects_invalid_magic() {
+    let mut bytes = sample_gguf(None);
+    bytes[0] = b'X';
+    assert!(matches!(
+        GgufFile::read(bytes.as_slice()),
+        Err(GgufError::InvalidMagic(_))
+    ));
+}
+
+#[test]
+fn rejects_truncated_file() {
+    let bytes = sample_gguf(None);
+    let truncated = &bytes[..bytes.len() - 4];
+    assert!(matches!(
+        GgufFile::read(truncated),
+        Err(GgufError::Io(err)) if err.kind() == std::io::ErrorKind::UnexpectedEof
+    ));
+}
+
+/// A header with one metadata value `key` of `value_type`, stored as `value`.
+fn single_value_gguf(value_type: u32, value: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::new();
+    buf.extend(GGUF_MAGIC);
+    buf.extend(3_u32.to_le_bytes());
+    buf.extend(0_u64.to_le_bytes());
+    buf.extend(1_u64.to_le_bytes());
+    push_string(&mut buf, "key");
+    buf.extend(value_type.to_le_bytes());
+    buf.extend(value);
+    buf
+}
+
+#[test]
+fn rejects_deeply_nested_arrays() {
+    let mut nested = Vec::new();
+    for _ in 0..=MAX_ARRAY_DEPTH {
+        // an array holding one array
+        nested.extend(9_u32.to_le_bytes());
+        nested.extend(1_u64.to_le_bytes());
+    }
+    let bytes = single_value_gguf(9, &nested);
+    assert!(matches!(
+        GgufFile::read(bytes.as_slice()),
+        Err(GgufError::ArrayTooDeep)
+    ));
+}
+
+#[test]
+fn reads_arrays_up_to_the_depth_limit() {
+    let mut nested = Vec::new();
+    for _ in 1..MAX_ARRAY_DEPTH {
+        nested.extend(9_u32.to_le_bytes());
+        nested.extend(1_u64.to_le_bytes());
+    }
+    // the innermost array holds no elements
+    nested.extend(4_u32.to_le_bytes());
+    nested.extend(0_u64.to_le_bytes());
+    let bytes = single_value_gguf(9, &nested);
+
+    let gguf = GgufFile::read(bytes.as_slice()).unwrap();
+    let mut value = gguf.get("key").unwrap();
+    let mut depth = 0;
+    while let GgufValue::Array(values) = value {
+        depth += 1;
+        match values.first() {
+            Some(inner) => value = inner,
+            None => break,
+        }
+    }
+    assert_eq!(depth, MAX_ARRAY_DEPTH);
+}
+
+#[test]
+fn rejects_arrays_larger_than_the_file() {
+    let mut array = Vec::new();
+    // u32 elements
+    array.extend(4_u32.to_le_bytes());
+    array.extend(1000_u64.to_le_bytes());
+    array.extend([0; 16]);
+    let bytes = single_value_gguf(9, &array);
+    assert!(matches!(
+        GgufFile::read_sized(bytes.as_slice(), bytes.len() as u64),
+        Err(GgufError::ArrayTooLarge {
+            n: 1000,
+            element_size: 4,
+            remaining: 16,
+        })
+    ));
+    // without a known length the array is read until the input ends
+    assert!(matches!(
+        GgufFile::read(bytes.as_slice()),
+        Err(GgufError::Io(err)) if err.kind() == std::io::ErrorKind::UnexpectedEof
+    ));
+}
+
+#[test]
+fn rejects_array_sizes_that_overflow() {
+    let mut array = Vec::new();
+    array.extend(10_u32.to_le_bytes());
+    array.extend(u64::MAX.to_le_bytes());
+    let bytes = single_value_gguf(9, &array);
+    assert!(matches!(
+        GgufFile::read_sized(bytes.as_slice(), bytes.len() as u64),
+        Err(GgufError::ArrayTooLarge { n: u64::MAX, .. })
+    ));
+}
+
+#[test]
+fn reads_arrays_that_fill_the_file() {
+    let bytes = sample_gguf(None);
+    assert_eq!(
+        GgufFile::read_sized(bytes.as_slice(), bytes.len() as u64).unwrap(),
+        GgufFile::read(bytes.as_slice()).unwrap()
+    );
+}
+
+#[test]
+fn abbreviates_long_arrays() {
+    let value = GgufValue::Array((0..10).map(GgufValue::I32).collect());
+    assert_eq!(
+        value.to_string(),
+        "[0, 1, 2, 3, 4, 5, 6, 7, ... (10 items)]"
+    );
+}
+
+#[test]
+fn estimate_requires_block_count() {
+    let bytes = sample_gguf(None);
+    let gguf = GgufFile::read(bytes.as_slice()).unwrap();
+    assert_eq!(
+        estimate::estimate_memory(&gguf, &estimate::MemoryEstimateParams::default()),
+        Err(estimate::MemoryEstimateError::MissingKey(
+            "llama.block_count".to_string()
+        ))
+    );
+}
+
+/// A two layer model with an 8x8 f32 tensor per layer and an output layer.
+fn layered_gguf() -> GgufFile {
+    let mut buf = Vec::new();
+    buf.extend(GGUF_MAGIC);
+    buf.extend(3_u32.to_le_bytes());
+    // n_tensors
+    buf.extend(3_u64.to_le_bytes());
+    // n_kv
+    buf.extend(3_u64.to_le_bytes());
+
+    push_string(&mut buf, "general.architecture");
+    buf.extend(8_u32.to_le_bytes());
+    push_string(&mut buf, "llama");
+
+    push_string(&mut buf, "llama.block_count");
+    buf.extend(4_u32.to_le_bytes());
+    buf.extend(2_u32.to_le_bytes());
+
+    push_string(&mut buf, "llama.embedding_length");
+    buf.extend(4_u32.to_le_bytes());
+    buf.extend(8_u32.to_le_bytes());
+
+    for (i, name) in [
+        "blk.0.attn_q.weight",
+        "blk.1.attn_q.weight",
+        "output.weight",
+    ]
+    .into_iter()
+    .enumerate()
+    {
+        push_string(&mut buf, name);
+        buf.extend(2_u32.to_le_bytes());
+        buf.extend(8_u64.to_le_bytes());
+        buf.extend(8_u64.to_le_bytes());
+        // GGML_TYPE_F32
+        buf.extend(0_u32.to_le_bytes());
+        buf.extend((i as u64 * 256).to_le_bytes());
+    }
+
+    GgufFile::read(buf.as_slice()).unwrap()
+}
+
+fn gpu(free: u64) -> estimate::GpuDevice {
+    estimate::GpuDevice {
+        description: "test".to_string(),
+        free,
+        total: free,
+    }
+}
+
+#[test]
+fn recommends_layers_that_fit() {
+    let gguf = layered_gguf();
+    let params = estimate::MemoryEstimateParams::default();
+    let gpu_total = |n_gpu_layers| {
+        let params = estimate::MemoryEstimateParams {
+            n_gpu_layers,
+            ..params
+        };
+        estimate::estimate_memory(&gguf, &params)
+            .unwrap()
+            .gpu
+            .total()
+    };
+    let recommend = |free_memory, safety_margin| {
+        estimate::recommend_n_gpu_layers(&gguf, &params, &[gpu(free_memory)], safety_margin)
+            .unwrap()
+    };
+
+    assert_eq!(
+        estimate::recommend_n_gpu_layers(&gguf, &params, &[], 0).unwrap(),
+        0
+    );
+    assert_eq!(recommend(0, 0), 0);
+    assert_eq!(recommend(gpu_total(1), 0), 1);
+    assert_eq!(recommend(gpu_total(2) + 100, 100), 2);
+    assert_eq!(recommend(gpu_total(2) + 100, 101), 1);
+    assert_eq!(recommend(u64::MAX, 0), 3);
+}
+
+#[test]
+fn splits_layers_by_free_memory() {
+    let gguf = layered_gguf();
+    let params = estimate::MemoryEstimateParams {
+        n_gpu_layers: 3,
+        ..Default::default()
+    };
+    let single = estimate::estimate_memory(&gguf, &params).unwrap();
+    let one = estimate::estimate_memory_on(&gguf, &params, &[gpu(1 << 30)]).unwrap();
+    assert_eq!(
+        (one.cpu, one.gpus.as_slice()),
+        (single.cpu, &[single.gpu][..])
+    );
+
+    // two equal GPUs share the two layers and the output layer: llama.cpp splits the three
+    // offloaded layers at 1.5, so both repeating layers go to the first
+    let devices = [gpu(1 << 30), gpu(1 << 30)];
+    let two = estimate::estimate_memory_on(&gguf, &params, &devices).unwrap();
+    assert_eq!(two.gpus.len(), 2);
+    assert_eq!(two.gpus[0].weights, 512);
+    assert_eq!(two.gpus[1].weights, 256);
+    assert!(two.gpus[0].compute > two.gpus[1].compute);
+    assert_eq!(two.cpu.weights, 0);
+    assert_eq!(two.total(), single.total());
+    assert!(two.fits(&devices, 0));
+
+    // a GPU without free memory gets no layers, and nothing fits on it
+    let devices = [gpu(0), gpu(1 << 30)];
+    let skewed = estimate::estimate_memory_on(&gguf, &params, &devices).unwrap();
+    assert_eq!(skewed.gpus[0], estimate::DeviceMemory::default());
+    assert_eq!(skewed.gpus[1].weights, 768);
+    assert!(skewed.fits(&devices, 0));
+    assert!(!skewed.fits(&devices[..1], 0));
+    assert!(!skewed.fits(&[gpu(0), gpu(skewed.gpus[1].total() - 1)], 0));
+
+    // without devices everything stays on the CPU
+    let none = estimate::estimate_memory_on(&gguf, &params, &[]).unwrap();
+    assert!(none.gpus.is_empty());
+    assert_eq!(none.cpu.weights, 768);
+}
+
+#[test]
+fn writer_round_trips() {
+    let bytes = writer::GgufWriter::new()
+        .with_metadata(
+            "general.architecture",
+            GgufValue::String("llama".to_string()),
+        )
+        .with_metadata("llama.block_count", GgufValue::U32(1))
+        .with_metadata(
+            "tokenizer.ggml.scores",
+            GgufValue::Array(vec![GgufValue::F32(0.5), GgufValue::F32(-1.0)]),
+        )
+        .with_metadata("empty", GgufValue::Array(Vec::new()))
+        .with_f32_tensor("a", vec![3], vec![1.0, 2.0, 3.0])
+        .with_f32_tensor("b", vec![2, 2], vec![4.0; 4])
+        .to_bytes();
+    let gguf = GgufFile::read(bytes.as_slice()).unwrap();
+
+    assert_eq!(gguf.version(), 3);
+    assert_eq!(gguf.metadata().len(), 4);
+    assert_eq!(
+        gguf.get("tokenizer.ggml.scores"),
+        Some(&GgufValue::Array(vec![
+            GgufValue::F32(0.5),
+            GgufValue::F32(-1.0)
+        ]))
+    );
+    assert_eq!(gguf.get("empty"), Some(&GgufValue::Array(Vec::new())));
+
+    let b = gguf.tensor("b").unwrap();
+    assert_eq!(b.dimensions, vec![2, 2]);
+    assert_eq!(b.offset, GGUF_DEFAULT_ALIGNMENT);
+    let start = usize::try_from(gguf.data_offset() + b.offset).unwrap();
+    assert_eq!(bytes[start..start + 4], 4.0_f32.to_le_bytes());
+    assert_eq!(bytes.len(), start + 16);
+}
+
+#[test]
+fn reads_well_known_metadata() {
+    let string = |s: &str| GgufValue::String(s.to_string());
+    let bytes = writer::GgufWriter::new()
+        .with_metadata("general.architecture", string("llama"))
+        .with_metadata("general.quantization_version", GgufValue::U32(2))
+        .with_metadata("llama.embedding_length", GgufValue::U64(4096))
+        .with_metadata("llama.rope.dimension_count", GgufValue::U32(128))
+        .with_metadata("llama.rope.scaling.type", string("yarn"))
+        .with_metadata("llama.rope.scale_linear", GgufValue::F32(4.0))
+        .with_metadata("tokenizer.ggml.model", string("llama"))
+        .with_metadata("tokenizer.chat_template", string("{{ messages }}"))
+        .to_bytes();
+    let gguf = GgufFile::read(bytes.as_slice()).unwrap();
+
+    assert_eq!(gguf.architecture(), Some("llama"));
+    assert_eq!(gguf.quantization_version(), Some(2));
+    assert_eq!(gguf.embedding_length(), Some(4096));
+    assert_eq!(gguf.context_length(), None);
+    assert_eq!(gguf.chat_template(), Some("{{ messages }}"));
+    let tokenizer = gguf.tokenizer_model().unwrap();
+    assert_eq!(tokenizer, metadata::TokenizerModel::Llama);
+    assert_eq!(tokenizer.vocab_type(), Some(crate::model::VocabType::SPM));
+    assert_eq!(
+        gguf.rope(),
+        Some(metadata::RopeParams {
+            dimension_count: Some(128),
+            freq_base: None,
+            scaling_type: crate::context::params::RopeScalingType::Yarn,
+            scaling_factor: Some(4.0),
+            original_context_length: None,
+        })
+    );
+}
+
+#[test]
+fn reads_longrope_as_unscaled_rope() {
+    let string = |s: &str| GgufValue::String(s.to_string());
+    let bytes = writer::GgufWriter::new()
+        .with_metadata("general.architecture", string("phi3"))
+        .with_metadata("phi3.context_length", GgufValue::U32(131_072))
+        .with_metadata("phi3.rope.scaling.type", string("longrope"))
+        .with_metadata(
+            "phi3.rope.scaling.original_context_length",
+            GgufValue::U32(4096),
+        )
+        .to_bytes();
+    let gguf = GgufFile::read(bytes.as_slice()).unwrap();
+
+    let rope = gguf.rope().unwrap();
+    assert_eq!(
+        rope.scaling_type,
+        crate::context::params::RopeScalingType::None
+    );
+    assert_eq!(rope.original_context_length, Some(4096));
+}
+
+#[test]
+fn round_trips_tokenizer_names() {
+    for name in ["llama", "gpt2", "bert", "t5", "rwkv", "no_vocab", "plamo2"] {
+        assert_eq!(metadata::TokenizerModel::from_name(name).name(), name);
+    }
+}
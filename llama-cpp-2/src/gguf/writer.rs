@@ -0,0 +1,203 @@
+//! A writer for GGUF files with `f32` tensors.
+//!
+//! This writes the same format [`GgufFile`](super::GgufFile) reads, which is enough to generate
+//! small models and vocabularies for tests (see `test_utils` with the `test-utils` feature) or
+//! to store custom tensors next to a model.
+//!
+//! ```
+//! # use llama_cpp_2::gguf::writer::GgufWriter;
+//! # use llama_cpp_2::gguf::{GgufFile, GgufValue};
+//! let bytes = GgufWriter::new()
+//!     .with_metadata("general.architecture", GgufValue::String("llama".to_string()))
+//!     .with_f32_tensor("output_norm.weight", vec![4], vec![1.0; 4])
+//!     .to_bytes();
+//! let gguf = GgufFile::read(bytes.as_slice()).unwrap();
+//! assert_eq!(gguf.tensor("output_norm.weight").unwrap().n_elements(), 4);
+//! ```
+
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use super::{GgufValue, GGUF_DEFAULT_ALIGNMENT, GGUF_MAGIC};
+
+/// Builds a GGUF v3 file from metadata and `f32` tensors.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct GgufWriter {
+    metadata: Vec<(String, GgufValue)>,
+    tensors: Vec<(String, Vec<u64>, Vec<f32>)>,
+}
+
+impl GgufWriter {
+    /// A writer without metadata or tensors.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a metadata value. Keys are written in the order they are added.
+    #[must_use]
+    pub fn with_metadata(mut self, key: impl Into<String>, value: GgufValue) -> Self {
+        self.metadata.push((key.into(), value));
+        self
+    }
+
+    /// Add an `f32` tensor with the given `dimensions`, innermost first (ggml's `ne`).
+    ///
+    /// # Panics
+    ///
+    /// If `data` does not have as many elements as the dimensions describe.
+    #[must_use]
+    pub fn with_f32_tensor(
+        mut self,
+        name: impl Into<String>,
+        dimensions: Vec<u64>,
+        data: Vec<f32>,
+    ) -> Self {
+        assert_eq!(
+            dimensions.iter().product::<u64>(),
+            data.len() as u64,
+            "tensor data does not match its dimensions"
+        );
+        self.tensors.push((name.into(), dimensions, data));
+        self
+    }
+
+    /// Write the file to `writer`.
+    ///
+    /// # Errors
+    ///
+    /// If writing fails.
+    ///
+    /// # Panics
+    ///
+    /// If a tensor has more than [`u32::MAX`] dimensions.
+    pub fn write(&self, writer: impl Write) -> std::io::Result<()> {
+        let mut writer = CountingWriter {
+            inner: writer,
+            position: 0,
+        };
+
+        writer.put(&GGUF_MAGIC)?;
+        writer.put(&3_u32.to_le_bytes())?;
+        writer.put(&(self.tensors.len() as u64).to_le_bytes())?;
+        writer.put(&(self.metadata.len() as u64).to_le_bytes())?;
+        for (key, value) in &self.metadata {
+            writer.put_string(key)?;
+            writer.put(&value_type(value).to_le_bytes())?;
+            writer.put_value(value)?;
+        }
+
+        let mut offset = 0_u64;
+        for (name, dimensions, data) in &self.tensors {
+            let n_dims = u32::try_from(dimensions.len()).expect("n_dims fits into a u32");
+            writer.put_string(name)?;
+            writer.put(&n_dims.to_le_bytes())?;
+            for dimension in dimensions {
+                writer.put(&dimension.to_le_bytes())?;
+            }
+            // GGML_TYPE_F32
+            writer.put(&0_u32.to_le_bytes())?;
+            writer.put(&offset.to_le_bytes())?;
+            offset = align(offset + 4 * data.len() as u64);
+        }
+
+        for (_, _, data) in &self.tensors {
+            let padding = align(writer.position) - writer.position;
+            writer.put(&vec![
+                0;
+                usize::try_from(padding)
+                    .expect("padding fits into a usize")
+            ])?;
+            for value in data {
+                writer.put(&value.to_le_bytes())?;
+            }
+        }
+        writer.inner.flush()
+    }
+
+    /// Write the file to `path`.
+    ///
+    /// # Errors
+    ///
+    /// If the file cannot be created or written.
+    pub fn write_to_file(&self, path: impl AsRef<Path>) -> std::io::Result<()> {
+        self.write(BufWriter::new(File::create(path)?))
+    }
+
+    /// The bytes of the file.
+    #[must_use]
+    #[allow(clippy::missing_panics_doc)] // writing to a vec does not fail
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        self.write(&mut bytes)
+            .expect("writing to a vec does not fail");
+        bytes
+    }
+}
+
+fn align(position: u64) -> u64 {
+    position.div_ceil(GGUF_DEFAULT_ALIGNMENT) * GGUF_DEFAULT_ALIGNMENT
+}
+
+/// The `gguf_type` of a value.
+fn value_type(value: &GgufValue) -> u32 {
+    match value {
+        GgufValue::U8(_) => 0,
+        GgufValue::I8(_) => 1,
+        GgufValue::U16(_) => 2,
+        GgufValue::I16(_) => 3,
+        GgufValue::U32(_) => 4,
+        GgufValue::I32(_) => 5,
+        GgufValue::F32(_) => 6,
+        GgufValue::Bool(_) => 7,
+        GgufValue::String(_) => 8,
+        GgufValue::Array(_) => 9,
+        GgufValue::U64(_) => 10,
+        GgufValue::I64(_) => 11,
+        GgufValue::F64(_) => 12,
+    }
+}
+
+/// A little endian writer that keeps track of its position, the counterpart of `GgufReader`.
+struct CountingWriter<W> {
+    inner: W,
+    position: u64,
+}
+
+impl<W: Write> CountingWriter<W> {
+    fn put(&mut self, bytes: &[u8]) -> std::io::Result<()> {
+        self.inner.write_all(bytes)?;
+        self.position += bytes.len() as u64;
+        Ok(())
+    }
+
+    fn put_string(&mut self, string: &str) -> std::io::Result<()> {
+        self.put(&(string.len() as u64).to_le_bytes())?;
+        self.put(string.as_bytes())
+    }
+
+    fn put_value(&mut self, value: &GgufValue) -> std::io::Result<()> {
+        match value {
+            GgufValue::U8(v) => self.put(&v.to_le_bytes()),
+            GgufValue::I8(v) => self.put(&v.to_le_bytes()),
+            GgufValue::U16(v) => self.put(&v.to_le_bytes()),
+            GgufValue::I16(v) => self.put(&v.to_le_bytes()),
+            GgufValue::U32(v) => self.put(&v.to_le_bytes()),
+            GgufValue::I32(v) => self.put(&v.to_le_bytes()),
+            GgufValue::F32(v) => self.put(&v.to_le_bytes()),
+            GgufValue::Bool(v) => self.put(&[u8::from(*v)]),
+            GgufValue::String(v) => self.put_string(v),
+            GgufValue::U64(v) => self.put(&v.to_le_bytes()),
+            GgufValue::I64(v) => self.put(&v.to_le_bytes()),
+            GgufValue::F64(v) => self.put(&v.to_le_bytes()),
+            GgufValue::Array(values) => {
+                // empty arrays are written as arrays of u32
+                let element_type = values.first().map_or(4, value_type);
+                self.put(&element_type.to_le_bytes())?;
+                self.put(&(values.len() as u64).to_le_bytes())?;
+                values.iter().try_for_each(|value| self.put_value(value))
+            }
+        }
+    }
+}
@@ -9,8 +9,16 @@ use std::fmt::{Debug, Formatter};
 use llama_cpp_sys_2::{llama_grammar, llama_grammar_element, llama_gretype};
 use std::ptr::NonNull;
 use std::str::FromStr;
+use std::sync::Arc;
 use tracing::error;
 
+pub mod builder;
+pub mod cache;
+#[cfg(feature = "json")]
+pub mod json_schema;
+pub mod mask;
+pub mod regex;
+
 /// Details of extraneous characters after a rule error.
 #[derive(thiserror::Error, Debug)]
 #[error("Extraneous chars after rule {name:?}: {chars:?}")]
@@ -99,6 +107,10 @@ pub enum GrammarParseError {
 pub struct LlamaGrammar {
     parse: ParseState,
     pub(crate) grammar: NonNull<llama_grammar>,
+    /// The allowed tokens of the states this grammar's [`cache::CompiledGrammar`] reached.
+    pub(crate) masks: Option<Arc<mask::TokenMaskCache>>,
+    /// The tokens accepted since the grammar was compiled, see [`mask::advance`].
+    pub(crate) state: u64,
 }
 
 impl Clone for LlamaGrammar {
@@ -107,6 +119,8 @@ impl Clone for LlamaGrammar {
         Self {
             parse: self.parse.clone(),
             grammar: NonNull::new(grammar).expect("copied grammar should never be null"),
+            masks: self.masks.clone(),
+            state: self.state,
         }
     }
 }
@@ -121,6 +135,8 @@ impl Debug for LlamaGrammar {
         f.debug_struct("LlamaGrammar")
             .field("grammar", &self.grammar)
             .field("parse", &self.parse)
+            .field("masks", &self.masks.is_some())
+            .field("state", &self.state)
             .finish()
     }
 }
@@ -437,12 +453,126 @@ pub enum LlamaGrammarFromStrError {
     /// There was an error parsing the grammar.
     #[error("Failed to parse grammar {0}")]
     ParseError(#[from] GrammarParseError),
+    /// The grammar parsed but cannot be used, see [`validate`].
+    #[error("Invalid grammar {0}")]
+    InvalidGrammar(#[from] GrammarError),
     /// Llama-cpp returned null - this can occur for many reasons, but should ideally be caught on
     /// the rust side beforehand.
     #[error("llama-cpp returned null")]
     LlamaCppNullError,
 }
 
+/// What is wrong with a grammar found by [`validate`].
+#[derive(thiserror::Error, Debug)]
+pub enum GrammarErrorKind {
+    /// The grammar could not be parsed.
+    #[error("{0}")]
+    Parse(#[from] GrammarParseError),
+    /// There is no `root` rule to start from.
+    #[error("the grammar has no root rule")]
+    MissingRoot,
+    /// A rule is referenced but never defined.
+    #[error("undefined rule {name:?}")]
+    UndefinedRule {
+        /// the name of the rule
+        name: String,
+    },
+}
+
+/// An error in a grammar and where in the grammar it is (both 1-based). Parse errors point at the
+/// start of the rule that failed to parse, undefined rules at their first use.
+#[derive(thiserror::Error, Debug)]
+#[error("line {line}, column {column}: {kind}")]
+#[allow(clippy::module_name_repetitions)]
+pub struct GrammarError {
+    /// The line of the error.
+    pub line: usize,
+    /// The column of the error in characters.
+    pub column: usize,
+    /// The error.
+    pub kind: GrammarErrorKind,
+}
+
+impl GrammarError {
+    fn at(gbnf: &str, offset: usize, kind: GrammarErrorKind) -> Self {
+        let before = &gbnf[..offset];
+        let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+        Self {
+            line: before.matches('\n').count() + 1,
+            column: before[line_start..].chars().count() + 1,
+            kind,
+        }
+    }
+}
+
+/// Check that `gbnf` is a grammar that can be used for sampling: it parses, has a `root` rule and
+/// every rule it references is defined. This does not need a model or the llama backend.
+///
+/// # Errors
+///
+/// If the grammar is invalid, see [`GrammarError`].
+///
+/// # Examples
+///
+/// ```
+/// # use llama_cpp_2::grammar::{validate, GrammarErrorKind};
+/// assert!(validate(r#"root ::= "yes" | "no""#).is_ok());
+///
+/// let err = validate("root ::= answer\n").unwrap_err();
+/// assert!(matches!(err.kind, GrammarErrorKind::UndefinedRule { ref name } if name == "answer"));
+/// assert_eq!((err.line, err.column), (1, 10));
+/// ```
+pub fn validate(gbnf: &str) -> Result<(), GrammarError> {
+    let mut parse_state = ParseState::new();
+    let mut remaining = Some(gbnf);
+    while let Some(rest) = remaining {
+        let rule_start = ParseState::consume_whitespace_and_comments(rest, true);
+        remaining = parse_state
+            .parse_rule(rest)
+            .map_err(|err| GrammarError::at(gbnf, gbnf.len() - rule_start.len(), err.into()))?;
+    }
+    parse_state.check(gbnf)
+}
+
+impl ParseState {
+    /// Check for a root rule and undefined rules.
+    fn check(&self, gbnf: &str) -> Result<(), GrammarError> {
+        if !self.symbol_ids.contains_key("root") {
+            return Err(GrammarError::at(gbnf, 0, GrammarErrorKind::MissingRoot));
+        }
+        let undefined = self
+            .symbol_ids
+            .iter()
+            .filter(
+                |(_, &id)| !matches!(self.rules.get(id as usize), Some(rule) if !rule.is_empty()),
+            )
+            .map(|(name, _)| (Self::find_reference(gbnf, name), name))
+            .min();
+        match undefined {
+            Some((offset, name)) => Err(GrammarError::at(
+                gbnf,
+                offset,
+                GrammarErrorKind::UndefinedRule { name: name.clone() },
+            )),
+            None => Ok(()),
+        }
+    }
+
+    /// The offset of the first occurrence of `name` as a whole rule name in `gbnf`.
+    fn find_reference(gbnf: &str, name: &str) -> usize {
+        let is_name_char = |c: char| c.is_alphanumeric() || c == '-' || c == '_';
+        gbnf.match_indices(name)
+            .find(|(i, _)| {
+                !gbnf[..*i].chars().next_back().is_some_and(is_name_char)
+                    && !gbnf[i + name.len()..]
+                        .chars()
+                        .next()
+                        .is_some_and(is_name_char)
+            })
+            .map_or(0, |(i, _)| i)
+    }
+}
+
 impl FromStr for ParseState {
     type Err = GrammarParseError;
 
@@ -461,6 +591,7 @@ impl FromStr for LlamaGrammar {
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         let mut parse_state = ParseState::from_str(s)?;
+        parse_state.check(s)?;
 
         let n_rules = parse_state.rules.len();
         let root_id = parse_state.get_symbol_id("root");
@@ -477,6 +608,8 @@ impl FromStr for LlamaGrammar {
         Ok(Self {
             parse: parse_state,
             grammar: NonNull::new(grammar).ok_or(LlamaGrammarFromStrError::LlamaCppNullError)?,
+            masks: None,
+            state: 0,
         })
     }
 }
@@ -0,0 +1,456 @@
+//! Build grammars in Rust instead of writing GBNF by hand.
+//!
+//! An [`Expr`] is a typed GBNF expression (literals, character classes, rule references,
+//! sequences, alternatives and repetitions) and a [`GrammarBuilder`] is a set of named rules.
+//! Literals and character classes are escaped when serialized, so any string can be matched
+//! exactly.
+//!
+//! ```
+//! # use llama_cpp_2::grammar::builder::{chars, iso_date, literal, one_of, rule, GrammarBuilder};
+//! let gbnf = GrammarBuilder::new()
+//!     .rule("root", literal("{\"due\": \"").then(rule("date")).then(literal("\", \"priority\": "))
+//!         .then(one_of(["\"low\"", "\"high\""])).then(literal("}")))
+//!     .rule("date", iso_date())
+//!     .to_gbnf();
+//! llama_cpp_2::grammar::validate(&gbnf)?;
+//! assert!(gbnf.starts_with("root ::= \"{\\\"due\\\": \\\"\" date"));
+//! # Ok::<(), llama_cpp_2::grammar::GrammarError>(())
+//! ```
+
+use std::fmt::{Display, Formatter, Write};
+use std::ops::RangeInclusive;
+use std::str::FromStr;
+
+use crate::grammar::{LlamaGrammar, LlamaGrammarFromStrError};
+
+/// A GBNF expression.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Expr {
+    /// Exactly this string.
+    Literal(String),
+    /// One character in (or, if `negated`, not in) any of the ranges.
+    CharClass {
+        /// The inclusive ranges of characters.
+        ranges: Vec<RangeInclusive<char>>,
+        /// Match the characters outside of the ranges instead.
+        negated: bool,
+    },
+    /// A reference to a rule.
+    Rule(String),
+    /// Each expression after the other.
+    Sequence(Vec<Expr>),
+    /// Any one of the expressions.
+    Alternatives(Vec<Expr>),
+    /// The expression repeated between `min` and `max` (unbounded if `None`) times.
+    Repeat {
+        /// The repeated expression.
+        expr: Box<Expr>,
+        /// The minimal number of repetitions.
+        min: u32,
+        /// The maximal number of repetitions.
+        max: Option<u32>,
+    },
+}
+
+/// Match exactly `text`.
+#[must_use]
+pub fn literal(text: impl Into<String>) -> Expr {
+    Expr::Literal(text.into())
+}
+
+/// Match one character of `ranges`, e.g. `chars(['a'..='z', '0'..='9'])`.
+#[must_use]
+pub fn chars(ranges: impl IntoIterator<Item = RangeInclusive<char>>) -> Expr {
+    Expr::CharClass {
+        ranges: ranges.into_iter().collect(),
+        negated: false,
+    }
+}
+
+/// Match one character that is not in `ranges`.
+#[must_use]
+pub fn not_chars(ranges: impl IntoIterator<Item = RangeInclusive<char>>) -> Expr {
+    Expr::CharClass {
+        ranges: ranges.into_iter().collect(),
+        negated: true,
+    }
+}
+
+/// Match the rule `name`.
+#[must_use]
+pub fn rule(name: impl Into<String>) -> Expr {
+    Expr::Rule(name.into())
+}
+
+/// Match `exprs` one after the other.
+#[must_use]
+pub fn seq(exprs: impl IntoIterator<Item = Expr>) -> Expr {
+    Expr::Sequence(exprs.into_iter().collect())
+}
+
+/// Match any one of `exprs`.
+#[must_use]
+pub fn alt(exprs: impl IntoIterator<Item = Expr>) -> Expr {
+    Expr::Alternatives(exprs.into_iter().collect())
+}
+
+/// Match exactly one of `values`, e.g. the variants of an enum.
+#[must_use]
+pub fn one_of(values: impl IntoIterator<Item = impl Into<String>>) -> Expr {
+    alt(values.into_iter().map(literal))
+}
+
+/// A decimal digit.
+#[must_use]
+pub fn digit() -> Expr {
+    chars(['0'..='9'])
+}
+
+/// A date in ISO 8601 format, `YYYY-MM-DD`, with months `01` to `12` and days `01` to `31`.
+#[must_use]
+pub fn iso_date() -> Expr {
+    seq([
+        digit().repeat(4, Some(4)),
+        literal("-"),
+        alt([
+            literal("0").then(chars(['1'..='9'])),
+            literal("1").then(chars(['0'..='2'])),
+        ]),
+        literal("-"),
+        alt([
+            literal("0").then(chars(['1'..='9'])),
+            chars(['1'..='2']).then(digit()),
+            literal("3").then(chars(['0'..='1'])),
+        ]),
+    ])
+}
+
+impl Expr {
+    /// Match `self` followed by `next`.
+    #[must_use]
+    pub fn then(self, next: Expr) -> Expr {
+        match self {
+            Expr::Sequence(mut exprs) => {
+                exprs.push(next);
+                Expr::Sequence(exprs)
+            }
+            expr => Expr::Sequence(vec![expr, next]),
+        }
+    }
+
+    /// Match `self` or `other`.
+    #[must_use]
+    pub fn or(self, other: Expr) -> Expr {
+        match self {
+            Expr::Alternatives(mut exprs) => {
+                exprs.push(other);
+                Expr::Alternatives(exprs)
+            }
+            expr => Expr::Alternatives(vec![expr, other]),
+        }
+    }
+
+    /// Match `self` between `min` and `max` (unbounded if `None`) times.
+    #[must_use]
+    pub fn repeat(self, min: u32, max: Option<u32>) -> Expr {
+        Expr::Repeat {
+            expr: Box::new(self),
+            min,
+            max,
+        }
+    }
+
+    /// Match `self` or nothing, `?` in GBNF.
+    #[must_use]
+    pub fn optional(self) -> Expr {
+        self.repeat(0, Some(1))
+    }
+
+    /// Match `self` any number of times, `*` in GBNF.
+    #[must_use]
+    pub fn zero_or_more(self) -> Expr {
+        self.repeat(0, None)
+    }
+
+    /// Match `self` at least once, `+` in GBNF.
+    #[must_use]
+    pub fn one_or_more(self) -> Expr {
+        self.repeat(1, None)
+    }
+
+    /// Match `self` at least once, with `separator` between the repetitions.
+    #[must_use]
+    pub fn separated_by(self, separator: Expr) -> Expr {
+        self.clone().then(separator.then(self).zero_or_more())
+    }
+
+    /// Whether the expression needs parentheses to be followed by a postfix operator.
+    fn is_atom(&self) -> bool {
+        match self {
+            Expr::Literal(_) | Expr::CharClass { .. } | Expr::Rule(_) => true,
+            Expr::Sequence(exprs) | Expr::Alternatives(exprs) => {
+                exprs.len() == 1 && exprs[0].is_atom()
+            }
+            Expr::Repeat { .. } => false,
+        }
+    }
+}
+
+/// A character escaped for a GBNF literal or character class.
+fn write_char(f: &mut Formatter<'_>, c: char, in_class: bool) -> std::fmt::Result {
+    match c {
+        '"' | '\\' | '[' | ']' => write!(f, "\\{c}"),
+        '\n' => f.write_str("\\n"),
+        '\r' => f.write_str("\\r"),
+        '\t' => f.write_str("\\t"),
+        '-' | '^' if in_class => write!(f, "\\x{:02X}", u32::from(c)),
+        c if c.is_control() => match u32::from(c) {
+            code @ 0..=0xFF => write!(f, "\\x{code:02X}"),
+            code @ 0x100..=0xFFFF => write!(f, "\\u{code:04X}"),
+            code => write!(f, "\\U{code:08X}"),
+        },
+        c => f.write_char(c),
+    }
+}
+
+/// Serializes the expression as GBNF.
+impl Display for Expr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Expr::Literal(text) => {
+                f.write_char('"')?;
+                for c in text.chars() {
+                    write_char(f, c, false)?;
+                }
+                f.write_char('"')
+            }
+            Expr::CharClass { ranges, negated } => {
+                f.write_str(if *negated { "[^" } else { "[" })?;
+                for range in ranges {
+                    write_char(f, *range.start(), true)?;
+                    if range.start() != range.end() {
+                        f.write_char('-')?;
+                        write_char(f, *range.end(), true)?;
+                    }
+                }
+                f.write_char(']')
+            }
+            Expr::Rule(name) => f.write_str(name),
+            Expr::Sequence(exprs) if exprs.is_empty() => f.write_str("\"\""),
+            Expr::Sequence(exprs) => {
+                for (i, expr) in exprs.iter().enumerate() {
+                    if i > 0 {
+                        f.write_char(' ')?;
+                    }
+                    if matches!(expr, Expr::Alternatives(alternatives) if alternatives.len() > 1) {
+                        write!(f, "({expr})")?;
+                    } else {
+                        write!(f, "{expr}")?;
+                    }
+                }
+                Ok(())
+            }
+            Expr::Alternatives(exprs) if exprs.is_empty() => f.write_str("\"\""),
+            Expr::Alternatives(exprs) => {
+                for (i, expr) in exprs.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(" | ")?;
+                    }
+                    write!(f, "{expr}")?;
+                }
+                Ok(())
+            }
+            Expr::Repeat { expr, min, max } => write_repeat(f, expr, *min, *max),
+        }
+    }
+}
+
+/// Write `expr{min,max}` with the postfix operators the parser supports.
+fn write_repeat(
+    f: &mut Formatter<'_>,
+    expr: &Expr,
+    min: u32,
+    max: Option<u32>,
+) -> std::fmt::Result {
+    let atom = if expr.is_atom() {
+        expr.to_string()
+    } else {
+        format!("({expr})")
+    };
+    let (required, optional) = match max {
+        Some(max) => (min.min(max), max.saturating_sub(min)),
+        None => (min.saturating_sub(1), 0),
+    };
+    let mut parts: Vec<String> = (0..required).map(|_| atom.clone()).collect();
+    match max {
+        None if min == 0 => parts.push(format!("{atom}*")),
+        None => parts.push(format!("{atom}+")),
+        // nest the optional repetitions so that each one requires the previous one
+        Some(_) if optional > 0 => {
+            let nested =
+                (1..optional).fold(format!("{atom}?"), |tail, _| format!("({atom} {tail})?"));
+            parts.push(nested);
+        }
+        Some(_) => {}
+    }
+    if parts.is_empty() {
+        f.write_str("\"\"")
+    } else {
+        f.write_str(&parts.join(" "))
+    }
+}
+
+/// A set of named rules that serializes to GBNF, starting with `root`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GrammarBuilder {
+    rules: Vec<(String, Expr)>,
+}
+
+impl GrammarBuilder {
+    /// An empty grammar.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add the rule `name ::= expr`, replacing an earlier rule of the same name. Names must start
+    /// with a letter and consist of letters, digits, `-` and `_`.
+    #[must_use]
+    pub fn rule(mut self, name: impl Into<String>, expr: Expr) -> Self {
+        let name = name.into();
+        match self
+            .rules
+            .iter_mut()
+            .find(|(existing, _)| *existing == name)
+        {
+            Some((_, existing)) => *existing = expr,
+            None => self.rules.push((name, expr)),
+        }
+        self
+    }
+
+    /// Add the rules `json-value`, `json-object`, `json-array`, `json-string`, `json-number` and
+    /// `json-ws`, so that `rule("json-value")` matches any JSON value.
+    #[must_use]
+    pub fn with_json_rules(self) -> Self {
+        let ws = || rule("json-ws");
+        let member = || seq([rule("json-string"), literal(":"), ws(), rule("json-value")]);
+        let hex = || chars(['0'..='9', 'a'..='f', 'A'..='F']);
+        self.rule(
+            "json-value",
+            alt([
+                rule("json-object"),
+                rule("json-array"),
+                rule("json-string"),
+                rule("json-number"),
+                one_of(["true", "false", "null"]),
+            ])
+            .then(ws()),
+        )
+        .rule(
+            "json-object",
+            seq([
+                literal("{"),
+                ws(),
+                member().separated_by(literal(",").then(ws())).optional(),
+                literal("}"),
+            ]),
+        )
+        .rule(
+            "json-array",
+            seq([
+                literal("["),
+                ws(),
+                rule("json-value")
+                    .separated_by(literal(",").then(ws()))
+                    .optional(),
+                literal("]"),
+            ]),
+        )
+        .rule(
+            "json-string",
+            seq([
+                literal("\""),
+                alt([
+                    not_chars(['"'..='"', '\\'..='\\', '\0'..='\x1f']),
+                    literal("\\").then(alt([
+                        chars([
+                            '"'..='"',
+                            '\\'..='\\',
+                            '/'..='/',
+                            'b'..='b',
+                            'f'..='f',
+                            'n'..='n',
+                            'r'..='r',
+                            't'..='t',
+                        ]),
+                        literal("u").then(hex().repeat(4, Some(4))),
+                    ])),
+                ])
+                .zero_or_more(),
+                literal("\""),
+            ]),
+        )
+        .rule(
+            "json-number",
+            seq([
+                literal("-").optional(),
+                alt([
+                    literal("0"),
+                    chars(['1'..='9']).then(digit().zero_or_more()),
+                ]),
+                literal(".").then(digit().one_or_more()).optional(),
+                seq([
+                    chars(['e'..='e', 'E'..='E']),
+                    chars(['-'..='-', '+'..='+']).optional(),
+                    digit().one_or_more(),
+                ])
+                .optional(),
+            ]),
+        )
+        .rule(
+            "json-ws",
+            chars([' '..=' ', '\t'..='\t', '\n'..='\n']).repeat(0, Some(20)),
+        )
+    }
+
+    /// A grammar matching any JSON value.
+    ///
+    /// ```
+    /// # use llama_cpp_2::grammar::builder::GrammarBuilder;
+    /// let grammar = GrammarBuilder::json().build()?;
+    /// # Ok::<(), llama_cpp_2::grammar::LlamaGrammarFromStrError>(())
+    /// ```
+    #[must_use]
+    pub fn json() -> Self {
+        Self::new()
+            .with_json_rules()
+            .rule("root", rule("json-value"))
+    }
+
+    /// Serialize the rules as GBNF, `root` first.
+    #[must_use]
+    pub fn to_gbnf(&self) -> String {
+        let mut gbnf = String::new();
+        let (root, rest): (Vec<_>, Vec<_>) =
+            self.rules.iter().partition(|(name, _)| name == "root");
+        for (name, expr) in root.into_iter().chain(rest) {
+            let _ = writeln!(gbnf, "{name} ::= {expr}");
+        }
+        gbnf
+    }
+
+    /// Parse the grammar into a [`LlamaGrammar`].
+    ///
+    /// # Errors
+    ///
+    /// If a rule has no valid name, a referenced rule is missing or there is no `root` rule. Use
+    /// [`validate`](crate::grammar::validate) on [`GrammarBuilder::to_gbnf`] for an error with
+    /// its location.
+    pub fn build(&self) -> Result<LlamaGrammar, LlamaGrammarFromStrError> {
+        LlamaGrammar::from_str(&self.to_gbnf())
+    }
+}
+
+#[cfg(test)]
+mod tests;
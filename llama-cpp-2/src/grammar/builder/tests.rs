@@ -0,0 +1,58 @@
+use super::*;
+use crate::grammar;
+
+#[test]
+fn escapes_literals_and_char_classes() {
+    assert_eq!(literal("a\"b\\c\n[]").to_string(), r#""a\"b\\c\n\[\]""#);
+    assert_eq!(chars(['a'..='z', '-'..='-']).to_string(), r"[a-z\x2D]");
+    assert_eq!(
+        not_chars(['^'..='^', '\0'..='\x1f']).to_string(),
+        r"[^\x5E\x00-\x1F]"
+    );
+}
+
+#[test]
+fn parenthesizes_by_precedence() {
+    let expr = literal("a").or(literal("b")).then(literal("c"));
+    assert_eq!(expr.to_string(), r#"("a" | "b") "c""#);
+    let expr = seq([literal("a"), literal("b")]).zero_or_more();
+    assert_eq!(expr.to_string(), r#"("a" "b")*"#);
+    assert_eq!(rule("item").one_or_more().to_string(), "item+");
+}
+
+#[test]
+fn bounded_repetitions_nest() {
+    assert_eq!(digit().repeat(2, Some(2)).to_string(), "[0-9] [0-9]");
+    assert_eq!(
+        digit().repeat(1, Some(3)).to_string(),
+        "[0-9] ([0-9] [0-9]?)?"
+    );
+    assert_eq!(digit().repeat(3, None).to_string(), "[0-9] [0-9] [0-9]+");
+    assert_eq!(digit().repeat(0, Some(0)).to_string(), r#""""#);
+}
+
+#[test]
+fn builds_valid_grammars() {
+    let gbnf = GrammarBuilder::json().to_gbnf();
+    assert!(gbnf.starts_with("root ::= json-value\n"), "{gbnf}");
+    grammar::validate(&gbnf).unwrap_or_else(|err| panic!("{err}\n{gbnf}"));
+
+    let gbnf = GrammarBuilder::new()
+        .rule("date", iso_date())
+        .rule("root", one_of(["yes", "no"]).or(rule("date")))
+        .to_gbnf();
+    assert!(
+        gbnf.starts_with(r#"root ::= "yes" | "no" | date"#),
+        "{gbnf}"
+    );
+    grammar::validate(&gbnf).unwrap_or_else(|err| panic!("{err}\n{gbnf}"));
+}
+
+#[test]
+fn rules_replace_rules_of_the_same_name() {
+    let gbnf = GrammarBuilder::new()
+        .rule("root", literal("a"))
+        .rule("root", literal("b"))
+        .to_gbnf();
+    assert_eq!(gbnf, "root ::= \"b\"\n");
+}
@@ -0,0 +1,218 @@
+//! Parse a grammar once and reuse it for many requests.
+//!
+//! Parsing a GBNF grammar and building llama.cpp's grammar from it takes long for the grammars of
+//! large JSON schemas, while copying a built grammar is cheap. A [`CompiledGrammar`] keeps the
+//! built grammar in its start state and hands out copies with [`CompiledGrammar::instantiate`],
+//! one per generation, since sampling advances the grammar it is given.
+//!
+//! A server that sees the same few grammars or schemas over and over can share a
+//! [`GrammarCache`] between its request handlers, which keeps the most recently used grammars by
+//! their text:
+//!
+//! ```
+//! # use std::num::NonZeroUsize;
+//! # use llama_cpp_2::grammar::cache::GrammarCache;
+//! # use llama_cpp_2::generate::GenerationParams;
+//! let cache = GrammarCache::new(NonZeroUsize::new(64).unwrap());
+//! for _request in 0..3 {
+//!     let grammar = cache.get_or_compile(r#"root ::= "yes" | "no""#)?;
+//!     let params = GenerationParams::default().with_grammar(grammar.instantiate());
+//!     // ... generate with `params`
+//! }
+//! assert_eq!((cache.hits(), cache.misses()), (2, 1));
+//! # Ok::<(), llama_cpp_2::grammar::LlamaGrammarFromStrError>(())
+//! ```
+
+use std::collections::HashMap;
+use std::num::NonZeroUsize;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
+use super::mask::TokenMaskCache;
+use super::{LlamaGrammar, LlamaGrammarFromStrError};
+
+/// A grammar that was parsed once and can be instantiated for any number of generations. Cloning
+/// it is cheap and shares the parsed grammar.
+#[derive(Debug, Clone)]
+pub struct CompiledGrammar {
+    grammar: Arc<LlamaGrammar>,
+}
+
+impl CompiledGrammar {
+    /// Parse and build `gbnf`.
+    ///
+    /// # Errors
+    ///
+    /// If the grammar is invalid, see [`LlamaGrammarFromStrError`].
+    pub fn new(gbnf: &str) -> Result<Self, LlamaGrammarFromStrError> {
+        Ok(LlamaGrammar::from_str(gbnf)?.into())
+    }
+
+    /// A fresh copy of the grammar in its start state, for one generation.
+    #[must_use]
+    pub fn instantiate(&self) -> LlamaGrammar {
+        LlamaGrammar::clone(&self.grammar)
+    }
+
+    /// Remember the allowed tokens of up to `capacity` grammar states, shared by every instance,
+    /// see [`mask`](super::mask). Replaces the cache this grammar had.
+    #[must_use]
+    pub fn with_mask_cache(mut self, capacity: NonZeroUsize) -> Self {
+        Arc::make_mut(&mut self.grammar).masks = Some(Arc::new(TokenMaskCache::new(capacity)));
+        self
+    }
+
+    /// The cache of allowed tokens shared by the instances, if the grammar has one.
+    #[must_use]
+    pub fn mask_cache(&self) -> Option<&TokenMaskCache> {
+        self.grammar.masks.as_deref()
+    }
+}
+
+/// Keeps `grammar` in the state it is in, usually its start state.
+impl From<LlamaGrammar> for CompiledGrammar {
+    fn from(grammar: LlamaGrammar) -> Self {
+        Self {
+            grammar: Arc::new(grammar),
+        }
+    }
+}
+
+impl FromStr for CompiledGrammar {
+    type Err = LlamaGrammarFromStrError;
+
+    fn from_str(gbnf: &str) -> Result<Self, Self::Err> {
+        Self::new(gbnf)
+    }
+}
+
+/// A least recently used cache of [`CompiledGrammar`]s keyed by their GBNF text, see the
+/// [module docs](self). It can be shared between threads.
+#[derive(Debug)]
+pub struct GrammarCache {
+    capacity: NonZeroUsize,
+    mask_capacity: Option<NonZeroUsize>,
+    state: Mutex<CacheState>,
+}
+
+#[derive(Debug, Default)]
+struct CacheState {
+    /// The grammars and when they were last used.
+    grammars: HashMap<String, (CompiledGrammar, u64)>,
+    clock: u64,
+    hits: u64,
+    misses: u64,
+}
+
+// the cache is meant to be shared between request handlers
+const _: () = {
+    const fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<GrammarCache>();
+};
+
+impl GrammarCache {
+    /// An empty cache that keeps at most `capacity` grammars.
+    #[must_use]
+    pub fn new(capacity: NonZeroUsize) -> Self {
+        Self {
+            capacity,
+            mask_capacity: None,
+            state: Mutex::new(CacheState::default()),
+        }
+    }
+
+    /// Give every grammar this cache compiles a [mask cache](CompiledGrammar::with_mask_cache)
+    /// of `capacity` states.
+    #[must_use]
+    pub fn with_mask_cache(mut self, capacity: NonZeroUsize) -> Self {
+        self.mask_capacity = Some(capacity);
+        self
+    }
+
+    /// The grammar for `gbnf`, compiled now if it is not cached. Compiling evicts the least
+    /// recently used grammar if the cache is full.
+    ///
+    /// The cache is not locked while a grammar compiles, so two threads asking for the same new
+    /// grammar at once may both compile it.
+    ///
+    /// # Errors
+    ///
+    /// If the grammar is invalid. Invalid grammars are not cached.
+    pub fn get_or_compile(&self, gbnf: &str) -> Result<CompiledGrammar, LlamaGrammarFromStrError> {
+        {
+            let mut state = self.lock();
+            state.clock += 1;
+            let now = state.clock;
+            if let Some((grammar, last_used)) = state.grammars.get_mut(gbnf) {
+                *last_used = now;
+                let grammar = grammar.clone();
+                state.hits += 1;
+                return Ok(grammar);
+            }
+            state.misses += 1;
+        }
+
+        let mut grammar = CompiledGrammar::new(gbnf)?;
+        if let Some(capacity) = self.mask_capacity {
+            grammar = grammar.with_mask_cache(capacity);
+        }
+        let mut state = self.lock();
+        if !state.grammars.contains_key(gbnf) && state.grammars.len() >= self.capacity.get() {
+            let oldest = state
+                .grammars
+                .iter()
+                .min_by_key(|(_, (_, last_used))| *last_used)
+                .map(|(gbnf, _)| gbnf.clone());
+            if let Some(oldest) = oldest {
+                state.grammars.remove(&oldest);
+            }
+        }
+        let now = state.clock;
+        state
+            .grammars
+            .insert(gbnf.to_string(), (grammar.clone(), now));
+        Ok(grammar)
+    }
+
+    /// The maximum number of cached grammars.
+    #[must_use]
+    pub fn capacity(&self) -> NonZeroUsize {
+        self.capacity
+    }
+
+    /// The number of cached grammars.
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.lock().grammars.len()
+    }
+
+    /// Whether no grammar is cached.
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The number of [`GrammarCache::get_or_compile`] calls answered from the cache.
+    #[must_use]
+    pub fn hits(&self) -> u64 {
+        self.lock().hits
+    }
+
+    /// The number of [`GrammarCache::get_or_compile`] calls that had to compile the grammar.
+    #[must_use]
+    pub fn misses(&self) -> u64 {
+        self.lock().misses
+    }
+
+    /// Remove every cached grammar. The hit and miss counts are kept.
+    pub fn clear(&self) {
+        self.lock().grammars.clear();
+    }
+
+    fn lock(&self) -> MutexGuard<'_, CacheState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,47 @@
+use super::*;
+
+fn one_of(words: &[&str]) -> String {
+    let words: Vec<String> = words.iter().map(|word| format!("{word:?}")).collect();
+    format!("root ::= {}\n", words.join(" | "))
+}
+
+#[test]
+fn reuses_compiled_grammars() {
+    let cache = GrammarCache::new(NonZeroUsize::new(2).unwrap());
+    let yes_no = one_of(&["yes", "no"]);
+    cache.get_or_compile(&yes_no).unwrap();
+    cache.get_or_compile(&yes_no).unwrap();
+    assert_eq!((cache.hits(), cache.misses(), cache.len()), (1, 1, 1));
+
+    // instances are independent copies
+    let grammar = cache.get_or_compile(&yes_no).unwrap();
+    let (a, b) = (grammar.instantiate(), grammar.instantiate());
+    assert_ne!(a.grammar, b.grammar);
+}
+
+#[test]
+fn evicts_the_least_recently_used_grammar() {
+    let cache = GrammarCache::new(NonZeroUsize::new(2).unwrap());
+    let (a, b, c) = (one_of(&["a"]), one_of(&["b"]), one_of(&["c"]));
+    cache.get_or_compile(&a).unwrap();
+    cache.get_or_compile(&b).unwrap();
+    // a is now more recent than b, so c replaces b
+    cache.get_or_compile(&a).unwrap();
+    cache.get_or_compile(&c).unwrap();
+    assert_eq!(cache.len(), 2);
+
+    let misses = cache.misses();
+    cache.get_or_compile(&a).unwrap();
+    cache.get_or_compile(&c).unwrap();
+    assert_eq!(cache.misses(), misses);
+    cache.get_or_compile(&b).unwrap();
+    assert_eq!(cache.misses(), misses + 1);
+}
+
+#[test]
+fn does_not_cache_invalid_grammars() {
+    let cache = GrammarCache::new(NonZeroUsize::new(2).unwrap());
+    assert!(cache.get_or_compile("root ::= missing\n").is_err());
+    assert!(cache.is_empty());
+    assert_eq!(cache.misses(), 1);
+}
@@ -0,0 +1,614 @@
+//! Convert JSON schemas to GBNF grammars and validate JSON values against them.
+//!
+//! The supported subset covers what structured output usually needs: `type` (including arrays of
+//! types), `properties` and `required`, `items` with `minItems` and `maxItems`, `enum`, `const`,
+//! `anyOf` / `oneOf` and local `$ref`s into `$defs` or `definitions`. Keywords the grammar cannot
+//! express (such as `minimum` or `maxLength`) are only checked by [`validate`], and unknown
+//! keywords are ignored.
+//!
+//! The grammar of an object with `properties` only generates the listed properties, whatever
+//! `additionalProperties` says, as the model never needs others to match the schema. [`validate`]
+//! follows JSON schema and accepts other properties unless `additionalProperties` is `false`.
+//!
+//! `minItems` must be at most [`MAX_REPEATED_ITEMS`], as the grammar spells out every required
+//! item. A larger `maxItems` is not enforced by the grammar, only by [`validate`].
+//!
+//! Properties are generated in the order of the schema's JSON object, which is alphabetical
+//! unless `serde_json` is built with `preserve_order`.
+//!
+//! ```
+//! # use llama_cpp_2::grammar::json_schema::{to_gbnf, validate};
+//! let schema = serde_json::json!({
+//!     "type": "object",
+//!     "properties": { "name": { "type": "string" }, "age": { "type": "integer", "minimum": 0 } },
+//!     "required": ["name", "age"],
+//! });
+//! let gbnf = to_gbnf(&schema)?;
+//! llama_cpp_2::grammar::validate(&gbnf)?;
+//!
+//! assert!(validate(&schema, &serde_json::json!({ "name": "Ada", "age": 36 })).is_ok());
+//! assert!(validate(&schema, &serde_json::json!({ "name": "Ada", "age": -1 })).is_err());
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+
+use serde_json::{Map, Value};
+
+/// A schema could not be converted to a grammar.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum JsonSchemaError {
+    /// A `$ref` does not point into the `$defs` or `definitions` of the root schema.
+    #[error("unsupported or unresolvable $ref {0}")]
+    UnresolvedRef(String),
+    /// A `type` is not one of the JSON schema types.
+    #[error("unknown type {0}")]
+    UnknownType(String),
+    /// A schema is neither an object nor a boolean.
+    #[error("invalid schema at {0}")]
+    InvalidSchema(String),
+    /// An array requires more than [`MAX_REPEATED_ITEMS`] items.
+    #[error("minItems {0} is more than the supported {MAX_REPEATED_ITEMS}")]
+    TooManyItems(u64),
+}
+
+/// The largest `minItems` and, in the grammar, `maxItems` of an array schema, see the
+/// [module docs](self).
+pub const MAX_REPEATED_ITEMS: u64 = 1024;
+
+const SPACE: &str = r#"" "?"#;
+
+/// The rules of the primitive JSON values, added to a grammar when used.
+const PRIMITIVES: &[(&str, &str)] = &[
+    ("boolean", r#"("true" | "false") space"#),
+    ("null", r#""null" space"#),
+    ("integral-part", r#"[0] | [1-9] [0-9]*"#),
+    ("integer", r#""-"? integral-part space"#),
+    (
+        "number",
+        r#""-"? integral-part ("." [0-9]+)? ([eE] [-+]? [0-9]+)? space"#,
+    ),
+    (
+        "char",
+        r#"[^"\\\x7F\x00-\x1F] | "\\" (["\\/bfnrt] | "u" [0-9a-fA-F] [0-9a-fA-F] [0-9a-fA-F] [0-9a-fA-F])"#,
+    ),
+    ("string", r#""\"" char* "\"" space"#),
+    (
+        "value",
+        r#"object | array | string | number | boolean | null"#,
+    ),
+    (
+        "object",
+        r#""{" space (string ":" space value ("," space string ":" space value)*)? "}" space"#,
+    ),
+    (
+        "array",
+        r#""[" space (value ("," space value)*)? "]" space"#,
+    ),
+];
+
+/// The primitive rules each primitive rule refers to.
+fn primitive_dependencies(name: &str) -> &'static [&'static str] {
+    match name {
+        "integer" | "number" => &["integral-part"],
+        "string" => &["char"],
+        "value" => &["object", "array", "string", "number", "boolean", "null"],
+        "object" => &["string", "value"],
+        "array" => &["value"],
+        _ => &[],
+    }
+}
+
+struct Converter<'a> {
+    root: &'a Value,
+    rules: BTreeMap<String, String>,
+    refs: BTreeMap<String, String>,
+}
+
+impl Converter<'_> {
+    fn primitive(&mut self, name: &str) -> String {
+        if !self.rules.contains_key(name) {
+            let (_, rule) = PRIMITIVES
+                .iter()
+                .find(|(primitive, _)| *primitive == name)
+                .expect("known primitive");
+            self.rules.insert(name.to_string(), (*rule).to_string());
+            for dependency in primitive_dependencies(name) {
+                self.primitive(dependency);
+            }
+        }
+        name.to_string()
+    }
+
+    /// Add `rule` under a name derived from `name` that is not taken yet.
+    fn add_rule(&mut self, name: &str, rule: String) -> String {
+        let base: String = name
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+            .collect();
+        let base = if base.is_empty() {
+            "rule".to_string()
+        } else {
+            base
+        };
+        let mut name = base.clone();
+        let mut i = 1;
+        while self
+            .rules
+            .get(&name)
+            .is_some_and(|existing| *existing != rule)
+            || PRIMITIVES.iter().any(|(primitive, _)| *primitive == name)
+        {
+            name = format!("{base}{i}");
+            i += 1;
+        }
+        self.rules.insert(name.clone(), rule);
+        name
+    }
+
+    fn visit(&mut self, schema: &Value, name: &str) -> Result<String, JsonSchemaError> {
+        let schema = match schema {
+            Value::Bool(true) => return Ok(self.primitive("value")),
+            Value::Object(schema) => schema,
+            _ => return Err(JsonSchemaError::InvalidSchema(name.to_string())),
+        };
+
+        if let Some(reference) = schema.get("$ref").and_then(Value::as_str) {
+            return self.visit_ref(reference);
+        }
+        if let Some(value) = schema.get("const") {
+            return Ok(self.add_rule(name, format!("{} space", literal(value))));
+        }
+        if let Some(values) = schema.get("enum").and_then(Value::as_array) {
+            let alternatives: Vec<String> = values.iter().map(literal).collect();
+            return Ok(self.add_rule(name, format!("({}) space", alternatives.join(" | "))));
+        }
+        if let Some(schemas) = schema
+            .get("anyOf")
+            .or_else(|| schema.get("oneOf"))
+            .and_then(Value::as_array)
+        {
+            let alternatives = schemas
+                .iter()
+                .enumerate()
+                .map(|(i, schema)| self.visit(schema, &format!("{name}-{i}")))
+                .collect::<Result<Vec<_>, _>>()?;
+            return Ok(self.add_rule(name, alternatives.join(" | ")));
+        }
+
+        match schema.get("type") {
+            Some(Value::Array(types)) => {
+                let alternatives = types
+                    .iter()
+                    .map(|kind| {
+                        let mut schema = schema.clone();
+                        schema.insert("type".to_string(), kind.clone());
+                        let kind = kind.as_str().unwrap_or_default();
+                        self.visit_typed(&schema, kind, &format!("{name}-{kind}"))
+                    })
+                    .collect::<Result<Vec<_>, _>>()?;
+                Ok(self.add_rule(name, alternatives.join(" | ")))
+            }
+            Some(Value::String(kind)) => self.visit_typed(schema, kind, name),
+            Some(kind) => Err(JsonSchemaError::UnknownType(kind.to_string())),
+            None if schema.contains_key("properties") => self.visit_typed(schema, "object", name),
+            None if schema.contains_key("items") => self.visit_typed(schema, "array", name),
+            None => Ok(self.primitive("value")),
+        }
+    }
+
+    fn visit_ref(&mut self, reference: &str) -> Result<String, JsonSchemaError> {
+        if let Some(rule) = self.refs.get(reference) {
+            return Ok(rule.clone());
+        }
+        let root = self.root;
+        let target = reference
+            .strip_prefix("#/$defs/")
+            .map(|name| ("$defs", name))
+            .or_else(|| {
+                reference
+                    .strip_prefix("#/definitions/")
+                    .map(|name| ("definitions", name))
+            })
+            .and_then(|(defs, name)| Some((root.get(defs)?.get(name)?, name)));
+        let Some((target, def_name)) = target else {
+            return Err(JsonSchemaError::UnresolvedRef(reference.to_string()));
+        };
+
+        // reserve the name first so recursive references resolve to it
+        let rule = self.add_rule(def_name, format!("<{reference}>"));
+        self.refs.insert(reference.to_string(), rule.clone());
+        let body = self.visit(target, &format!("{rule}-def"))?;
+        self.rules.insert(rule.clone(), body);
+        Ok(rule)
+    }
+
+    fn visit_typed(
+        &mut self,
+        schema: &Map<String, Value>,
+        kind: &str,
+        name: &str,
+    ) -> Result<String, JsonSchemaError> {
+        match kind {
+            "string" | "number" | "integer" | "boolean" | "null" => Ok(self.primitive(kind)),
+            "object" => self.visit_object(schema, name),
+            "array" => self.visit_array(schema, name),
+            kind => Err(JsonSchemaError::UnknownType(kind.to_string())),
+        }
+    }
+
+    fn visit_object(
+        &mut self,
+        schema: &Map<String, Value>,
+        name: &str,
+    ) -> Result<String, JsonSchemaError> {
+        let Some(properties) = schema.get("properties").and_then(Value::as_object) else {
+            return Ok(self.primitive("object"));
+        };
+        let required: Vec<&str> = schema
+            .get("required")
+            .and_then(Value::as_array)
+            .map(|required| required.iter().filter_map(Value::as_str).collect())
+            .unwrap_or_default();
+
+        let mut required_kvs = Vec::new();
+        let mut optional_kvs = Vec::new();
+        for (key, property) in properties {
+            let value = self.visit(property, &format!("{name}-{key}"))?;
+            let kv = format!(
+                "{} space \":\" space {value}",
+                literal(&Value::String(key.clone()))
+            );
+            if required.contains(&key.as_str()) {
+                required_kvs.push(kv);
+            } else {
+                optional_kvs.push(kv);
+            }
+        }
+
+        let mut body = String::new();
+        body.push_str(&required_kvs.join(" \",\" space "));
+        if required_kvs.is_empty() {
+            if !optional_kvs.is_empty() {
+                // any subset of the optional properties, in order
+                let alternatives: Vec<String> = (0..optional_kvs.len())
+                    .map(|i| {
+                        let mut alternative = optional_kvs[i].clone();
+                        for kv in &optional_kvs[i + 1..] {
+                            alternative.push_str(&format!(" (\",\" space {kv})?"));
+                        }
+                        alternative
+                    })
+                    .collect();
+                body = format!("({})?", alternatives.join(" | "));
+            }
+        } else {
+            for kv in &optional_kvs {
+                body.push_str(&format!(" (\",\" space {kv})?"));
+            }
+        }
+        Ok(self.add_rule(name, format!("\"{{\" space {body} \"}}\" space")))
+    }
+
+    fn visit_array(
+        &mut self,
+        schema: &Map<String, Value>,
+        name: &str,
+    ) -> Result<String, JsonSchemaError> {
+        let item = match schema.get("items") {
+            Some(items) => self.visit(items, &format!("{name}-item"))?,
+            None => self.primitive("value"),
+        };
+        let min = schema.get("minItems").and_then(Value::as_u64).unwrap_or(0);
+        if min > MAX_REPEATED_ITEMS {
+            return Err(JsonSchemaError::TooManyItems(min));
+        }
+        // spelling out thousands of optional items is no use, leave that bound to `validate`
+        let max = schema
+            .get("maxItems")
+            .and_then(Value::as_u64)
+            .filter(|&max| max <= MAX_REPEATED_ITEMS);
+
+        let required = vec![item.as_str(); usize::try_from(min).expect("min is at most 1024")];
+        let mut body = required.join(" \",\" space ");
+        let separator = |i: u64| if i == 0 { "" } else { "\",\" space " };
+        let optional = match max {
+            // nest the optional items so that each one requires the previous one
+            Some(max) => (min..max).rev().fold(String::new(), |tail, i| {
+                let sep = separator(i);
+                if tail.is_empty() {
+                    format!("({sep}{item})?")
+                } else {
+                    format!("({sep}{item} {tail})?")
+                }
+            }),
+            None if min == 0 => format!("({item} (\",\" space {item})*)?"),
+            None => format!("(\",\" space {item})*"),
+        };
+        if !optional.is_empty() {
+            if !body.is_empty() {
+                body.push(' ');
+            }
+            body.push_str(&optional);
+        }
+        Ok(self.add_rule(name, format!("\"[\" space {body} \"]\" space")))
+    }
+}
+
+/// `value` serialized as JSON, as a GBNF string literal.
+fn literal(value: &Value) -> String {
+    let json = value.to_string();
+    let mut literal = String::with_capacity(json.len() + 2);
+    literal.push('"');
+    for c in json.chars() {
+        match c {
+            '"' => literal.push_str("\\\""),
+            '\\' => literal.push_str("\\\\"),
+            '\n' => literal.push_str("\\n"),
+            '\r' => literal.push_str("\\r"),
+            '\t' => literal.push_str("\\t"),
+            c => literal.push(c),
+        }
+    }
+    literal.push('"');
+    literal
+}
+
+/// Convert a JSON schema to a GBNF grammar whose `root` rule matches JSON values of the schema.
+///
+/// # Errors
+///
+/// See [`JsonSchemaError`].
+pub fn to_gbnf(schema: &Value) -> Result<String, JsonSchemaError> {
+    let mut converter = Converter {
+        root: schema,
+        rules: BTreeMap::new(),
+        refs: BTreeMap::new(),
+    };
+    converter
+        .rules
+        .insert("space".to_string(), SPACE.to_string());
+    let root = converter.visit(schema, "root")?;
+    if root != "root" {
+        converter.rules.insert("root".to_string(), root);
+    }
+
+    let mut gbnf = format!("root ::= {}\n", converter.rules["root"]);
+    for (name, rule) in &converter.rules {
+        if name != "root" {
+            gbnf.push_str(&format!("{name} ::= {rule}\n"));
+        }
+    }
+    Ok(gbnf)
+}
+
+/// A way in which a JSON value does not match a schema.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SchemaViolation {
+    /// A JSON pointer to the offending value, empty for the root.
+    pub path: String,
+    /// What is wrong with the value.
+    pub message: String,
+}
+
+impl Display for SchemaViolation {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if self.path.is_empty() {
+            write!(f, "{}", self.message)
+        } else {
+            write!(f, "at {}: {}", self.path, self.message)
+        }
+    }
+}
+
+fn type_matches(kind: &str, value: &Value) -> bool {
+    match kind {
+        "object" => value.is_object(),
+        "array" => value.is_array(),
+        "string" => value.is_string(),
+        "number" => value.is_number(),
+        "integer" => {
+            value.is_i64()
+                || value.is_u64()
+                || value.as_f64().is_some_and(|number| number.fract() == 0.0)
+        }
+        "boolean" => value.is_boolean(),
+        "null" => value.is_null(),
+        _ => true,
+    }
+}
+
+struct Validator<'a> {
+    root: &'a Value,
+    violations: Vec<SchemaViolation>,
+}
+
+impl Validator<'_> {
+    fn violation(&mut self, path: &str, message: String) {
+        self.violations.push(SchemaViolation {
+            path: path.to_string(),
+            message,
+        });
+    }
+
+    fn resolve<'s>(&'s self, schema: &'s Value) -> Option<&'s Value> {
+        let Some(reference) = schema.get("$ref").and_then(Value::as_str) else {
+            return Some(schema);
+        };
+        let pointer = reference.strip_prefix('#')?;
+        self.root.pointer(pointer)
+    }
+
+    fn check(&mut self, schema: &Value, value: &Value, path: &str) {
+        let Some(schema) = self.resolve(schema).cloned() else {
+            self.violation(
+                path,
+                format!("unresolvable schema reference {}", schema["$ref"]),
+            );
+            return;
+        };
+        let Value::Object(schema) = schema else {
+            if schema == Value::Bool(false) {
+                self.violation(path, "no value is allowed here".to_string());
+            }
+            return;
+        };
+
+        if let Some(expected) = schema.get("const") {
+            if expected != value {
+                self.violation(path, format!("expected {expected}, got {value}"));
+            }
+        }
+        if let Some(values) = schema.get("enum").and_then(Value::as_array) {
+            if !values.contains(value) {
+                let allowed = Value::Array(values.clone());
+                self.violation(path, format!("{value} is not one of {allowed}"));
+            }
+        }
+        if let Some(schemas) = schema
+            .get("anyOf")
+            .or_else(|| schema.get("oneOf"))
+            .and_then(Value::as_array)
+        {
+            let root = self.root;
+            let matches = schemas
+                .iter()
+                .any(|schema| validate_at(root, schema, value, path).is_empty());
+            if !matches {
+                self.violation(
+                    path,
+                    "does not match any of the allowed schemas".to_string(),
+                );
+            }
+        }
+
+        let kinds: Vec<&str> = match schema.get("type") {
+            Some(Value::String(kind)) => vec![kind.as_str()],
+            Some(Value::Array(kinds)) => kinds.iter().filter_map(Value::as_str).collect(),
+            _ => Vec::new(),
+        };
+        if !kinds.is_empty() && !kinds.iter().any(|kind| type_matches(kind, value)) {
+            self.violation(
+                path,
+                format!("expected {}, got {value}", kinds.join(" or ")),
+            );
+            return;
+        }
+
+        match value {
+            Value::Object(object) => self.check_object(&schema, object, path),
+            Value::Array(items) => self.check_array(&schema, items, path),
+            Value::String(string) => {
+                let len = string.chars().count() as u64;
+                self.check_bounds(&schema, ("minLength", "maxLength"), len, "characters", path);
+            }
+            Value::Number(number) => {
+                let number = number.as_f64().unwrap_or(f64::NAN);
+                if let Some(min) = schema.get("minimum").and_then(Value::as_f64) {
+                    if number < min {
+                        self.violation(path, format!("expected at least {min}, got {number}"));
+                    }
+                }
+                if let Some(max) = schema.get("maximum").and_then(Value::as_f64) {
+                    if number > max {
+                        self.violation(path, format!("expected at most {max}, got {number}"));
+                    }
+                }
+            }
+            Value::Bool(_) | Value::Null => {}
+        }
+    }
+
+    fn check_object(
+        &mut self,
+        schema: &Map<String, Value>,
+        object: &Map<String, Value>,
+        path: &str,
+    ) {
+        if let Some(required) = schema.get("required").and_then(Value::as_array) {
+            for key in required.iter().filter_map(Value::as_str) {
+                if !object.contains_key(key) {
+                    self.violation(path, format!("missing required property {key:?}"));
+                }
+            }
+        }
+        let properties = schema.get("properties").and_then(Value::as_object);
+        for (key, property) in object {
+            let child = format!("{path}/{}", key.replace('~', "~0").replace('/', "~1"));
+            match properties.and_then(|properties| properties.get(key)) {
+                Some(property_schema) => self.check(property_schema, property, &child),
+                None => match schema.get("additionalProperties") {
+                    Some(Value::Bool(false)) => {
+                        self.violation(path, format!("unexpected property {key:?}"));
+                    }
+                    Some(additional @ Value::Object(_)) => self.check(additional, property, &child),
+                    _ => {}
+                },
+            }
+        }
+    }
+
+    fn check_array(&mut self, schema: &Map<String, Value>, items: &[Value], path: &str) {
+        self.check_bounds(
+            schema,
+            ("minItems", "maxItems"),
+            items.len() as u64,
+            "items",
+            path,
+        );
+        if let Some(item_schema) = schema.get("items") {
+            for (i, item) in items.iter().enumerate() {
+                self.check(item_schema, item, &format!("{path}/{i}"));
+            }
+        }
+    }
+
+    /// Check a length against the `(min, max)` keywords of `schema`.
+    fn check_bounds(
+        &mut self,
+        schema: &Map<String, Value>,
+        (min, max): (&str, &str),
+        len: u64,
+        unit: &str,
+        path: &str,
+    ) {
+        if let Some(min) = schema.get(min).and_then(Value::as_u64) {
+            if len < min {
+                self.violation(path, format!("expected at least {min} {unit}, got {len}"));
+            }
+        }
+        if let Some(max) = schema.get(max).and_then(Value::as_u64) {
+            if len > max {
+                self.violation(path, format!("expected at most {max} {unit}, got {len}"));
+            }
+        }
+    }
+}
+
+/// The violations of `value` against `schema`, a subschema of `root`.
+fn validate_at(root: &Value, schema: &Value, value: &Value, path: &str) -> Vec<SchemaViolation> {
+    let mut validator = Validator {
+        root,
+        violations: Vec::new(),
+    };
+    validator.check(schema, value, path);
+    validator.violations
+}
+
+/// Check `value` against `schema`.
+///
+/// # Errors
+///
+/// Every way in which `value` does not match `schema`.
+pub fn validate(schema: &Value, value: &Value) -> Result<(), Vec<SchemaViolation>> {
+    let violations = validate_at(schema, schema, value, "");
+    if violations.is_empty() {
+        Ok(())
+    } else {
+        Err(violations)
+    }
+}
+
+#[cfg(test)]
+mod tests;
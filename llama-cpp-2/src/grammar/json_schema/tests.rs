@@ -0,0 +1,161 @@
+use serde_json::json;
+
+use super::*;
+use crate::grammar;
+
+fn gbnf(schema: &Value) -> String {
+    let gbnf = to_gbnf(schema).unwrap();
+    grammar::validate(&gbnf).unwrap_or_else(|err| panic!("{err}\n{gbnf}"));
+    gbnf
+}
+
+#[test]
+fn object_with_required_and_optional_properties() {
+    let gbnf = gbnf(&json!({
+        "type": "object",
+        "properties": {
+            "a": { "type": "string" },
+            "b": { "type": "integer" },
+            "c": { "type": "boolean" },
+        },
+        "required": ["a", "c"],
+    }));
+    assert!(gbnf.starts_with("root ::= "), "{gbnf}");
+    assert!(
+        gbnf.contains(r#""\"b\"" space ":" space integer"#),
+        "{gbnf}"
+    );
+}
+
+#[test]
+fn only_optional_properties() {
+    gbnf(&json!({
+        "properties": { "a": { "type": "number" }, "b": { "type": "null" } },
+    }));
+}
+
+#[test]
+fn arrays() {
+    gbnf(&json!({ "type": "array", "items": { "type": "string" } }));
+    gbnf(&json!({ "type": "array", "items": { "type": "string" }, "minItems": 2 }));
+    let bounded = gbnf(&json!({ "items": { "type": "integer" }, "minItems": 1, "maxItems": 3 }));
+    assert!(
+        bounded.starts_with(
+            r#"root ::= "[" space integer ("," space integer ("," space integer)?)? "]" space"#
+        ),
+        "{bounded}"
+    );
+}
+
+#[test]
+fn huge_item_counts() {
+    assert_eq!(
+        to_gbnf(&json!({ "type": "array", "minItems": 4_000_000_000_u64 })),
+        Err(JsonSchemaError::TooManyItems(4_000_000_000))
+    );
+    // a huge maxItems is left to `validate`
+    let schema = json!({ "type": "array", "items": { "type": "integer" }, "maxItems": u64::MAX });
+    let unbounded = gbnf(&schema);
+    assert!(
+        unbounded.starts_with(r#"root ::= "[" space (integer ("," space integer)*)? "]" space"#),
+        "{unbounded}"
+    );
+    assert!(validate(&schema, &json!([1, 2, 3])).is_ok());
+}
+
+#[test]
+fn only_listed_properties_are_generated() {
+    let schema = json!({ "type": "object", "properties": { "a": { "type": "integer" } } });
+    assert!(!gbnf(&schema).contains("value"));
+    // validation follows JSON schema
+    assert!(validate(&schema, &json!({ "a": 1, "b": 2 })).is_ok());
+    let closed = json!({
+        "type": "object",
+        "properties": { "a": { "type": "integer" } },
+        "additionalProperties": false,
+    });
+    assert!(validate(&closed, &json!({ "a": 1, "b": 2 })).is_err());
+}
+
+#[test]
+fn enums_consts_and_unions() {
+    let gbnf = gbnf(&json!({
+        "anyOf": [
+            { "enum": ["red", "green", 1, null] },
+            { "const": { "ok": true } },
+            { "type": ["string", "null"] },
+        ],
+    }));
+    assert!(
+        gbnf.contains(r#""\"red\"" | "\"green\"" | "1" | "null""#),
+        "{gbnf}"
+    );
+    assert!(gbnf.contains(r#""{\"ok\":true}""#), "{gbnf}");
+}
+
+#[test]
+fn recursive_refs() {
+    let gbnf = gbnf(&json!({
+        "$ref": "#/$defs/node",
+        "$defs": {
+            "node": {
+                "type": "object",
+                "properties": {
+                    "value": { "type": "integer" },
+                    "children": { "type": "array", "items": { "$ref": "#/$defs/node" } },
+                },
+                "required": ["value"],
+            },
+        },
+    }));
+    assert!(gbnf.contains("node ::= "), "{gbnf}");
+}
+
+#[test]
+fn unresolved_ref() {
+    assert_eq!(
+        to_gbnf(&json!({ "$ref": "#/$defs/missing" })),
+        Err(JsonSchemaError::UnresolvedRef(
+            "#/$defs/missing".to_string()
+        ))
+    );
+    assert_eq!(
+        to_gbnf(&json!({ "type": "date" })),
+        Err(JsonSchemaError::UnknownType("date".to_string()))
+    );
+}
+
+#[test]
+fn validate_reports_every_violation() {
+    let schema = json!({
+        "type": "object",
+        "properties": {
+            "name": { "type": "string", "maxLength": 3 },
+            "tags": { "type": "array", "items": { "type": "string" }, "maxItems": 1 },
+        },
+        "required": ["name", "id"],
+    });
+    let violations = validate(&schema, &json!({ "name": "long", "tags": ["a", 2] })).unwrap_err();
+    let messages: Vec<String> = violations.iter().map(ToString::to_string).collect();
+    assert_eq!(
+        messages,
+        [
+            "missing required property \"id\"",
+            "at /name: expected at most 3 characters, got 4",
+            "at /tags: expected at most 1 items, got 2",
+            "at /tags/1: expected string, got 2",
+        ]
+    );
+}
+
+#[test]
+fn validate_refs_and_unions() {
+    let schema = json!({
+        "$defs": { "id": { "type": "integer", "minimum": 1 } },
+        "anyOf": [{ "$ref": "#/$defs/id" }, { "type": "null" }],
+    });
+    assert!(validate(&schema, &json!(3)).is_ok());
+    assert!(validate(&schema, &json!(null)).is_ok());
+    assert!(validate(&schema, &json!(0)).is_err());
+    assert!(validate(&schema, &json!("3")).is_err());
+}
@@ -0,0 +1,211 @@
+//! Remember which tokens a grammar allows, instead of checking the whole vocabulary every step.
+//!
+//! Applying a grammar checks every token of the vocabulary against the grammar's parse stacks,
+//! which on models with large vocabularies (128k tokens and more) costs more than the decode.
+//! A [`TokenMaskCache`] attached to a [`CompiledGrammar`] stores the set of allowed tokens once
+//! it has been computed, keyed by the tokens the grammar accepted since it was compiled, so
+//! later generations with that grammar skip the check for every state an earlier one already
+//! reached. This pays off when many generations share a grammar and begin the same way, such as
+//! the keys and punctuation of a fixed JSON schema or a choice between a few labels:
+//!
+//! ```
+//! # use std::num::NonZeroUsize;
+//! # use llama_cpp_2::grammar::cache::CompiledGrammar;
+//! # use llama_cpp_2::generate::GenerationParams;
+//! let grammar = CompiledGrammar::new(r#"root ::= "positive" | "negative""#)?
+//!     .with_mask_cache(NonZeroUsize::new(4096).unwrap());
+//! for _review in 0..3 {
+//!     let params = GenerationParams::default().with_grammar(grammar.instantiate());
+//!     // ... generate with `params`
+//! }
+//! let masks = grammar.mask_cache().unwrap();
+//! println!("{} of {} masks reused", masks.hits(), masks.hits() + masks.misses());
+//! # Ok::<(), llama_cpp_2::grammar::LlamaGrammarFromStrError>(())
+//! ```
+//!
+//! Within one generation the grammar rarely returns to a state it was in, so on its own the
+//! cache does not speed up the first generation. For that, sample
+//! [lazily](crate::generate::GenerationParams::with_lazy_grammar), which only checks the whole
+//! vocabulary when the token sampled without the grammar is not allowed.
+//!
+//! A cache belongs to one model: the masks are sets of token ids, and a cache used with a model
+//! of a different vocabulary size starts over.
+//!
+//! [`CompiledGrammar`]: super::cache::CompiledGrammar
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::num::NonZeroUsize;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
+use crate::token::data_array::LlamaTokenDataArray;
+use crate::token::LlamaToken;
+
+/// A least recently used cache of the tokens a grammar allows in each state it reached, see the
+/// [module docs](self). It can be shared between threads.
+#[derive(Debug)]
+pub struct TokenMaskCache {
+    capacity: NonZeroUsize,
+    state: Mutex<CacheState>,
+}
+
+#[derive(Debug, Default)]
+struct CacheState {
+    /// The masks by grammar state and when they were last used.
+    masks: HashMap<u64, (Arc<TokenMask>, u64)>,
+    clock: u64,
+    hits: u64,
+    misses: u64,
+}
+
+const _: () = {
+    const fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<TokenMaskCache>();
+};
+
+impl TokenMaskCache {
+    /// An empty cache that keeps at most `capacity` masks. A mask takes one bit per token of
+    /// the vocabulary, 16 KiB for a vocabulary of 128k tokens.
+    #[must_use]
+    pub fn new(capacity: NonZeroUsize) -> Self {
+        Self {
+            capacity,
+            state: Mutex::new(CacheState::default()),
+        }
+    }
+
+    /// The maximum number of cached masks.
+    #[must_use]
+    pub fn capacity(&self) -> NonZeroUsize {
+        self.capacity
+    }
+
+    /// The number of cached masks.
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.lock().masks.len()
+    }
+
+    /// Whether no mask is cached.
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The number of grammar applications answered from the cache.
+    #[must_use]
+    pub fn hits(&self) -> u64 {
+        self.lock().hits
+    }
+
+    /// The number of grammar applications that had to check the whole vocabulary.
+    #[must_use]
+    pub fn misses(&self) -> u64 {
+        self.lock().misses
+    }
+
+    /// Remove every cached mask. The hit and miss counts are kept.
+    pub fn clear(&self) {
+        self.lock().masks.clear();
+    }
+
+    /// The mask of grammar state `state` for a vocabulary of `n_vocab` tokens, if it is cached.
+    pub(crate) fn get(&self, state: u64, n_vocab: usize) -> Option<Arc<TokenMask>> {
+        let mut cache = self.lock();
+        cache.clock += 1;
+        let now = cache.clock;
+        let found = match cache.masks.get_mut(&state) {
+            Some((mask, last_used)) if mask.n_vocab == n_vocab => {
+                *last_used = now;
+                Some(Arc::clone(mask))
+            }
+            _ => None,
+        };
+        if found.is_some() {
+            cache.hits += 1;
+        } else {
+            cache.misses += 1;
+        }
+        found
+    }
+
+    /// Cache the mask of grammar state `state`, evicting the least recently used mask if the
+    /// cache is full.
+    pub(crate) fn insert(&self, state: u64, mask: Arc<TokenMask>) {
+        let mut cache = self.lock();
+        if !cache.masks.contains_key(&state) && cache.masks.len() >= self.capacity.get() {
+            let oldest = cache
+                .masks
+                .iter()
+                .min_by_key(|(_, (_, last_used))| *last_used)
+                .map(|(state, _)| *state);
+            if let Some(oldest) = oldest {
+                cache.masks.remove(&oldest);
+            }
+        }
+        let now = cache.clock;
+        cache.masks.insert(state, (mask, now));
+    }
+
+    fn lock(&self) -> MutexGuard<'_, CacheState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// The state a grammar in state `state` is in after accepting `token`. The start state is `0`.
+pub(crate) fn advance(state: u64, token: LlamaToken) -> u64 {
+    // `DefaultHasher::new` is not randomly seeded, so states are the same in every grammar copy
+    let mut hasher = DefaultHasher::new();
+    state.hash(&mut hasher);
+    token.0.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// The tokens a grammar allows in one state, one bit per token id.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct TokenMask {
+    n_vocab: usize,
+    bits: Vec<u64>,
+}
+
+impl TokenMask {
+    /// The tokens of `candidates` the grammar did not rule out, after the grammar was applied to
+    /// every token of a vocabulary of `n_vocab` tokens.
+    pub(crate) fn from_candidates(n_vocab: usize, candidates: &LlamaTokenDataArray) -> Self {
+        let mut bits = vec![0; n_vocab.div_ceil(64)];
+        for data in &candidates.data {
+            if data.logit() <= f32::NEG_INFINITY {
+                continue;
+            }
+            if let Ok(id) = usize::try_from(data.id().0) {
+                if let Some(word) = bits.get_mut(id / 64) {
+                    *word |= 1 << (id % 64);
+                }
+            }
+        }
+        Self { n_vocab, bits }
+    }
+
+    /// Whether the grammar allows `token`.
+    pub(crate) fn allows(&self, token: LlamaToken) -> bool {
+        usize::try_from(token.0).is_ok_and(|id| {
+            self.bits
+                .get(id / 64)
+                .is_some_and(|word| word & (1 << (id % 64)) != 0)
+        })
+    }
+
+    /// Rule out the tokens of `candidates` the grammar does not allow, as applying the grammar
+    /// would.
+    pub(crate) fn apply(&self, candidates: &mut LlamaTokenDataArray) {
+        for data in &mut candidates.data {
+            if !self.allows(data.id()) {
+                data.set_logit(f32::NEG_INFINITY);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests;
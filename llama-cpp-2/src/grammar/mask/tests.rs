@@ -0,0 +1,69 @@
+use super::*;
+use crate::token::data::LlamaTokenData;
+
+fn candidates(logits: &[f32]) -> LlamaTokenDataArray {
+    LlamaTokenDataArray::from_logits(logits)
+}
+
+fn mask_of(allowed: &[i32], n_vocab: usize) -> Arc<TokenMask> {
+    let logits: Vec<f32> = (0..n_vocab)
+        .map(|id| {
+            if allowed.contains(&i32::try_from(id).unwrap()) {
+                0.0
+            } else {
+                f32::NEG_INFINITY
+            }
+        })
+        .collect();
+    Arc::new(TokenMask::from_candidates(n_vocab, &candidates(&logits)))
+}
+
+#[test]
+fn masks_rule_out_what_the_grammar_did() {
+    let mask = mask_of(&[0, 63, 64, 129], 130);
+    for id in [0, 63, 64, 129] {
+        assert!(mask.allows(LlamaToken(id)), "{id}");
+    }
+    for id in [1, 62, 65, 128, 130, -1] {
+        assert!(!mask.allows(LlamaToken(id)), "{id}");
+    }
+
+    let mut candidates = LlamaTokenDataArray::from_iter(
+        [129, 2, 0].map(|id| LlamaTokenData::new(LlamaToken(id), 1.0, 0.0)),
+        false,
+    );
+    mask.apply(&mut candidates);
+    let logits: Vec<f32> = candidates.data.iter().map(LlamaTokenData::logit).collect();
+    assert_eq!(logits, [1.0, f32::NEG_INFINITY, 1.0]);
+}
+
+#[test]
+fn states_depend_on_every_accepted_token() {
+    let (a, b) = (LlamaToken(1), LlamaToken(2));
+    assert_eq!(advance(advance(0, a), b), advance(advance(0, a), b));
+    assert_ne!(advance(advance(0, a), b), advance(advance(0, b), a));
+    assert_ne!(advance(0, a), 0);
+}
+
+#[test]
+fn evicts_the_least_recently_used_mask() {
+    let cache = TokenMaskCache::new(NonZeroUsize::new(2).unwrap());
+    assert!(cache.get(1, 8).is_none());
+    cache.insert(1, mask_of(&[1], 8));
+    cache.insert(2, mask_of(&[2], 8));
+    // 1 is now more recent than 2, so 3 replaces 2
+    assert!(cache.get(1, 8).is_some());
+    cache.insert(3, mask_of(&[3], 8));
+    assert_eq!(cache.len(), 2);
+    assert!(cache.get(2, 8).is_none());
+    assert!(cache.get(3, 8).unwrap().allows(LlamaToken(3)));
+    assert_eq!((cache.hits(), cache.misses()), (2, 2));
+}
+
+#[test]
+fn ignores_masks_of_another_vocabulary() {
+    let cache = TokenMaskCache::new(NonZeroUsize::new(2).unwrap());
+    cache.insert(1, mask_of(&[1], 8));
+    assert!(cache.get(1, 16).is_none());
+    assert!(cache.get(1, 8).is_some());
+}
@@ -0,0 +1,777 @@
+//! Constrain generation to a regular expression.
+//!
+//! [`Regex`] compiles a pattern to a DFA over characters. [`RegexConstraint`] walks the text of
+//! every token in the vocabulary through the DFA and masks the logits of the tokens that cannot
+//! continue a match, so the output always matches the pattern when the model ends it. It is a
+//! [`LogitsProcessor`] and plugs into
+//! [`GenerationParams::with_logits_processor`](crate::generate::GenerationParams::with_logits_processor).
+//!
+//! The syntax is the subset shared by most regex engines: literals, `.`, character classes
+//! (`[a-z]`, `[^"]`, `\d`, `\w`, `\s` and their negations), groups (`(...)` and `(?:...)`),
+//! alternation and the quantifiers `*`, `+`, `?`, `{n}`, `{n,}` and `{n,m}`. The pattern always
+//! matches the whole output, a leading `^` and a trailing `$` are allowed but change nothing.
+//! Backreferences, lookaround, word boundaries and flags are not supported.
+//!
+//! ```no_run
+//! # use std::ops::ControlFlow;
+//! # use llama_cpp_2::context::LlamaContext;
+//! # use llama_cpp_2::generate::GenerationParams;
+//! # use llama_cpp_2::grammar::regex::{Regex, RegexConstraint};
+//! # use llama_cpp_2::model::AddBos;
+//! # fn run(ctx: &mut LlamaContext) -> Result<(), Box<dyn std::error::Error>> {
+//! let phone = Regex::new(r"\(\d{3}\) \d{3}-\d{4}")?;
+//! let prompt = ctx.model.str_to_token("Call us at", AddBos::Always)?;
+//! let mut params = GenerationParams::default()
+//!     .with_logits_processor(RegexConstraint::new(ctx.model, phone));
+//! let generation = ctx.generate(&prompt, &mut params, |_| ControlFlow::Continue(()))?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::collections::HashMap;
+use std::fmt::{Debug, Formatter};
+use std::str::FromStr;
+
+use crate::context::sample::logits_processor::LogitsProcessor;
+use crate::model::{LlamaModel, Special};
+use crate::token::LlamaToken;
+
+/// The largest code point.
+const MAX_CHAR: u32 = char::MAX as u32;
+
+/// The most DFA states a pattern may compile to.
+const MAX_STATES: usize = 10_000;
+
+/// The largest count a bounded quantifier may have.
+const MAX_REPEAT: u32 = 1_000;
+
+/// The target of a DFA transition that cannot lead to a match.
+const DEAD: usize = usize::MAX;
+
+/// A pattern could not be compiled.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum RegexError {
+    /// The pattern is not a valid regex.
+    #[error("invalid regex at character {position}: {message}")]
+    Syntax {
+        /// The index of the character (not byte) the error was found at.
+        position: usize,
+        /// What is wrong.
+        message: &'static str,
+    },
+    /// The pattern uses a feature that cannot be expressed as a DFA or is not implemented.
+    #[error("unsupported regex feature at character {position}: {feature}")]
+    Unsupported {
+        /// The index of the character (not byte) the feature starts at.
+        position: usize,
+        /// The feature.
+        feature: &'static str,
+    },
+    /// The pattern compiles to more DFA states than the given limit.
+    #[error("the regex needs more than {0} DFA states")]
+    TooComplex(usize),
+    /// No string matches the pattern.
+    #[error("no string matches the regex")]
+    Unsatisfiable,
+}
+
+/// A parsed pattern. Characters are `u32` so that classes can be complemented without caring
+/// about the surrogate range.
+#[derive(Debug, Clone)]
+enum Node {
+    /// One character out of sorted, non-overlapping inclusive ranges.
+    Class(Vec<(u32, u32)>),
+    /// The nodes one after another, the empty string if there are none.
+    Concat(Vec<Node>),
+    /// One of the nodes.
+    Alt(Vec<Node>),
+    /// The node repeated between `min` and `max` (unbounded if `None`) times.
+    Repeat(Box<Node>, u32, Option<u32>),
+}
+
+/// Sort and merge `ranges`.
+fn normalize(mut ranges: Vec<(u32, u32)>) -> Vec<(u32, u32)> {
+    ranges.sort_unstable();
+    let mut merged: Vec<(u32, u32)> = Vec::with_capacity(ranges.len());
+    for (lo, hi) in ranges {
+        match merged.last_mut() {
+            Some(last) if lo <= last.1.saturating_add(1) => last.1 = last.1.max(hi),
+            _ => merged.push((lo, hi)),
+        }
+    }
+    merged
+}
+
+/// Every character not in `ranges`.
+fn complement(ranges: &[(u32, u32)]) -> Vec<(u32, u32)> {
+    let mut gaps = Vec::new();
+    let mut next = 0;
+    for &(lo, hi) in &normalize(ranges.to_vec()) {
+        if lo > next {
+            gaps.push((next, lo - 1));
+        }
+        next = hi.saturating_add(1);
+    }
+    if next <= MAX_CHAR {
+        gaps.push((next, MAX_CHAR));
+    }
+    gaps
+}
+
+/// A single character as a class.
+fn single(c: char) -> Vec<(u32, u32)> {
+    vec![(u32::from(c), u32::from(c))]
+}
+
+/// A recursive descent parser for patterns.
+struct Parser {
+    chars: Vec<char>,
+    position: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.position).copied()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.peek();
+        self.position += usize::from(c.is_some());
+        c
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        let eaten = self.peek() == Some(c);
+        self.position += usize::from(eaten);
+        eaten
+    }
+
+    fn syntax(&self, message: &'static str) -> RegexError {
+        RegexError::Syntax {
+            position: self.position,
+            message,
+        }
+    }
+
+    fn unsupported(start: usize, feature: &'static str) -> RegexError {
+        RegexError::Unsupported {
+            position: start,
+            feature,
+        }
+    }
+
+    /// `concat ('|' concat)*`
+    fn alternatives(&mut self) -> Result<Node, RegexError> {
+        let mut branches = vec![self.concat()?];
+        while self.eat('|') {
+            branches.push(self.concat()?);
+        }
+        Ok(if branches.len() == 1 {
+            branches.swap_remove(0)
+        } else {
+            Node::Alt(branches)
+        })
+    }
+
+    /// `repeat*`
+    fn concat(&mut self) -> Result<Node, RegexError> {
+        let mut nodes = Vec::new();
+        while !matches!(self.peek(), None | Some('|' | ')')) {
+            nodes.push(self.repeat()?);
+        }
+        Ok(Node::Concat(nodes))
+    }
+
+    /// `atom quantifier*`
+    fn repeat(&mut self) -> Result<Node, RegexError> {
+        let mut node = self.atom()?;
+        while let Some(quantifier @ ('*' | '+' | '?' | '{')) = self.peek() {
+            self.position += 1;
+            let (min, max) = match quantifier {
+                '*' => (0, None),
+                '+' => (1, None),
+                '?' => (0, Some(1)),
+                _ => self.counts()?,
+            };
+            // lazy quantifiers match the same set of complete strings
+            self.eat('?');
+            node = Node::Repeat(Box::new(node), min, max);
+        }
+        Ok(node)
+    }
+
+    /// The counts of a `{n}`, `{n,}` or `{n,m}` quantifier, after the `{`.
+    fn counts(&mut self) -> Result<(u32, Option<u32>), RegexError> {
+        let min = self.number()?;
+        let max = if self.eat(',') {
+            if self.peek() == Some('}') {
+                None
+            } else {
+                Some(self.number()?)
+            }
+        } else {
+            Some(min)
+        };
+        if !self.eat('}') {
+            return Err(self.syntax("expected `}`"));
+        }
+        if max.is_some_and(|max| max < min) {
+            return Err(self.syntax("the minimum count is larger than the maximum"));
+        }
+        if max.unwrap_or(min) > MAX_REPEAT {
+            return Err(self.syntax("counts larger than 1000 are not supported"));
+        }
+        Ok((min, max))
+    }
+
+    fn number(&mut self) -> Result<u32, RegexError> {
+        let start = self.position;
+        while self.peek().is_some_and(|c| c.is_ascii_digit()) {
+            self.position += 1;
+        }
+        self.chars[start..self.position]
+            .iter()
+            .collect::<String>()
+            .parse()
+            .map_err(|_| self.syntax("expected a count"))
+    }
+
+    fn atom(&mut self) -> Result<Node, RegexError> {
+        let start = self.position;
+        let Some(c) = self.next() else {
+            return Err(self.syntax("unexpected end of the pattern"));
+        };
+        Ok(match c {
+            '(' => {
+                if self.eat('?') && !self.eat(':') {
+                    return Err(Self::unsupported(start, "lookaround and group flags"));
+                }
+                let node = self.alternatives()?;
+                if !self.eat(')') {
+                    return Err(self.syntax("unclosed group"));
+                }
+                node
+            }
+            '[' => Node::Class(self.class()?),
+            '.' => Node::Class(complement(&single('\n'))),
+            '^' if self.position == 1 => Node::Concat(Vec::new()),
+            '$' if self.peek().is_none() => Node::Concat(Vec::new()),
+            '^' | '$' => return Err(Self::unsupported(start, "anchors inside the pattern")),
+            '\\' => Node::Class(self.escape()?),
+            '*' | '+' | '?' | '{' => return Err(self.syntax("nothing to repeat")),
+            c => Node::Class(single(c)),
+        })
+    }
+
+    /// The characters of a class, after the `[`.
+    fn class(&mut self) -> Result<Vec<(u32, u32)>, RegexError> {
+        let negated = self.eat('^');
+        let mut ranges = Vec::new();
+        let mut first = true;
+        loop {
+            let Some(c) = self.next() else {
+                return Err(self.syntax("unclosed character class"));
+            };
+            let lo = match c {
+                ']' if !first => break,
+                '\\' => {
+                    let set = self.escape()?;
+                    match set[..] {
+                        [(lo, hi)] if lo == hi => lo,
+                        _ => {
+                            ranges.extend(set);
+                            first = false;
+                            continue;
+                        }
+                    }
+                }
+                c => u32::from(c),
+            };
+            first = false;
+            let is_range = self.peek() == Some('-')
+                && !matches!(self.chars.get(self.position + 1), None | Some(']'));
+            if !is_range {
+                ranges.push((lo, lo));
+                continue;
+            }
+            self.position += 1;
+            let hi = match self.next() {
+                Some('\\') => match self.escape()?[..] {
+                    [(hi, hi2)] if hi == hi2 => hi,
+                    _ => return Err(self.syntax("a range cannot end with a class")),
+                },
+                Some(c) => u32::from(c),
+                None => return Err(self.syntax("unclosed character class")),
+            };
+            if hi < lo {
+                return Err(self.syntax("range out of order"));
+            }
+            ranges.push((lo, hi));
+        }
+        Ok(if negated {
+            complement(&ranges)
+        } else {
+            normalize(ranges)
+        })
+    }
+
+    /// The characters of an escape, after the `\`.
+    fn escape(&mut self) -> Result<Vec<(u32, u32)>, RegexError> {
+        let digits = vec![(u32::from('0'), u32::from('9'))];
+        let word = normalize(vec![
+            (u32::from('0'), u32::from('9')),
+            (u32::from('A'), u32::from('Z')),
+            (u32::from('_'), u32::from('_')),
+            (u32::from('a'), u32::from('z')),
+        ]);
+        let space = vec![(0x09, 0x0D), (0x20, 0x20)];
+        let start = self.position.saturating_sub(1);
+        let Some(c) = self.next() else {
+            return Err(self.syntax("unexpected end of the pattern"));
+        };
+        Ok(match c {
+            'd' => digits,
+            'D' => complement(&digits),
+            'w' => word,
+            'W' => complement(&word),
+            's' => space,
+            'S' => complement(&space),
+            'n' => single('\n'),
+            't' => single('\t'),
+            'r' => single('\r'),
+            'f' => single('\x0C'),
+            'v' => single('\x0B'),
+            '0' => single('\0'),
+            'x' => self.hex(2)?,
+            'u' => self.hex(4)?,
+            'b' | 'B' => return Err(Self::unsupported(start, "word boundaries")),
+            '1'..='9' => return Err(Self::unsupported(start, "backreferences")),
+            'p' | 'P' => return Err(Self::unsupported(start, "unicode classes")),
+            c if c.is_ascii_alphanumeric() => return Err(self.syntax("unknown escape")),
+            c => single(c),
+        })
+    }
+
+    /// A character given by `len` hex digits.
+    fn hex(&mut self, len: usize) -> Result<Vec<(u32, u32)>, RegexError> {
+        let digits: String = self.chars.iter().skip(self.position).take(len).collect();
+        let c = Some(&digits)
+            .filter(|digits| digits.len() == len && digits.chars().all(|c| c.is_ascii_hexdigit()))
+            .and_then(|digits| u32::from_str_radix(digits, 16).ok())
+            .and_then(char::from_u32)
+            .ok_or_else(|| self.syntax("invalid hex escape"))?;
+        self.position += len;
+        Ok(single(c))
+    }
+}
+
+/// A state of the NFA.
+#[derive(Debug, Clone)]
+enum NfaState {
+    /// Consume a character of the class and go to the state.
+    Class(Vec<(u32, u32)>, usize),
+    /// Go to any of the states without consuming anything.
+    Split(Vec<usize>),
+    /// The end of a match.
+    Match,
+}
+
+/// Whether the class contains `c`.
+fn contains(ranges: &[(u32, u32)], c: u32) -> bool {
+    ranges.iter().any(|&(lo, hi)| lo <= c && c <= hi)
+}
+
+/// Add the NFA states for `node`, continuing at `next`. Returns the first state.
+fn compile(states: &mut Vec<NfaState>, node: &Node, next: usize) -> usize {
+    let push = |states: &mut Vec<NfaState>, state| {
+        states.push(state);
+        states.len() - 1
+    };
+    match node {
+        Node::Class(ranges) => push(states, NfaState::Class(ranges.clone(), next)),
+        Node::Concat(nodes) => nodes
+            .iter()
+            .rev()
+            .fold(next, |next, node| compile(states, node, next)),
+        Node::Alt(nodes) => {
+            let starts = nodes
+                .iter()
+                .map(|node| compile(states, node, next))
+                .collect();
+            push(states, NfaState::Split(starts))
+        }
+        Node::Repeat(node, min, max) => {
+            let mut next = next;
+            match max {
+                None => {
+                    let split = push(states, NfaState::Split(Vec::new()));
+                    let body = compile(states, node, split);
+                    states[split] = NfaState::Split(vec![body, next]);
+                    next = split;
+                }
+                Some(max) => {
+                    for _ in *min..*max {
+                        let body = compile(states, node, next);
+                        next = push(states, NfaState::Split(vec![body, next]));
+                    }
+                }
+            }
+            for _ in 0..*min {
+                next = compile(states, node, next);
+            }
+            next
+        }
+    }
+}
+
+/// The sorted non-split states reachable from `starts` without consuming a character.
+fn closure(states: &[NfaState], starts: impl IntoIterator<Item = usize>) -> Vec<usize> {
+    let mut visited = vec![false; states.len()];
+    let mut stack: Vec<usize> = starts.into_iter().collect();
+    let mut set = Vec::new();
+    while let Some(state) = stack.pop() {
+        if std::mem::replace(&mut visited[state], true) {
+            continue;
+        }
+        match &states[state] {
+            NfaState::Split(next) => stack.extend(next),
+            _ => set.push(state),
+        }
+    }
+    set.sort_unstable();
+    set
+}
+
+/// A DFA over characters. The alphabet is split into intervals that no class of the pattern
+/// cuts, and every state has one transition per interval.
+#[derive(Debug, Clone)]
+struct Dfa {
+    /// The first character of every interval, starting with 0.
+    boundaries: Vec<u32>,
+    /// `transitions[state][interval]`, [`DEAD`] if no match can follow.
+    transitions: Vec<Vec<usize>>,
+    /// Whether the input up to the state is a match.
+    accepting: Vec<bool>,
+}
+
+impl Dfa {
+    /// Build the DFA of `node` by subset construction and remove the states that cannot reach a
+    /// match. State 0 is the start.
+    fn new(node: &Node) -> Result<Self, RegexError> {
+        let mut states = vec![NfaState::Match];
+        let start = compile(&mut states, node, 0);
+
+        let mut boundaries = vec![0];
+        for state in &states {
+            if let NfaState::Class(ranges, _) = state {
+                for &(lo, hi) in ranges {
+                    boundaries.push(lo);
+                    if hi < MAX_CHAR {
+                        boundaries.push(hi + 1);
+                    }
+                }
+            }
+        }
+        boundaries.sort_unstable();
+        boundaries.dedup();
+
+        let mut sets = vec![closure(&states, [start])];
+        let mut ids = HashMap::from([(sets[0].clone(), 0)]);
+        let mut transitions = Vec::new();
+        let mut accepting = Vec::new();
+        while let Some(set) = sets.get(transitions.len()).cloned() {
+            accepting.push(set.iter().any(|&s| matches!(states[s], NfaState::Match)));
+            let mut row = Vec::with_capacity(boundaries.len());
+            for &c in &boundaries {
+                let targets = set.iter().filter_map(|&s| match &states[s] {
+                    NfaState::Class(ranges, next) if contains(ranges, c) => Some(*next),
+                    _ => None,
+                });
+                let target = closure(&states, targets);
+                if target.is_empty() {
+                    row.push(DEAD);
+                    continue;
+                }
+                let id = if let Some(&id) = ids.get(&target) {
+                    id
+                } else {
+                    if sets.len() >= MAX_STATES {
+                        return Err(RegexError::TooComplex(MAX_STATES));
+                    }
+                    ids.insert(target.clone(), sets.len());
+                    sets.push(target);
+                    sets.len() - 1
+                };
+                row.push(id);
+            }
+            transitions.push(row);
+        }
+
+        let mut live = accepting.clone();
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for state in 0..transitions.len() {
+                if !live[state] && transitions[state].iter().any(|&t| t != DEAD && live[t]) {
+                    live[state] = true;
+                    changed = true;
+                }
+            }
+        }
+        if !live[0] {
+            return Err(RegexError::Unsatisfiable);
+        }
+        for row in &mut transitions {
+            for target in row.iter_mut().filter(|target| **target != DEAD) {
+                if !live[*target] {
+                    *target = DEAD;
+                }
+            }
+        }
+
+        Ok(Self {
+            boundaries,
+            transitions,
+            accepting,
+        })
+    }
+
+    /// The state after reading `text` from `state`, [`DEAD`] if no match can follow.
+    fn walk(&self, mut state: usize, text: &str) -> usize {
+        for c in text.chars() {
+            if state == DEAD {
+                break;
+            }
+            let interval = self.boundaries.partition_point(|&b| b <= u32::from(c)) - 1;
+            state = self.transitions[state][interval];
+        }
+        state
+    }
+
+    fn is_accepting(&self, state: usize) -> bool {
+        state != DEAD && self.accepting[state]
+    }
+}
+
+/// A compiled regular expression that matches whole strings. See the [module](self) docs for the
+/// supported syntax.
+///
+/// ```
+/// # use llama_cpp_2::grammar::regex::Regex;
+/// let id = Regex::new(r"[A-Z]{3}-\d{4}")?;
+/// assert!(id.is_match("ABC-1234"));
+/// assert!(!id.is_match("ABC-1234 and more"));
+/// assert!(id.is_prefix("AB"));
+/// # Ok::<(), llama_cpp_2::grammar::regex::RegexError>(())
+/// ```
+#[derive(Clone)]
+pub struct Regex {
+    pattern: String,
+    dfa: Dfa,
+}
+
+impl Regex {
+    /// Compile `pattern`.
+    ///
+    /// # Errors
+    ///
+    /// - if the pattern is invalid or uses unsupported syntax
+    /// - if the DFA of the pattern has more than 10 000 states
+    /// - if no string matches the pattern
+    pub fn new(pattern: &str) -> Result<Self, RegexError> {
+        let mut parser = Parser {
+            chars: pattern.chars().collect(),
+            position: 0,
+        };
+        let node = parser.alternatives()?;
+        if parser.peek().is_some() {
+            return Err(parser.syntax("unmatched `)`"));
+        }
+        Ok(Self {
+            pattern: pattern.to_string(),
+            dfa: Dfa::new(&node)?,
+        })
+    }
+
+    /// The pattern the regex was compiled from.
+    #[must_use]
+    pub fn as_str(&self) -> &str {
+        &self.pattern
+    }
+
+    /// Whether all of `text` matches.
+    #[must_use]
+    pub fn is_match(&self, text: &str) -> bool {
+        self.dfa.is_accepting(self.dfa.walk(0, text))
+    }
+
+    /// Whether `text` is the start of a match, i.e. can still be completed to one.
+    #[must_use]
+    pub fn is_prefix(&self, text: &str) -> bool {
+        self.dfa.walk(0, text) != DEAD
+    }
+}
+
+impl Debug for Regex {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Regex")
+            .field("pattern", &self.pattern)
+            .field("n_states", &self.dfa.transitions.len())
+            .finish()
+    }
+}
+
+impl FromStr for Regex {
+    type Err = RegexError;
+
+    fn from_str(pattern: &str) -> Result<Self, Self::Err> {
+        Self::new(pattern)
+    }
+}
+
+/// A [`LogitsProcessor`] that only allows tokens that keep the generated text a prefix of a match
+/// of a [`Regex`], and the end of generation tokens once it is a complete match.
+///
+/// The constraint starts with the first call, treating the history it sees then as the prompt, and
+/// follows the tokens added to it afterwards. [`RegexConstraint::reset`] makes it start over for
+/// another generation. The allowed tokens of every DFA state are computed the first time the state
+/// is reached and cached.
+///
+/// Tokens are matched by their text, so tokens that are not valid UTF-8 on their own (such as
+/// byte fallback tokens) and control tokens are never allowed.
+pub struct RegexConstraint {
+    regex: Regex,
+    /// The tokens with a non-empty text, sorted by id.
+    vocab: Vec<(LlamaToken, Box<str>)>,
+    /// The end of generation tokens.
+    eog: Vec<LlamaToken>,
+    state: usize,
+    /// The length of the history the last call saw.
+    seen: Option<usize>,
+    /// The allowed tokens of the states reached so far.
+    masks: HashMap<usize, Vec<LlamaToken>>,
+}
+
+impl RegexConstraint {
+    /// Constrain the generation of `model` to `regex`. This reads the text of every token in the
+    /// vocabulary.
+    #[must_use]
+    pub fn new(model: &LlamaModel, regex: Regex) -> Self {
+        let mut vocab = Vec::new();
+        let mut eog = Vec::new();
+        for (token, text) in model.tokens(Special::Plaintext) {
+            if model.is_eog_token(token) {
+                eog.push(token);
+            } else if let Ok(text) = text {
+                if !text.is_empty() {
+                    vocab.push((token, text.into_boxed_str()));
+                }
+            }
+        }
+        Self::from_vocab(regex, vocab, eog)
+    }
+
+    fn from_vocab(regex: Regex, vocab: Vec<(LlamaToken, Box<str>)>, eog: Vec<LlamaToken>) -> Self {
+        Self {
+            regex,
+            vocab,
+            eog,
+            state: 0,
+            seen: None,
+            masks: HashMap::new(),
+        }
+    }
+
+    /// The regex the output is constrained to.
+    #[must_use]
+    pub fn regex(&self) -> &Regex {
+        &self.regex
+    }
+
+    /// Whether the tokens seen so far form a complete match.
+    #[must_use]
+    pub fn is_match(&self) -> bool {
+        self.regex.dfa.is_accepting(self.state)
+    }
+
+    /// Start over, e.g. for another generation. The cached masks are kept.
+    pub fn reset(&mut self) {
+        self.state = 0;
+        self.seen = None;
+    }
+
+    /// Follow a token that was added to the history.
+    fn advance(&mut self, token: LlamaToken) {
+        if self.state == DEAD || self.eog.contains(&token) {
+            return;
+        }
+        self.state = match self.vocab.binary_search_by_key(&token, |(token, _)| *token) {
+            Ok(index) => self.regex.dfa.walk(self.state, &self.vocab[index].1),
+            Err(_) => DEAD,
+        };
+    }
+
+    /// The allowed tokens of the current state.
+    fn allowed(&mut self) -> &[LlamaToken] {
+        if self.state == DEAD {
+            return &[];
+        }
+        let Self {
+            regex,
+            vocab,
+            state,
+            masks,
+            ..
+        } = self;
+        masks.entry(*state).or_insert_with(|| {
+            vocab
+                .iter()
+                .filter(|(_, text)| regex.dfa.walk(*state, text) != DEAD)
+                .map(|(token, _)| *token)
+                .collect()
+        })
+    }
+}
+
+impl Debug for RegexConstraint {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RegexConstraint")
+            .field("regex", &self.regex)
+            .field("n_vocab", &self.vocab.len())
+            .field("state", &self.state)
+            .field("n_cached_masks", &self.masks.len())
+            .finish_non_exhaustive()
+    }
+}
+
+impl LogitsProcessor for RegexConstraint {
+    fn process(&mut self, logits: &mut [f32], history: &[LlamaToken]) {
+        let seen = self.seen.unwrap_or(history.len()).min(history.len());
+        for &token in &history[seen..] {
+            self.advance(token);
+        }
+        self.seen = Some(history.len());
+
+        // the end of generation tokens are the only way out of an accepting state without
+        // transitions, and the only way to end gracefully after an unexpected token
+        let can_end = self.is_match() || self.state == DEAD;
+        let mut keep = vec![false; logits.len()];
+        let mut allow = |token: LlamaToken| {
+            if let Some(keep) = usize::try_from(token.0).ok().and_then(|i| keep.get_mut(i)) {
+                *keep = true;
+            }
+        };
+        if can_end {
+            self.eog.iter().copied().for_each(&mut allow);
+        }
+        self.allowed().iter().copied().for_each(allow);
+        for (logit, keep) in logits.iter_mut().zip(keep) {
+            if !keep {
+                *logit = f32::NEG_INFINITY;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,120 @@
+use super::*;
+
+fn regex(pattern: &str) -> Regex {
+    Regex::new(pattern).unwrap_or_else(|err| panic!("{pattern}: {err}"))
+}
+
+#[test]
+fn matches_whole_strings() {
+    let phone = regex(r"\(\d{3}\) \d{3}-\d{4}");
+    assert!(phone.is_match("(555) 123-4567"));
+    assert!(!phone.is_match("(555) 123-456"));
+    assert!(!phone.is_match("(555) 123-45678"));
+    assert!(phone.is_prefix("(55"));
+    assert!(!phone.is_prefix("55"));
+
+    let words = regex(r"^(?:[a-z]+|\d+)(, ?(?:[a-z]+|\d+))*$");
+    assert!(words.is_match("a, 12,bc"));
+    assert!(!words.is_match("a,,b"));
+
+    let quoted = regex(r#""[^"\\]*""#);
+    assert!(quoted.is_match(r#""hé llo""#));
+    assert!(!quoted.is_match(r#""a"b""#));
+
+    let any = regex("a.c");
+    assert!(any.is_match("a🦙c"));
+    assert!(!any.is_match("a\nc"));
+}
+
+#[test]
+fn bounded_and_lazy_quantifiers() {
+    let version = regex(r"v\d{1,2}(\.\d+){2,}?");
+    assert!(version.is_match("v1.2.3"));
+    assert!(version.is_match("v10.0.0.1"));
+    assert!(!version.is_match("v1.2"));
+    assert!(!version.is_match("v100.0.0"));
+    assert!(regex("x{0}").is_match(""));
+}
+
+#[test]
+fn class_ranges_and_escapes() {
+    let class = regex(r"[\d\-a-c_]+");
+    assert!(class.is_match("1-b_"));
+    assert!(!class.is_match("d"));
+    assert!(regex(r"[]a]").is_match("]"));
+    assert!(regex(r"[a-]").is_match("-"));
+    assert!(regex(r"\x41é\.\t").is_match("Aé.\t"));
+    assert!(regex(r"[^\s]").is_match("x"));
+    assert!(!regex(r"\S").is_match(" "));
+}
+
+#[test]
+fn rejects_invalid_and_unsupported_patterns() {
+    assert!(matches!(Regex::new("(a"), Err(RegexError::Syntax { .. })));
+    assert!(matches!(Regex::new("a)"), Err(RegexError::Syntax { .. })));
+    assert!(matches!(Regex::new("*a"), Err(RegexError::Syntax { .. })));
+    assert!(matches!(
+        Regex::new("a{3,2}"),
+        Err(RegexError::Syntax { .. })
+    ));
+    assert!(matches!(
+        Regex::new("[z-a]"),
+        Err(RegexError::Syntax { .. })
+    ));
+    assert!(matches!(
+        Regex::new(r"(a)\1"),
+        Err(RegexError::Unsupported { position: 3, .. })
+    ));
+    assert!(matches!(
+        Regex::new("(?=a)"),
+        Err(RegexError::Unsupported { .. })
+    ));
+    assert_eq!(
+        Regex::new(r"[^\s\S]").unwrap_err(),
+        RegexError::Unsatisfiable
+    );
+}
+
+fn constraint(pattern: &str, vocab: &[&str]) -> RegexConstraint {
+    let vocab = (1..)
+        .map(LlamaToken::new)
+        .zip(vocab.iter().map(|text| Box::from(*text)))
+        .collect();
+    RegexConstraint::from_vocab(regex(pattern), vocab, vec![LlamaToken::new(0)])
+}
+
+fn allowed(constraint: &mut RegexConstraint, history: &[LlamaToken]) -> Vec<i32> {
+    let mut logits = vec![0.0; constraint.vocab.len() + 1];
+    constraint.process(&mut logits, history);
+    (0..)
+        .zip(logits)
+        .filter(|(_, logit)| logit.is_finite())
+        .map(|(token, _)| token)
+        .collect()
+}
+
+#[test]
+fn masks_tokens_that_cannot_continue_a_match() {
+    // 1: "1", 2: "12", 3: "-", 4: "1-2", 5: "a"
+    let mut constraint = constraint(r"\d{2}-\d", &["1", "12", "-", "1-2", "a"]);
+    let prompt = [LlamaToken::new(5), LlamaToken::new(5)];
+    assert_eq!(allowed(&mut constraint, &prompt), [1, 2]);
+
+    let history = [&prompt[..], &[LlamaToken::new(1)]].concat();
+    assert_eq!(allowed(&mut constraint, &history), [1, 4]);
+
+    let history = [&history[..], &[LlamaToken::new(4)]].concat();
+    assert_eq!(allowed(&mut constraint, &history), [0]);
+    assert!(constraint.is_match());
+
+    constraint.reset();
+    assert_eq!(allowed(&mut constraint, &prompt), [1, 2]);
+}
+
+#[test]
+fn ends_after_an_unexpected_token() {
+    let mut constraint = constraint("ab", &["a", "b", "c"]);
+    assert_eq!(allowed(&mut constraint, &[]), [1]);
+    assert_eq!(allowed(&mut constraint, &[LlamaToken::new(3)]), [0]);
+    assert!(!constraint.is_match());
+}
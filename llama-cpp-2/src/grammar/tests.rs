@@ -88,3 +88,32 @@ fn check_parse_char_range() {
         parse_state
     );
 }
+
+#[test]
+fn validate_accepts_grammar_files() {
+    let json = std::fs::read_to_string("src/grammar/json.gbnf").unwrap();
+    validate(&json).unwrap();
+}
+
+#[test]
+fn validate_reports_missing_root() {
+    let err = validate(r#"answer ::= "yes""#).unwrap_err();
+    assert!(matches!(err.kind, GrammarErrorKind::MissingRoot));
+}
+
+#[test]
+fn validate_reports_undefined_rule_position() {
+    let err = validate("root ::= item+\n# items\nitem ::= \"a\" | other\n").unwrap_err();
+    assert!(matches!(err.kind, GrammarErrorKind::UndefinedRule { ref name } if name == "other"));
+    assert_eq!((err.line, err.column), (3, 16));
+}
+
+#[test]
+fn validate_reports_parse_error_position() {
+    let err = validate("root ::= item\n\nitem \"a\"\n").unwrap_err();
+    assert!(matches!(
+        err.kind,
+        GrammarErrorKind::Parse(GrammarParseError::ExpectedEqualsAfterName { .. })
+    ));
+    assert_eq!((err.line, err.column), (3, 1));
+}
@@ -12,21 +12,51 @@
 //! # Feature Flags
 //!
 //! - `cuda` enables CUDA gpu support.
+//! - `metal` enables Metal gpu support and adds [`metal`] to query the device's memory budget on
+//!   macOS and iOS.
 //! - `sampler` adds the [`context::sample::sampler`] struct for a more rusty way of sampling.
+//! - `openai` adds serde types for OpenAI-compatible chat completion requests and responses in
+//!   [`openai`], and tool calling for Hermes, Qwen and Llama 3.1 style models in [`openai::tools`].
+//! - `json` adds [`grammar::json_schema`] to turn JSON schemas into grammars and
+//!   [`context::LlamaContext::generate_json`] to generate and deserialize schema-valid JSON.
+//! - `serde` implements `Serialize` and `Deserialize` for tokens and token data arrays, e.g. to
+//!   dump sampling traces to JSON.
+//! - `metrics` records token counts, decode latency and KV cache usage through the `metrics`
+//!   crate (e.g. for a Prometheus exporter), see [`metrics`].
+//! - `tracing` adds spans (with token counts, sequence ids and durations) around decoding,
+//!   encoding, tokenization, sampling and [`context::LlamaContext::generate`].
+//! - `hf-hub` adds [`model::LlamaModel::from_hf`] to download models from the Hugging Face Hub.
 use std::ffi::NulError;
 use std::fmt::Debug;
 use std::num::NonZeroI32;
 
+use crate::context::session::{LoadSessionError, SaveSessionError};
 use crate::llama_batch::BatchAddError;
 use std::os::raw::c_int;
 use std::path::PathBuf;
 use std::string::FromUtf8Error;
 
+pub mod chat;
 pub mod context;
+pub mod embedding;
+pub mod generate;
+pub mod ggml_type;
+pub mod gguf;
 pub mod grammar;
 pub mod llama_backend;
 pub mod llama_batch;
+#[cfg(all(feature = "metal", any(target_os = "macos", target_os = "ios")))]
+pub mod metal;
+#[cfg(feature = "metrics")]
+pub mod metrics;
 pub mod model;
+#[cfg(feature = "openai")]
+pub mod openai;
+pub mod quantize;
+pub mod speculative;
+pub mod system_info;
+#[cfg(any(test, feature = "test-utils"))]
+pub mod test_utils;
 pub mod timing;
 pub mod token;
 pub mod token_type;
@@ -34,8 +64,32 @@ pub mod token_type;
 /// A failable result from a llama.cpp function.
 pub type Result<T> = std::result::Result<T, LLamaCppError>;
 
+/// The crate wide error type. Every error returned by a function in this crate converts into it
+/// with `?`, so callers can handle everything in one place and still match on the cause.
+/// Functions that panic on invalid input, such as
+/// [`LlamaContext::get_logits_ith`](context::LlamaContext::get_logits_ith), have a `try_`
+/// counterpart returning the error instead.
+///
+/// ```
+/// # use llama_cpp_2::{DecodeError, LlamaError};
+/// fn describe(err: &LlamaError) -> &'static str {
+///     match err {
+///         LlamaError::LlamaModelLoadError(_) => "could not load the model",
+///         LlamaError::LlamaContextLoadError(_) => "could not create a context",
+///         LlamaError::DecodeError(DecodeError::NoKvCacheSlot) => "the context is full",
+///         LlamaError::TokenizeError(_) => "could not tokenize the prompt",
+///         LlamaError::SamplerError(_) => "could not sample a token",
+///         _ => "something else went wrong",
+///     }
+/// }
+/// # let err = LlamaError::from(DecodeError::NoKvCacheSlot);
+/// # assert_eq!(describe(&err), "the context is full");
+/// ```
+pub type LlamaError = LLamaCppError;
+
 /// All errors that can occur in the llama-cpp crate.
-#[derive(Debug, Eq, PartialEq, thiserror::Error)]
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
 pub enum LLamaCppError {
     /// The backend was already initialized. This can generally be ignored as initializing the backend
     /// is idempotent.
@@ -62,6 +116,139 @@ pub enum LLamaCppError {
     /// see [`EmbeddingsError`]
     #[error(transparent)]
     EmbeddingError(#[from] EmbeddingsError),
+    /// There was an error converting a string to tokens.
+    #[error(transparent)]
+    TokenizeError(#[from] StringToTokenError),
+    /// There was an error converting a token to a string.
+    #[error(transparent)]
+    TokenToStringError(#[from] TokenToStringError),
+    /// There was an error creating a chat message.
+    #[error(transparent)]
+    NewLlamaChatMessageError(#[from] NewLlamaChatMessageError),
+    /// There was an error applying a chat template.
+    #[error(transparent)]
+    ApplyChatTemplateError(#[from] ApplyChatTemplateError),
+    /// There was an error reading logits.
+    #[error(transparent)]
+    LogitsError(#[from] LogitsError),
+    /// There was an error sampling a token.
+    #[error(transparent)]
+    SamplerError(#[from] SamplerError),
+    /// There was an error loading a lora adapter.
+    #[error(transparent)]
+    LlamaLoraAdapterInitError(#[from] LlamaLoraAdapterInitError),
+    /// There was an error setting a lora adapter.
+    #[error(transparent)]
+    LlamaLoraAdapterSetError(#[from] LlamaLoraAdapterSetError),
+    /// There was an error removing a lora adapter.
+    #[error(transparent)]
+    LlamaLoraAdapterRemoveError(#[from] LlamaLoraAdapterRemoveError),
+    /// There was an error saving a session file.
+    #[error(transparent)]
+    SaveSessionError(#[from] SaveSessionError),
+    /// There was an error loading a session file.
+    #[error(transparent)]
+    LoadSessionError(#[from] LoadSessionError),
+    /// There was an error applying a prompt to the KV cache.
+    #[error(transparent)]
+    ApplyPromptError(#[from] context::session::ApplyPromptError),
+    /// The context params do not fit together or do not fit the model.
+    #[error(transparent)]
+    ContextParamsError(#[from] context::params::ContextParamsError),
+    /// The rope scaling does not fit the model.
+    #[error(transparent)]
+    RopeScalingError(#[from] context::params::RopeScalingError),
+    /// There was an error running an infill.
+    #[error(transparent)]
+    InfillError(#[from] context::infill::InfillError),
+    /// There was an error scoring pairs.
+    #[error(transparent)]
+    ScorePairsError(#[from] context::score::ScorePairsError),
+    /// There was an error generating.
+    #[error(transparent)]
+    GenerateError(#[from] generate::GenerateError),
+    /// Generating failed for some requests of a slot manager.
+    #[error(transparent)]
+    StepError(#[from] generate::slots::StepError),
+    /// There was an error choosing an option.
+    #[error(transparent)]
+    ChooseError(#[from] generate::choice::ChooseError),
+    /// There was an error generating JSON.
+    #[cfg(feature = "json")]
+    #[error(transparent)]
+    GenerateJsonError(#[from] generate::json::GenerateJsonError),
+    /// There was an error in a chat session.
+    #[error(transparent)]
+    ChatError(#[from] chat::ChatError),
+    /// There was an error tokenizing a conversation with a prompt format.
+    #[error(transparent)]
+    PromptFormatError(#[from] chat::format::PromptFormatError),
+    /// There was an error rendering a Jinja chat template.
+    #[cfg(feature = "jinja")]
+    #[error(transparent)]
+    JinjaError(#[from] chat::jinja::JinjaError),
+    /// There was an error embedding a batch of texts.
+    #[error(transparent)]
+    EmbedBatchError(#[from] embedding::batch::EmbedBatchError),
+    /// There was an error embedding tokens.
+    #[error(transparent)]
+    EmbedTokensError(#[from] embedding::tokens::EmbedTokensError),
+    /// There was an error reading or writing a GGUF file.
+    #[error(transparent)]
+    GgufError(#[from] gguf::GgufError),
+    /// There was an error estimating the memory of a model.
+    #[error(transparent)]
+    MemoryEstimateError(#[from] gguf::estimate::MemoryEstimateError),
+    /// There was an error creating a grammar.
+    #[error(transparent)]
+    LlamaGrammarFromStrError(#[from] grammar::LlamaGrammarFromStrError),
+    /// There was an error parsing a grammar.
+    #[error(transparent)]
+    GrammarParseError(#[from] grammar::GrammarParseError),
+    /// A grammar is invalid.
+    #[error(transparent)]
+    GrammarError(#[from] grammar::GrammarError),
+    /// There was an error compiling a regex to a grammar.
+    #[error(transparent)]
+    RegexError(#[from] grammar::regex::RegexError),
+    /// There was an error converting a JSON schema to a grammar.
+    #[cfg(feature = "json")]
+    #[error(transparent)]
+    JsonSchemaError(#[from] grammar::json_schema::JsonSchemaError),
+    /// There was an error quantizing a model.
+    #[error(transparent)]
+    QuantizeError(#[from] quantize::QuantizeError),
+    /// There was an error reading or writing an importance matrix.
+    #[error(transparent)]
+    ImatrixError(#[from] quantize::imatrix::ImatrixError),
+    /// There was an error computing an importance matrix.
+    #[error(transparent)]
+    ImatrixComputeError(#[from] quantize::imatrix::ImatrixComputeError),
+    /// A ggml type or file type is unknown.
+    #[error(transparent)]
+    GgmlTypeFromIntError(#[from] ggml_type::GgmlTypeFromIntError),
+    /// A vocabulary type is unknown.
+    #[error(transparent)]
+    VocabTypeFromIntError(#[from] model::LlamaTokenTypeFromIntError),
+    /// A token type is unknown.
+    #[error(transparent)]
+    TokenTypeFromIntError(#[from] token_type::LlamaTokenTypeFromIntError),
+    /// There was an error loading a model from the Hugging Face Hub.
+    #[cfg(feature = "hf-hub")]
+    #[error(transparent)]
+    LlamaModelFromHfError(#[from] model::hf::LlamaModelFromHfError),
+    /// There was an error converting a response format to a grammar.
+    #[cfg(all(feature = "openai", feature = "json"))]
+    #[error(transparent)]
+    ResponseFormatError(#[from] openai::ResponseFormatError),
+    /// A request's logit bias is not keyed by token ids.
+    #[cfg(feature = "openai")]
+    #[error(transparent)]
+    LogitBiasError(#[from] openai::LogitBiasError),
+    /// There was an error parsing a tool call.
+    #[cfg(feature = "openai")]
+    #[error(transparent)]
+    ParseToolCallError(#[from] openai::tools::ParseToolCallError),
 }
 
 /// There was an error while getting the chat template from a model.
@@ -84,10 +271,14 @@ pub enum LlamaContextLoadError {
     /// llama.cpp returned null
     #[error("null reference from llama.cpp")]
     NullReturn,
+    /// The params do not fit together or do not fit the model, see
+    /// [`LlamaContextParams::validate`](context::params::LlamaContextParams::validate).
+    #[error("invalid context params: {0}")]
+    InvalidParams(#[from] context::params::ContextParamsError),
 }
 
 /// Failed to decode a batch.
-#[derive(Debug, Eq, PartialEq, thiserror::Error)]
+#[derive(Debug, Clone, Eq, PartialEq, thiserror::Error)]
 pub enum DecodeError {
     /// No kv cache slot was available.
     #[error("Decode Error 1: NoKvCacheSlot")]
@@ -95,13 +286,26 @@ pub enum DecodeError {
     /// The number of tokens in the batch was 0.
     #[error("Decode Error -1: n_tokens == 0")]
     NTokensZero,
+    /// The decode was aborted by a [`context::cancel::CancellationToken`].
+    #[error("Decode Error: aborted")]
+    Aborted,
+    /// The model attends non-causally (an embedding encoder such as BERT), which needs the whole
+    /// batch in one ubatch. See
+    /// [`LlamaContextParams::for_embeddings`](context::params::LlamaContextParams::for_embeddings).
+    #[error("Decode Error: non-causal attention needs all {n_tokens} tokens in one ubatch, but n_ubatch is {n_ubatch}")]
+    UbatchTooSmall {
+        /// The number of tokens in the batch.
+        n_tokens: i32,
+        /// The ubatch size of the context.
+        n_ubatch: u32,
+    },
     /// An unknown error occurred.
     #[error("Decode Error {0}: unknown")]
     Unknown(c_int),
 }
 
 /// Failed to decode a batch.
-#[derive(Debug, Eq, PartialEq, thiserror::Error)]
+#[derive(Debug, Clone, Eq, PartialEq, thiserror::Error)]
 pub enum EncodeError {
     /// No kv cache slot was available.
     #[error("Encode Error 1: NoKvCacheSlot")]
@@ -109,6 +313,14 @@ pub enum EncodeError {
     /// The number of tokens in the batch was 0.
     #[error("Encode Error -1: n_tokens == 0")]
     NTokensZero,
+    /// The encoder needs the whole batch in one ubatch.
+    #[error("Encode Error: the encoder needs all {n_tokens} tokens in one ubatch, but n_ubatch is {n_ubatch}")]
+    UbatchTooSmall {
+        /// The number of tokens in the batch.
+        n_tokens: i32,
+        /// The ubatch size of the context.
+        n_ubatch: u32,
+    },
     /// An unknown error occurred.
     #[error("Encode Error {0}: unknown")]
     Unknown(c_int),
@@ -128,6 +340,36 @@ pub enum EmbeddingsError {
     NonePoolType,
 }
 
+/// Failed to read the logits of a token.
+#[derive(Debug, Clone, Eq, PartialEq, thiserror::Error)]
+pub enum LogitsError {
+    /// Logits were not requested for the token in the last decoded batch.
+    #[error("logit {0} is not initialized")]
+    NotInitialized(i32),
+    /// The index is not within the context.
+    #[error("n_ctx ({n_ctx}) must be greater than i ({i})")]
+    OutOfContext {
+        /// The requested index.
+        i: i32,
+        /// The size of the context.
+        n_ctx: u32,
+    },
+    /// llama.cpp returned null.
+    #[error("null reference from llama.cpp")]
+    NullReturn,
+    /// No token of the sequence requested logits in the batch.
+    #[error("no token of sequence {0} has logits in the batch")]
+    SeqNotInBatch(i32),
+}
+
+/// Failed to sample a token.
+#[derive(Debug, Clone, Eq, PartialEq, thiserror::Error)]
+pub enum SamplerError {
+    /// There were no candidates to sample from.
+    #[error("no candidates to sample from")]
+    EmptyCandidates,
+}
+
 /// Decode a error from llama.cpp into a [`DecodeError`].
 impl From<NonZeroI32> for DecodeError {
     fn from(value: NonZeroI32) -> Self {
@@ -162,6 +404,14 @@ pub enum LlamaModelLoadError {
     /// Failed to convert the path to a rust str. This means the path was not valid unicode
     #[error("failed to convert path {0} to str")]
     PathToStrError(PathBuf),
+    /// The model was loaded with `check_tensors` and these tensors contain invalid data.
+    ///
+    /// llama.cpp only reports which tensors are invalid in its log, so this is best effort: the
+    /// names are picked from the messages it logged on the loading thread, and a load that fails
+    /// without such messages (e.g. because a newer llama.cpp words them differently) is
+    /// [`LlamaModelLoadError::NullResult`].
+    #[error("tensors with invalid data: {}", .0.join(", "))]
+    InvalidTensors(Vec<String>),
 }
 
 /// An error that can occur when loading a model.
@@ -243,7 +493,7 @@ pub fn mlock_supported() -> bool {
 }
 
 /// An error that can occur when converting a token to a string.
-#[derive(Debug, thiserror::Error, Clone)]
+#[derive(Debug, Eq, PartialEq, thiserror::Error, Clone)]
 #[non_exhaustive]
 pub enum TokenToStringError {
     /// the token type was unknown
@@ -258,7 +508,7 @@ pub enum TokenToStringError {
 }
 
 /// Failed to convert a string to a token sequence.
-#[derive(Debug, thiserror::Error)]
+#[derive(Debug, Eq, PartialEq, thiserror::Error)]
 pub enum StringToTokenError {
     /// the string contained a null byte and thus could not be converted to a c string.
     #[error("{0}")]
@@ -269,7 +519,7 @@ pub enum StringToTokenError {
 }
 
 /// Failed to apply model chat template.
-#[derive(Debug, thiserror::Error)]
+#[derive(Debug, Eq, PartialEq, thiserror::Error)]
 pub enum NewLlamaChatMessageError {
     /// the string contained a null byte and thus could not be converted to a c string.
     #[error("{0}")]
@@ -277,7 +527,7 @@ pub enum NewLlamaChatMessageError {
 }
 
 /// Failed to apply model chat template.
-#[derive(Debug, thiserror::Error)]
+#[derive(Debug, Eq, PartialEq, thiserror::Error)]
 pub enum ApplyChatTemplateError {
     /// the buffer was too small.
     #[error("The buffer was too small. Please contact a maintainer and we will update it.")]
@@ -325,3 +575,6 @@ pub fn ggml_time_us() -> i64 {
 pub fn llama_supports_mlock() -> bool {
     unsafe { llama_cpp_sys_2::llama_supports_mlock() }
 }
+
+#[cfg(test)]
+mod tests;
@@ -2,8 +2,11 @@
 
 use crate::LLamaCppError;
 use llama_cpp_sys_2::ggml_log_level;
-use std::sync::atomic::AtomicBool;
+use std::ffi::CStr;
 use std::sync::atomic::Ordering::SeqCst;
+use std::sync::atomic::{AtomicBool, AtomicU8};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::thread::{self, ThreadId};
 
 /// Representation of an initialized llama backend
 /// This is required as a parameter for most llama functions as the backend must be initialized
@@ -36,7 +39,10 @@ impl LlamaBackend {
     ///
     /// let backend = LlamaBackend::init()?;
     /// // the llama backend can only be initialized once
-    /// assert_eq!(Err(LLamaCppError::BackendAlreadyInitialized), LlamaBackend::init());
+    /// assert!(matches!(
+    ///     LlamaBackend::init(),
+    ///     Err(LLamaCppError::BackendAlreadyInitialized)
+    /// ));
     ///
     ///# Ok(())
     ///# }
@@ -70,21 +76,194 @@ impl LlamaBackend {
         Ok(LlamaBackend {})
     }
 
-    /// Change the output of llama.cpp's logging to be voided instead of pushed to `stderr`.
+    /// Change the output of llama.cpp's logging to be voided instead of pushed to `stderr`. See
+    /// [`LlamaBackend::silence_logs`], which can be called before the backend is initialized.
     pub fn void_logs(&mut self) {
-        unsafe extern "C" fn void_log(
-            _level: ggml_log_level,
-            _text: *const ::std::os::raw::c_char,
-            _user_data: *mut ::std::os::raw::c_void,
-        ) {
-        }
+        Self::silence_logs();
+    }
 
-        unsafe {
-            llama_cpp_sys_2::llama_log_set(Some(void_log), std::ptr::null_mut());
+    /// Only print the messages llama.cpp and ggml log at `level` or above to stderr instead of
+    /// all of them. This can be called before [`LlamaBackend::init`], so that e.g. only warnings
+    /// and errors appear instead of the system info and the metadata of every loaded model:
+    ///
+    /// ```
+    /// # use llama_cpp_2::llama_backend::{LlamaBackend, LogLevel};
+    /// LlamaBackend::set_log_level(LogLevel::Warn);
+    /// let backend = LlamaBackend::init()?;
+    /// # Ok::<(), llama_cpp_2::LLamaCppError>(())
+    /// ```
+    ///
+    /// The level is global and independent of the `tracing` integration. A few messages that
+    /// ggml prints directly to stderr, such as some backend initialization messages, bypass it.
+    pub fn set_log_level(level: LogLevel) {
+        MIN_LOG_LEVEL.store(level as u8, SeqCst);
+        install_log_callback(&captures());
+    }
+
+    /// Print none of the messages llama.cpp and ggml log, see [`LlamaBackend::set_log_level`].
+    /// Messages are still [captured](with_captured_logs) for the errors of this crate that
+    /// include them.
+    pub fn silence_logs() {
+        MIN_LOG_LEVEL.store(SILENT, SeqCst);
+        install_log_callback(&captures());
+    }
+}
+
+/// The severity of a message logged by llama.cpp or ggml, from the most verbose to the most
+/// severe.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[repr(u8)]
+pub enum LogLevel {
+    /// Debugging details.
+    Debug = 1,
+    /// Progress and information, such as the system info and the metadata of loaded models.
+    Info,
+    /// Something that might be a problem.
+    Warn,
+    /// An operation failed.
+    Error,
+}
+
+impl LogLevel {
+    /// The level of a `ggml_log_level`, `None` for levels of newer llama.cpp versions.
+    #[must_use]
+    pub fn from_ggml(level: ggml_log_level) -> Option<Self> {
+        match level {
+            llama_cpp_sys_2::GGML_LOG_LEVEL_DEBUG => Some(Self::Debug),
+            llama_cpp_sys_2::GGML_LOG_LEVEL_INFO => Some(Self::Info),
+            llama_cpp_sys_2::GGML_LOG_LEVEL_WARN => Some(Self::Warn),
+            llama_cpp_sys_2::GGML_LOG_LEVEL_ERROR => Some(Self::Error),
+            _ => None,
         }
     }
 }
 
+/// The minimum [`LogLevel`] as a `u8`, [`UNFILTERED`] or [`SILENT`].
+static MIN_LOG_LEVEL: AtomicU8 = AtomicU8::new(UNFILTERED);
+
+/// Every message is printed by llama.cpp's own callback.
+const UNFILTERED: u8 = 0;
+
+/// No message is printed.
+const SILENT: u8 = u8::MAX;
+
+/// Whether a message of `level` is printed with the [`MIN_LOG_LEVEL`] `min`. Messages of unknown
+/// levels are printed unless logs are silenced.
+fn is_printed(level: ggml_log_level, min: u8) -> bool {
+    match min {
+        SILENT => false,
+        min => LogLevel::from_ggml(level).is_none_or(|level| level as u8 >= min),
+    }
+}
+
+/// The messages collected by the running [`with_captured_logs`] calls, innermost last.
+static CAPTURES: Mutex<Vec<Capture>> = Mutex::new(Vec::new());
+
+/// The messages collected by one [`with_captured_logs`] call.
+struct Capture {
+    /// The thread that called [`with_captured_logs`], only its messages are collected.
+    thread: ThreadId,
+    lines: Vec<String>,
+}
+
+/// Lock [`CAPTURES`]. The lock is also taken inside the log callback, which must not panic.
+fn captures() -> MutexGuard<'static, Vec<Capture>> {
+    CAPTURES.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Install the log callback for the current [`MIN_LOG_LEVEL`] and `captures`. Called with the
+/// lock of [`CAPTURES`] held, so that concurrent calls cannot install an outdated callback.
+fn install_log_callback(captures: &[Capture]) {
+    let callback: llama_cpp_sys_2::ggml_log_callback =
+        match (captures.is_empty(), MIN_LOG_LEVEL.load(SeqCst)) {
+            (false, _) => Some(capture_log),
+            // llama.cpp falls back to logging to stderr
+            (true, UNFILTERED) => None,
+            (true, _) => Some(filter_log),
+        };
+    unsafe { llama_cpp_sys_2::llama_log_set(callback, std::ptr::null_mut()) }
+}
+
+unsafe extern "C" fn filter_log(
+    level: ggml_log_level,
+    text: *const ::std::os::raw::c_char,
+    _user_data: *mut ::std::os::raw::c_void,
+) {
+    if text.is_null() || !is_printed(level, MIN_LOG_LEVEL.load(SeqCst)) {
+        return;
+    }
+    eprint!("{}", CStr::from_ptr(text).to_string_lossy());
+}
+
+/// Prints like [`filter_log`] and also collects the message for every capture of the logging
+/// thread.
+unsafe extern "C" fn capture_log(
+    level: ggml_log_level,
+    text: *const ::std::os::raw::c_char,
+    _user_data: *mut ::std::os::raw::c_void,
+) {
+    if text.is_null() {
+        return;
+    }
+    let text = CStr::from_ptr(text).to_string_lossy();
+    if is_printed(level, MIN_LOG_LEVEL.load(SeqCst)) {
+        eprint!("{text}");
+    }
+    let thread = thread::current().id();
+    for capture in captures().iter_mut().filter(|c| c.thread == thread) {
+        capture.lines.push(text.to_string());
+    }
+}
+
+/// Ends the capture of [`with_captured_logs`] on the thread it holds, even on unwind.
+struct EndCapture(Option<ThreadId>);
+
+impl EndCapture {
+    /// End the capture and take its messages.
+    fn end(&mut self) -> Vec<String> {
+        let Some(thread) = self.0.take() else {
+            return Vec::new();
+        };
+        let mut captures = captures();
+        let lines = captures
+            .iter()
+            .rposition(|capture| capture.thread == thread)
+            .map(|i| captures.remove(i).lines)
+            .unwrap_or_default();
+        install_log_callback(&captures);
+        lines
+    }
+}
+
+impl Drop for EndCapture {
+    fn drop(&mut self) {
+        self.end();
+    }
+}
+
+/// Run `f` while collecting the messages llama.cpp logs on the calling thread. Messages are
+/// still printed if their level is, see [`LlamaBackend::set_log_level`].
+///
+/// The captures live in a process-wide slot, so calls on other threads and nested calls each get
+/// their own messages, and the callback of [`LlamaBackend::set_log_level`] is back in place once
+/// the last capture ends. Messages that llama.cpp logs from its worker threads are not collected,
+/// and a callback installed by calling `llama_log_set` directly is replaced while capturing.
+pub(crate) fn with_captured_logs<T>(f: impl FnOnce() -> T) -> (T, Vec<String>) {
+    let thread = thread::current().id();
+    {
+        let mut captures = captures();
+        captures.push(Capture {
+            thread,
+            lines: Vec::new(),
+        });
+        install_log_callback(&captures);
+    }
+    let mut end = EndCapture(Some(thread));
+    let result = f();
+    let lines = end.end();
+    (result, lines)
+}
+
 /// A rusty wrapper around `numa_strategy`.
 #[derive(Debug, Eq, PartialEq, Copy, Clone)]
 pub enum NumaStrategy {
@@ -187,6 +366,64 @@ mod tests {
         }
     }
 
+    #[test]
+    fn log_levels_filter_by_severity() {
+        let levels = [
+            llama_cpp_sys_2::GGML_LOG_LEVEL_DEBUG,
+            llama_cpp_sys_2::GGML_LOG_LEVEL_INFO,
+            llama_cpp_sys_2::GGML_LOG_LEVEL_WARN,
+            llama_cpp_sys_2::GGML_LOG_LEVEL_ERROR,
+        ];
+        let printed = |min| levels.map(|level| is_printed(level, min));
+        assert_eq!(printed(UNFILTERED), [true; 4]);
+        assert_eq!(printed(LogLevel::Warn as u8), [false, false, true, true]);
+        assert_eq!(printed(SILENT), [false; 4]);
+        assert!(LogLevel::Debug < LogLevel::Error);
+    }
+
+    /// Log `text` the way llama.cpp does while a capture is installed.
+    fn log(text: &str) {
+        let text = std::ffi::CString::new(text).unwrap();
+        unsafe {
+            capture_log(
+                llama_cpp_sys_2::GGML_LOG_LEVEL_DEBUG,
+                text.as_ptr(),
+                std::ptr::null_mut(),
+            );
+        }
+    }
+
+    #[test]
+    fn captures_nest_on_one_thread() {
+        let (((), inner), outer) = with_captured_logs(|| {
+            log("outer\n");
+            with_captured_logs(|| log("inner\n"))
+        });
+        assert_eq!(inner, vec!["inner\n"]);
+        assert_eq!(outer, vec!["outer\n", "inner\n"]);
+        assert!(!captures()
+            .iter()
+            .any(|c| c.thread == thread::current().id()));
+    }
+
+    #[test]
+    fn captures_ignore_other_threads() {
+        let ((), lines) = with_captured_logs(|| {
+            log("mine\n");
+            thread::spawn(|| log("theirs\n")).join().unwrap();
+        });
+        assert_eq!(lines, vec!["mine\n"]);
+    }
+
+    #[test]
+    fn captures_end_on_unwind() {
+        let panicked = std::panic::catch_unwind(|| with_captured_logs(|| panic!("load failed")));
+        assert!(panicked.is_err());
+        assert!(!captures()
+            .iter()
+            .any(|c| c.thread == thread::current().id()));
+    }
+
     #[test]
     fn check_invalid_numa() {
         let invalid = 800;
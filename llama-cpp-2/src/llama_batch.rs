@@ -1,5 +1,7 @@
 //! Safe wrapper around `llama_batch`.
 
+use std::collections::BTreeMap;
+
 use crate::token::LlamaToken;
 use llama_cpp_sys_2::{llama_batch, llama_batch_free, llama_batch_init, llama_pos, llama_seq_id};
 
@@ -8,6 +10,8 @@ use llama_cpp_sys_2::{llama_batch, llama_batch_free, llama_batch_init, llama_pos
 pub struct LlamaBatch {
     /// The number of tokens the batch was allocated with. they are safe to write to - but not necessarily read from as they are not necessarily initialized
     allocated: usize,
+    /// The size of a single embedding for batches created with [`LlamaBatch::new_embeddings`], 0 for token batches.
+    n_embd: usize,
     /// The logits that are initialized. Used by [`LlamaContext`] to ensure that only initialized logits are accessed.
     pub(crate) initialized_logits: Vec<i32>,
     /// The llama_cpp batch. always initialize by `llama_cpp_sys_2::llama_batch_init(allocated, <unknown>, <unknown>)`
@@ -15,11 +19,25 @@ pub struct LlamaBatch {
 }
 
 /// Errors that can occur when adding a token to a batch.
-#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
 pub enum BatchAddError {
     /// There was not enough space in the batch to add the token.
     #[error("Insufficient Space of {0}")]
     InsufficientSpace(usize),
+    /// Tokens can not be added to a batch created with [`LlamaBatch::new_embeddings`].
+    #[error("Cannot add a token to an embeddings batch")]
+    NotATokenBatch,
+    /// Embeddings can only be added to a batch created with [`LlamaBatch::new_embeddings`].
+    #[error("Cannot add an embedding to a token batch")]
+    NotAnEmbeddingsBatch,
+    /// The embedding does not have the size the batch was created with.
+    #[error("Expected an embedding of size {expected} but got {actual}")]
+    EmbeddingSizeMismatch {
+        /// The embedding size of the batch.
+        expected: usize,
+        /// The size of the provided embedding.
+        actual: usize,
+    },
 }
 
 impl LlamaBatch {
@@ -40,7 +58,8 @@ impl LlamaBatch {
     ///
     /// # Errors
     ///
-    /// returns a error if there is insufficient space in the buffer
+    /// - returns a error if there is insufficient space in the buffer
+    /// - returns a error if the batch was created with [`LlamaBatch::new_embeddings`]
     pub fn add(
         &mut self,
         LlamaToken(id): LlamaToken,
@@ -48,6 +67,9 @@ impl LlamaBatch {
         seq_ids: &[i32],
         logits: bool,
     ) -> Result<(), BatchAddError> {
+        if self.llama_batch.token.is_null() {
+            return Err(BatchAddError::NotATokenBatch);
+        }
         if self.allocated
             < usize::try_from(self.n_tokens() + 1).expect("cannot fit n_tokens into a usize")
         {
@@ -58,6 +80,127 @@ impl LlamaBatch {
         unsafe {
             // batch.token   [batch.n_tokens] = id;
             self.llama_batch.token.add(offset_usize).write(id);
+        }
+        self.push_pos_seq_logits(pos, seq_ids, logits);
+        Ok(())
+    }
+
+    /// add an embedding to the batch for sequences `seq_ids` at position `pos`. The embedding takes
+    /// the place of a token, so it must be produced by an encoder projecting into the same
+    /// embedding space as the model (e.g. a CLIP projector for a vision model).
+    ///
+    /// # Panics
+    ///
+    /// - [`self.llama_batch.n_tokens`] does not fit into a usize
+    /// - [`seq_ids.len()`] does not fit into a [`llama_seq_id`]
+    ///
+    /// # Errors
+    ///
+    /// - returns a error if there is insufficient space in the buffer
+    /// - returns a error if the batch was not created with [`LlamaBatch::new_embeddings`]
+    /// - returns a error if `embd` does not have the size the batch was created with
+    pub fn add_embedding(
+        &mut self,
+        embd: &[f32],
+        pos: llama_pos,
+        seq_ids: &[i32],
+        logits: bool,
+    ) -> Result<(), BatchAddError> {
+        if self.llama_batch.embd.is_null() {
+            return Err(BatchAddError::NotAnEmbeddingsBatch);
+        }
+        if embd.len() != self.n_embd {
+            return Err(BatchAddError::EmbeddingSizeMismatch {
+                expected: self.n_embd,
+                actual: embd.len(),
+            });
+        }
+        if self.allocated
+            < usize::try_from(self.n_tokens() + 1).expect("cannot fit n_tokens into a usize")
+        {
+            return Err(BatchAddError::InsufficientSpace(self.allocated));
+        }
+        let offset_usize =
+            usize::try_from(self.llama_batch.n_tokens).expect("cannot fit n_tokens into a usize");
+        unsafe {
+            // memcpy(batch.embd + n_tokens * n_embd, embd, n_embd * sizeof(float));
+            self.llama_batch
+                .embd
+                .add(offset_usize * self.n_embd)
+                .copy_from_nonoverlapping(embd.as_ptr(), self.n_embd);
+        }
+        self.push_pos_seq_logits(pos, seq_ids, logits);
+        Ok(())
+    }
+
+    /// Add a row-major `[n_tokens x n_embd]` matrix of embeddings to the batch for the given
+    /// sequence id, starting at position `pos_0`.
+    ///
+    /// If `logits_last` is true, the last embedding will have its logits initialized.
+    ///
+    /// # Returns
+    ///
+    /// The position after the last added embedding, i.e. where the next token of the sequence goes.
+    ///
+    /// # Errors
+    ///
+    /// - returns a error if there is insufficient space in the buffer
+    /// - returns a error if the batch was not created with [`LlamaBatch::new_embeddings`]
+    /// - returns a error if the length of `embds` is not a multiple of the batch's embedding size
+    ///
+    /// # Panics
+    ///
+    /// - [`self.llama_batch.n_tokens`] does not fit into a [`usize`]
+    ///
+    /// ```
+    /// # use llama_cpp_2::llama_batch::LlamaBatch;
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// let n_embd = 4;
+    /// let mut batch = LlamaBatch::new_embeddings(8, n_embd, 1);
+    /// let image = vec![0.5_f32; 3 * n_embd];
+    /// let n_past = batch.add_embeddings(&image, 10, 0, false)?;
+    /// assert_eq!(n_past, 13);
+    /// assert_eq!(batch.n_tokens(), 3);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn add_embeddings(
+        &mut self,
+        embds: &[f32],
+        pos_0: llama_pos,
+        seq_id: i32,
+        logits_last: bool,
+    ) -> Result<llama_pos, BatchAddError> {
+        if self.llama_batch.embd.is_null() {
+            return Err(BatchAddError::NotAnEmbeddingsBatch);
+        }
+        if self.n_embd == 0 || embds.len() % self.n_embd != 0 {
+            return Err(BatchAddError::EmbeddingSizeMismatch {
+                expected: self.n_embd,
+                actual: embds.len(),
+            });
+        }
+        let n_tokens_0 =
+            usize::try_from(self.llama_batch.n_tokens).expect("cannot fit n_tokens into a usize");
+        let n_tokens = embds.len() / self.n_embd;
+        if self.allocated < n_tokens_0 + n_tokens {
+            return Err(BatchAddError::InsufficientSpace(self.allocated));
+        }
+
+        let mut pos = pos_0;
+        for (i, embd) in embds.chunks_exact(self.n_embd).enumerate() {
+            self.add_embedding(embd, pos, &[seq_id], logits_last && i == n_tokens - 1)?;
+            pos += 1;
+        }
+
+        Ok(pos)
+    }
+
+    /// Write everything but the token / embedding for the next entry and bump `n_tokens`.
+    fn push_pos_seq_logits(&mut self, pos: llama_pos, seq_ids: &[i32], logits: bool) {
+        let offset = self.llama_batch.n_tokens;
+        let offset_usize = usize::try_from(offset).expect("cannot fit n_tokens into a usize");
+        unsafe {
             // batch.pos     [batch.n_tokens] = pos,
             self.llama_batch.pos.add(offset_usize).write(pos);
             // batch.n_seq_id[batch.n_tokens] = seq_ids.size();
@@ -87,8 +230,134 @@ impl LlamaBatch {
 
         // batch.n_tokens++;
         self.llama_batch.n_tokens += 1;
+    }
 
-        Ok(())
+    /// Whether the `i`th token of the batch belongs to `seq_id`. False for indices past the tokens
+    /// added so far.
+    pub(crate) fn has_seq_id(&self, i: i32, seq_id: i32) -> bool {
+        self.seq_ids(i).contains(&seq_id)
+    }
+
+    /// The token and position of the `i`th token of the batch, with no token for embeddings.
+    /// `None` for indices past the tokens added so far.
+    pub(crate) fn token_pos(&self, i: i32) -> Option<(Option<LlamaToken>, llama_pos)> {
+        if !(0..self.n_tokens()).contains(&i) {
+            return None;
+        }
+        let i = usize::try_from(i).expect("i is not negative");
+        unsafe {
+            let token = (!self.llama_batch.token.is_null())
+                .then(|| LlamaToken(*self.llama_batch.token.add(i)));
+            Some((token, *self.llama_batch.pos.add(i)))
+        }
+    }
+
+    /// The sequences of the `i`th token of the batch. Empty for indices past the tokens added so
+    /// far.
+    pub(crate) fn seq_ids(&self, i: i32) -> &[llama_seq_id] {
+        if !(0..self.n_tokens()).contains(&i) {
+            return &[];
+        }
+        let i = usize::try_from(i).expect("i is not negative");
+        unsafe {
+            let n_seq_id = usize::try_from(*self.llama_batch.n_seq_id.add(i)).unwrap_or(0);
+            std::slice::from_raw_parts(*self.llama_batch.seq_id.add(i), n_seq_id)
+        }
+    }
+
+    /// Add `tokens` to `seq_id` at positions starting from `pos`, requesting logits for the last
+    /// one only. Tokens added to `seq_id` alone before lose their logits, so when every sequence
+    /// is added this way exactly its final token has logits. After decoding, find them with
+    /// [`LlamaBatch::seq_logits_index`] or [`LlamaContext::seq_logits`](crate::context::LlamaContext::seq_logits)
+    /// instead of tracking batch indices by hand.
+    ///
+    /// ```
+    /// # use llama_cpp_2::llama_batch::LlamaBatch;
+    /// # use llama_cpp_2::token::LlamaToken;
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// let tokens = [LlamaToken::new(1), LlamaToken::new(2), LlamaToken::new(3)];
+    /// let mut batch = LlamaBatch::new(8, 2);
+    /// batch.add_sequence_at(&tokens[..2], 0, 0)?;
+    /// batch.add_sequence_at(&tokens, 5, 1)?;
+    /// batch.add_sequence_at(&tokens[2..], 2, 0)?;
+    /// assert_eq!(batch.seq_logits_index(0), Some(5));
+    /// assert_eq!(batch.seq_logits_index(1), Some(4));
+    /// assert_eq!(batch.logits_indices().len(), 2);
+    /// # Ok(())
+    /// # }
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if there is insufficient space in the buffer or the batch was created
+    /// with [`LlamaBatch::new_embeddings`]. Nothing is added then.
+    ///
+    /// # Panics
+    ///
+    /// - [`self.llama_batch.n_tokens`] does not fit into a [`usize`]
+    /// - a position does not fit into a [`llama_pos`]
+    pub fn add_sequence_at(
+        &mut self,
+        tokens: &[LlamaToken],
+        pos: llama_pos,
+        seq_id: i32,
+    ) -> Result<(), BatchAddError> {
+        if self.llama_batch.token.is_null() {
+            return Err(BatchAddError::NotATokenBatch);
+        }
+        let n_tokens_0 =
+            usize::try_from(self.llama_batch.n_tokens).expect("cannot fit n_tokens into a usize");
+        if self.allocated < n_tokens_0 + tokens.len() {
+            return Err(BatchAddError::InsufficientSpace(self.allocated));
+        }
+        let Some((last, init)) = tokens.split_last() else {
+            return Ok(());
+        };
+
+        let earlier: Vec<i32> = self
+            .initialized_logits
+            .iter()
+            .copied()
+            .filter(|&i| self.seq_ids(i) == [seq_id])
+            .collect();
+        for i in earlier {
+            let offset = usize::try_from(i).expect("i is not negative");
+            unsafe { self.llama_batch.logits.add(offset).write(0) };
+            self.initialized_logits.retain(|&l| l != i);
+        }
+
+        let mut pos = pos;
+        for &token in init {
+            self.add(token, pos, &[seq_id], false)?;
+            pos = pos.checked_add(1).expect("pos fits into a llama_pos");
+        }
+        self.add(*last, pos, &[seq_id], true)
+    }
+
+    /// The index of the last token of `seq_id` with logits in the batch, which is the `i` to pass
+    /// to [`LlamaContext::try_get_logits_ith`](crate::context::LlamaContext::try_get_logits_ith)
+    /// or the samplers after decoding it. `None` if no token of `seq_id` has logits.
+    #[must_use]
+    pub fn seq_logits_index(&self, seq_id: i32) -> Option<i32> {
+        self.initialized_logits
+            .iter()
+            .copied()
+            .filter(|&i| self.has_seq_id(i, seq_id))
+            .max()
+    }
+
+    /// [`LlamaBatch::seq_logits_index`] of every sequence with logits in the batch, by sequence
+    /// id.
+    #[must_use]
+    pub fn logits_indices(&self) -> BTreeMap<i32, i32> {
+        let mut indices = BTreeMap::new();
+        for &i in &self.initialized_logits {
+            for &seq_id in self.seq_ids(i) {
+                let index = indices.entry(seq_id).or_insert(i);
+                *index = (*index).max(i);
+            }
+        }
+        indices
     }
 
     /// Add a sequence of tokens to the batch for the given sequence id. If `logits_all` is true, the
@@ -144,11 +413,65 @@ impl LlamaBatch {
 
         LlamaBatch {
             allocated: n_tokens,
+            n_embd: 0,
+            initialized_logits: vec![],
+            llama_batch: batch,
+        }
+    }
+
+    /// Create a new `LlamaBatch` that can contain up to `n_tokens` embeddings of size `n_embd`
+    /// instead of tokens. Used to feed externally computed embeddings (e.g. from an image or audio
+    /// encoder) into the model. `n_embd` must match [`crate::model::LlamaModel::n_embd`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if `n_tokens` or `n_embd` is greater than `i32::MAX`.
+    #[must_use]
+    pub fn new_embeddings(n_tokens: usize, n_embd: usize, n_seq_max: i32) -> Self {
+        let n_tokens_i32 = i32::try_from(n_tokens).expect("cannot fit n_tokens into a i32");
+        let n_embd_i32 = i32::try_from(n_embd).expect("cannot fit n_embd into a i32");
+        let batch = unsafe { llama_batch_init(n_tokens_i32, n_embd_i32, n_seq_max) };
+
+        LlamaBatch {
+            allocated: n_tokens,
+            n_embd,
             initialized_logits: vec![],
             llama_batch: batch,
         }
     }
 
+    /// Create a batch holding exactly `tokens` on sequence 0 at positions `0..tokens.len()` with
+    /// logits enabled for the last token, the equivalent of `llama_batch_get_one` for prompts.
+    ///
+    /// Positions always start at 0, so this is meant for the first decode of a sequence. Use
+    /// [`LlamaBatch::add`] with explicit positions to continue it.
+    ///
+    /// # Errors
+    ///
+    /// Never in practice, the batch is allocated with room for every token.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `tokens.len()` is greater than `i32::MAX`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use llama_cpp_2::llama_batch::LlamaBatch;
+    /// # use llama_cpp_2::token::LlamaToken;
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// let tokens = [LlamaToken::new(1), LlamaToken::new(2), LlamaToken::new(3)];
+    /// let batch = LlamaBatch::get_one(&tokens)?;
+    /// assert_eq!(batch.n_tokens(), 3);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn get_one(tokens: &[LlamaToken]) -> Result<Self, BatchAddError> {
+        let mut batch = Self::new(tokens.len().max(1), 1);
+        batch.add_sequence(tokens, 0, false)?;
+        Ok(batch)
+    }
+
     /// Returns the number of tokens in the batch.
     #[must_use]
     pub fn n_tokens(&self) -> i32 {
@@ -0,0 +1,84 @@
+//! Query the memory budget of the Metal device.
+//!
+//! Metal does not fail gracefully when an app allocates more than the device's working set, on
+//! iOS the app is killed. Compare [`device_memory`] with a
+//! [`MemoryEstimate`](crate::gguf::estimate::MemoryEstimate) to pick `n_gpu_layers` and `n_ctx`
+//! before loading a model.
+//!
+//! ```no_run
+//! # use llama_cpp_2::gguf::GgufFile;
+//! # use llama_cpp_2::gguf::estimate::{estimate_memory, MemoryEstimateParams};
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! let memory = llama_cpp_2::metal::device_memory().expect("no metal device");
+//! let gguf = GgufFile::open("path/to/model.gguf")?;
+//! let params = MemoryEstimateParams { n_ctx: 4096, n_gpu_layers: 99, ..Default::default() };
+//! if estimate_memory(&gguf, &params)?.gpu.total() > memory.available() {
+//!     println!("does not fit, offload fewer layers or use a smaller context");
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use std::ffi::{c_char, c_void};
+
+#[link(name = "Metal", kind = "framework")]
+extern "C" {
+    fn MTLCreateSystemDefaultDevice() -> *mut c_void;
+}
+
+#[link(name = "objc")]
+extern "C" {
+    fn sel_registerName(name: *const c_char) -> *const c_void;
+    fn objc_msgSend();
+}
+
+/// The memory of the default Metal device in bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MetalDeviceMemory {
+    /// `recommendedMaxWorkingSetSize`: how much memory the device can use without affecting its
+    /// performance (or, on iOS, getting the app killed).
+    pub recommended_max_working_set_size: u64,
+    /// `currentAllocatedSize`: how much memory the process has currently allocated on the device.
+    pub current_allocated_size: u64,
+    /// Whether the device shares its memory with the CPU (Apple silicon).
+    pub has_unified_memory: bool,
+}
+
+impl MetalDeviceMemory {
+    /// The memory that can still be allocated within the recommended working set.
+    #[must_use]
+    pub fn available(&self) -> u64 {
+        self.recommended_max_working_set_size
+            .saturating_sub(self.current_allocated_size)
+    }
+}
+
+/// Send the argument-less message `selector` (a nul terminated byte string) to `object`.
+unsafe fn send<R>(object: *mut c_void, selector: &[u8]) -> R {
+    let send: unsafe extern "C" fn(*mut c_void, *const c_void) -> R =
+        std::mem::transmute(objc_msgSend as unsafe extern "C" fn());
+    send(object, sel_registerName(selector.as_ptr().cast()))
+}
+
+/// Query the memory of the system default Metal device. `None` if there is no Metal device.
+#[must_use]
+pub fn device_memory() -> Option<MetalDeviceMemory> {
+    let device = unsafe { MTLCreateSystemDefaultDevice() };
+    if device.is_null() {
+        return None;
+    }
+    let memory = unsafe {
+        MetalDeviceMemory {
+            recommended_max_working_set_size: send::<u64>(
+                device,
+                b"recommendedMaxWorkingSetSize\0",
+            ),
+            // an NSUInteger, which is 64 bit on every platform with Metal
+            current_allocated_size: send::<u64>(device, b"currentAllocatedSize\0"),
+            has_unified_memory: send::<bool>(device, b"hasUnifiedMemory\0"),
+        }
+    };
+    // MTLCreateSystemDefaultDevice returns a retained object
+    unsafe { send::<()>(device, b"release\0") };
+    Some(memory)
+}
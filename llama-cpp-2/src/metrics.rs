@@ -0,0 +1,65 @@
+//! Counters, histograms and gauges recorded through the [`metrics`] facade.
+//!
+//! With the `metrics` feature, decoding and generation record the metrics below into whichever
+//! recorder the application installed, e.g. `metrics-exporter-prometheus` to serve them on a
+//! `/metrics` endpoint. Without a recorder recording is a no-op. Call [`describe`] once after
+//! installing the recorder to register units and help texts.
+
+use std::time::Duration;
+
+use metrics::Unit;
+
+/// Counter of tokens sampled by [`LlamaContext::generate`](crate::context::LlamaContext::generate).
+pub const TOKENS_GENERATED: &str = "llama_tokens_generated_total";
+/// Counter of prompt tokens decoded by
+/// [`LlamaContext::generate`](crate::context::LlamaContext::generate).
+pub const PROMPT_TOKENS: &str = "llama_prompt_tokens_total";
+/// Counter of tokens passed to [`LlamaContext::decode`](crate::context::LlamaContext::decode).
+pub const DECODED_TOKENS: &str = "llama_decoded_tokens_total";
+/// Histogram of the duration of [`LlamaContext::decode`](crate::context::LlamaContext::decode)
+/// calls in seconds.
+pub const DECODE_DURATION: &str = "llama_decode_duration_seconds";
+/// Gauge of the number of used KV cache cells after the latest decode.
+pub const KV_CACHE_USED_CELLS: &str = "llama_kv_cache_used_cells";
+/// Gauge of the fraction of the KV cache in use after the latest decode.
+pub const KV_CACHE_USAGE: &str = "llama_kv_cache_usage_ratio";
+/// Gauge of requests waiting for a free sequence, set with [`set_queue_depth`].
+pub const QUEUE_DEPTH: &str = "llama_queue_depth";
+
+/// Register units and descriptions of all metrics with the installed recorder.
+pub fn describe() {
+    metrics::describe_counter!(TOKENS_GENERATED, Unit::Count, "Tokens generated");
+    metrics::describe_counter!(PROMPT_TOKENS, Unit::Count, "Prompt tokens processed");
+    metrics::describe_counter!(DECODED_TOKENS, Unit::Count, "Tokens decoded");
+    metrics::describe_histogram!(DECODE_DURATION, Unit::Seconds, "Duration of a decode call");
+    metrics::describe_gauge!(KV_CACHE_USED_CELLS, Unit::Count, "Used KV cache cells");
+    metrics::describe_gauge!(
+        KV_CACHE_USAGE,
+        Unit::Percent,
+        "Fraction of the KV cache in use"
+    );
+    metrics::describe_gauge!(QUEUE_DEPTH, Unit::Count, "Requests waiting for a sequence");
+}
+
+/// Set the number of requests waiting for a free sequence (or slot) of a context.
+#[allow(clippy::cast_precision_loss)]
+pub fn set_queue_depth(depth: usize) {
+    metrics::gauge!(QUEUE_DEPTH).set(depth as f64);
+}
+
+pub(crate) fn record_decode(n_tokens: i32, duration: Duration, used_cells: i32, n_ctx: u32) {
+    metrics::counter!(DECODED_TOKENS).increment(u64::try_from(n_tokens).unwrap_or(0));
+    metrics::histogram!(DECODE_DURATION).record(duration.as_secs_f64());
+    metrics::gauge!(KV_CACHE_USED_CELLS).set(f64::from(used_cells));
+    if n_ctx > 0 {
+        metrics::gauge!(KV_CACHE_USAGE).set(f64::from(used_cells) / f64::from(n_ctx));
+    }
+}
+
+pub(crate) fn record_prompt(n_tokens: usize) {
+    metrics::counter!(PROMPT_TOKENS).increment(u64::try_from(n_tokens).unwrap_or(u64::MAX));
+}
+
+pub(crate) fn record_generated_token() {
+    metrics::counter!(TOKENS_GENERATED).increment(1);
+}
@@ -8,7 +8,8 @@ use std::ptr::NonNull;
 
 use crate::context::params::LlamaContextParams;
 use crate::context::LlamaContext;
-use crate::llama_backend::LlamaBackend;
+use crate::ggml_type::GgmlFileType;
+use crate::llama_backend::{with_captured_logs, LlamaBackend};
 use crate::model::params::LlamaModelParams;
 use crate::token::LlamaToken;
 use crate::token_type::{LlamaTokenAttr, LlamaTokenAttrs};
@@ -17,7 +18,12 @@ use crate::{
     LlamaModelLoadError, NewLlamaChatMessageError, StringToTokenError, TokenToStringError,
 };
 
+#[cfg(feature = "hf-hub")]
+pub mod hf;
+pub mod manager;
 pub mod params;
+pub mod registry;
+pub mod vocab;
 
 /// A safe wrapper around `llama_model`.
 #[derive(Debug)]
@@ -50,12 +56,27 @@ impl LlamaChatMessage {
             content: CString::new(content)?,
         })
     }
+
+    /// The role of the message.
+    #[must_use]
+    pub fn role(&self) -> &str {
+        // always valid UTF-8 as it was created from a String
+        self.role.to_str().unwrap_or_default()
+    }
+
+    /// The content of the message.
+    #[must_use]
+    pub fn content(&self) -> &str {
+        self.content.to_str().unwrap_or_default()
+    }
 }
 
 /// How to determine if we should prepend a bos token to tokens
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum AddBos {
-    /// Add the beginning of stream token to the start of the string.
+    /// Add the special tokens the model adds when tokenizing: the beginning of stream token at
+    /// the start of the string if [`LlamaModel::add_bos_token`], the end of stream token at the
+    /// end if [`LlamaModel::add_eos_token`].
     Always,
     /// Do not add the beginning of stream token to the start of the string.
     Never,
@@ -87,6 +108,35 @@ impl LlamaModel {
         u32::try_from(n_ctx_train).expect("n_ctx_train fits into an u32")
     }
 
+    /// The size of the sliding attention window of models that use sliding window attention
+    /// (Gemma 2, Mistral), or `None` if every layer attends to the whole context. Tokens further
+    /// back than the window are invisible to the sliding layers, even though they are still in
+    /// the KV cache.
+    ///
+    /// The linked llama.cpp has neither `llama_model_n_swa` nor the `swa_full` context parameter,
+    /// so [`LlamaContextParams`](crate::context::params::LlamaContextParams) has no `swa_full`
+    /// option yet. llama.cpp always keeps the full cache for these models, so
+    /// [`LlamaContext::clear_kv_cache_seq`] and [`LlamaContext::kv_cache_shift`] work on them as on
+    /// any other model. This reads the `<arch>.attention.sliding_window` metadata instead.
+    #[must_use]
+    pub fn n_swa(&self) -> Option<u32> {
+        let arch = self.meta_val_str("general.architecture")?;
+        self.meta_val_str(&format!("{arch}.attention.sliding_window"))?
+            .parse()
+            .ok()
+            .filter(|&n_swa| n_swa > 0)
+    }
+
+    /// Whether tokens attend only to the tokens before them, read from the
+    /// `<arch>.attention.causal` metadata. Only embedding encoders such as BERT and nomic-bert
+    /// set it to `false`; they need every sequence in a single ubatch.
+    #[must_use]
+    pub fn has_causal_attention(&self) -> bool {
+        self.meta_val_str("general.architecture")
+            .and_then(|arch| self.meta_val_str(&format!("{arch}.attention.causal")))
+            .map_or(true, |causal| causal != "false")
+    }
+
     /// Get all tokens in the model.
     pub fn tokens(
         &self,
@@ -111,6 +161,23 @@ impl LlamaModel {
         LlamaToken(token)
     }
 
+    /// Whether the model was trained with the beginning of stream token in front of every
+    /// sequence, from `tokenizer.ggml.add_bos_token` or else the default of its tokenizer type.
+    /// [`AddBos::Always`] only adds the token if this is true; prompts assembled from tokens by
+    /// hand should check it before prepending [`LlamaModel::token_bos`].
+    #[must_use]
+    pub fn add_bos_token(&self) -> bool {
+        unsafe { llama_cpp_sys_2::llama_add_bos_token(self.model.as_ptr()) == 1 }
+    }
+
+    /// Whether the model was trained with the end of stream token after every sequence, from
+    /// `tokenizer.ggml.add_eos_token` or else the default of its tokenizer type.
+    /// [`AddBos::Always`] appends it if this is true.
+    #[must_use]
+    pub fn add_eos_token(&self) -> bool {
+        unsafe { llama_cpp_sys_2::llama_add_eos_token(self.model.as_ptr()) == 1 }
+    }
+
     /// Get the newline token.
     #[must_use]
     pub fn token_nl(&self) -> LlamaToken {
@@ -118,6 +185,29 @@ impl LlamaModel {
         LlamaToken(token)
     }
 
+    /// Get the separator token of BERT style models (`[SEP]`). Negative if the model has none.
+    #[must_use]
+    pub fn token_sep(&self) -> LlamaToken {
+        let token = unsafe { llama_cpp_sys_2::llama_token_sep(self.model.as_ptr()) };
+        LlamaToken(token)
+    }
+
+    /// Get the classification token of BERT style models (`[CLS]`). Negative if the model has
+    /// none.
+    #[must_use]
+    pub fn token_cls(&self) -> LlamaToken {
+        let token = unsafe { llama_cpp_sys_2::llama_token_cls(self.model.as_ptr()) };
+        LlamaToken(token)
+    }
+
+    /// Whether the model has an encoder (T5), whose input has to be
+    /// [encoded](crate::context::LlamaContext::encode) before decoding, see
+    /// [`LlamaContext::generate_seq2seq`](crate::context::LlamaContext::generate_seq2seq).
+    #[must_use]
+    pub fn has_encoder(&self) -> bool {
+        unsafe { llama_cpp_sys_2::llama_model_has_encoder(self.model.as_ptr()) }
+    }
+
     /// Get the decoder start token token.
     #[must_use]
     pub fn decode_start_token(&self) -> LlamaToken {
@@ -126,6 +216,40 @@ impl LlamaModel {
         LlamaToken(token)
     }
 
+    /// Get the fill-in-the-middle prefix token. Negative if the model has none.
+    #[must_use]
+    pub fn token_prefix(&self) -> LlamaToken {
+        let token = unsafe { llama_cpp_sys_2::llama_token_prefix(self.model.as_ptr()) };
+        LlamaToken(token)
+    }
+
+    /// Get the fill-in-the-middle middle token. Negative if the model has none.
+    #[must_use]
+    pub fn token_middle(&self) -> LlamaToken {
+        let token = unsafe { llama_cpp_sys_2::llama_token_middle(self.model.as_ptr()) };
+        LlamaToken(token)
+    }
+
+    /// Get the fill-in-the-middle suffix token. Negative if the model has none.
+    #[must_use]
+    pub fn token_suffix(&self) -> LlamaToken {
+        let token = unsafe { llama_cpp_sys_2::llama_token_suffix(self.model.as_ptr()) };
+        LlamaToken(token)
+    }
+
+    /// Get the end of turn token. Negative if the model has none.
+    #[must_use]
+    pub fn token_eot(&self) -> LlamaToken {
+        let token = unsafe { llama_cpp_sys_2::llama_token_eot(self.model.as_ptr()) };
+        LlamaToken(token)
+    }
+
+    /// Check if a token marks the end of generation (eos, eot, ...).
+    #[must_use]
+    pub fn is_eog_token(&self, LlamaToken(id): LlamaToken) -> bool {
+        unsafe { llama_cpp_sys_2::llama_token_is_eog(self.model.as_ptr(), id) }
+    }
+
     /// Convert single token to a string.
     ///
     /// # Errors
@@ -173,6 +297,65 @@ impl LlamaModel {
         Ok(builder)
     }
 
+    /// Convert a sequence of tokens back to the bytes they were tokenized from, using llama.cpp's
+    /// whole-sequence `llama_detokenize`.
+    ///
+    /// Unlike concatenating [`LlamaModel::token_to_bytes`] of every token, this applies the
+    /// tokenizer's rules across token boundaries, such as removing the space sentencepiece
+    /// prepends to the text. For tokens returned by [`LlamaModel::str_to_token`] with
+    /// [`AddBos::Never`] the result is the original text byte for byte, unless the tokenizer
+    /// normalizes its input (e.g. the lowercasing and whitespace cleanup of BERT vocabularies).
+    /// The result is bytes because a sequence cut at an arbitrary token may end inside a utf8
+    /// character.
+    ///
+    /// With [`Special::Tokenize`] special tokens are rendered as their text (`<|im_start|>`),
+    /// with [`Special::Plaintext`] they are omitted. `remove_special` drops the leading bos and
+    /// trailing eos token if the model adds them when tokenizing.
+    ///
+    /// # Errors
+    ///
+    /// - [`TokenToStringError::InsufficientBufferSpace`] if llama.cpp keeps asking for more space
+    ///   than it reported.
+    ///
+    /// # Panics
+    ///
+    /// - if there are more than [`i32::MAX`] tokens or bytes.
+    pub fn detokenize(
+        &self,
+        tokens: &[LlamaToken],
+        special: Special,
+        remove_special: bool,
+    ) -> Result<Vec<u8>, TokenToStringError> {
+        let n_tokens = c_int::try_from(tokens.len()).expect("n_tokens fits into a c_int");
+        let unparse_special = special == Special::Tokenize;
+        let mut buf = vec![0_u8; tokens.len() * 4 + 16];
+        for _ in 0..2 {
+            let len = c_int::try_from(buf.len()).expect("buffer size fits into a c_int");
+            let n_bytes = unsafe {
+                llama_cpp_sys_2::llama_detokenize(
+                    self.model.as_ptr(),
+                    tokens.as_ptr().cast::<llama_cpp_sys_2::llama_token>(),
+                    n_tokens,
+                    buf.as_mut_ptr().cast::<std::os::raw::c_char>(),
+                    len,
+                    remove_special,
+                    unparse_special,
+                )
+            };
+            match usize::try_from(n_bytes) {
+                Ok(n_bytes) => {
+                    buf.truncate(n_bytes);
+                    return Ok(buf);
+                }
+                // a negative result is the number of bytes needed
+                Err(_) => buf.resize(n_bytes.unsigned_abs() as usize, 0),
+            }
+        }
+        Err(TokenToStringError::InsufficientBufferSpace(
+            c_int::try_from(buf.len()).expect("buffer size fits into a c_int"),
+        ))
+    }
+
     /// Convert a string to a Vector of tokens.
     ///
     /// # Errors
@@ -200,6 +383,30 @@ impl LlamaModel {
         str: &str,
         add_bos: AddBos,
     ) -> Result<Vec<LlamaToken>, StringToTokenError> {
+        self.str_to_token_with_special(str, add_bos, Special::Tokenize)
+    }
+
+    /// Like [`LlamaModel::str_to_token`], but `special` decides whether the text of special
+    /// tokens (such as `<|im_start|>`) becomes those tokens or is tokenized as plain text.
+    ///
+    /// # Errors
+    ///
+    /// - if [`str`] contains a null byte.
+    ///
+    /// # Panics
+    ///
+    /// - if there is more than [`usize::MAX`] [`LlamaToken`]s in [`str`].
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "trace", skip_all, fields(n_bytes = str.len()))
+    )]
+    pub fn str_to_token_with_special(
+        &self,
+        str: &str,
+        add_bos: AddBos,
+        special: Special,
+    ) -> Result<Vec<LlamaToken>, StringToTokenError> {
+        let parse_special = special == Special::Tokenize;
         let add_bos = match add_bos {
             AddBos::Always => true,
             AddBos::Never => false,
@@ -220,7 +427,7 @@ impl LlamaModel {
                 buffer.as_mut_ptr(),
                 buffer_capacity,
                 add_bos,
-                true,
+                parse_special,
             )
         };
 
@@ -236,7 +443,7 @@ impl LlamaModel {
                     buffer.as_mut_ptr(),
                     -size,
                     add_bos,
-                    true,
+                    parse_special,
                 )
             }
         } else {
@@ -357,7 +564,7 @@ impl LlamaModel {
             }
         }
     }
-    /// The number of tokens the model was trained on.
+    /// The number of tokens in the vocabulary of the model. Every token id is below it.
     ///
     /// This returns a `c_int` for maximum compatibility. Most of the time it can be cast to an i32
     /// without issue.
@@ -384,6 +591,29 @@ impl LlamaModel {
         unsafe { llama_cpp_sys_2::llama_n_embd(self.model.as_ptr()) }
     }
 
+    /// The total size of the weights in bytes.
+    #[must_use]
+    pub fn size(&self) -> u64 {
+        unsafe { llama_cpp_sys_2::llama_model_size(self.model.as_ptr()) }
+    }
+
+    /// The number of parameters of the model.
+    #[must_use]
+    pub fn n_params(&self) -> u64 {
+        unsafe { llama_cpp_sys_2::llama_model_n_params(self.model.as_ptr()) }
+    }
+
+    /// The quantization the model file was made with, read from `general.file_type`. Returns
+    /// `None` if the file does not store it or it is unknown.
+    #[must_use]
+    pub fn file_type(&self) -> Option<GgmlFileType> {
+        let file_type = self
+            .meta_val_str("general.file_type")?
+            .parse::<llama_cpp_sys_2::llama_ftype>()
+            .ok()?;
+        GgmlFileType::try_from(file_type).ok()
+    }
+
     /// Get chat template from model.
     ///
     /// # Errors
@@ -421,12 +651,36 @@ impl LlamaModel {
         Ok(template.to_owned())
     }
 
+    /// Get a metadata value of the model as a string, e.g. `general.architecture`. Returns `None`
+    /// if the key is missing or contains a null byte.
+    #[must_use]
+    pub fn meta_val_str(&self, key: &str) -> Option<String> {
+        let key = CString::new(key).ok()?;
+        let mut buf = vec![0_u8; 128];
+        loop {
+            let len = unsafe {
+                llama_cpp_sys_2::llama_model_meta_val_str(
+                    self.model.as_ptr(),
+                    key.as_ptr(),
+                    buf.as_mut_ptr().cast(),
+                    buf.len(),
+                )
+            };
+            let len = usize::try_from(len).ok()?;
+            if len < buf.len() {
+                buf.truncate(len);
+                return Some(String::from_utf8_lossy(&buf).into_owned());
+            }
+            buf.resize(len + 1, 0);
+        }
+    }
+
     /// Loads a model from a file.
     ///
     /// # Errors
     ///
     /// See [`LlamaModelLoadError`] for more information.
-    #[tracing::instrument(skip_all, fields(params))]
+    #[tracing::instrument(skip_all, fields(params, path = %path.as_ref().display()))]
     pub fn load_from_file(
         _: &LlamaBackend,
         path: impl AsRef<Path>,
@@ -439,15 +693,56 @@ impl LlamaModel {
             .ok_or(LlamaModelLoadError::PathToStrError(path.to_path_buf()))?;
 
         let cstr = CString::new(path)?;
-        let llama_model =
-            unsafe { llama_cpp_sys_2::llama_load_model_from_file(cstr.as_ptr(), params.params) };
-
-        let model = NonNull::new(llama_model).ok_or(LlamaModelLoadError::NullResult)?;
+        let load =
+            || unsafe { llama_cpp_sys_2::llama_load_model_from_file(cstr.as_ptr(), params.params) };
+
+        let model = if params.check_tensors() {
+            let (llama_model, logs) = with_captured_logs(load);
+            NonNull::new(llama_model).ok_or_else(|| {
+                let invalid = invalid_tensors(&logs);
+                if invalid.is_empty() {
+                    LlamaModelLoadError::NullResult
+                } else {
+                    LlamaModelLoadError::InvalidTensors(invalid)
+                }
+            })?
+        } else {
+            NonNull::new(load()).ok_or(LlamaModelLoadError::NullResult)?
+        };
 
         tracing::debug!(?path, "Loaded model");
         Ok(LlamaModel { model })
     }
 
+    /// Loads only the vocabulary of a model from a file, see [`LlamaModelParams::with_vocab_only`].
+    /// [`LlamaVocab::load_from_file`](vocab::LlamaVocab::load_from_file) wraps it in a handle
+    /// that can only tokenize.
+    ///
+    /// # Errors
+    ///
+    /// See [`LlamaModelLoadError`] for more information.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use llama_cpp_2::llama_backend::LlamaBackend;
+    /// # use llama_cpp_2::model::{AddBos, LlamaModel};
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// let backend = LlamaBackend::init()?;
+    /// let vocab = LlamaModel::load_vocab_from_file(&backend, "path/to/model.gguf")?;
+    /// let n_tokens = vocab.str_to_token("Hello, World!", AddBos::Always)?.len();
+    /// println!("the prompt is {n_tokens} tokens long");
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn load_vocab_from_file(
+        backend: &LlamaBackend,
+        path: impl AsRef<Path>,
+    ) -> Result<Self, LlamaModelLoadError> {
+        let params = LlamaModelParams::default().with_vocab_only(true);
+        Self::load_from_file(backend, path, &params)
+    }
+
     /// Initializes a lora adapter from a file.
     ///
     /// # Errors
@@ -483,6 +778,8 @@ impl LlamaModel {
     /// # Errors
     ///
     /// There is many ways this can fail. See [`LlamaContextLoadError`] for more information.
+    /// The params are [validated](LlamaContextParams::validate) first, as llama.cpp aborts the
+    /// process on some invalid combinations.
     // we intentionally do not derive Copy on `LlamaContextParams` to allow llama.cpp to change the type to be non-trivially copyable.
     #[allow(clippy::needless_pass_by_value)]
     pub fn new_context(
@@ -490,6 +787,7 @@ impl LlamaModel {
         _: &LlamaBackend,
         params: LlamaContextParams,
     ) -> Result<LlamaContext, LlamaContextLoadError> {
+        params.validate(self)?;
         let context_params = params.context_params;
         let context = unsafe {
             llama_cpp_sys_2::llama_new_context_with_model(self.model.as_ptr(), context_params)
@@ -506,7 +804,7 @@ impl LlamaModel {
     ///
     /// # Errors
     /// There are many ways this can fail. See [`ApplyChatTemplateError`] for more information.
-    #[tracing::instrument(skip_all)]
+    #[tracing::instrument(skip_all, fields(n_messages = chat.len(), add_ass = add_ass))]
     pub fn apply_chat_template(
         &self,
         tmpl: Option<String>,
@@ -560,6 +858,25 @@ impl LlamaModel {
     }
 }
 
+/// The names of the tensors llama.cpp reported as invalid while loading with `check_tensors`
+/// (`tensor '<name>' has invalid data`).
+fn invalid_tensors(logs: &[String]) -> Vec<String> {
+    let mut names = Vec::new();
+    for line in logs {
+        let Some((_, rest)) = line.split_once("tensor '") else {
+            continue;
+        };
+        let Some((name, rest)) = rest.split_once('\'') else {
+            continue;
+        };
+        // the same tensor can be reported by the validation and the resulting load error
+        if rest.starts_with(" has invalid data") && !names.iter().any(|n| n == name) {
+            names.push(name.to_string());
+        }
+    }
+    names
+}
+
 impl Drop for LlamaModel {
     fn drop(&mut self) {
         unsafe { llama_cpp_sys_2::llama_free_model(self.model.as_ptr()) }
@@ -574,6 +891,10 @@ pub enum VocabType {
     BPE = llama_cpp_sys_2::LLAMA_VOCAB_TYPE_BPE as _,
     /// Sentence Piece Tokenizer
     SPM = llama_cpp_sys_2::LLAMA_VOCAB_TYPE_SPM as _,
+    /// Word Piece Tokenizer, used by BERT style embedding models
+    WPM = llama_cpp_sys_2::LLAMA_VOCAB_TYPE_WPM as _,
+    /// Unigram Tokenizer, used by T5
+    UGM = llama_cpp_sys_2::LLAMA_VOCAB_TYPE_UGM as _,
 }
 
 /// There was an error converting a `llama_vocab_type` to a `VocabType`.
@@ -591,6 +912,8 @@ impl TryFrom<llama_cpp_sys_2::llama_vocab_type> for VocabType {
         match value {
             llama_cpp_sys_2::LLAMA_VOCAB_TYPE_BPE => Ok(VocabType::BPE),
             llama_cpp_sys_2::LLAMA_VOCAB_TYPE_SPM => Ok(VocabType::SPM),
+            llama_cpp_sys_2::LLAMA_VOCAB_TYPE_WPM => Ok(VocabType::WPM),
+            llama_cpp_sys_2::LLAMA_VOCAB_TYPE_UGM => Ok(VocabType::UGM),
             unknown => Err(LlamaTokenTypeFromIntError::UnknownValue(unknown)),
         }
     }
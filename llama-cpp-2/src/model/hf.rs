@@ -0,0 +1,125 @@
+//! Download models from the Hugging Face Hub.
+//!
+//! Files are stored in the standard Hugging Face cache (`~/.cache/huggingface/hub`, or
+//! `$HF_HOME`), so a model that was downloaded before is loaded without touching the network.
+//!
+//! Interrupted downloads are not resumed: the synchronous API of `hf-hub` 0.3 downloads every
+//! file into a freshly named temporary file, so the next call starts the file over. Files
+//! that completed before the interruption, such as the earlier parts of a split model, are
+//! cached and not downloaded again.
+
+use std::path::PathBuf;
+
+use hf_hub::api::sync::{ApiBuilder, ApiError};
+
+use crate::llama_backend::LlamaBackend;
+use crate::model::params::LlamaModelParams;
+use crate::model::LlamaModel;
+use crate::LlamaModelLoadError;
+
+/// Failed to download or load a model from the Hugging Face Hub.
+#[derive(Debug, thiserror::Error)]
+pub enum LlamaModelFromHfError {
+    /// The Hugging Face API client could not be created.
+    #[error("failed to create the Hugging Face API client: {0}")]
+    Api(#[source] ApiError),
+    /// A file could not be downloaded.
+    #[error("failed to download {file}: {source}")]
+    Download {
+        /// The file that failed to download.
+        file: String,
+        /// The error of the download.
+        #[source]
+        source: ApiError,
+    },
+    /// The downloaded model could not be loaded.
+    #[error("{0}")]
+    Load(#[from] LlamaModelLoadError),
+}
+
+/// The files of a model split with `gguf-split`: `file` is the first split
+/// (`<name>-00001-of-<n>.gguf`) and the other splits are next to it. `None` if `file` is not the
+/// first split.
+fn splits(file: &str) -> Option<Vec<String>> {
+    let stem = file.strip_suffix(".gguf")?;
+    let (prefix, count) = stem.rsplit_once("-of-")?;
+    let prefix = prefix.strip_suffix("-00001")?;
+    if count.len() != 5 || !count.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let count: u32 = count.parse().ok()?;
+    Some(
+        (1..=count)
+            .map(|i| format!("{prefix}-{i:05}-of-{count:05}.gguf"))
+            .collect(),
+    )
+}
+
+impl LlamaModel {
+    /// Download `file` from the model repository `repo` (e.g. `"org/repo"`) into the Hugging Face
+    /// cache and return its local path.
+    ///
+    /// If `file` is the first part of a split model (`<name>-00001-of-<n>.gguf`) all parts are
+    /// downloaded and the path of the first one is returned, which is what
+    /// [`LlamaModel::load_from_file`] expects. A file whose download fails is downloaded from
+    /// the start on the next call, see the [module docs](self).
+    ///
+    /// # Errors
+    ///
+    /// - if the API client cannot be created or a file cannot be downloaded.
+    pub fn download_from_hf(repo: &str, file: &str) -> Result<PathBuf, LlamaModelFromHfError> {
+        let api = ApiBuilder::new()
+            .with_progress(true)
+            .build()
+            .map_err(LlamaModelFromHfError::Api)?;
+        let repo = api.model(repo.to_string());
+
+        let files = splits(file).unwrap_or_else(|| vec![file.to_string()]);
+        let mut first = None;
+        for file in files {
+            tracing::debug!(%file, "downloading from the Hugging Face Hub");
+            let path = repo
+                .get(&file)
+                .map_err(|source| LlamaModelFromHfError::Download { file, source })?;
+            first.get_or_insert(path);
+        }
+        Ok(first.expect("at least one file is downloaded"))
+    }
+
+    /// Download `file` from `repo` with [`LlamaModel::download_from_hf`] and load it, like
+    /// llama.cpp's `-hf` option.
+    ///
+    /// # Errors
+    ///
+    /// See [`LlamaModelFromHfError`].
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use llama_cpp_2::llama_backend::LlamaBackend;
+    /// # use llama_cpp_2::model::params::LlamaModelParams;
+    /// # use llama_cpp_2::model::LlamaModel;
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// let backend = LlamaBackend::init()?;
+    /// let model = LlamaModel::from_hf(
+    ///     &backend,
+    ///     "TheBloke/Llama-2-7B-Chat-GGUF",
+    ///     "llama-2-7b-chat.Q4_K_M.gguf",
+    ///     &LlamaModelParams::default(),
+    /// )?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn from_hf(
+        backend: &LlamaBackend,
+        repo: &str,
+        file: &str,
+        params: &LlamaModelParams,
+    ) -> Result<Self, LlamaModelFromHfError> {
+        let path = Self::download_from_hf(repo, file)?;
+        Ok(Self::load_from_file(backend, path, params)?)
+    }
+}
+
+#[cfg(test)]
+mod tests;
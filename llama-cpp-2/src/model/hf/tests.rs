@@ -0,0 +1,31 @@
+use super::*;
+
+#[test]
+fn first_split_lists_every_split() {
+    assert_eq!(
+        splits("model-Q4_K_M-00001-of-00003.gguf"),
+        Some(vec![
+            "model-Q4_K_M-00001-of-00003.gguf".to_string(),
+            "model-Q4_K_M-00002-of-00003.gguf".to_string(),
+            "model-Q4_K_M-00003-of-00003.gguf".to_string(),
+        ])
+    );
+}
+
+#[test]
+fn other_splits_are_downloaded_alone() {
+    assert_eq!(splits("model-00002-of-00003.gguf"), None);
+}
+
+#[test]
+fn split_count_has_five_digits() {
+    assert_eq!(splits("model-00001-of-3.gguf"), None);
+    assert_eq!(splits("model-00001-of-000003.gguf"), None);
+    assert_eq!(splits("model-00001-of-0000x.gguf"), None);
+}
+
+#[test]
+fn plain_file_is_not_split() {
+    assert_eq!(splits("llama-2-7b-chat.Q4_K_M.gguf"), None);
+    assert_eq!(splits("model-00001-of-00003.bin"), None);
+}
@@ -0,0 +1,150 @@
+//! Swap the model of a long running process without restarting it.
+//!
+//! A [`ModelManager`] hands out the current model as an [`Arc<LlamaModel>`]. A request keeps its
+//! `Arc` (and creates its contexts from it) for as long as it runs, so replacing the model only
+//! affects requests that start afterwards. The old model is unloaded when the last request using
+//! it drops its `Arc`.
+//!
+//! ```no_run
+//! # use std::sync::Arc;
+//! # use llama_cpp_2::llama_backend::LlamaBackend;
+//! # use llama_cpp_2::model::manager::ModelManager;
+//! # use llama_cpp_2::model::params::LlamaModelParams;
+//! # use llama_cpp_2::model::LlamaModel;
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! let backend = Arc::new(LlamaBackend::init()?);
+//! let manager = Arc::new(ModelManager::load(backend, "model-v1.gguf", &LlamaModelParams::default())?);
+//!
+//! // in a request handler
+//! let model = manager.current();
+//! // ... create a context from `model` and generate
+//!
+//! // later, e.g. from an admin endpoint
+//! let reload = manager.load_in_background(|backend| {
+//!     LlamaModel::load_from_file(backend, "model-v2.gguf", &LlamaModelParams::default())
+//! });
+//! reload.join().expect("model loading thread panicked")?;
+//! assert_eq!(manager.version(), 1);
+//! # Ok(())
+//! # }
+//! ```
+
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, PoisonError, RwLock, Weak};
+use std::thread::JoinHandle;
+
+use crate::llama_backend::LlamaBackend;
+use crate::model::params::LlamaModelParams;
+use crate::model::LlamaModel;
+use crate::LlamaModelLoadError;
+
+/// Owns the model currently used for new requests and keeps track of replaced models that are
+/// still in use.
+#[derive(Debug)]
+pub struct ModelManager {
+    backend: Arc<LlamaBackend>,
+    current: RwLock<Arc<LlamaModel>>,
+    version: AtomicU64,
+    retired: Mutex<Vec<Weak<LlamaModel>>>,
+}
+
+impl ModelManager {
+    /// Create a manager serving `model`.
+    #[must_use]
+    pub fn new(backend: Arc<LlamaBackend>, model: LlamaModel) -> Self {
+        Self {
+            backend,
+            current: RwLock::new(Arc::new(model)),
+            version: AtomicU64::new(0),
+            retired: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Load `path` and create a manager serving it.
+    ///
+    /// # Errors
+    ///
+    /// See [`LlamaModelLoadError`].
+    pub fn load(
+        backend: Arc<LlamaBackend>,
+        path: impl AsRef<Path>,
+        params: &LlamaModelParams,
+    ) -> Result<Self, LlamaModelLoadError> {
+        let model = LlamaModel::load_from_file(&backend, path, params)?;
+        Ok(Self::new(backend, model))
+    }
+
+    /// The backend models are loaded with.
+    #[must_use]
+    pub fn backend(&self) -> &Arc<LlamaBackend> {
+        &self.backend
+    }
+
+    /// The model new requests should use. Keep the returned `Arc` until the request is done.
+    #[must_use]
+    pub fn current(&self) -> Arc<LlamaModel> {
+        Arc::clone(&self.current.read().unwrap_or_else(PoisonError::into_inner))
+    }
+
+    /// How often the model was replaced. Useful to tag responses with the model that produced
+    /// them.
+    #[must_use]
+    pub fn version(&self) -> u64 {
+        self.version.load(Ordering::Acquire)
+    }
+
+    /// Serve `model` to new requests. The previous model is unloaded once no request uses it
+    /// anymore.
+    pub fn replace(&self, model: LlamaModel) {
+        let model = Arc::new(model);
+        let previous = {
+            let mut current = self.current.write().unwrap_or_else(PoisonError::into_inner);
+            self.version.fetch_add(1, Ordering::AcqRel);
+            std::mem::replace(&mut *current, model)
+        };
+        let mut retired = self.retired.lock().unwrap_or_else(PoisonError::into_inner);
+        retired.retain(|model| model.strong_count() > 0);
+        retired.push(Arc::downgrade(&previous));
+        tracing::info!(
+            version = self.version(),
+            draining = retired.len(),
+            "replaced model"
+        );
+    }
+
+    /// Load a model on a background thread with `load` and [`replace`](Self::replace) the current
+    /// one with it once it is loaded. Requests keep being served by the current model while the
+    /// new one loads. If loading fails the current model stays in place.
+    ///
+    /// The returned handle yields the result of `load`.
+    ///
+    /// # Panics
+    ///
+    /// - if the thread cannot be spawned
+    pub fn load_in_background<F>(
+        self: &Arc<Self>,
+        load: F,
+    ) -> JoinHandle<Result<(), LlamaModelLoadError>>
+    where
+        F: FnOnce(&LlamaBackend) -> Result<LlamaModel, LlamaModelLoadError> + Send + 'static,
+    {
+        let manager = Arc::clone(self);
+        std::thread::Builder::new()
+            .name("llama-model-load".to_string())
+            .spawn(move || {
+                let model = load(&manager.backend)?;
+                manager.replace(model);
+                Ok(())
+            })
+            .expect("failed to spawn the model loading thread")
+    }
+
+    /// The number of replaced models that are still in use by requests and therefore loaded.
+    #[must_use]
+    pub fn draining(&self) -> usize {
+        let mut retired = self.retired.lock().unwrap_or_else(PoisonError::into_inner);
+        retired.retain(|model| model.strong_count() > 0);
+        retired.len()
+    }
+}
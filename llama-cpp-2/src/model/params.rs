@@ -126,6 +126,12 @@ impl LlamaModelParams {
         self.params.vocab_only
     }
 
+    /// validate the data of every tensor while loading
+    #[must_use]
+    pub fn check_tensors(&self) -> bool {
+        self.params.check_tensors
+    }
+
     /// use mmap if possible
     #[must_use]
     pub fn use_mmap(&self) -> bool {
@@ -161,13 +167,45 @@ impl LlamaModelParams {
         self
     }
 
-    /// sets `vocab_only`
+    /// sets `vocab_only`. When set only the vocabulary is loaded and none of the weights, which
+    /// makes loading take milliseconds instead of seconds. This is enough for everything
+    /// tokenizer related ([`LlamaModel::str_to_token`], [`LlamaModel::token_to_str`], chat
+    /// templates and metadata), but a context cannot be created from such a model.
+    ///
+    /// [`LlamaModel::str_to_token`]: crate::model::LlamaModel::str_to_token
+    /// [`LlamaModel::token_to_str`]: crate::model::LlamaModel::token_to_str
+    ///
+    /// ```
+    /// # use llama_cpp_2::model::params::LlamaModelParams;
+    /// let params = LlamaModelParams::default().with_vocab_only(true);
+    /// assert!(params.vocab_only());
+    /// ```
     #[must_use]
     pub fn with_vocab_only(mut self, vocab_only: bool) -> Self {
         self.params.vocab_only = vocab_only;
         self
     }
 
+    /// sets `check_tensors`. When set every tensor is checked for NaN/inf values and invalid
+    /// quantization data while loading, so a corrupted download fails to load with
+    /// [`LlamaModelLoadError::InvalidTensors`] (or [`LlamaModelLoadError::NullResult`] if the
+    /// invalid tensors cannot be told from llama.cpp's log) instead of producing garbage at
+    /// inference time. This reads every weight and thus makes loading considerably slower.
+    ///
+    /// [`LlamaModelLoadError::InvalidTensors`]: crate::LlamaModelLoadError::InvalidTensors
+    /// [`LlamaModelLoadError::NullResult`]: crate::LlamaModelLoadError::NullResult
+    ///
+    /// ```
+    /// # use llama_cpp_2::model::params::LlamaModelParams;
+    /// let params = LlamaModelParams::default().with_check_tensors(true);
+    /// assert!(params.check_tensors());
+    /// ```
+    #[must_use]
+    pub fn with_check_tensors(mut self, check_tensors: bool) -> Self {
+        self.params.check_tensors = check_tensors;
+        self
+    }
+
     /// sets `use_mlock`
     #[must_use]
     pub fn with_use_mlock(mut self, use_mlock: bool) -> Self {
@@ -188,6 +226,7 @@ impl LlamaModelParams {
 /// assert_eq!(params.vocab_only(), false, "vocab_only should be false");
 /// assert_eq!(params.use_mmap(), true, "use_mmap should be true");
 /// assert_eq!(params.use_mlock(), false, "use_mlock should be false");
+/// assert_eq!(params.check_tensors(), false, "check_tensors should be false");
 /// ```
 impl Default for LlamaModelParams {
     fn default() -> Self {
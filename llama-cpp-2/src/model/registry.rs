@@ -0,0 +1,168 @@
+//! Hold several models at once behind a single backend.
+//!
+//! A [`ModelRegistry`] initializes the backend once and keeps the loaded models by name, e.g. a
+//! chat model, an embedding model and a reranker. Models are handed out as [`Arc<LlamaModel>`]:
+//! removing a model from the registry only unloads it once the last user drops its `Arc`.
+//!
+//! # Thread safety
+//!
+//! A [`LlamaModel`] is immutable after loading, llama.cpp only reads from it while decoding, so
+//! it is [`Send`] and [`Sync`] and one model can serve contexts on many threads at once. A
+//! [`LlamaContext`](crate::context::LlamaContext) holds the mutable decoding state and is neither,
+//! so each context has to stay on the thread that created it:
+//!
+//! ```compile_fail
+//! # use llama_cpp_2::context::LlamaContext;
+//! fn assert_send<T: Send>() {}
+//! assert_send::<LlamaContext<'static>>();
+//! ```
+//!
+//! # Example
+//!
+//! ```no_run
+//! # use llama_cpp_2::model::params::LlamaModelParams;
+//! # use llama_cpp_2::model::registry::ModelRegistry;
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! let registry = ModelRegistry::new()?;
+//! let params = LlamaModelParams::default();
+//! registry.load("chat", "chat.gguf", &params)?;
+//! registry.load("embed", "embed.gguf", &params)?;
+//!
+//! let chat = registry.get("chat").expect("the chat model was loaded");
+//! std::thread::scope(|s| {
+//!     s.spawn(|| {
+//!         let embed = registry.get("embed").expect("the embedding model was loaded");
+//!         // ... create a context from `embed` on this thread
+//!     });
+//!     // ... and one from `chat` on this one
+//! });
+//! # Ok(())
+//! # }
+//! ```
+
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, PoisonError, RwLock};
+
+use crate::llama_backend::LlamaBackend;
+use crate::model::params::LlamaModelParams;
+use crate::model::LlamaModel;
+use crate::LlamaModelLoadError;
+
+/// Loaded models by name, sharing one backend.
+#[derive(Debug)]
+pub struct ModelRegistry {
+    backend: Arc<LlamaBackend>,
+    models: RwLock<HashMap<String, Arc<LlamaModel>>>,
+}
+
+// the registry is meant to be shared between request handlers
+const _: () = {
+    const fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<ModelRegistry>();
+};
+
+impl ModelRegistry {
+    /// Initialize the backend and create an empty registry.
+    ///
+    /// # Errors
+    ///
+    /// - if the backend was already initialized, use [`ModelRegistry::with_backend`] in that case.
+    pub fn new() -> crate::Result<Self> {
+        Ok(Self::with_backend(Arc::new(LlamaBackend::init()?)))
+    }
+
+    /// Create an empty registry loading models with an already initialized `backend`.
+    #[must_use]
+    pub fn with_backend(backend: Arc<LlamaBackend>) -> Self {
+        Self {
+            backend,
+            models: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// The backend shared by all models of the registry.
+    #[must_use]
+    pub fn backend(&self) -> &Arc<LlamaBackend> {
+        &self.backend
+    }
+
+    /// Load `path` and register it as `name`, replacing (but not immediately unloading) a model
+    /// previously registered under that name.
+    ///
+    /// The registry is not locked while the model loads, so other models stay available.
+    ///
+    /// # Errors
+    ///
+    /// See [`LlamaModelLoadError`].
+    pub fn load(
+        &self,
+        name: impl Into<String>,
+        path: impl AsRef<Path>,
+        params: &LlamaModelParams,
+    ) -> Result<Arc<LlamaModel>, LlamaModelLoadError> {
+        let model = Arc::new(LlamaModel::load_from_file(&self.backend, path, params)?);
+        self.insert(name, Arc::clone(&model));
+        Ok(model)
+    }
+
+    /// Register `model` as `name`. Returns the model previously registered under that name.
+    pub fn insert(
+        &self,
+        name: impl Into<String>,
+        model: Arc<LlamaModel>,
+    ) -> Option<Arc<LlamaModel>> {
+        self.models
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(name.into(), model)
+    }
+
+    /// The model registered as `name`.
+    #[must_use]
+    pub fn get(&self, name: &str) -> Option<Arc<LlamaModel>> {
+        self.models
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(name)
+            .cloned()
+    }
+
+    /// Remove the model registered as `name`. It is unloaded once the returned `Arc` and every
+    /// other reference to it are dropped.
+    pub fn remove(&self, name: &str) -> Option<Arc<LlamaModel>> {
+        self.models
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(name)
+    }
+
+    /// The names of the registered models, sorted.
+    #[must_use]
+    pub fn names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self
+            .models
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .keys()
+            .cloned()
+            .collect();
+        names.sort_unstable();
+        names
+    }
+
+    /// The number of registered models.
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.models
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .len()
+    }
+
+    /// Whether no model is registered.
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
@@ -0,0 +1,282 @@
+//! The vocabulary of a model.
+//!
+//! Newer llama.cpp splits the tokenizer out of the model into a `llama_vocab` with its own
+//! `llama_vocab_*` functions. The linked llama.cpp still keeps it in the model, so [`LlamaVocab`]
+//! wraps a [`LlamaModel`] and forwards to it, giving code that only tokenizes a handle without
+//! access to the weights, and one place to switch to the new functions once they are linked.
+//!
+//! A vocabulary either borrows a loaded model ([`LlamaModel::vocab`]):
+//!
+//! ```no_run
+//! # use llama_cpp_2::model::{AddBos, LlamaModel};
+//! # fn run(model: &LlamaModel) -> Result<(), Box<dyn std::error::Error>> {
+//! let vocab = model.vocab();
+//! let tokens = vocab.str_to_token("Hello, World!", AddBos::Always)?;
+//! assert!(tokens.iter().all(|token| token.0 < vocab.n_vocab()));
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! or owns one loaded without its weights ([`LlamaVocab::load_from_file`]), for services that
+//! only count tokens or budget prompts. It is cheap to clone and can be shared between threads:
+//!
+//! ```no_run
+//! # use llama_cpp_2::llama_backend::LlamaBackend;
+//! # use llama_cpp_2::model::AddBos;
+//! # use llama_cpp_2::model::vocab::LlamaVocab;
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! let backend = LlamaBackend::init()?;
+//! let vocab = LlamaVocab::load_from_file(&backend, "path/to/model.gguf")?;
+//! let counter = vocab.clone();
+//! let n_tokens = std::thread::spawn(move || counter.count_tokens("Hello, World!", AddBos::Always))
+//!     .join()
+//!     .expect("the thread does not panic")?;
+//! println!("the prompt is {n_tokens} tokens long");
+//! # Ok(())
+//! # }
+//! ```
+
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::llama_backend::LlamaBackend;
+use crate::model::{AddBos, LlamaModel, Special, VocabType};
+use crate::token::LlamaToken;
+use crate::token_type::LlamaTokenAttrs;
+use crate::{LlamaModelLoadError, StringToTokenError, TokenToStringError};
+
+/// The tokenizer and special tokens of a model, see the [module docs](self).
+#[derive(Debug, Clone)]
+pub struct LlamaVocab<'a> {
+    model: VocabModel<'a>,
+}
+
+/// The model a [`LlamaVocab`] reads from.
+#[derive(Debug, Clone)]
+enum VocabModel<'a> {
+    /// A model loaded by the caller.
+    Borrowed(&'a LlamaModel),
+    /// A model loaded with only its vocabulary.
+    Owned(Arc<LlamaModel>),
+}
+
+impl LlamaModel {
+    /// The vocabulary of the model, see [`LlamaVocab`].
+    #[must_use]
+    pub fn vocab(&self) -> LlamaVocab<'_> {
+        LlamaVocab {
+            model: VocabModel::Borrowed(self),
+        }
+    }
+}
+
+impl LlamaVocab<'static> {
+    /// Load only the vocabulary of the model at `path`, see [`LlamaModel::load_vocab_from_file`].
+    /// No weights are loaded and no context is needed to tokenize.
+    ///
+    /// # Errors
+    ///
+    /// See [`LlamaModelLoadError`] for more information.
+    pub fn load_from_file(
+        backend: &LlamaBackend,
+        path: impl AsRef<Path>,
+    ) -> Result<Self, LlamaModelLoadError> {
+        let model = LlamaModel::load_vocab_from_file(backend, path)?;
+        Ok(Self {
+            model: VocabModel::Owned(Arc::new(model)),
+        })
+    }
+}
+
+impl LlamaVocab<'_> {
+    /// The model the vocabulary reads from. For a vocabulary from
+    /// [`LlamaVocab::load_from_file`] this is a model without weights, which cannot create a
+    /// context.
+    #[must_use]
+    pub fn model(&self) -> &LlamaModel {
+        match &self.model {
+            VocabModel::Borrowed(model) => model,
+            VocabModel::Owned(model) => model,
+        }
+    }
+
+    /// The number of tokens `str` is tokenized into, e.g. to check that a prompt fits into a
+    /// context before sending it to the model.
+    ///
+    /// # Errors
+    ///
+    /// See [`LlamaModel::str_to_token`].
+    pub fn count_tokens(&self, str: &str, add_bos: AddBos) -> Result<usize, StringToTokenError> {
+        Ok(self.str_to_token(str, add_bos)?.len())
+    }
+
+    /// The number of tokens in the vocabulary, see [`LlamaModel::n_vocab`].
+    #[must_use]
+    pub fn n_vocab(&self) -> i32 {
+        self.model().n_vocab()
+    }
+
+    /// The type of the tokenizer, see [`LlamaModel::vocab_type`].
+    #[must_use]
+    pub fn vocab_type(&self) -> VocabType {
+        self.model().vocab_type()
+    }
+
+    /// Every token with its text, see [`LlamaModel::tokens`].
+    pub fn tokens(
+        &self,
+        special: Special,
+    ) -> impl Iterator<Item = (LlamaToken, Result<String, TokenToStringError>)> + '_ {
+        self.model().tokens(special)
+    }
+
+    /// Tokenize `str`, see [`LlamaModel::str_to_token`].
+    ///
+    /// # Errors
+    ///
+    /// See [`LlamaModel::str_to_token`].
+    pub fn str_to_token(
+        &self,
+        str: &str,
+        add_bos: AddBos,
+    ) -> Result<Vec<LlamaToken>, StringToTokenError> {
+        self.model().str_to_token(str, add_bos)
+    }
+
+    /// Tokenize `str`, see [`LlamaModel::str_to_token_with_special`].
+    ///
+    /// # Errors
+    ///
+    /// See [`LlamaModel::str_to_token_with_special`].
+    pub fn str_to_token_with_special(
+        &self,
+        str: &str,
+        add_bos: AddBos,
+        special: Special,
+    ) -> Result<Vec<LlamaToken>, StringToTokenError> {
+        self.model()
+            .str_to_token_with_special(str, add_bos, special)
+    }
+
+    /// The text of `token`, see [`LlamaModel::token_to_str`].
+    ///
+    /// # Errors
+    ///
+    /// See [`LlamaModel::token_to_str`].
+    pub fn token_to_str(
+        &self,
+        token: LlamaToken,
+        special: Special,
+    ) -> Result<String, TokenToStringError> {
+        self.model().token_to_str(token, special)
+    }
+
+    /// The bytes of `token`, see [`LlamaModel::token_to_bytes`].
+    ///
+    /// # Errors
+    ///
+    /// See [`LlamaModel::token_to_bytes`].
+    pub fn token_to_bytes(
+        &self,
+        token: LlamaToken,
+        special: Special,
+    ) -> Result<Vec<u8>, TokenToStringError> {
+        self.model().token_to_bytes(token, special)
+    }
+
+    /// The text of `tokens`, see [`LlamaModel::tokens_to_str`].
+    ///
+    /// # Errors
+    ///
+    /// See [`LlamaModel::tokens_to_str`].
+    pub fn tokens_to_str(
+        &self,
+        tokens: &[LlamaToken],
+        special: Special,
+    ) -> Result<String, TokenToStringError> {
+        self.model().tokens_to_str(tokens, special)
+    }
+
+    /// The bytes `tokens` were tokenized from, see [`LlamaModel::detokenize`].
+    ///
+    /// # Errors
+    ///
+    /// See [`LlamaModel::detokenize`].
+    pub fn detokenize(
+        &self,
+        tokens: &[LlamaToken],
+        special: Special,
+        remove_special: bool,
+    ) -> Result<Vec<u8>, TokenToStringError> {
+        self.model().detokenize(tokens, special, remove_special)
+    }
+
+    /// The attributes of `token`, see [`LlamaModel::token_attr`].
+    #[must_use]
+    pub fn token_attr(&self, token: LlamaToken) -> LlamaTokenAttrs {
+        self.model().token_attr(token)
+    }
+
+    /// Whether `token` ends generation, see [`LlamaModel::is_eog_token`].
+    #[must_use]
+    pub fn is_eog_token(&self, token: LlamaToken) -> bool {
+        self.model().is_eog_token(token)
+    }
+
+    /// The beginning of stream token.
+    #[must_use]
+    pub fn token_bos(&self) -> LlamaToken {
+        self.model().token_bos()
+    }
+
+    /// The end of stream token.
+    #[must_use]
+    pub fn token_eos(&self) -> LlamaToken {
+        self.model().token_eos()
+    }
+
+    /// The end of turn token. Negative if the vocabulary has none.
+    #[must_use]
+    pub fn token_eot(&self) -> LlamaToken {
+        self.model().token_eot()
+    }
+
+    /// The newline token.
+    #[must_use]
+    pub fn token_nl(&self) -> LlamaToken {
+        self.model().token_nl()
+    }
+
+    /// The separator token (`[SEP]`). Negative if the vocabulary has none.
+    #[must_use]
+    pub fn token_sep(&self) -> LlamaToken {
+        self.model().token_sep()
+    }
+
+    /// The classification token (`[CLS]`). Negative if the vocabulary has none.
+    #[must_use]
+    pub fn token_cls(&self) -> LlamaToken {
+        self.model().token_cls()
+    }
+
+    /// The fill-in-the-middle prefix token. Negative if the vocabulary has none.
+    #[must_use]
+    pub fn token_prefix(&self) -> LlamaToken {
+        self.model().token_prefix()
+    }
+
+    /// The fill-in-the-middle middle token. Negative if the vocabulary has none.
+    #[must_use]
+    pub fn token_middle(&self) -> LlamaToken {
+        self.model().token_middle()
+    }
+
+    /// The fill-in-the-middle suffix token. Negative if the vocabulary has none.
+    #[must_use]
+    pub fn token_suffix(&self) -> LlamaToken {
+        self.model().token_suffix()
+    }
+}
+
+#[cfg(test)]
+mod tests;
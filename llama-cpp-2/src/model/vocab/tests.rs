@@ -0,0 +1,93 @@
+use super::*;
+use crate::test_utils::{self, TinyModel};
+
+/// Write the vocabulary of the tiny model to a temporary file and load it with `load`.
+fn load_from_file<T>(name: &str, load: impl FnOnce(&Path) -> T) -> T {
+    let path = std::env::temp_dir().join(format!(
+        "llama-cpp-2-vocab-{name}-{}.gguf",
+        std::process::id()
+    ));
+    TinyModel::default()
+        .gguf(false)
+        .write_to_file(&path)
+        .unwrap();
+    let loaded = load(&path);
+    let _ = std::fs::remove_file(&path);
+    loaded
+}
+
+const TEXT: &str = "hello world, the cat is a dog";
+
+#[test]
+fn borrowed_vocab_matches_the_model() {
+    let model = TinyModel::default()
+        .load_vocab(test_utils::backend())
+        .unwrap();
+    let vocab = model.vocab();
+
+    assert_eq!(vocab.n_vocab(), model.n_vocab());
+    assert_eq!(
+        usize::try_from(vocab.n_vocab()).unwrap(),
+        TinyModel::default().vocab().len()
+    );
+    let tokens = vocab.str_to_token(TEXT, AddBos::Always).unwrap();
+    assert_eq!(tokens, model.str_to_token(TEXT, AddBos::Always).unwrap());
+    assert_eq!(
+        vocab.count_tokens(TEXT, AddBos::Always).unwrap(),
+        tokens.len()
+    );
+    for &token in &tokens {
+        assert_eq!(
+            vocab.token_to_str(token, Special::Tokenize).unwrap(),
+            model.token_to_str(token, Special::Tokenize).unwrap()
+        );
+    }
+    // byte tokens are not valid utf8 on their own, so compare the bytes of every piece
+    for (token, _) in vocab.tokens(Special::Tokenize) {
+        assert_eq!(
+            vocab.token_to_bytes(token, Special::Tokenize).unwrap(),
+            model.token_to_bytes(token, Special::Tokenize).unwrap()
+        );
+    }
+    assert_eq!(vocab.token_bos(), model.token_bos());
+    assert_eq!(vocab.token_eos(), model.token_eos());
+}
+
+#[test]
+fn owned_vocab_round_trips_text() {
+    let vocab = load_from_file("owned", |path| {
+        LlamaVocab::load_from_file(test_utils::backend(), path).unwrap()
+    });
+    let tokens = vocab.str_to_token(TEXT, AddBos::Never).unwrap();
+    assert!(!tokens.is_empty());
+    assert_eq!(
+        vocab
+            .detokenize(&tokens, Special::Plaintext, false)
+            .unwrap(),
+        TEXT.as_bytes()
+    );
+
+    // a clone tokenizes on another thread
+    let counter = vocab.clone();
+    let n_tokens = std::thread::spawn(move || counter.count_tokens(TEXT, AddBos::Always))
+        .join()
+        .unwrap()
+        .unwrap();
+    assert_eq!(n_tokens, tokens.len() + 1);
+}
+
+#[test]
+fn vocab_only_model_tokenizes_like_a_full_one() {
+    let vocab_only = load_from_file("model", |path| {
+        LlamaModel::load_vocab_from_file(test_utils::backend(), path).unwrap()
+    });
+    let model = TinyModel::default().load(test_utils::backend()).unwrap();
+    let tokens = vocab_only.str_to_token(TEXT, AddBos::Always).unwrap();
+    assert_eq!(tokens, model.str_to_token(TEXT, AddBos::Always).unwrap());
+    assert_eq!(
+        vocab_only
+            .detokenize(&tokens, Special::Plaintext, true)
+            .unwrap(),
+        model.detokenize(&tokens, Special::Plaintext, true).unwrap()
+    );
+}
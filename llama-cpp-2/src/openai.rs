@@ -0,0 +1,838 @@
+//! OpenAI-compatible chat completion types.
+//!
+//! These mirror the JSON bodies of the `/v1/chat/completions` endpoint closely enough to be
+//! (de)serialized directly from requests made by OpenAI clients. Only the fields that map onto
+//! something llama.cpp can do are included, plus a few extensions understood by llama.cpp's own
+//! server (`top_k`, `min_p`, `repeat_penalty`).
+//!
+//! # Examples
+//!
+//! ```
+//! # use llama_cpp_2::openai::ChatCompletionRequest;
+//! let request: ChatCompletionRequest = serde_json::from_str(
+//!     r#"{
+//!         "model": "llama",
+//!         "messages": [
+//!             {"role": "system", "content": "You are a helpful assistant."},
+//!             {"role": "user", "content": "Hello!"}
+//!         ],
+//!         "temperature": 0.7,
+//!         "stop": "\n\n",
+//!         "stream": true
+//!     }"#,
+//! )
+//! .unwrap();
+//!
+//! assert!(request.stream);
+//! assert_eq!(request.stop.unwrap().into_vec(), vec!["\n\n".to_string()]);
+//!
+//! let messages = request.chat_messages().unwrap();
+//! assert_eq!(messages.len(), 2);
+//! ```
+
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::context::sample::logit_bias::LogitBias;
+use crate::generate::stream::StreamEvent;
+use crate::generate::{self, GenerationParams, GenerationStats, SamplingParams};
+
+#[cfg(feature = "json")]
+use crate::grammar::cache::{CompiledGrammar, GrammarCache};
+#[cfg(feature = "json")]
+use crate::grammar::json_schema::{self, JsonSchemaError};
+#[cfg(feature = "json")]
+use crate::grammar::LlamaGrammarFromStrError;
+use crate::model::LlamaChatMessage;
+use crate::token::LlamaToken;
+use crate::NewLlamaChatMessageError;
+
+pub mod tools;
+
+/// The author of a message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    /// Instructions for the model.
+    System,
+    /// A message from the user.
+    User,
+    /// A message from the model.
+    Assistant,
+    /// The result of a tool call.
+    Tool,
+}
+
+impl Role {
+    /// The role as it is passed to chat templates.
+    ///
+    /// ```
+    /// # use llama_cpp_2::openai::Role;
+    /// assert_eq!(Role::Assistant.as_str(), "assistant");
+    /// ```
+    #[must_use]
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Role::System => "system",
+            Role::User => "user",
+            Role::Assistant => "assistant",
+            Role::Tool => "tool",
+        }
+    }
+}
+
+/// The content of a message. Either a plain string or a list of typed parts.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum MessageContent {
+    /// A plain text message.
+    Text(String),
+    /// A message made up of several parts.
+    Parts(Vec<ContentPart>),
+}
+
+impl MessageContent {
+    /// The text of the message. Text parts are concatenated, other parts are skipped.
+    ///
+    /// ```
+    /// # use llama_cpp_2::openai::{ContentPart, MessageContent};
+    /// let content = MessageContent::Parts(vec![
+    ///     ContentPart::Text { text: "Hello, ".to_string() },
+    ///     ContentPart::Text { text: "world".to_string() },
+    /// ]);
+    /// assert_eq!(content.text(), "Hello, world");
+    /// ```
+    #[must_use]
+    pub fn text(&self) -> String {
+        match self {
+            MessageContent::Text(text) => text.clone(),
+            MessageContent::Parts(parts) => parts
+                .iter()
+                .filter_map(|part| match part {
+                    ContentPart::Text { text } => Some(text.as_str()),
+                    ContentPart::ImageUrl { .. } => None,
+                })
+                .collect(),
+        }
+    }
+}
+
+impl From<String> for MessageContent {
+    fn from(text: String) -> Self {
+        MessageContent::Text(text)
+    }
+}
+
+/// A single part of a multi-part message.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ContentPart {
+    /// A text part.
+    Text {
+        /// The text.
+        text: String,
+    },
+    /// An image part.
+    ImageUrl {
+        /// The image.
+        image_url: ImageUrl,
+    },
+}
+
+/// An image referenced from a message.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ImageUrl {
+    /// Either a URL or a base64 encoded data URL.
+    pub url: String,
+    /// The requested level of detail.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+/// A message in a chat completion request or response.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ChatCompletionMessage {
+    /// The author of the message.
+    pub role: Role,
+    /// The content of the message. May be missing on assistant messages that only call tools.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content: Option<MessageContent>,
+    /// An optional name for the participant.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// Tool calls made by the assistant.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_calls: Option<Vec<ToolCall>>,
+    /// The tool call this message is a response to.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_call_id: Option<String>,
+}
+
+impl ChatCompletionMessage {
+    /// Create a new message with the given role and text content.
+    ///
+    /// ```
+    /// # use llama_cpp_2::openai::{ChatCompletionMessage, Role};
+    /// let message = ChatCompletionMessage::new(Role::User, "Hello!");
+    /// assert_eq!(message.text(), "Hello!");
+    /// ```
+    #[must_use]
+    pub fn new(role: Role, content: impl Into<String>) -> Self {
+        Self {
+            role,
+            content: Some(MessageContent::Text(content.into())),
+            name: None,
+            tool_calls: None,
+            tool_call_id: None,
+        }
+    }
+
+    /// The text content of the message, or an empty string if there is none.
+    #[must_use]
+    pub fn text(&self) -> String {
+        self.content
+            .as_ref()
+            .map(MessageContent::text)
+            .unwrap_or_default()
+    }
+}
+
+impl TryFrom<&ChatCompletionMessage> for LlamaChatMessage {
+    type Error = NewLlamaChatMessageError;
+
+    fn try_from(message: &ChatCompletionMessage) -> Result<Self, Self::Error> {
+        LlamaChatMessage::new(message.role.as_str().to_string(), message.text())
+    }
+}
+
+/// One or more sequences that stop generation.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Stop {
+    /// A single stop sequence.
+    One(String),
+    /// Several stop sequences.
+    Many(Vec<String>),
+}
+
+impl Stop {
+    /// All stop sequences as a vec.
+    #[must_use]
+    pub fn into_vec(self) -> Vec<String> {
+        match self {
+            Stop::One(stop) => vec![stop],
+            Stop::Many(stops) => stops,
+        }
+    }
+}
+
+/// A tool the model may call.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Tool {
+    /// The type of the tool. Currently always `function`.
+    #[serde(rename = "type")]
+    pub kind: String,
+    /// The function definition.
+    pub function: FunctionDefinition,
+}
+
+/// The definition of a function the model may call.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FunctionDefinition {
+    /// The name of the function.
+    pub name: String,
+    /// A description of what the function does.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    /// The parameters the function accepts as a JSON schema.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parameters: Option<serde_json::Value>,
+}
+
+/// A call of a tool made by the model.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ToolCall {
+    /// The id of the call.
+    pub id: String,
+    /// The type of the tool. Currently always `function`.
+    #[serde(rename = "type")]
+    pub kind: String,
+    /// The function that was called.
+    pub function: FunctionCall,
+}
+
+/// The function called by a [`ToolCall`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FunctionCall {
+    /// The name of the function.
+    pub name: String,
+    /// The arguments as a JSON encoded string.
+    pub arguments: String,
+}
+
+/// The requested format of the response.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ResponseFormat {
+    /// Free form text.
+    Text,
+    /// Any valid JSON object.
+    JsonObject,
+    /// JSON matching a schema.
+    JsonSchema {
+        /// The schema.
+        json_schema: serde_json::Value,
+    },
+}
+
+#[cfg(feature = "json")]
+impl ResponseFormat {
+    /// The GBNF grammar responses of this format must match, `None` for free form text. A
+    /// `json_schema` is either the schema itself or OpenAI's `{"name": .., "schema": ..}` wrapper.
+    ///
+    /// # Errors
+    ///
+    /// If the schema cannot be converted, see [`JsonSchemaError`].
+    pub fn to_gbnf(&self) -> Result<Option<String>, JsonSchemaError> {
+        match self {
+            Self::Text => Ok(None),
+            Self::JsonObject => {
+                json_schema::to_gbnf(&serde_json::json!({ "type": "object" })).map(Some)
+            }
+            Self::JsonSchema { json_schema } => {
+                let schema = json_schema.get("schema").unwrap_or(json_schema);
+                json_schema::to_gbnf(schema).map(Some)
+            }
+        }
+    }
+}
+
+/// Failed to build the grammar of a [`ResponseFormat`].
+#[cfg(feature = "json")]
+#[derive(Debug, thiserror::Error)]
+pub enum ResponseFormatError {
+    /// The schema could not be converted to a grammar.
+    #[error("{0}")]
+    Schema(#[from] JsonSchemaError),
+    /// The grammar generated from the schema was rejected.
+    #[error("{0}")]
+    Grammar(#[from] LlamaGrammarFromStrError),
+}
+
+/// The body of a `/v1/chat/completions` request.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ChatCompletionRequest {
+    /// The model to use.
+    #[serde(default)]
+    pub model: String,
+    /// The conversation so far.
+    pub messages: Vec<ChatCompletionMessage>,
+    /// Sampling temperature.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f32>,
+    /// Nucleus sampling probability mass.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f32>,
+    /// Top-k sampling. Non standard, understood by llama.cpp's server.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub top_k: Option<i32>,
+    /// Min-p sampling. Non standard, understood by llama.cpp's server.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_p: Option<f32>,
+    /// Repetition penalty. Non standard, understood by llama.cpp's server.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub repeat_penalty: Option<f32>,
+    /// Penalty for tokens based on their frequency in the output so far.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub frequency_penalty: Option<f32>,
+    /// Penalty for tokens that have appeared in the output so far.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub presence_penalty: Option<f32>,
+    /// Bias added to the logits of the given token ids.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub logit_bias: Option<HashMap<String, f32>>,
+    /// The seed used for sampling.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seed: Option<u32>,
+    /// The maximum number of tokens to generate.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_tokens: Option<u32>,
+    /// Newer name for `max_tokens`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_completion_tokens: Option<u32>,
+    /// Sequences that stop generation.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stop: Option<Stop>,
+    /// The number of choices to generate.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub n: Option<u32>,
+    /// Stream the response as server sent events of [`ChatCompletionChunk`]s.
+    #[serde(default)]
+    pub stream: bool,
+    /// Return the log probabilities of the generated tokens.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub logprobs: Option<bool>,
+    /// The number of most likely tokens to return log probabilities for.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub top_logprobs: Option<u8>,
+    /// Tools the model may call.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tools: Option<Vec<Tool>>,
+    /// Controls which (if any) tool is called.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_choice: Option<serde_json::Value>,
+    /// The requested format of the response.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub response_format: Option<ResponseFormat>,
+    /// An identifier for the end user.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+}
+
+impl ChatCompletionRequest {
+    /// Convert the messages into [`LlamaChatMessage`]s for use with
+    /// [`crate::model::LlamaModel::apply_chat_template`].
+    ///
+    /// # Errors
+    ///
+    /// If a role or content contains a null byte.
+    pub fn chat_messages(&self) -> Result<Vec<LlamaChatMessage>, NewLlamaChatMessageError> {
+        self.messages
+            .iter()
+            .map(LlamaChatMessage::try_from)
+            .collect()
+    }
+
+    /// Like [`ChatCompletionRequest::chat_messages`], but present the request's `tools` to the model
+    /// and render earlier tool calls and results in `format`, see
+    /// [`ToolCallFormat::chat_messages`](tools::ToolCallFormat::chat_messages).
+    ///
+    /// # Errors
+    ///
+    /// If a role or content contains a null byte.
+    pub fn chat_messages_with_tools(
+        &self,
+        format: tools::ToolCallFormat,
+    ) -> Result<Vec<LlamaChatMessage>, NewLlamaChatMessageError> {
+        format.chat_messages(&self.messages, self.tools.as_deref().unwrap_or_default())
+    }
+
+    /// The grammar of the request's `response_format`, taken from `cache` so that requests with
+    /// the same schema share one compiled grammar. `None` for free form text.
+    ///
+    /// # Errors
+    ///
+    /// If the schema cannot be converted or the grammar is rejected, see
+    /// [`ResponseFormatError`].
+    #[cfg(feature = "json")]
+    pub fn grammar(
+        &self,
+        cache: &GrammarCache,
+    ) -> Result<Option<CompiledGrammar>, ResponseFormatError> {
+        let Some(format) = &self.response_format else {
+            return Ok(None);
+        };
+        match format.to_gbnf()? {
+            Some(gbnf) => Ok(Some(cache.get_or_compile(&gbnf)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// The maximum number of tokens to generate, preferring `max_completion_tokens` over the
+    /// deprecated `max_tokens`.
+    #[must_use]
+    pub fn max_tokens(&self) -> Option<u32> {
+        self.max_completion_tokens.or(self.max_tokens)
+    }
+
+    /// The request's `logit_bias` as a logits processor, `None` if it biases no token.
+    ///
+    /// # Errors
+    ///
+    /// If a key is not a token id.
+    pub fn logit_bias(&self) -> Result<Option<LogitBias>, LogitBiasError> {
+        let Some(biases) = &self.logit_bias else {
+            return Ok(None);
+        };
+        let biases = biases
+            .iter()
+            .map(|(key, &value)| match key.parse::<i32>() {
+                Ok(id) if id >= 0 => Ok((LlamaToken::new(id), value)),
+                _ => Err(LogitBiasError::InvalidTokenId(key.clone())),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let bias = LogitBias::new(biases);
+        Ok((!bias.is_empty()).then_some(bias))
+    }
+}
+
+/// A key of a request's `logit_bias` is not a token id.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum LogitBiasError {
+    /// The key is not a non-negative integer.
+    #[error("logit_bias key {0:?} is not a token id")]
+    InvalidTokenId(String),
+}
+
+/// The sampling parameters of the request, with the defaults of [`SamplingParams`] for every
+/// parameter it does not set.
+///
+/// ```
+/// # use llama_cpp_2::generate::SamplingParams;
+/// # use llama_cpp_2::openai::ChatCompletionRequest;
+/// let request: ChatCompletionRequest =
+///     serde_json::from_str(r#"{"messages": [], "temperature": 0.2, "seed": 7}"#).unwrap();
+/// let sampling = SamplingParams::from(&request);
+/// assert_eq!(sampling.temperature, 0.2);
+/// assert_eq!(sampling.seed, Some(7));
+/// assert_eq!(sampling.top_k, SamplingParams::default().top_k);
+/// ```
+impl From<&ChatCompletionRequest> for SamplingParams {
+    fn from(request: &ChatCompletionRequest) -> Self {
+        let defaults = SamplingParams::default();
+        SamplingParams {
+            temperature: request.temperature.unwrap_or(defaults.temperature),
+            top_k: request.top_k.unwrap_or(defaults.top_k),
+            top_p: request.top_p.unwrap_or(defaults.top_p),
+            min_p: request.min_p.unwrap_or(defaults.min_p),
+            repeat_penalty: request.repeat_penalty.unwrap_or(defaults.repeat_penalty),
+            frequency_penalty: request
+                .frequency_penalty
+                .unwrap_or(defaults.frequency_penalty),
+            presence_penalty: request
+                .presence_penalty
+                .unwrap_or(defaults.presence_penalty),
+            seed: request.seed,
+            ..defaults
+        }
+    }
+}
+
+/// The generation parameters of the request: its [sampling parameters](SamplingParams),
+/// `max_tokens`, `stop` strings and `logit_bias`. The grammar of `response_format` is left to
+/// [`ChatCompletionRequest::grammar`], which needs a cache.
+impl TryFrom<&ChatCompletionRequest> for GenerationParams {
+    type Error = LogitBiasError;
+
+    fn try_from(request: &ChatCompletionRequest) -> Result<Self, Self::Error> {
+        let mut params = GenerationParams::default().with_sampling(request.into());
+        if let Some(max_tokens) = request.max_tokens() {
+            params = params.with_max_tokens(usize::try_from(max_tokens).unwrap_or(usize::MAX));
+        }
+        if let Some(stop) = request.stop.clone() {
+            params = params.with_stop_strings(stop.into_vec());
+        }
+        if let Some(bias) = request.logit_bias()? {
+            params = params.with_logits_processor(bias);
+        }
+        Ok(params)
+    }
+}
+
+/// Why generation stopped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FinishReason {
+    /// The model produced an end of generation token or hit a stop sequence.
+    Stop,
+    /// The token limit was reached.
+    Length,
+    /// The model called a tool.
+    ToolCalls,
+    /// Content was omitted.
+    ContentFilter,
+}
+
+/// How OpenAI clients see why a generation ended: running out of tokens or context is
+/// [`FinishReason::Length`], everything else is [`FinishReason::Stop`]. Whether the model called
+/// a tool is up to the tool call parser, so [`FinishReason::ToolCalls`] is never returned.
+impl From<&generate::FinishReason> for FinishReason {
+    fn from(reason: &generate::FinishReason) -> Self {
+        match reason {
+            generate::FinishReason::MaxTokens
+            | generate::FinishReason::ContextFull
+            | generate::FinishReason::Timeout => FinishReason::Length,
+            generate::FinishReason::EndOfGeneration
+            | generate::FinishReason::GrammarExhausted
+            | generate::FinishReason::StopString(_)
+            | generate::FinishReason::StopToken(_)
+            | generate::FinishReason::Cancelled
+            | generate::FinishReason::Other(_) => FinishReason::Stop,
+        }
+    }
+}
+
+/// Token usage of a request.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Usage {
+    /// Tokens in the prompt.
+    pub prompt_tokens: u32,
+    /// Tokens generated.
+    pub completion_tokens: u32,
+    /// Sum of prompt and completion tokens.
+    pub total_tokens: u32,
+}
+
+impl Usage {
+    /// Create a new usage, computing the total. The total saturates at [`u32::MAX`].
+    ///
+    /// ```
+    /// # use llama_cpp_2::openai::Usage;
+    /// assert_eq!(Usage::new(10, 5).total_tokens, 15);
+    /// assert_eq!(Usage::new(u32::MAX, 1).total_tokens, u32::MAX);
+    /// ```
+    #[must_use]
+    pub fn new(prompt_tokens: u32, completion_tokens: u32) -> Self {
+        Self {
+            prompt_tokens,
+            completion_tokens,
+            total_tokens: prompt_tokens.saturating_add(completion_tokens),
+        }
+    }
+}
+
+/// The usage of a finished generation. Counts that do not fit into a `u32` saturate.
+impl From<&GenerationStats> for Usage {
+    fn from(stats: &GenerationStats) -> Self {
+        let count = |n_tokens: usize| u32::try_from(n_tokens).unwrap_or(u32::MAX);
+        Usage::new(
+            count(stats.n_prompt_tokens),
+            count(stats.n_generated_tokens),
+        )
+    }
+}
+
+/// A single choice of a [`ChatCompletionResponse`].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Choice {
+    /// The index of the choice.
+    pub index: u32,
+    /// The generated message.
+    pub message: ChatCompletionMessage,
+    /// Why generation stopped.
+    pub finish_reason: Option<FinishReason>,
+}
+
+/// The body of a non streaming `/v1/chat/completions` response.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ChatCompletionResponse {
+    /// A unique id for the completion.
+    pub id: String,
+    /// Always `chat.completion`.
+    pub object: String,
+    /// Unix timestamp (in seconds) of when the completion was created.
+    pub created: u64,
+    /// The model used.
+    pub model: String,
+    /// The generated choices.
+    pub choices: Vec<Choice>,
+    /// Token usage.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub usage: Option<Usage>,
+}
+
+impl ChatCompletionResponse {
+    /// Create a response with a single assistant choice.
+    ///
+    /// ```
+    /// # use llama_cpp_2::openai::{ChatCompletionResponse, FinishReason, Usage};
+    /// let response = ChatCompletionResponse::new(
+    ///     "chatcmpl-1",
+    ///     "llama",
+    ///     0,
+    ///     "Hi there!",
+    ///     FinishReason::Stop,
+    ///     Usage::new(10, 3),
+    /// );
+    /// let json = serde_json::to_value(&response).unwrap();
+    /// assert_eq!(json["object"], "chat.completion");
+    /// assert_eq!(json["choices"][0]["message"]["content"], "Hi there!");
+    /// assert_eq!(json["choices"][0]["finish_reason"], "stop");
+    /// ```
+    #[must_use]
+    pub fn new(
+        id: impl Into<String>,
+        model: impl Into<String>,
+        created: u64,
+        content: impl Into<String>,
+        finish_reason: FinishReason,
+        usage: Usage,
+    ) -> Self {
+        Self {
+            id: id.into(),
+            object: "chat.completion".to_string(),
+            created,
+            model: model.into(),
+            choices: vec![Choice {
+                index: 0,
+                message: ChatCompletionMessage::new(Role::Assistant, content),
+                finish_reason: Some(finish_reason),
+            }],
+            usage: Some(usage),
+        }
+    }
+}
+
+/// A partial call of a tool in a [`Delta`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ToolCallDelta {
+    /// The index of the tool call this delta belongs to.
+    pub index: u32,
+    /// The id of the call. Only sent with the first delta of a call.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    /// The type of the tool. Only sent with the first delta of a call.
+    #[serde(rename = "type", default, skip_serializing_if = "Option::is_none")]
+    pub kind: Option<String>,
+    /// The part of the function call.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub function: Option<FunctionCallDelta>,
+}
+
+/// A partial function call in a [`ToolCallDelta`].
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FunctionCallDelta {
+    /// The name of the function. Only sent with the first delta of a call.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// The next fragment of the JSON encoded arguments.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub arguments: Option<String>,
+}
+
+/// The change to a message carried by a [`ChatCompletionChunk`].
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Delta {
+    /// The role, sent with the first chunk only.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub role: Option<Role>,
+    /// The next piece of content.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content: Option<String>,
+    /// The next pieces of tool calls.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_calls: Option<Vec<ToolCallDelta>>,
+}
+
+/// A single choice of a [`ChatCompletionChunk`].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ChunkChoice {
+    /// The index of the choice.
+    pub index: u32,
+    /// The change to the message.
+    pub delta: Delta,
+    /// Why generation stopped. Only set on the last chunk.
+    pub finish_reason: Option<FinishReason>,
+}
+
+/// A single server sent event of a streaming `/v1/chat/completions` response.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ChatCompletionChunk {
+    /// A unique id for the completion, shared by all chunks.
+    pub id: String,
+    /// Always `chat.completion.chunk`.
+    pub object: String,
+    /// Unix timestamp (in seconds) of when the completion was created.
+    pub created: u64,
+    /// The model used.
+    pub model: String,
+    /// The choices this chunk updates.
+    pub choices: Vec<ChunkChoice>,
+    /// Token usage. Only set on the last chunk.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub usage: Option<Usage>,
+}
+
+impl ChatCompletionChunk {
+    /// Create a chunk carrying a single delta for the first choice.
+    ///
+    /// ```
+    /// # use llama_cpp_2::openai::{ChatCompletionChunk, Delta};
+    /// let delta = Delta { content: Some("Hi".to_string()), ..Delta::default() };
+    /// let chunk = ChatCompletionChunk::new("chatcmpl-1", "llama", 0, delta, None);
+    /// let json = serde_json::to_value(&chunk).unwrap();
+    /// assert_eq!(json["object"], "chat.completion.chunk");
+    /// assert_eq!(json["choices"][0]["delta"]["content"], "Hi");
+    /// ```
+    #[must_use]
+    pub fn new(
+        id: impl Into<String>,
+        model: impl Into<String>,
+        created: u64,
+        delta: Delta,
+        finish_reason: Option<FinishReason>,
+    ) -> Self {
+        Self {
+            id: id.into(),
+            object: "chat.completion.chunk".to_string(),
+            created,
+            model: model.into(),
+            choices: vec![ChunkChoice {
+                index: 0,
+                delta,
+                finish_reason,
+            }],
+            usage: None,
+        }
+    }
+
+    /// The first chunk of a stream, announcing the assistant role.
+    #[must_use]
+    pub fn role(id: impl Into<String>, model: impl Into<String>, created: u64) -> Self {
+        let delta = Delta {
+            role: Some(Role::Assistant),
+            ..Delta::default()
+        };
+        Self::new(id, model, created, delta, None)
+    }
+
+    /// The chunk to stream for an event of
+    /// [`LlamaContext::generate_stream`](crate::context::LlamaContext::generate_stream):
+    ///
+    /// - text as a content delta, `None` if it is empty
+    /// - a tool call fragment as a [`ToolCallDelta`]
+    /// - [`StreamEvent::Done`] as the last chunk, with the finish reason and the usage
+    /// - `None` for [`StreamEvent::Error`], which has no chunk and is up to the server to report
+    #[must_use]
+    pub fn from_event(
+        event: &StreamEvent,
+        id: impl Into<String>,
+        model: impl Into<String>,
+        created: u64,
+    ) -> Option<Self> {
+        let (delta, finish_reason, usage) = match event {
+            StreamEvent::Token { text, .. } | StreamEvent::Text(text) if !text.is_empty() => {
+                let delta = Delta {
+                    content: Some(text.clone()),
+                    ..Delta::default()
+                };
+                (delta, None, None)
+            }
+            StreamEvent::ToolCallDelta(fragment) => {
+                let delta = Delta {
+                    tool_calls: Some(vec![ToolCallDelta::from(fragment.clone())]),
+                    ..Delta::default()
+                };
+                (delta, None, None)
+            }
+            StreamEvent::Done {
+                finish_reason,
+                stats,
+            } => (
+                Delta::default(),
+                Some(FinishReason::from(finish_reason)),
+                Some(Usage::from(stats)),
+            ),
+            StreamEvent::Token { .. } | StreamEvent::Text(_) | StreamEvent::Error(_) => {
+                return None
+            }
+        };
+        let mut chunk = Self::new(id, model, created, delta, finish_reason);
+        chunk.usage = usage;
+        Some(chunk)
+    }
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,168 @@
+use std::collections::HashMap;
+
+use super::*;
+use crate::context::sample::logits_processor::LogitsProcessor;
+
+fn request(json: &str) -> ChatCompletionRequest {
+    serde_json::from_str(json).unwrap()
+}
+
+#[test]
+fn sampling_params_take_the_request_values() {
+    let sampling = SamplingParams::from(&request(
+        r#"{
+            "messages": [],
+            "temperature": 0.1,
+            "top_p": 0.5,
+            "top_k": 3,
+            "min_p": 0.2,
+            "repeat_penalty": 1.1,
+            "frequency_penalty": 0.3,
+            "presence_penalty": 0.4,
+            "seed": 42
+        }"#,
+    ));
+    assert_eq!(
+        sampling,
+        SamplingParams {
+            temperature: 0.1,
+            top_k: 3,
+            top_p: 0.5,
+            min_p: 0.2,
+            repeat_penalty: 1.1,
+            frequency_penalty: 0.3,
+            presence_penalty: 0.4,
+            seed: Some(42),
+            ..SamplingParams::default()
+        }
+    );
+}
+
+#[test]
+fn sampling_params_default_missing_values() {
+    let sampling = SamplingParams::from(&request(r#"{"messages": []}"#));
+    assert_eq!(sampling, SamplingParams::default());
+}
+
+#[test]
+fn generation_params_include_stop_max_tokens_and_logit_bias() {
+    let mut params = GenerationParams::try_from(&request(
+        r#"{
+            "messages": [],
+            "seed": 1,
+            "max_tokens": 8,
+            "stop": ["\n", "User:"],
+            "logit_bias": {"0": -100, "2": 5}
+        }"#,
+    ))
+    .unwrap();
+    assert_eq!(params.sampling.seed, Some(1));
+    assert_eq!(params.stopping.len(), 2);
+    let [processor] = &mut params.logits_processors[..] else {
+        panic!("expected the logit bias processor");
+    };
+    let mut logits = vec![0.0, 1.0, 2.0];
+    processor.process(&mut logits, &[]);
+    assert_eq!(logits, vec![-100.0, 1.0, 7.0]);
+}
+
+#[test]
+fn generation_params_of_a_bare_request_are_the_defaults() {
+    let params = GenerationParams::try_from(&request(r#"{"messages": []}"#)).unwrap();
+    assert_eq!(params.sampling, SamplingParams::default());
+    assert!(params.stopping.is_empty());
+    assert!(params.logits_processors.is_empty());
+}
+
+#[test]
+fn logit_bias_keys_must_be_token_ids() {
+    for key in ["hello", "-1", "1.5"] {
+        let mut request = request(r#"{"messages": []}"#);
+        request.logit_bias = Some(HashMap::from([(key.to_string(), 1.0)]));
+        assert_eq!(
+            request.logit_bias().unwrap_err(),
+            LogitBiasError::InvalidTokenId(key.to_string())
+        );
+        assert!(GenerationParams::try_from(&request).is_err());
+    }
+}
+
+#[test]
+fn empty_logit_bias_is_none() {
+    let request = request(r#"{"messages": [], "logit_bias": {}}"#);
+    assert!(request.logit_bias().unwrap().is_none());
+}
+
+#[test]
+fn finish_reasons_map_to_openai() {
+    let cases = [
+        (generate::FinishReason::EndOfGeneration, FinishReason::Stop),
+        (generate::FinishReason::MaxTokens, FinishReason::Length),
+        (generate::FinishReason::Timeout, FinishReason::Length),
+        (generate::FinishReason::ContextFull, FinishReason::Length),
+        (
+            generate::FinishReason::StopString("\n".to_string()),
+            FinishReason::Stop,
+        ),
+        (
+            generate::FinishReason::StopToken(LlamaToken::new(2)),
+            FinishReason::Stop,
+        ),
+        (generate::FinishReason::GrammarExhausted, FinishReason::Stop),
+        (generate::FinishReason::Cancelled, FinishReason::Stop),
+        (
+            generate::FinishReason::Other("custom".to_string()),
+            FinishReason::Stop,
+        ),
+    ];
+    for (reason, expected) in cases {
+        assert_eq!(FinishReason::from(&reason), expected, "{reason:?}");
+    }
+}
+
+#[test]
+fn stream_events_become_chunks() {
+    let chunk =
+        |event: StreamEvent| ChatCompletionChunk::from_event(&event, "chatcmpl-1", "llama", 7);
+    let role = ChatCompletionChunk::role("chatcmpl-1", "llama", 7);
+    assert_eq!(role.choices[0].delta.role, Some(Role::Assistant));
+
+    let token = chunk(StreamEvent::Token {
+        token: LlamaToken::new(1),
+        text: "Hi".to_string(),
+        logprob: 0.0,
+    })
+    .unwrap();
+    assert_eq!(token.id, "chatcmpl-1");
+    assert_eq!(token.created, 7);
+    assert_eq!(token.choices[0].delta.content.as_deref(), Some("Hi"));
+    assert_eq!(token.choices[0].finish_reason, None);
+    assert_eq!(token.usage, None);
+    assert!(chunk(StreamEvent::Text(String::new())).is_none());
+    assert!(chunk(StreamEvent::Error(generate::GenerateError::EmptyPrompt)).is_none());
+
+    let call = chunk(StreamEvent::ToolCallDelta(
+        generate::stream::ToolCallFragment {
+            index: 0,
+            name: Some("weather".to_string()),
+            arguments: "{".to_string(),
+        },
+    ))
+    .unwrap();
+    let calls = call.choices[0].delta.tool_calls.as_ref().unwrap();
+    assert_eq!(calls[0].id.as_deref(), Some("call_0"));
+
+    let stats = GenerationStats {
+        n_prompt_tokens: 10,
+        n_generated_tokens: 3,
+        ..GenerationStats::default()
+    };
+    let done = chunk(StreamEvent::Done {
+        finish_reason: generate::FinishReason::MaxTokens,
+        stats,
+    })
+    .unwrap();
+    assert_eq!(done.choices[0].delta, Delta::default());
+    assert_eq!(done.choices[0].finish_reason, Some(FinishReason::Length));
+    assert_eq!(done.usage, Some(Usage::new(10, 3)));
+}
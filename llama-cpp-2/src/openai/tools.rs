@@ -0,0 +1,538 @@
+//! Tool calling with models trained for it.
+//!
+//! llama.cpp's built-in chat templates know nothing about tools, so [`ToolCallFormat`] renders
+//! tool definitions, tool calls and tool results into plain messages the way the model families'
+//! own templates do, and parses the calls back out of the generated text.
+//!
+//! ```
+//! # use llama_cpp_2::openai::tools::ToolCallFormat;
+//! # use llama_cpp_2::openai::{ChatCompletionMessage, FunctionDefinition, Role, Tool};
+//! let tools = vec![Tool {
+//!     kind: "function".to_string(),
+//!     function: FunctionDefinition {
+//!         name: "get_weather".to_string(),
+//!         description: Some("The current weather in a city".to_string()),
+//!         parameters: Some(serde_json::json!({
+//!             "type": "object",
+//!             "properties": { "city": { "type": "string" } },
+//!             "required": ["city"],
+//!         })),
+//!     },
+//! }];
+//! let messages = vec![ChatCompletionMessage::new(Role::User, "Is it raining in Paris?")];
+//! let chat = ToolCallFormat::Hermes.chat_messages(&messages, &tools)?;
+//! // let prompt = model.apply_chat_template(None, chat, true)?;
+//! // ... generate ...
+//! let output = "<tool_call>\n{\"name\": \"get_weather\", \"arguments\": {\"city\": \"Paris\"}}\n</tool_call>";
+//!
+//! let parsed = ToolCallFormat::Hermes.parse(output)?;
+//! assert_eq!(parsed.tool_calls[0].function.name, "get_weather");
+//! assert_eq!(parsed.tool_calls[0].function.arguments, r#"{"city":"Paris"}"#);
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+
+use serde_json::Value;
+
+use crate::generate::stream::{ToolCallFragment, ToolCallParser};
+use crate::model::LlamaChatMessage;
+use crate::openai::{
+    ChatCompletionMessage, FinishReason, FunctionCall, FunctionCallDelta, Role, Tool, ToolCall,
+    ToolCallDelta,
+};
+use crate::NewLlamaChatMessageError;
+
+/// How a model family expects tools to be presented and formats its calls.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ToolCallFormat {
+    /// Hermes 2 Pro and Hermes 3 (ChatML): tools in `<tools></tools>` in the system prompt, calls
+    /// as `<tool_call>{"name": ..., "arguments": ...}</tool_call>` and results as
+    /// `<tool_response>` in `tool` messages.
+    Hermes,
+    /// Qwen 2 and 2.5 (ChatML): the tags of [`ToolCallFormat::Hermes`] with Qwen's system prompt,
+    /// results are sent in `user` messages.
+    Qwen,
+    /// Llama 3.1 and later: tools as JSON in the first user message, calls as
+    /// `{"name": ..., "parameters": ...}` (optionally after `<|python_tag|>`, or as
+    /// `<function=name>{...}</function>`) and results in `ipython` messages.
+    Llama31,
+}
+
+/// Failed to parse a tool call out of generated text.
+#[derive(Debug, thiserror::Error)]
+pub enum ParseToolCallError {
+    /// A tool call is not valid JSON.
+    #[error("tool call {call:?} is not valid JSON: {source}")]
+    InvalidJson {
+        /// The text of the call.
+        call: String,
+        /// The error of the JSON parser.
+        #[source]
+        source: serde_json::Error,
+    },
+    /// A tool call has no `name`.
+    #[error("tool call {0} has no name")]
+    MissingName(Value),
+}
+
+/// The text and tool calls of a generated message.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ParsedToolCalls {
+    /// The text outside of tool calls, trimmed.
+    pub content: String,
+    /// The tool calls in the order they were generated. Ids are `call_0`, `call_1`, ..., replace
+    /// them if they need to be unique across a conversation.
+    pub tool_calls: Vec<ToolCall>,
+}
+
+impl ParsedToolCalls {
+    /// The finish reason of a response with this message.
+    #[must_use]
+    pub fn finish_reason(&self) -> FinishReason {
+        if self.tool_calls.is_empty() {
+            FinishReason::Stop
+        } else {
+            FinishReason::ToolCalls
+        }
+    }
+
+    /// An assistant message with the content and tool calls.
+    #[must_use]
+    pub fn into_message(self) -> ChatCompletionMessage {
+        let mut message = ChatCompletionMessage::new(Role::Assistant, self.content);
+        if message.text().is_empty() && !self.tool_calls.is_empty() {
+            message.content = None;
+        }
+        if !self.tool_calls.is_empty() {
+            message.tool_calls = Some(self.tool_calls);
+        }
+        message
+    }
+
+    fn push(&mut self, name: String, arguments: String) {
+        self.tool_calls.push(ToolCall {
+            id: format!("call_{}", self.tool_calls.len()),
+            kind: "function".to_string(),
+            function: FunctionCall { name, arguments },
+        });
+    }
+
+    /// Add a call of the form `{"name": ..., "<arguments_key>": ...}`.
+    fn push_json(&mut self, call: &str, arguments_key: &str) -> Result<(), ParseToolCallError> {
+        let mut value: Value = serde_json::from_str(call.trim()).map_err(|source| {
+            ParseToolCallError::InvalidJson {
+                call: call.to_string(),
+                source,
+            }
+        })?;
+        let Some(name) = value
+            .get("name")
+            .and_then(Value::as_str)
+            .map(str::to_string)
+        else {
+            return Err(ParseToolCallError::MissingName(value));
+        };
+        let arguments = value
+            .get_mut(arguments_key)
+            .or_else(|| value.get_mut("arguments"))
+            .map_or(Value::Object(serde_json::Map::new()), Value::take);
+        let arguments = match arguments {
+            // some models encode the arguments as a string
+            Value::String(arguments) => arguments,
+            arguments => arguments.to_string(),
+        };
+        self.push(name, arguments);
+        Ok(())
+    }
+}
+
+impl ToolCallFormat {
+    /// Guess the format from a model's chat template (see
+    /// [`LlamaModel::get_chat_template`](crate::model::LlamaModel::get_chat_template)). `None` if
+    /// the template does not use tools in a known way.
+    ///
+    /// ```
+    /// # use llama_cpp_2::openai::tools::ToolCallFormat;
+    /// let template = "{%- if tools %}<|im_start|>system\n<tools>{{ tools }}</tools>...<tool_call>";
+    /// assert_eq!(ToolCallFormat::detect(template), Some(ToolCallFormat::Hermes));
+    /// assert_eq!(ToolCallFormat::detect("{{ messages }}"), None);
+    /// ```
+    #[must_use]
+    pub fn detect(template: &str) -> Option<Self> {
+        if template.contains("<tool_call>") {
+            if template.contains("Qwen") {
+                Some(Self::Qwen)
+            } else {
+                Some(Self::Hermes)
+            }
+        } else if template.contains("<|start_header_id|>")
+            && (template.contains("<|python_tag|>") || template.contains("ipython"))
+        {
+            Some(Self::Llama31)
+        } else {
+            None
+        }
+    }
+
+    /// The instructions that present `tools` to the model.
+    #[must_use]
+    pub fn tools_prompt(self, tools: &[Tool]) -> String {
+        let definitions = tools.iter().map(|tool| {
+            let definition = serde_json::json!({ "type": tool.kind, "function": tool.function });
+            match self {
+                Self::Hermes | Self::Qwen => definition.to_string(),
+                Self::Llama31 => format!("{definition:#}"),
+            }
+        });
+        match self {
+            Self::Hermes => format!(
+                "You are a function calling AI model. You are provided with function signatures \
+                 within <tools></tools> XML tags. You may call one or more functions to assist \
+                 with the user query. Don't make assumptions about what values to plug into \
+                 functions. Here are the available tools: <tools> {} </tools> For each function \
+                 call return a json object with function name and arguments within \
+                 <tool_call></tool_call> XML tags as follows:\n<tool_call>\n{{\"name\": \
+                 <function-name>, \"arguments\": <args-dict>}}\n</tool_call>",
+                definitions.collect::<Vec<_>>().join(" ")
+            ),
+            Self::Qwen => format!(
+                "# Tools\n\nYou may call one or more functions to assist with the user query.\n\n\
+                 You are provided with function signatures within <tools></tools> XML tags:\n\
+                 <tools>\n{}\n</tools>\n\nFor each function call, return a json object with \
+                 function name and arguments within <tool_call></tool_call> XML tags:\n\
+                 <tool_call>\n{{\"name\": <function-name>, \"arguments\": <args-json-object>}}\n\
+                 </tool_call>",
+                definitions.collect::<Vec<_>>().join("\n")
+            ),
+            Self::Llama31 => format!(
+                "Given the following functions, please respond with a JSON for a function call \
+                 with its proper arguments that best answers the given prompt.\n\nRespond in the \
+                 format {{\"name\": function name, \"parameters\": dictionary of argument name \
+                 and its value}}. Do not use variables.\n\n{}",
+                definitions.map(|d| d + "\n\n").collect::<String>()
+            ),
+        }
+    }
+
+    /// A tool call as the model would have generated it.
+    fn format_call(self, call: &FunctionCall) -> String {
+        let arguments = serde_json::from_str::<Value>(&call.arguments)
+            .unwrap_or_else(|_| Value::String(call.arguments.clone()));
+        match self {
+            Self::Hermes | Self::Qwen => format!(
+                "<tool_call>\n{}\n</tool_call>",
+                serde_json::json!({ "name": call.name, "arguments": arguments })
+            ),
+            Self::Llama31 => {
+                serde_json::json!({ "name": call.name, "parameters": arguments }).to_string()
+            }
+        }
+    }
+
+    /// Convert `messages` into [`LlamaChatMessage`]s for
+    /// [`LlamaModel::apply_chat_template`](crate::model::LlamaModel::apply_chat_template),
+    /// presenting `tools` to the model and rendering the tool calls and results of earlier turns.
+    ///
+    /// With [`ToolCallFormat::Hermes`] and [`ToolCallFormat::Qwen`] the tools are added to the
+    /// system message (one is inserted if there is none), with [`ToolCallFormat::Llama31`] to the
+    /// first user message. Without tools only the calls and results are rendered.
+    ///
+    /// # Errors
+    ///
+    /// If a role or content contains a null byte.
+    pub fn chat_messages(
+        self,
+        messages: &[ChatCompletionMessage],
+        tools: &[Tool],
+    ) -> Result<Vec<LlamaChatMessage>, NewLlamaChatMessageError> {
+        let mut chat: Vec<(String, String)> = Vec::with_capacity(messages.len() + 1);
+        for message in messages {
+            let mut content = message.text();
+            let role = match (self, message.role) {
+                (Self::Hermes | Self::Qwen, Role::Tool) => {
+                    content = format!("<tool_response>\n{content}\n</tool_response>");
+                    if self == Self::Qwen {
+                        // Qwen sends consecutive results in a single user message
+                        if let Some((role, previous)) = chat.last_mut() {
+                            if role == "user" && previous.ends_with("</tool_response>") {
+                                previous.push('\n');
+                                previous.push_str(&content);
+                                continue;
+                            }
+                        }
+                        "user"
+                    } else {
+                        "tool"
+                    }
+                }
+                (Self::Llama31, Role::Tool) => "ipython",
+                (_, role) => role.as_str(),
+            };
+            for call in message.tool_calls.iter().flatten() {
+                if !content.is_empty() {
+                    content.push('\n');
+                }
+                content.push_str(&self.format_call(&call.function));
+            }
+            chat.push((role.to_string(), content));
+        }
+
+        if !tools.is_empty() {
+            let prompt = self.tools_prompt(tools);
+            let (target, fallback_position) = match self {
+                Self::Hermes | Self::Qwen => ("system", 0),
+                Self::Llama31 => ("user", chat.len()),
+            };
+            match chat.iter_mut().find(|(role, _)| role == target) {
+                Some((_, content)) if target == "system" => {
+                    *content = format!("{content}\n\n{prompt}");
+                }
+                Some((_, content)) => *content = format!("{prompt}{content}"),
+                None => chat.insert(fallback_position, (target.to_string(), prompt)),
+            }
+        }
+
+        chat.into_iter()
+            .map(|(role, content)| LlamaChatMessage::new(role, content))
+            .collect()
+    }
+
+    /// Split generated text into content and tool calls.
+    ///
+    /// ```
+    /// # use llama_cpp_2::openai::tools::ToolCallFormat;
+    /// let parsed = ToolCallFormat::Llama31
+    ///     .parse(r#"<|python_tag|>{"name": "search", "parameters": {"query": "rust"}}"#)?;
+    /// assert_eq!(parsed.tool_calls[0].function.name, "search");
+    /// assert_eq!(parsed.content, "");
+    ///
+    /// let parsed = ToolCallFormat::Llama31.parse("It is sunny.")?;
+    /// assert!(parsed.tool_calls.is_empty());
+    /// assert_eq!(parsed.content, "It is sunny.");
+    /// # Ok::<(), llama_cpp_2::openai::tools::ParseToolCallError>(())
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// If a tool call is not a JSON object with a `name`. Text that does not look like a tool
+    /// call is returned as content.
+    pub fn parse(self, output: &str) -> Result<ParsedToolCalls, ParseToolCallError> {
+        let mut parsed = ParsedToolCalls::default();
+        match self {
+            Self::Hermes | Self::Qwen => {
+                let mut rest = output;
+                while let Some((before, call)) = rest.split_once("<tool_call>") {
+                    parsed.content.push_str(before);
+                    // the closing tag may be missing if generation stopped on it
+                    let (call, after) = call.split_once("</tool_call>").unwrap_or((call, ""));
+                    parsed.push_json(call, "arguments")?;
+                    rest = after;
+                }
+                parsed.content.push_str(rest);
+            }
+            Self::Llama31 => {
+                let mut rest = output.strip_prefix("<|python_tag|>").unwrap_or(output);
+                while let Some((before, call)) = rest.split_once("<function=") {
+                    parsed.content.push_str(before);
+                    let (call, after) = call.split_once("</function>").unwrap_or((call, ""));
+                    let (name, arguments) = call.split_once('>').unwrap_or((call, "{}"));
+                    parsed.push(name.trim().to_string(), arguments_from(arguments)?);
+                    rest = after;
+                }
+                let trimmed = rest.trim();
+                if parsed.tool_calls.is_empty() && trimmed.starts_with('{') {
+                    parsed.push_json(trimmed, "parameters")?;
+                } else {
+                    parsed.content.push_str(rest);
+                }
+            }
+        }
+        parsed.content = parsed.content.trim().to_string();
+        Ok(parsed)
+    }
+}
+
+impl ToolCallFormat {
+    /// A [`ToolCallParser`] for
+    /// [`GenerationParams::with_tool_call_parser`](crate::generate::GenerationParams::with_tool_call_parser)
+    /// that takes the calls of this format out of the streamed text. Every call is sent as a
+    /// single fragment once it is complete.
+    ///
+    /// ```
+    /// # use llama_cpp_2::generate::stream::ToolCallParser;
+    /// # use llama_cpp_2::openai::tools::ToolCallFormat;
+    /// let mut stream = ToolCallFormat::Hermes.stream();
+    /// let mut calls = Vec::new();
+    /// assert_eq!(stream.push("Let me check. <tool", &mut calls), "Let me check. ");
+    /// assert_eq!(stream.push("_call>{\"name\": \"get_weather\", ", &mut calls), "");
+    /// assert_eq!(stream.push("\"arguments\": {}}</tool_call>", &mut calls), "");
+    /// assert_eq!(calls[0].name.as_deref(), Some("get_weather"));
+    /// assert_eq!(stream.finish(&mut calls), "");
+    /// ```
+    #[must_use]
+    pub fn stream(self) -> ToolCallStream {
+        ToolCallStream {
+            format: self,
+            buffer: String::new(),
+            state: StreamState::Content,
+            seen_content: false,
+            n_calls: 0,
+        }
+    }
+
+    /// The texts that start a call and the texts that end them, `None` if a call lasts until the
+    /// end of the output.
+    fn markers(self) -> &'static [(&'static str, Option<&'static str>)] {
+        match self {
+            Self::Hermes | Self::Qwen => &[("<tool_call>", Some("</tool_call>"))],
+            Self::Llama31 => &[
+                ("<function=", Some("</function>")),
+                ("<|python_tag|>", None),
+            ],
+        }
+    }
+}
+
+/// Where a [`ToolCallStream`] is in the output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum StreamState {
+    /// Outside of a call.
+    Content,
+    /// Inside of a call that ends with the text, or with the output if `None`.
+    Call(Option<&'static str>),
+}
+
+/// Separates tool calls of a [`ToolCallFormat`] from the content while streaming, see
+/// [`ToolCallFormat::stream`]. Calls that cannot be parsed are passed on as content.
+#[derive(Debug, Clone)]
+pub struct ToolCallStream {
+    format: ToolCallFormat,
+    /// Text that was not passed on yet.
+    buffer: String,
+    state: StreamState,
+    /// Whether content other than whitespace was passed on.
+    seen_content: bool,
+    n_calls: usize,
+}
+
+impl ToolCallStream {
+    /// Parse a complete call, adding it to `tool_calls`, and return the content around it.
+    fn parse_call(&mut self, call: &str, tool_calls: &mut Vec<ToolCallFragment>) -> String {
+        let Ok(parsed) = self.format.parse(call) else {
+            return call.to_string();
+        };
+        for call in parsed.tool_calls {
+            tool_calls.push(ToolCallFragment {
+                index: self.n_calls,
+                name: Some(call.function.name),
+                arguments: call.function.arguments,
+            });
+            self.n_calls += 1;
+        }
+        parsed.content
+    }
+
+    /// The length of the end of the buffer that could be the start of a marker.
+    fn partial_marker_len(&self) -> usize {
+        self.format
+            .markers()
+            .iter()
+            .flat_map(|(marker, _)| (1..marker.len()).map(|len| &marker[..len]))
+            .filter(|prefix| self.buffer.ends_with(prefix))
+            .map(str::len)
+            .max()
+            .unwrap_or(0)
+    }
+}
+
+impl ToolCallParser for ToolCallStream {
+    fn push(&mut self, text: &str, tool_calls: &mut Vec<ToolCallFragment>) -> String {
+        self.buffer.push_str(text);
+        let mut content = String::new();
+        loop {
+            let seen_content = self.seen_content || !content.trim().is_empty();
+            match self.state {
+                StreamState::Call(None) => break,
+                StreamState::Call(Some(end)) => {
+                    let Some(position) = self.buffer.find(end) else {
+                        break;
+                    };
+                    let call: String = self.buffer.drain(..position + end.len()).collect();
+                    content.push_str(&self.parse_call(&call, tool_calls));
+                    self.state = StreamState::Content;
+                }
+                // Llama 3.1 may answer with nothing but the JSON of a call
+                StreamState::Content
+                    if self.format == ToolCallFormat::Llama31
+                        && !seen_content
+                        && self.buffer.trim_start().starts_with('{') =>
+                {
+                    self.state = StreamState::Call(None);
+                }
+                StreamState::Content => {
+                    let start = self
+                        .format
+                        .markers()
+                        .iter()
+                        .filter_map(|&(marker, end)| self.buffer.find(marker).map(|i| (i, end)))
+                        .min_by_key(|&(position, _)| position);
+                    if let Some((position, end)) = start {
+                        content.extend(self.buffer.drain(..position));
+                        self.state = StreamState::Call(end);
+                        continue;
+                    }
+                    let keep = if self.format == ToolCallFormat::Llama31
+                        && !seen_content
+                        && self.buffer.trim().is_empty()
+                    {
+                        self.buffer.len()
+                    } else {
+                        self.partial_marker_len()
+                    };
+                    content.extend(self.buffer.drain(..self.buffer.len() - keep));
+                    break;
+                }
+            }
+        }
+        self.seen_content |= !content.trim().is_empty();
+        content
+    }
+
+    fn finish(&mut self, tool_calls: &mut Vec<ToolCallFragment>) -> String {
+        let rest = std::mem::take(&mut self.buffer);
+        let content = match self.state {
+            StreamState::Content => rest,
+            StreamState::Call(_) => self.parse_call(&rest, tool_calls),
+        };
+        self.state = StreamState::Content;
+        self.seen_content = false;
+        self.n_calls = 0;
+        content
+    }
+}
+
+/// The delta of a streaming response for a fragment of a call, with the id `call_<index>`.
+impl From<ToolCallFragment> for ToolCallDelta {
+    fn from(fragment: ToolCallFragment) -> Self {
+        let first = fragment.name.is_some();
+        Self {
+            index: u32::try_from(fragment.index).unwrap_or(u32::MAX),
+            id: first.then(|| format!("call_{}", fragment.index)),
+            kind: first.then(|| "function".to_string()),
+            function: Some(FunctionCallDelta {
+                name: fragment.name,
+                arguments: Some(fragment.arguments),
+            }),
+        }
+    }
+}
+
+/// The JSON encoded arguments of a call from their generated text.
+fn arguments_from(arguments: &str) -> Result<String, ParseToolCallError> {
+    let value: Value = serde_json::from_str(arguments.trim()).map_err(|source| {
+        ParseToolCallError::InvalidJson {
+            call: arguments.to_string(),
+            source,
+        }
+    })?;
+    Ok(value.to_string())
+}
@@ -0,0 +1,317 @@
+//! Quantize a model file, like llama.cpp's `llama-quantize`.
+//!
+//! The quantizations below about 3 bits per weight need an [`Imatrix`] computed over some text
+//! to keep the model usable. As `llama-quantize` does, the output and token embedding tensors can
+//! be kept at a higher precision than the rest:
+//!
+//! ```no_run
+//! # use llama_cpp_2::ggml_type::{GgmlFileType, GgmlType};
+//! # use llama_cpp_2::llama_backend::LlamaBackend;
+//! # use llama_cpp_2::quantize::imatrix::Imatrix;
+//! # use llama_cpp_2::quantize::{quantize_model, QuantizeParams};
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! let backend = LlamaBackend::init()?;
+//! let params = QuantizeParams::new(GgmlFileType::MostlyIQ2XS)
+//!     .with_imatrix(Imatrix::load("imatrix.dat")?)
+//!     .with_output_tensor_type(GgmlType::Q8_0)
+//!     .with_token_embedding_type(GgmlType::Q8_0);
+//! quantize_model(&backend, "model-f16.gguf", "model-iq2_xs.gguf", &params)?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::ffi::{CString, NulError};
+use std::fmt::{Debug, Formatter};
+use std::path::{Path, PathBuf};
+
+use crate::ggml_type::{GgmlFileType, GgmlType};
+use crate::llama_backend::{with_captured_logs, LlamaBackend};
+use imatrix::Imatrix;
+
+pub mod imatrix;
+
+#[cfg(test)]
+mod tests;
+
+/// An error that can occur while quantizing a model.
+#[derive(Debug, thiserror::Error)]
+#[allow(clippy::module_name_repetitions)]
+pub enum QuantizeError {
+    /// A path was not valid utf8.
+    #[error("failed to convert path {0} to str")]
+    PathToStrError(PathBuf),
+    /// A path or tensor name contained a null byte.
+    #[error("null byte in string {0}")]
+    NulError(#[from] NulError),
+    /// The file type is unusable without an importance matrix and none was given.
+    #[error("quantizing to {0} needs an importance matrix")]
+    ImatrixRequired(GgmlFileType),
+    /// llama.cpp failed to quantize the model, with the message it logged if there was one.
+    #[error("failed to quantize: {}", .0.as_deref().unwrap_or("unknown error"))]
+    Failed(Option<String>),
+}
+
+/// A safe wrapper around `llama_model_quantize_params`.
+#[derive(Clone)]
+#[allow(clippy::module_name_repetitions)]
+pub struct QuantizeParams {
+    params: llama_cpp_sys_2::llama_model_quantize_params,
+    file_type: GgmlFileType,
+    imatrix: Option<Imatrix>,
+}
+
+impl Debug for QuantizeParams {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("QuantizeParams")
+            .field("file_type", &self.file_type)
+            .field("n_threads", &self.params.nthread)
+            .field("output_tensor_type", &self.output_tensor_type())
+            .field("token_embedding_type", &self.token_embedding_type())
+            .field("allow_requantize", &self.params.allow_requantize)
+            .field(
+                "quantize_output_tensor",
+                &self.params.quantize_output_tensor,
+            )
+            .field("only_copy", &self.params.only_copy)
+            .field("pure", &self.params.pure_)
+            .field("keep_split", &self.params.keep_split)
+            .field(
+                "imatrix_entries",
+                &self.imatrix.as_ref().map(|i| i.entries().len()),
+            )
+            .finish()
+    }
+}
+
+/// A tensor type override, `None` for `GGML_TYPE_COUNT`: llama.cpp picks the type.
+fn tensor_type_from_raw(raw: llama_cpp_sys_2::ggml_type) -> Option<GgmlType> {
+    GgmlType::try_from(raw).ok()
+}
+
+impl QuantizeParams {
+    /// Quantize to `file_type` with llama.cpp's defaults for everything else.
+    ///
+    /// ```
+    /// # use llama_cpp_2::ggml_type::GgmlFileType;
+    /// # use llama_cpp_2::quantize::QuantizeParams;
+    /// let params = QuantizeParams::new(GgmlFileType::MostlyQ4KM);
+    /// assert_eq!(params.file_type(), GgmlFileType::MostlyQ4KM);
+    /// assert_eq!(params.output_tensor_type(), None);
+    /// assert!(params.quantize_output_tensor());
+    /// ```
+    #[must_use]
+    pub fn new(file_type: GgmlFileType) -> Self {
+        let mut params = unsafe { llama_cpp_sys_2::llama_model_quantize_default_params() };
+        params.ftype = file_type.into();
+        Self {
+            params,
+            file_type,
+            imatrix: None,
+        }
+    }
+
+    /// The file type to quantize to.
+    #[must_use]
+    pub fn file_type(&self) -> GgmlFileType {
+        self.file_type
+    }
+
+    /// Set the number of threads, 0 (the default) uses all hardware threads.
+    #[must_use]
+    pub fn with_n_threads(mut self, n_threads: i32) -> Self {
+        self.params.nthread = n_threads;
+        self
+    }
+
+    /// Get the number of threads.
+    #[must_use]
+    pub fn n_threads(&self) -> i32 {
+        self.params.nthread
+    }
+
+    /// Store the output tensor as `tensor_type` instead of the type llama.cpp picks for the file
+    /// type (`--output-tensor-type`).
+    #[must_use]
+    pub fn with_output_tensor_type(mut self, tensor_type: GgmlType) -> Self {
+        self.params.output_tensor_type = tensor_type.into();
+        self
+    }
+
+    /// Get the type of the output tensor, `None` if llama.cpp picks it.
+    #[must_use]
+    pub fn output_tensor_type(&self) -> Option<GgmlType> {
+        tensor_type_from_raw(self.params.output_tensor_type)
+    }
+
+    /// Store the token embeddings as `tensor_type` instead of the type llama.cpp picks for the
+    /// file type (`--token-embedding-type`).
+    #[must_use]
+    pub fn with_token_embedding_type(mut self, tensor_type: GgmlType) -> Self {
+        self.params.token_embedding_type = tensor_type.into();
+        self
+    }
+
+    /// Get the type of the token embeddings, `None` if llama.cpp picks it.
+    #[must_use]
+    pub fn token_embedding_type(&self) -> Option<GgmlType> {
+        tensor_type_from_raw(self.params.token_embedding_type)
+    }
+
+    /// Allow quantizing tensors that are already quantized, which loses more quality than
+    /// quantizing from the original weights (`--allow-requantize`).
+    #[must_use]
+    pub fn with_allow_requantize(mut self, allow_requantize: bool) -> Self {
+        self.params.allow_requantize = allow_requantize;
+        self
+    }
+
+    /// Get whether quantized tensors may be quantized again.
+    #[must_use]
+    pub fn allow_requantize(&self) -> bool {
+        self.params.allow_requantize
+    }
+
+    /// Set whether the output tensor is quantized at all, true by default
+    /// (`--leave-output-tensor` sets it to false).
+    #[must_use]
+    pub fn with_quantize_output_tensor(mut self, quantize_output_tensor: bool) -> Self {
+        self.params.quantize_output_tensor = quantize_output_tensor;
+        self
+    }
+
+    /// Get whether the output tensor is quantized.
+    #[must_use]
+    pub fn quantize_output_tensor(&self) -> bool {
+        self.params.quantize_output_tensor
+    }
+
+    /// Only copy the tensors, e.g. to rewrite the metadata or the split (`COPY`).
+    #[must_use]
+    pub fn with_only_copy(mut self, only_copy: bool) -> Self {
+        self.params.only_copy = only_copy;
+        self
+    }
+
+    /// Get whether the tensors are only copied.
+    #[must_use]
+    pub fn only_copy(&self) -> bool {
+        self.params.only_copy
+    }
+
+    /// Quantize every tensor to the [main type](GgmlFileType::main_type) of the file type instead
+    /// of keeping sensitive ones at a higher precision (`--pure`).
+    #[must_use]
+    pub fn with_pure(mut self, pure: bool) -> Self {
+        self.params.pure_ = pure;
+        self
+    }
+
+    /// Get whether every tensor gets the main type.
+    #[must_use]
+    pub fn pure(&self) -> bool {
+        self.params.pure_
+    }
+
+    /// Keep the input split into the same number of files (`--keep-split`).
+    #[must_use]
+    pub fn with_keep_split(mut self, keep_split: bool) -> Self {
+        self.params.keep_split = keep_split;
+        self
+    }
+
+    /// Get whether the split of the input is kept.
+    #[must_use]
+    pub fn keep_split(&self) -> bool {
+        self.params.keep_split
+    }
+
+    /// Weigh the quantization of every tensor with an entry in `imatrix` by its activations
+    /// (`--imatrix`).
+    #[must_use]
+    pub fn with_imatrix(mut self, imatrix: Imatrix) -> Self {
+        self.imatrix = Some(imatrix);
+        self
+    }
+
+    /// Get the importance matrix.
+    #[must_use]
+    pub fn imatrix(&self) -> Option<&Imatrix> {
+        self.imatrix.as_ref()
+    }
+}
+
+/// The importance matrix in the C++ form llama.cpp reads, freed on drop.
+struct RawImatrix(*mut llama_cpp_sys_2::llama_rs_imatrix);
+
+impl RawImatrix {
+    fn new(imatrix: &Imatrix) -> Result<Self, NulError> {
+        let raw = Self(unsafe { llama_cpp_sys_2::llama_rs_imatrix_new() });
+        for (name, entry) in imatrix.entries() {
+            let name = CString::new(name.as_str())?;
+            let values = entry.mean();
+            unsafe {
+                llama_cpp_sys_2::llama_rs_imatrix_insert(
+                    raw.0,
+                    name.as_ptr(),
+                    values.as_ptr(),
+                    values.len(),
+                );
+            }
+        }
+        Ok(raw)
+    }
+}
+
+impl Drop for RawImatrix {
+    fn drop(&mut self) {
+        unsafe { llama_cpp_sys_2::llama_rs_imatrix_free(self.0) }
+    }
+}
+
+/// Quantize the model file at `input` into a new file at `output`.
+///
+/// # Errors
+///
+/// See [`QuantizeError`] for more information.
+pub fn quantize_model(
+    _backend: &LlamaBackend,
+    input: impl AsRef<Path>,
+    output: impl AsRef<Path>,
+    params: &QuantizeParams,
+) -> Result<(), QuantizeError> {
+    if params.file_type.needs_imatrix() && params.imatrix.is_none() && !params.only_copy() {
+        return Err(QuantizeError::ImatrixRequired(params.file_type));
+    }
+    let path_to_cstring = |path: &Path| {
+        let path = path
+            .to_str()
+            .ok_or_else(|| QuantizeError::PathToStrError(path.to_path_buf()))?;
+        Ok::<_, QuantizeError>(CString::new(path)?)
+    };
+    let input = path_to_cstring(input.as_ref())?;
+    let output = path_to_cstring(output.as_ref())?;
+
+    let imatrix = params.imatrix.as_ref().map(RawImatrix::new).transpose()?;
+    let mut raw_params = params.params;
+    if let Some(imatrix) = &imatrix {
+        raw_params.imatrix = imatrix.0.cast();
+    }
+    let (result, logs) = with_captured_logs(|| unsafe {
+        llama_cpp_sys_2::llama_model_quantize(input.as_ptr(), output.as_ptr(), &raw_params)
+    });
+    drop(imatrix);
+
+    if result == 0 {
+        Ok(())
+    } else {
+        Err(QuantizeError::Failed(failure_message(&logs)))
+    }
+}
+
+/// The reason llama.cpp logs when `llama_model_quantize` throws.
+fn failure_message(logs: &[String]) -> Option<String> {
+    logs.iter().rev().find_map(|line| {
+        let (_, message) = line.split_once("failed to quantize: ")?;
+        Some(message.trim_end().to_string())
+    })
+}
@@ -0,0 +1,675 @@
+//! Importance matrices, the `imatrix.dat` files of llama.cpp's `llama-imatrix`.
+//!
+//! An importance matrix holds, for every weight matrix of a model, the squared activations of its
+//! input columns summed over a calibration dataset. Low-bit quantizations use it to spend their
+//! precision on the weights that matter most for that data.
+//!
+//! [`Imatrix::compute`] runs the calibration text through the model to collect them, so the
+//! whole workflow of `llama-imatrix` and `llama-quantize` can be done from rust:
+//!
+//! ```no_run
+//! # use llama_cpp_2::ggml_type::GgmlFileType;
+//! # use llama_cpp_2::llama_backend::LlamaBackend;
+//! # use llama_cpp_2::model::params::LlamaModelParams;
+//! # use llama_cpp_2::model::LlamaModel;
+//! # use llama_cpp_2::quantize::imatrix::{Imatrix, ImatrixParams};
+//! # use llama_cpp_2::quantize::{quantize_model, QuantizeParams};
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! let backend = LlamaBackend::init()?;
+//! let model = LlamaModel::load_from_file(&backend, "model-f16.gguf", &LlamaModelParams::default())?;
+//! let text = std::fs::read_to_string("calibration.txt")?;
+//! let params = ImatrixParams::default().with_dataset("calibration.txt");
+//! let imatrix = Imatrix::compute(&backend, &model, &text, &params)?;
+//! imatrix.save("imatrix.dat")?;
+//! drop(model);
+//!
+//! let params = QuantizeParams::new(GgmlFileType::MostlyIQ2XS).with_imatrix(imatrix);
+//! quantize_model(&backend, "model-f16.gguf", "model-iq2_xs.gguf", &params)?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::collections::BTreeMap;
+use std::ffi::{c_void, CStr};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Write};
+use std::num::NonZeroU32;
+use std::path::Path;
+use std::string::FromUtf8Error;
+use std::sync::{Mutex, PoisonError};
+
+use crate::context::params::LlamaContextParams;
+use crate::llama_backend::LlamaBackend;
+use crate::llama_batch::{BatchAddError, LlamaBatch};
+use crate::model::{AddBos, LlamaModel};
+use crate::{DecodeError, LlamaContextLoadError, StringToTokenError};
+
+/// The name of the output tensor, which is only collected with
+/// [`ImatrixParams::with_process_output`].
+const OUTPUT_TENSOR_NAME: &str = "output.weight";
+
+/// An error that can occur while reading an importance matrix.
+#[derive(Debug, thiserror::Error)]
+#[allow(clippy::module_name_repetitions)]
+pub enum ImatrixError {
+    /// Reading the file failed (this includes the file being truncated).
+    #[error("{0}")]
+    Io(#[from] std::io::Error),
+    /// A count in the file is negative.
+    #[error("invalid {what} {value}")]
+    InvalidCount {
+        /// What was counted.
+        what: &'static str,
+        /// The count read.
+        value: i32,
+    },
+    /// A tensor name or the dataset was not valid utf8.
+    #[error("{0}")]
+    FromUtf8Error(#[from] FromUtf8Error),
+}
+
+/// The activations of one weight matrix.
+#[derive(Debug, Clone, PartialEq, Default)]
+#[allow(clippy::module_name_repetitions)]
+pub struct ImatrixEntry {
+    /// The number of times the matrix was multiplied with while collecting.
+    pub n_calls: u32,
+    /// The squared activations of every input column, summed over all calls.
+    pub values: Vec<f32>,
+}
+
+impl ImatrixEntry {
+    /// The mean squared activation of every input column, what quantization weighs columns by.
+    ///
+    /// ```
+    /// # use llama_cpp_2::quantize::imatrix::ImatrixEntry;
+    /// let entry = ImatrixEntry { n_calls: 2, values: vec![1.0, 4.0] };
+    /// assert_eq!(entry.mean(), vec![0.5, 2.0]);
+    /// ```
+    #[must_use]
+    #[allow(clippy::cast_precision_loss)]
+    pub fn mean(&self) -> Vec<f32> {
+        if self.n_calls == 0 {
+            return self.values.clone();
+        }
+        let n_calls = self.n_calls as f32;
+        self.values.iter().map(|value| value / n_calls).collect()
+    }
+}
+
+/// An importance matrix: the [`ImatrixEntry`] of every weight matrix by tensor name.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Imatrix {
+    entries: BTreeMap<String, ImatrixEntry>,
+    chunks: Option<u32>,
+    dataset: Option<String>,
+}
+
+impl Imatrix {
+    /// Read the importance matrix file at `path`.
+    ///
+    /// # Errors
+    ///
+    /// See [`ImatrixError`] for more information.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, ImatrixError> {
+        Self::read(BufReader::new(File::open(path)?))
+    }
+
+    /// Read an importance matrix from `reader`.
+    ///
+    /// # Errors
+    ///
+    /// See [`ImatrixError`] for more information.
+    pub fn read(mut reader: impl Read) -> Result<Self, ImatrixError> {
+        let n_entries = read_count(&mut reader, "number of entries")?;
+        let mut entries = BTreeMap::new();
+        for _ in 0..n_entries {
+            let name = read_string(&mut reader, "name length")?;
+            let n_calls = read_count(&mut reader, "number of calls")?;
+            let n_values = read_count(&mut reader, "number of values")?;
+            let mut values = Vec::with_capacity(n_values.min(1 << 20) as usize);
+            for _ in 0..n_values {
+                values.push(f32::from_le_bytes(read_array(&mut reader)?));
+            }
+            entries.insert(name, ImatrixEntry { n_calls, values });
+        }
+
+        // files written before llama.cpp recorded the dataset end here
+        let chunks = match read_array(&mut reader) {
+            Ok(chunks) => Some(i32::from_le_bytes(chunks)),
+            Err(err) if err.kind() == ErrorKind::UnexpectedEof => None,
+            Err(err) => return Err(err.into()),
+        };
+        let chunks = chunks
+            .map(|value| {
+                u32::try_from(value).map_err(|_| ImatrixError::InvalidCount {
+                    what: "number of chunks",
+                    value,
+                })
+            })
+            .transpose()?;
+        let dataset = match chunks {
+            Some(_) => Some(read_string(&mut reader, "dataset length")?),
+            None => None,
+        };
+        Ok(Self {
+            entries,
+            chunks,
+            dataset,
+        })
+    }
+
+    /// The entries by tensor name.
+    #[must_use]
+    pub fn entries(&self) -> &BTreeMap<String, ImatrixEntry> {
+        &self.entries
+    }
+
+    /// Get the entry of the tensor `name`.
+    #[must_use]
+    pub fn get(&self, name: &str) -> Option<&ImatrixEntry> {
+        self.entries.get(name)
+    }
+
+    /// The number of chunks of the dataset that were evaluated, if the file records it.
+    #[must_use]
+    pub fn chunks(&self) -> Option<u32> {
+        self.chunks
+    }
+
+    /// The file name of the calibration dataset, if the file records it.
+    #[must_use]
+    pub fn dataset(&self) -> Option<&str> {
+        self.dataset.as_deref()
+    }
+
+    /// Write the importance matrix to a new file at `path`, in the format `llama-imatrix` writes.
+    ///
+    /// # Errors
+    ///
+    /// If the file cannot be written.
+    pub fn save(&self, path: impl AsRef<Path>) -> std::io::Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        self.write(&mut writer)?;
+        writer.flush()
+    }
+
+    /// Write the importance matrix to `writer`, in the format `llama-imatrix` writes.
+    ///
+    /// # Errors
+    ///
+    /// If writing fails or a count does not fit the `int32_t` of the format.
+    pub fn write(&self, mut writer: impl Write) -> std::io::Result<()> {
+        write_count(&mut writer, self.entries.len())?;
+        for (name, entry) in &self.entries {
+            write_string(&mut writer, name)?;
+            write_count(&mut writer, entry.n_calls)?;
+            write_count(&mut writer, entry.values.len())?;
+            for value in &entry.values {
+                writer.write_all(&value.to_le_bytes())?;
+            }
+        }
+        if let Some(chunks) = self.chunks {
+            write_count(&mut writer, chunks)?;
+            write_string(&mut writer, self.dataset.as_deref().unwrap_or_default())?;
+        }
+        Ok(())
+    }
+
+    /// Keep only the entries of the tensors for which `keep` returns true, like the
+    /// `--include-weights` and `--exclude-weights` options of `llama-quantize`. Tensors without an
+    /// entry are quantized without guidance.
+    ///
+    /// ```
+    /// # use llama_cpp_2::quantize::imatrix::Imatrix;
+    /// let mut imatrix = Imatrix::default();
+    /// imatrix.retain(|name| !name.contains("attn_v"));
+    /// assert!(imatrix.entries().is_empty());
+    /// ```
+    pub fn retain(&mut self, mut keep: impl FnMut(&str) -> bool) {
+        self.entries.retain(|name, _| keep(name));
+    }
+}
+
+fn read_array<const N: usize>(reader: &mut impl Read) -> std::io::Result<[u8; N]> {
+    let mut buf = [0; N];
+    reader.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+/// Read an `int32_t` that counts something, which must not be negative.
+fn read_count(reader: &mut impl Read, what: &'static str) -> Result<u32, ImatrixError> {
+    let value = i32::from_le_bytes(read_array(reader)?);
+    u32::try_from(value).map_err(|_| ImatrixError::InvalidCount { what, value })
+}
+
+/// Read a string prefixed with its `int32_t` length.
+fn read_string(reader: &mut impl Read, what: &'static str) -> Result<String, ImatrixError> {
+    let len = read_count(reader, what)?;
+    let mut buf = Vec::with_capacity(len.min(1 << 16) as usize);
+    let read = reader.take(u64::from(len)).read_to_end(&mut buf)?;
+    if read as u64 != u64::from(len) {
+        return Err(std::io::Error::from(ErrorKind::UnexpectedEof).into());
+    }
+    Ok(String::from_utf8(buf)?)
+}
+
+/// Write a count or length as the `int32_t` of the format.
+fn write_count(writer: &mut impl Write, count: impl TryInto<i32>) -> std::io::Result<()> {
+    let count = count
+        .try_into()
+        .map_err(|_| std::io::Error::new(ErrorKind::InvalidInput, "count does not fit an i32"))?;
+    writer.write_all(&count.to_le_bytes())
+}
+
+fn write_string(writer: &mut impl Write, s: &str) -> std::io::Result<()> {
+    write_count(writer, s.len())?;
+    writer.write_all(s.as_bytes())
+}
+
+/// An error that can occur while computing an importance matrix.
+#[derive(Debug, thiserror::Error)]
+pub enum ImatrixComputeError {
+    /// The calibration text is shorter than one chunk.
+    #[error("the text has {n_tokens} tokens, at least one chunk of {n_ctx} is needed")]
+    TooShort {
+        /// The number of tokens of the text.
+        n_tokens: usize,
+        /// The number of tokens of a chunk.
+        n_ctx: u32,
+    },
+    /// A weight matrix was multiplied with activations of different sizes, which happens when
+    /// tensors names are not unique.
+    #[error("tensor {name} has {expected} columns but was multiplied with {actual}")]
+    ShapeMismatch {
+        /// The name of the tensor.
+        name: String,
+        /// The number of columns collected before.
+        expected: usize,
+        /// The number of columns of this multiplication.
+        actual: usize,
+    },
+    /// See [`LlamaContextLoadError`].
+    #[error("{0}")]
+    ContextLoad(#[from] LlamaContextLoadError),
+    /// See [`StringToTokenError`].
+    #[error("{0}")]
+    Tokenize(#[from] StringToTokenError),
+    /// See [`BatchAddError`].
+    #[error("{0}")]
+    BatchAdd(#[from] BatchAddError),
+    /// See [`DecodeError`].
+    #[error("{0}")]
+    Decode(#[from] DecodeError),
+}
+
+/// How [`Imatrix::compute`] runs the calibration text, with the defaults of `llama-imatrix`.
+#[derive(Debug, Clone)]
+#[allow(clippy::module_name_repetitions)]
+pub struct ImatrixParams {
+    n_ctx: NonZeroU32,
+    n_chunks: Option<usize>,
+    process_output: bool,
+    dataset: Option<String>,
+    context_params: LlamaContextParams,
+}
+
+impl Default for ImatrixParams {
+    fn default() -> Self {
+        Self {
+            n_ctx: NonZeroU32::new(512).expect("512 is not zero"),
+            n_chunks: None,
+            process_output: false,
+            dataset: None,
+            context_params: LlamaContextParams::default(),
+        }
+    }
+}
+
+impl ImatrixParams {
+    /// Set the number of tokens of each chunk the text is split into, 512 by default. Every
+    /// chunk is evaluated on its own, from an empty cache.
+    #[must_use]
+    pub fn with_n_ctx(mut self, n_ctx: NonZeroU32) -> Self {
+        self.n_ctx = n_ctx;
+        self
+    }
+
+    /// Get the number of tokens of each chunk.
+    #[must_use]
+    pub fn n_ctx(&self) -> NonZeroU32 {
+        self.n_ctx
+    }
+
+    /// Evaluate at most `n_chunks` chunks instead of the whole text.
+    #[must_use]
+    pub fn with_n_chunks(mut self, n_chunks: usize) -> Self {
+        self.n_chunks = Some(n_chunks);
+        self
+    }
+
+    /// Get the maximum number of chunks, `None` for the whole text.
+    #[must_use]
+    pub fn n_chunks(&self) -> Option<usize> {
+        self.n_chunks
+    }
+
+    /// Also collect the activations of the output tensor, which quantizations keep at a high
+    /// precision anyway. Off by default.
+    #[must_use]
+    pub fn with_process_output(mut self, process_output: bool) -> Self {
+        self.process_output = process_output;
+        self
+    }
+
+    /// Get whether the output tensor is collected.
+    #[must_use]
+    pub fn process_output(&self) -> bool {
+        self.process_output
+    }
+
+    /// Record `dataset` as the name of the calibration text in the importance matrix.
+    #[must_use]
+    pub fn with_dataset(mut self, dataset: impl Into<String>) -> Self {
+        self.dataset = Some(dataset.into());
+        self
+    }
+
+    /// Get the name of the calibration text.
+    #[must_use]
+    pub fn dataset(&self) -> Option<&str> {
+        self.dataset.as_deref()
+    }
+
+    /// Create the context with `context_params`, e.g. to set the threads. The context size is
+    /// [`ImatrixParams::n_ctx`] and the evaluation callback is replaced.
+    #[must_use]
+    pub fn with_context_params(mut self, context_params: LlamaContextParams) -> Self {
+        self.context_params = context_params;
+        self
+    }
+}
+
+/// The activations collected for one weight matrix.
+#[derive(Debug, Default)]
+struct Stats {
+    n_calls: u32,
+    values: Vec<f32>,
+    counts: Vec<u32>,
+}
+
+/// What the evaluation callback collects into.
+#[derive(Debug)]
+struct Collector {
+    process_output: bool,
+    stats: BTreeMap<String, Stats>,
+    /// Activations copied from a gpu, as `f32` so the rows read from it are aligned.
+    scratch: Vec<f32>,
+    error: Option<ImatrixComputeError>,
+}
+
+impl Collector {
+    /// Whether the scheduler should hand out the result of `t`.
+    ///
+    /// # Safety
+    ///
+    /// `t` must point to a valid tensor whose sources are valid.
+    unsafe fn wants(&self, t: &llama_cpp_sys_2::ggml_tensor) -> bool {
+        // the experts of every token are collected
+        if t.op == llama_cpp_sys_2::GGML_OP_MUL_MAT_ID {
+            return true;
+        }
+        if t.op != llama_cpp_sys_2::GGML_OP_MUL_MAT {
+            return false;
+        }
+        let src1 = &*t.src[1];
+        // like llama-imatrix, skip the small batches of generation and non f32 activations
+        if src1.ne[1] < 16 || src1.type_ != llama_cpp_sys_2::GGML_TYPE_F32 {
+            return false;
+        }
+        let name = weight_name(&*t.src[0]);
+        name.starts_with("blk.") || (self.process_output && name == OUTPUT_TENSOR_NAME)
+    }
+
+    /// Copy the bytes of `tensor` to the host, if they are not there already.
+    ///
+    /// # Safety
+    ///
+    /// `tensor` must point to a valid, computed tensor.
+    unsafe fn host_data(
+        scratch: &mut Vec<f32>,
+        tensor: &llama_cpp_sys_2::ggml_tensor,
+    ) -> *const u8 {
+        if llama_cpp_sys_2::ggml_backend_buffer_is_host(tensor.buffer) {
+            return tensor.data.cast();
+        }
+        let n_bytes = llama_cpp_sys_2::ggml_nbytes(tensor);
+        scratch.resize(n_bytes.div_ceil(std::mem::size_of::<f32>()), 0.0);
+        llama_cpp_sys_2::ggml_backend_tensor_get(tensor, scratch.as_mut_ptr().cast(), 0, n_bytes);
+        scratch.as_ptr().cast()
+    }
+
+    /// Accumulate the squared activations of the multiplication `t`.
+    ///
+    /// # Safety
+    ///
+    /// `t` must point to a valid, computed tensor the collector [wants](Collector::wants).
+    unsafe fn collect(&mut self, t: &llama_cpp_sys_2::ggml_tensor) {
+        let src0 = &*t.src[0];
+        let src1 = &*t.src[1];
+        let name = weight_name(src0);
+        let n_columns = dim(src1, 0);
+        let data = Self::host_data(&mut self.scratch, src1);
+        // the rows of `src1` by their index in dimensions 1 and 2
+        let row = |i1: usize, i2: usize| {
+            let row = data.add(i1 * src1.nb[1] + i2 * src1.nb[2]).cast::<f32>();
+            std::slice::from_raw_parts(row, n_columns)
+        };
+
+        if t.op == llama_cpp_sys_2::GGML_OP_MUL_MAT_ID {
+            // `src0` stacks the experts, `ids` holds the experts chosen for every token
+            let n_experts = dim(src0, 2);
+            let ids = &*t.src[2];
+            let mut id_bytes = vec![0_u8; llama_cpp_sys_2::ggml_nbytes(ids)];
+            llama_cpp_sys_2::ggml_backend_tensor_get(
+                ids,
+                id_bytes.as_mut_ptr().cast(),
+                0,
+                id_bytes.len(),
+            );
+            let Some(stats) = self.stats_of(name, n_columns * n_experts) else {
+                return;
+            };
+            for token in 0..dim(src1, 2) {
+                for slot in 0..dim(ids, 0) {
+                    let offset = token * ids.nb[1] + slot * ids.nb[0];
+                    let id = i32::from_ne_bytes(
+                        id_bytes[offset..offset + 4]
+                            .try_into()
+                            .expect("slice of 4 bytes"),
+                    );
+                    let Some(expert) = usize::try_from(id).ok().filter(|e| *e < n_experts) else {
+                        continue;
+                    };
+                    let start = expert * n_columns;
+                    stats.accumulate(start, row(slot % dim(src1, 1), token));
+                }
+            }
+        } else {
+            let Some(stats) = self.stats_of(name, n_columns) else {
+                return;
+            };
+            for i1 in 0..dim(src1, 1) {
+                stats.accumulate(0, row(i1, 0));
+            }
+        }
+    }
+
+    /// The stats of the tensor `name` for one more call, or `None` after recording an error if
+    /// they have a different number of columns.
+    fn stats_of(&mut self, name: String, n_values: usize) -> Option<&mut Stats> {
+        let stats = self.stats.entry(name.clone()).or_default();
+        if stats.values.is_empty() {
+            stats.values = vec![0.0; n_values];
+            stats.counts = vec![0; n_values];
+        } else if stats.values.len() != n_values {
+            self.error
+                .get_or_insert(ImatrixComputeError::ShapeMismatch {
+                    name,
+                    expected: stats.values.len(),
+                    actual: n_values,
+                });
+            return None;
+        }
+        stats.n_calls += 1;
+        Some(stats)
+    }
+
+    fn into_imatrix(self, chunks: usize, dataset: Option<String>) -> Imatrix {
+        let entries = self
+            .stats
+            .into_iter()
+            // like llama-imatrix, skip tensors with columns that were never activated, e.g.
+            // experts no token was routed to
+            .filter(|(_, stats)| stats.counts.iter().all(|count| *count > 0))
+            .map(|(name, stats)| {
+                let n_calls = stats.n_calls;
+                #[allow(clippy::cast_precision_loss)]
+                let values = stats
+                    .values
+                    .iter()
+                    .zip(&stats.counts)
+                    .map(|(value, count)| value / *count as f32 * n_calls as f32)
+                    .collect();
+                (name, ImatrixEntry { n_calls, values })
+            })
+            .collect();
+        Imatrix {
+            entries,
+            chunks: Some(u32::try_from(chunks).unwrap_or(u32::MAX)),
+            dataset,
+        }
+    }
+}
+
+impl Stats {
+    fn accumulate(&mut self, start: usize, row: &[f32]) {
+        let values = &mut self.values[start..start + row.len()];
+        let counts = &mut self.counts[start..start + row.len()];
+        for ((value, count), x) in values.iter_mut().zip(counts).zip(row) {
+            *value += x * x;
+            *count += 1;
+        }
+    }
+}
+
+/// The size of dimension `i` of `tensor`.
+fn dim(tensor: &llama_cpp_sys_2::ggml_tensor, i: usize) -> usize {
+    usize::try_from(tensor.ne[i]).unwrap_or(0)
+}
+
+/// The name of a weight, without the `backend#name#copy` decoration of the copies the scheduler
+/// makes for pipeline parallelism.
+fn weight_name(tensor: &llama_cpp_sys_2::ggml_tensor) -> String {
+    let name = unsafe { CStr::from_ptr(tensor.name.as_ptr()) }.to_string_lossy();
+    match name.split_once('#') {
+        Some((_, rest)) => rest.split('#').next().unwrap_or(rest).to_string(),
+        None => name.into_owned(),
+    }
+}
+
+/// The evaluation callback, with the [`Collector`] as user data.
+unsafe extern "C" fn collect_activations(
+    t: *mut llama_cpp_sys_2::ggml_tensor,
+    ask: bool,
+    user_data: *mut c_void,
+) -> bool {
+    let collector = &*user_data.cast::<Mutex<Collector>>();
+    let mut collector = collector.lock().unwrap_or_else(PoisonError::into_inner);
+    if ask {
+        collector.wants(&*t)
+    } else {
+        collector.collect(&*t);
+        // returning false would stop evaluating the graph
+        true
+    }
+}
+
+impl Imatrix {
+    /// Compute the importance matrix of `model` over `text`, like `llama-imatrix`: the text is
+    /// split into chunks of [`ImatrixParams::n_ctx`] tokens and the activations of the weight
+    /// matrices are collected while evaluating every chunk.
+    ///
+    /// A few hundred chunks of text similar to what the model will be used for are typical.
+    ///
+    /// # Errors
+    ///
+    /// See [`ImatrixComputeError`] for more information.
+    pub fn compute(
+        backend: &LlamaBackend,
+        model: &LlamaModel,
+        text: &str,
+        params: &ImatrixParams,
+    ) -> Result<Self, ImatrixComputeError> {
+        let n_ctx = params.n_ctx.get();
+        let tokens = model.str_to_token(text, AddBos::Always)?;
+        let chunk_len = n_ctx as usize;
+        let available = tokens.len() / chunk_len;
+        if available == 0 {
+            return Err(ImatrixComputeError::TooShort {
+                n_tokens: tokens.len(),
+                n_ctx,
+            });
+        }
+        let n_chunks = params.n_chunks.map_or(available, |n| n.min(available));
+        // every chunk starts with the bos token, if the model has one
+        let bos = model.token_bos();
+        let add_bos = tokens.first() == Some(&bos);
+
+        // declared before the context, which points to it, so it outlives the context
+        let collector = Mutex::new(Collector {
+            process_output: params.process_output,
+            stats: BTreeMap::new(),
+            scratch: Vec::new(),
+            error: None,
+        });
+        let context_params = params
+            .context_params
+            .clone()
+            .with_n_ctx(Some(params.n_ctx))
+            .with_cb_eval(Some(collect_activations))
+            .with_cb_eval_user_data(std::ptr::addr_of!(collector).cast_mut().cast());
+        let mut ctx = model.new_context(backend, context_params)?;
+        let n_batch = (ctx.n_batch() as usize).min(chunk_len);
+        let mut batch = LlamaBatch::new(n_batch, 1);
+
+        for chunk in tokens.chunks_exact(chunk_len).take(n_chunks) {
+            ctx.clear_kv_cache();
+            for (first, tokens) in chunk.chunks(n_batch).enumerate() {
+                batch.clear();
+                for (i, &token) in tokens.iter().enumerate() {
+                    let pos = first * n_batch + i;
+                    let token = if pos == 0 && add_bos { bos } else { token };
+                    // the output tensor only sees the tokens with logits
+                    let logits = params.process_output || pos + 1 == chunk_len;
+                    batch.add(token, i32::try_from(pos).unwrap_or(i32::MAX), &[0], logits)?;
+                }
+                ctx.decode(&mut batch)?;
+            }
+            let error = collector
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .error
+                .take();
+            if let Some(error) = error {
+                return Err(error);
+            }
+        }
+        drop(ctx);
+
+        let collector = collector
+            .into_inner()
+            .unwrap_or_else(PoisonError::into_inner);
+        Ok(collector.into_imatrix(n_chunks, params.dataset.clone()))
+    }
+}
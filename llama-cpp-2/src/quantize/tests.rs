@@ -0,0 +1,86 @@
+use super::imatrix::{Imatrix, ImatrixError};
+use super::*;
+
+fn push_string(buf: &mut Vec<u8>, s: &str) {
+    buf.extend(i32::try_from(s.len()).unwrap().to_le_bytes());
+    buf.extend(s.as_bytes());
+}
+
+/// An imatrix file with two entries, optionally followed by the chunk count and dataset.
+fn sample_imatrix(with_dataset: bool) -> Vec<u8> {
+    let mut buf = Vec::new();
+    buf.extend(2_i32.to_le_bytes());
+    for (name, values) in [
+        ("blk.0.attn_q.weight", [2.0_f32, 4.0]),
+        ("output.weight", [1.0, 3.0]),
+    ] {
+        push_string(&mut buf, name);
+        // ncall
+        buf.extend(2_i32.to_le_bytes());
+        // nval
+        buf.extend(2_i32.to_le_bytes());
+        for value in values {
+            buf.extend(value.to_le_bytes());
+        }
+    }
+    if with_dataset {
+        buf.extend(100_i32.to_le_bytes());
+        push_string(&mut buf, "wiki.train.raw");
+    }
+    buf
+}
+
+#[test]
+fn read_imatrix() {
+    let imatrix = Imatrix::read(sample_imatrix(true).as_slice()).unwrap();
+    assert_eq!(imatrix.entries().len(), 2);
+    let entry = imatrix.get("blk.0.attn_q.weight").unwrap();
+    assert_eq!(entry.n_calls, 2);
+    assert_eq!(entry.mean(), vec![1.0, 2.0]);
+    assert_eq!(imatrix.chunks(), Some(100));
+    assert_eq!(imatrix.dataset(), Some("wiki.train.raw"));
+}
+
+#[test]
+fn read_imatrix_without_dataset() {
+    let mut imatrix = Imatrix::read(sample_imatrix(false).as_slice()).unwrap();
+    assert_eq!(imatrix.chunks(), None);
+    assert_eq!(imatrix.dataset(), None);
+
+    imatrix.retain(|name| name != "output.weight");
+    assert_eq!(
+        imatrix.entries().keys().collect::<Vec<_>>(),
+        ["blk.0.attn_q.weight"]
+    );
+}
+
+#[test]
+fn read_truncated_imatrix() {
+    let buf = sample_imatrix(false);
+    let err = Imatrix::read(&buf[..buf.len() - 2]).unwrap_err();
+    assert!(matches!(err, ImatrixError::Io(_)), "{err:?}");
+}
+
+#[test]
+fn failure_message_from_logs() {
+    let logs = [
+        "llama_model_quantize_internal: meta size = 741536 bytes\n".to_string(),
+        "llama_model_quantize: failed to quantize: tensor 'output.weight' data is not within the file bounds\n".to_string(),
+    ];
+    assert_eq!(
+        failure_message(&logs).as_deref(),
+        Some("tensor 'output.weight' data is not within the file bounds")
+    );
+    assert_eq!(failure_message(&logs[..1]), None);
+}
+
+#[test]
+fn write_imatrix_round_trips() {
+    for with_dataset in [true, false] {
+        let buf = sample_imatrix(with_dataset);
+        let imatrix = Imatrix::read(buf.as_slice()).unwrap();
+        let mut written = Vec::new();
+        imatrix.write(&mut written).unwrap();
+        assert_eq!(written, buf);
+    }
+}
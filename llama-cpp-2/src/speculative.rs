@@ -0,0 +1,160 @@
+//! Bookkeeping for speculative decoding.
+//!
+//! In speculative decoding a small draft model proposes a few tokens which the target model then
+//! verifies in a single batch. Every verification round yields the accepted prefix of the draft
+//! plus one token sampled from the target. [`SpeculativeStats`] tracks these rounds to tell
+//! whether the draft model pays for itself.
+//!
+//! # Examples
+//!
+//! ```
+//! # use std::time::Duration;
+//! # use llama_cpp_2::speculative::SpeculativeStats;
+//! let mut stats = SpeculativeStats::default();
+//! // the draft model proposed 4 tokens, the target accepted 3 of them
+//! stats.record_round(4, 3, Duration::from_millis(8), Duration::from_millis(40));
+//! stats.record_round(4, 1, Duration::from_millis(8), Duration::from_millis(40));
+//!
+//! assert_eq!(stats.drafted(), 8);
+//! assert_eq!(stats.accepted(), 4);
+//! assert_eq!(stats.generated(), 6);
+//! assert_eq!(stats.acceptance_rate(), 0.5);
+//! // 6 tokens in 96ms compared to 40ms per token without a draft model
+//! let speedup = stats.speedup_estimate().unwrap();
+//! assert!((speedup - 2.5).abs() < 1e-9);
+//! ```
+
+use std::fmt::{Display, Formatter};
+use std::time::Duration;
+
+/// Statistics of the verification rounds of a speculative decoding request.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SpeculativeStats {
+    rounds: u64,
+    drafted: u64,
+    accepted: u64,
+    draft_time: Duration,
+    target_time: Duration,
+}
+
+impl SpeculativeStats {
+    /// Record a verification round.
+    ///
+    /// # Parameters
+    ///
+    /// * `drafted` - The number of tokens proposed by the draft model.
+    /// * `accepted` - The number of drafted tokens the target model agreed with.
+    /// * `draft_time` - The time spent generating the draft.
+    /// * `target_time` - The time spent decoding the draft with the target model.
+    pub fn record_round(
+        &mut self,
+        drafted: usize,
+        accepted: usize,
+        draft_time: Duration,
+        target_time: Duration,
+    ) {
+        debug_assert!(
+            accepted <= drafted,
+            "accepted more tokens than were drafted"
+        );
+        self.rounds += 1;
+        self.drafted += drafted as u64;
+        self.accepted += accepted.min(drafted) as u64;
+        self.draft_time += draft_time;
+        self.target_time += target_time;
+    }
+
+    /// The number of verification rounds.
+    #[must_use]
+    pub fn rounds(&self) -> u64 {
+        self.rounds
+    }
+
+    /// The number of tokens proposed by the draft model.
+    #[must_use]
+    pub fn drafted(&self) -> u64 {
+        self.drafted
+    }
+
+    /// The number of drafted tokens accepted by the target model.
+    #[must_use]
+    pub fn accepted(&self) -> u64 {
+        self.accepted
+    }
+
+    /// The number of tokens generated, the accepted tokens plus one token from the target model
+    /// per round.
+    #[must_use]
+    pub fn generated(&self) -> u64 {
+        self.accepted + self.rounds
+    }
+
+    /// The share of drafted tokens that were accepted, between 0 and 1.
+    #[must_use]
+    #[allow(clippy::cast_precision_loss)]
+    pub fn acceptance_rate(&self) -> f64 {
+        if self.drafted == 0 {
+            0.0
+        } else {
+            self.accepted as f64 / self.drafted as f64
+        }
+    }
+
+    /// The average number of tokens generated per target model decode.
+    #[must_use]
+    #[allow(clippy::cast_precision_loss)]
+    pub fn tokens_per_round(&self) -> f64 {
+        if self.rounds == 0 {
+            0.0
+        } else {
+            self.generated() as f64 / self.rounds as f64
+        }
+    }
+
+    /// The time spent in the draft model.
+    #[must_use]
+    pub fn draft_time(&self) -> Duration {
+        self.draft_time
+    }
+
+    /// The time spent in the target model.
+    #[must_use]
+    pub fn target_time(&self) -> Duration {
+        self.target_time
+    }
+
+    /// An estimate of the speedup over decoding with the target model alone.
+    ///
+    /// Decoding a short draft takes about as long as decoding a single token, so the average
+    /// round of the target model is used as the cost of a token without speculation. Values
+    /// below 1 mean the draft model slows generation down. `None` until a round with a non-zero
+    /// duration was recorded.
+    #[must_use]
+    #[allow(clippy::cast_precision_loss)]
+    pub fn speedup_estimate(&self) -> Option<f64> {
+        let total = (self.draft_time + self.target_time).as_secs_f64();
+        if self.rounds == 0 || total == 0.0 {
+            return None;
+        }
+        let per_token = self.target_time.as_secs_f64() / self.rounds as f64;
+        Some(self.generated() as f64 * per_token / total)
+    }
+}
+
+impl Display for SpeculativeStats {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "drafted {} accepted {} ({:.1}%) in {} rounds, {:.2} tokens per round",
+            self.drafted,
+            self.accepted,
+            self.acceptance_rate() * 100.0,
+            self.rounds,
+            self.tokens_per_round(),
+        )?;
+        if let Some(speedup) = self.speedup_estimate() {
+            write!(f, ", estimated speedup {speedup:.2}x")?;
+        }
+        Ok(())
+    }
+}
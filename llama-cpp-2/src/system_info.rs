@@ -0,0 +1,199 @@
+//! Report the CPU features available to ggml, for diagnostics and to pick quantizations.
+//!
+//! ```
+//! # use llama_cpp_2::system_info::{cpu_features, CpuFeatures};
+//! let compiled = cpu_features();
+//! let host = CpuFeatures::detect_host();
+//! println!("ggml uses: {compiled}");
+//! println!("this cpu supports: {host}");
+//! if compiled.avx2 && !host.avx2 {
+//!     println!("ggml was built for a newer cpu than this one");
+//! }
+//! ```
+
+use std::ffi::CStr;
+use std::fmt::{Display, Formatter};
+
+use llama_cpp_sys_2::ggml_type;
+
+/// SIMD and matrix extensions relevant to ggml's CPU kernels.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[allow(clippy::struct_excessive_bools)]
+pub struct CpuFeatures {
+    /// x86 SSE3.
+    pub sse3: bool,
+    /// x86 SSSE3.
+    pub ssse3: bool,
+    /// x86 AVX.
+    pub avx: bool,
+    /// x86 AVX-VNNI.
+    pub avx_vnni: bool,
+    /// x86 AVX2.
+    pub avx2: bool,
+    /// x86 FMA.
+    pub fma: bool,
+    /// x86 F16C.
+    pub f16c: bool,
+    /// x86 AVX-512 foundation.
+    pub avx512: bool,
+    /// x86 AVX-512 VBMI.
+    pub avx512_vbmi: bool,
+    /// x86 AVX-512 VNNI.
+    pub avx512_vnni: bool,
+    /// x86 AVX-512 BF16.
+    pub avx512_bf16: bool,
+    /// Arm NEON.
+    pub neon: bool,
+    /// Arm fused multiply add.
+    pub arm_fma: bool,
+    /// Arm half precision vector arithmetic.
+    pub fp16_va: bool,
+    /// Arm SVE.
+    pub sve: bool,
+    /// Arm int8 matrix multiplication (i8mm).
+    pub matmul_int8: bool,
+    /// WebAssembly SIMD.
+    pub wasm_simd: bool,
+    /// POWER VSX.
+    pub vsx: bool,
+}
+
+/// The features ggml was compiled to use. With the default native build these are the features
+/// of the build machine, so they can differ from [`CpuFeatures::detect_host`] when the binary is
+/// shipped to other machines.
+#[must_use]
+pub fn cpu_features() -> CpuFeatures {
+    use llama_cpp_sys_2 as sys;
+    let has = |f: unsafe extern "C" fn() -> std::os::raw::c_int| unsafe { f() != 0 };
+    CpuFeatures {
+        sse3: has(sys::ggml_cpu_has_sse3),
+        ssse3: has(sys::ggml_cpu_has_ssse3),
+        avx: has(sys::ggml_cpu_has_avx),
+        avx_vnni: has(sys::ggml_cpu_has_avx_vnni),
+        avx2: has(sys::ggml_cpu_has_avx2),
+        fma: has(sys::ggml_cpu_has_fma),
+        f16c: has(sys::ggml_cpu_has_f16c),
+        avx512: has(sys::ggml_cpu_has_avx512),
+        avx512_vbmi: has(sys::ggml_cpu_has_avx512_vbmi),
+        avx512_vnni: has(sys::ggml_cpu_has_avx512_vnni),
+        avx512_bf16: has(sys::ggml_cpu_has_avx512_bf16),
+        neon: has(sys::ggml_cpu_has_neon),
+        arm_fma: has(sys::ggml_cpu_has_arm_fma),
+        fp16_va: has(sys::ggml_cpu_has_fp16_va),
+        sve: has(sys::ggml_cpu_has_sve),
+        matmul_int8: has(sys::ggml_cpu_has_matmul_int8),
+        wasm_simd: has(sys::ggml_cpu_has_wasm_simd),
+        vsx: has(sys::ggml_cpu_has_vsx),
+    }
+}
+
+impl CpuFeatures {
+    /// Detect the features of the CPU this process runs on.
+    #[must_use]
+    pub fn detect_host() -> Self {
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        {
+            Self {
+                sse3: std::is_x86_feature_detected!("sse3"),
+                ssse3: std::is_x86_feature_detected!("ssse3"),
+                avx: std::is_x86_feature_detected!("avx"),
+                avx_vnni: std::is_x86_feature_detected!("avxvnni"),
+                avx2: std::is_x86_feature_detected!("avx2"),
+                fma: std::is_x86_feature_detected!("fma"),
+                f16c: std::is_x86_feature_detected!("f16c"),
+                avx512: std::is_x86_feature_detected!("avx512f"),
+                avx512_vbmi: std::is_x86_feature_detected!("avx512vbmi"),
+                avx512_vnni: std::is_x86_feature_detected!("avx512vnni"),
+                avx512_bf16: std::is_x86_feature_detected!("avx512bf16"),
+                ..Self::default()
+            }
+        }
+        #[cfg(target_arch = "aarch64")]
+        {
+            Self {
+                neon: std::arch::is_aarch64_feature_detected!("neon"),
+                arm_fma: std::arch::is_aarch64_feature_detected!("neon"),
+                fp16_va: std::arch::is_aarch64_feature_detected!("fp16"),
+                sve: std::arch::is_aarch64_feature_detected!("sve"),
+                matmul_int8: std::arch::is_aarch64_feature_detected!("i8mm"),
+                ..Self::default()
+            }
+        }
+        #[cfg(not(any(target_arch = "x86", target_arch = "x86_64", target_arch = "aarch64")))]
+        {
+            cpu_features()
+        }
+    }
+
+    /// The names of the available features, in the order llama.cpp prints them.
+    pub fn names(&self) -> impl Iterator<Item = &'static str> {
+        [
+            (self.avx, "AVX"),
+            (self.avx_vnni, "AVX_VNNI"),
+            (self.avx2, "AVX2"),
+            (self.avx512, "AVX512"),
+            (self.avx512_vbmi, "AVX512_VBMI"),
+            (self.avx512_vnni, "AVX512_VNNI"),
+            (self.avx512_bf16, "AVX512_BF16"),
+            (self.fma, "FMA"),
+            (self.neon, "NEON"),
+            (self.sve, "SVE"),
+            (self.arm_fma, "ARM_FMA"),
+            (self.f16c, "F16C"),
+            (self.fp16_va, "FP16_VA"),
+            (self.wasm_simd, "WASM_SIMD"),
+            (self.sse3, "SSE3"),
+            (self.ssse3, "SSSE3"),
+            (self.vsx, "VSX"),
+            (self.matmul_int8, "MATMUL_INT8"),
+        ]
+        .into_iter()
+        .filter_map(|(has, name)| has.then_some(name))
+    }
+
+    /// The `Q4_0` variant with weights repacked for these features, if one is faster than plain
+    /// `Q4_0`: `Q4_0_4_8` with int8 matrix multiplication and `Q4_0_4_4` with NEON.
+    #[must_use]
+    pub fn preferred_q4_0(&self) -> Option<ggml_type> {
+        if self.matmul_int8 {
+            Some(llama_cpp_sys_2::GGML_TYPE_Q4_0_4_8)
+        } else if self.neon {
+            Some(llama_cpp_sys_2::GGML_TYPE_Q4_0_4_4)
+        } else {
+            None
+        }
+    }
+}
+
+/// Space separated feature names, e.g. `AVX AVX2 FMA F16C SSE3 SSSE3`.
+impl Display for CpuFeatures {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        for (i, name) in self.names().enumerate() {
+            if i > 0 {
+                f.write_str(" ")?;
+            }
+            f.write_str(name)?;
+        }
+        Ok(())
+    }
+}
+
+/// llama.cpp's system info line (`AVX = 1 | AVX2 = 1 | ...`), including the GPU backends it was
+/// built with.
+///
+/// ```
+/// # use llama_cpp_2::system_info::system_info;
+/// assert!(system_info().contains("AVX"));
+/// ```
+///
+/// # Panics
+///
+/// - if llama.cpp returns invalid UTF-8
+#[must_use]
+pub fn system_info() -> String {
+    let info = unsafe { CStr::from_ptr(llama_cpp_sys_2::llama_print_system_info()) };
+    info.to_str()
+        .expect("system info is valid UTF-8")
+        .to_string()
+}
@@ -0,0 +1,447 @@
+//! Tiny generated models for tests that need a [`LlamaModel`], without downloading one.
+//!
+//! [`TinyModel`] writes a llama architecture GGUF with a small sentencepiece vocabulary and
+//! random weights of a few KB. It tokenizes like a real model and can be decoded, sampled from
+//! and batched, so sampling, batching and tokenization code can run in CI. The output is
+//! meaningless text, so tests should check the mechanics, not the content.
+//! [`TinyArchitecture::T5`] generates an encoder-decoder model instead.
+//!
+//! Enable the `test-utils` feature in the dev-dependencies to use it from another crate:
+//!
+//! ```toml
+//! [dev-dependencies]
+//! llama-cpp-2 = { version = "*", features = ["test-utils"] }
+//! ```
+//!
+//! ```no_run
+//! # use llama_cpp_2::llama_backend::LlamaBackend;
+//! # use llama_cpp_2::model::AddBos;
+//! # use llama_cpp_2::test_utils::TinyModel;
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! let backend = LlamaBackend::init()?;
+//! let vocab = TinyModel::default().load_vocab(&backend)?;
+//! let tokens = vocab.str_to_token("hello world", AddBos::Always)?;
+//! // <s> ▁hello ▁world
+//! assert_eq!(tokens.len(), 3);
+//! # Ok(())
+//! # }
+//! ```
+
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::OnceLock;
+
+use crate::gguf::writer::GgufWriter;
+use crate::gguf::GgufValue;
+use crate::llama_backend::LlamaBackend;
+use crate::model::params::LlamaModelParams;
+use crate::model::LlamaModel;
+use crate::LlamaModelLoadError;
+
+#[cfg(test)]
+mod tests;
+
+/// The sentencepiece word boundary marker.
+const SPACE: char = '\u{2581}';
+
+/// The fill-in-the-middle tokens of [`TinyModel::with_fim_tokens`] and the metadata key of each.
+const FIM_TOKENS: [(&str, &str); 4] = [
+    ("<PRE>", "tokenizer.ggml.prefix_token_id"),
+    ("<SUF>", "tokenizer.ggml.suffix_token_id"),
+    ("<MID>", "tokenizer.ggml.middle_token_id"),
+    ("<EOT>", "tokenizer.ggml.eot_token_id"),
+];
+
+/// The number of relative position buckets of [`TinyArchitecture::T5`].
+const T5_RELATIVE_BUCKETS: u32 = 32;
+
+/// Failed to create a [`TinyModel`].
+#[derive(Debug, thiserror::Error)]
+pub enum TinyModelError {
+    /// The model file could not be written.
+    #[error("failed to write the model: {0}")]
+    Io(#[from] std::io::Error),
+    /// llama.cpp could not load the model.
+    #[error("failed to load the model: {0}")]
+    Load(#[from] LlamaModelLoadError),
+}
+
+/// The architecture of a generated model.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TinyArchitecture {
+    /// A decoder-only llama model.
+    #[default]
+    Llama,
+    /// A T5 encoder-decoder model, to run with
+    /// [`LlamaContext::generate_seq2seq`](crate::context::LlamaContext::generate_seq2seq). Its
+    /// decoder starts from the `<s>` token.
+    T5,
+}
+
+impl TinyArchitecture {
+    /// The `general.architecture` of the model, which prefixes its hyperparameter keys.
+    #[must_use]
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Llama => "llama",
+            Self::T5 => "t5",
+        }
+    }
+}
+
+/// The configuration of a generated model.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TinyModel {
+    architecture: TinyArchitecture,
+    words: Vec<String>,
+    special_tokens: Vec<String>,
+    fim_tokens: bool,
+    n_embd: u32,
+    n_head: u32,
+    n_ff: u32,
+    n_layer: u32,
+    n_ctx_train: u32,
+    seed: u64,
+}
+
+/// A single layer model with 16 embedding dimensions and a vocabulary of a few english words.
+impl Default for TinyModel {
+    fn default() -> Self {
+        Self {
+            architecture: TinyArchitecture::Llama,
+            words: [
+                "hello", "world", "the", "a", "is", "of", "and", "to", "cat", "dog",
+            ]
+            .map(String::from)
+            .to_vec(),
+            special_tokens: Vec::new(),
+            fim_tokens: false,
+            n_embd: 16,
+            n_head: 2,
+            n_ff: 32,
+            n_layer: 1,
+            n_ctx_train: 512,
+            seed: 42,
+        }
+    }
+}
+
+impl TinyModel {
+    /// The architecture, [`TinyArchitecture::Llama`] by default.
+    #[must_use]
+    pub fn with_architecture(mut self, architecture: TinyArchitecture) -> Self {
+        self.architecture = architecture;
+        self
+    }
+
+    /// The words that become whole tokens. Any other text is tokenized into characters of these
+    /// words, or bytes.
+    #[must_use]
+    pub fn with_words<S: Into<String>>(mut self, words: impl IntoIterator<Item = S>) -> Self {
+        self.words = words.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Control tokens added after the words, such as `<|im_start|>`. They are only tokenized
+    /// from text with [`Special::Tokenize`](crate::model::Special::Tokenize).
+    #[must_use]
+    pub fn with_special_tokens<S: Into<String>>(
+        mut self,
+        tokens: impl IntoIterator<Item = S>,
+    ) -> Self {
+        self.special_tokens = tokens.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Add the fill-in-the-middle control tokens `<PRE>`, `<SUF>`, `<MID>` and `<EOT>` after the
+    /// special tokens and declare them as the prefix, suffix, middle and end of turn tokens.
+    #[must_use]
+    pub fn with_fim_tokens(mut self) -> Self {
+        self.special_tokens
+            .extend(FIM_TOKENS.map(|(token, _)| token.to_string()));
+        self.fim_tokens = true;
+        self
+    }
+
+    /// The number of layers.
+    #[must_use]
+    pub fn with_n_layer(mut self, n_layer: u32) -> Self {
+        self.n_layer = n_layer;
+        self
+    }
+
+    /// The context size the model claims to be trained on.
+    #[must_use]
+    pub fn with_n_ctx_train(mut self, n_ctx_train: u32) -> Self {
+        self.n_ctx_train = n_ctx_train;
+        self
+    }
+
+    /// The seed of the random weights. The same seed always gives the same model.
+    #[must_use]
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    /// The vocabulary: `<unk>`, `<s>`, `</s>`, the 256 byte tokens, the characters and prefixes
+    /// of every word, then the special tokens.
+    #[must_use]
+    pub fn vocab(&self) -> Vec<String> {
+        let mut vocab: Vec<String> = ["<unk>", "<s>", "</s>"].map(String::from).to_vec();
+        vocab.extend((0..=255).map(|byte| format!("<0x{byte:02X}>")));
+        let mut pieces = vec![SPACE.to_string()];
+        for word in &self.words {
+            pieces.extend(word.chars().map(String::from));
+            let word = format!("{SPACE}{word}");
+            let boundaries = word.char_indices().map(|(i, _)| i).skip(2);
+            pieces.extend(boundaries.map(|end| word[..end].to_string()));
+            pieces.push(word);
+        }
+        for piece in pieces
+            .into_iter()
+            .chain(self.special_tokens.iter().cloned())
+        {
+            if !vocab.contains(&piece) {
+                vocab.push(piece);
+            }
+        }
+        vocab
+    }
+
+    /// The GGUF of the model. Without weights only the vocabulary can be used, see
+    /// [`TinyModel::load_vocab`].
+    #[must_use]
+    pub fn gguf(&self, with_weights: bool) -> GgufWriter {
+        let vocab = self.vocab();
+        let scores = vocab
+            .iter()
+            .enumerate()
+            .map(|(id, piece)| {
+                // special and byte tokens are never merged, longer pieces are merged first
+                if id < 259 {
+                    GgufValue::F32(0.0)
+                } else {
+                    let len = u16::try_from(piece.chars().count()).unwrap_or(u16::MAX);
+                    GgufValue::F32(f32::from(len))
+                }
+            })
+            .collect();
+        let token_types = vocab
+            .iter()
+            .enumerate()
+            .map(|(id, piece)| match id {
+                0 => GgufValue::I32(2),
+                1 | 2 => GgufValue::I32(3),
+                3..=258 => GgufValue::I32(6),
+                _ if self.special_tokens.contains(piece) => GgufValue::I32(3),
+                _ => GgufValue::I32(1),
+            })
+            .collect();
+        let n_vocab = vocab.len() as u64;
+        let fim_ids: Vec<_> = FIM_TOKENS
+            .iter()
+            .filter(|_| self.fim_tokens)
+            .filter_map(|&(token, key)| {
+                let id = vocab.iter().position(|piece| piece == token)?;
+                Some((key, GgufValue::U32(u32::try_from(id).ok()?)))
+            })
+            .collect();
+
+        let arch = self.architecture.name();
+        let mut gguf = GgufWriter::new()
+            .with_metadata("general.architecture", GgufValue::String(arch.into()))
+            .with_metadata("general.name", GgufValue::String("tiny".into()))
+            .with_metadata(
+                format!("{arch}.context_length"),
+                GgufValue::U32(self.n_ctx_train),
+            )
+            .with_metadata(
+                format!("{arch}.embedding_length"),
+                GgufValue::U32(self.n_embd),
+            )
+            .with_metadata(format!("{arch}.block_count"), GgufValue::U32(self.n_layer))
+            .with_metadata(
+                format!("{arch}.feed_forward_length"),
+                GgufValue::U32(self.n_ff),
+            )
+            .with_metadata(
+                format!("{arch}.attention.head_count"),
+                GgufValue::U32(self.n_head),
+            )
+            .with_metadata(
+                format!("{arch}.attention.head_count_kv"),
+                GgufValue::U32(self.n_head),
+            )
+            .with_metadata(
+                format!("{arch}.attention.layer_norm_rms_epsilon"),
+                GgufValue::F32(1e-5),
+            )
+            .with_metadata("tokenizer.ggml.model", GgufValue::String("llama".into()))
+            .with_metadata(
+                "tokenizer.ggml.tokens",
+                GgufValue::Array(vocab.into_iter().map(GgufValue::String).collect()),
+            )
+            .with_metadata("tokenizer.ggml.scores", GgufValue::Array(scores))
+            .with_metadata("tokenizer.ggml.token_type", GgufValue::Array(token_types))
+            .with_metadata("tokenizer.ggml.unknown_token_id", GgufValue::U32(0))
+            .with_metadata("tokenizer.ggml.bos_token_id", GgufValue::U32(1))
+            .with_metadata("tokenizer.ggml.eos_token_id", GgufValue::U32(2))
+            .with_metadata("tokenizer.ggml.add_bos_token", GgufValue::Bool(true));
+        for (key, id) in fim_ids {
+            gguf = gguf.with_metadata(key, id);
+        }
+        if self.architecture == TinyArchitecture::T5 {
+            gguf = gguf
+                .with_metadata(
+                    "t5.attention.relative_buckets_count",
+                    GgufValue::U32(T5_RELATIVE_BUCKETS),
+                )
+                .with_metadata("t5.decoder_start_token_id", GgufValue::U32(1));
+        }
+        if !with_weights {
+            return gguf;
+        }
+
+        let mut rng = SplitMix64(self.seed);
+        let mut tensor = |gguf: GgufWriter, name: String, dimensions: Vec<u64>| {
+            let n = dimensions.iter().product();
+            let data = if name.ends_with("norm.weight") {
+                (0..n).map(|_| 1.0).collect()
+            } else {
+                (0..n).map(|_| rng.next_weight()).collect()
+            };
+            gguf.with_f32_tensor(name, dimensions, data)
+        };
+        let (n_embd, n_ff) = (u64::from(self.n_embd), u64::from(self.n_ff));
+        gguf = tensor(gguf, "token_embd.weight".into(), vec![n_embd, n_vocab]);
+        let attention = |prefix: &str| {
+            ["q", "k", "v"]
+                .map(|name| (format!("{prefix}_{name}"), vec![n_embd, n_embd]))
+                .into_iter()
+        };
+        let feed_forward = [
+            ("ffn_norm".to_string(), vec![n_embd]),
+            ("ffn_gate".to_string(), vec![n_embd, n_ff]),
+            ("ffn_down".to_string(), vec![n_ff, n_embd]),
+            ("ffn_up".to_string(), vec![n_embd, n_ff]),
+        ];
+        match self.architecture {
+            TinyArchitecture::Llama => {
+                for i in 0..self.n_layer {
+                    let layer = std::iter::once(("attn_norm".to_string(), vec![n_embd]))
+                        .chain(attention("attn"))
+                        .chain([("attn_output".to_string(), vec![n_embd, n_embd])])
+                        .chain(feed_forward.clone());
+                    for (name, dimensions) in layer {
+                        gguf = tensor(gguf, format!("blk.{i}.{name}.weight"), dimensions);
+                    }
+                }
+                gguf = tensor(gguf, "output_norm.weight".into(), vec![n_embd]);
+            }
+            TinyArchitecture::T5 => {
+                let n_head = u64::from(self.n_head);
+                let buckets = u64::from(T5_RELATIVE_BUCKETS);
+                for stack in ["enc", "dec"] {
+                    for i in 0..self.n_layer {
+                        // like T5, only the first layer has a relative position bias
+                        let bias =
+                            (i == 0).then(|| ("attn_rel_b".to_string(), vec![n_head, buckets]));
+                        let mut layer: Vec<_> = bias
+                            .into_iter()
+                            .chain([("attn_norm".to_string(), vec![n_embd])])
+                            .chain(attention("attn"))
+                            .chain([("attn_o".to_string(), vec![n_embd, n_embd])])
+                            .collect();
+                        if stack == "dec" {
+                            layer.push(("cross_attn_norm".to_string(), vec![n_embd]));
+                            layer.extend(attention("cross_attn"));
+                            layer.push(("cross_attn_o".to_string(), vec![n_embd, n_embd]));
+                        }
+                        layer.extend(feed_forward.clone());
+                        for (name, dimensions) in layer {
+                            gguf =
+                                tensor(gguf, format!("{stack}.blk.{i}.{name}.weight"), dimensions);
+                        }
+                    }
+                    gguf = tensor(gguf, format!("{stack}.output_norm.weight"), vec![n_embd]);
+                }
+            }
+        }
+        tensor(gguf, "output.weight".into(), vec![n_embd, n_vocab])
+    }
+
+    /// Write the model with weights to `path`.
+    ///
+    /// # Errors
+    ///
+    /// If the file cannot be written.
+    pub fn write(&self, path: impl AsRef<Path>) -> std::io::Result<()> {
+        self.gguf(true).write_to_file(path)
+    }
+
+    /// Generate the model and load it with default params.
+    ///
+    /// # Errors
+    ///
+    /// See [`TinyModelError`].
+    pub fn load(&self, backend: &LlamaBackend) -> Result<LlamaModel, TinyModelError> {
+        load(backend, &self.gguf(true), &LlamaModelParams::default())
+    }
+
+    /// Generate only the vocabulary and load it, see [`LlamaModel::load_vocab_from_file`].
+    ///
+    /// # Errors
+    ///
+    /// See [`TinyModelError`].
+    pub fn load_vocab(&self, backend: &LlamaBackend) -> Result<LlamaModel, TinyModelError> {
+        let params = LlamaModelParams::default().with_vocab_only(true);
+        load(backend, &self.gguf(false), &params)
+    }
+}
+
+/// The backend of the tests of a process. llama.cpp can only be initialized once at a time, so
+/// tests that run in parallel share this one instead of calling [`LlamaBackend::init`].
+///
+/// # Panics
+///
+/// If the backend was already initialized with [`LlamaBackend::init`].
+pub fn backend() -> &'static LlamaBackend {
+    static BACKEND: OnceLock<LlamaBackend> = OnceLock::new();
+    BACKEND.get_or_init(|| LlamaBackend::init().expect("the backend is initialized elsewhere"))
+}
+
+/// Load `gguf` through a temporary file, which is removed again once loaded.
+fn load(
+    backend: &LlamaBackend,
+    gguf: &GgufWriter,
+    params: &LlamaModelParams,
+) -> Result<LlamaModel, TinyModelError> {
+    static N_FILES: AtomicUsize = AtomicUsize::new(0);
+    let path = std::env::temp_dir().join(format!(
+        "llama-cpp-2-tiny-{}-{}.gguf",
+        std::process::id(),
+        N_FILES.fetch_add(1, Ordering::Relaxed)
+    ));
+    gguf.write_to_file(&path)?;
+    let model = LlamaModel::load_from_file(backend, &path, params);
+    // on windows a mapped file cannot be removed, it is left in the temp dir then
+    let _ = std::fs::remove_file(&path);
+    Ok(model?)
+}
+
+/// A small deterministic random number generator for the weights.
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    /// A weight in `[-0.5, 0.5)`.
+    #[allow(clippy::cast_precision_loss)]
+    fn next_weight(&mut self) -> f32 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^= z >> 31;
+        (z >> 40) as f32 / (1 << 24) as f32 - 0.5
+    }
+}
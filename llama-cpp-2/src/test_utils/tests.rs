@@ -0,0 +1,111 @@
+use super::*;
+use crate::gguf::GgufFile;
+
+#[test]
+fn vocab_has_word_prefixes() {
+    let vocab = TinyModel::default().with_words(["hi"]).vocab();
+    assert_eq!(&vocab[..3], ["<unk>", "<s>", "</s>"]);
+    assert_eq!(vocab[3], "<0x00>");
+    assert_eq!(vocab[258], "<0xFF>");
+    assert_eq!(
+        &vocab[259..],
+        ["\u{2581}", "h", "i", "\u{2581}h", "\u{2581}hi"]
+    );
+}
+
+#[test]
+fn special_tokens_are_control_tokens() {
+    let model = TinyModel::default()
+        .with_words(["hi"])
+        .with_special_tokens(["<|im_start|>"]);
+    let vocab = model.vocab();
+    assert_eq!(vocab.last().map(String::as_str), Some("<|im_start|>"));
+    let bytes = model.gguf(false).to_bytes();
+    let gguf = GgufFile::read(bytes.as_slice()).unwrap();
+    let types = gguf
+        .get("tokenizer.ggml.token_type")
+        .and_then(GgufValue::as_array)
+        .unwrap();
+    assert_eq!(types.last(), Some(&GgufValue::I32(3)));
+    assert_eq!(types[vocab.len() - 2], GgufValue::I32(1));
+}
+
+#[test]
+fn gguf_describes_a_llama_model() {
+    let model = TinyModel::default().with_n_layer(2);
+    let bytes = model.gguf(true).to_bytes();
+    let gguf = GgufFile::read(bytes.as_slice()).unwrap();
+
+    assert_eq!(
+        gguf.get("general.architecture").and_then(GgufValue::as_str),
+        Some("llama")
+    );
+    let n_vocab = model.vocab().len();
+    let tokens = gguf
+        .get("tokenizer.ggml.tokens")
+        .and_then(GgufValue::as_array);
+    assert_eq!(tokens.map(<[_]>::len), Some(n_vocab));
+    assert_eq!(gguf.tensors().len(), 3 + 2 * 9);
+    let embd = gguf.tensor("token_embd.weight").unwrap();
+    assert_eq!(embd.dimensions, vec![16, n_vocab as u64]);
+    assert!(gguf.tensor("blk.1.ffn_down.weight").is_some());
+}
+
+#[test]
+fn vocab_only_gguf_has_no_tensors() {
+    let bytes = TinyModel::default().gguf(false).to_bytes();
+    let gguf = GgufFile::read(bytes.as_slice()).unwrap();
+    assert!(gguf.tensors().is_empty());
+    assert!(gguf.get("tokenizer.ggml.scores").is_some());
+}
+
+#[test]
+fn weights_are_deterministic() {
+    let model = TinyModel::default();
+    assert_eq!(model.gguf(true), model.gguf(true));
+    assert_ne!(model.gguf(true), model.clone().with_seed(7).gguf(true));
+}
+
+#[test]
+fn t5_gguf_has_encoder_and_decoder_stacks() {
+    let model = TinyModel::default()
+        .with_architecture(TinyArchitecture::T5)
+        .with_n_layer(2);
+    let bytes = model.gguf(true).to_bytes();
+    let gguf = GgufFile::read(bytes.as_slice()).unwrap();
+
+    assert_eq!(
+        gguf.get("general.architecture").and_then(GgufValue::as_str),
+        Some("t5")
+    );
+    assert!(gguf.get("t5.block_count").is_some());
+    assert!(gguf.get("llama.block_count").is_none());
+    // token_embd, output and an output norm per stack, the relative bias of the first layers,
+    // 9 tensors per encoder layer and 14 per decoder layer
+    assert_eq!(gguf.tensors().len(), 4 + 2 + 2 * (9 + 14));
+    let bias = gguf.tensor("enc.blk.0.attn_rel_b.weight").unwrap();
+    assert_eq!(bias.dimensions, vec![2, 32]);
+    assert!(gguf.tensor("enc.blk.1.attn_rel_b.weight").is_none());
+    assert!(gguf.tensor("dec.blk.1.cross_attn_o.weight").is_some());
+    assert!(gguf.tensor("enc.blk.1.cross_attn_o.weight").is_none());
+}
+
+#[test]
+fn fim_tokens_are_declared_in_the_metadata() {
+    let model = TinyModel::default().with_fim_tokens();
+    let vocab = model.vocab();
+    let bytes = model.gguf(false).to_bytes();
+    let gguf = GgufFile::read(bytes.as_slice()).unwrap();
+    let id = |key: &str| match gguf.get(key) {
+        Some(GgufValue::U32(id)) => vocab[*id as usize].as_str(),
+        other => panic!("{key} is {other:?}"),
+    };
+    assert_eq!(id("tokenizer.ggml.prefix_token_id"), "<PRE>");
+    assert_eq!(id("tokenizer.ggml.suffix_token_id"), "<SUF>");
+    assert_eq!(id("tokenizer.ggml.middle_token_id"), "<MID>");
+    assert_eq!(id("tokenizer.ggml.eot_token_id"), "<EOT>");
+
+    let bytes = TinyModel::default().gguf(false).to_bytes();
+    let gguf = GgufFile::read(bytes.as_slice()).unwrap();
+    assert!(gguf.get("tokenizer.ggml.prefix_token_id").is_none());
+}
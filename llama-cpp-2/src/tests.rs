@@ -0,0 +1,25 @@
+use std::error::Error;
+use std::num::NonZeroU32;
+
+use super::*;
+use crate::context::params::LlamaContextParams;
+use crate::llama_batch::LlamaBatch;
+use crate::test_utils::{self, TinyModel};
+
+#[test]
+fn a_failed_decode_reaches_the_caller_as_a_decode_error() {
+    let model = TinyModel::default().load(test_utils::backend()).unwrap();
+    let params = LlamaContextParams::default().with_n_ctx(NonZeroU32::new(64));
+    let mut ctx = model.new_context(test_utils::backend(), params).unwrap();
+    let mut decode = |batch: &mut LlamaBatch| -> Result<(), LlamaError> { Ok(ctx.decode(batch)?) };
+
+    let err = decode(&mut LlamaBatch::new(8, 1)).unwrap_err();
+    assert!(matches!(
+        err,
+        LlamaError::DecodeError(DecodeError::NTokensZero)
+    ));
+    let source = err
+        .source()
+        .and_then(|source| source.downcast_ref::<DecodeError>());
+    assert_eq!(source, Some(&DecodeError::NTokensZero));
+}
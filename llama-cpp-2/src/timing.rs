@@ -1,5 +1,24 @@
 //! Safe wrapper around `llama_timings`.
-use std::fmt::{Debug, Display, Formatter};
+//!
+//! llama.cpp's `llama_print_timings` writes to stderr. [`LlamaContext::timings`] returns the same
+//! numbers as a [`LlamaTimings`], which formats like it and can be written to any
+//! [`io::Write`] or [`fmt::Write`], or logged field by field through the application's own
+//! logging:
+//!
+//! ```
+//! # use llama_cpp_2::timing::LlamaTimings;
+//! let timings = LlamaTimings::new(0.0, 1500.0, 300.0, 4.0, 200.0, 1000.0, 50, 100, 50);
+//! let mut log = Vec::new();
+//! timings.write_to(&mut log)?;
+//! assert!(String::from_utf8(log).unwrap().starts_with("load time = 300.00 ms\n"));
+//! assert_eq!(timings.prompt_eval_tokens_per_second(), 500.0);
+//! assert_eq!(timings.eval_tokens_per_second(), 50.0);
+//! # Ok::<(), std::io::Error>(())
+//! ```
+//!
+//! [`LlamaContext::timings`]: crate::context::LlamaContext::timings
+use std::fmt::{self, Debug, Display, Formatter};
+use std::io;
 
 /// A wrapper around `llama_timings`.
 #[derive(Clone, Copy, Debug)]
@@ -101,6 +120,49 @@ impl LlamaTimings {
         self.timings.n_eval
     }
 
+    /// Get the time from the start to the end in milliseconds.
+    #[must_use]
+    pub fn t_total_ms(&self) -> f64 {
+        self.t_end_ms() - self.t_start_ms()
+    }
+
+    /// Get the number of samples per second. Not finite if nothing was sampled.
+    #[must_use]
+    pub fn sample_tokens_per_second(&self) -> f64 {
+        1e3 / self.t_sample_ms() * f64::from(self.n_sample())
+    }
+
+    /// Get the number of prompt tokens evaluated per second. Not finite if no prompt was
+    /// evaluated.
+    #[must_use]
+    pub fn prompt_eval_tokens_per_second(&self) -> f64 {
+        1e3 / self.t_p_eval_ms() * f64::from(self.n_p_eval())
+    }
+
+    /// Get the number of evaluations per second. Not finite if nothing was evaluated.
+    #[must_use]
+    pub fn eval_tokens_per_second(&self) -> f64 {
+        1e3 / self.t_eval_ms() * f64::from(self.n_eval())
+    }
+
+    /// Write the timings to `writer` as formatted by [`Display`], followed by a newline.
+    ///
+    /// # Errors
+    ///
+    /// If writing fails.
+    pub fn write_to(&self, mut writer: impl io::Write) -> io::Result<()> {
+        writeln!(writer, "{self}")
+    }
+
+    /// Format the timings into `writer` as [`Display`] does, followed by a newline.
+    ///
+    /// # Errors
+    ///
+    /// If writing fails.
+    pub fn format_to(&self, mut writer: impl fmt::Write) -> fmt::Result {
+        writeln!(writer, "{self}")
+    }
+
     /// Set the start time in milliseconds.
     pub fn set_t_start_ms(&mut self, t_start_ms: f64) {
         self.timings.t_start_ms = t_start_ms;
@@ -156,7 +218,7 @@ impl Display for LlamaTimings {
             self.t_sample_ms(),
             self.n_sample(),
             self.t_sample_ms() / f64::from(self.n_sample()),
-            1e3 / self.t_sample_ms() * f64::from(self.n_sample())
+            self.sample_tokens_per_second()
         )?;
         writeln!(
             f,
@@ -164,7 +226,7 @@ impl Display for LlamaTimings {
             self.t_p_eval_ms(),
             self.n_p_eval(),
             self.t_p_eval_ms() / f64::from(self.n_p_eval()),
-            1e3 / self.t_p_eval_ms() * f64::from(self.n_p_eval())
+            self.prompt_eval_tokens_per_second()
         )?;
         writeln!(
             f,
@@ -172,12 +234,8 @@ impl Display for LlamaTimings {
             self.t_eval_ms(),
             self.n_eval(),
             self.t_eval_ms() / f64::from(self.n_eval()),
-            1e3 / self.t_eval_ms() * f64::from(self.n_eval())
+            self.eval_tokens_per_second()
         )?;
-        write!(
-            f,
-            "total time = {:.2} ms",
-            self.t_end_ms() - self.t_start_ms()
-        )
+        write!(f, "total time = {:.2} ms", self.t_total_ms())
     }
 }
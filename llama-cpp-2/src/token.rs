@@ -9,6 +9,11 @@ pub mod data_array;
 /// A safe wrapper for `llama_token`.
 #[repr(transparent)]
 #[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(transparent)
+)]
 #[allow(clippy::module_name_repetitions)]
 pub struct LlamaToken(pub llama_cpp_sys_2::llama_token);
 
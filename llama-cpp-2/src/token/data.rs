@@ -6,12 +6,44 @@ use crate::token::LlamaToken;
 /// Do not rely on `repr(transparent)` for this type. It should be considered an implementation
 /// detail and may change across minor versions.
 #[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(from = "SerdeLlamaTokenData", into = "SerdeLlamaTokenData")
+)]
 #[repr(transparent)]
 #[allow(clippy::module_name_repetitions)]
 pub struct LlamaTokenData {
     data: llama_cpp_sys_2::llama_token_data,
 }
 
+/// The serialized form of [`LlamaTokenData`]: `{"id": 1, "logit": 0.5, "p": 0.1}`.
+#[cfg(feature = "serde")]
+#[derive(serde::Serialize, serde::Deserialize)]
+struct SerdeLlamaTokenData {
+    id: LlamaToken,
+    logit: f32,
+    p: f32,
+}
+
+#[cfg(feature = "serde")]
+impl From<SerdeLlamaTokenData> for LlamaTokenData {
+    fn from(SerdeLlamaTokenData { id, logit, p }: SerdeLlamaTokenData) -> Self {
+        Self::new(id, logit, p)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl From<LlamaTokenData> for SerdeLlamaTokenData {
+    fn from(data: LlamaTokenData) -> Self {
+        Self {
+            id: data.id(),
+            logit: data.logit(),
+            p: data.p(),
+        }
+    }
+}
+
 impl LlamaTokenData {
     /// Create a new token data from a token, logit, and probability.
     /// ```
@@ -8,10 +8,14 @@ use std::ptr;
 
 /// a safe wrapper around `llama_token_data_array`.
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 #[allow(clippy::module_name_repetitions)]
 pub struct LlamaTokenDataArray {
     /// the underlying data
     pub data: Vec<LlamaTokenData>,
+    /// the index of the token selected by the last call to a `sample_token*` function, if any
+    #[cfg_attr(feature = "serde", serde(default))]
+    selected: Option<usize>,
     /// is the data sorted?
     pub sorted: bool,
 }
@@ -32,7 +36,11 @@ impl LlamaTokenDataArray {
     /// ```
     #[must_use]
     pub fn new(data: Vec<LlamaTokenData>, sorted: bool) -> Self {
-        Self { data, sorted }
+        Self {
+            data,
+            selected: None,
+            sorted,
+        }
     }
 
     /// Create a new `LlamaTokenDataArray` from an iterator and weather or not the data is sorted.
@@ -52,6 +60,264 @@ impl LlamaTokenDataArray {
     {
         Self::new(data.into_iter().collect(), sorted)
     }
+
+    /// Create a new unsorted `LlamaTokenDataArray` from a row of logits, such as the one returned
+    /// by [`LlamaContext::get_logits_ith`]. The token id of every entry is its index.
+    ///
+    /// ```
+    /// # use llama_cpp_2::token::data_array::LlamaTokenDataArray;
+    /// # use llama_cpp_2::token::LlamaToken;
+    /// let array = LlamaTokenDataArray::from_logits(&[0.5, 2.0, -1.0]);
+    /// assert_eq!(array.data[1].id(), LlamaToken::new(1));
+    /// assert_eq!(array.data[1].logit(), 2.0);
+    /// assert!(!array.sorted);
+    /// ```
+    #[must_use]
+    pub fn from_logits(logits: &[f32]) -> Self {
+        Self::from_iter(
+            (0..)
+                .zip(logits)
+                .map(|(id, logit)| LlamaTokenData::new(LlamaToken::new(id), *logit, 0.0)),
+            false,
+        )
+    }
+
+    /// The index in [`Self::data`] of the token selected by the last call to a `sample_token*`
+    /// function, if any. Changing the candidates resets it.
+    #[must_use]
+    pub fn selected(&self) -> Option<usize> {
+        self.selected
+    }
+
+    /// The token selected by the last call to a `sample_token*` function, if any.
+    #[must_use]
+    pub fn selected_token(&self) -> Option<LlamaToken> {
+        self.selected
+            .and_then(|i| self.data.get(i))
+            .map(LlamaTokenData::id)
+    }
+
+    /// Sort the candidates by logit in descending order.
+    ///
+    /// ```
+    /// # use llama_cpp_2::token::data_array::LlamaTokenDataArray;
+    /// # use llama_cpp_2::token::LlamaToken;
+    /// let mut array = LlamaTokenDataArray::from_logits(&[0.5, 2.0, -1.0]);
+    /// array.sort_by_logit();
+    /// let ids = array.data.iter().map(|d| d.id().0).collect::<Vec<_>>();
+    /// assert_eq!(ids, vec![1, 0, 2]);
+    /// assert!(array.sorted);
+    /// ```
+    pub fn sort_by_logit(&mut self) {
+        if !self.sorted {
+            self.data.sort_by(|a, b| b.logit().total_cmp(&a.logit()));
+            self.sorted = true;
+            self.selected = None;
+        }
+    }
+
+    /// Sort the candidates by logit and set their probabilities to the softmax of the logits. This
+    /// is the same as [`Self::sample_softmax`] without going through llama.cpp. If every logit is
+    /// `-inf` no candidate is possible and all probabilities are `0`.
+    ///
+    /// ```
+    /// # use llama_cpp_2::token::data_array::LlamaTokenDataArray;
+    /// let mut array = LlamaTokenDataArray::from_logits(&[0.0, 0.0, f32::NEG_INFINITY]);
+    /// array.softmax();
+    /// assert_eq!(array.data.iter().map(|d| d.p()).collect::<Vec<_>>(), vec![0.5, 0.5, 0.0]);
+    /// ```
+    pub fn softmax(&mut self) {
+        self.sort_by_logit();
+        let Some(max) = self.data.first().map(LlamaTokenData::logit) else {
+            return;
+        };
+        if max.is_infinite() && max.is_sign_negative() {
+            // -inf - -inf is NaN
+            for data in &mut self.data {
+                data.set_p(0.0);
+            }
+            return;
+        }
+        let mut sum = 0.0;
+        for data in &mut self.data {
+            let p = (data.logit() - max).exp();
+            data.set_p(p);
+            sum += p;
+        }
+        for data in &mut self.data {
+            data.set_p(data.p() / sum);
+        }
+    }
+
+    /// The `n` candidates with the highest logits, highest first. Unlike
+    /// [`Self::sample_top_k`] this leaves the array unchanged.
+    ///
+    /// ```
+    /// # use llama_cpp_2::token::data_array::LlamaTokenDataArray;
+    /// let array = LlamaTokenDataArray::from_logits(&[0.5, 2.0, -1.0, 1.0]);
+    /// let top = array.top_n(2);
+    /// assert_eq!(top.iter().map(|d| d.id().0).collect::<Vec<_>>(), vec![1, 3]);
+    /// ```
+    #[must_use]
+    pub fn top_n(&self, n: usize) -> Vec<LlamaTokenData> {
+        if self.sorted {
+            return self.data.iter().take(n).copied().collect();
+        }
+        let mut data = self.data.clone();
+        let by_logit = |a: &LlamaTokenData, b: &LlamaTokenData| b.logit().total_cmp(&a.logit());
+        if n < data.len() {
+            data.select_nth_unstable_by(n, by_logit);
+            data.truncate(n);
+        }
+        data.sort_by(by_logit);
+        data
+    }
+
+    /// Divide the logits by `temperature`, the Rust counterpart of [`Self::sample_temp`]. A
+    /// temperature of `0` or less keeps only the most likely candidate, like greedy sampling.
+    /// The probabilities are not updated, call [`Self::softmax`] afterwards.
+    ///
+    /// ```
+    /// # use llama_cpp_2::token::data_array::LlamaTokenDataArray;
+    /// let mut array = LlamaTokenDataArray::from_logits(&[1.0, 2.0, -4.0]);
+    /// array.apply_temperature(2.0);
+    /// assert_eq!(array.data.iter().map(|d| d.logit()).collect::<Vec<_>>(), vec![0.5, 1.0, -2.0]);
+    ///
+    /// array.apply_temperature(0.0);
+    /// assert_eq!(array.data.len(), 1);
+    /// assert_eq!(array.data[0].id().0, 1);
+    /// ```
+    pub fn apply_temperature(&mut self, temperature: f32) {
+        self.selected = None;
+        if temperature <= 0.0 {
+            self.keep_top_k(1, 1);
+            return;
+        }
+        for data in &mut self.data {
+            data.set_logit(data.logit() / temperature);
+        }
+    }
+
+    /// Keep the `k` candidates with the highest logits, but at least `min_keep`. `k` of `0`
+    /// keeps all of them. The Rust counterpart of [`Self::sample_top_k`], the candidates are
+    /// sorted afterwards.
+    ///
+    /// ```
+    /// # use llama_cpp_2::token::data_array::LlamaTokenDataArray;
+    /// let mut array = LlamaTokenDataArray::from_logits(&[0.5, 2.0, -1.0, 1.0]);
+    /// array.keep_top_k(2, 1);
+    /// assert_eq!(array.data.iter().map(|d| d.id().0).collect::<Vec<_>>(), vec![1, 3]);
+    /// ```
+    pub fn keep_top_k(&mut self, k: usize, min_keep: usize) {
+        let k = if k == 0 {
+            self.data.len()
+        } else {
+            k.max(min_keep)
+        };
+        if k < self.data.len() {
+            self.data = self.top_n(k);
+        }
+        self.sort_by_logit();
+        self.selected = None;
+    }
+
+    /// Keep the most likely candidates up to a cumulative probability of `p`, but at least
+    /// `min_keep`. The Rust counterpart of [`Self::sample_top_p`], the candidates are sorted and
+    /// their probabilities are the softmax of the logits before truncation.
+    ///
+    /// ```
+    /// # use llama_cpp_2::token::data_array::LlamaTokenDataArray;
+    /// let mut array = LlamaTokenDataArray::from_logits(&[0.1, 0.2, 0.7]);
+    /// array.keep_top_p(0.5, 1);
+    /// assert_eq!(array.data.iter().map(|d| d.id().0).collect::<Vec<_>>(), vec![2, 1]);
+    /// ```
+    pub fn keep_top_p(&mut self, p: f32, min_keep: usize) {
+        if p >= 1.0 {
+            return;
+        }
+        self.softmax();
+        let mut cumulative = 0.0;
+        let mut n_keep = self.data.len();
+        for (i, data) in self.data.iter().enumerate() {
+            cumulative += data.p();
+            if cumulative >= p && i + 1 >= min_keep {
+                n_keep = i + 1;
+                break;
+            }
+        }
+        self.data.truncate(n_keep);
+        self.selected = None;
+    }
+
+    /// Drop the candidates whose probability is less than `p` times the probability of the most
+    /// likely one, but keep at least `min_keep`. The Rust counterpart of [`Self::sample_min_p`],
+    /// the candidates are sorted afterwards.
+    ///
+    /// ```
+    /// # use llama_cpp_2::token::data_array::LlamaTokenDataArray;
+    /// // probabilities relative to the first: 1, e^-1, e^-4
+    /// let mut array = LlamaTokenDataArray::from_logits(&[4.0, 3.0, 0.0]);
+    /// array.keep_min_p(0.1, 1);
+    /// assert_eq!(array.data.iter().map(|d| d.id().0).collect::<Vec<_>>(), vec![0, 1]);
+    /// ```
+    pub fn keep_min_p(&mut self, p: f32, min_keep: usize) {
+        if p <= 0.0 {
+            return;
+        }
+        self.sort_by_logit();
+        let Some(max) = self.data.first().map(LlamaTokenData::logit) else {
+            return;
+        };
+        // p_i >= p * p_max is logit_i >= logit_max + ln(p), without needing the softmax
+        let min_logit = max + p.ln();
+        let n_keep = self
+            .data
+            .iter()
+            .position(|data| data.logit() < min_logit)
+            .unwrap_or(self.data.len())
+            .max(min_keep);
+        self.data.truncate(n_keep);
+        self.selected = None;
+    }
+
+    /// Select the candidate at `u` of the cumulative probabilities of the candidates in their
+    /// current order and return it, the Rust counterpart of [`Self::sample_token`] with the
+    /// random number drawn by the caller. `u` is clamped to `[0, 1)`, the probabilities are
+    /// normalized first if they do not sum to one. Returns `None` if there are no candidates.
+    ///
+    /// ```
+    /// # use llama_cpp_2::token::data_array::LlamaTokenDataArray;
+    /// # use llama_cpp_2::token::LlamaToken;
+    /// let mut array = LlamaTokenDataArray::from_logits(&[0.0, 0.0, f32::NEG_INFINITY]);
+    /// array.softmax();
+    /// assert_eq!(array.select_by_probability(0.25), Some(array.data[0].id()));
+    /// assert_eq!(array.select_by_probability(0.75), Some(array.data[1].id()));
+    /// assert_eq!(array.selected(), Some(1));
+    /// ```
+    pub fn select_by_probability(&mut self, u: f32) -> Option<LlamaToken> {
+        let sum: f32 = self.data.iter().map(LlamaTokenData::p).sum();
+        if self.data.is_empty() || sum <= 0.0 {
+            self.selected = None;
+            return None;
+        }
+        let target = u.clamp(0.0, 1.0) * sum;
+        let mut cumulative = 0.0;
+        // rounding can leave the target at or just above the total, select the last possible one
+        let mut index = self
+            .data
+            .iter()
+            .rposition(|data| data.p() > 0.0)
+            .unwrap_or(self.data.len() - 1);
+        for (i, data) in self.data.iter().enumerate() {
+            cumulative += data.p();
+            if target < cumulative {
+                index = i;
+                break;
+            }
+        }
+        self.selected = Some(index);
+        Some(self.data[index].id())
+    }
 }
 
 impl LlamaTokenDataArray {
@@ -77,6 +343,7 @@ impl LlamaTokenDataArray {
             sorted: self.sorted,
         };
         let result = modify(&mut c_llama_token_data_array);
+        self.selected = None;
         assert!(
             ptr::eq(data, c_llama_token_data_array.data),
             "data pointer changed"
@@ -229,13 +496,23 @@ impl LlamaTokenDataArray {
     }
 
     /// Randomly selects a token from the candidates based on their probabilities.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "trace", skip_all, fields(n_candidates = self.data.len()))
+    )]
     pub fn sample_token(&mut self, ctx: &mut LlamaContext) -> LlamaToken {
         let llama_token = unsafe {
             self.modify_as_c_llama_token_data_array(|c_llama_token_data_array| {
                 llama_cpp_sys_2::llama_sample_token(ctx.context.as_ptr(), c_llama_token_data_array)
             })
         };
-        LlamaToken(llama_token)
+        self.select(LlamaToken(llama_token))
+    }
+
+    /// Record `token` as the selected one and return it.
+    fn select(&mut self, token: LlamaToken) -> LlamaToken {
+        self.selected = self.data.iter().position(|data| data.id() == token);
+        token
     }
 
     /// Top-K sampling described in academic paper [The Curious Case of Neural Text Degeneration](https://arxiv.org/abs/1904.09751)
@@ -372,6 +649,9 @@ impl LlamaTokenDataArray {
             })
         };
         *mu = unsafe { *mu_ptr };
-        LlamaToken(token)
+        self.select(LlamaToken(token))
     }
 }
+
+#[cfg(test)]
+mod tests;
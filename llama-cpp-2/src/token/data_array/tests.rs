@@ -0,0 +1,24 @@
+use super::*;
+
+#[test]
+fn softmax_of_only_masked_logits_is_zero() {
+    let mut array = LlamaTokenDataArray::from_logits(&[f32::NEG_INFINITY; 3]);
+    array.softmax();
+    assert_eq!(
+        array.data.iter().map(LlamaTokenData::p).collect::<Vec<_>>(),
+        vec![0.0; 3]
+    );
+    assert_eq!(array.select_by_probability(0.5), None);
+    assert_eq!(array.selected(), None);
+}
+
+#[test]
+fn softmax_ignores_masked_logits() {
+    let mut array = LlamaTokenDataArray::from_logits(&[f32::NEG_INFINITY, 1.0, f32::NEG_INFINITY]);
+    array.softmax();
+    assert_eq!(array.data[0].id(), LlamaToken::new(1));
+    assert_eq!(
+        array.data.iter().map(LlamaTokenData::p).collect::<Vec<_>>(),
+        vec![1.0, 0.0, 0.0]
+    );
+}
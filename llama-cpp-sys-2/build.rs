@@ -142,6 +142,339 @@ fn macos_link_search_path() -> Option<String> {
     None
 }
 
+/// Check that the headers of a prebuilt llama.cpp match the vendored ones the safe wrapper is
+/// written against. A mismatch means the library has a different API or ABI, which
+/// `LLAMA_PREBUILT_SKIP_VERSION_CHECK=1` downgrades to a warning.
+fn check_prebuilt_headers(prebuilt_include: &Path, llama_src: &Path) {
+    let vendored = [
+        llama_src.join("include/llama.h"),
+        llama_src.join("ggml/include/ggml.h"),
+    ];
+    let skip = env::var("LLAMA_PREBUILT_SKIP_VERSION_CHECK")
+        .map(|v| v == "1")
+        .unwrap_or(false);
+
+    for vendored in vendored {
+        let name = vendored.file_name().unwrap();
+        let prebuilt = prebuilt_include.join(name);
+        let prebuilt_header = std::fs::read_to_string(&prebuilt)
+            .unwrap_or_else(|e| panic!("Failed to read {}: {}", prebuilt.display(), e));
+        let Ok(vendored_header) = std::fs::read_to_string(&vendored) else {
+            println!(
+                "cargo:warning=llama.cpp submodule not checked out, cannot check {} of the prebuilt library",
+                name.to_string_lossy()
+            );
+            continue;
+        };
+        if prebuilt_header != vendored_header {
+            let message = format!(
+                "{} does not match the vendored {}: the prebuilt llama.cpp is a different version than the one llama-cpp-sys-2 was written against",
+                prebuilt.display(),
+                vendored.display()
+            );
+            if skip {
+                println!("cargo:warning={}", message);
+            } else {
+                panic!("{} (set LLAMA_PREBUILT_SKIP_VERSION_CHECK=1 to link it anyway)", message);
+            }
+        }
+    }
+}
+
+/// Whether `lib` (without `lib` prefix and extension) is one of the libraries of llama.cpp.
+fn is_llama_lib(lib: &str) -> bool {
+    lib == "llama" || lib.starts_with("ggml")
+}
+
+/// Whether `lib_dir` contains llama as a shared library.
+fn has_shared_llama(lib_dir: &Path) -> bool {
+    ["libllama.so", "libllama.dylib", "llama.dll"]
+        .iter()
+        .any(|name| lib_dir.join(name).exists())
+}
+
+/// Whether the target is WebAssembly. Only emscripten is supported, it provides the libc,
+/// libc++ and (with the `atomics` target feature) the pthreads ggml needs.
+fn is_wasm(target: &str) -> bool {
+    target.starts_with("wasm32")
+}
+
+/// Check that the target can be built for at all, before anything is compiled.
+fn check_wasm_target(target: &str) {
+    if target != "wasm32-unknown-emscripten" {
+        panic!("{target} is not supported, build for wasm32-unknown-emscripten instead");
+    }
+    for backend in ["cuda", "metal", "vulkan"] {
+        if env::var(format!("CARGO_FEATURE_{}", backend.to_uppercase())).is_ok() {
+            panic!("the {backend} feature is not available on {target}");
+        }
+    }
+}
+
+/// Whether the target is compiled with the target feature `feature` (e.g. `simd128`).
+fn has_target_feature(feature: &str) -> bool {
+    env::var("CARGO_CFG_TARGET_FEATURE")
+        .is_ok_and(|features| features.split(',').any(|enabled| enabled == feature))
+}
+
+/// The emscripten cmake toolchain and sysroot of the `EMSDK` the environment was set up with.
+fn emsdk_paths() -> Option<(PathBuf, PathBuf)> {
+    let emscripten = PathBuf::from(env::var("EMSDK").ok()?).join("upstream/emscripten");
+    Some((
+        emscripten.join("cmake/Modules/Platform/Emscripten.cmake"),
+        emscripten.join("cache/sysroot"),
+    ))
+}
+
+/// Configure ggml for emscripten: a static build on the CPU only, with wasm SIMD and threads when
+/// the Rust side is compiled with them (`-C target-feature=+simd128,+atomics`). Without
+/// `atomics` ggml runs single-threaded; set the thread counts of the context to 1.
+fn configure_wasm(config: &mut Config) {
+    if env::var("CMAKE_TOOLCHAIN_FILE").is_err() {
+        let (toolchain, _) = emsdk_paths()
+            .expect("set EMSDK (source emsdk_env.sh) or CMAKE_TOOLCHAIN_FILE to build for wasm");
+        config.define("CMAKE_TOOLCHAIN_FILE", toolchain);
+    }
+    config
+        .define("BUILD_SHARED_LIBS", "OFF")
+        .define("GGML_NATIVE", "OFF")
+        .define("GGML_OPENMP", "OFF")
+        .define("GGML_BLAS", "OFF")
+        .define("GGML_METAL", "OFF")
+        .define("GGML_CUDA", "OFF")
+        .define("GGML_VULKAN", "OFF")
+        .define("LLAMA_BUILD_TESTS", "OFF")
+        .define("LLAMA_BUILD_EXAMPLES", "OFF")
+        .define("LLAMA_BUILD_SERVER", "OFF");
+    if has_target_feature("simd128") {
+        config.cflag("-msimd128").cxxflag("-msimd128");
+    }
+    if has_target_feature("atomics") {
+        config.cflag("-pthread").cxxflag("-pthread");
+    }
+}
+
+/// The root of the Android NDK, from the variables the NDK tools and gradle set.
+fn android_ndk() -> Option<PathBuf> {
+    ["ANDROID_NDK_HOME", "ANDROID_NDK_ROOT", "ANDROID_NDK", "NDK_HOME"]
+        .iter()
+        .find_map(|var| env::var(var).ok())
+        .map(PathBuf::from)
+}
+
+/// The Android ABI name of a Rust target.
+fn android_abi(target: &str) -> &'static str {
+    match target.split('-').next() {
+        Some("aarch64") => "arm64-v8a",
+        Some("armv7" | "arm") => "armeabi-v7a",
+        Some("x86_64") => "x86_64",
+        Some("i686") => "x86",
+        _ => panic!("{target} is not an Android target the NDK supports"),
+    }
+}
+
+/// Configure a cross build with the NDK's cmake toolchain. The libraries are aligned to 16 KB
+/// pages (`LLAMA_ANDROID_PAGE_SIZE`), which devices with 16 KB pages require and 4 KB devices
+/// accept, OpenMP is left out (the NDK's libomp is not linked into apps by default) and the C++
+/// runtime is `libc++_shared`, like gradle's default.
+fn configure_android(config: &mut Config, target: &str) {
+    if env::var("CMAKE_TOOLCHAIN_FILE").is_err() {
+        let ndk = android_ndk().expect(
+            "set ANDROID_NDK_HOME (or ANDROID_NDK_ROOT) or CMAKE_TOOLCHAIN_FILE to build for android",
+        );
+        config.define(
+            "CMAKE_TOOLCHAIN_FILE",
+            ndk.join("build/cmake/android.toolchain.cmake"),
+        );
+    }
+    let platform = env::var("ANDROID_PLATFORM").unwrap_or_else(|_| "android-28".to_string());
+    let page_size = env::var("LLAMA_ANDROID_PAGE_SIZE").unwrap_or_else(|_| "16384".to_string());
+    config
+        .define("ANDROID_ABI", android_abi(target))
+        .define("ANDROID_PLATFORM", platform)
+        .define("ANDROID_STL", "c++_shared")
+        .define("GGML_NATIVE", "OFF")
+        .define("GGML_OPENMP", "OFF")
+        .define(
+            "CMAKE_SHARED_LINKER_FLAGS",
+            format!("-Wl,-z,max-page-size={page_size}"),
+        );
+}
+
+/// The Apple platforms with their own SDK, from the target triple.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ApplePlatform {
+    MacOs,
+    Ios,
+    IosSimulator,
+    /// iOS apps running on macOS (`*-apple-ios-macabi`).
+    MacCatalyst,
+}
+
+impl ApplePlatform {
+    fn from_target(target: &str) -> Option<Self> {
+        if target.ends_with("-apple-darwin") {
+            Some(Self::MacOs)
+        } else if target.ends_with("-apple-ios-macabi") {
+            Some(Self::MacCatalyst)
+        } else if target.ends_with("-apple-ios-sim") || target == "x86_64-apple-ios" {
+            Some(Self::IosSimulator)
+        } else if target.ends_with("-apple-ios") {
+            Some(Self::Ios)
+        } else {
+            None
+        }
+    }
+
+    /// The name of the SDK for `xcrun --sdk` and `CMAKE_OSX_SYSROOT`.
+    fn sdk(self) -> &'static str {
+        match self {
+            Self::MacOs | Self::MacCatalyst => "macosx",
+            Self::Ios => "iphoneos",
+            Self::IosSimulator => "iphonesimulator",
+        }
+    }
+}
+
+/// The path of an Xcode SDK.
+fn apple_sdk_path(sdk: &str) -> Option<String> {
+    let output = Command::new("xcrun")
+        .args(["--sdk", sdk, "--show-sdk-path"])
+        .output()
+        .ok()?;
+    output
+        .status
+        .success()
+        .then(|| String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+/// The minimum iOS version, `IPHONEOS_DEPLOYMENT_TARGET` like rustc reads it.
+fn ios_deployment_target() -> String {
+    env::var("IPHONEOS_DEPLOYMENT_TARGET").unwrap_or_else(|_| "14.0".to_string())
+}
+
+/// Configure the SDK, architecture and Metal of an Apple target. The Metal shaders are embedded
+/// into the library, so apps and static binaries need no `default.metallib` or
+/// `ggml-metal.metal` next to them. Metal is always on for iOS and Mac Catalyst (every device
+/// since the A7 has it, and the simulator runs on the Mac's GPU) and keeps ggml's default on
+/// macOS. Apple's clang has no OpenMP, so it is off outside of macOS.
+fn configure_apple(config: &mut Config, target: &str, platform: ApplePlatform) {
+    let arch = if target.starts_with("x86_64") {
+        "x86_64"
+    } else {
+        "arm64"
+    };
+    config
+        .define("CMAKE_OSX_ARCHITECTURES", arch)
+        .define("GGML_BLAS", "OFF")
+        .define("GGML_METAL_EMBED_LIBRARY", "ON");
+    match platform {
+        ApplePlatform::MacOs => {}
+        ApplePlatform::Ios | ApplePlatform::IosSimulator => {
+            config
+                .define("CMAKE_SYSTEM_NAME", "iOS")
+                .define("CMAKE_OSX_SYSROOT", platform.sdk())
+                .define("CMAKE_OSX_DEPLOYMENT_TARGET", ios_deployment_target())
+                .define("GGML_METAL", "ON")
+                .define("GGML_OPENMP", "OFF");
+        }
+        ApplePlatform::MacCatalyst => {
+            // cmake has no system name for catalyst, the macOS SDK with the macabi triple is it
+            let triple = format!("--target={arch}-apple-ios{}-macabi", ios_deployment_target());
+            config
+                .define("CMAKE_OSX_SYSROOT", platform.sdk())
+                .define("GGML_METAL", "ON")
+                .define("GGML_OPENMP", "OFF")
+                .cflag(&triple)
+                .cxxflag(&triple);
+        }
+    }
+}
+
+/// The tuning options of the CUDA backend, each behind a `cuda-*` feature.
+const CUDA_OPTIONS: [(&str, &str); 6] = [
+    ("cuda-force-mmq", "GGML_CUDA_FORCE_MMQ"),
+    ("cuda-force-cublas", "GGML_CUDA_FORCE_CUBLAS"),
+    ("cuda-f16", "GGML_CUDA_F16"),
+    ("cuda-no-vmm", "GGML_CUDA_NO_VMM"),
+    ("cuda-no-peer-copy", "GGML_CUDA_NO_PEER_COPY"),
+    ("cuda-fa-all-quants", "GGML_CUDA_FA_ALL_QUANTS"),
+];
+
+/// Turn on the CUDA options of the enabled `cuda-*` features and compile for the GPU
+/// architectures in `LLAMA_CUDA_ARCHITECTURES` (e.g. `86;89`, default: the ones ggml picks).
+/// Fewer architectures build much faster, and `native` builds for the GPUs of the build host.
+fn configure_cuda(config: &mut Config) {
+    for (feature, option) in CUDA_OPTIONS {
+        let var = format!("CARGO_FEATURE_{}", feature.to_uppercase().replace('-', "_"));
+        if env::var(var).is_ok() {
+            config.define(option, "ON");
+        }
+    }
+    if let Ok(architectures) = env::var("LLAMA_CUDA_ARCHITECTURES") {
+        config.define("CMAKE_CUDA_ARCHITECTURES", architectures);
+    }
+}
+
+fn build_with_cmake(
+    llama_dst: &Path,
+    target: &str,
+    build_shared_libs: bool,
+    profile: &str,
+    static_crt: bool,
+) {
+    let mut config = Config::new(llama_dst);
+    let android = target.contains("android") && cfg!(feature = "android");
+    let apple = ApplePlatform::from_target(target);
+    // the android and non-macOS Apple configurations turn OpenMP off themselves
+    let openmp = cfg!(feature = "openmp")
+        && !android
+        && apple.is_none_or(|platform| platform == ApplePlatform::MacOs);
+
+    if is_wasm(target) {
+        configure_wasm(&mut config);
+    } else {
+        config.define(
+            "BUILD_SHARED_LIBS",
+            if build_shared_libs { "ON" } else { "OFF" },
+        );
+
+        if cfg!(windows) {
+            config.static_crt(static_crt);
+        }
+
+        if cfg!(feature = "vulkan") {
+            config.define("GGML_VULKAN", "ON");
+        }
+
+        if cfg!(feature = "cuda") {
+            config.define("GGML_CUDA", "ON");
+            configure_cuda(&mut config);
+        }
+
+        if openmp {
+            config.define("GGML_OPENMP", "ON");
+        }
+
+        if android {
+            configure_android(&mut config, target);
+        }
+
+        if let Some(platform) = apple {
+            configure_apple(&mut config, target, platform);
+        }
+    }
+
+    // General
+    config
+        .profile(profile)
+        .very_verbose(std::env::var("CMAKE_VERBOSE").is_ok()) // Not verbose by default
+        .always_configure(false);
+
+    let build_dir = config.build();
+    println!("cargo:rustc-link-search={}", build_dir.display());
+}
+
 fn main() {
 
     let target = env::var("TARGET").unwrap();
@@ -152,10 +485,23 @@ fn main() {
     let manifest_dir = env::var("CARGO_MANIFEST_DIR").expect("Failed to get CARGO_MANIFEST_DIR");
     let llama_src = Path::new(&manifest_dir).join("llama.cpp");
     let build_shared_libs = cfg!(feature = "cuda") || cfg!(feature = "dynamic-link");
+    let wasm = is_wasm(&target);
+    if wasm {
+        check_wasm_target(&target);
+    }
+
+    // Link a prebuilt or system installed llama.cpp (the install prefix, with `include` and
+    // `lib` directories) instead of compiling it.
+    let prebuilt_dir = env::var("LLAMA_PREBUILT_DIR").ok().map(PathBuf::from);
+    let build_shared_libs = prebuilt_dir
+        .as_ref()
+        .map_or(build_shared_libs, |dir| has_shared_llama(&dir.join("lib")));
 
     let build_shared_libs = std::env::var("LLAMA_BUILD_SHARED_LIBS")
         .map(|v| v == "1")
         .unwrap_or(build_shared_libs);
+    // emscripten links everything into one module
+    let build_shared_libs = build_shared_libs && !wasm;
     let profile = env::var("LLAMA_LIB_PROFILE").unwrap_or("Release".to_string());
     let static_crt = env::var("LLAMA_STATIC_CRT")
         .map(|v| v == "1")
@@ -166,9 +512,26 @@ fn main() {
     debug_log!("TARGET_DIR: {}", target_dir.display());
     debug_log!("OUT_DIR: {}", out_dir.display());
     debug_log!("BUILD_SHARED: {}", build_shared_libs);
+    debug_log!("PREBUILT_DIR: {:?}", prebuilt_dir);
+    println!("cargo:rerun-if-env-changed=LLAMA_PREBUILT_DIR");
+    println!("cargo:rerun-if-env-changed=LLAMA_PREBUILT_SKIP_VERSION_CHECK");
+    println!("cargo:rerun-if-env-changed=ANDROID_PLATFORM");
+    println!("cargo:rerun-if-env-changed=LLAMA_ANDROID_PAGE_SIZE");
+    println!("cargo:rerun-if-env-changed=IPHONEOS_DEPLOYMENT_TARGET");
+    println!("cargo:rerun-if-env-changed=LLAMA_CUDA_ARCHITECTURES");
+
+    let android = target.contains("android");
+    if android && !cfg!(feature = "android") {
+        println!("cargo:warning=building for {target} without the android feature, enable it to configure the NDK toolchain");
+    }
+    let android = android && cfg!(feature = "android");
+
+    if let Some(prebuilt_dir) = &prebuilt_dir {
+        check_prebuilt_headers(&prebuilt_dir.join("include"), &llama_src);
+    }
 
     // Prepare sherpa-onnx source
-    if !llama_dst.exists() {
+    if prebuilt_dir.is_none() && !llama_dst.exists() {
         debug_log!("Copy {} to {}", llama_src.display(), llama_dst.display());
         copy_folder(&llama_src, &llama_dst);
     }
@@ -182,16 +545,60 @@ fn main() {
     );
 
     // Bindings
-    let bindings = bindgen::Builder::default()
-        .header("wrapper.h")
-        .clang_arg(format!("-I{}", llama_dst.join("include").display()))
-        .clang_arg(format!("-I{}", llama_dst.join("ggml/include").display()))
+    let bindings = match &prebuilt_dir {
+        Some(prebuilt_dir) => bindgen::Builder::default()
+            .header(prebuilt_dir.join("include/llama.h").to_string_lossy())
+            .clang_arg(format!("-I{}", prebuilt_dir.join("include").display())),
+        None => bindgen::Builder::default()
+            .header("wrapper.h")
+            .clang_arg(format!("-I{}", llama_dst.join("include").display()))
+            .clang_arg(format!("-I{}", llama_dst.join("ggml/include").display())),
+    };
+    // clang finds the macOS SDK by itself, not the iOS ones
+    let bindings = match ApplePlatform::from_target(&target)
+        .filter(|platform| *platform != ApplePlatform::MacOs)
+        .and_then(|platform| apple_sdk_path(platform.sdk()))
+    {
+        Some(sdk) => bindings.clang_args(["-isysroot", &sdk]),
+        None => bindings,
+    };
+    // bindgen targets the wasm triple on its own, but clang needs emscripten's libc headers
+    let bindings = match emsdk_paths().filter(|_| wasm) {
+        Some((_, sysroot)) => bindings.clang_arg(format!("--sysroot={}", sysroot.display())),
+        None => bindings,
+    };
+    // ggml.h and ggml-backend.h come in through llama.h, the allocator only with the ggml feature
+    let ggml_include = match &prebuilt_dir {
+        Some(prebuilt_dir) => prebuilt_dir.join("include"),
+        None => llama_dst.join("ggml/include"),
+    };
+    let bindings = if cfg!(feature = "ggml") {
+        bindings.header(ggml_include.join("ggml-alloc.h").to_string_lossy())
+    } else {
+        bindings
+    };
+    // the device count and memory queries of the GPU backends
+    let bindings = if cfg!(feature = "cuda") {
+        bindings.header(ggml_include.join("ggml-cuda.h").to_string_lossy())
+    } else {
+        bindings
+    };
+    let bindings = if cfg!(feature = "vulkan") {
+        bindings.header(ggml_include.join("ggml-vulkan.h").to_string_lossy())
+    } else {
+        bindings
+    };
+    let bindings = bindings
+        .header("shim/quantize.h")
         .parse_callbacks(Box::new(bindgen::CargoCallbacks::new()))
         .derive_partialeq(true)
         .allowlist_function("ggml_.*")
         .allowlist_type("ggml_.*")
         .allowlist_function("llama_.*")
         .allowlist_type("llama_.*")
+        // the GGUF reader and writer, declared in ggml.h
+        .allowlist_function("gguf_.*")
+        .allowlist_type("gguf_.*")
         .prepend_enum_name(false)
         .generate()
         .expect("Failed to generate bindings");
@@ -204,31 +611,53 @@ fn main() {
         .expect("Failed to write bindings");
 
     println!("cargo:rerun-if-changed=wrapper.h");
+    println!("cargo:rerun-if-changed=shim");
     println!("cargo:rerun-if-changed=./sherpa-onnx");
 
     debug_log!("Bindings Created");
 
-    // Build with Cmake
+    // Build with Cmake, unless a prebuilt library is linked
+    let lib_root = match &prebuilt_dir {
+        Some(prebuilt_dir) => prebuilt_dir.clone(),
+        None => {
+            build_with_cmake(&llama_dst, &target, build_shared_libs, &profile, static_crt);
+            out_dir.clone()
+        }
+    };
 
-    let mut config = Config::new(&llama_dst);
+    // The C shims over llama.cpp's C++ only parameters. The C++ standard library is linked below
+    // for every platform.
+    cc::Build::new()
+        .cpp(true)
+        .std("c++17")
+        .file("shim/quantize.cpp")
+        .cpp_link_stdlib(None)
+        .static_crt(static_crt)
+        .compile("llama-rs-shim");
 
-    config
-        .define(
-            "BUILD_SHARED_LIBS",
-            if build_shared_libs { "ON" } else { "OFF" },
-        );
+    // Search paths
+    println!("cargo:rustc-link-search={}", lib_root.join("lib").display());
 
-    if cfg!(target_os = "macos") {
-        config.define("GGML_BLAS", "OFF");
-    }
+    // Link libraries
+    let llama_libs_kind = if build_shared_libs { "dylib" } else { "static" };
+    let llama_libs = extract_lib_names(&lib_root, build_shared_libs)
+        .into_iter()
+        // a system prefix contains many more libraries than llama and ggml
+        .filter(|lib| prebuilt_dir.is_none() || is_llama_lib(lib));
 
-    if cfg!(windows) {
-        config.static_crt(static_crt);
+
+    for lib in llama_libs {
+        debug_log!(
+            "LINK {}",
+            format!("cargo:rustc-link-lib={}={}", llama_libs_kind, lib)
+        );
+        println!(
+            "{}",
+            format!("cargo:rustc-link-lib={}={}", llama_libs_kind, lib)
+        );
     }
-    
 
     if cfg!(feature = "vulkan") {
-        config.define("GGML_VULKAN", "ON");
         if cfg!(windows) {
             let vulkan_path = env::var("VULKAN_SDK").expect("Please install Vulkan SDK and ensure that VULKAN_SDK env variable is set");
             let vulkan_lib_path = Path::new(&vulkan_path).join("Lib");
@@ -236,44 +665,23 @@ fn main() {
             println!("cargo:rustc-link-lib=vulkan-1");
         }
 
-        if cfg!(target_os = "linux") {
+        if cfg!(target_os = "linux") && !android {
             println!("cargo:rustc-link-lib=vulkan");
         }
     }
 
-    if cfg!(feature = "cuda") {
-        config.define("GGML_CUDA", "ON");
-    }
-
-    if cfg!(feature = "openmp") {
-        config.define("GGML_OPENMP", "ON");
+    // emcc links libc++ itself; the host specific libraries below do not exist for wasm
+    if wasm {
+        return;
     }
 
-    // General
-    config
-        .profile(&profile)
-        .very_verbose(std::env::var("CMAKE_VERBOSE").is_ok()) // Not verbose by default
-        .always_configure(false);
-
-    let build_dir = config.build();
-
-    // Search paths
-    println!("cargo:rustc-link-search={}", out_dir.join("lib").display());
-    println!("cargo:rustc-link-search={}", build_dir.display());
-
-    // Link libraries
-    let llama_libs_kind = if build_shared_libs { "dylib" } else { "static" };
-    let llama_libs = extract_lib_names(&out_dir, build_shared_libs);
-
-    for lib in llama_libs {
-        debug_log!(
-            "LINK {}",
-            format!("cargo:rustc-link-lib={}={}", llama_libs_kind, lib)
-        );
-        println!(
-            "{}",
-            format!("cargo:rustc-link-lib={}={}", llama_libs_kind, lib)
-        );
+    // the NDK has no libstdc++ or libgomp, only its own libc++ and the system vulkan loader
+    if android {
+        println!("cargo:rustc-link-lib=dylib=c++_shared");
+        if cfg!(feature = "vulkan") {
+            println!("cargo:rustc-link-lib=vulkan");
+        }
+        return;
     }
 
     // OpenMP
@@ -288,8 +696,9 @@ fn main() {
         println!("cargo:rustc-link-lib=dylib=msvcrtd");
     }
 
-    // // macOS
-    if cfg!(target_os = "macos") {
+    // Apple
+    let apple = ApplePlatform::from_target(&target);
+    if apple.is_some() {
         println!("cargo:rustc-link-lib=framework=Foundation");
         println!("cargo:rustc-link-lib=framework=Metal");
         println!("cargo:rustc-link-lib=framework=MetalKit");
@@ -302,7 +711,7 @@ fn main() {
         println!("cargo:rustc-link-lib=dylib=stdc++");
     }
 
-    if target.contains("apple") {
+    if apple == Some(ApplePlatform::MacOs) {
         // On (older) OSX we need to link against the clang runtime,
         // which is hidden in some non-default path.
         //
@@ -315,7 +724,10 @@ fn main() {
 
     // copy DLLs to target
     if build_shared_libs {
-        let libs_assets = extract_lib_assets(&out_dir);
+        let libs_assets = extract_lib_assets(&lib_root).into_iter().filter(|asset| {
+            let stem = asset.file_stem().unwrap().to_string_lossy();
+            prebuilt_dir.is_none() || is_llama_lib(stem.strip_prefix("lib").unwrap_or(&stem))
+        });
         for asset in libs_assets {
             let asset_clone = asset.clone();
             let filename = asset_clone.file_name().unwrap();
@@ -0,0 +1,20 @@
+#include "quantize.h"
+
+#include <string>
+#include <unordered_map>
+#include <vector>
+
+using imatrix_map = std::unordered_map<std::string, std::vector<float>>;
+
+struct llama_rs_imatrix * llama_rs_imatrix_new(void) {
+    return reinterpret_cast<struct llama_rs_imatrix *>(new imatrix_map());
+}
+
+void llama_rs_imatrix_insert(struct llama_rs_imatrix * imatrix, const char * name, const float * values, size_t n_values) {
+    auto & map = *reinterpret_cast<imatrix_map *>(imatrix);
+    map[name] = std::vector<float>(values, values + n_values);
+}
+
+void llama_rs_imatrix_free(struct llama_rs_imatrix * imatrix) {
+    delete reinterpret_cast<imatrix_map *>(imatrix);
+}
@@ -0,0 +1,24 @@
+// Helpers for the parts of llama_model_quantize_params that are C++ types behind a void pointer.
+#pragma once
+
+#include <stddef.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+// The importance matrix llama_model_quantize_params.imatrix points to, a
+// std::unordered_map<std::string, std::vector<float>> from tensor names to the mean squared
+// activations of their columns.
+struct llama_rs_imatrix;
+
+struct llama_rs_imatrix * llama_rs_imatrix_new(void);
+
+// Set the values of the tensor `name`, replacing earlier ones.
+void llama_rs_imatrix_insert(struct llama_rs_imatrix * imatrix, const char * name, const float * values, size_t n_values);
+
+void llama_rs_imatrix_free(struct llama_rs_imatrix * imatrix);
+
+#ifdef __cplusplus
+}
+#endif